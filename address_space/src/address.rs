@@ -158,33 +158,62 @@ impl AddressRange {
         AddressRange { base, size }
     }
 
-    /// Find the intersection with other `AddressRange`.
-    /// Return the intersection of Self and the given address range.
-    /// Return None if not overlaps.
+    /// Find the intersection with other `AddressRange`, same as `intersection`.
     ///
     /// # Arguments
     ///
     /// * `other` - Other AddressRange.
     pub fn find_intersection(&self, other: AddressRange) -> Option<AddressRange> {
-        let end = self.base.checked_add(self.size)?;
-        let other_end = other.base.checked_add(other.size)?;
+        self.intersection(other)
+    }
 
-        if end <= other.base || other_end <= self.base {
+    /// Return the intersection of Self and the given address range.
+    /// Return None if not overlaps, or either range wraps around the end of
+    /// address space. Ranges ending at the very end of address space are
+    /// handled, whose end address is not representable.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - Other AddressRange.
+    pub fn intersection(&self, other: AddressRange) -> Option<AddressRange> {
+        if self.size == 0 || other.size == 0 {
             return None;
         }
+        let last = self.base.checked_add(self.size - 1)?;
+        let other_last = other.base.checked_add(other.size - 1)?;
 
         let start = std::cmp::max(self.base, other.base);
+        let last = std::cmp::min(last, other_last);
+        if start > last {
+            return None;
+        }
         Some(AddressRange {
             base: start,
-            size: std::cmp::min(end, other_end).offset_from(start),
+            size: last.offset_from(start) + 1,
         })
     }
 
+    /// Whether [`addr`, `addr` + `size`) is inside this address range.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start address.
+    /// * `size` - Size of memory segment.
+    pub fn contains(&self, addr: GuestAddress, size: u64) -> bool {
+        addr >= self.base && size <= self.size && addr.offset_from(self.base) <= self.size - size
+    }
+
     /// Return the end address of this address range.
+    /// The caller has to guarantee no overflow occurs.
     #[inline]
     pub fn end_addr(&self) -> GuestAddress {
         self.base.unchecked_add(self.size)
     }
+
+    /// Return the end address of this address range, return None if overflows.
+    pub fn end_addr_checked(&self) -> Option<GuestAddress> {
+        self.base.checked_add(self.size)
+    }
 }
 
 #[cfg(test)]
@@ -294,5 +323,60 @@ mod test {
         };
 
         assert_eq!(range.end_addr(), GuestAddress(65_u64));
+        assert_eq!(range.end_addr_checked(), Some(GuestAddress(65_u64)));
+
+        let top = AddressRange::from((u64::max_value() - 9, 10));
+        assert_eq!(top.end_addr_checked(), None);
+        assert_eq!(
+            AddressRange::from((u64::max_value() - 9, 9)).end_addr_checked(),
+            Some(GuestAddress(u64::max_value()))
+        );
+    }
+
+    #[test]
+    fn test_address_range_contains() {
+        let range = AddressRange::from((0x1000, 0x1000));
+        assert!(range.contains(GuestAddress(0x1000), 0x1000));
+        assert!(range.contains(GuestAddress(0x1fff), 1));
+        assert!(range.contains(GuestAddress(0x2000), 0));
+        assert!(!range.contains(GuestAddress(0xfff), 1));
+        assert!(!range.contains(GuestAddress(0x1fff), 2));
+        assert!(!range.contains(GuestAddress(0x2000), 1));
+
+        // `addr` + `size` wraps around to inside the range.
+        assert!(!range.contains(GuestAddress(u64::max_value()), 0x1002));
+        let top = AddressRange::from((u64::max_value() - 0xfff, 0x1000));
+        assert!(top.contains(GuestAddress(u64::max_value()), 1));
+        assert!(!top.contains(GuestAddress(u64::max_value()), 2));
+        for size in (0..64).map(|shift| 1_u64 << shift) {
+            let addr = GuestAddress(u64::max_value() - size / 4);
+            assert_eq!(top.contains(addr, size), size <= 1);
+            assert!(!range.contains(addr, size));
+        }
+    }
+
+    #[test]
+    fn test_address_range_intersection_edge() {
+        let top = AddressRange::from((u64::max_value() - 0xfff, 0x1000));
+        let all = AddressRange::from((0, u64::max_value()));
+        assert_eq!(
+            top.intersection(AddressRange::from((u64::max_value() - 0x7ff, 0x800))),
+            Some(AddressRange::from((u64::max_value() - 0x7ff, 0x800)))
+        );
+        assert_eq!(
+            all.intersection(top),
+            Some(AddressRange::from((u64::max_value() - 0xfff, 0xfff)))
+        );
+        assert_eq!(top.intersection(all), all.intersection(top));
+
+        // Ranges wrapping around never intersect.
+        let wrapped = AddressRange::from((u64::max_value() - 0xf, 0x20));
+        assert!(wrapped
+            .intersection(AddressRange::from((0, 0x10)))
+            .is_none());
+        assert!(wrapped.intersection(top).is_none());
+        assert!(top
+            .intersection(AddressRange::from((u64::max_value(), 0)))
+            .is_none());
     }
 }
@@ -10,26 +10,227 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::sync::{Arc, Mutex, RwLock};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use machine_manager::config::MachineMemConfig;
+use util::arc_swap::ArcSwap;
 use util::byte_code::ByteCode;
 
 use crate::errors::{ErrorKind, Result, ResultExt};
 use crate::{
-    AddressRange, FlatRange, GuestAddress, Listener, ListenerReqType, Region, RegionIoEventFd,
-    RegionType,
+    create_host_mmaps, page_size, AddressRange, FlatRange, GuestAddress, GuestMemSlice, Listener,
+    ListenerReqType, Region, RegionIoEventFd, RegionType, VcpusPaused,
 };
 
+/// Piece of a vectored access resolved against one `FlatView`.
+enum VectoredPiece<'a> {
+    /// Contiguous host memory at (host address, size), made of Ram pieces of
+    /// (flat-range, offset in flat-range, size).
+    Host(u64, u64, Vec<(&'a FlatRange, u64, u64)>),
+    /// Piece of (flat-range, offset in flat-range, size) dispatched to the
+    /// owner region of flat-range.
+    Region(&'a FlatRange, u64, u64),
+}
+
+/// Ring of guest writes batched in coalesced MMIO ranges, which is flushed
+/// by `AddressSpace::drain_coalesced`.
+pub trait CoalescedRing {
+    /// Pop the oldest write in the ring, as its address and data written.
+    fn pop(&mut self) -> Option<(GuestAddress, Vec<u8>)>;
+}
+
 /// Contain an array of `FlatRange`.
 #[derive(Default, Clone)]
 pub struct FlatView(pub Vec<FlatRange>);
 
 impl FlatView {
-    fn find_flatrange(&self, addr: GuestAddress) -> Option<&FlatRange> {
-        match self.0.binary_search_by_key(&addr, |x| x.addr_range.base) {
-            Ok(x) => Some(&self.0[x]),
-            Err(x) if (x > 0 && addr < self.0[x - 1].addr_range.end_addr()) => Some(&self.0[x - 1]),
-            _ => None,
+    /// Return the flat-range which contains `addr`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    pub fn find_range(&self, addr: GuestAddress) -> Option<&FlatRange> {
+        self.find_range_index(addr).map(|idx| &self.0[idx])
+    }
+
+    /// Return index of the flat-range which contains `addr`, flat-ranges are
+    /// sorted and don't overlap, so binary search over their end addresses.
+    fn find_range_index(&self, addr: GuestAddress) -> Option<usize> {
+        self.0
+            .binary_search_by(|fr| {
+                if fr.addr_range.end_addr() <= addr {
+                    std::cmp::Ordering::Less
+                } else if fr.addr_range.base > addr {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .ok()
+    }
+
+    /// Same as `find_range`, but try the flat-range at index `last_hit` first,
+    /// and update `last_hit` when another flat-range is found.
+    fn find_range_cached(&self, addr: GuestAddress, last_hit: &AtomicUsize) -> Option<&FlatRange> {
+        let cached = last_hit.load(Ordering::Relaxed);
+        if let Some(fr) = self.0.get(cached) {
+            if fr.addr_range.contains(addr, 1) {
+                return Some(fr);
+            }
+        }
+        let idx = self.find_range_index(addr)?;
+        last_hit.store(idx, Ordering::Relaxed);
+        Some(&self.0[idx])
+    }
+
+    /// Split access of `count` bytes at `addr` by flat-ranges.
+    /// Return pieces of (flat-range, offset in flat-range, size).
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the access overflows, or the first address not mapped.
+    fn split_access(
+        &self,
+        addr: GuestAddress,
+        count: u64,
+        last_hit: &AtomicUsize,
+    ) -> Result<Vec<(&FlatRange, u64, u64)>> {
+        if addr.checked_add(count).is_none() {
+            return Err(ErrorKind::Overflow(addr.raw_value()).into());
+        }
+        let mut pieces = Vec::new();
+        let mut start = addr;
+        let mut remain = count;
+        loop {
+            let fr = self
+                .find_range_cached(start, last_hit)
+                .chain_err(|| ErrorKind::AddrInvalid(start.raw_value()))?;
+            let offset = start.offset_from(fr.addr_range.base);
+            let size = std::cmp::min(remain, fr.addr_range.size - offset);
+            pieces.push((fr, offset, size));
+            start = start.unchecked_add(size);
+            remain -= size;
+            if remain == 0 {
+                return Ok(pieces);
+            }
+        }
+    }
+
+    /// Merge consecutive flat-ranges which are contiguous pieces of the same
+    /// region, e.g. rendered from adjacent aliases of it, so that listeners
+    /// get as few ranges as possible.
+    pub(crate) fn canonicalize(&mut self) {
+        let mut merged: Vec<FlatRange> = Vec::with_capacity(self.0.len());
+        for fr in self.0.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if last.owner.is_same(&fr.owner)
+                    && last.addr_range.end_addr() == fr.addr_range.base
+                    && last.offset_in_region + last.addr_range.size == fr.offset_in_region
+                    && last.log_dirty == fr.log_dirty
+                    && last.rom == fr.rom
+                {
+                    last.addr_range.size += fr.addr_range.size;
+                    last.coalesced.extend(fr.coalesced);
+                    continue;
+                }
+            }
+            merged.push(fr);
+        }
+        self.0 = merged;
+    }
+
+    /// Split vectored access of segments in `iov` by flat-ranges, Ram pieces
+    /// of contiguous host memory are merged.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if some segment is not mapped, or it's a write to rom.
+    fn split_vectored(
+        &self,
+        iov: &[(GuestAddress, u64)],
+        last_hit: &AtomicUsize,
+        write: bool,
+    ) -> Result<Vec<VectoredPiece>> {
+        let mut pieces: Vec<VectoredPiece> = Vec::new();
+        for (addr, count) in iov.iter() {
+            for (fr, offset, size) in self.split_access(*addr, *count, last_hit)? {
+                if fr.owner.region_type() != RegionType::Ram {
+                    pieces.push(VectoredPiece::Region(fr, offset, size));
+                    continue;
+                }
+                if write && fr.owner.is_rom() {
+                    return Err(ErrorKind::ReadOnly(
+                        fr.owner.name().to_string(),
+                        fr.addr_range.base.raw_value() + offset,
+                    )
+                    .into());
+                }
+                let host_addr = range_host_address(fr, offset)?;
+                if let Some(VectoredPiece::Host(start, len, rams)) = pieces.last_mut() {
+                    if *start + *len == host_addr {
+                        *len += size;
+                        rams.push((fr, offset, size));
+                        continue;
+                    }
+                }
+                pieces.push(VectoredPiece::Host(
+                    host_addr,
+                    size,
+                    vec![(fr, offset, size)],
+                ));
+            }
+        }
+        Ok(pieces)
+    }
+}
+
+/// Return host address of `offset` in flat-range `fr`, which must be Ram.
+fn range_host_address(fr: &FlatRange, offset: u64) -> Result<u64> {
+    let region_type = fr.owner.region_type();
+    if region_type != RegionType::Ram {
+        return Err(ErrorKind::RegionType(region_type).into());
+    }
+    // Ram region always has host address.
+    Ok(fr.owner.get_host_address().unwrap() + fr.offset_in_region + offset)
+}
+
+/// Size of x86 port-IO space, whose addresses are 16-bit.
+const PIO_SPACE_SIZE: u64 = 1 << 16;
+
+/// Magic at the beginning of RAM snapshot written by `AddressSpace::save_ram`.
+const RAM_SNAPSHOT_MAGIC: &[u8; 8] = b"SVRAMSNP";
+/// Page size of zero-page elision in RAM snapshot.
+const SNAPSHOT_PAGE_SIZE: u64 = 0x1000;
+/// Payload of RAM snapshot record is raw contents of the range.
+const SNAPSHOT_RAW: u64 = 0;
+/// Payload of RAM snapshot record is a bitmap of non-zero pages, one bit per
+/// page in u64 words, followed by contents of the non-zero pages.
+const SNAPSHOT_ZERO_ELIDED: u64 = 1;
+
+fn read_u64(src: &mut dyn std::io::Read) -> Result<u64> {
+    let mut bytes = [0_u8; 8];
+    src.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Type of address space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpaceType {
+    /// Memory space with 64-bit addresses.
+    Memory,
+    /// x86 port-IO space with 16-bit addresses, whose ioeventfds are
+    /// registered with PIO flag.
+    Pio,
+}
+
+impl SpaceType {
+    /// Maximum size of address space of this type.
+    pub fn max_size(self) -> u64 {
+        match self {
+            SpaceType::Memory => u64::max_value(),
+            SpaceType::Pio => PIO_SPACE_SIZE,
         }
     }
 }
@@ -39,27 +240,59 @@ impl FlatView {
 pub struct AddressSpace {
     /// Root Region of this AddressSpace.
     root: Region,
+    /// Type of this AddressSpace.
+    space_type: SpaceType,
     /// Flat_view is the output of rendering all regions in this address-space.
-    /// Every time the topology changed (add/delete region), flat_view will be updated.
-    flat_view: Arc<RwLock<FlatView>>,
-    /// The triggered call-backs when flat_view changed.
-    listeners: Arc<Mutex<Vec<Box<dyn Listener>>>>,
+    /// Every time the topology changed (add/delete region), a new flat_view is
+    /// built and swapped in. Readers clone the snapshot without lock.
+    flat_view: Arc<ArcSwap<FlatView>>,
+    /// Serialize topology updates and listener (un)registration.
+    update_lock: Arc<Mutex<()>>,
+    /// The triggered call-backs when flat_view changed, with their ids.
+    listeners: Arc<Mutex<Vec<(u64, Box<dyn Listener>)>>>,
+    /// Id of the next registered listener.
+    next_listener_id: Arc<AtomicU64>,
     /// The current layout of ioeventfds, which is compared with new ones in topology-update stage.
     ioeventfds: Arc<Mutex<Vec<RegionIoEventFd>>>,
+    /// Index of the flat-range hit by the last access, consecutive accesses
+    /// mostly hit the same flat-range.
+    last_hit: Arc<AtomicUsize>,
 }
 
 impl AddressSpace {
-    /// Create a new `AddressSpace` according to the given root region.
+    /// Create a new memory `AddressSpace` according to the given root region.
     ///
     /// # Arguments
     ///
     /// * `root` - Root region of address space.
     pub fn new(root: Region) -> Result<Arc<AddressSpace>> {
+        AddressSpace::new_with_type(root, SpaceType::Memory)
+    }
+
+    /// Create a new `AddressSpace` of `space_type` according to the given
+    /// root region, e.g. port-IO space on x86.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Root region of address space.
+    /// * `space_type` - Type of address space.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if size of `root` exceeds limit of `space_type`.
+    pub fn new_with_type(root: Region, space_type: SpaceType) -> Result<Arc<AddressSpace>> {
+        if root.size() > space_type.max_size() {
+            return Err(ErrorKind::SpaceSize(root.size(), space_type.max_size()).into());
+        }
         let space = Arc::new(AddressSpace {
             root: root.clone(),
-            flat_view: Arc::new(RwLock::new(FlatView::default())),
+            space_type,
+            flat_view: Arc::new(ArcSwap::new(Arc::new(FlatView::default()))),
+            update_lock: Arc::new(Mutex::new(())),
             listeners: Arc::new(Mutex::new(Vec::new())),
+            next_listener_id: Arc::new(AtomicU64::new(0)),
             ioeventfds: Arc::new(Mutex::new(Vec::new())),
+            last_hit: Arc::new(AtomicUsize::new(0)),
         });
 
         root.set_belonged_address_space(&space);
@@ -75,7 +308,85 @@ impl AddressSpace {
         &self.root
     }
 
-    /// Register the listener to the `AddressSpace`.
+    /// Get the type of AddressSpace.
+    pub fn space_type(&self) -> SpaceType {
+        self.space_type
+    }
+
+    /// Get the current snapshot of flat_view.
+    pub(crate) fn flat_view(&self) -> Arc<FlatView> {
+        self.flat_view.load()
+    }
+
+    /// Add a Ram region named `name` to root region at runtime, e.g. hotplugged
+    /// memory, which is mapped by listeners, such as a new KVM memory slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the Ram region, unique in root region.
+    /// * `addr` - Guest address of the Ram region.
+    /// * `size` - Size of the Ram region.
+    /// * `mem_config` - Backend of memory, `mem_size` is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * Region named `name` already exists in root region.
+    /// * Fail to map memory.
+    /// * Fail to add the region to root region.
+    pub fn add_ram_region(
+        &self,
+        name: &str,
+        addr: GuestAddress,
+        size: u64,
+        mem_config: &MachineMemConfig,
+    ) -> Result<()> {
+        if self.root.subregions().iter().any(|r| r.name() == name) {
+            bail!("Add Ram region failed: region {} already exists", name);
+        }
+        let mapping = create_host_mmaps(&[(addr.raw_value(), size)], mem_config)?
+            .pop()
+            .unwrap();
+        self.root
+            .add_subregion(
+                Region::init_ram_region_named(name, mapping),
+                addr.raw_value(),
+            )
+            .chain_err(|| format!("Failed to add Ram region {}", name))
+    }
+
+    /// Remove Ram region named `name` from root region, the memory is unmapped
+    /// once no access is using it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the Ram region.
+    /// * `paused` - Proof that all vcpus are parked outside `KVM_RUN`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * No Ram region named `name` in root region.
+    /// * Fail to delete the region from root region.
+    pub fn remove_ram_region(&self, name: &str, paused: &dyn VcpusPaused) -> Result<()> {
+        let region = self
+            .root
+            .subregions()
+            .into_iter()
+            .find(|r| r.name() == name && r.region_type() == RegionType::Ram);
+        match region {
+            Some(region) => self
+                .root
+                .delete_ram_subregion(&region, paused)
+                .map(|_| ())
+                .chain_err(|| format!("Failed to remove Ram region {}", name)),
+            None => bail!("Remove Ram region failed: no Ram region named {}", name),
+        }
+    }
+
+    /// Register the listener to the `AddressSpace`, current regions and
+    /// ioeventfds are added to it first.
+    /// Return the id of listener, which is used to unregister it.
     ///
     /// # Arguments
     ///
@@ -84,23 +395,65 @@ impl AddressSpace {
     /// # Errors
     ///
     /// Return Error if fail to call `listener`.
-    pub fn register_listener(&self, listener: Box<dyn Listener>) -> Result<()> {
-        for fr in self.flat_view.read().unwrap().0.iter() {
+    pub fn register_listener(&self, listener: Box<dyn Listener>) -> Result<u64> {
+        let _update = self.update_lock.lock().unwrap();
+        for fr in self.flat_view().0.iter() {
             listener
                 .handle_request(Some(&fr), None, ListenerReqType::AddRegion)
                 .chain_err(|| "Failed to call listener")?;
         }
+        for evtfd in self.ioeventfds.lock().unwrap().iter() {
+            listener
+                .handle_request(None, Some(evtfd), ListenerReqType::AddIoeventfd)
+                .chain_err(|| "Failed to call listener")?;
+        }
 
+        let id = self.next_listener_id.fetch_add(1, Ordering::SeqCst);
         let mut idx = 0;
         let mut mls = self.listeners.lock().unwrap();
         while idx < mls.len() {
-            let ml = mls.get(idx).unwrap();
+            let (_, ml) = mls.get(idx).unwrap();
             if ml.priority() >= listener.priority() {
                 break;
             }
             idx += 1;
         }
-        mls.insert(idx, listener);
+        mls.insert(idx, (id, listener));
+        Ok(id)
+    }
+
+    /// Unregister the listener from the `AddressSpace`, current ioeventfds
+    /// and regions are deleted from it before.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id returned by `register_listener`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * No listener with `id` is registered.
+    /// * Fail to call the listener.
+    pub fn unregister_listener(&self, id: u64) -> Result<()> {
+        let _update = self.update_lock.lock().unwrap();
+        let mut mls = self.listeners.lock().unwrap();
+        let idx = match mls.iter().position(|(ml_id, _)| *ml_id == id) {
+            Some(idx) => idx,
+            None => bail!("Unregister listener failed: no listener with id {}", id),
+        };
+        let (_, listener) = mls.remove(idx);
+        drop(mls);
+
+        for evtfd in self.ioeventfds.lock().unwrap().iter() {
+            listener
+                .handle_request(None, Some(evtfd), ListenerReqType::DeleteIoeventfd)
+                .chain_err(|| "Failed to call listener")?;
+        }
+        for fr in self.flat_view().0.iter() {
+            listener
+                .handle_request(Some(&fr), None, ListenerReqType::DeleteRegion)
+                .chain_err(|| "Failed to call listener")?;
+        }
         Ok(())
     }
 
@@ -124,19 +477,21 @@ impl AddressSpace {
         let listeners = self.listeners.lock().unwrap();
         match req_type {
             ListenerReqType::DeleteRegion | ListenerReqType::AddIoeventfd => {
-                listeners.iter().rev().try_for_each(|ml| {
+                listeners.iter().rev().try_for_each(|(_, ml)| {
                     ml.handle_request(flat_range, evtfd, req_type)
                         .chain_err(|| "Failed to call listener")
                 })
             }
-            _ => listeners.iter().try_for_each(|ml| {
+            _ => listeners.iter().try_for_each(|(_, ml)| {
                 ml.handle_request(flat_range, evtfd, req_type)
                     .chain_err(|| "Failed to call listener")
             }),
         }
     }
 
-    /// Update the topology pass.
+    /// Update the topology pass. Ranges which are the same piece of the same
+    /// region in both flatviews are kept, others are deleted or added, so a
+    /// moved range is reported as deletion and addition.
     ///
     /// # Arguments
     ///
@@ -160,14 +515,11 @@ impl AddressSpace {
 
             if let Some(old_r) = old_range {
                 if let Some(new_r) = new_range {
-                    if old_r.addr_range == new_r.addr_range {
+                    if old_r == new_r {
                         old_idx += 1;
                         new_idx += 1;
                         continue;
-                    } else if old_r.addr_range.base < new_r.addr_range.base
-                        || (old_r.addr_range.base == new_r.addr_range.base
-                            && old_r.addr_range.size != new_r.addr_range.size)
-                    {
+                    } else if old_r.addr_range.base <= new_r.addr_range.base {
                         if !is_add {
                             self.call_listeners(Some(old_r), None, ListenerReqType::DeleteRegion)?;
                         }
@@ -226,46 +578,166 @@ impl AddressSpace {
     /// Update IoEventfds.
     /// This function will compare new ioeventfds generated from `FlatView` with old ones
     /// which is stored in AddressSpace, and then update them.
+    /// Both arrays are sorted by `RegionIoEventFd::before`, so listeners are only
+    /// notified of changed ioeventfds.
     fn update_ioeventfds(&self) -> Result<()> {
-        let flatview = self.flat_view.read().unwrap();
+        let flatview = self.flat_view();
         let mut ioeventfds = Vec::<RegionIoEventFd>::new();
 
         for fr in flatview.0.iter() {
-            let region_base = fr.addr_range.base.unchecked_sub(fr.offset_in_region).0;
+            let region_base = fr.region_base().0;
             for evtfd in fr.owner.ioeventfds().iter() {
                 let mut evtfd_clone = evtfd.try_clone()?;
-                evtfd_clone.addr_range.base =
-                    evtfd_clone.addr_range.base.unchecked_add(region_base);
-                if fr
-                    .addr_range
-                    .find_intersection(evtfd_clone.addr_range)
-                    .is_some()
-                {
+                // Base of region seen through an alias may wrap around.
+                evtfd_clone.addr_range.base = GuestAddress(
+                    evtfd_clone
+                        .addr_range
+                        .base
+                        .raw_value()
+                        .wrapping_add(region_base),
+                );
+                if fr.addr_range.intersection(evtfd_clone.addr_range).is_some() {
                     ioeventfds.push(evtfd_clone);
                 }
             }
         }
 
+        ioeventfds.sort_by(|a, b| {
+            if a.before(b) {
+                std::cmp::Ordering::Less
+            } else if b.before(a) {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        });
+
         self.update_ioeventfds_pass(&ioeventfds)?;
         *self.ioeventfds.lock().unwrap() = ioeventfds;
         Ok(())
     }
 
-    /// Return the host address according to the given `GuestAddress`.
+    /// Return the host address of `size` bytes at the given `GuestAddress`,
+    /// which must be in one Ram flat-range.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    /// * `size` - Size of the segment.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * `addr` is not mapped.
+    /// * `addr` is not in Ram region.
+    /// * The segment crosses the end of flat-range.
+    pub fn get_host_address(&self, addr: GuestAddress, size: u64) -> Result<u64> {
+        let view = self.flat_view();
+        let range = view
+            .find_range_cached(addr, &self.last_hit)
+            .chain_err(|| ErrorKind::AddrInvalid(addr.raw_value()))?;
+        let offset = addr.offset_from(range.addr_range.base);
+        if size > range.addr_range.size - offset {
+            return Err(ErrorKind::CrossRegions(addr.raw_value(), size).into());
+        }
+        range_host_address(range, offset)
+    }
+
+    /// Return host segments of `size` bytes at the given `GuestAddress`,
+    /// one (host address, length) pair for each Ram flat-range it crosses.
     ///
     /// # Arguments
     ///
     /// * `addr` - Guest address.
-    pub fn get_host_address(&self, addr: GuestAddress) -> Option<u64> {
-        let view = &self.flat_view.read().unwrap();
+    /// * `size` - Size of the segment.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if part of the segment is not mapped or not in Ram region.
+    pub fn get_address_map(&self, addr: GuestAddress, size: u64) -> Result<Vec<(u64, u64)>> {
+        let view = self.flat_view();
+        let mut segments = Vec::new();
+        for (fr, offset, len) in view.split_access(addr, size, &self.last_hit)? {
+            segments.push((range_host_address(fr, offset)?, len));
+        }
+        Ok(segments)
+    }
 
-        view.find_flatrange(addr).and_then(|range| {
-            let offset = addr.offset_from(range.addr_range.base);
-            range
+    /// Free guest memory of `size` bytes at `addr` back to host, e.g. pages
+    /// given up by guest through balloon. Pages partially in the range are
+    /// skipped. Discarded pages read as zero afterwards.
+    ///
+    /// Return the number of bytes discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    /// * `size` - Size of memory.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if part of the range is not mapped or not in Ram region,
+    /// or fail to discard memory.
+    pub fn discard_range(&self, addr: GuestAddress, size: u64) -> Result<u64> {
+        let view = self.flat_view();
+        let mut discarded = 0;
+        for (fr, offset, len) in view.split_access(addr, size, &self.last_hit)? {
+            discarded += fr
                 .owner
-                .get_host_address()
-                .map(|host| host + range.offset_in_region + offset)
-        })
+                .discard(fr.offset_in_region + offset, len)
+                .chain_err(|| {
+                    format!(
+                        "Failed to discard memory at 0x{:x}",
+                        fr.addr_range.base.raw_value() + offset
+                    )
+                })?;
+        }
+        Ok(discarded)
+    }
+
+    /// Total size of guest memory mapped by Ram regions.
+    pub fn ram_size(&self) -> u64 {
+        self.flat_view()
+            .0
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram)
+            .map(|fr| fr.addr_range.size)
+            .sum()
+    }
+
+    /// Number of enabled non-container regions in this AddressSpace.
+    pub fn region_count(&self) -> usize {
+        self.root.leaf_count()
+    }
+
+    /// Number of flat-ranges in the current flat-view.
+    pub fn flat_range_count(&self) -> usize {
+        self.flat_view().0.len()
+    }
+
+    /// Return the largest address range not mapped by any region, or `None`
+    /// if the whole AddressSpace is mapped.
+    pub fn largest_gap(&self) -> Option<AddressRange> {
+        let view = self.flat_view();
+        let mut largest: Option<AddressRange> = None;
+        let mut start = 0_u64;
+        let ends = view
+            .0
+            .iter()
+            .map(|fr| {
+                (
+                    fr.addr_range.base.raw_value(),
+                    fr.addr_range.end_addr().raw_value(),
+                )
+            })
+            .chain(std::iter::once((self.root.size(), self.root.size())));
+        for (base, end) in ends {
+            if base > start && largest.map_or(true, |r| base - start > r.size) {
+                largest = Some(AddressRange::new(GuestAddress(start), base - start));
+            }
+            start = std::cmp::max(start, end);
+        }
+        largest
     }
 
     /// Check if the GuestAddress is in one of Ram region.
@@ -274,24 +746,37 @@ impl AddressSpace {
     ///
     /// * `addr` - Guest address.
     pub fn address_in_memory(&self, addr: GuestAddress, size: u64) -> bool {
-        let view = &self.flat_view.read().unwrap();
+        let view = self.flat_view();
 
-        view.find_flatrange(addr).map_or(false, |range| {
-            range.owner.region_type() == RegionType::Ram
-                && size <= range.addr_range.end_addr().offset_from(addr)
-        })
+        view.find_range_cached(addr, &self.last_hit)
+            .map_or(false, |range| {
+                range.owner.region_type() == RegionType::Ram
+                    && range.addr_range.contains(addr, size)
+            })
     }
 
     /// Return the end address fo memory  according to all Ram regions in AddressSpace.
     pub fn memory_end_address(&self) -> GuestAddress {
-        let view = &self.flat_view.read().unwrap().0;
-        view.iter()
+        let view = self.flat_view();
+        view.0
+            .iter()
             .filter(|fr| fr.owner.region_type() == RegionType::Ram)
             .max_by_key(|fr| fr.addr_range.end_addr())
             .map_or(GuestAddress(0), |fr| fr.addr_range.end_addr())
     }
 
-    /// Read memory segment to `dst`.
+    /// Return address ranges of all Ram regions in AddressSpace, in ascending order.
+    pub fn ram_ranges(&self) -> Vec<AddressRange> {
+        let view = self.flat_view();
+        view.0
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram)
+            .map(|fr| fr.addr_range)
+            .collect()
+    }
+
+    /// Read memory segment to `dst`. The segment may cross flat-ranges,
+    /// each piece is read from its owner region.
     ///
     /// # Arguments
     ///
@@ -301,24 +786,304 @@ impl AddressSpace {
     ///
     /// # Errors
     ///
-    /// Return Error if the `addr` is not mapped.
+    /// Return Error if part of the segment is not mapped, nothing is read then.
     pub fn read(&self, dst: &mut dyn std::io::Write, addr: GuestAddress, count: u64) -> Result<()> {
-        let view = &self.flat_view.read().unwrap();
+        let view = self.flat_view();
 
-        let (fr, offset) = view
-            .find_flatrange(addr)
-            .map(|fr| (fr, addr.offset_from(fr.addr_range.base)))
-            .chain_err(|| ErrorKind::AddrInvalid(addr.raw_value()))?;
+        for (fr, offset, size) in view.split_access(addr, count, &self.last_hit)? {
+            fr.owner
+                .read(dst, fr.region_base(), fr.offset_in_region + offset, size)?;
+        }
+        Ok(())
+    }
 
-        fr.owner.read(
-            dst,
-            fr.addr_range.base.unchecked_sub(fr.offset_in_region),
-            fr.offset_in_region + offset,
-            count,
-        )
+    /// Dump guest memory to `dst`. Each range is written as a header of its
+    /// guest base address and size, both u64 in little-endian, followed by
+    /// raw contents of the range.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - Destination of the dump.
+    /// * `ranges` - Ranges to dump, all Ram ranges if None.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if some range is not in Ram region, or fail to write `dst`.
+    pub fn dump_memory(
+        &self,
+        dst: &mut dyn std::io::Write,
+        ranges: Option<&[AddressRange]>,
+    ) -> Result<()> {
+        let ranges = match ranges {
+            Some(ranges) => ranges.to_vec(),
+            None => self.ram_ranges(),
+        };
+        for range in ranges.iter() {
+            let base = range.base.raw_value();
+            // Check the whole range is Ram before writing its header.
+            self.get_address_map(range.base, range.size)
+                .chain_err(|| format!("Failed to dump memory at 0x{:x}", base))?;
+            dst.write_all(&base.to_le_bytes())?;
+            dst.write_all(&range.size.to_le_bytes())?;
+            self.read(dst, range.base, range.size)
+                .chain_err(|| format!("Failed to dump memory at 0x{:x}", base))?;
+        }
+        dst.flush()?;
+        Ok(())
+    }
+
+    /// Save contents of all Ram ranges to file `path`, vcpus should be paused.
+    ///
+    /// The file starts with magic, the number of ranges and the table of
+    /// (guest base, size) of ranges. Then each range is written as a record
+    /// of guest base, size, payload type and payload, where all-zero pages
+    /// are elided if there are any. All numbers are u64 in little-endian.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the snapshot file, created or truncated.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to write the file.
+    pub fn save_ram(&self, path: &std::path::Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .chain_err(|| format!("Failed to create RAM snapshot {}", path.display()))?;
+        let mut dst = std::io::BufWriter::new(file);
+        let ranges = self.ram_ranges();
+
+        dst.write_all(RAM_SNAPSHOT_MAGIC)?;
+        dst.write_all(&(ranges.len() as u64).to_le_bytes())?;
+        for range in ranges.iter() {
+            dst.write_all(&range.base.raw_value().to_le_bytes())?;
+            dst.write_all(&range.size.to_le_bytes())?;
+        }
+
+        for range in ranges.iter() {
+            let base = range.base.raw_value();
+            let bitmap = self
+                .nonzero_pages(*range)
+                .chain_err(|| format!("Failed to save RAM at 0x{:x}", base))?;
+            let pages = (range.size + SNAPSHOT_PAGE_SIZE - 1) / SNAPSHOT_PAGE_SIZE;
+            let nonzero = bitmap.iter().map(|w| w.count_ones() as u64).sum::<u64>();
+
+            dst.write_all(&base.to_le_bytes())?;
+            dst.write_all(&range.size.to_le_bytes())?;
+            if nonzero == pages {
+                dst.write_all(&SNAPSHOT_RAW.to_le_bytes())?;
+                self.read(&mut dst, range.base, range.size)
+                    .chain_err(|| format!("Failed to save RAM at 0x{:x}", base))?;
+                continue;
+            }
+            dst.write_all(&SNAPSHOT_ZERO_ELIDED.to_le_bytes())?;
+            for word in bitmap.iter() {
+                dst.write_all(&word.to_le_bytes())?;
+            }
+            for page in (0..pages).filter(|p| bitmap[(p / 64) as usize] & (1 << (p % 64)) != 0) {
+                let offset = page * SNAPSHOT_PAGE_SIZE;
+                let len = std::cmp::min(SNAPSHOT_PAGE_SIZE, range.size - offset);
+                self.read(&mut dst, range.base.unchecked_add(offset), len)
+                    .chain_err(|| format!("Failed to save RAM at 0x{:x}", base + offset))?;
+            }
+        }
+        dst.flush()?;
+        Ok(())
+    }
+
+    /// Return bitmap of pages in `range` which are not all-zero.
+    fn nonzero_pages(&self, range: AddressRange) -> Result<Vec<u64>> {
+        let pages = (range.size + SNAPSHOT_PAGE_SIZE - 1) / SNAPSHOT_PAGE_SIZE;
+        let mut bitmap = vec![0_u64; ((pages + 63) / 64) as usize];
+        let mut buf = [0_u8; SNAPSHOT_PAGE_SIZE as usize];
+        for page in 0..pages {
+            let offset = page * SNAPSHOT_PAGE_SIZE;
+            let len = std::cmp::min(SNAPSHOT_PAGE_SIZE, range.size - offset);
+            self.read(
+                &mut &mut buf[..len as usize],
+                range.base.unchecked_add(offset),
+                len,
+            )?;
+            if buf[..len as usize].iter().any(|b| *b != 0) {
+                bitmap[(page / 64) as usize] |= 1 << (page % 64);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// Load contents of Ram ranges from snapshot file `path` written by
+    /// `save_ram`, vcpus should be paused. Read-only Ram is restored too.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the snapshot file.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * The file is not a RAM snapshot.
+    /// * Ram ranges of snapshot don't match the current ones, nothing is
+    ///   written to guest memory then.
+    /// * Fail to read the file or the file is truncated.
+    pub fn load_ram(&self, path: &std::path::Path) -> Result<()> {
+        let file = std::fs::File::open(path)
+            .chain_err(|| format!("Failed to open RAM snapshot {}", path.display()))?;
+        let mut src = std::io::BufReader::new(file);
+
+        let mut magic = [0_u8; 8];
+        src.read_exact(&mut magic)?;
+        if &magic != RAM_SNAPSHOT_MAGIC {
+            bail!("{} is not a RAM snapshot", path.display());
+        }
+        let ranges = self.ram_ranges();
+        let count = read_u64(&mut src)?;
+        if count != ranges.len() as u64 {
+            bail!(
+                "RAM snapshot has {} ranges, but guest has {}",
+                count,
+                ranges.len()
+            );
+        }
+        for range in ranges.iter() {
+            let base = read_u64(&mut src)?;
+            let size = read_u64(&mut src)?;
+            if base != range.base.raw_value() || size != range.size {
+                bail!(
+                    "RAM range (0x{:x}, 0x{:x}) of snapshot doesn't match guest's (0x{:x}, 0x{:x})",
+                    base,
+                    size,
+                    range.base.raw_value(),
+                    range.size
+                );
+            }
+        }
+
+        for range in ranges.iter() {
+            let base = range.base.raw_value();
+            if read_u64(&mut src)? != base || read_u64(&mut src)? != range.size {
+                bail!("Corrupted RAM snapshot record at 0x{:x}", base);
+            }
+            match read_u64(&mut src)? {
+                SNAPSHOT_RAW => self
+                    .restore_ram(&mut src, range.base, range.size)
+                    .chain_err(|| format!("Failed to load RAM at 0x{:x}", base))?,
+                SNAPSHOT_ZERO_ELIDED => {
+                    let pages = (range.size + SNAPSHOT_PAGE_SIZE - 1) / SNAPSHOT_PAGE_SIZE;
+                    let mut bitmap = Vec::with_capacity(((pages + 63) / 64) as usize);
+                    for _ in 0..(pages + 63) / 64 {
+                        bitmap.push(read_u64(&mut src)?);
+                    }
+                    for page in 0..pages {
+                        let offset = page * SNAPSHOT_PAGE_SIZE;
+                        let len = std::cmp::min(SNAPSHOT_PAGE_SIZE, range.size - offset);
+                        let addr = range.base.unchecked_add(offset);
+                        let ret = if bitmap[(page / 64) as usize] & (1 << (page % 64)) != 0 {
+                            self.restore_ram(&mut src, addr, len)
+                        } else {
+                            self.restore_ram(&mut std::io::repeat(0).take(len), addr, len)
+                        };
+                        ret.chain_err(|| {
+                            format!("Failed to load RAM at 0x{:x}", addr.raw_value())
+                        })?;
+                    }
+                }
+                t => bail!("Unknown RAM snapshot payload type {} at 0x{:x}", t, base),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `count` bytes from `src` to Ram at `addr` in host memory directly,
+    /// so that read-only Ram is written as well.
+    fn restore_ram(
+        &self,
+        src: &mut dyn std::io::Read,
+        addr: GuestAddress,
+        count: u64,
+    ) -> Result<()> {
+        let view = self.flat_view();
+        for (fr, offset, size) in view.split_access(addr, count, &self.last_hit)? {
+            let host_addr = range_host_address(fr, offset)?;
+            // Safe because Ram region of `fr` is mapped in the whole range.
+            unsafe { GuestMemSlice::from_raw(host_addr, size) }.write_from(src)?;
+            fr.owner.mark_dirty(fr.offset_in_region + offset, size);
+        }
+        Ok(())
+    }
+
+    /// Read guest memory segments of (address, size) in `iov` to `dst` in order.
+    /// All segments are resolved against one flat-view, and Ram pieces of
+    /// contiguous host memory are copied at once. Pieces in other regions are
+    /// read by their owner regions.
+    ///
+    /// Return the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if some segment is not mapped, nothing is read then.
+    pub fn read_vectored(
+        &self,
+        iov: &[(GuestAddress, u64)],
+        dst: &mut dyn std::io::Write,
+    ) -> Result<u64> {
+        let view = self.flat_view();
+        let mut total = 0;
+        for piece in view.split_vectored(iov, &self.last_hit, false)? {
+            match piece {
+                VectoredPiece::Host(host_addr, len, _) => {
+                    // Safe because the Ram pieces merged are all mapped.
+                    unsafe { GuestMemSlice::from_raw(host_addr, len) }.read_to(dst)?;
+                    total += len;
+                }
+                VectoredPiece::Region(fr, offset, size) => {
+                    fr.owner
+                        .read(dst, fr.region_base(), fr.offset_in_region + offset, size)?;
+                    total += size;
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// Write data from `src` to guest memory segments of (address, size) in
+    /// `iov` in order. All segments are resolved against one flat-view, and
+    /// Ram pieces of contiguous host memory are copied at once. Pieces in
+    /// other regions are written by their owner regions.
+    ///
+    /// Return the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if some segment is not mapped or in rom, nothing is
+    /// written then.
+    pub fn write_vectored(
+        &self,
+        src: &mut dyn std::io::Read,
+        iov: &[(GuestAddress, u64)],
+    ) -> Result<u64> {
+        let view = self.flat_view();
+        let mut total = 0;
+        for piece in view.split_vectored(iov, &self.last_hit, true)? {
+            match piece {
+                VectoredPiece::Host(host_addr, len, rams) => {
+                    // Safe because the Ram pieces merged are all mapped.
+                    unsafe { GuestMemSlice::from_raw(host_addr, len) }.write_from(src)?;
+                    for (fr, offset, size) in rams {
+                        fr.owner.mark_dirty(fr.offset_in_region + offset, size);
+                    }
+                    total += len;
+                }
+                VectoredPiece::Region(fr, offset, size) => {
+                    fr.owner
+                        .write(src, fr.region_base(), fr.offset_in_region + offset, size)?;
+                    total += size;
+                }
+            }
+        }
+        Ok(total)
     }
 
-    /// Write data to specified guest address.
+    /// Write data to specified guest address. The segment may cross
+    /// flat-ranges, each piece is written to its owner region.
     ///
     /// # Arguments
     ///
@@ -328,21 +1093,15 @@ impl AddressSpace {
     ///
     /// # Errors
     ///
-    /// Return Error if the `addr` is not mapped.
+    /// Return Error if part of the segment is not mapped, nothing is written then.
     pub fn write(&self, src: &mut dyn std::io::Read, addr: GuestAddress, count: u64) -> Result<()> {
-        let view = &self.flat_view.read().unwrap();
+        let view = self.flat_view();
 
-        let (fr, offset) = view
-            .find_flatrange(addr)
-            .map(|fr| (fr, addr.offset_from(fr.addr_range.base)))
-            .chain_err(|| ErrorKind::AddrInvalid(addr.raw_value()))?;
-
-        fr.owner.write(
-            src,
-            fr.addr_range.base.unchecked_sub(fr.offset_in_region),
-            fr.offset_in_region + offset,
-            count,
-        )
+        for (fr, offset, size) in view.split_access(addr, count, &self.last_hit)? {
+            fr.owner
+                .write(src, fr.region_base(), fr.offset_in_region + offset, size)?;
+        }
+        Ok(())
     }
 
     /// Write an object to memory.
@@ -376,19 +1135,133 @@ impl AddressSpace {
         Ok(obj)
     }
 
-    /// Update the topology of memory.
-    pub fn update_topology(&self) -> Result<()> {
-        let old_fv = self.flat_view.read().unwrap();
+    /// Get and reset dirty pages in [`addr`, `addr` + `size`) of Ram regions
+    /// which log dirty pages, see `Region::set_log_dirty`. Pages written by
+    /// guest are collected from listeners, those written by `write` are
+    /// collected from regions.
+    /// Return bitmap of pages, bit `n` is set iff the page at `addr` + `n` *
+    /// page size is dirty.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Page-aligned start address.
+    /// * `size` - Size of memory, rounded up to page size.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `addr` isn't page-aligned, or fail to call listeners.
+    pub fn get_dirty_log(&self, addr: GuestAddress, size: u64) -> Result<Vec<u64>> {
+        if addr.raw_value() % page_size() != 0 {
+            return Err(ErrorKind::AddrNotAligned(addr.raw_value()).into());
+        }
+        let pages = (size + page_size() - 1) / page_size();
+        let mut bitmap = vec![0_u64; ((pages + 63) / 64) as usize];
+        let range = AddressRange::new(addr, pages * page_size());
 
-        let addr_range = AddressRange::new(GuestAddress(0), self.root.size());
-        let new_fv = self.root.generate_flatview(GuestAddress(0), addr_range)?;
+        for fr in self.flat_view().0.iter() {
+            if fr.owner.region_type() != RegionType::Ram || !fr.log_dirty {
+                continue;
+            }
+            let intersect = match fr.addr_range.intersection(range) {
+                Some(r) => r,
+                None => continue,
+            };
+            let offset = fr.offset_in_region + intersect.base.offset_from(fr.addr_range.base);
+            for page_offset in fr.owner.take_dirty_pages(offset, intersect.size) {
+                let page_addr = (fr.addr_range.base.raw_value() + page_offset)
+                    .saturating_sub(fr.offset_in_region)
+                    .max(intersect.base.raw_value());
+                let page = (page_addr - addr.raw_value()) / page_size();
+                bitmap[(page / 64) as usize] |= 1_u64 << (page % 64);
+            }
+        }
+
+        for (_, listener) in self.listeners.lock().unwrap().iter() {
+            listener
+                .sync_dirty_log(addr, range.size, &mut bitmap)
+                .chain_err(|| "Failed to call listener")?;
+        }
+        Ok(bitmap)
+    }
+
+    /// Flush guest writes batched in coalesced MMIO `ring` to their regions,
+    /// in the order guest made them, see `Region::set_coalesced_io`. It should
+    /// be called on each vcpu exit before the exit is handled, so that the
+    /// batched writes land before the access which exits.
+    /// Return the number of flushed writes.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if a write fails, later writes stay in `ring` then.
+    pub fn drain_coalesced(&self, ring: &mut dyn CoalescedRing) -> Result<usize> {
+        let mut count = 0_usize;
+        while let Some((addr, data)) = ring.pop() {
+            self.write(&mut data.as_slice(), addr, data.len() as u64)
+                .chain_err(|| {
+                    format!(
+                        "Failed to flush coalesced write to 0x{:x}",
+                        addr.raw_value()
+                    )
+                })?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Dump the region tree of this address space, one region per line with
+    /// its address range, priority, type and name, like `info mtree` of QEMU.
+    pub fn dump_tree(&self) -> String {
+        let mut out = String::new();
+        self.root.render_tree(GuestAddress(0), 0, &mut out);
+        out
+    }
+
+    /// Dump the flat view of this address space, one flat-range per line with
+    /// its address range, and priority, type, name of its owner, followed by
+    /// the offset in owner if not zero.
+    pub fn dump_flatview(&self) -> String {
+        let mut out = String::new();
+        for fr in self.flat_view().0.iter() {
+            out.push_str(&format!(
+                "{:016x}-{:016x} (prio {}, {}): {}",
+                fr.addr_range.base.raw_value(),
+                fr.addr_range.end_addr().raw_value() - 1,
+                fr.owner.priority(),
+                fr.owner.type_name(),
+                fr.owner.name()
+            ));
+            if fr.offset_in_region != 0 {
+                out.push_str(&format!(" @{:016x}", fr.offset_in_region));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Update the topology of memory.
+    /// The new flat_view is built and passed to listeners aside, then swapped
+    /// in, so that accesses are never blocked by listeners. Listeners check
+    /// the new flat_view first, nothing is changed if any of them rejects it.
+    pub fn update_topology(&self) -> Result<()> {
+        let _update = self.update_lock.lock().unwrap();
+        let old_fv = self.flat_view();
+
+        let addr_range = AddressRange::new(GuestAddress(0), self.root.size());
+        let new_fv = self.root.generate_flatview(GuestAddress(0), addr_range)?;
+        for (_, ml) in self.listeners.lock().unwrap().iter() {
+            ml.check_ranges(&new_fv.0)
+                .chain_err(|| "New flat view is rejected by listener")?;
+        }
 
         self.update_topology_pass(&old_fv, &new_fv, false)?;
         self.update_topology_pass(&old_fv, &new_fv, true)?;
 
-        drop(old_fv);
-        *self.flat_view.write().unwrap() = new_fv;
+        self.flat_view.store(Arc::new(new_fv));
+        self.last_hit.store(0, Ordering::Relaxed);
         self.update_ioeventfds()?;
+        if log_enabled!(log::Level::Trace) {
+            trace!("Flat view updated:\n{}", self.dump_flatview());
+        }
         Ok(())
     }
 }
@@ -398,7 +1271,8 @@ mod test {
     use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
-    use crate::{HostMemMapping, RegionOps};
+    use crate::errors::Error;
+    use crate::{dirty_page_count, dirty_pfns, HostMemMapping, RegionOps};
 
     #[derive(Default, Clone)]
     struct TestListener {
@@ -477,7 +1351,7 @@ mod test {
         space.register_listener(listener4).unwrap();
 
         let mut pre_prior = std::i32::MIN;
-        for listener in space.listeners.lock().unwrap().iter() {
+        for (_, listener) in space.listeners.lock().unwrap().iter() {
             let curr = listener.priority();
             assert!(pre_prior <= curr);
             pre_prior = curr;
@@ -506,12 +1380,12 @@ mod test {
         //        [CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC]
         let region_b = Region::init_container_region(4000);
         let region_c = Region::init_io_region(6000, default_ops.clone());
-        region_b.set_priority(2);
-        region_c.set_priority(1);
+        region_b.set_priority(2).unwrap();
+        region_c.set_priority(1).unwrap();
         root.add_subregion(region_b.clone(), 2000).unwrap();
         root.add_subregion(region_c.clone(), 0).unwrap();
 
-        assert_eq!(space.flat_view.read().unwrap().0.len(), 1);
+        assert_eq!(space.flat_view().0.len(), 1);
         assert_eq!(listener.reqs.lock().unwrap().len(), 1);
         assert_eq!(
             listener.reqs.lock().unwrap().get(0).unwrap().1,
@@ -530,7 +1404,7 @@ mod test {
         let region_d = Region::init_io_region(1000, default_ops);
         region_b.add_subregion(region_d.clone(), 0).unwrap();
 
-        assert_eq!(space.flat_view.read().unwrap().0.len(), 3);
+        assert_eq!(space.flat_view().0.len(), 3);
         assert_eq!(listener.reqs.lock().unwrap().len(), 4);
         // delete flat-range 0~6000 first, belonging to region_c
         assert_eq!(
@@ -581,7 +1455,7 @@ mod test {
         space.register_listener(Box::new(listener.clone())).unwrap();
 
         let region_b = Region::init_io_region(2000, default_ops.clone());
-        region_b.set_priority(1);
+        region_b.set_priority(1).unwrap();
         region_b.set_ioeventfds(&ioeventfds);
         let region_c = Region::init_io_region(2000, default_ops);
         region_c.set_ioeventfds(&ioeventfds);
@@ -608,6 +1482,383 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_move_ioeventfd() {
+        let ioeventfds = vec![
+            RegionIoEventFd {
+                fd: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+                addr_range: AddressRange::from((8, std::mem::size_of::<u16>() as u64)),
+                data_match: true,
+                data: 1_u64,
+            },
+            RegionIoEventFd {
+                fd: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+                addr_range: AddressRange::from((0, std::mem::size_of::<u32>() as u64)),
+                data_match: false,
+                data: 0_u64,
+            },
+        ];
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let ioeventfd_reqs = |listener: &TestListener| {
+            listener
+                .reqs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(req_type, range)| match req_type {
+                    ListenerReqType::AddIoeventfd => Some((true, range.base.raw_value())),
+                    ListenerReqType::DeleteIoeventfd => Some((false, range.base.raw_value())),
+                    _ => None,
+                })
+                .collect::<Vec<(bool, u64)>>()
+        };
+
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+
+        let region = Region::init_io_region(1000, default_ops);
+        region.set_ioeventfds(&ioeventfds);
+        root.add_subregion(region.clone(), 1000).unwrap();
+        assert_eq!(ioeventfd_reqs(&listener), vec![(true, 1000), (true, 1008)]);
+        listener.reqs.lock().unwrap().clear();
+
+        // Same ioeventfds at the same place, nothing changes.
+        region.set_ioeventfds(&ioeventfds);
+        space.update_topology().unwrap();
+        assert!(listener.reqs.lock().unwrap().is_empty());
+
+        // Move region, old ioeventfds are deleted and new ones are added.
+        root.delete_subregion(&region).unwrap();
+        root.add_subregion(region, 3000).unwrap();
+        assert_eq!(
+            ioeventfd_reqs(&listener),
+            vec![(false, 1000), (false, 1008), (true, 3000), (true, 3008)]
+        );
+        assert_eq!(space.ioeventfds.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_find_range() {
+        let owner = Region::init_container_region(u64::max_value());
+        // Flat-ranges of size 50 every 100 bytes, the last one ends at max address.
+        let mut ranges: Vec<FlatRange> = (0..999_u64)
+            .map(|i| FlatRange {
+                addr_range: AddressRange::new(GuestAddress(i * 100), 50),
+                owner: owner.clone(),
+                offset_in_region: i * 100,
+                log_dirty: false,
+                rom: false,
+                coalesced: Vec::new(),
+            })
+            .collect();
+        ranges.push(FlatRange {
+            addr_range: AddressRange::new(GuestAddress(u64::max_value() - 50), 50),
+            owner,
+            offset_in_region: 0,
+            log_dirty: false,
+            rom: false,
+            coalesced: Vec::new(),
+        });
+        let view = FlatView(ranges);
+        let linear_find = |addr: GuestAddress| {
+            view.0
+                .iter()
+                .position(|fr| fr.addr_range.base <= addr && addr < fr.addr_range.end_addr())
+        };
+
+        let mut probes = vec![0, u64::max_value() - 51, u64::max_value() - 1];
+        for i in 0..1000_u64 {
+            probes.extend_from_slice(&[
+                i * 100,
+                i * 100 + 1,
+                i * 100 + 49,
+                i * 100 + 50,
+                i * 100 + 99,
+            ]);
+        }
+        let last_hit = AtomicUsize::new(0);
+        for addr in probes.into_iter().map(GuestAddress) {
+            let expected = linear_find(addr);
+            assert_eq!(view.find_range_index(addr), expected);
+            assert_eq!(
+                view.find_range_cached(addr, &last_hit)
+                    .map(|fr| fr.addr_range.base),
+                expected.map(|idx| view.0[idx].addr_range.base)
+            );
+            if let Some(idx) = expected {
+                assert_eq!(last_hit.load(Ordering::Relaxed), idx);
+            }
+        }
+    }
+
+    #[test]
+    fn test_disable_region() {
+        let ioeventfds = vec![RegionIoEventFd {
+            fd: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            addr_range: AddressRange::from((0, 4)),
+            data_match: false,
+            data: 0,
+        }];
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  b:           [BBBBBBBBBBBBB]
+        //  c:                  [CCCCCCCCCCCCC]
+        // the flat_view is as follows when b is enabled,
+        //               [BBBBBBBBBBBBB][CCCCC]
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+
+        let region_b = Region::init_io_region(2000, default_ops.clone());
+        region_b.set_priority(1).unwrap();
+        region_b.set_ioeventfds(&ioeventfds);
+        let region_c = Region::init_io_region(2000, default_ops);
+        root.add_subregion(region_c, 2000).unwrap();
+        listener.reqs.lock().unwrap().clear();
+
+        // Initially disabled region isn't rendered.
+        region_b.set_enabled(false).unwrap();
+        root.add_subregion(region_b.clone(), 1000).unwrap();
+        assert!(listener.reqs.lock().unwrap().is_empty());
+        assert_eq!(space.flat_view().0.len(), 1);
+        assert!(space.ioeventfds.lock().unwrap().is_empty());
+
+        region_b.set_enabled(true).unwrap();
+        assert_eq!(space.flat_view().0.len(), 2);
+        assert_eq!(
+            space.flat_view().0[1].addr_range,
+            AddressRange::from((3000, 1000))
+        );
+        assert_eq!(space.ioeventfds.lock().unwrap().len(), 1);
+        listener.reqs.lock().unwrap().clear();
+
+        // Disabling shrinks flat view back, and drops ioeventfds.
+        region_b.set_enabled(false).unwrap();
+        assert_eq!(space.flat_view().0.len(), 1);
+        assert_eq!(
+            space.flat_view().0[0].addr_range,
+            AddressRange::from((2000, 2000))
+        );
+        assert!(space.ioeventfds.lock().unwrap().is_empty());
+        // Region is deleted, and ioeventfd of region_b is deleted but not re-added.
+        let mut deleted_evtfds = Vec::new();
+        for (req, range) in listener.reqs.lock().unwrap().iter() {
+            match req {
+                ListenerReqType::DeleteIoeventfd => deleted_evtfds.push(*range),
+                ListenerReqType::AddIoeventfd => panic!("Disabled region has no ioeventfd"),
+                _ => {}
+            }
+        }
+        assert_eq!(deleted_evtfds, vec![AddressRange::from((1000, 4))]);
+
+        // Setting the same state again changes nothing.
+        listener.reqs.lock().unwrap().clear();
+        region_b.set_enabled(false).unwrap();
+        assert!(listener.reqs.lock().unwrap().is_empty());
+        assert!(!region_b.is_enabled());
+    }
+
+    #[test]
+    fn test_listener_sequence() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let take_reqs = || -> Vec<(bool, u64, u64)> {
+            listener
+                .reqs
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|(req, range)| match req {
+                    ListenerReqType::AddRegion => (true, range.base.raw_value(), range.size),
+                    ListenerReqType::DeleteRegion => (false, range.base.raw_value(), range.size),
+                    _ => panic!("Unexpected ioeventfd request"),
+                })
+                .collect()
+        };
+
+        // region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  a:    [AAAAAAAAAAAAAAAAAAAAAAAAAAA]
+        //  b:                  [BBBBBBBBBBBBB]
+        let region_a = Region::init_io_region(4000, default_ops.clone());
+        let region_b = Region::init_io_region(2000, default_ops);
+        root.add_subregion(region_a, 0).unwrap();
+        assert_eq!(take_reqs(), vec![(true, 0, 4000)]);
+
+        // b above a splits it.
+        region_b.set_priority(1).unwrap();
+        root.add_subregion(region_b.clone(), 2000).unwrap();
+        assert_eq!(
+            take_reqs(),
+            vec![(false, 0, 4000), (true, 0, 2000), (true, 2000, 2000)]
+        );
+
+        root.delete_subregion(&region_b).unwrap();
+        assert_eq!(
+            take_reqs(),
+            vec![(false, 0, 2000), (false, 2000, 2000), (true, 0, 4000)]
+        );
+
+        // b below a is shadowed.
+        region_b.set_priority(-1).unwrap();
+        root.add_subregion(region_b, 2000).unwrap();
+        assert!(take_reqs().is_empty());
+    }
+
+    #[test]
+    fn test_remap_subregion() {
+        let root = Region::init_container_region(0x10000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout
+        //        0      0x2000  0x4000  0x6000  0x8000  0xa000  0xc000
+        //        |------|-------|-------|-------|-------|-------|
+        //  A:    [AAAAAA]
+        //  B:                   [                       ]
+        //  C:                   [CCCCCCC]
+        //  D:                                   [DDDDDDD]
+        //  E:                                                   [EEEEEEE]
+        let region_a = Region::init_io_region(0x2000, default_ops.clone());
+        let region_b = Region::init_container_region(0x6000);
+        let region_c = Region::init_io_region(0x2000, default_ops.clone());
+        let region_d = Region::init_io_region(0x2000, default_ops.clone());
+        let region_e = Region::init_io_region(0x2000, default_ops.clone());
+        root.add_subregion(region_a, 0).unwrap();
+        root.add_subregion(region_b.clone(), 0x4000).unwrap();
+        region_b.add_subregion(region_c.clone(), 0).unwrap();
+        region_b.add_subregion(region_d, 0x4000).unwrap();
+        root.add_subregion(region_e, 0xc000).unwrap();
+        assert_eq!(space.flat_view().0.len(), 4);
+        listener.reqs.lock().unwrap().clear();
+
+        // Move C from 0x4000 to 0x6000, others are untouched.
+        region_b.delete_subregion(&region_c).unwrap();
+        region_b.add_subregion(region_c, 0x2000).unwrap();
+        {
+            let reqs = listener.reqs.lock().unwrap();
+            assert_eq!(reqs.len(), 2);
+            assert!(matches_req(
+                &reqs[0],
+                false,
+                AddressRange::from((0x4000, 0x2000))
+            ));
+            assert!(matches_req(
+                &reqs[1],
+                true,
+                AddressRange::from((0x6000, 0x2000))
+            ));
+        }
+        listener.reqs.lock().unwrap().clear();
+
+        // Another region at the same range takes the place of C.
+        let region_f = Region::init_io_region(0x2000, default_ops);
+        region_f.set_priority(1).unwrap();
+        region_b.add_subregion(region_f.clone(), 0x2000).unwrap();
+        {
+            let reqs = listener.reqs.lock().unwrap();
+            assert_eq!(reqs.len(), 2);
+            assert!(matches_req(
+                &reqs[0],
+                false,
+                AddressRange::from((0x6000, 0x2000))
+            ));
+            assert!(matches_req(
+                &reqs[1],
+                true,
+                AddressRange::from((0x6000, 0x2000))
+            ));
+        }
+        let view = space.flat_view();
+        assert!(view.0.iter().any(|fr| fr.owner.is_same(&region_f)));
+    }
+
+    #[test]
+    fn test_unregister_listener() {
+        let ioeventfds = vec![RegionIoEventFd {
+            fd: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            addr_range: AddressRange::from((0, 4)),
+            data_match: false,
+            data: 0,
+        }];
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let region = Region::init_io_region(2000, default_ops.clone());
+        region.set_ioeventfds(&ioeventfds);
+        root.add_subregion(region, 1000).unwrap();
+
+        // Late listener gets current region and ioeventfd.
+        let listener = TestListener::default();
+        let id = space.register_listener(Box::new(listener.clone())).unwrap();
+        {
+            let reqs = listener.reqs.lock().unwrap();
+            assert_eq!(reqs.len(), 2);
+            assert!(matches_req(
+                &reqs[0],
+                true,
+                AddressRange::from((1000, 2000))
+            ));
+            assert!(matches_req(&reqs[1], true, AddressRange::from((1000, 4))));
+        }
+        listener.reqs.lock().unwrap().clear();
+
+        space.unregister_listener(id).unwrap();
+        {
+            let reqs = listener.reqs.lock().unwrap();
+            assert_eq!(reqs.len(), 2);
+            assert!(matches_req(&reqs[0], false, AddressRange::from((1000, 4))));
+            assert!(matches_req(
+                &reqs[1],
+                false,
+                AddressRange::from((1000, 2000))
+            ));
+        }
+        listener.reqs.lock().unwrap().clear();
+
+        // Unregistered listener isn't called any more.
+        root.add_subregion(Region::init_io_region(1000, default_ops), 5000)
+            .unwrap();
+        assert!(listener.reqs.lock().unwrap().is_empty());
+        assert!(space.unregister_listener(id).is_err());
+    }
+
+    /// Whether `req` adds (`add`) or deletes region or ioeventfd of `range`.
+    fn matches_req(req: &(ListenerReqType, AddressRange), add: bool, range: AddressRange) -> bool {
+        let is_add = match req.0 {
+            ListenerReqType::AddRegion | ListenerReqType::AddIoeventfd => true,
+            ListenerReqType::DeleteRegion | ListenerReqType::DeleteIoeventfd => false,
+        };
+        is_add == add && req.1 == range
+    }
+
     #[test]
     fn test_subregion_ioeventfd() {
         let ioeventfds = vec![RegionIoEventFd {
@@ -676,14 +1927,21 @@ mod test {
         assert_eq!(space.address_in_memory(GuestAddress(1000), 0), false);
         assert_eq!(space.address_in_memory(GuestAddress(1500), 0), false);
         assert!(space.address_in_memory(GuestAddress(2900), 0));
+        assert_eq!(
+            space.ram_ranges(),
+            vec![
+                AddressRange::new(GuestAddress(0), 1000),
+                AddressRange::new(GuestAddress(2000), 1000)
+            ]
+        );
 
         assert_eq!(
-            space.get_host_address(GuestAddress(500)),
-            Some(ram1.host_address() + 500)
+            space.get_host_address(GuestAddress(500), 500).unwrap(),
+            ram1.host_address() + 500
         );
         assert_eq!(
-            space.get_host_address(GuestAddress(2500)),
-            Some(ram2.host_address() + 500)
+            space.get_host_address(GuestAddress(2500), 8).unwrap(),
+            ram2.host_address() + 500
         );
 
         // region layout
@@ -695,7 +1953,7 @@ mod test {
         // the flat_view is as follows,
         //        [AAAAAA][CCCCCCCCC][BB]
         let region_c = Region::init_io_region(1500, default_ops);
-        region_c.set_priority(1);
+        region_c.set_priority(1).unwrap();
         root.add_subregion(region_c, 1000).unwrap();
 
         assert_eq!(
@@ -709,14 +1967,478 @@ mod test {
         assert!(space.address_in_memory(GuestAddress(2900), 0));
 
         assert_eq!(
-            space.get_host_address(GuestAddress(500)),
-            Some(ram1.host_address() + 500)
+            space.get_host_address(GuestAddress(500), 8).unwrap(),
+            ram1.host_address() + 500
+        );
+        assert!(space.get_host_address(GuestAddress(2400), 8).is_err());
+        assert_eq!(
+            space.ram_ranges(),
+            vec![
+                AddressRange::new(GuestAddress(0), 1000),
+                AddressRange::new(GuestAddress(2500), 500)
+            ]
+        );
+        assert_eq!(
+            space.get_host_address(GuestAddress(2500), 8).unwrap(),
+            ram2.host_address() + 500
+        );
+    }
+
+    #[test]
+    fn test_discard_range() {
+        let page = crate::page_size();
+        let root = Region::init_container_region(8 * page);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 4 * page, -1, 0, false, false).unwrap());
+        root.add_subregion(Region::init_ram_region(ram.clone()), 0)
+            .unwrap();
+        let mem = unsafe {
+            std::slice::from_raw_parts_mut(ram.host_address() as *mut u8, (4 * page) as usize)
+        };
+
+        for byte in mem.iter_mut() {
+            *byte = 0x5a;
+        }
+        // Only page 1 is covered entirely.
+        assert_eq!(
+            space
+                .discard_range(GuestAddress(page / 2), 2 * page)
+                .unwrap(),
+            page
+        );
+        let page = page as usize;
+        assert!(mem[..page].iter().all(|byte| *byte == 0x5a));
+        assert!(mem[page..2 * page].iter().all(|byte| *byte == 0));
+        assert!(mem[2 * page..].iter().all(|byte| *byte == 0x5a));
+        let page = page as u64;
+
+        assert_eq!(space.discard_range(GuestAddress(1), page).unwrap(), 0);
+        assert!(space.discard_range(GuestAddress(0), 5 * page).is_err());
+
+        // Shared file-backed memory is freed by punching hole.
+        let mem_config = MachineMemConfig {
+            mem_share: true,
+            ..Default::default()
+        };
+        let shared = crate::create_host_mmaps(&[(4 * page, 2 * page)], &mem_config).unwrap();
+        root.add_subregion(Region::init_ram_region(shared[0].clone()), 4 * page)
+            .unwrap();
+        let mem = unsafe {
+            std::slice::from_raw_parts_mut(shared[0].host_address() as *mut u8, (2 * page) as usize)
+        };
+        for byte in mem.iter_mut() {
+            *byte = 0x5a;
+        }
+        assert_eq!(
+            space
+                .discard_range(GuestAddress(3 * page), 3 * page)
+                .unwrap(),
+            3 * page
+        );
+        assert!(mem.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_rom_attribute() {
+        // Record (is add request, base address, rom) of region requests.
+        #[derive(Default, Clone)]
+        struct RomListener {
+            reqs: Arc<Mutex<Vec<(bool, u64, bool)>>>,
+        }
+        impl Listener for RomListener {
+            fn priority(&self) -> i32 {
+                2
+            }
+
+            fn handle_request(
+                &self,
+                range: Option<&FlatRange>,
+                _eventfd: Option<&RegionIoEventFd>,
+                req_type: ListenerReqType,
+            ) -> Result<()> {
+                let is_add = match req_type {
+                    ListenerReqType::AddRegion => true,
+                    ListenerReqType::DeleteRegion => false,
+                    _ => return Ok(()),
+                };
+                let range = range.unwrap();
+                self.reqs.lock().unwrap().push((
+                    is_add,
+                    range.addr_range.base.raw_value(),
+                    range.rom,
+                ));
+                Ok(())
+            }
+        }
+
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let listener = RomListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+        let new_ram = |addr: u64| {
+            Arc::new(HostMemMapping::new(GuestAddress(addr), 0x1000, -1, 0, false, false).unwrap())
+        };
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        let rom = Region::init_ram_region(new_ram(0x1000));
+        rom.set_rom(true).unwrap();
+        root.add_subregion(Region::init_ram_region(new_ram(0)), 0)
+            .unwrap();
+        root.add_subregion(rom.clone(), 0x1000).unwrap();
+        root.add_subregion(
+            Region::init_rom_device_region(new_ram(0x2000), ops.clone()),
+            0x2000,
+        )
+        .unwrap();
+        root.add_subregion(Region::init_io_region(0x1000, ops), 0x3000)
+            .unwrap();
+        let mut adds = listener.reqs.lock().unwrap().clone();
+        adds.sort();
+        assert_eq!(
+            adds,
+            vec![
+                (true, 0, false),
+                (true, 0x1000, true),
+                (true, 0x2000, true),
+                (true, 0x3000, false)
+            ]
+        );
+
+        // Toggling rom re-adds the flat-range.
+        listener.reqs.lock().unwrap().clear();
+        rom.set_rom(false).unwrap();
+        assert_eq!(
+            *listener.reqs.lock().unwrap(),
+            vec![(false, 0x1000, true), (true, 0x1000, false)]
+        );
+        assert!(
+            !space
+                .flat_view()
+                .find_range(GuestAddress(0x1000))
+                .unwrap()
+                .rom
+        );
+    }
+
+    #[test]
+    fn test_pio_space() {
+        let root = Region::init_container_region(1 << 16);
+        let space = AddressSpace::new_with_type(root.clone(), SpaceType::Pio).unwrap();
+        assert_eq!(space.space_type(), SpaceType::Pio);
+        assert_eq!(
+            AddressSpace::new(Region::init_container_region(1 << 16))
+                .unwrap()
+                .space_type(),
+            SpaceType::Memory
+        );
+        match AddressSpace::new_with_type(Region::init_container_region(1 << 17), SpaceType::Pio) {
+            Err(Error(ErrorKind::SpaceSize(size, max), _)) => {
+                assert_eq!((size, max), (1 << 17, 1 << 16))
+            }
+            _ => panic!("expect SpaceSize error"),
+        }
+
+        // Each port region returns its port number plus offset.
+        let port_ops = |port: u16| RegionOps {
+            read: Arc::new(
+                move |data: &mut [u8], _: GuestAddress, offset: u64| -> bool {
+                    data[0] = (port + offset as u16) as u8;
+                    true
+                },
+            ),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        root.add_subregion(Region::init_io_region(8, port_ops(0x3f8)), 0x3f8)
+            .unwrap();
+        root.add_subregion(Region::init_io_region(2, port_ops(0x70)), 0x70)
+            .unwrap();
+
+        assert_eq!(space.read_object::<u8>(GuestAddress(0x3fd)).unwrap(), 0xfd);
+        assert_eq!(space.read_object::<u8>(GuestAddress(0x71)).unwrap(), 0x71);
+        assert!(space.read_object::<u8>(GuestAddress(0x72)).is_err());
+        assert!(space.read_object::<u8>(GuestAddress(0x10000)).is_err());
+        assert!(root
+            .add_subregion(Region::init_io_region(2, port_ops(0xffff)), 0xffff)
+            .is_err());
+    }
+
+    #[test]
+    fn test_vectored_access() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1000, -1, 0, false, false).unwrap());
+        let ram2 =
+            Arc::new(HostMemMapping::new(GuestAddress(1000), 1000, -1, 0, false, false).unwrap());
+        let io_data = Arc::new(Mutex::new(vec![0_u8; 1000]));
+        let io_read = io_data.clone();
+        let io_write = io_data.clone();
+        let io_ops = RegionOps {
+            read: Arc::new(
+                move |data: &mut [u8], _: GuestAddress, offset: u64| -> bool {
+                    let io = io_read.lock().unwrap();
+                    data.copy_from_slice(&io[offset as usize..offset as usize + data.len()]);
+                    true
+                },
+            ),
+            write: Arc::new(move |data: &[u8], _: GuestAddress, offset: u64| -> bool {
+                let mut io = io_write.lock().unwrap();
+                io[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+                true
+            }),
+        };
+
+        // region layout
+        //        0      1000   2000   3000   4000
+        //        |------|------|------|------|
+        //  ram:  [111111][222222]
+        //  io:                        [IIIIII]
+        root.add_subregion(Region::init_ram_region(ram1.clone()), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2.clone()), 1000)
+            .unwrap();
+        root.add_subregion(Region::init_io_region(1000, io_ops), 3000)
+            .unwrap();
+
+        let iov = [
+            (GuestAddress(900), 200),
+            (GuestAddress(3000), 8),
+            (GuestAddress(1500), 10),
+        ];
+        let src: Vec<u8> = (0..218).map(|x| x as u8).collect();
+        assert_eq!(
+            space.write_vectored(&mut src.as_slice(), &iov).unwrap(),
+            218
         );
-        assert!(space.get_host_address(GuestAddress(2400)).is_none());
+        assert_eq!(space.read_object::<u8>(GuestAddress(900)).unwrap(), 0);
+        assert_eq!(space.read_object::<u8>(GuestAddress(1099)).unwrap(), 199);
+        assert_eq!(&io_data.lock().unwrap()[..8], &src[200..208]);
+        assert_eq!(space.read_object::<u8>(GuestAddress(1509)).unwrap(), 217);
+
+        let mut dst = Vec::new();
+        assert_eq!(space.read_vectored(&iov, &mut dst).unwrap(), 218);
+        assert_eq!(dst, src);
+
+        // Adjacent segments in one Ram region are merged.
+        let view = space.flat_view();
+        let iov = [
+            (GuestAddress(0), 100),
+            (GuestAddress(100), 100),
+            (GuestAddress(200), 800),
+            (GuestAddress(3000), 8),
+        ];
+        let pieces = view.split_vectored(&iov, &space.last_hit, true).unwrap();
+        assert_eq!(pieces.len(), 2);
+        match &pieces[0] {
+            VectoredPiece::Host(host_addr, len, rams) => {
+                assert_eq!(*host_addr, ram1.host_address());
+                assert_eq!(*len, 1000);
+                assert_eq!(rams.len(), 3);
+            }
+            _ => panic!("expect Host piece"),
+        }
+
+        // Nothing is written if some segment isn't mapped.
+        let iov = [(GuestAddress(0), 10), (GuestAddress(2500), 10)];
+        assert!(space
+            .write_vectored(&mut [0xff_u8; 20].as_ref(), &iov)
+            .is_err());
+        assert_eq!(space.read_object::<u8>(GuestAddress(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_get_address_map() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1000, -1, 0, false, false).unwrap());
+        let ram2 =
+            Arc::new(HostMemMapping::new(GuestAddress(1000), 1000, -1, 0, false, false).unwrap());
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout
+        //        0      1000   2000   3000   4000
+        //        |------|------|------|------|
+        //  ram:  [111111][222222]
+        //  io:                        [IIIIII]
+        root.add_subregion(Region::init_ram_region(ram1.clone()), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2.clone()), 1000)
+            .unwrap();
+        root.add_subregion(Region::init_io_region(1000, default_ops), 3000)
+            .unwrap();
+
         assert_eq!(
-            space.get_host_address(GuestAddress(2500)),
-            Some(ram2.host_address() + 500)
+            space.get_host_address(GuestAddress(900), 100).unwrap(),
+            ram1.host_address() + 900
         );
+        match space.get_host_address(GuestAddress(2000), 8) {
+            Err(Error(ErrorKind::AddrInvalid(addr), _)) => assert_eq!(addr, 2000),
+            _ => panic!("expect AddrInvalid error"),
+        }
+        match space.get_host_address(GuestAddress(900), 200) {
+            Err(Error(ErrorKind::CrossRegions(addr, size), _)) => {
+                assert_eq!((addr, size), (900, 200))
+            }
+            _ => panic!("expect CrossRegions error"),
+        }
+        match space.get_host_address(GuestAddress(3000), 8) {
+            Err(Error(ErrorKind::RegionType(t), _)) => assert_eq!(t, RegionType::IO),
+            _ => panic!("expect RegionType error"),
+        }
+
+        assert_eq!(
+            space.get_address_map(GuestAddress(900), 200).unwrap(),
+            vec![(ram1.host_address() + 900, 100), (ram2.host_address(), 100)]
+        );
+        assert!(space.get_address_map(GuestAddress(1900), 200).is_err());
+        assert!(space.get_address_map(GuestAddress(2900), 200).is_err());
+    }
+
+    #[test]
+    fn test_dump_memory() {
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000, -1, 0, false, false).unwrap());
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(0x2000), 0x1000, -1, 0, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2), 0x2000)
+            .unwrap();
+        space
+            .write(&mut [0x11_u8; 0x1000].as_ref(), GuestAddress(0), 0x1000)
+            .unwrap();
+        space
+            .write(
+                &mut [0x22_u8; 0x1000].as_ref(),
+                GuestAddress(0x2000),
+                0x1000,
+            )
+            .unwrap();
+
+        let file_path = String::from("dump_memory_test");
+        let mut file = std::fs::File::create(&file_path).unwrap();
+        space.dump_memory(&mut file, None).unwrap();
+        drop(file);
+
+        let dump = std::fs::read(&file_path).unwrap();
+        assert_eq!(dump.len(), 2 * (16 + 0x1000));
+        for (idx, (base, byte)) in [(0_u64, 0x11_u8), (0x2000, 0x22)].iter().enumerate() {
+            let hdr = &dump[idx * (16 + 0x1000)..];
+            let mut word = [0_u8; 8];
+            word.copy_from_slice(&hdr[..8]);
+            assert_eq!(u64::from_le_bytes(word), *base);
+            word.copy_from_slice(&hdr[8..16]);
+            assert_eq!(u64::from_le_bytes(word), 0x1000);
+            assert!(hdr[16..16 + 0x1000].iter().all(|b| b == byte));
+        }
+        std::fs::remove_file(&file_path).unwrap();
+
+        let mut buf = Vec::new();
+        let subset = [AddressRange::from((0x2800, 0x10))];
+        space.dump_memory(&mut buf, Some(&subset)).unwrap();
+        assert_eq!(&buf[..8], &0x2800_u64.to_le_bytes());
+        assert_eq!(&buf[8..16], &0x10_u64.to_le_bytes());
+        assert_eq!(&buf[16..], &[0x22_u8; 0x10]);
+
+        let mut buf = Vec::new();
+        let hole = [AddressRange::from((0xf00, 0x200))];
+        assert!(space.dump_memory(&mut buf, Some(&hole)).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_dump() {
+        let root = Region::init_container_region_named("system", 0x8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 0x2000, -1, 0, false, false).unwrap());
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout
+        //        0      0x1000   0x2000   0x3000   0x4000   0x5000
+        //        |------|--------|--------|--------|--------|
+        //  ram:  [RRRRRRRRRRRRRRR]
+        //  pci:                           [                 ]
+        //  uart:                                   [U]
+        //  cmos:         [C]
+        let pci = Region::init_container_region_named("pci", 0x2000);
+        let uart = Region::init_io_region_named("uart", 0x8, default_ops.clone());
+        let cmos = Region::init_io_region(0x2, default_ops);
+        cmos.set_priority(1).unwrap();
+        root.add_subregion(Region::init_ram_region_named("pc.ram", ram), 0)
+            .unwrap();
+        root.add_subregion(pci.clone(), 0x3000).unwrap();
+        pci.add_subregion(uart, 0x1000).unwrap();
+        root.add_subregion(cmos, 0x1000).unwrap();
+
+        assert_eq!(
+            space.dump_tree(),
+            "0000000000000000-0000000000007fff (prio 0, container): system\n\
+             \x20 0000000000001000-0000000000001001 (prio 1, i/o): anon\n\
+             \x20 0000000000003000-0000000000004fff (prio 0, container): pci\n\
+             \x20   0000000000004000-0000000000004007 (prio 0, i/o): uart\n\
+             \x20 0000000000000000-0000000000001fff (prio 0, ram): pc.ram\n"
+        );
+        assert_eq!(
+            space.dump_flatview(),
+            "0000000000000000-0000000000000fff (prio 0, ram): pc.ram\n\
+             0000000000001000-0000000000001001 (prio 1, i/o): anon\n\
+             0000000000001002-0000000000001fff (prio 0, ram): pc.ram @0000000000001002\n\
+             0000000000004000-0000000000004007 (prio 0, i/o): uart\n"
+        );
+    }
+
+    #[test]
+    fn test_add_remove_ram_region() {
+        struct FakePauseGuard;
+        impl VcpusPaused for FakePauseGuard {}
+
+        let root = Region::init_container_region(0x10000);
+        let space = AddressSpace::new(root).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+        let mem_config = MachineMemConfig::default();
+
+        space
+            .add_ram_region("dimm0", GuestAddress(0x4000), 0x1000, &mem_config)
+            .unwrap();
+        assert!(space
+            .add_ram_region("dimm0", GuestAddress(0x8000), 0x1000, &mem_config)
+            .is_err());
+        match listener.reqs.lock().unwrap().as_slice() {
+            [(ListenerReqType::AddRegion, range)] => {
+                assert_eq!(*range, AddressRange::new(GuestAddress(0x4000), 0x1000))
+            }
+            _ => panic!("expect one AddRegion request"),
+        }
+        assert!(space.address_in_memory(GuestAddress(0x4000), 0x1000));
+
+        let data = 0x1234_5678_u64;
+        space.write_object(&data, GuestAddress(0x4ff8)).unwrap();
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(0x4ff8)).unwrap(),
+            data
+        );
+
+        assert!(space.remove_ram_region("dimm1", &FakePauseGuard).is_err());
+        space.remove_ram_region("dimm0", &FakePauseGuard).unwrap();
+        assert!(space.read_object::<u64>(GuestAddress(0x4ff8)).is_err());
+        assert!(space.write_object(&data, GuestAddress(0x4ff8)).is_err());
+        assert!(space.dump_flatview().is_empty());
     }
 
     #[test]
@@ -735,4 +2457,512 @@ mod test {
         assert_eq!(data1, 10000);
         assert!(space.write_object(&data, GuestAddress(993)).is_err());
     }
+
+    #[test]
+    fn test_access_across_ranges() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1000, -1, 0, false, false).unwrap());
+        let ram2 =
+            Arc::new(HostMemMapping::new(GuestAddress(1000), 1000, -1, 0, false, false).unwrap());
+        let ram3 =
+            Arc::new(HostMemMapping::new(GuestAddress(3000), 1000, -1, 0, false, false).unwrap());
+        for ram in &[&ram1, &ram2, &ram3] {
+            root.add_subregion(
+                Region::init_ram_region((*ram).clone()),
+                ram.start_address().raw_value(),
+            )
+            .unwrap();
+        }
+        let io_data = Arc::new(Mutex::new(Vec::new()));
+        let io_data_clone = io_data.clone();
+        let io_ops = RegionOps {
+            read: Arc::new(|data: &mut [u8], _: GuestAddress, _: u64| -> bool {
+                data.iter_mut().for_each(|b| *b = 0xa5);
+                true
+            }),
+            write: Arc::new(
+                move |data: &[u8], base: GuestAddress, offset: u64| -> bool {
+                    io_data_clone
+                        .lock()
+                        .unwrap()
+                        .push((base.raw_value(), offset, data.to_vec()));
+                    true
+                },
+            ),
+        };
+        root.add_subregion(Region::init_io_region(1000, io_ops), 4000)
+            .unwrap();
+
+        // RAM-RAM.
+        let data: Vec<u8> = (0..16).collect();
+        space
+            .write(&mut data.as_slice(), GuestAddress(992), 16)
+            .unwrap();
+        let mut res = Vec::new();
+        space.read(&mut res, GuestAddress(992), 16).unwrap();
+        assert_eq!(res, data);
+        let mut res = Vec::new();
+        space.read(&mut res, GuestAddress(1000), 8).unwrap();
+        assert_eq!(res, data[8..].to_vec());
+
+        // RAM-gap-RAM fails at the gap, and nothing is written.
+        let zeros = vec![0_u8; 1200];
+        match space.write(&mut zeros.as_slice(), GuestAddress(1900), 1200) {
+            Err(Error(ErrorKind::AddrInvalid(addr), _)) => assert_eq!(addr, 2000),
+            _ => panic!("Access across the gap should fail"),
+        }
+        let mut res = Vec::new();
+        space.read(&mut res, GuestAddress(992), 16).unwrap();
+        assert_eq!(res, data);
+        let mut res = Vec::new();
+        assert!(space.read(&mut res, GuestAddress(1900), 1200).is_err());
+        assert!(res.is_empty());
+
+        // RAM-IO, IO part is passed to its ops.
+        space
+            .write(&mut data.as_slice(), GuestAddress(3996), 16)
+            .unwrap();
+        assert_eq!(
+            io_data.lock().unwrap().clone(),
+            vec![(4000, 0, data[4..].to_vec())]
+        );
+        let mut res = Vec::new();
+        space.read(&mut res, GuestAddress(3996), 8).unwrap();
+        assert_eq!(res, vec![0, 1, 2, 3, 0xa5, 0xa5, 0xa5, 0xa5]);
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(3996)).unwrap(),
+            u64::from_le_bytes([0, 1, 2, 3, 0xa5, 0xa5, 0xa5, 0xa5])
+        );
+    }
+
+    #[test]
+    fn test_concurrent_update_topology() {
+        let root = Region::init_container_region(8192);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 4096, -1, 0, false, false).unwrap());
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        space
+            .write_object(&0x5555_5555_5555_5555_u64, GuestAddress(4088))
+            .unwrap();
+        let io_ops = RegionOps {
+            read: Arc::new(|data: &mut [u8], _: GuestAddress, _: u64| -> bool {
+                data.iter_mut().for_each(|byte| *byte = 0xaa);
+                true
+            }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let io_region = Region::init_io_region(1000, io_ops);
+
+        let updater = std::thread::spawn(move || {
+            for _ in 0..200 {
+                root.add_subregion(io_region.clone(), 4096).unwrap();
+                root.delete_subregion(&io_region).unwrap();
+            }
+        });
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let space = space.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..2000 {
+                        assert_eq!(
+                            space.read_object::<u64>(GuestAddress(4088)).unwrap(),
+                            0x5555_5555_5555_5555
+                        );
+                        // Access across Ram and IO region sees a whole view.
+                        let mut data = Vec::new();
+                        if space.read(&mut data, GuestAddress(4088), 16).is_ok() {
+                            assert_eq!(data[..8], [0x55_u8; 8]);
+                            assert_eq!(data[8..], [0xaa_u8; 8]);
+                        } else {
+                            assert!(data.is_empty());
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        updater.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(space.flat_view().0.len(), 1);
+    }
+
+    #[test]
+    fn test_dirty_log() {
+        let page = page_size();
+        let root = Region::init_container_region(64 * page);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 16 * page, -1, 0, false, false).unwrap());
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(32 * page), 16 * page, -1, 0, false, false).unwrap(),
+        );
+        let region_a = Region::init_ram_region(ram1.clone());
+        let region_b = Region::init_ram_region(ram2.clone());
+        root.add_subregion(region_a.clone(), ram1.start_address().raw_value())
+            .unwrap();
+        root.add_subregion(region_b.clone(), ram2.start_address().raw_value())
+            .unwrap();
+        assert!(Region::init_container_region(page)
+            .set_log_dirty(true)
+            .is_err());
+
+        // Only region_b logs dirty pages.
+        region_b.set_log_dirty(true).unwrap();
+        assert!(region_b.is_log_dirty());
+        let data: u64 = 0x1234;
+        space.write_object(&data, GuestAddress(page)).unwrap();
+        space.write_object(&data, GuestAddress(33 * page)).unwrap();
+        space
+            .write_object(&data, GuestAddress(35 * page + 8))
+            .unwrap();
+        space
+            .write_object(&data, GuestAddress(35 * page + 16))
+            .unwrap();
+        // Object crosses page boundary.
+        space
+            .write_object(&data, GuestAddress(41 * page - 4))
+            .unwrap();
+
+        let bitmap = space.get_dirty_log(GuestAddress(0), 64 * page).unwrap();
+        assert_eq!(dirty_page_count(&bitmap), 4);
+        assert_eq!(
+            dirty_pfns(&bitmap, GuestAddress(0)).collect::<Vec<u64>>(),
+            vec![33, 35, 40, 41]
+        );
+        // Dirty log is reset after fetched.
+        let bitmap = space.get_dirty_log(GuestAddress(0), 64 * page).unwrap();
+        assert_eq!(dirty_page_count(&bitmap), 0);
+
+        // Range in the middle of region_b.
+        space.write_object(&data, GuestAddress(33 * page)).unwrap();
+        space.write_object(&data, GuestAddress(47 * page)).unwrap();
+        let bitmap = space
+            .get_dirty_log(GuestAddress(40 * page), 8 * page)
+            .unwrap();
+        assert_eq!(
+            dirty_pfns(&bitmap, GuestAddress(40 * page)).collect::<Vec<u64>>(),
+            vec![47]
+        );
+        let bitmap = space.get_dirty_log(GuestAddress(32 * page), page).unwrap();
+        assert_eq!(dirty_pfns(&bitmap, GuestAddress(32 * page)).count(), 1);
+        assert!(space.get_dirty_log(GuestAddress(1), page).is_err());
+
+        // Disabling logging re-renders region_b.
+        region_b.set_log_dirty(false).unwrap();
+        space.write_object(&data, GuestAddress(33 * page)).unwrap();
+        let bitmap = space.get_dirty_log(GuestAddress(0), 64 * page).unwrap();
+        assert_eq!(dirty_page_count(&bitmap), 0);
+        assert!(space.flat_view().0.iter().all(|fr| !fr.log_dirty));
+    }
+
+    #[test]
+    fn test_write_read_only_region() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1000, -1, 0, false, false).unwrap());
+        let ram2 =
+            Arc::new(HostMemMapping::new(GuestAddress(2000), 1000, -1, 0, false, false).unwrap());
+        let region_a = Region::init_ram_region(ram1.clone());
+        let region_b = Region::init_ram_region(ram2.clone());
+        region_b.set_rom(true).unwrap();
+        root.add_subregion(region_a, ram1.start_address().raw_value())
+            .unwrap();
+        root.add_subregion(region_b, ram2.start_address().raw_value())
+            .unwrap();
+
+        let data: u64 = 10000;
+        assert!(space.write_object(&data, GuestAddress(992)).is_ok());
+        match space.write_object(&data, GuestAddress(2008)) {
+            Err(Error(ErrorKind::ReadOnly(_, addr), _)) => assert_eq!(addr, 2008),
+            _ => panic!("Writing to read-only region should fail"),
+        }
+        assert_eq!(space.read_object::<u64>(GuestAddress(2008)).unwrap(), 0);
+        assert_eq!(
+            space.get_host_address(GuestAddress(2008), 8).unwrap(),
+            ram2.host_address() + 8
+        );
+
+        let view = space.flat_view();
+        assert!(!view.find_range(GuestAddress(992)).unwrap().read_only());
+        assert!(view.find_range(GuestAddress(2008)).unwrap().read_only());
+    }
+
+    #[test]
+    fn test_space_statistics() {
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // memory region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  A:    [                                                       ]
+        //  C:    [CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC]
+        //  B:                  [                          ]
+        //  D:                  [DDDDD]
+        //  E:                                [EEEEE]
+        //
+        // the flat_view is as follows
+        //        [CCCCCCCCCCCC][DDDDD][CCCCC][EEEEE][CCCCC]
+        {
+            let region_a = Region::init_container_region(8000);
+            let space = AddressSpace::new(region_a.clone()).unwrap();
+            let region_b = Region::init_container_region(4000);
+            let region_c = Region::init_io_region(6000, default_ops.clone());
+            let region_d = Region::init_io_region(1000, default_ops.clone());
+            let region_e = Region::init_io_region(1000, default_ops.clone());
+
+            region_b.set_priority(2).unwrap();
+            region_c.set_priority(1).unwrap();
+            region_a.add_subregion(region_b.clone(), 2000).unwrap();
+            region_a.add_subregion(region_c, 0).unwrap();
+            region_b.add_subregion(region_d, 0).unwrap();
+            region_b.add_subregion(region_e, 2000).unwrap();
+
+            assert_eq!(space.ram_size(), 0);
+            assert_eq!(space.region_count(), 3);
+            assert_eq!(space.flat_range_count(), 5);
+            assert_eq!(
+                space.largest_gap(),
+                Some(AddressRange::from((6000u64, 2000u64)))
+            );
+
+            region_b.set_enabled(false).unwrap();
+            assert_eq!(space.region_count(), 1);
+            assert_eq!(space.flat_range_count(), 1);
+        }
+
+        // memory region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  A:    [                                                       ]
+        //  C:    [CCCCCC]                                                    1
+        //  B:                  [                                  ]          1
+        //  D:                  [DDDDDDDDDDDDDDDDDDDD]                        2
+        //  E:                                [EEEEEEEEEEEEE]                 3
+        //
+        // the flat_view is as follows, C is Ram here
+        //        [CCCCCC]      [DDDDDDDDDDDD][EEEEEEEEEEEEE]
+        {
+            let region_a = Region::init_container_region(8000);
+            let space = AddressSpace::new(region_a.clone()).unwrap();
+            let region_b = Region::init_container_region(5000);
+            let ram =
+                Arc::new(HostMemMapping::new(GuestAddress(0), 1000, -1, 0, false, false).unwrap());
+            let region_c = Region::init_ram_region(ram);
+            let region_d = Region::init_io_region(3000, default_ops.clone());
+            let region_e = Region::init_io_region(2000, default_ops.clone());
+
+            region_a.add_subregion(region_b.clone(), 2000).unwrap();
+            region_a.add_subregion(region_c, 0).unwrap();
+            region_d.set_priority(2).unwrap();
+            region_e.set_priority(3).unwrap();
+            region_b.add_subregion(region_d, 0).unwrap();
+            region_b.add_subregion(region_e, 2000).unwrap();
+
+            assert_eq!(space.ram_size(), 1000);
+            assert_eq!(space.region_count(), 3);
+            assert_eq!(space.flat_range_count(), 3);
+            assert_eq!(
+                space.largest_gap(),
+                Some(AddressRange::from((6000u64, 2000u64)))
+            );
+
+            let full = Region::init_io_region(8000, default_ops);
+            full.set_priority(-1).unwrap();
+            region_a.add_subregion(full, 0).unwrap();
+            assert_eq!(space.region_count(), 4);
+            assert_eq!(space.largest_gap(), None);
+        }
+    }
+
+    #[test]
+    fn test_listener_reject_view() {
+        struct LimitListener;
+        impl Listener for LimitListener {
+            fn priority(&self) -> i32 {
+                0
+            }
+
+            fn check_ranges(&self, ranges: &[FlatRange]) -> Result<()> {
+                if ranges.len() > 1 {
+                    bail!("Too many ranges");
+                }
+                Ok(())
+            }
+        }
+
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        space.register_listener(Box::new(LimitListener)).unwrap();
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let io1 = Region::init_io_region(0x1000, ops.clone());
+        let io2 = Region::init_io_region(0x1000, ops);
+        root.add_subregion(io1.clone(), 0).unwrap();
+
+        assert!(root.add_subregion(io2.clone(), 0x2000).is_err());
+        assert_eq!(root.subregions().len(), 1);
+        assert_eq!(space.flat_range_count(), 1);
+
+        io1.set_enabled(false).unwrap();
+        root.add_subregion(io2, 0x2000).unwrap();
+        assert!(io1.set_enabled(true).is_err());
+        assert!(!io1.is_enabled());
+        assert_eq!(
+            space.flat_view().0[0].addr_range,
+            AddressRange::from((0x2000u64, 0x1000u64))
+        );
+    }
+
+    #[test]
+    fn test_save_load_ram() {
+        let new_space = |ram2_size: u64| {
+            let root = Region::init_container_region(0x2000000);
+            let space = AddressSpace::new(root.clone()).unwrap();
+            let ram1 = Arc::new(
+                HostMemMapping::new(GuestAddress(0), 0x800000, -1, 0, false, false).unwrap(),
+            );
+            let ram2 = Arc::new(
+                HostMemMapping::new(GuestAddress(0x1000000), ram2_size, -1, 0, false, false)
+                    .unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram1), 0)
+                .unwrap();
+            let region2 = Region::init_ram_region(ram2);
+            root.add_subregion(region2.clone(), 0x1000000).unwrap();
+            (space, region2)
+        };
+        let (space, region2) = new_space(0x2800);
+
+        // Every third page of the 8 MiB range is left zero.
+        let mut ram1_data = vec![0_u8; 0x800000];
+        for (page, chunk) in ram1_data.chunks_mut(0x1000).enumerate() {
+            if page % 3 != 0 {
+                for (idx, b) in chunk.iter_mut().enumerate() {
+                    *b = ((page * 7 + idx) % 251 + 1) as u8;
+                }
+            }
+        }
+        let ram2_data: Vec<u8> = (0..0x2800_u32).map(|i| (i % 13 + 1) as u8).collect();
+        space
+            .write(&mut ram1_data.as_slice(), GuestAddress(0), 0x800000)
+            .unwrap();
+        space
+            .write(&mut ram2_data.as_slice(), GuestAddress(0x1000000), 0x2800)
+            .unwrap();
+
+        let file_path = std::path::Path::new("save_load_ram_test");
+        space.save_ram(file_path).unwrap();
+        // Zero pages of the first range are elided, the second is raw.
+        let header = 16 + 2 * 16;
+        let ram1_record = 24 + 0x800 / 64 * 8 + (0x800 - 683) * 0x1000;
+        let ram2_record = 24 + 0x2800;
+        assert_eq!(
+            std::fs::metadata(file_path).unwrap().len(),
+            (header + ram1_record + ram2_record) as u64
+        );
+
+        let fill = |space: &AddressSpace| {
+            space
+                .write(
+                    &mut vec![0xff_u8; 0x800000].as_slice(),
+                    GuestAddress(0),
+                    0x800000,
+                )
+                .unwrap();
+        };
+        fill(&space);
+        region2.set_rom(true).unwrap();
+        space.load_ram(file_path).unwrap();
+        let mut ram1_read = Vec::new();
+        space
+            .read(&mut ram1_read, GuestAddress(0), 0x800000)
+            .unwrap();
+        assert!(ram1_read == ram1_data);
+        let mut ram2_read = Vec::new();
+        space
+            .read(&mut ram2_read, GuestAddress(0x1000000), 0x2800)
+            .unwrap();
+        assert_eq!(ram2_read, ram2_data);
+
+        // Layout mismatch is rejected before touching guest memory.
+        let (other, _) = new_space(0x3000);
+        fill(&other);
+        assert!(other.load_ram(file_path).is_err());
+        let mut other_read = Vec::new();
+        other
+            .read(&mut other_read, GuestAddress(0), 0x1000)
+            .unwrap();
+        assert_eq!(other_read, vec![0xff_u8; 0x1000]);
+
+        std::fs::write(file_path, b"NOTASNAPSHOT").unwrap();
+        assert!(space.load_ram(file_path).is_err());
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_drain_coalesced() {
+        struct TestRing(std::collections::VecDeque<(GuestAddress, Vec<u8>)>);
+        impl CoalescedRing for TestRing {
+            fn pop(&mut self) -> Option<(GuestAddress, Vec<u8>)> {
+                self.0.pop_front()
+            }
+        }
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let writes_clone = writes.clone();
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(move |data: &[u8], _: GuestAddress, offset: u64| -> bool {
+                writes_clone.lock().unwrap().push((offset, data.to_vec()));
+                true
+            }),
+        };
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let io = Region::init_io_region(0x1000, ops);
+        io.set_coalesced_io(vec![AddressRange::from((0, 0x1000))])
+            .unwrap();
+        root.add_subregion(io, 0x1000).unwrap();
+
+        let mut ring = TestRing(
+            vec![
+                (GuestAddress(0x1010), vec![1_u8, 2, 3, 4]),
+                (GuestAddress(0x1000), vec![5_u8]),
+                (GuestAddress(0x1010), vec![6_u8, 7]),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(space.drain_coalesced(&mut ring).unwrap(), 3);
+        assert!(ring.0.is_empty());
+        assert_eq!(
+            *writes.lock().unwrap(),
+            vec![
+                (0x10, vec![1_u8, 2, 3, 4]),
+                (0, vec![5_u8]),
+                (0x10, vec![6_u8, 7]),
+            ]
+        );
+
+        // Drain stops at an unmapped write, later ones stay in the ring.
+        writes.lock().unwrap().clear();
+        ring.0.push_back((GuestAddress(0x3000), vec![8_u8]));
+        ring.0.push_back((GuestAddress(0x1020), vec![9_u8]));
+        assert!(space.drain_coalesced(&mut ring).is_err());
+        assert!(writes.lock().unwrap().is_empty());
+        assert_eq!(ring.0.len(), 1);
+        assert_eq!(space.drain_coalesced(&mut ring).unwrap(), 1);
+        assert_eq!(*writes.lock().unwrap(), vec![(0x20, vec![9_u8])]);
+    }
 }
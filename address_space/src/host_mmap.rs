@@ -10,14 +10,226 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::ffi::CString;
 use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use machine_manager::config::MachineMemConfig;
+use machine_manager::config::{MachineMemConfig, ThpPolicy};
 
 use crate::errors::{ErrorKind, Result, ResultExt};
-use crate::{AddressRange, GuestAddress};
+use crate::{page_size, GuestAddress};
+
+/// Magic number of hugetlbfs in `statfs.f_type`.
+const HUGETLBFS_MAGIC: u32 = 0x9584_58f6;
+/// Shift of log2 of huge page size in mmap flags.
+const MAP_HUGE_SHIFT: u32 = 26;
+
+/// Return huge page size of file system described by `fs`,
+/// or None if it's not hugetlbfs.
+fn hugepage_size_of(fs: &libc::statfs) -> Option<u64> {
+    if fs.f_type as u32 == HUGETLBFS_MAGIC {
+        Some(fs.f_bsize as u64)
+    } else {
+        None
+    }
+}
+
+/// Return huge page size of file system where `file_path` is, or None if
+/// it's not hugetlbfs. If `file_path` doesn't exist, its parent is checked.
+///
+/// # Errors
+///
+/// Return Error if fail to statfs.
+fn hugepage_size(file_path: &str) -> Result<Option<u64>> {
+    let mut path = Path::new(file_path);
+    if !path.exists() {
+        path = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    let path_cstr = CString::new(path.as_os_str().as_bytes())
+        .chain_err(|| format!("Invalid path {}", path.display()))?;
+
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path_cstr.as_ptr(), &mut fs) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .chain_err(|| format!("Failed to statfs {}", path.display()));
+    }
+    Ok(hugepage_size_of(&fs))
+}
+
+/// Return huge page size of file system where `fd` is, or None if it's not hugetlbfs.
+///
+/// # Errors
+///
+/// Return Error if fail to fstatfs.
+fn fd_hugepage_size(fd: RawFd) -> Result<Option<u64>> {
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(fd, &mut fs) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .chain_err(|| format!("Failed to fstatfs fd {}", fd));
+    }
+    Ok(hugepage_size_of(&fs))
+}
+
+/// Check huge page requirement of memory backend `name` of `mem_size` bytes,
+/// which is on file system of huge page size `hugepage_size` if any.
+fn check_hugepage(
+    name: &str,
+    hugepage_size: Option<u64>,
+    mem_size: u64,
+    required: bool,
+) -> Result<()> {
+    match hugepage_size {
+        Some(page_size) if mem_size % page_size != 0 => {
+            Err(ErrorKind::HugePageUnaligned(mem_size, page_size).into())
+        }
+        None if required => bail!("Memory backend {} is not on hugetlbfs", name),
+        _ => Ok(()),
+    }
+}
+
+/// Mappings of at least this size are preallocated by multiple threads.
+const PREALLOC_MULTI_THREAD_SIZE: u64 = 1 << 30;
+/// Maximum number of threads to preallocate a mapping.
+const PREALLOC_MAX_THREADS: u64 = 16;
+
+/// Fault in memory of `size` bytes at `host_addr` by touching one byte per
+/// page, the pages are split between `threads` threads.
+///
+/// # Arguments
+///
+/// * `host_addr` - Start host address of memory, aligned to `page_size`.
+/// * `size` - Size of memory, multiple of `page_size`.
+/// * `page_size` - Page size of memory.
+/// * `threads` - Number of threads.
+fn touch_pages(host_addr: u64, size: u64, page_size: u64, threads: u64) {
+    let pages = size / page_size;
+    let pages_per_thread = (pages + threads - 1) / threads;
+    let workers: Vec<_> = (0..threads)
+        .map(|idx| {
+            let start = host_addr + idx * pages_per_thread * page_size;
+            let count = std::cmp::min(
+                pages_per_thread,
+                pages.saturating_sub(idx * pages_per_thread),
+            );
+            std::thread::spawn(move || {
+                for page in 0..count {
+                    // Safe as the page is in mapped memory. Write back the same
+                    // byte, so that contents are kept.
+                    unsafe {
+                        let addr = (start + page * page_size) as *mut u8;
+                        std::ptr::write_volatile(addr, std::ptr::read_volatile(addr));
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        if worker.join().is_err() {
+            error!("Failed to join thread of memory preallocation");
+        }
+    }
+}
+
+/// Return RLIMIT_MEMLOCK of this process in bytes, `u64::max_value()` if unlimited.
+fn memlock_limit() -> Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } < 0 {
+        return Err(std::io::Error::last_os_error()).chain_err(|| "Failed to get RLIMIT_MEMLOCK");
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        Ok(u64::max_value())
+    } else {
+        Ok(limit.rlim_cur as u64)
+    }
+}
+
+/// Check if `mem_size` bytes of memory can be locked under RLIMIT_MEMLOCK `limit`.
+///
+/// # Errors
+///
+/// Return Error telling the limit to raise if `mem_size` exceeds `limit`.
+fn check_memlock_limit(mem_size: u64, limit: u64) -> Result<()> {
+    if mem_size > limit {
+        return Err(ErrorKind::MemLockLimit(mem_size, limit).into());
+    }
+    Ok(())
+}
+
+/// Advise transparent huge page `policy` for memory of `size` bytes at
+/// `host_addr` by `madvise`, failure is logged only.
+///
+/// # Arguments
+///
+/// * `host_addr` - Start host address of memory.
+/// * `size` - Size of memory.
+/// * `policy` - Transparent huge page policy.
+/// * `madvise` - Syscall of madvise, return negative value on failure.
+fn advise_thp<F>(host_addr: u64, size: u64, policy: ThpPolicy, madvise: F)
+where
+    F: FnOnce(u64, u64, libc::c_int) -> libc::c_int,
+{
+    let advice = match policy {
+        ThpPolicy::Default => return,
+        ThpPolicy::Always => libc::MADV_HUGEPAGE,
+        ThpPolicy::Never => libc::MADV_NOHUGEPAGE,
+    };
+    if madvise(host_addr, size, advice) < 0 {
+        warn!(
+            "madvise {:?} of transparent huge page failed: {}",
+            policy,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Create anonymous shared memory of `len` bytes by memfd, which is sealed
+/// against resizing if `seal` is set, so that peers sharing it can't resize it.
+///
+/// # Errors
+///
+/// Return Error if fail to create, set length of or seal memfd.
+pub(crate) fn create_memfd(len: u64, seal: bool) -> Result<File> {
+    let name = CString::new("stratovirt_anon_mem").unwrap();
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            name.as_ptr(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).chain_err(|| "Failed to create memfd");
+    }
+    let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+    file.set_len(len)
+        .chain_err(|| format!("Failed to set length of memfd to {}", len))?;
+    if seal {
+        let seals = libc::F_SEAL_GROW | libc::F_SEAL_SHRINK;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(std::io::Error::last_os_error()).chain_err(|| "Failed to seal memfd");
+        }
+    }
+    Ok(file)
+}
+
+/// Round `size` up to multiple of `page_size` if any.
+fn round_up(size: u64, page_size: Option<u64>) -> u64 {
+    match page_size {
+        Some(page_size) => (size + page_size - 1) / page_size * page_size,
+        None => size,
+    }
+}
 
 /// FileBackend represents backend-file of `HostMemMapping`.
 pub struct FileBackend {
@@ -25,11 +237,14 @@ pub struct FileBackend {
     pub file: File,
     /// Offset from where the file begins.
     pub offset: u64,
+    /// Huge page size if the file is on hugetlbfs.
+    pub hugepage_size: Option<u64>,
 }
 
 impl FileBackend {
     /// Construct a new FileBackend according to path and length.
     /// If the file is already created, this function does not change its length.
+    /// If the file is on hugetlbfs, the length is rounded up to huge page size.
     ///
     /// # Arguments
     ///
@@ -42,7 +257,10 @@ impl FileBackend {
     /// * fail to create the file.
     /// * fail to open the file.
     /// * fail to set file length.
+    /// * fail to detect file system of the file.
     pub fn new(file_path: &str, file_len: u64) -> Result<FileBackend> {
+        let hugepage_size = hugepage_size(file_path)?;
+        let file_len = round_up(file_len, hugepage_size);
         let path = std::path::Path::new(&file_path);
         let file = if path.is_dir() {
             let fs_path = format!("{}{}", file_path, "/stratovirt_backmem_XXXXXX");
@@ -74,8 +292,68 @@ impl FileBackend {
         Ok(FileBackend {
             file,
             offset: 0_u64,
+            hugepage_size,
         })
     }
+
+    /// Construct a FileBackend from an opened `fd`, e.g. passed by management
+    /// software. The fd is duplicated, so the caller keeps its ownership.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - Fd of a regular file or memfd.
+    /// * `offset` - Offset from where memory begins in the file.
+    /// * `len` - Length of memory.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * `fd` is not a regular file or memfd.
+    /// * the file is shorter than `offset` + `len`.
+    /// * fail to stat or duplicate `fd`.
+    pub fn from_fd(fd: RawFd, offset: u64, len: u64) -> Result<FileBackend> {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .chain_err(|| format!("Failed to stat fd {}", fd));
+        }
+        if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+            bail!("Memory backend fd {} is not a regular file or memfd", fd);
+        }
+        let required = match offset.checked_add(len) {
+            Some(end) => end,
+            None => return Err(ErrorKind::Overflow(offset).into()),
+        };
+        if (stat.st_size as u64) < required {
+            return Err(ErrorKind::BackendTooSmall(stat.st_size as u64, required).into());
+        }
+        let hugepage_size = fd_hugepage_size(fd)?;
+
+        let dup_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup_fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .chain_err(|| format!("Failed to duplicate fd {}", fd));
+        }
+        Ok(FileBackend {
+            file: unsafe { File::from_raw_fd(dup_fd) },
+            offset,
+            hugepage_size,
+        })
+    }
+
+    /// Alignment of file offsets to map, which is huge page size on
+    /// hugetlbfs, or page size otherwise.
+    pub fn alignment(&self) -> u64 {
+        self.hugepage_size.unwrap_or_else(page_size)
+    }
+}
+
+/// Length of backing file for `ranges`, where each range begins at an offset
+/// aligned to `align`.
+fn aligned_file_len(ranges: &[(u64, u64)], align: u64) -> u64 {
+    ranges
+        .iter()
+        .fold(0, |acc, x| acc + round_up(x.1, Some(align)))
 }
 
 /// Create HostMemMappings according to address ranges.
@@ -84,58 +362,138 @@ impl FileBackend {
 ///
 /// * `ranges` - The guest address range that will be mapped.
 /// * `mem_config` - Machine memory config.
+///
+/// # Errors
+///
+/// If `mem_prealloc` is set, all memory is faulted in before return.
+/// If `mem_lock` is set, all memory is locked in host RAM.
+/// Each range is bound to host NUMA node in `host_nodes` if any.
+/// `thp_policy` is applied unless memory is on hugetlbfs.
+///
+/// Memory is backed by `mem_fd` if any, else by `mem_path` if any.
+///
+/// Return Error if
+/// * `hugepage` is required, but backend is not on hugetlbfs.
+/// * backend is on hugetlbfs, but size of memory isn't multiple of huge page size.
+/// * backing file is smaller than memory, whose ranges begin at offsets
+///   aligned to huge page size or page size. Length of an existing file is kept.
+/// * `mem_lock` is set, but memory exceeds RLIMIT_MEMLOCK.
+/// * Fail to create backend file, map, bind or lock memory.
 pub fn create_host_mmaps(
     ranges: &[(u64, u64)],
     mem_config: &MachineMemConfig,
 ) -> Result<Vec<Arc<HostMemMapping>>> {
     let mut f_back: Option<FileBackend> = None;
 
-    if let Some(path) = &mem_config.mem_path {
-        let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
-        f_back = Some(FileBackend::new(&path, file_len)?);
-    } else if mem_config.mem_share {
-        let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
-
-        let anon_mem_name = String::from("stratovirt_anon_mem");
-        let anon_fd =
-            unsafe { libc::syscall(libc::SYS_memfd_create, anon_mem_name.as_ptr(), 0) } as RawFd;
-        let anon_file = unsafe { File::from_raw_fd(anon_fd) };
+    // Root is likely to have CAP_IPC_LOCK, which isn't limited.
+    if mem_config.mem_lock && unsafe { libc::geteuid() } != 0 {
+        let mem_size = ranges.iter().fold(0, |acc, x| acc + x.1);
+        check_memlock_limit(mem_size, memlock_limit()?)?;
+    }
 
-        anon_file.set_len(file_len)?;
+    if let Some(fd) = mem_config.mem_fd {
+        let mem_size = ranges.iter().fold(0, |acc, x| acc + x.1);
+        let align = fd_hugepage_size(fd)?.unwrap_or_else(page_size);
+        let fb = FileBackend::from_fd(fd, 0, aligned_file_len(ranges, align))?;
+        check_hugepage(
+            &format!("fd {}", fd),
+            fb.hugepage_size,
+            mem_size,
+            mem_config.hugepage,
+        )?;
+        f_back = Some(fb);
+    } else if let Some(path) = &mem_config.mem_path {
+        let hugepage_size = hugepage_size(&path)?;
+        let mem_size = ranges.iter().fold(0, |acc, x| acc + x.1);
+        check_hugepage(path, hugepage_size, mem_size, mem_config.hugepage)?;
+        let file_len = aligned_file_len(ranges, hugepage_size.unwrap_or_else(page_size));
+        let fb = FileBackend::new(&path, file_len)?;
+        let have = fb
+            .file
+            .metadata()
+            .chain_err(|| format!("Failed to get length of {}", path))?
+            .len();
+        if have < file_len {
+            return Err(ErrorKind::BackendTooSmall(have, file_len).into());
+        }
+        f_back = Some(fb);
+    } else if mem_config.mem_share {
+        let file_len = aligned_file_len(ranges, page_size());
+        let anon_file = create_memfd(file_len, mem_config.mem_seal)?;
         f_back = Some(FileBackend {
             file: anon_file,
             offset: 0,
+            hugepage_size: None,
         });
     }
 
     let mut mappings = Vec::new();
     for range in ranges.iter() {
-        let (fd, offset) = if let Some(fb) = f_back.as_ref() {
-            (fb.file.as_raw_fd(), fb.offset)
+        let (fd, offset, hugepage_size) = if let Some(fb) = f_back.as_ref() {
+            (fb.file.as_raw_fd(), fb.offset, fb.hugepage_size)
         } else {
-            (-1, 0)
+            (-1, 0, None)
         };
-        mappings.push(Arc::new(HostMemMapping::new(
+        let mut mapping = HostMemMapping::with_hugepage_size(
             GuestAddress(range.0),
             range.1,
             fd,
             offset,
             mem_config.dump_guest_core,
             mem_config.mem_share,
-        )?));
+            hugepage_size,
+        )?;
+        if hugepage_size.is_none() {
+            mapping.set_thp_policy(mem_config.thp_policy);
+        }
+        if let Some(node) = mem_config.host_nodes.get(mappings.len()) {
+            mapping.bind_host_numa_node(*node)?;
+        }
+        if mem_config.mem_lock {
+            mapping.lock()?;
+        }
+        mappings.push(Arc::new(mapping));
 
         if let Some(mut fb) = f_back.as_mut() {
-            fb.offset += range.1
+            fb.offset += round_up(range.1, Some(fb.alignment()));
         }
     }
 
+    if mem_config.mem_prealloc {
+        let mem_page_size = f_back
+            .as_ref()
+            .and_then(|fb| fb.hugepage_size)
+            .unwrap_or_else(page_size);
+        let now = std::time::Instant::now();
+        for mapping in mappings.iter() {
+            mapping.prealloc(mem_page_size);
+        }
+        info!(
+            "Preallocate {} bytes of guest memory in {:?}",
+            ranges.iter().fold(0, |acc, x| acc + x.1),
+            now.elapsed()
+        );
+    }
+
     Ok(mappings)
 }
 
 /// Record information of memory mapping.
 pub struct HostMemMapping {
-    /// Record the range of one memory segment.
-    address_range: AddressRange,
+    /// Guest address of the memory segment.
+    guest_addr: GuestAddress,
+    /// Size of the memory segment, which changes on `remap`.
+    size: AtomicU64,
+    /// Size of mapped host memory, which is size of the segment rounded up to
+    /// huge page size for hugetlbfs backend.
+    mapped_size: AtomicU64,
+    /// Size of host address space reserved for this mapping, which mapped
+    /// memory can grow within.
+    reserved_size: u64,
+    /// Flags of mmap, used to map memory again on growing.
+    map_flags: i32,
+    /// Include guest memory in core file or not.
+    dump_guest_core: bool,
     /// The start address of mapped memory.
     host_addr: *mut u8,
     /// The raw file descriptor that backs this mapping.
@@ -144,11 +502,19 @@ pub struct HostMemMapping {
     /// Offset in file that backs this mapping.
     /// If anonymous mapping, this field is 0.
     file_offset: u64,
+    /// If mapped memory is shared with other mappings of the backing file.
+    is_share: bool,
+    /// Page size of mapped memory, which is huge page size for hugetlbfs.
+    page_size: u64,
+    /// If mapped memory is locked in host RAM.
+    locked: bool,
+    /// Host NUMA node which mapped memory is bound to.
+    host_numa_node: Option<u32>,
 }
 
 // Send and Sync is not auto-implemented for raw pointer type
-// implementing them is safe because field of HostMemMapping won't change once initialized,
-// only access(r/w) is permitted
+// implementing them is safe because host address of HostMemMapping won't change once
+// shared, only access(r/w) and resizing in place are permitted
 unsafe impl Send for HostMemMapping {}
 unsafe impl Sync for HostMemMapping {}
 
@@ -175,7 +541,47 @@ impl HostMemMapping {
         dump_guest_core: bool,
         is_share: bool,
     ) -> Result<HostMemMapping> {
+        HostMemMapping::with_hugepage_size(
+            guest_addr,
+            size,
+            file_back,
+            file_offset,
+            dump_guest_core,
+            is_share,
+            None,
+        )
+    }
+
+    /// Construct a new HostMemMapping backed by huge pages of `hugepage_size`
+    /// if any, the mapped size is rounded up to huge page size.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_addr` - The start address im memory.
+    /// * `size` - Size of memory that will be mapped.
+    /// * `file_back` - The file's raw fd that backs memory,
+    /// * `file_offset` - Offset in the file that backs memory.
+    /// * `dump_guest_core` - Include guest memory in core file or not.
+    /// * `is_share` - This mapping is sharable or not.
+    /// * `hugepage_size` - Huge page size, a power of 2.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to map memory.
+    pub fn with_hugepage_size(
+        guest_addr: GuestAddress,
+        size: u64,
+        file_back: RawFd,
+        file_offset: u64,
+        dump_guest_core: bool,
+        is_share: bool,
+        hugepage_size: Option<u64>,
+    ) -> Result<HostMemMapping> {
+        let mapped_size = round_up(size, hugepage_size);
         let mut flags = libc::MAP_NORESERVE;
+        if let Some(page_size) = hugepage_size {
+            flags |= libc::MAP_HUGETLB | (page_size.trailing_zeros() << MAP_HUGE_SHIFT) as i32;
+        }
         if file_back == -1 {
             flags |= libc::MAP_ANONYMOUS;
         }
@@ -188,7 +594,7 @@ impl HostMemMapping {
         let host_addr = unsafe {
             let hva = libc::mmap(
                 std::ptr::null_mut() as *mut libc::c_void,
-                size as libc::size_t,
+                mapped_size as libc::size_t,
                 libc::PROT_READ | libc::PROT_WRITE,
                 flags,
                 file_back,
@@ -204,7 +610,7 @@ impl HostMemMapping {
             unsafe {
                 let madvise_res = libc::madvise(
                     host_addr as *mut libc::c_void,
-                    size as libc::size_t,
+                    mapped_size as libc::size_t,
                     libc::MADV_DONTDUMP,
                 );
                 if madvise_res < 0 {
@@ -214,24 +620,215 @@ impl HostMemMapping {
         }
 
         Ok(HostMemMapping {
-            address_range: AddressRange {
-                base: guest_addr,
-                size,
-            },
+            guest_addr,
+            size: AtomicU64::new(size),
+            mapped_size: AtomicU64::new(mapped_size),
+            reserved_size: mapped_size,
+            map_flags: flags,
+            dump_guest_core,
             host_addr: host_addr as *mut u8,
             fd: file_back,
             file_offset,
+            is_share,
+            page_size: hugepage_size.unwrap_or_else(page_size),
+            locked: false,
+            host_numa_node: None,
         })
     }
 
+    /// Reserve host address space for mapped memory to grow up to `max_size`
+    /// by `remap`. Memory is mapped again at the start of the reserved range,
+    /// so this must be done right after construction, before mapped memory is
+    /// accessed, locked or bound to host NUMA node.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_size` - Maximum size of mapped memory.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if mapped memory is locked or bound, or fail to reserve
+    /// host address space or map memory.
+    pub fn reserve(&mut self, max_size: u64) -> Result<()> {
+        let reserved_size = round_up(max_size, Some(self.page_size));
+        if reserved_size <= self.reserved_size {
+            return Ok(());
+        }
+        if self.locked || self.host_numa_node.is_some() {
+            bail!("Can't reserve address space for locked or bound memory mapping");
+        }
+        // Reserve one more page to align the start to page size of the mapping.
+        let len = reserved_size + self.page_size;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut() as *mut libc::c_void,
+                len as libc::size_t,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error())
+                .chain_err(|| format!("Failed to reserve {} bytes of address space", len));
+        }
+        let base = base as u64;
+        let start = round_up(base, Some(self.page_size));
+        unsafe {
+            if start > base {
+                libc::munmap(base as *mut libc::c_void, (start - base) as libc::size_t);
+            }
+            libc::munmap(
+                (start + reserved_size) as *mut libc::c_void,
+                (base + len - start - reserved_size) as libc::size_t,
+            );
+        }
+
+        let hva = unsafe {
+            libc::mmap(
+                start as *mut libc::c_void,
+                self.mapped_size() as libc::size_t,
+                libc::PROT_READ | libc::PROT_WRITE,
+                self.map_flags | libc::MAP_FIXED,
+                self.fd,
+                self.file_offset as i64,
+            )
+        };
+        if hva == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::munmap(start as *mut libc::c_void, reserved_size as libc::size_t) };
+            return Err(err).chain_err(|| "Failed to map memory in reserved address space");
+        }
+        if !self.dump_guest_core {
+            let ret = unsafe {
+                libc::madvise(hva, self.mapped_size() as libc::size_t, libc::MADV_DONTDUMP)
+            };
+            if ret < 0 {
+                error!("madvise with MADV_DONTDUMP failed");
+            }
+        }
+        unsafe {
+            libc::munmap(
+                self.host_addr as *mut libc::c_void,
+                self.reserved_size as libc::size_t,
+            );
+        }
+        self.host_addr = hva as *mut u8;
+        self.reserved_size = reserved_size;
+        Ok(())
+    }
+
+    /// Lock mapped memory in host RAM, it's unlocked on drop.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to mlock, e.g. RLIMIT_MEMLOCK is exceeded.
+    pub fn lock(&mut self) -> Result<()> {
+        if self.locked {
+            return Ok(());
+        }
+        let ret = unsafe {
+            libc::mlock(
+                self.host_addr as *const libc::c_void,
+                self.mapped_size() as libc::size_t,
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            let limit = memlock_limit()
+                .map(|limit| limit.to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            return Err(err).chain_err(|| {
+                format!(
+                    "Failed to lock {} bytes of memory, RLIMIT_MEMLOCK is {}",
+                    self.mapped_size(),
+                    limit
+                )
+            });
+        }
+        self.locked = true;
+        Ok(())
+    }
+
+    /// Bind mapped memory to host NUMA node `node`, which should be done
+    /// before memory is faulted in.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - Host NUMA node id.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the node is invalid or mbind fails.
+    pub fn bind_host_numa_node(&mut self, node: u32) -> Result<()> {
+        util::numa::mbind(self.host_address(), self.mapped_size(), &[node]).chain_err(|| {
+            format!(
+                "Failed to bind memory range 0x{:x}-0x{:x} to host NUMA node {}",
+                self.start_address().raw_value(),
+                self.start_address().raw_value() + self.size(),
+                node
+            )
+        })?;
+        self.host_numa_node = Some(node);
+        Ok(())
+    }
+
+    /// Apply transparent huge page `policy` to mapped memory, failure is
+    /// logged only.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Transparent huge page policy.
+    pub fn set_thp_policy(&self, policy: ThpPolicy) {
+        advise_thp(
+            self.host_address(),
+            self.mapped_size(),
+            policy,
+            |addr, size, advice| unsafe {
+                libc::madvise(addr as *mut libc::c_void, size as libc::size_t, advice)
+            },
+        );
+    }
+
+    /// Get host NUMA node which mapped memory is bound to, if any.
+    pub fn host_numa_node(&self) -> Option<u32> {
+        self.host_numa_node
+    }
+
+    /// Whether mapped memory is locked in host RAM.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Fault in all mapped memory, by multiple threads for large mapping.
+    ///
+    /// # Arguments
+    ///
+    /// * `page_size` - Page size of the mapping.
+    fn prealloc(&self, page_size: u64) {
+        let threads = if self.mapped_size() < PREALLOC_MULTI_THREAD_SIZE {
+            1
+        } else {
+            let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+            std::cmp::max(1, std::cmp::min(cpus as u64, PREALLOC_MAX_THREADS))
+        };
+        touch_pages(self.host_address(), self.mapped_size(), page_size, threads);
+    }
+
     /// Get size of mapped memory.
     pub fn size(&self) -> u64 {
-        self.address_range.size
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Get size of mapped host memory.
+    fn mapped_size(&self) -> u64 {
+        self.mapped_size.load(Ordering::SeqCst)
     }
 
     /// Get start address of mapped memory.
     pub fn start_address(&self) -> GuestAddress {
-        self.address_range.base
+        self.guest_addr
     }
 
     /// Get start `HVA` (host virtual address) of mapped memory.
@@ -250,15 +847,238 @@ impl HostMemMapping {
     pub fn file_backend(&self) -> (RawFd, u64) {
         (self.fd, self.file_offset)
     }
+
+    /// Resize mapped memory to `new_size` in place, the host address never
+    /// changes because translations of it may be held elsewhere, so mapped
+    /// memory only grows within the range reserved by `reserve`, and the
+    /// released part stays reserved on shrinking. The backing file, if any,
+    /// is extended or truncated when this mapping is at its end, otherwise
+    /// the released part is discarded.
+    ///
+    /// Accesses to the released part must be stopped before shrinking.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_size` - New size, aligned to page size of the mapping.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * `new_size` is zero or not aligned.
+    /// * `new_size` exceeds the reserved size.
+    /// * The mapping can't grow beyond other data of the backing file.
+    /// * Fail to resize the backing file, e.g. it's sealed.
+    /// * Fail to map or unmap memory.
+    pub fn remap(&self, new_size: u64) -> Result<()> {
+        if new_size == 0 || new_size % self.page_size != 0 {
+            bail!(
+                "Size {} of memory mapping is not aligned to page size {}",
+                new_size,
+                self.page_size
+            );
+        }
+        let old_size = self.mapped_size();
+        if new_size == old_size {
+            self.size.store(new_size, Ordering::SeqCst);
+            return Ok(());
+        }
+        if new_size > self.reserved_size {
+            bail!(
+                "Can't grow memory mapping to {} beyond reserved size {}",
+                new_size,
+                self.reserved_size
+            );
+        }
+        let file_len = if self.fd != -1 {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(self.fd, &mut stat) } < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .chain_err(|| format!("Failed to fstat fd {}", self.fd));
+            }
+            Some(stat.st_size as u64)
+        } else {
+            None
+        };
+        // Only the last mapping of backing file owns the end of it.
+        let file_tail = file_len.map_or(false, |len| self.file_offset + old_size >= len);
+
+        if new_size > old_size {
+            if let Some(len) = file_len {
+                if self.file_offset + new_size > len {
+                    if !file_tail {
+                        bail!(
+                            "Can't grow memory mapping beyond offset {} of backing file of length {}",
+                            self.file_offset + old_size,
+                            len
+                        );
+                    }
+                    self.truncate_file(self.file_offset + new_size)?;
+                }
+            }
+        } else if file_tail {
+            self.truncate_file(self.file_offset + new_size)?;
+        } else {
+            self.discard(new_size, old_size - new_size)?;
+        }
+
+        // Map the grown part over the reserved range, or reserve the released
+        // part again.
+        let (start, len) = if new_size > old_size {
+            (old_size, new_size - old_size)
+        } else {
+            (new_size, old_size - new_size)
+        };
+        let hva = unsafe {
+            if new_size > old_size {
+                libc::mmap(
+                    (self.host_address() + start) as *mut libc::c_void,
+                    len as libc::size_t,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    self.map_flags | libc::MAP_FIXED,
+                    self.fd,
+                    if self.fd == -1 {
+                        0
+                    } else {
+                        (self.file_offset + start) as i64
+                    },
+                )
+            } else {
+                libc::mmap(
+                    (self.host_address() + start) as *mut libc::c_void,
+                    len as libc::size_t,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            }
+        };
+        if hva == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).chain_err(|| {
+                format!(
+                    "Failed to resize memory mapping from {} to {} in place",
+                    old_size, new_size
+                )
+            });
+        }
+        self.mapped_size.store(new_size, Ordering::SeqCst);
+        self.size.store(new_size, Ordering::SeqCst);
+
+        if new_size < old_size {
+            return Ok(());
+        }
+        let grown_addr = self.host_address() + old_size;
+        if !self.dump_guest_core {
+            let ret = unsafe {
+                libc::madvise(
+                    grown_addr as *mut libc::c_void,
+                    (new_size - old_size) as libc::size_t,
+                    libc::MADV_DONTDUMP,
+                )
+            };
+            if ret < 0 {
+                error!("madvise with MADV_DONTDUMP failed");
+            }
+        }
+        if let Some(node) = self.host_numa_node {
+            util::numa::mbind(grown_addr, new_size - old_size, &[node])
+                .chain_err(|| format!("Failed to bind grown memory to host NUMA node {}", node))?;
+        }
+        if self.locked {
+            let ret = unsafe {
+                libc::mlock(
+                    grown_addr as *const libc::c_void,
+                    (new_size - old_size) as libc::size_t,
+                )
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .chain_err(|| "Failed to lock grown memory");
+            }
+        }
+        Ok(())
+    }
+
+    /// Set length of backing file to `len`.
+    fn truncate_file(&self, len: u64) -> Result<()> {
+        if unsafe { libc::ftruncate(self.fd, len as libc::off_t) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .chain_err(|| format!("Failed to set length of backing file to {}", len));
+        }
+        Ok(())
+    }
+
+    /// Free pages in [`offset`, `offset` + `size`) of mapped memory back to
+    /// host, pages partially in the range are kept. Discarded pages read as
+    /// zero afterwards, or as contents of backing file for private mapping.
+    ///
+    /// Return the number of bytes discarded.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to punch hole in backing file or madvise.
+    pub fn discard(&self, offset: u64, size: u64) -> Result<u64> {
+        let start = round_up(offset, Some(self.page_size));
+        let end = std::cmp::min(offset.saturating_add(size), self.mapped_size()) / self.page_size
+            * self.page_size;
+        if end <= start {
+            return Ok(0);
+        }
+        let len = end - start;
+
+        if self.fd != -1 && self.is_share {
+            let ret = unsafe {
+                libc::fallocate(
+                    self.fd,
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    (self.file_offset + start) as libc::off_t,
+                    len as libc::off_t,
+                )
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error()).chain_err(|| {
+                    format!(
+                        "Failed to punch hole in backing file, offset {}, size {}",
+                        self.file_offset + start,
+                        len
+                    )
+                });
+            }
+        } else {
+            let ret = unsafe {
+                libc::madvise(
+                    (self.host_addr as u64 + start) as *mut libc::c_void,
+                    len as libc::size_t,
+                    libc::MADV_DONTNEED,
+                )
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error()).chain_err(|| {
+                    format!(
+                        "Failed to discard memory, host address 0x{:x}, size {}",
+                        self.host_addr as u64 + start,
+                        len
+                    )
+                });
+            }
+        }
+        Ok(len)
+    }
 }
 
 impl Drop for HostMemMapping {
     /// Release the memory mapping.
     fn drop(&mut self) {
         unsafe {
+            if self.locked {
+                libc::munlock(
+                    self.host_addr as *const libc::c_void,
+                    self.mapped_size() as libc::size_t,
+                );
+            }
             libc::munmap(
                 self.host_addr as *mut libc::c_void,
-                self.size() as libc::size_t,
+                self.reserved_size as libc::size_t,
             );
         }
     }
@@ -322,4 +1142,298 @@ mod test {
 
         std::fs::remove_file(file_path).unwrap();
     }
+
+    #[test]
+    fn test_file_backend_from_fd() {
+        let file_path = String::from("back_mem_test_fd");
+        let file = File::create(file_path.clone()).unwrap();
+        file.set_len(0x3000).unwrap();
+        let fd = file.as_raw_fd();
+
+        let f_back = FileBackend::from_fd(fd, 0x1000, 0x2000).unwrap();
+        assert_eq!(f_back.offset, 0x1000);
+        assert_eq!(f_back.hugepage_size, None);
+        assert_ne!(f_back.file.as_raw_fd(), fd);
+        assert!(FileBackend::from_fd(fd, 0x1000, 0x3000).is_err());
+        assert!(FileBackend::from_fd(fd, u64::max_value(), 0x1000).is_err());
+        drop(f_back);
+        // Caller still owns the fd.
+        assert_eq!(file.metadata().unwrap().len(), 0x3000);
+
+        let dir = File::open("/tmp").unwrap();
+        assert!(FileBackend::from_fd(dir.as_raw_fd(), 0, 0x1000).is_err());
+        assert!(FileBackend::from_fd(-1, 0, 0x1000).is_err());
+
+        // Fd is preferred over path.
+        let mut mem_config = MachineMemConfig {
+            mem_path: Some(String::from("/nonexistent/back_mem")),
+            mem_fd: Some(fd),
+            mem_share: true,
+            ..Default::default()
+        };
+        let mappings = create_host_mmaps(&[(0, 0x1000), (0x1000, 0x2000)], &mem_config).unwrap();
+        assert_eq!(mappings[1].file_backend().1, 0x1000);
+        unsafe { *(mappings[1].host_address() as *mut u8) = 0x5a };
+        let mut byte = [0_u8; 1];
+        std::os::unix::fs::FileExt::read_exact_at(&file, &mut byte, 0x1000).unwrap();
+        assert_eq!(byte[0], 0x5a);
+
+        mem_config.hugepage = true;
+        assert!(create_host_mmaps(&[(0, 0x1000)], &mem_config).is_err());
+        mem_config.hugepage = false;
+        assert!(create_host_mmaps(&[(0, 0x4000)], &mem_config).is_err());
+
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_backend_offset_alignment() {
+        let align = page_size();
+        let file_path = String::from("back_mem_test_align");
+        let mem_config = MachineMemConfig {
+            mem_path: Some(file_path.clone()),
+            mem_share: true,
+            ..Default::default()
+        };
+        let ranges = [(0, align / 2), (align, align), (2 * align, align + 1)];
+        let mappings = create_host_mmaps(&ranges, &mem_config).unwrap();
+        assert_eq!(mappings[0].file_backend().1, 0);
+        assert_eq!(mappings[1].file_backend().1, align);
+        assert_eq!(mappings[2].file_backend().1, 2 * align);
+        assert_eq!(
+            std::fs::metadata(&file_path).unwrap().len(),
+            aligned_file_len(&ranges, align)
+        );
+        assert_eq!(aligned_file_len(&ranges, align), 4 * align);
+        drop(mappings);
+        std::fs::remove_file(&file_path).unwrap();
+
+        let mem_config = MachineMemConfig {
+            mem_share: true,
+            ..Default::default()
+        };
+        let mappings = create_host_mmaps(&ranges, &mem_config).unwrap();
+        assert_eq!(mappings[2].file_backend().1, 2 * align);
+    }
+
+    #[test]
+    fn test_short_file_backend() {
+        let align = page_size();
+        let file_path = String::from("back_mem_test_short");
+        let file = File::create(file_path.clone()).unwrap();
+        file.set_len(align).unwrap();
+
+        let mem_config = MachineMemConfig {
+            mem_path: Some(file_path.clone()),
+            ..Default::default()
+        };
+        match create_host_mmaps(&[(0, align), (align, align)], &mem_config) {
+            Err(crate::errors::Error(ErrorKind::BackendTooSmall(have, need), _)) => {
+                assert_eq!(have, align);
+                assert_eq!(need, 2 * align);
+            }
+            _ => panic!("Short backing file should be rejected"),
+        }
+        // Length of existing file is kept.
+        assert_eq!(file.metadata().unwrap().len(), align);
+        assert!(create_host_mmaps(&[(0, align)], &mem_config).is_ok());
+
+        let mem_config = MachineMemConfig {
+            mem_fd: Some(file.as_raw_fd()),
+            ..Default::default()
+        };
+        match create_host_mmaps(&[(0, align / 2), (align, align / 2)], &mem_config) {
+            Err(crate::errors::Error(ErrorKind::BackendTooSmall(have, need), _)) => {
+                assert_eq!(have, align);
+                assert_eq!(need, 2 * align);
+            }
+            _ => panic!("Short backing fd should be rejected"),
+        }
+
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_hugepage_detection() {
+        let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+        fs.f_bsize = (2 << 20) as _;
+        fs.f_type = HUGETLBFS_MAGIC as _;
+        assert_eq!(hugepage_size_of(&fs), Some(2 << 20));
+        // TMPFS_MAGIC
+        fs.f_type = 0x0102_1994 as _;
+        assert_eq!(hugepage_size_of(&fs), None);
+
+        // Parent of nonexistent file is checked.
+        assert_eq!(hugepage_size("/tmp").unwrap(), None);
+        assert_eq!(hugepage_size("/tmp/stratovirt_no_such_file").unwrap(), None);
+        assert!(hugepage_size("/stratovirt_no_such_dir/file").is_err());
+
+        assert_eq!(round_up(3 << 20, Some(2 << 20)), 4 << 20);
+        assert_eq!(round_up(3 << 20, None), 3 << 20);
+    }
+
+    /// Return whether all pages of memory at `host_addr` are resident.
+    fn resident(host_addr: u64, size: u64) -> bool {
+        let pages = (size / page_size()) as usize;
+        let mut vec = vec![0_u8; pages];
+        let ret = unsafe {
+            libc::mincore(
+                host_addr as *mut libc::c_void,
+                size as libc::size_t,
+                vec.as_mut_ptr(),
+            )
+        };
+        ret == 0 && vec.iter().all(|v| v & 1 == 1)
+    }
+
+    #[test]
+    fn test_prealloc() {
+        let size = 16 << 20;
+        let mem_config = MachineMemConfig {
+            mem_prealloc: true,
+            ..Default::default()
+        };
+        let mappings = create_host_mmaps(&[(0, size)], &mem_config).unwrap();
+        assert!(resident(mappings[0].host_address(), size));
+
+        // Pages are touched by multiple threads, and contents are kept.
+        let mapping = HostMemMapping::new(GuestAddress(0), size, -1, 0, false, false).unwrap();
+        unsafe { *(mapping.host_address() as *mut u8) = 0x5a };
+        touch_pages(mapping.host_address(), size, page_size(), 3);
+        assert!(resident(mapping.host_address(), size));
+        assert_eq!(unsafe { *(mapping.host_address() as *const u8) }, 0x5a);
+    }
+
+    #[test]
+    fn test_memlock_limit() {
+        assert!(check_memlock_limit(16 << 20, 64 << 10).is_err());
+        assert!(check_memlock_limit(16 << 20, 16 << 20).is_ok());
+        assert!(check_memlock_limit(16 << 20, u64::max_value()).is_ok());
+        match check_memlock_limit(2 << 20, 1 << 20) {
+            Err(crate::errors::Error(ErrorKind::MemLockLimit(size, limit), _)) => {
+                assert_eq!((size, limit), (2 << 20, 1 << 20))
+            }
+            _ => panic!("expect MemLockLimit error"),
+        }
+
+        let mut mapping =
+            HostMemMapping::new(GuestAddress(0), 1 << 12, -1, 0, false, false).unwrap();
+        assert!(!mapping.is_locked());
+        // One page is likely to be under RLIMIT_MEMLOCK.
+        if mapping.lock().is_ok() {
+            assert!(mapping.is_locked());
+        }
+    }
+
+    #[test]
+    fn test_bind_host_numa_node() {
+        let mut mapping =
+            HostMemMapping::new(GuestAddress(0), 1 << 12, -1, 0, false, false).unwrap();
+        assert_eq!(mapping.host_numa_node(), None);
+        assert!(mapping.bind_host_numa_node(1024).is_err());
+        assert_eq!(mapping.host_numa_node(), None);
+        // Node 0 always exists, but mbind may be forbidden, e.g. by seccomp.
+        if mapping.bind_host_numa_node(0).is_ok() {
+            assert_eq!(mapping.host_numa_node(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_advise_thp() {
+        let advise = |policy: ThpPolicy, ret: libc::c_int| {
+            let mut calls = Vec::new();
+            advise_thp(0x1000, 0x2000, policy, |addr, size, advice| {
+                calls.push((addr, size, advice));
+                ret
+            });
+            calls
+        };
+        assert!(advise(ThpPolicy::Default, 0).is_empty());
+        assert_eq!(
+            advise(ThpPolicy::Always, 0),
+            vec![(0x1000, 0x2000, libc::MADV_HUGEPAGE)]
+        );
+        assert_eq!(
+            advise(ThpPolicy::Never, 0),
+            vec![(0x1000, 0x2000, libc::MADV_NOHUGEPAGE)]
+        );
+        // Failure is not fatal.
+        assert_eq!(advise(ThpPolicy::Never, -1).len(), 1);
+
+        let mapping = HostMemMapping::new(GuestAddress(0), 1 << 21, -1, 0, false, false).unwrap();
+        mapping.set_thp_policy(ThpPolicy::Never);
+    }
+
+    #[test]
+    fn test_memfd_seal() {
+        let get_seals = |file: &File| unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        let resize_seals = libc::F_SEAL_GROW | libc::F_SEAL_SHRINK;
+
+        let file = create_memfd(1 << 20, true).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1 << 20);
+        assert_eq!(get_seals(&file) & resize_seals, resize_seals);
+        assert!(file.set_len(2 << 20).is_err());
+        assert!(file.set_len(1 << 10).is_err());
+
+        let file = create_memfd(1 << 20, false).unwrap();
+        assert_eq!(get_seals(&file) & resize_seals, 0);
+        assert!(file.set_len(2 << 20).is_ok());
+
+        let mem_config = MachineMemConfig {
+            mem_share: true,
+            ..Default::default()
+        };
+        let mappings = create_host_mmaps(&[(0, 1 << 20), (1 << 20, 1 << 20)], &mem_config).unwrap();
+        assert_eq!(mappings[1].file_backend().1, 1 << 20);
+    }
+
+    #[test]
+    fn test_remap() {
+        let size = 4 * page_size();
+        let file = create_memfd(size, false).unwrap();
+        let mapping =
+            HostMemMapping::new(GuestAddress(0), size, file.as_raw_fd(), 0, false, true).unwrap();
+        let host_addr = mapping.host_address();
+
+        assert!(mapping.remap(size + 1).is_err());
+        assert!(mapping.remap(0).is_err());
+
+        // Shrinking truncates the backing file, and growing extends it.
+        mapping.remap(2 * page_size()).unwrap();
+        assert_eq!(mapping.size(), 2 * page_size());
+        assert_eq!(file.metadata().unwrap().len(), 2 * page_size());
+        unsafe { *(host_addr as *mut u8) = 0x5a };
+        mapping.remap(size).unwrap();
+        assert_eq!(mapping.host_address(), host_addr);
+        assert_eq!(file.metadata().unwrap().len(), size);
+        unsafe {
+            assert_eq!(*(host_addr as *const u8), 0x5a);
+            assert_eq!(*((host_addr + size - 1) as *const u8), 0);
+        }
+
+        // Can't grow beyond the reserved address space.
+        assert!(mapping.remap(2 * size).is_err());
+        assert_eq!(mapping.size(), size);
+
+        // Anonymous private mapping grows within the reserved address space.
+        let mut mapping = HostMemMapping::new(GuestAddress(0), size, -1, 0, false, false).unwrap();
+        mapping.reserve(2 * size).unwrap();
+        let host_addr = mapping.host_address();
+        unsafe { *(host_addr as *mut u8) = 0x5a };
+        mapping.remap(2 * size).unwrap();
+        assert_eq!(mapping.host_address(), host_addr);
+        assert_eq!(mapping.size(), 2 * size);
+        unsafe {
+            assert_eq!(*(host_addr as *const u8), 0x5a);
+            *((host_addr + 2 * size - 1) as *mut u8) = 0xa5;
+        }
+        assert!(mapping.remap(4 * size).is_err());
+
+        // Sealed backing file can't be resized, the mapping is kept.
+        let file = create_memfd(size, true).unwrap();
+        let mapping =
+            HostMemMapping::new(GuestAddress(0), size, file.as_raw_fd(), 0, false, true).unwrap();
+        assert!(mapping.remap(2 * page_size()).is_err());
+        assert_eq!(mapping.size(), size);
+    }
 }
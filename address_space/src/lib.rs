@@ -39,7 +39,7 @@
 //!     // 2. create an Ram-type Region, and set it's priority
 //!     let mem_mapping = Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000, -1, 0, false, false).unwrap());
 //!     let ram_region = Region::init_ram_region(mem_mapping.clone());
-//!     ram_region.set_priority(10);
+//!     ram_region.set_priority(10).unwrap();
 //!
 //!     // 3. create a IO-type Region
 //!     let dev = Arc::new(Mutex::new(DummyDevice));
@@ -69,7 +69,7 @@
 //! }
 //! ```
 
-extern crate kvm_bindings;
+extern crate hypervisor;
 extern crate kvm_ioctls;
 extern crate libc;
 extern crate machine_manager;
@@ -84,16 +84,18 @@ mod address;
 mod address_space;
 mod host_mmap;
 mod listener;
+mod mem_slice;
 mod region;
 
 pub use address::{AddressRange, GuestAddress};
-pub use address_space::AddressSpace;
+pub use address_space::{AddressSpace, CoalescedRing, SpaceType};
 pub use host_mmap::{create_host_mmaps, FileBackend, HostMemMapping};
 #[cfg(target_arch = "x86_64")]
 pub use listener::KvmIoListener;
 pub use listener::KvmMemoryListener;
 pub use listener::{Listener, ListenerReqType};
-pub use region::{FlatRange, Region, RegionIoEventFd, RegionType};
+pub use mem_slice::GuestMemSlice;
+pub use region::{FlatRange, Region, RegionIoEventFd, RegionType, VcpusPaused};
 
 pub mod errors {
     error_chain! {
@@ -104,8 +106,24 @@ pub mod errors {
             KvmListener(crate::listener::errors::Error, crate::listener::errors::ErrorKind);
         }
         errors {
-            RegionOverlap(addr: u64) {
-                display("Region overlap with others, addr {}", addr)
+            RegionOverlap(
+                new_name: String,
+                new: crate::AddressRange,
+                existing_name: String,
+                existing: crate::AddressRange
+            ) {
+                display(
+                    "Region {} (offset 0x{:x}, size 0x{:x}) overlaps with region {} (offset 0x{:x}, size 0x{:x})",
+                    new_name,
+                    new.base.raw_value(),
+                    new.size,
+                    existing_name,
+                    existing.base.raw_value(),
+                    existing.size
+                )
+            }
+            SpaceSize(size: u64, max: u64) {
+                display("Size 0x{:x} of root region exceeds address space limit 0x{:x}", size, max)
             }
             IoEventFd {
                 display("Failed to clone EventFd")
@@ -119,21 +137,54 @@ pub mod errors {
             AddrInvalid(addr: u64) {
                 display("Failed to find matched region, addr {}", addr)
             }
+            CrossRegions(addr: u64, size: u64) {
+                display("Segment crosses regions, addr {}, size {}", addr, size)
+            }
             Overflow(addr: u64) {
                 display("Address overflows, addr is {}", addr)
             }
+            MappingRange(offset: u64, len: u64, size: u64) {
+                display("Access of {} bytes at offset 0x{:x} exceeds host mapping of size 0x{:x}", len, offset, size)
+            }
             FileBackend {
                 display("Exceed file-backend length")
             }
             Mmap {
                 display("Failed to mmap")
             }
-            IoAccess(offset: u64) {
-                display("Access io region failed, offset is {}", offset)
+            MemLockLimit(size: u64, limit: u64) {
+                display(
+                    "Locking {} bytes of memory exceeds RLIMIT_MEMLOCK {}, raise it to at least {} KiB (ulimit -l)",
+                    size,
+                    limit,
+                    (size + 1023) / 1024
+                )
+            }
+            BackendTooSmall(have: u64, need: u64) {
+                display("Backing file too small: have {} need {}", have, need)
+            }
+            HugePageUnaligned(size: u64, page_size: u64) {
+                display("Memory size {} is not aligned to huge page size {}", size, page_size)
+            }
+            IoAccess(name: String, base: u64, offset: u64, size: u64, is_write: bool) {
+                display(
+                    "Failed to {} {} bytes of io region {} at base 0x{:x}, offset 0x{:x}",
+                    if *is_write { "write" } else { "read" },
+                    size,
+                    name,
+                    base,
+                    offset
+                )
+            }
+            IoRetry(name: String, offset: u64) {
+                display("Access io region {} should be retried, offset is {}", name, offset)
             }
             RegionType(t: crate::RegionType) {
                 display("Wrong region type, {:#?}", t)
             }
+            ReadOnly(name: String, addr: u64) {
+                display("Write to read-only region {}, addr {}", name, addr)
+            }
         }
     }
 }
@@ -161,8 +212,93 @@ pub struct RegionOps {
     pub write: std::sync::Arc<dyn Fn(&[u8], GuestAddress, u64) -> bool + Send + Sync>,
 }
 
+/// Error of an access to `SizedRegionOps`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoAccessError {
+    /// The access isn't supported by device.
+    Unsupported,
+    /// Device is busy, the access should be retried later.
+    Retry,
+}
+
+/// Sizes of accesses accepted by `SizedRegionOps`, which are powers of 2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AccessSize {
+    /// Minimum size of an access.
+    pub min: u64,
+    /// Maximum size of an access, larger accesses are split.
+    pub max: u64,
+}
+
+/// Operations of `Region` which get naturally-aligned accesses of sizes in
+/// `AccessSize`, the size of an access is the length of `data`.
+#[derive(Clone)]
+pub struct SizedRegionOps {
+    /// Read data from Region to argument `data`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A u8-type array, whose length is the access size.
+    /// * `base` - Base address.
+    /// * `offset` - Offset from base address.
+    pub read: std::sync::Arc<
+        dyn Fn(&mut [u8], GuestAddress, u64) -> std::result::Result<(), IoAccessError>
+            + Send
+            + Sync,
+    >,
+    /// Write `data` to memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A u8-type array, whose length is the access size.
+    /// * `base` - Base address.
+    /// * `offset` - Offset from base address.
+    pub write: std::sync::Arc<
+        dyn Fn(&[u8], GuestAddress, u64) -> std::result::Result<(), IoAccessError> + Send + Sync,
+    >,
+}
+
+/// Adapt `RegionOps`, whose failures are `Unsupported`.
+impl From<RegionOps> for SizedRegionOps {
+    fn from(ops: RegionOps) -> Self {
+        let (read, write) = (ops.read, ops.write);
+        SizedRegionOps {
+            read: std::sync::Arc::new(move |data, base, offset| {
+                if read(data, base, offset) {
+                    Ok(())
+                } else {
+                    Err(IoAccessError::Unsupported)
+                }
+            }),
+            write: std::sync::Arc::new(move |data, base, offset| {
+                if write(data, base, offset) {
+                    Ok(())
+                } else {
+                    Err(IoAccessError::Unsupported)
+                }
+            }),
+        }
+    }
+}
+
 /// Gets the page size of system.
 #[inline]
 pub fn page_size() -> u64 {
     unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
 }
+
+/// Count dirty pages in `bitmap` returned by `AddressSpace::get_dirty_log`.
+pub fn dirty_page_count(bitmap: &[u64]) -> u64 {
+    bitmap.iter().map(|bits| u64::from(bits.count_ones())).sum()
+}
+
+/// Iterate page frame numbers of dirty pages in `bitmap` returned by
+/// `AddressSpace::get_dirty_log`, whose first bit is for page at `base`.
+pub fn dirty_pfns(bitmap: &[u64], base: GuestAddress) -> impl Iterator<Item = u64> + '_ {
+    let base_pfn = base.raw_value() / page_size();
+    bitmap.iter().enumerate().flat_map(move |(idx, bits)| {
+        (0..64_u64)
+            .filter(move |bit| bits & (1_u64 << bit) != 0)
+            .map(move |bit| base_pfn + idx as u64 * 64 + bit)
+    })
+}
@@ -13,11 +13,14 @@
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use kvm_bindings::kvm_userspace_memory_region;
-use kvm_ioctls::{IoEventAddress, NoDatamatch, VmFd};
+use hypervisor::kvm::CoalescedMmioRing;
+use hypervisor::{DataMatch, IoEventAddr, MemorySlot, VmOps};
+use kvm_ioctls::VmFd;
 use util::num_ops::round_down;
 
-use crate::{page_size, AddressRange, FlatRange, RegionIoEventFd, RegionType};
+use crate::{
+    page_size, AddressRange, CoalescedRing, FlatRange, GuestAddress, RegionIoEventFd, RegionType,
+};
 
 pub mod errors {
     error_chain! {
@@ -25,6 +28,9 @@ pub mod errors {
             NoAvailKvmSlot {
                 display("No available kvm_mem_slot, used up")
             }
+            SlotLimit(limit: usize, name: String) {
+                display("Exceed limit of {} kvm_mem_slots when mapping region {}", limit, name)
+            }
             NoMatchedKvmSlot(addr: u64, sz: u64) {
                 display("Failed to find matched kvm_mem_slot, addr {}, size {}", addr, sz)
             }
@@ -36,6 +42,12 @@ pub mod errors {
 }
 use self::errors::{ErrorKind, Result, ResultExt};
 
+impl CoalescedRing for CoalescedMmioRing {
+    fn pop(&mut self) -> Option<(GuestAddress, Vec<u8>)> {
+        CoalescedMmioRing::pop(self).map(|(addr, data)| (GuestAddress(addr), data))
+    }
+}
+
 /// Request type of listener.
 #[derive(Debug, Copy, Clone)]
 pub enum ListenerReqType {
@@ -68,6 +80,34 @@ pub trait Listener: Send + Sync {
     ) -> std::result::Result<(), crate::errors::Error> {
         Ok(())
     }
+
+    /// Check whether all `_ranges` of a new flat view can be handled by this
+    /// listener, before any of them is added.
+    ///
+    /// # Arguments
+    ///
+    /// * `_ranges` - FlatRanges of the new flat view.
+    fn check_ranges(&self, _ranges: &[FlatRange]) -> std::result::Result<(), crate::errors::Error> {
+        Ok(())
+    }
+
+    /// Collect and reset dirty pages logged by this listener in
+    /// [`_addr`, `_addr` + `_size`), setting bit `n` of `_bitmap` for the
+    /// page at `_addr` + `n` * page size.
+    ///
+    /// # Arguments
+    ///
+    /// * `_addr` - Page-aligned start address.
+    /// * `_size` - Size of memory.
+    /// * `_bitmap` - Dirty bitmap of the memory.
+    fn sync_dirty_log(
+        &self,
+        _addr: GuestAddress,
+        _size: u64,
+        _bitmap: &mut [u64],
+    ) -> std::result::Result<(), crate::errors::Error> {
+        Ok(())
+    }
 }
 
 /// Records information that manage the slot resource and current usage.
@@ -82,10 +122,13 @@ struct MemSlot {
     pub size: u64,
     /// Host address.
     pub host_addr: u64,
-    /// Flag.
+    /// Flag, `MEM_SLOT_LOG_DIRTY` or not.
     pub flag: u32,
 }
 
+/// Dirty pages in memory slot are logged.
+const MEM_SLOT_LOG_DIRTY: u32 = 1 << 0;
+
 /// Kvm memory listener.
 #[derive(Clone)]
 pub struct KvmMemoryListener {
@@ -95,6 +138,8 @@ pub struct KvmMemoryListener {
     fd: Arc<VmFd>,
     /// Record all MemSlots.
     slots: Arc<Mutex<Vec<MemSlot>>>,
+    /// Whether KVM supports read-only memory slots.
+    readonly_mem: bool,
 }
 
 impl KvmMemoryListener {
@@ -109,6 +154,7 @@ impl KvmMemoryListener {
             as_id: Arc::new(AtomicU32::new(0)),
             fd: vmfd,
             slots: Arc::new(Mutex::new(vec![MemSlot::default(); nr_slots as usize])),
+            readonly_mem: vmfd.readonly_mem_supported(),
         }
     }
 
@@ -205,7 +251,43 @@ impl KvmMemoryListener {
         Ok(AddressRange::new(aligned_addr, aligned_size))
     }
 
-    /// Callback function for adding Region, which only care about Ram-type Region yet.
+    /// Whether `flat_range` is mapped to a KVM memory slot. Rom device
+    /// regions are mapped only if read-only slots are supported, so that
+    /// guest writes to them exit to userspace.
+    fn maps_range(&self, flat_range: &FlatRange) -> bool {
+        match flat_range.owner.region_type() {
+            RegionType::Ram => true,
+            RegionType::RomDevice => self.readonly_mem,
+            _ => false,
+        }
+    }
+
+    /// Check that KVM memory slots are enough for mapping `ranges`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges` - FlatRanges of a new flat view.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the first region which exceeds the slot limit.
+    fn check_slot_limit(&self, ranges: &[FlatRange]) -> Result<()> {
+        let limit = self.slots.lock().unwrap().len();
+        let mut used = 0_usize;
+        for fr in ranges.iter().filter(|fr| self.maps_range(fr)) {
+            if Self::align_mem_slot(fr.addr_range, page_size()).is_err() {
+                continue;
+            }
+            used += 1;
+            if used > limit {
+                return Err(ErrorKind::SlotLimit(limit, fr.owner.name().to_string()).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Callback function for adding Region, which maps Ram-type Region, and rom
+    /// device Region if possible, to a KVM memory slot, read-only if it's `rom`.
     ///
     /// # Arguments
     ///
@@ -215,9 +297,18 @@ impl KvmMemoryListener {
     ///
     /// Return Error if fail to delete kvm_mem_slot.
     fn add_region(&self, flat_range: &FlatRange) -> Result<()> {
-        if flat_range.owner.region_type() != RegionType::Ram {
+        if flat_range.owner.region_type() == RegionType::IO {
+            return self.add_coalesced_zones(flat_range);
+        }
+        if !self.maps_range(flat_range) {
             return Ok(());
         }
+        if flat_range.read_only() && !self.readonly_mem {
+            warn!(
+                "KVM doesn't support read-only memory, region {} is writable by guest",
+                flat_range.owner.name()
+            );
+        }
 
         let (aligned_addr, aligned_size) =
             Self::align_mem_slot(flat_range.addr_range, page_size()).map(|r| (r.base, r.size))?;
@@ -229,16 +320,22 @@ impl KvmMemoryListener {
             + align_adjust;
 
         let slot_idx = self.get_free_slot(aligned_addr.raw_value(), aligned_size, aligned_hva)?;
+        self.slots.lock().unwrap()[slot_idx as usize].flag = if flat_range.log_dirty {
+            MEM_SLOT_LOG_DIRTY
+        } else {
+            0
+        };
 
-        let kvm_region = kvm_userspace_memory_region {
+        let mem_slot = MemorySlot {
             slot: slot_idx | (self.as_id.load(Ordering::SeqCst) << 16),
-            guest_phys_addr: aligned_addr.raw_value(),
-            memory_size: aligned_size,
-            userspace_addr: aligned_hva,
-            flags: 0,
+            guest_addr: aligned_addr.raw_value(),
+            size: aligned_size,
+            host_addr: aligned_hva,
+            log_dirty: flat_range.log_dirty,
+            read_only: flat_range.read_only() && self.readonly_mem,
         };
         unsafe {
-            self.fd.set_user_memory_region(kvm_region).or_else(|e| {
+            self.fd.set_memory_slot(mem_slot).or_else(|e| {
                 self.delete_slot(aligned_addr.raw_value(), aligned_size)
                     .chain_err(|| "Failed to delete kvm_mem_slot")?;
                 Err(e).chain_err(|| {
@@ -253,13 +350,17 @@ impl KvmMemoryListener {
         Ok(())
     }
 
-    /// Callback function for deleting Region, which only care about Ram-type Region yet.
+    /// Callback function for deleting Region, which unmaps the KVM memory slot
+    /// of Region mapped by `add_region`.
     ///
     /// # Arguments
     ///
     /// * `flat_range` - Corresponding FlatRange of new-deleted region.
     fn delete_region(&self, flat_range: &FlatRange) -> Result<()> {
-        if flat_range.owner.region_type() != RegionType::Ram {
+        if flat_range.owner.region_type() == RegionType::IO {
+            return self.delete_coalesced_zones(flat_range);
+        }
+        if !self.maps_range(flat_range) {
             return Ok(());
         }
 
@@ -268,15 +369,16 @@ impl KvmMemoryListener {
 
         let mem_slot = self.delete_slot(aligned_addr.raw_value(), aligned_size)?;
 
-        let kvm_region = kvm_userspace_memory_region {
+        let deleted_slot = MemorySlot {
             slot: mem_slot.index | (self.as_id.load(Ordering::SeqCst) << 16),
-            guest_phys_addr: mem_slot.guest_addr,
-            memory_size: 0_u64,
-            userspace_addr: mem_slot.host_addr,
-            flags: 0,
+            guest_addr: mem_slot.guest_addr,
+            size: 0_u64,
+            host_addr: mem_slot.host_addr,
+            log_dirty: false,
+            read_only: false,
         };
         unsafe {
-            self.fd.set_user_memory_region(kvm_region).chain_err(|| {
+            self.fd.set_memory_slot(deleted_slot).chain_err(|| {
                 format!(
                     "KVM unregister memory region failed: addr {}",
                     aligned_addr.raw_value(),
@@ -287,6 +389,97 @@ impl KvmMemoryListener {
         Ok(())
     }
 
+    /// Register coalesced MMIO ranges of IO-type `flat_range` to KVM, so that
+    /// guest writes to them are batched in the coalesced ring.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to register a range, the registered ones are
+    /// unregistered then.
+    fn add_coalesced_zones(&self, flat_range: &FlatRange) -> Result<()> {
+        for (idx, zone) in flat_range.coalesced.iter().enumerate() {
+            if let Err(e) = self
+                .fd
+                .register_coalesced_mmio(zone.base.raw_value(), zone.size)
+            {
+                for added in flat_range.coalesced[..idx].iter() {
+                    if let Err(e) = self
+                        .fd
+                        .unregister_coalesced_mmio(added.base.raw_value(), added.size)
+                    {
+                        error!("Failed to unregister coalesced MMIO: {}", e);
+                    }
+                }
+                return Err(e).chain_err(|| {
+                    format!(
+                        "KVM register coalesced MMIO failed: addr {}, size {}",
+                        zone.base.raw_value(),
+                        zone.size
+                    )
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Unregister coalesced MMIO ranges registered by `add_coalesced_zones`.
+    fn delete_coalesced_zones(&self, flat_range: &FlatRange) -> Result<()> {
+        for zone in flat_range.coalesced.iter() {
+            self.fd
+                .unregister_coalesced_mmio(zone.base.raw_value(), zone.size)
+                .chain_err(|| {
+                    format!(
+                        "KVM unregister coalesced MMIO failed: addr {}, size {}",
+                        zone.base.raw_value(),
+                        zone.size
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Fetch dirty bitmaps of logging slots which intersect [`addr`, `addr` + `size`),
+    /// and set bits of dirty pages in the range to `bitmap`. Dirty bitmap of the
+    /// whole slot is reset by KVM.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Page-aligned start address.
+    /// * `size` - Size of memory.
+    /// * `bitmap` - Dirty bitmap of the memory.
+    fn sync_slots_dirty_log(&self, addr: u64, size: u64, bitmap: &mut [u64]) -> Result<()> {
+        let range = AddressRange::from((addr, size));
+        let slots = self.slots.lock().unwrap().clone();
+        for slot in slots
+            .iter()
+            .filter(|s| s.size != 0 && s.flag & MEM_SLOT_LOG_DIRTY != 0)
+        {
+            if AddressRange::from((slot.guest_addr, slot.size))
+                .find_intersection(range)
+                .is_none()
+            {
+                continue;
+            }
+            let slot_bitmap = self
+                .fd
+                .get_dirty_log(
+                    slot.index | (self.as_id.load(Ordering::SeqCst) << 16),
+                    slot.size,
+                )
+                .chain_err(|| format!("Failed to get dirty log of slot {}", slot.index))?;
+            for (idx, bits) in slot_bitmap.iter().enumerate() {
+                for bit in (0..64).filter(|bit| bits & (1_u64 << bit) != 0) {
+                    let page_addr = slot.guest_addr + (idx as u64 * 64 + bit) * page_size();
+                    if page_addr >= addr && page_addr < addr + size {
+                        let page = (page_addr - addr) / page_size();
+                        bitmap[(page / 64) as usize] |= 1_u64 << (page % 64);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Register a IoEvent to `/dev/kvm`.
     ///
     /// # Arguments
@@ -297,26 +490,16 @@ impl KvmMemoryListener {
     ///
     /// Return Error if the length of ioeventfd data is unexpected or syscall failed.
     fn add_ioeventfd(&self, ioevtfd: &RegionIoEventFd) -> Result<()> {
-        let io_addr = IoEventAddress::Mmio(ioevtfd.addr_range.base.raw_value());
-
-        let ioctl_ret = if ioevtfd.data_match {
-            let length = ioevtfd.addr_range.size;
-            match length {
-                2 => self
-                    .fd
-                    .register_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u16),
-                4 => self
-                    .fd
-                    .register_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u32),
-                8 => self
-                    .fd
-                    .register_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u64),
-                _ => bail!("Unexpected ioeventfd data length"),
-            }
+        let io_addr = IoEventAddr::Mmio(ioevtfd.addr_range.base.raw_value());
+        let datamatch = if ioevtfd.data_match {
+            DataMatch::with_len(ioevtfd.addr_range.size, ioevtfd.data)
+                .chain_err(|| "Unexpected ioeventfd data length")?
         } else {
-            self.fd.register_ioevent(&ioevtfd.fd, &io_addr, NoDatamatch)
+            DataMatch::None
         };
 
+        let ioctl_ret = self.fd.register_ioeventfd(&ioevtfd.fd, io_addr, datamatch);
+
         ioctl_ret.chain_err(|| {
             format!(
                 "KVM register ioeventfd failed: mmio-addr {}",
@@ -333,26 +516,18 @@ impl KvmMemoryListener {
     ///
     /// * `ioevtfd` - IoEvent would be deleted.
     fn delete_ioeventfd(&self, ioevtfd: &RegionIoEventFd) -> Result<()> {
-        let io_addr = IoEventAddress::Mmio(ioevtfd.addr_range.base.raw_value());
-        let ioctl_ret = if ioevtfd.data_match {
-            let length = ioevtfd.addr_range.size;
-            match length {
-                2 => self
-                    .fd
-                    .unregister_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u16),
-                4 => self
-                    .fd
-                    .unregister_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u32),
-                8 => self
-                    .fd
-                    .unregister_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u64),
-                _ => bail!("Unexpected ioeventfd data length"),
-            }
+        let io_addr = IoEventAddr::Mmio(ioevtfd.addr_range.base.raw_value());
+        let datamatch = if ioevtfd.data_match {
+            DataMatch::with_len(ioevtfd.addr_range.size, ioevtfd.data)
+                .chain_err(|| "Unexpected ioeventfd data length")?
         } else {
-            self.fd
-                .unregister_ioevent(&ioevtfd.fd, &io_addr, NoDatamatch)
+            DataMatch::None
         };
 
+        let ioctl_ret = self
+            .fd
+            .unregister_ioeventfd(&ioevtfd.fd, io_addr, datamatch);
+
         ioctl_ret.chain_err(|| {
             format!(
                 "KVM unregister ioeventfd failed: mmio-addr {}",
@@ -370,6 +545,12 @@ impl Listener for KvmMemoryListener {
         10_i32
     }
 
+    /// Check that KVM memory slots are enough for `ranges`.
+    fn check_ranges(&self, ranges: &[FlatRange]) -> std::result::Result<(), crate::errors::Error> {
+        self.check_slot_limit(ranges)?;
+        Ok(())
+    }
+
     /// Deal with the request.
     ///
     /// # Arguments
@@ -404,6 +585,15 @@ impl Listener for KvmMemoryListener {
         }
         Ok(())
     }
+
+    fn sync_dirty_log(
+        &self,
+        addr: GuestAddress,
+        size: u64,
+        bitmap: &mut [u64],
+    ) -> std::result::Result<(), crate::errors::Error> {
+        Ok(self.sync_slots_dirty_log(addr.raw_value(), size, bitmap)?)
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -432,26 +622,16 @@ impl KvmIoListener {
     ///
     /// Return Error if the length of ioeventfd data is unexpected or syscall failed.
     fn add_ioeventfd(&self, ioevtfd: &RegionIoEventFd) -> Result<()> {
-        let io_addr = IoEventAddress::Pio(ioevtfd.addr_range.base.raw_value());
-
-        let ioctl_ret = if ioevtfd.data_match {
-            let length = ioevtfd.addr_range.size;
-            match length {
-                2 => self
-                    .fd
-                    .register_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u16),
-                4 => self
-                    .fd
-                    .register_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u32),
-                8 => self
-                    .fd
-                    .register_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u64),
-                _ => bail!("unexpected ioeventfd data length"),
-            }
+        let io_addr = IoEventAddr::Pio(ioevtfd.addr_range.base.raw_value());
+        let datamatch = if ioevtfd.data_match {
+            DataMatch::with_len(ioevtfd.addr_range.size, ioevtfd.data)
+                .chain_err(|| "Unexpected ioeventfd data length")?
         } else {
-            self.fd.register_ioevent(&ioevtfd.fd, &io_addr, NoDatamatch)
+            DataMatch::None
         };
 
+        let ioctl_ret = self.fd.register_ioeventfd(&ioevtfd.fd, io_addr, datamatch);
+
         ioctl_ret.chain_err(|| {
             format!(
                 "KVM register ioeventfd failed: mmio-addr {}",
@@ -468,27 +648,18 @@ impl KvmIoListener {
     ///
     /// * `ioevtfd` - IoEvent of Region.
     fn delete_ioeventfd(&self, ioevtfd: &RegionIoEventFd) -> Result<()> {
-        let io_addr = IoEventAddress::Pio(ioevtfd.addr_range.base.raw_value());
-
-        let ioctl_ret = if ioevtfd.data_match {
-            let length = ioevtfd.addr_range.size;
-            match length {
-                2 => self
-                    .fd
-                    .unregister_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u16),
-                4 => self
-                    .fd
-                    .unregister_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u32),
-                8 => self
-                    .fd
-                    .unregister_ioevent(&ioevtfd.fd, &io_addr, ioevtfd.data as u64),
-                _ => bail!("Unexpected ioeventfd data length"),
-            }
+        let io_addr = IoEventAddr::Pio(ioevtfd.addr_range.base.raw_value());
+        let datamatch = if ioevtfd.data_match {
+            DataMatch::with_len(ioevtfd.addr_range.size, ioevtfd.data)
+                .chain_err(|| "Unexpected ioeventfd data length")?
         } else {
-            self.fd
-                .unregister_ioevent(&ioevtfd.fd, &io_addr, NoDatamatch)
+            DataMatch::None
         };
 
+        let ioctl_ret = self
+            .fd
+            .unregister_ioeventfd(&ioevtfd.fd, io_addr, datamatch);
+
         ioctl_ret.chain_err(|| {
             format!(
                 "KVM unregister ioeventfd failed: io-addr {}",
@@ -536,12 +707,13 @@ impl Listener for KvmIoListener {
 
 #[cfg(test)]
 mod test {
-    use kvm_ioctls::Kvm;
+    use kvm_ioctls::{Kvm, NoDatamatch};
     use libc::EFD_NONBLOCK;
     use vmm_sys_util::eventfd::EventFd;
 
+    use super::errors::Error;
     use super::*;
-    use crate::{GuestAddress, HostMemMapping, Region, RegionIoEventFd};
+    use crate::{AddressSpace, GuestAddress, HostMemMapping, Region, RegionIoEventFd, RegionOps};
 
     fn generate_region_ioeventfd<T: Into<u64>>(addr: u64, datamatch: T) -> RegionIoEventFd {
         let data = datamatch.into();
@@ -563,6 +735,9 @@ mod test {
             ),
             owner: Region::init_ram_region(mem_mapping.clone()),
             offset_in_region,
+            log_dirty: false,
+            rom: false,
+            coalesced: Vec::new(),
         }
     }
 
@@ -727,6 +902,79 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_slot_limit() {
+        let kml = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => KvmMemoryListener::new(2, Arc::new(vm_fd)),
+            Err(_) => return,
+        };
+        let page = page_size();
+        let ranges = vec![
+            create_ram_range(0, page, 0),
+            create_ram_range(2 * page, page, 0),
+            create_ram_range(4 * page, page, 0),
+        ];
+        assert!(kml.check_slot_limit(&ranges[..2]).is_ok());
+        match kml.check_slot_limit(&ranges) {
+            Err(Error(ErrorKind::SlotLimit(2, _), _)) => {}
+            _ => panic!("expected SlotLimit error"),
+        }
+
+        let root = Region::init_container_region(u64::max_value());
+        let space = AddressSpace::new(root.clone()).unwrap();
+        space.register_listener(Box::new(kml)).unwrap();
+        let new_ram = |addr: u64| {
+            Region::init_ram_region(Arc::new(
+                HostMemMapping::new(GuestAddress(addr), page, -1, 0, false, false).unwrap(),
+            ))
+        };
+        let ram1 = new_ram(0);
+        root.add_subregion(ram1.clone(), 0).unwrap();
+        root.add_subregion(new_ram(2 * page), 2 * page).unwrap();
+
+        // The third slot is refused, and the region is not added.
+        assert!(root.add_subregion(new_ram(4 * page), 4 * page).is_err());
+        assert_eq!(root.subregions().len(), 2);
+        assert_eq!(space.flat_range_count(), 2);
+
+        // Re-enabling a region fails if its slot is taken meanwhile.
+        ram1.set_enabled(false).unwrap();
+        root.add_subregion(new_ram(4 * page), 4 * page).unwrap();
+        assert!(ram1.set_enabled(true).is_err());
+        assert!(!ram1.is_enabled());
+        assert_eq!(space.flat_range_count(), 2);
+    }
+
+    #[test]
+    fn test_coalesced_zones() {
+        let kml = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => KvmMemoryListener::new(4, Arc::new(vm_fd)),
+            Err(_) => return,
+        };
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let io_range = FlatRange {
+            addr_range: AddressRange::from((0xd000_0000, 0x1000)),
+            owner: Region::init_io_region(0x1000, ops),
+            offset_in_region: 0,
+            log_dirty: false,
+            rom: false,
+            coalesced: vec![
+                AddressRange::from((0xd000_0100, 0x100)),
+                AddressRange::from((0xd000_0800, 0x800)),
+            ],
+        };
+
+        assert!(kml
+            .handle_request(Some(&io_range), None, ListenerReqType::AddRegion)
+            .is_ok());
+        assert!(kml
+            .handle_request(Some(&io_range), None, ListenerReqType::DeleteRegion)
+            .is_ok());
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_kvm_io_listener() {
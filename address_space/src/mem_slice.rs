@@ -0,0 +1,152 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use crate::errors::{ErrorKind, Result};
+use crate::HostMemMapping;
+
+/// Size of the bounce buffer used when copying guest memory.
+const COPY_CHUNK_SIZE: usize = 0x4000;
+
+/// A bounded view of guest memory in host virtual address space.
+///
+/// Guest memory may be modified by vCPUs or devices at any time, so no Rust
+/// reference to it is ever created. Data is copied through a small bounce
+/// buffer with `copy_nonoverlapping` instead.
+pub struct GuestMemSlice<'a> {
+    /// Start of the slice in host virtual address space.
+    addr: *mut u8,
+    /// Length of the slice.
+    len: usize,
+    _marker: PhantomData<&'a HostMemMapping>,
+}
+
+impl<'a> GuestMemSlice<'a> {
+    /// Create a slice of `len` bytes at `offset` of `mapping`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the range does not fit in `mapping`.
+    pub fn new(mapping: &'a HostMemMapping, offset: u64, len: u64) -> Result<Self> {
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| ErrorKind::Overflow(offset))?;
+        if end > mapping.size() || len > std::usize::MAX as u64 {
+            return Err(ErrorKind::MappingRange(offset, len, mapping.size()).into());
+        }
+
+        Ok(GuestMemSlice {
+            addr: (mapping.host_address() + offset) as *mut u8,
+            len: len as usize,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Create a slice from raw host address.
+    ///
+    /// # Safety
+    ///
+    /// `[addr, addr + len)` must be mapped during the lifetime of the slice.
+    pub(crate) unsafe fn from_raw(addr: u64, len: u64) -> Self {
+        GuestMemSlice {
+            addr: addr as *mut u8,
+            len: len as usize,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Length of the slice.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the slice is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the whole slice to `dst`.
+    pub fn read_to(&self, dst: &mut dyn Write) -> Result<()> {
+        let mut buf = [0_u8; COPY_CHUNK_SIZE];
+        let mut done = 0;
+        while done < self.len {
+            let count = std::cmp::min(COPY_CHUNK_SIZE, self.len - done);
+            // Safe because [addr, addr + len) is checked at construction.
+            unsafe { std::ptr::copy_nonoverlapping(self.addr.add(done), buf.as_mut_ptr(), count) };
+            dst.write_all(&buf[..count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Fill the whole slice with data from `src`.
+    pub fn write_from(&self, src: &mut dyn Read) -> Result<()> {
+        let mut buf = [0_u8; COPY_CHUNK_SIZE];
+        let mut done = 0;
+        while done < self.len {
+            let count = std::cmp::min(COPY_CHUNK_SIZE, self.len - done);
+            src.read_exact(&mut buf[..count])?;
+            // Safe because [addr, addr + len) is checked at construction.
+            unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), self.addr.add(done), count) };
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::Error;
+    use crate::GuestAddress;
+
+    #[test]
+    fn test_mem_slice_copy() {
+        let size = 3 * COPY_CHUNK_SIZE as u64 + 0x123;
+        let mapping = HostMemMapping::new(GuestAddress(0), size, -1, 0, false, false).unwrap();
+        let data: Vec<u8> = (0..size - 0x10).map(|i| (i % 251) as u8).collect();
+
+        let slice = GuestMemSlice::new(&mapping, 0x10, size - 0x10).unwrap();
+        assert_eq!(slice.len(), data.len());
+        slice.write_from(&mut data.as_slice()).unwrap();
+
+        let mut read = Vec::new();
+        slice.read_to(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        let mut head = Vec::new();
+        GuestMemSlice::new(&mapping, 0, 0x11)
+            .unwrap()
+            .read_to(&mut head)
+            .unwrap();
+        assert_eq!(head[..0x10], [0_u8; 0x10]);
+        assert_eq!(head[0x10], data[0]);
+    }
+
+    #[test]
+    fn test_mem_slice_out_of_mapping() {
+        let mapping = HostMemMapping::new(GuestAddress(0), 0x1000, -1, 0, false, false).unwrap();
+
+        assert!(GuestMemSlice::new(&mapping, 0, 0x1000).is_ok());
+        assert!(GuestMemSlice::new(&mapping, 0x1000, 0).is_ok());
+        match GuestMemSlice::new(&mapping, 0xff0, 0x20) {
+            Err(Error(ErrorKind::MappingRange(0xff0, 0x20, 0x1000), _)) => {}
+            _ => panic!("expected MappingRange error"),
+        }
+        match GuestMemSlice::new(&mapping, u64::max_value(), 2) {
+            Err(Error(ErrorKind::Overflow(_), _)) => {}
+            _ => panic!("expected Overflow error"),
+        }
+    }
+}
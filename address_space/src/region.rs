@@ -11,12 +11,15 @@
 // See the Mulan PSL v2 for more details.
 
 use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock, Weak};
 
 use crate::address_space::FlatView;
-use crate::errors::{ErrorKind, Result};
-use crate::{AddressRange, AddressSpace, GuestAddress, HostMemMapping, RegionOps};
+use crate::errors::{ErrorKind, Result, ResultExt};
+use crate::{
+    page_size, AccessSize, AddressRange, AddressSpace, GuestAddress, GuestMemSlice, HostMemMapping,
+    IoAccessError, RegionOps, SizedRegionOps,
+};
 
 /// Types of Region.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -25,27 +28,76 @@ pub enum RegionType {
     Ram,
     /// IO type.
     IO,
+    /// ROM device type, read from backing memory and write to `ops`.
+    RomDevice,
+    /// Alias type, a window into another region.
+    Alias,
     /// Container type.
     Container,
 }
 
+/// Proof that all vcpus are parked outside `KVM_RUN`.
+///
+/// Removing guest RAM under running vcpus is unsafe, so topology changes
+/// which delete Ram-type regions require an implementor of this trait,
+/// usually a guard that resumes vcpus on drop.
+pub trait VcpusPaused {}
+
+/// Name of Region which isn't given one.
+const ANON_REGION_NAME: &str = "anon";
+
+/// Error of failed access of `size` bytes to offset `offset` of IO region
+/// `name` based at `base`.
+fn io_access_error(
+    e: IoAccessError,
+    name: &str,
+    base: GuestAddress,
+    offset: u64,
+    size: u64,
+    is_write: bool,
+) -> crate::errors::Error {
+    match e {
+        IoAccessError::Unsupported => {
+            ErrorKind::IoAccess(name.to_string(), base.raw_value(), offset, size, is_write).into()
+        }
+        IoAccessError::Retry => ErrorKind::IoRetry(name.to_string(), offset).into(),
+    }
+}
+
 /// Represents a memory region, used by mem-mapped IO or Ram.
 #[derive(Clone)]
 pub struct Region {
+    /// Name of Region, used in error messages and dumps.
+    name: Arc<str>,
     /// Type of Region, won't be changed once initialized.
     region_type: RegionType,
     /// The priority of Region, only valid in parent Container-type Region.
     priority: Arc<AtomicI32>,
     /// Size of Region.
     size: Arc<AtomicU64>,
+    /// If Ram-type Region is read-only, writing to it fails.
+    rom: Arc<AtomicBool>,
+    /// Disabled Region stays in its parent, but isn't rendered into flat view.
+    enabled: Arc<AtomicBool>,
+    /// Bitmap of pages written by `write`, one bit per page. It's `Some` iff
+    /// dirty page logging is enabled for Ram-type Region.
+    dirty_bitmap: Arc<Mutex<Option<Vec<u64>>>>,
     /// Offset in parent Container-type region.It won't be changed once initialized.
     offset: Arc<Mutex<GuestAddress>>,
     /// If not Ram-type Region, `mem_mapping` is None. It won't be changed once initialized.
     mem_mapping: Option<Arc<HostMemMapping>>,
     /// `ops` provides read/write function.
-    ops: Option<RegionOps>,
+    ops: Option<SizedRegionOps>,
+    /// Sizes of accesses to `ops`, or `None` if accesses are passed as they are.
+    access: Option<AccessSize>,
+    /// If not Alias-type Region, `alias` is None. It won't be changed once initialized.
+    alias: Option<Arc<Region>>,
+    /// Offset of the window in `alias` region.
+    alias_offset: u64,
     /// ioeventfds within this Region.
     io_evtfds: Arc<Mutex<Vec<RegionIoEventFd>>>,
+    /// Coalesced MMIO ranges of IO-type Region, as offsets within it.
+    coalesced: Arc<Mutex<Vec<AddressRange>>>,
     /// Weak pointer pointing to the father address-spaces.
     space: Arc<RwLock<Weak<AddressSpace>>>,
     /// Sub-regions array, keep sorted
@@ -104,7 +156,7 @@ impl RegionIoEventFd {
 }
 
 /// FlatRange is a piece of continuous memory address。
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone)]
 pub struct FlatRange {
     /// The address range.
     pub addr_range: AddressRange,
@@ -112,6 +164,50 @@ pub struct FlatRange {
     pub owner: Region,
     /// The offset within Region.
     pub offset_in_region: u64,
+    /// Whether dirty page logging of owner is enabled when this flat-range is
+    /// rendered, so that toggling it re-adds the flat-range to listeners.
+    pub log_dirty: bool,
+    /// Whether owner is read-only to guest when this flat-range is rendered,
+    /// i.e. rom or rom device, so that toggling it re-adds the flat-range too.
+    pub rom: bool,
+    /// Coalesced MMIO ranges of owner within this flat-range, as guest
+    /// addresses, see `Region::set_coalesced_io`.
+    pub coalesced: Vec<AddressRange>,
+}
+
+/// Implement PartialEq/Eq for FlatRange, which is the same iff it's the same
+/// piece of the same Region, so that unchanged ranges are kept in topology update.
+impl PartialEq for FlatRange {
+    fn eq(&self, other: &FlatRange) -> bool {
+        self.addr_range == other.addr_range
+            && self.offset_in_region == other.offset_in_region
+            && self.log_dirty == other.log_dirty
+            && self.rom == other.rom
+            && self.coalesced == other.coalesced
+            && self.owner.is_same(&other.owner)
+    }
+}
+
+impl Eq for FlatRange {}
+
+impl FlatRange {
+    /// Base address of owner region, when this flat-range is at its place.
+    /// It only identifies the region to its `ops`, so may wrap around.
+    pub(crate) fn region_base(&self) -> GuestAddress {
+        GuestAddress(
+            self.addr_range
+                .base
+                .raw_value()
+                .wrapping_sub(self.offset_in_region),
+        )
+    }
+
+    /// Whether this flat-range is backed by read-only memory, which should
+    /// be mapped read-only for guest, e.g. `KVM_MEM_READONLY`. Guest writes
+    /// to ROM device then exit to its `ops`.
+    pub fn read_only(&self) -> bool {
+        self.rom
+    }
 }
 
 /// Implement PartialEq/Eq for comparison of Region.
@@ -139,16 +235,24 @@ impl Region {
         size: u64,
         region_type: RegionType,
         mem_mapping: Option<Arc<HostMemMapping>>,
-        ops: Option<RegionOps>,
+        ops: Option<SizedRegionOps>,
     ) -> Region {
         Region {
+            name: Arc::from(ANON_REGION_NAME),
             region_type,
             priority: Arc::new(AtomicI32::new(0)),
             offset: Arc::new(Mutex::new(GuestAddress(0))),
             size: Arc::new(AtomicU64::new(size)),
+            rom: Arc::new(AtomicBool::new(false)),
+            enabled: Arc::new(AtomicBool::new(true)),
+            dirty_bitmap: Arc::new(Mutex::new(None)),
             mem_mapping,
             ops,
+            access: None,
+            alias: None,
+            alias_offset: 0,
             io_evtfds: Arc::new(Mutex::new(Vec::new())),
+            coalesced: Arc::new(Mutex::new(Vec::new())),
             space: Arc::new(RwLock::new(Weak::new())),
             subregions: Arc::new(RwLock::new(Vec::new())),
         }
@@ -163,6 +267,16 @@ impl Region {
         Region::init_region_internal(mem_mapping.size(), RegionType::Ram, Some(mem_mapping), None)
     }
 
+    /// Initialize Ram-type region with name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of this Ram region.
+    /// * `mem_mapping` - Mapped memory of this Ram region.
+    pub fn init_ram_region_named(name: &str, mem_mapping: Arc<HostMemMapping>) -> Region {
+        Region::init_ram_region(mem_mapping).with_name(name)
+    }
+
     /// Initialize IO-type region.
     ///
     /// # Arguments
@@ -170,7 +284,77 @@ impl Region {
     /// * `size` - Size of IO region.
     /// * `dev` - Operation of Region.
     pub fn init_io_region(size: u64, ops: RegionOps) -> Region {
-        Region::init_region_internal(size, RegionType::IO, None, Some(ops))
+        Region::init_region_internal(size, RegionType::IO, None, Some(ops.into()))
+    }
+
+    /// Initialize IO-type region with name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of this IO region.
+    /// * `size` - Size of IO region.
+    /// * `dev` - Operation of Region.
+    pub fn init_io_region_named(name: &str, size: u64, ops: RegionOps) -> Region {
+        Region::init_io_region(size, ops).with_name(name)
+    }
+
+    /// Initialize IO-type region, whose `ops` get naturally-aligned accesses
+    /// of sizes in `access`. Larger accesses are split.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Size of IO region.
+    /// * `ops` - Operation of Region.
+    /// * `access` - Sizes of accesses which `ops` accepts.
+    pub fn init_sized_io_region(size: u64, ops: SizedRegionOps, access: AccessSize) -> Region {
+        let mut region = Region::init_region_internal(size, RegionType::IO, None, Some(ops));
+        region.access = Some(access);
+        region
+    }
+
+    /// Initialize RomDevice-type region, e.g. PCI option ROM or pflash.
+    /// Reads are served from `mem_mapping`, writes are passed to `ops`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_mapping` - Mapped memory holding ROM contents.
+    /// * `ops` - Operation of Region, only `write` is used.
+    pub fn init_rom_device_region(mem_mapping: Arc<HostMemMapping>, ops: RegionOps) -> Region {
+        Region::init_region_internal(
+            mem_mapping.size(),
+            RegionType::RomDevice,
+            Some(mem_mapping),
+            Some(ops.into()),
+        )
+    }
+
+    /// Initialize Alias-type region, a window of `size` bytes at `alias_offset`
+    /// in `origin`. Accesses to the alias are forwarded to `origin`, and in
+    /// flat view the window is owned by `origin`.
+    ///
+    /// # Arguments
+    ///
+    /// * `origin` - Aliased region, which is Ram, IO or RomDevice type.
+    /// * `alias_offset` - Offset of the window in `origin`.
+    /// * `size` - Size of the window.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * `origin` is a container or an alias.
+    /// * The window overflows or exceeds `origin`.
+    pub fn init_alias_region(origin: Region, alias_offset: u64, size: u64) -> Result<Region> {
+        if origin.region_type() == RegionType::Container
+            || origin.region_type() == RegionType::Alias
+        {
+            return Err(ErrorKind::RegionType(origin.region_type()).into());
+        }
+        origin.check_valid_offset(alias_offset, size)?;
+
+        let mut region = Region::init_region_internal(size, RegionType::Alias, None, None);
+        region.alias = Some(Arc::new(origin));
+        region.alias_offset = alias_offset;
+        Ok(region)
     }
 
     /// Initialize Container-type region.
@@ -182,6 +366,80 @@ impl Region {
         Region::init_region_internal(size, RegionType::Container, None, None)
     }
 
+    /// Initialize Container-type region with name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of container region.
+    /// * `size` - Size of container region.
+    pub fn init_container_region_named(name: &str, size: u64) -> Region {
+        Region::init_container_region(size).with_name(name)
+    }
+
+    fn with_name(mut self, name: &str) -> Region {
+        self.name = Arc::from(name);
+        self
+    }
+
+    /// Get the name of this region, "anon" if not named.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the short type name of this region used in dumps.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self.region_type {
+            RegionType::Ram if self.is_rom() => "rom",
+            RegionType::Ram => "ram",
+            RegionType::IO => "i/o",
+            RegionType::Container => "container",
+            RegionType::RomDevice => "romd",
+            RegionType::Alias => "alias",
+        }
+    }
+
+    /// Render this region and its sub-regions to `out`, one line per region,
+    /// sub-regions are indented.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Base address of this region.
+    /// * `depth` - Depth of this region in the tree.
+    /// * `out` - The rendered text.
+    pub(crate) fn render_tree(&self, base: GuestAddress, depth: usize, out: &mut String) {
+        out.push_str(&format!(
+            "{:indent$}{:016x}-{:016x} (prio {}, {}): {}",
+            "",
+            base.raw_value(),
+            base.raw_value().wrapping_add(self.size()).wrapping_sub(1),
+            self.priority(),
+            self.type_name(),
+            self.name(),
+            indent = depth * 2
+        ));
+        if let Some(origin) = &self.alias {
+            out.push_str(&format!(" @{} {:016x}", origin.name(), self.alias_offset));
+        }
+        if !self.is_enabled() {
+            out.push_str(" [disabled]");
+        }
+        out.push('\n');
+
+        for sub_r in self.subregions.read().unwrap().iter() {
+            sub_r.render_tree(
+                base.unchecked_add(sub_r.offset().raw_value()),
+                depth + 1,
+                out,
+            );
+        }
+    }
+
+    /// Whether `other` is this region or a clone of it, rather than a region
+    /// equal in priority, type, offset and size.
+    pub(crate) fn is_same(&self, other: &Region) -> bool {
+        Arc::ptr_eq(&self.offset, &other.offset)
+    }
+
     /// Get the type of this region.
     pub fn region_type(&self) -> RegionType {
         self.region_type
@@ -192,13 +450,333 @@ impl Region {
         self.priority.load(Ordering::SeqCst)
     }
 
-    /// Set the priority of this region.
+    /// Set the priority of this region. If this region belongs to an
+    /// address-space, it's re-sorted in its parent and the topology is updated.
     ///
     /// # Arguments
     ///
     /// * `prior` - Priority of region.
-    pub fn set_priority(&self, prior: i32) {
-        self.priority.store(prior, Ordering::SeqCst);
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * This region would overlap with a sibling of the new priority.
+    /// * Failed to update topology of the belonged address-space, the priority
+    ///   is restored then.
+    pub fn set_priority(&self, prior: i32) -> Result<()> {
+        if self.priority() == prior {
+            return Ok(());
+        }
+        let space = self.space.read().unwrap().upgrade();
+        let parent = space.as_ref().and_then(|s| s.root().find_parent(self));
+        if let Some(parent) = &parent {
+            let range = AddressRange::new(self.offset(), self.size());
+            self.check_sibling_overlap(parent, range, prior)?;
+        }
+
+        let old = self.priority.swap(prior, Ordering::SeqCst);
+        if let Some(parent) = &parent {
+            parent.resort_subregion(self);
+        }
+        if let Some(space) = space {
+            if let Err(e) = space.update_topology() {
+                self.priority.store(old, Ordering::SeqCst);
+                if let Some(parent) = &parent {
+                    parent.resort_subregion(self);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the container region which has `child` as a direct sub-region,
+    /// in the tree rooted at this region.
+    fn find_parent(&self, child: &Region) -> Option<Region> {
+        let sub_regions = self.subregions.read().unwrap();
+        if sub_regions.iter().any(|sub_r| sub_r.is_same(child)) {
+            return Some(self.clone());
+        }
+        sub_regions
+            .iter()
+            .find_map(|sub_r| sub_r.find_parent(child))
+    }
+
+    /// Move `child` to the position of its current priority in sub-regions.
+    fn resort_subregion(&self, child: &Region) {
+        let mut sub_regions = self.subregions.write().unwrap();
+        if let Some(index) = sub_regions.iter().position(|sub_r| sub_r.is_same(child)) {
+            let child = sub_regions.remove(index);
+            Self::insert_subregion(&mut sub_regions, child);
+        }
+    }
+
+    /// Insert `child` before sub-regions of lower or equal priority, so
+    /// sub-regions are in descending order by priority.
+    fn insert_subregion(sub_regions: &mut Vec<Region>, child: Region) {
+        let index = sub_regions
+            .iter()
+            .position(|sub_r| child.priority() >= sub_r.priority())
+            .unwrap_or(sub_regions.len());
+        sub_regions.insert(index, child);
+    }
+
+    /// Whether this region is read-only, only valid for Ram-type region.
+    pub fn is_rom(&self) -> bool {
+        self.rom.load(Ordering::SeqCst)
+    }
+
+    /// Mark Ram-type region read-only or writable, e.g. for firmware
+    /// or ROM contents. Reading and host address are not affected.
+    ///
+    /// # Arguments
+    ///
+    /// * `rom` - If this region is read-only.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to update topology of the address space.
+    pub fn set_rom(&self, rom: bool) -> Result<()> {
+        if self.rom.swap(rom, Ordering::SeqCst) == rom {
+            return Ok(());
+        }
+        if let Some(space) = self.space.read().unwrap().upgrade() {
+            space.update_topology()?;
+        }
+        Ok(())
+    }
+
+    /// Whether memory of this region is read-only for guest, i.e. it's a
+    /// rom Ram region or a rom device region.
+    pub(crate) fn is_read_only(&self) -> bool {
+        match self.region_type {
+            RegionType::Ram => self.is_rom(),
+            RegionType::RomDevice => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this region is enabled, i.e. rendered into flat view.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Enable or disable this region without removing it from its parent,
+    /// e.g. for PCI BARs whose decoding is switched by guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - If this region is rendered into flat view.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if failed to update topology of the belonged address-space.
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        if self.enabled.swap(enabled, Ordering::SeqCst) == enabled {
+            return Ok(());
+        }
+        if let Some(space) = self.space.read().unwrap().upgrade() {
+            if let Err(e) = space.update_topology() {
+                self.enabled.store(!enabled, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether dirty page logging is enabled for this region.
+    pub fn is_log_dirty(&self) -> bool {
+        self.dirty_bitmap.lock().unwrap().is_some()
+    }
+
+    /// Enable or disable dirty page logging of Ram-type region. Pages written
+    /// by guest are logged by listeners, and pages written by `write` are
+    /// logged in this region. Logged pages are fetched by
+    /// `AddressSpace::get_dirty_log`.
+    ///
+    /// # Arguments
+    ///
+    /// * `log` - If dirty pages are logged.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * This region is not Ram-type.
+    /// * Failed to update topology of the belonged address-space.
+    pub fn set_log_dirty(&self, log: bool) -> Result<()> {
+        if self.region_type != RegionType::Ram {
+            return Err(ErrorKind::RegionType(self.region_type).into());
+        }
+        {
+            let mut bitmap = self.dirty_bitmap.lock().unwrap();
+            if bitmap.is_some() == log {
+                return Ok(());
+            }
+            *bitmap = if log {
+                let pages = (self.size() + page_size() - 1) / page_size();
+                Some(vec![0_u64; ((pages + 63) / 64) as usize])
+            } else {
+                None
+            };
+        }
+        if let Some(space) = self.space.read().unwrap().upgrade() {
+            space.update_topology()?;
+        }
+        Ok(())
+    }
+
+    /// Coalesced MMIO ranges of this region, as offsets within it.
+    pub fn coalesced_io(&self) -> Vec<AddressRange> {
+        self.coalesced.lock().unwrap().clone()
+    }
+
+    /// Mark ranges of IO-type region as coalesced MMIO, i.e. guest writes to
+    /// them need no immediate response, e.g. frame buffers. Such writes are
+    /// batched by listeners, and flushed to `ops` in order by
+    /// `AddressSpace::drain_coalesced`. Reads still exit to `ops` at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges` - Ranges as offsets within this region, empty to clear.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * This region is not IO-type.
+    /// * Some range exceeds this region.
+    /// * Failed to update topology of the belonged address-space, the ranges
+    ///   are restored then.
+    pub fn set_coalesced_io(&self, ranges: Vec<AddressRange>) -> Result<()> {
+        if self.region_type != RegionType::IO {
+            return Err(ErrorKind::RegionType(self.region_type).into());
+        }
+        for range in ranges.iter() {
+            self.check_valid_offset(range.base.raw_value(), range.size)?;
+        }
+
+        let old = std::mem::replace(&mut *self.coalesced.lock().unwrap(), ranges);
+        if let Some(space) = self.space.read().unwrap().upgrade() {
+            if let Err(e) = space.update_topology() {
+                *self.coalesced.lock().unwrap() = old;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Coalesced MMIO ranges within `size` bytes at `offset` of this region,
+    /// as guest addresses if the piece is placed at `base`.
+    fn coalesced_at(&self, base: GuestAddress, offset: u64, size: u64) -> Vec<AddressRange> {
+        let piece = AddressRange::from((offset, size));
+        self.coalesced
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|range| range.intersection(piece))
+            .map(|r| AddressRange::new(base.unchecked_add(r.base.raw_value() - offset), r.size))
+            .collect()
+    }
+
+    /// Resize Ram-type region to `new_size` keeping its contents, e.g. for
+    /// virtio-mem style memory resizing. Its memory mapping is resized in
+    /// place, and the topology of the belonged address-space is updated, so
+    /// listeners adjust mapping of it.
+    ///
+    /// Vcpus and devices must not access the released part when shrinking,
+    /// and the memory mapping can only grow within its reserved address space,
+    /// see `HostMemMapping::reserve`.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_size` - New size, aligned to page size of the memory mapping.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * This region is not Ram-type.
+    /// * The region would exceed its parent region, or overlap with a sibling
+    ///   of the same priority.
+    /// * Fail to resize the memory mapping, the region is unchanged then.
+    /// * Failed to update topology of the belonged address-space, the memory
+    ///   mapping and size are restored then.
+    pub fn resize(&self, new_size: u64) -> Result<()> {
+        if self.region_type != RegionType::Ram {
+            return Err(ErrorKind::RegionType(self.region_type).into());
+        }
+        let old_size = self.size();
+        if new_size == old_size {
+            return Ok(());
+        }
+        let space = self.space.read().unwrap().upgrade();
+        if let Some(parent) = space.as_ref().and_then(|s| s.root().find_parent(self)) {
+            parent.check_valid_offset(self.offset().raw_value(), new_size)?;
+            let range = AddressRange::new(self.offset(), new_size);
+            self.check_sibling_overlap(&parent, range, self.priority())?;
+        }
+
+        // Ram region always has memory mapping.
+        let mapping = self.mem_mapping.as_ref().unwrap();
+        mapping
+            .remap(new_size)
+            .chain_err(|| format!("Failed to resize region {} to {}", self.name(), new_size))?;
+        self.set_size_internal(new_size);
+
+        if let Some(space) = space {
+            if let Err(e) = space.update_topology() {
+                // Roll back, the flat view is unchanged if it's rejected.
+                if let Err(re) = mapping.remap(old_size) {
+                    error!(
+                        "Failed to restore size of region {} to {}: {}",
+                        self.name(),
+                        old_size,
+                        re
+                    );
+                }
+                self.set_size_internal(old_size);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set size of this region, and resize the dirty bitmap if logging.
+    fn set_size_internal(&self, size: u64) {
+        self.size.store(size, Ordering::SeqCst);
+        if let Some(bitmap) = self.dirty_bitmap.lock().unwrap().as_mut() {
+            let pages = (size + page_size() - 1) / page_size();
+            bitmap.resize(((pages + 63) / 64) as usize, 0);
+        }
+    }
+
+    /// Log pages in [`offset`, `offset` + `count`) as dirty, if logging.
+    pub(crate) fn mark_dirty(&self, offset: u64, count: u64) {
+        if count == 0 {
+            return;
+        }
+        if let Some(bitmap) = self.dirty_bitmap.lock().unwrap().as_mut() {
+            for page in offset / page_size()..=(offset + count - 1) / page_size() {
+                bitmap[(page / 64) as usize] |= 1_u64 << (page % 64);
+            }
+        }
+    }
+
+    /// Return offsets of dirty pages in [`offset`, `offset` + `size`) of this
+    /// region, and reset them.
+    pub(crate) fn take_dirty_pages(&self, offset: u64, size: u64) -> Vec<u64> {
+        let mut pages = Vec::new();
+        if size == 0 {
+            return pages;
+        }
+        if let Some(bitmap) = self.dirty_bitmap.lock().unwrap().as_mut() {
+            for page in offset / page_size()..=(offset + size - 1) / page_size() {
+                let (idx, bit) = ((page / 64) as usize, 1_u64 << (page % 64));
+                if bitmap[idx] & bit != 0 {
+                    bitmap[idx] &= !bit;
+                    pages.push(page * page_size());
+                }
+            }
+        }
+        pages
     }
 
     /// Get size of this region.
@@ -223,14 +801,37 @@ impl Region {
     }
 
     /// Get the host address if this region is backed by host-memory,
-    /// Return `None` if it is not a Ram-type region.
+    /// Return `None` if it is not a Ram-type or RomDevice-type region, or an
+    /// alias of them.
     pub fn get_host_address(&self) -> Option<u64> {
-        if self.region_type != RegionType::Ram {
+        if let Some(origin) = &self.alias {
+            return origin
+                .get_host_address()
+                .map(|host| host + self.alias_offset);
+        }
+        if self.region_type != RegionType::Ram && self.region_type != RegionType::RomDevice {
             return None;
         }
         self.mem_mapping.as_ref().map(|r| r.host_address())
     }
 
+    /// Free memory of Ram region in [`offset`, `offset` + `size`) back to host,
+    /// return the number of bytes discarded.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if this is not a Ram region or fail to discard memory.
+    pub(crate) fn discard(&self, offset: u64, size: u64) -> Result<u64> {
+        if let Some(origin) = &self.alias {
+            return origin.discard(self.alias_offset + offset, size);
+        }
+        if self.region_type != RegionType::Ram {
+            return Err(ErrorKind::RegionType(self.region_type).into());
+        }
+        // Ram region always has memory mapping.
+        self.mem_mapping.as_ref().unwrap().discard(offset, size)
+    }
+
     /// Get the file information if this region is backed by host-memory,
     /// Return `None` if it is not a Ram-type region.
     pub fn get_file_backend(&self) -> Option<(RawFd, u64)> {
@@ -243,6 +844,22 @@ impl Region {
         self.subregions.read().unwrap().clone()
     }
 
+    /// Count enabled non-container regions in the tree rooted at this region.
+    pub(crate) fn leaf_count(&self) -> usize {
+        if !self.is_enabled() {
+            return 0;
+        }
+        if self.region_type != RegionType::Container {
+            return 1;
+        }
+        self.subregions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|sub_r| sub_r.leaf_count())
+            .sum()
+    }
+
     /// Set `AddressSpace` for `region`,
     /// this function is called when this region is added to parent region or
     /// added to belonged address space.
@@ -272,16 +889,76 @@ impl Region {
     ///
     /// Return Error if the address overflows.
     fn check_valid_offset(&self, addr: u64, size: u64) -> Result<()> {
-        if addr
-            .checked_add(size)
-            .filter(|end| *end <= self.size())
-            .is_none()
-        {
+        if !AddressRange::from((0, self.size())).contains(GuestAddress(addr), size) {
             return Err(ErrorKind::Overflow(addr).into());
         }
         Ok(())
     }
 
+    /// Address range of this region if it's based at `base`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the end of the range overflows.
+    fn range_at(&self, base: GuestAddress) -> Result<AddressRange> {
+        let range = base
+            .checked_add(self.offset().raw_value())
+            .map(|region_base| AddressRange::new(region_base, self.size()))
+            .filter(|range| range.end_addr_checked().is_some());
+        match range {
+            Some(range) => Ok(range),
+            None => Err(ErrorKind::Overflow(base.raw_value()).into()),
+        }
+    }
+
+    /// Base address of aliased region, if this alias is based at `base`.
+    /// It only identifies the origin to its `ops`, so may wrap around.
+    fn origin_base(&self, base: GuestAddress) -> GuestAddress {
+        GuestAddress(base.raw_value().wrapping_sub(self.alias_offset))
+    }
+
+    /// Split IO access of `count` bytes at `offset` into naturally-aligned
+    /// accesses of sizes in `access`.
+    /// Return pieces of (start in the access, size).
+    ///
+    /// # Errors
+    ///
+    /// Return Error if some piece is smaller than the minimum access size.
+    fn split_io_access(
+        &self,
+        base: GuestAddress,
+        offset: u64,
+        count: u64,
+        is_write: bool,
+    ) -> Result<Vec<(usize, usize)>> {
+        let access = match self.access {
+            Some(access) => access,
+            None => return Ok(vec![(0, count as usize)]),
+        };
+        let mut pieces = Vec::new();
+        let mut start = 0_u64;
+        while start < count {
+            let addr = offset + start;
+            let mut size = access.max;
+            while size > 1 && (size > count - start || addr % size != 0) {
+                size >>= 1;
+            }
+            if size < access.min {
+                return Err(io_access_error(
+                    IoAccessError::Unsupported,
+                    self.name(),
+                    base,
+                    addr,
+                    size,
+                    is_write,
+                ));
+            }
+            pieces.push((start as usize, size as usize));
+            start += size;
+        }
+        Ok(pieces)
+    }
+
     /// Read memory segment to `dst`.
     ///
     /// # Arguments
@@ -307,12 +984,18 @@ impl Region {
         self.check_valid_offset(offset, count)?;
 
         match self.region_type {
-            RegionType::Ram => {
-                let host_addr = self.mem_mapping.as_ref().unwrap().host_address();
-                let slice = unsafe {
-                    std::slice::from_raw_parts((host_addr + offset) as *const u8, count as usize)
-                };
-                dst.write_all(slice)?;
+            RegionType::Alias => {
+                let origin = self.alias.as_ref().unwrap();
+                origin.read(
+                    dst,
+                    self.origin_base(base),
+                    self.alias_offset + offset,
+                    count,
+                )?;
+            }
+            RegionType::Ram | RegionType::RomDevice => {
+                let mapping = self.mem_mapping.as_ref().unwrap();
+                GuestMemSlice::new(mapping, offset, count)?.read_to(dst)?;
             }
             RegionType::IO => {
                 if count >= std::usize::MAX as u64 {
@@ -320,8 +1003,11 @@ impl Region {
                 }
                 let mut slice = vec![0_u8; count as usize];
                 let read_ops = self.ops.as_ref().unwrap().read.as_ref();
-                if !read_ops(&mut slice, base, offset) {
-                    return Err(ErrorKind::IoAccess(offset).into());
+                for (start, size) in self.split_io_access(base, offset, count, false)? {
+                    let piece_offset = offset + start as u64;
+                    read_ops(&mut slice[start..start + size], base, piece_offset).map_err(|e| {
+                        io_access_error(e, self.name(), base, piece_offset, size as u64, false)
+                    })?;
                 }
                 dst.write_all(&slice)?;
             }
@@ -347,6 +1033,7 @@ impl Region {
     /// * fail to access io region.
     /// * the region is a container.
     /// * the address overflows.
+    /// * the region is read-only Ram.
     pub fn write(
         &self,
         src: &mut dyn std::io::Read,
@@ -357,14 +1044,28 @@ impl Region {
         self.check_valid_offset(offset, count)?;
 
         match self.region_type {
+            RegionType::Alias => {
+                let origin = self.alias.as_ref().unwrap();
+                origin.write(
+                    src,
+                    self.origin_base(base),
+                    self.alias_offset + offset,
+                    count,
+                )?;
+            }
             RegionType::Ram => {
-                let host_addr = self.mem_mapping.as_ref().unwrap().host_address();
-                let slice = unsafe {
-                    std::slice::from_raw_parts_mut((host_addr + offset) as *mut u8, count as usize)
-                };
-                src.read_exact(slice)?;
+                if self.is_rom() {
+                    return Err(ErrorKind::ReadOnly(
+                        self.name().to_string(),
+                        base.raw_value() + offset,
+                    )
+                    .into());
+                }
+                let mapping = self.mem_mapping.as_ref().unwrap();
+                GuestMemSlice::new(mapping, offset, count)?.write_from(src)?;
+                self.mark_dirty(offset, count);
             }
-            RegionType::IO => {
+            RegionType::IO | RegionType::RomDevice => {
                 if count >= std::usize::MAX as u64 {
                     return Err(ErrorKind::Overflow(count).into());
                 }
@@ -372,8 +1073,11 @@ impl Region {
                 src.read_exact(&mut slice)?;
 
                 let write_ops = self.ops.as_ref().unwrap().write.as_ref();
-                if !write_ops(&slice, base, offset) {
-                    return Err(ErrorKind::IoAccess(offset).into());
+                for (start, size) in self.split_io_access(base, offset, count, true)? {
+                    let piece_offset = offset + start as u64;
+                    write_ops(&slice[start..start + size], base, piece_offset).map_err(|e| {
+                        io_access_error(e, self.name(), base, piece_offset, size as u64, true)
+                    })?;
                 }
             }
             _ => {
@@ -412,34 +1116,76 @@ impl Region {
     /// * This region is not a Container.
     /// * The argument `offset` plus child region's size overflows or exceed this region's size.
     /// * The child-region already exists in sub-regions array.
-    /// * Failed to generate flat view (topology changed after adding sub-region).
+    /// * The child-region overlaps with a sub-region of the same priority.
+    /// * Failed to generate flat view (topology changed after adding sub-region),
+    ///   or listeners reject it, e.g. KVM memory slots are used up. The child
+    ///   region is removed again then.
     pub fn add_subregion(&self, child: Region, offset: u64) -> Result<()> {
+        self.add_subregion_internal(child, offset, true)
+    }
+
+    /// Add sub-region to this region, which may overlap with sub-regions of
+    /// the same priority, then the one added later takes precedence.
+    ///
+    /// # Arguments
+    ///
+    /// * `child` - Subregion of this region.
+    /// * `offset` - Offset of subregion.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * This region is not a Container.
+    /// * The argument `offset` plus child region's size overflows or exceed this region's size.
+    /// * Failed to generate flat view (topology changed after adding sub-region),
+    ///   or listeners reject it, e.g. KVM memory slots are used up. The child
+    ///   region is removed again then.
+    pub fn add_subregion_not_checked(&self, child: Region, offset: u64) -> Result<()> {
+        self.add_subregion_internal(child, offset, false)
+    }
+
+    fn add_subregion_internal(
+        &self,
+        child: Region,
+        offset: u64,
+        check_overlap: bool,
+    ) -> Result<()> {
         // check parent Region's property, and check if child Region's offset is valid or not
         if self.region_type() != RegionType::Container {
             return Err(ErrorKind::RegionType(self.region_type()).into());
         }
         self.check_valid_offset(offset, child.size())?;
 
+        let mut sub_regions = self.subregions.write().unwrap();
+        if check_overlap {
+            let new_range = AddressRange::new(GuestAddress(offset), child.size());
+            Self::check_priority_overlap(
+                sub_regions.iter(),
+                child.name(),
+                new_range,
+                child.priority(),
+            )?;
+        }
+
         // set child region's offset and father address-space
         child.set_offset(GuestAddress(offset));
         if let Some(space) = self.space.read().unwrap().upgrade() {
             child.set_belonged_address_space(&space)
         }
 
-        // insert to `subregion` array and update topology of father address-space
-        let mut sub_regions = self.subregions.write().unwrap();
-        let mut index = 0_usize;
-        while index < sub_regions.len() {
-            if child.priority() >= sub_regions.get(index).unwrap().priority() {
-                break;
-            }
-            index += 1;
-        }
-        sub_regions.insert(index, child);
+        // insert to `subregion` array and update topology of father address-space
+        Self::insert_subregion(&mut sub_regions, child.clone());
         drop(sub_regions);
 
         if let Some(space) = self.space.read().unwrap().upgrade() {
-            space.update_topology()?;
+            if let Err(e) = space.update_topology() {
+                // Roll back, the flat view is unchanged if it's rejected.
+                self.subregions
+                    .write()
+                    .unwrap()
+                    .retain(|sub_r| !sub_r.is_same(&child));
+                return Err(e);
+            }
         } else {
             debug!("add subregion to container region, which has no belonged address-space");
         }
@@ -447,7 +1193,45 @@ impl Region {
         Ok(())
     }
 
-    /// Delete sub-region of this region.
+    /// Check that `range` with `priority` doesn't overlap with any of `siblings`
+    /// of the same priority, overlap with other priorities is resolved by priority.
+    fn check_priority_overlap<'a>(
+        siblings: impl Iterator<Item = &'a Region>,
+        name: &str,
+        range: AddressRange,
+        priority: i32,
+    ) -> Result<()> {
+        for sub_r in siblings {
+            let existing = AddressRange::new(sub_r.offset(), sub_r.size());
+            if sub_r.priority() == priority && range.intersection(existing).is_some() {
+                return Err(ErrorKind::RegionOverlap(
+                    name.to_string(),
+                    range,
+                    sub_r.name().to_string(),
+                    existing,
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that this region overlaps with no sibling of the same priority
+    /// in `parent`, if it's at `range` with `priority`.
+    fn check_sibling_overlap(
+        &self,
+        parent: &Region,
+        range: AddressRange,
+        priority: i32,
+    ) -> Result<()> {
+        let sub_regions = parent.subregions.read().unwrap();
+        let siblings = sub_regions.iter().filter(|sub_r| !sub_r.is_same(self));
+        Self::check_priority_overlap(siblings, self.name(), range, priority)
+    }
+
+    /// Delete sub-region of this region, return the deleted one. Sub-regions
+    /// are matched by identity, so `child` must be the added region or a clone
+    /// of it, rather than a region equal to it.
     ///
     /// # Arguments
     ///
@@ -458,21 +1242,47 @@ impl Region {
     /// Return Error if
     /// * The child-region does not exist in sub-regions array.
     /// * Failed to generate flat view (topology changed after removing sub-region).
-    pub fn delete_subregion(&self, child: &Region) -> Result<()> {
-        let mut sub_regions = self.subregions.write().unwrap();
-        let mut removed = false;
-        for (index, sub_r) in sub_regions.iter().enumerate() {
-            if child == sub_r {
-                sub_regions.remove(index);
-                removed = true;
-                break;
-            }
+    /// * The child-region is Ram-type or alias of Ram, and this region belongs to
+    ///   an address-space, `delete_ram_subregion` should be used instead.
+    pub fn delete_subregion(&self, child: &Region) -> Result<Region> {
+        let ram = match &child.alias {
+            Some(origin) => origin.region_type() == RegionType::Ram,
+            None => child.region_type() == RegionType::Ram,
+        };
+        if ram && self.space.read().unwrap().upgrade().is_some() {
+            bail!("Delete Ram subregion failed: vcpus must be paused");
         }
-        drop(sub_regions);
+        self.delete_subregion_internal(child)
+    }
 
-        if !removed {
-            bail!("Delete subregion failed: no matched region");
-        }
+    /// Delete Ram-type sub-region of this region, which may be accessed by vcpus,
+    /// return the deleted one.
+    ///
+    /// # Arguments
+    ///
+    /// * `child` - Subregion of this region.
+    /// * `_paused` - Proof that all vcpus are parked outside `KVM_RUN`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if
+    /// * The child-region does not exist in sub-regions array.
+    /// * Failed to generate flat view (topology changed after removing sub-region).
+    pub fn delete_ram_subregion(
+        &self,
+        child: &Region,
+        _paused: &dyn VcpusPaused,
+    ) -> Result<Region> {
+        self.delete_subregion_internal(child)
+    }
+
+    fn delete_subregion_internal(&self, child: &Region) -> Result<Region> {
+        let mut sub_regions = self.subregions.write().unwrap();
+        let removed = match sub_regions.iter().position(|sub_r| sub_r.is_same(child)) {
+            Some(index) => sub_regions.remove(index),
+            None => bail!("Delete subregion failed: no matched region"),
+        };
+        drop(sub_regions);
 
         // get father address-space and update topology
         if let Some(space) = self.space.read().unwrap().upgrade() {
@@ -480,9 +1290,9 @@ impl Region {
         } else {
             debug!("add subregion to container region, which has no belonged address-space");
         }
-        child.del_belonged_address_space();
+        removed.del_belonged_address_space();
 
-        Ok(())
+        Ok(removed)
     }
 
     /// Recursive function to render region, terminate if this region is not a container.
@@ -502,11 +1312,14 @@ impl Region {
         addr_range: AddressRange,
         flat_view: &mut FlatView,
     ) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
         match self.region_type {
             RegionType::Container => {
-                let region_base = base.unchecked_add(self.offset().raw_value());
-                let region_range = AddressRange::new(region_base, self.size());
-                let intersect = match region_range.find_intersection(addr_range) {
+                let region_range = self.range_at(base)?;
+                let region_base = region_range.base;
+                let intersect = match region_range.intersection(addr_range) {
                     Some(r) => r,
                     None => bail!(
                         "Generate flat view failed: region_addr {} exceeds",
@@ -518,7 +1331,7 @@ impl Region {
                     sub_r.render_region_pass(region_base, intersect, flat_view)?;
                 }
             }
-            RegionType::Ram | RegionType::IO => {
+            RegionType::Ram | RegionType::IO | RegionType::RomDevice | RegionType::Alias => {
                 self.render_terminate_region(base, addr_range, flat_view)?;
             }
         }
@@ -542,9 +1355,11 @@ impl Region {
         addr_range: AddressRange,
         flat_view: &mut FlatView,
     ) -> Result<()> {
-        let region_range =
-            AddressRange::new(base.unchecked_add(self.offset().raw_value()), self.size());
-        let intersect = match region_range.find_intersection(addr_range) {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let region_range = self.range_at(base)?;
+        let intersect = match region_range.intersection(addr_range) {
             Some(r) => r,
             None => bail!(
                 "Gen flatview failed: region_addr {} exceeds",
@@ -552,7 +1367,12 @@ impl Region {
             ),
         };
 
-        let mut offset_in_region = intersect.base.offset_from(region_range.base);
+        // Window of alias is owned by the aliased region.
+        let (owner, offset_base) = match &self.alias {
+            Some(origin) => (origin.as_ref(), self.alias_offset),
+            None => (self, 0),
+        };
+        let mut offset_in_region = offset_base + intersect.base.offset_from(region_range.base);
         let mut start = intersect.base;
         let mut remain = intersect.size;
 
@@ -575,8 +1395,11 @@ impl Region {
                             base: start,
                             size: range_size,
                         },
-                        owner: self.clone(),
+                        owner: owner.clone(),
                         offset_in_region,
+                        log_dirty: owner.is_log_dirty(),
+                        rom: owner.is_read_only(),
+                        coalesced: owner.coalesced_at(start, offset_in_region, range_size),
                     },
                 );
                 index += 1;
@@ -596,8 +1419,11 @@ impl Region {
                 index,
                 FlatRange {
                     addr_range: AddressRange::new(start, remain),
-                    owner: self.clone(),
+                    owner: owner.clone(),
                     offset_in_region,
+                    log_dirty: owner.is_log_dirty(),
+                    rom: owner.is_read_only(),
+                    coalesced: owner.coalesced_at(start, offset_in_region, remain),
                 },
             );
         }
@@ -620,10 +1446,11 @@ impl Region {
         let mut flat_view = FlatView::default();
         match self.region_type {
             RegionType::Container => self.render_region_pass(base, addr_range, &mut flat_view)?,
-            RegionType::Ram | RegionType::IO => {
+            RegionType::Ram | RegionType::IO | RegionType::RomDevice | RegionType::Alias => {
                 self.render_terminate_region(base, addr_range, &mut flat_view)?
             }
         }
+        flat_view.canonicalize();
         Ok(flat_view)
     }
 }
@@ -631,11 +1458,13 @@ impl Region {
 #[cfg(test)]
 mod test {
     use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
 
     use libc::EFD_NONBLOCK;
     use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
+    use crate::errors::Error;
 
     #[derive(Default)]
     struct TestDevice {
@@ -699,6 +1528,38 @@ mod test {
         assert!(ram_region.check_valid_offset(100, 1000).is_err());
     }
 
+    #[test]
+    fn test_rom_region() {
+        let mem_mapping =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1024u64, -1, 0, false, false).unwrap());
+        let rom_region = Region::init_ram_region(mem_mapping.clone());
+        let data: [u8; 10] = [10; 10];
+        let mut res_data: [u8; 10] = [0; 10];
+        rom_region
+            .write(&mut data.as_ref(), GuestAddress(0x1000), 0, 10)
+            .unwrap();
+
+        rom_region.set_rom(true).unwrap();
+        assert!(rom_region.is_rom());
+        match rom_region.write(&mut [0_u8; 10].as_ref(), GuestAddress(0x1000), 0x10, 10) {
+            Err(Error(ErrorKind::ReadOnly(_, addr), _)) => assert_eq!(addr, 0x1010),
+            _ => panic!("Writing to read-only region should fail"),
+        }
+        rom_region
+            .read(&mut res_data.as_mut(), GuestAddress(0x1000), 0, 10)
+            .unwrap();
+        assert_eq!(&data, &res_data);
+        assert_eq!(
+            rom_region.get_host_address().unwrap(),
+            mem_mapping.host_address()
+        );
+
+        rom_region.set_rom(false).unwrap();
+        assert!(rom_region
+            .write(&mut data.as_ref(), GuestAddress(0x1000), 0x10, 10)
+            .is_ok());
+    }
+
     #[test]
     fn test_ram_region_access() {
         // the target guest address is 0~1024 (1024 not included)
@@ -771,6 +1632,160 @@ mod test {
         assert!(io_region.get_host_address().is_none());
     }
 
+    #[test]
+    fn test_io_access_error() {
+        let test_dev = Arc::new(Mutex::new(TestDevice::default()));
+        let test_dev_clone = test_dev.clone();
+        let read_ops = move |data: &mut [u8], addr: GuestAddress, offset: u64| -> bool {
+            test_dev_clone.lock().unwrap().read(data, addr, offset)
+        };
+        let write_ops = move |data: &[u8], addr: GuestAddress, offset: u64| -> bool {
+            test_dev.lock().unwrap().write(data, addr, offset)
+        };
+        let io_region = Region::init_io_region_named(
+            "test-dev",
+            16,
+            RegionOps {
+                read: Arc::new(read_ops),
+                write: Arc::new(write_ops),
+            },
+        );
+
+        // TestDevice only accepts 8-byte accesses.
+        let err = io_region
+            .write(&mut [0_u8; 4].as_ref(), GuestAddress(0x1000), 4, 4)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to write 4 bytes of io region test-dev at base 0x1000, offset 0x4"
+        );
+        match err {
+            Error(ErrorKind::IoAccess(name, base, offset, size, is_write), _) => {
+                assert_eq!(name, "test-dev");
+                assert_eq!((base, offset, size, is_write), (0x1000, 4, 4, true));
+            }
+            _ => panic!("expect IoAccess error"),
+        }
+
+        let root = Region::init_container_region(0x4000);
+        let space = crate::AddressSpace::new(root.clone()).unwrap();
+        root.add_subregion(io_region, 0x2000).unwrap();
+        match space.read(&mut [0_u8; 2].as_mut(), GuestAddress(0x2008), 2) {
+            Err(Error(ErrorKind::IoAccess(_, base, offset, size, is_write), _)) => {
+                assert_eq!((base, offset, size, is_write), (0x2000, 8, 2, false));
+            }
+            _ => panic!("expect IoAccess error"),
+        }
+    }
+
+    #[test]
+    fn test_rom_device_region() {
+        let mem_mapping =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1024u64, -1, 0, false, false).unwrap());
+        let rom = [0x55_u8; 8];
+        unsafe {
+            std::slice::from_raw_parts_mut(mem_mapping.host_address() as *mut u8, 8)
+                .copy_from_slice(&rom);
+        }
+
+        let test_dev = Arc::new(Mutex::new(TestDevice::default()));
+        let test_dev_clone = test_dev.clone();
+        let write_ops = move |data: &[u8], addr: GuestAddress, offset: u64| -> bool {
+            let mut device_locked = test_dev_clone.lock().unwrap();
+            device_locked.write(data, addr, offset)
+        };
+        let test_dev_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { false }),
+            write: Arc::new(write_ops),
+        };
+        let rom_region = Region::init_rom_device_region(mem_mapping.clone(), test_dev_ops);
+        assert_eq!(rom_region.region_type(), RegionType::RomDevice);
+        assert_eq!(rom_region.size(), 1024);
+        assert_eq!(
+            rom_region.get_host_address().unwrap(),
+            mem_mapping.host_address()
+        );
+
+        // Write goes to device, read still returns ROM contents.
+        let data = 0x1234_5678_u64.to_le_bytes();
+        rom_region
+            .write(&mut data.as_ref(), GuestAddress(0), 0, 8)
+            .unwrap();
+        assert_eq!(test_dev.lock().unwrap().head, 0x1234_5678);
+        let mut data_res = [0_u8; 8];
+        rom_region
+            .read(&mut data_res.as_mut(), GuestAddress(0), 0, 8)
+            .unwrap();
+        assert_eq!(data_res, rom);
+        // Device rejects the write.
+        assert!(rom_region
+            .write(&mut [0_u8; 4].as_ref(), GuestAddress(0), 0, 4)
+            .is_err());
+
+        let flat_view = rom_region
+            .generate_flatview(GuestAddress(0), AddressRange::from((0, 1024)))
+            .unwrap();
+        assert_eq!(flat_view.0.len(), 1);
+        assert_eq!(flat_view.0[0].owner.region_type(), RegionType::RomDevice);
+        assert!(flat_view.0[0].read_only());
+    }
+
+    #[test]
+    fn test_sized_io_region() {
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let accesses_clone = accesses.clone();
+        let read_ops = move |data: &mut [u8],
+                             _: GuestAddress,
+                             offset: u64|
+              -> std::result::Result<(), IoAccessError> {
+            accesses_clone.lock().unwrap().push((offset, data.len()));
+            for byte in data.iter_mut() {
+                *byte = offset as u8;
+            }
+            Ok(())
+        };
+        let write_ops =
+            |data: &[u8], _: GuestAddress, _: u64| -> std::result::Result<(), IoAccessError> {
+                if data[0] == 0xff {
+                    Err(IoAccessError::Retry)
+                } else {
+                    Ok(())
+                }
+            };
+        let ops = SizedRegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+        let io_region = Region::init_sized_io_region(16, ops, AccessSize { min: 4, max: 4 });
+        assert_eq!(io_region.region_type(), RegionType::IO);
+
+        // 8-byte read is split into two 4-byte reads.
+        let mut data = [0_u8; 8];
+        io_region
+            .read(&mut data.as_mut(), GuestAddress(0), 8, 8)
+            .unwrap();
+        assert_eq!(*accesses.lock().unwrap(), vec![(8, 4), (12, 4)]);
+        assert_eq!(data, [8, 8, 8, 8, 12, 12, 12, 12]);
+
+        // Accesses smaller than the minimum size are rejected.
+        let mut data = [0_u8; 2];
+        assert!(io_region
+            .read(&mut data.as_mut(), GuestAddress(0), 0, 2)
+            .is_err());
+        assert!(io_region
+            .read(&mut [0_u8; 4].as_mut(), GuestAddress(0), 2, 4)
+            .is_err());
+
+        // Busy device asks for a retry.
+        match io_region.write(&mut [0xff_u8; 4].as_ref(), GuestAddress(0), 4, 4) {
+            Err(Error(ErrorKind::IoRetry(_, 4), _)) => {}
+            _ => panic!("expect IoRetry error"),
+        }
+        io_region
+            .write(&mut [0_u8; 4].as_ref(), GuestAddress(0), 4, 4)
+            .unwrap();
+    }
+
     #[test]
     fn test_region_ioeventfd() {
         let mut fd1 = RegionIoEventFd {
@@ -814,7 +1829,7 @@ mod test {
 
         let io_region = Region::init_io_region(1 << 4, default_ops.clone());
         let io_region2 = Region::init_io_region(1 << 4, default_ops.clone());
-        io_region2.set_priority(10);
+        io_region2.set_priority(10).unwrap();
 
         // add duplicate io-region or ram-region will fail
         assert!(container.add_subregion(io_region.clone(), 0u64).is_ok());
@@ -850,6 +1865,102 @@ mod test {
         assert_eq!(container.subregions.read().unwrap().len(), 0);
     }
 
+    #[test]
+    fn test_delete_equal_subregion() {
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let io_a = Region::init_io_region(0x1000, ops.clone());
+        let io_b = Region::init_io_region(0x1000, ops);
+        root.add_subregion(io_a.clone(), 0x1000).unwrap();
+        root.add_subregion_not_checked(io_b.clone(), 0x1000)
+            .unwrap();
+        assert!(io_a == io_b);
+        assert!(space.flat_view().0[0].owner.is_same(&io_b));
+
+        // Deleting the shadowed one leaves the other's flat range intact.
+        let removed = root.delete_subregion(&io_a).unwrap();
+        assert!(removed.is_same(&io_a));
+        let view = space.flat_view();
+        assert_eq!(view.0.len(), 1);
+        assert!(view.0[0].owner.is_same(&io_b));
+        assert_eq!(view.0[0].addr_range, AddressRange::from((0x1000, 0x1000)));
+
+        assert!(root.delete_subregion(&io_a).is_err());
+        assert!(root.delete_subregion(&io_b).unwrap().is_same(&io_b));
+        assert!(space.flat_view().0.is_empty());
+    }
+
+    #[test]
+    fn test_delete_ram_subregion() {
+        struct FakePauseGuard;
+        impl VcpusPaused for FakePauseGuard {}
+
+        let space = AddressSpace::new(Region::init_container_region(1 << 20)).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1 << 12, -1, 0, false, false).unwrap());
+        let ram_region = Region::init_ram_region(ram);
+        space.root().add_subregion(ram_region.clone(), 0).unwrap();
+
+        // Ram can't be removed from an address-space without paused vcpus.
+        assert!(space.root().delete_subregion(&ram_region).is_err());
+        assert!(space
+            .root()
+            .delete_ram_subregion(&ram_region, &FakePauseGuard)
+            .is_ok());
+        assert!(space.root().subregions().is_empty());
+    }
+
+    #[test]
+    fn test_subregion_overlap() {
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let container = Region::init_container_region(0x1000);
+        container
+            .add_subregion(Region::init_io_region(0x100, default_ops.clone()), 0x100)
+            .unwrap();
+
+        // Exact duplicate.
+        match container.add_subregion(Region::init_io_region(0x100, default_ops.clone()), 0x100) {
+            Err(Error(ErrorKind::RegionOverlap(_, new, _, existing), _)) => {
+                assert_eq!(new, AddressRange::new(GuestAddress(0x100), 0x100));
+                assert_eq!(existing, AddressRange::new(GuestAddress(0x100), 0x100));
+            }
+            _ => panic!("expect RegionOverlap error"),
+        }
+        // Partial overlap.
+        match container.add_subregion(Region::init_io_region(0x100, default_ops.clone()), 0x180) {
+            Err(Error(ErrorKind::RegionOverlap(_, new, _, existing), _)) => {
+                assert_eq!(new, AddressRange::new(GuestAddress(0x180), 0x100));
+                assert_eq!(existing, AddressRange::new(GuestAddress(0x100), 0x100));
+            }
+            _ => panic!("expect RegionOverlap error"),
+        }
+        assert_eq!(container.subregions().len(), 1);
+
+        // Adjacent regions.
+        container
+            .add_subregion(Region::init_io_region(0x100, default_ops.clone()), 0)
+            .unwrap();
+        container
+            .add_subregion(Region::init_io_region(0x100, default_ops.clone()), 0x200)
+            .unwrap();
+        // Different priority.
+        let high = Region::init_io_region(0x200, default_ops.clone());
+        high.set_priority(1).unwrap();
+        container.add_subregion(high, 0x80).unwrap();
+        // Opt out of the check.
+        container
+            .add_subregion_not_checked(Region::init_io_region(0x100, default_ops), 0x100)
+            .unwrap();
+        assert_eq!(container.subregions().len(), 5);
+    }
+
     #[test]
     fn test_generate_flatview() {
         let default_ops = RegionOps {
@@ -875,8 +1986,8 @@ mod test {
             let region_d = Region::init_io_region(1000, default_ops.clone());
             let region_e = Region::init_io_region(1000, default_ops.clone());
 
-            region_b.set_priority(2);
-            region_c.set_priority(1);
+            region_b.set_priority(2).unwrap();
+            region_c.set_priority(1).unwrap();
             region_a.add_subregion(region_b.clone(), 2000).unwrap();
             region_a.add_subregion(region_c.clone(), 0).unwrap();
             region_b.add_subregion(region_d.clone(), 0).unwrap();
@@ -923,8 +2034,8 @@ mod test {
 
             region_a.add_subregion(region_b.clone(), 2000).unwrap();
             region_a.add_subregion(region_c.clone(), 0).unwrap();
-            region_d.set_priority(2);
-            region_e.set_priority(3);
+            region_d.set_priority(2).unwrap();
+            region_e.set_priority(3).unwrap();
             region_b.add_subregion(region_d.clone(), 0).unwrap();
             region_b.add_subregion(region_e.clone(), 2000).unwrap();
 
@@ -943,4 +2054,369 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_generate_flatview_overflow() {
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let container = Region::init_container_region(0x3000);
+        container
+            .add_subregion(Region::init_io_region(0x1000, ops), 0x1000)
+            .unwrap();
+
+        // Ends at the last address of address space.
+        let base = GuestAddress(u64::max_value() - 0x3000);
+        let view = container
+            .generate_flatview(base, AddressRange::new(base, 0x3000))
+            .unwrap();
+        assert_eq!(
+            view.0[0].addr_range,
+            AddressRange::new(base.unchecked_add(0x1000), 0x1000)
+        );
+
+        // Regions wrap around the end of address space.
+        for shift in 0..12 {
+            let base = GuestAddress(u64::max_value() - (0x1fff >> shift));
+            match container.generate_flatview(base, AddressRange::new(base, 0x1fff >> shift)) {
+                Err(Error(ErrorKind::Overflow(_), _)) => {}
+                _ => panic!("expected Overflow error at base 0x{:x}", base.raw_value()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_region_resize() {
+        let mb = 1_u64 << 20;
+        let root = Region::init_container_region(16 * mb);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let file = crate::host_mmap::create_memfd(4 * mb, false).unwrap();
+        let mut mapping =
+            HostMemMapping::new(GuestAddress(0), 4 * mb, file.as_raw_fd(), 0, false, true).unwrap();
+        mapping.reserve(8 * mb).unwrap();
+        let mapping = Arc::new(mapping);
+        let ram = Region::init_ram_region(mapping.clone());
+        root.add_subregion(ram.clone(), 0).unwrap();
+        assert_eq!(space.ram_size(), 4 * mb);
+        assert!(!space.address_in_memory(GuestAddress(4 * mb), 1));
+        let data: Vec<u8> = (0..4 * mb).map(|i| (i % 255) as u8).collect();
+        space
+            .write(&mut data.as_slice(), GuestAddress(0), 4 * mb)
+            .unwrap();
+
+        let host_addr = mapping.host_address();
+        ram.resize(8 * mb).unwrap();
+        assert_eq!(mapping.host_address(), host_addr);
+        assert_eq!(ram.size(), 8 * mb);
+        assert_eq!(space.ram_size(), 8 * mb);
+        let mut read = Vec::new();
+        space.read(&mut read, GuestAddress(0), 4 * mb).unwrap();
+        assert!(read == data);
+        space
+            .write_object(&0x1234_u64, GuestAddress(6 * mb))
+            .unwrap();
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(6 * mb)).unwrap(),
+            0x1234
+        );
+
+        // Can't exceed the parent region.
+        assert!(ram.resize(32 * mb).is_err());
+        assert_eq!(ram.size(), 8 * mb);
+
+        // Can't overlap with a sibling of the same priority, shrink to make
+        // room for it first.
+        ram.resize(4 * mb).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 4 * mb);
+        let sibling = Region::init_container_region(4 * mb);
+        root.add_subregion(sibling, 6 * mb).unwrap();
+        match ram.resize(8 * mb) {
+            Err(Error(ErrorKind::RegionOverlap(_, new, _, existing), _)) => {
+                assert_eq!(new, AddressRange::from((0, 8 * mb)));
+                assert_eq!(existing, AddressRange::from((6 * mb, 4 * mb)));
+            }
+            _ => panic!("expect RegionOverlap error"),
+        }
+        assert_eq!(ram.size(), 4 * mb);
+        assert_eq!(mapping.size(), 4 * mb);
+
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        match Region::init_io_region(0x1000, ops).resize(0x2000) {
+            Err(Error(ErrorKind::RegionType(RegionType::IO), _)) => {}
+            _ => panic!("expected RegionType error"),
+        }
+    }
+
+    #[test]
+    fn test_set_priority_runtime() {
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // memory region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  A:    [                                                       ]
+        //  C:    [CCCCCC]                                                    1
+        //  B:                  [                                  ]          1
+        //  D:                  [DDDDDDDDDDDDDDDDDDDD]                        2
+        //  E:                                [EEEEEEEEEEEEE]                 3
+        //
+        // the flat_view is as follows
+        //        [CCCCCC]      [DDDDDDDDDDDD][EEEEEEEEEEEEE]
+        let region_a = Region::init_container_region(8000);
+        let space = AddressSpace::new(region_a.clone()).unwrap();
+        let region_b = Region::init_container_region(5000);
+        let region_c = Region::init_io_region(1000, default_ops.clone());
+        let region_d = Region::init_io_region(3000, default_ops.clone());
+        let region_e = Region::init_io_region(2000, default_ops);
+
+        region_a.add_subregion(region_b.clone(), 2000).unwrap();
+        region_a.add_subregion(region_c, 0).unwrap();
+        region_d.set_priority(2).unwrap();
+        region_e.set_priority(3).unwrap();
+        region_b.add_subregion(region_d.clone(), 0).unwrap();
+        region_b.add_subregion(region_e.clone(), 2000).unwrap();
+
+        let ranges = |space: &AddressSpace| -> Vec<(u64, u64, i32)> {
+            space
+                .flat_view()
+                .0
+                .iter()
+                .map(|fr| {
+                    (
+                        fr.addr_range.base.raw_value(),
+                        fr.addr_range.size,
+                        fr.owner.priority(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            ranges(&space),
+            vec![(0, 1000, 0), (2000, 2000, 2), (4000, 2000, 3)]
+        );
+
+        // D can't take the priority of E while they overlap.
+        match region_d.set_priority(3) {
+            Err(Error(ErrorKind::RegionOverlap(_, new, _, existing), _)) => {
+                assert_eq!(new, AddressRange::from((0, 3000)));
+                assert_eq!(existing, AddressRange::from((2000, 2000)));
+            }
+            _ => panic!("expect RegionOverlap error"),
+        }
+        assert_eq!(region_d.priority(), 2);
+
+        // Flip priorities of D and E through a free priority, D takes the
+        // overlapped part now.
+        region_d.set_priority(4).unwrap();
+        region_e.set_priority(2).unwrap();
+        region_d.set_priority(3).unwrap();
+        assert_eq!(
+            ranges(&space),
+            vec![(0, 1000, 0), (2000, 3000, 3), (5000, 1000, 2)]
+        );
+        let subregions = region_b.subregions();
+        assert!(subregions[0].is_same(&region_d));
+        assert!(subregions[1].is_same(&region_e));
+    }
+
+    #[test]
+    fn test_coalesced_io() {
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let root = Region::init_container_region(0x4000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let io = Region::init_io_region(0x1000, default_ops.clone());
+        io.set_coalesced_io(vec![
+            AddressRange::from((0x100, 0x100)),
+            AddressRange::from((0x800, 0x400)),
+        ])
+        .unwrap();
+        root.add_subregion(io.clone(), 0x1000).unwrap();
+
+        // A region of higher priority splits the second range.
+        let cover = Region::init_io_region(0x100, default_ops);
+        cover.set_priority(1).unwrap();
+        root.add_subregion(cover, 0x1900).unwrap();
+
+        let coalesced = |space: &AddressSpace| -> Vec<Vec<(u64, u64)>> {
+            space
+                .flat_view()
+                .0
+                .iter()
+                .map(|fr| {
+                    fr.coalesced
+                        .iter()
+                        .map(|r| (r.base.raw_value(), r.size))
+                        .collect()
+                })
+                .collect()
+        };
+        assert_eq!(
+            coalesced(&space),
+            vec![
+                vec![(0x1100, 0x100), (0x1800, 0x100)],
+                vec![],
+                vec![(0x1a00, 0x200)],
+            ]
+        );
+
+        io.set_coalesced_io(Vec::new()).unwrap();
+        assert!(io.coalesced_io().is_empty());
+        assert_eq!(coalesced(&space), vec![vec![], vec![], vec![]]);
+
+        match io.set_coalesced_io(vec![AddressRange::from((0xf00, 0x200))]) {
+            Err(Error(ErrorKind::Overflow(0xf00), _)) => {}
+            _ => panic!("expected Overflow error"),
+        }
+        let ram = Region::init_ram_region(Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000, -1, 0, false, false).unwrap(),
+        ));
+        match ram.set_coalesced_io(vec![AddressRange::from((0, 0x100))]) {
+            Err(Error(ErrorKind::RegionType(RegionType::Ram), _)) => {}
+            _ => panic!("expected RegionType error"),
+        }
+    }
+
+    #[test]
+    fn test_merge_flat_ranges() {
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // The layout of test_generate_flatview, C is split by D and E.
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  A:    [                                                       ]
+        //  C:    [CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC]
+        //  B:                  [                          ]
+        //  D:                  [DDDDD]
+        //  E:                                [EEEEE]
+        let region_a = Region::init_container_region(8000);
+        let region_b = Region::init_container_region(4000);
+        let region_c = Region::init_io_region(6000, default_ops.clone());
+        let region_d = Region::init_io_region(1000, default_ops.clone());
+        let region_e = Region::init_io_region(1000, default_ops.clone());
+        region_b.set_priority(2).unwrap();
+        region_c.set_priority(1).unwrap();
+        region_a.add_subregion(region_b.clone(), 2000).unwrap();
+        region_a.add_subregion(region_c.clone(), 0).unwrap();
+        region_b.add_subregion(region_d.clone(), 0).unwrap();
+        region_b.add_subregion(region_e.clone(), 2000).unwrap();
+        let addr_range = AddressRange::from((0u64, region_a.size()));
+
+        // C is rendered in as few pieces as possible after D is deleted.
+        //        [CCCCCCCCCCCCCCCCCCCCCCCCCCC][EEEEE][CCCCC]
+        region_b.delete_subregion(&region_d).unwrap();
+        let view = region_a
+            .generate_flatview(GuestAddress(0), addr_range)
+            .unwrap();
+        let ranges: Vec<(u64, u64, u64)> = view
+            .0
+            .iter()
+            .map(|fr| {
+                (
+                    fr.addr_range.base.raw_value(),
+                    fr.addr_range.size,
+                    fr.offset_in_region,
+                )
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(0, 4000, 0), (4000, 1000, 0), (5000, 1000, 5000)]
+        );
+
+        region_b.delete_subregion(&region_e).unwrap();
+        let view = region_a
+            .generate_flatview(GuestAddress(0), addr_range)
+            .unwrap();
+        assert_eq!(view.0.len(), 1);
+        assert_eq!(view.0[0].addr_range, AddressRange::from((0, 6000)));
+
+        // Adjacent aliases of contiguous pieces of one region are merged,
+        // but not an alias of a discontiguous piece.
+        //        0      1000   2000   3000
+        //        |------|------|------|
+        //  ALIAS:[C 0~1000]
+        //  ALIAS:       [C 1000~2000]
+        //  ALIAS:              [C 0~1000]
+        let root = Region::init_container_region(3000);
+        for (offset, alias_offset) in [(0, 0), (1000, 1000), (2000, 0)].iter() {
+            let alias = Region::init_alias_region(region_c.clone(), *alias_offset, 1000).unwrap();
+            root.add_subregion(alias, *offset).unwrap();
+        }
+        let view = root
+            .generate_flatview(GuestAddress(0), AddressRange::from((0, 3000)))
+            .unwrap();
+        assert_eq!(view.0.len(), 2);
+        assert_eq!(view.0[0].addr_range, AddressRange::from((0, 2000)));
+        assert_eq!(view.0[0].offset_in_region, 0);
+        assert!(view.0[0].owner.is_same(&region_c));
+        assert_eq!(view.0[1].addr_range, AddressRange::from((2000, 1000)));
+    }
+
+    #[test]
+    fn test_alias_region() {
+        let mem_mapping =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 0x2000, -1, 0, false, false).unwrap());
+        let ram_region = Region::init_ram_region(mem_mapping.clone());
+        assert!(Region::init_alias_region(ram_region.clone(), 0x1800, 0x1000).is_err());
+        assert!(Region::init_alias_region(Region::init_container_region(0x1000), 0, 0x10).is_err());
+
+        // memory region layout
+        //        0      0x1000   0x2000   0x3000   0x4000  0x4800
+        //        |------|--------|--------|--------|-------|
+        //  RAM:  [              ]
+        //  ALIAS:                                  [RAM 0x1000~0x1800]
+        let alias_region = Region::init_alias_region(ram_region.clone(), 0x1000, 0x800).unwrap();
+        assert_eq!(alias_region.region_type(), RegionType::Alias);
+        assert_eq!(alias_region.size(), 0x800);
+        let root = Region::init_container_region(0x8000);
+        root.add_subregion(ram_region.clone(), 0).unwrap();
+        root.add_subregion(alias_region.clone(), 0x4000).unwrap();
+
+        let view = root
+            .generate_flatview(GuestAddress(0), AddressRange::from((0, 0x8000)))
+            .unwrap();
+        assert_eq!(view.0.len(), 2);
+        assert_eq!(view.0[1].addr_range, AddressRange::from((0x4000, 0x800)));
+        assert_eq!(view.0[1].owner.region_type(), RegionType::Ram);
+        assert_eq!(view.0[1].offset_in_region, 0x1000);
+
+        // Data written to RAM is visible in the window, and vice versa.
+        let data = [0x5a_u8; 8];
+        ram_region
+            .write(&mut data.as_ref(), GuestAddress(0), 0x1010, 8)
+            .unwrap();
+        let mut data_res = [0_u8; 8];
+        alias_region
+            .read(&mut data_res.as_mut(), GuestAddress(0x4000), 0x10, 8)
+            .unwrap();
+        assert_eq!(data_res, data);
+        alias_region
+            .write(&mut [0xa5_u8; 8].as_ref(), GuestAddress(0x4000), 0x7f8, 8)
+            .unwrap();
+        ram_region
+            .read(&mut data_res.as_mut(), GuestAddress(0), 0x17f8, 8)
+            .unwrap();
+        assert_eq!(data_res, [0xa5_u8; 8]);
+        assert_eq!(
+            alias_region.get_host_address().unwrap(),
+            mem_mapping.host_address() + 0x1000
+        );
+        // Access is bounded by the window.
+        assert!(alias_region
+            .read(&mut data_res.as_mut(), GuestAddress(0x4000), 0x7fc, 8)
+            .is_err());
+    }
 }
@@ -0,0 +1,71 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use util::byte_code::ByteCode;
+
+/// Magic number of arm64 Image header, "ARM\x64".
+pub const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241;
+/// `text_offset` of kernels before v3.17, whose header has `image_size` 0.
+pub const TEXT_OFFSET_LEGACY: u64 = 0x8_0000;
+
+// Structure below sourced from:
+// https://www.kernel.org/doc/html/latest/arm64/booting.html
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ImageHeader {
+    pub code0: u32,
+    pub code1: u32,
+    pub text_offset: u64,
+    pub image_size: u64,
+    pub flags: u64,
+    pub res2: u64,
+    pub res3: u64,
+    pub res4: u64,
+    pub magic: u32,
+    pub res5: u32,
+}
+
+impl ByteCode for ImageHeader {}
+
+impl ImageHeader {
+    /// Offset of kernel from a 2 MiB aligned base, and size of memory it
+    /// takes, `file_len` for old kernels which don't tell.
+    pub fn placement(&self, file_len: u64) -> (u64, u64) {
+        if self.image_size == 0 {
+            (TEXT_OFFSET_LEGACY, file_len)
+        } else {
+            (
+                u64::from_le(self.text_offset),
+                u64::from_le(self.image_size),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image_header() {
+        assert_eq!(std::mem::size_of::<ImageHeader>(), 64);
+
+        let mut header = ImageHeader {
+            magic: ARM64_IMAGE_MAGIC,
+            ..Default::default()
+        };
+        assert_eq!(header.placement(0x1234), (TEXT_OFFSET_LEGACY, 0x1234));
+        header.text_offset = 0;
+        header.image_size = 0x20_0000;
+        assert_eq!(header.placement(0x1234), (0, 0x20_0000));
+    }
+}
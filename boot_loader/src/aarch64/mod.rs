@@ -10,27 +10,40 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod image;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use self::errors::{ErrorKind, Result};
+use self::errors::{ErrorKind, Result, ResultExt};
 use address_space::{AddressSpace, GuestAddress};
+use image::{ImageHeader, ARM64_IMAGE_MAGIC};
+use util::boot_timeline::{mark, BootTimeline};
+use util::byte_code::ByteCode;
 use util::device_tree;
 
 pub mod errors {
-    use util::device_tree;
     error_chain! {
         links {
             AddressSpace(address_space::errors::Error, address_space::errors::ErrorKind);
         }
+        foreign_links {
+            Io(std::io::Error);
+        }
         errors {
-            DTBOverflow(size: u64) {
+            DTBOverflow(addr: u64, size: u64, mem_end: u64) {
                 display(
-                    "guest memory size {} should bigger than {}",
+                    "dtb [0x{:x}, +0x{:x}) exceeds guest memory end 0x{:x}",
+                    addr,
                     size,
-                    device_tree::FDT_MAX_SIZE
+                    mem_end
                 )
             }
+            DtbPlacement(size: u64) {
+                display("No room for dtb of size 0x{:x} below kernel and apart from initrd", size)
+            }
             InitrdOverflow(addr: u64, size: u32) {
                 display(
                     "Failed to allocate initrd image {} to memory {}.",
@@ -38,11 +51,22 @@ pub mod errors {
                      addr
                 )
             }
+            InvalidImage {
+                display("Kernel file is not an arm64 Image")
+            }
+            KernelOverflow(addr: u64, size: u64) {
+                display("Kernel of size 0x{:x} at 0x{:x} exceeds guest memory", size, addr)
+            }
         }
     }
 }
 
-const AARCH64_KERNEL_OFFSET: u64 = 0x8_0000;
+/// Kernel Image is loaded at `text_offset` from a base aligned to 2 MiB,
+/// initrd follows it with the same alignment.
+const AARCH64_KERNEL_ALIGN: u64 = 0x20_0000;
+/// Dtb is aligned to 8 bytes and at most 2 MiB large.
+const DTB_ALIGN: u64 = 8;
+const DTB_MAX_SIZE: u64 = 0x20_0000;
 
 /// Boot loader config used for aarch64.
 #[derive(Default, Debug)]
@@ -69,52 +93,294 @@ pub struct AArch64BootLoader {
     pub dtb_start: u64,
 }
 
-pub fn linux_bootloader(
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Read and check the 64-byte Image header, file is rewound to its start.
+///
+/// # Errors
+/// * `InvalidImage`: File is too short or magic number mismatches.
+fn read_image_header(kernel_image: &mut File) -> Result<ImageHeader> {
+    let mut buf = [0_u8; std::mem::size_of::<ImageHeader>()];
+    kernel_image.seek(SeekFrom::Start(0))?;
+    kernel_image
+        .read_exact(&mut buf)
+        .chain_err(|| ErrorKind::InvalidImage)?;
+    kernel_image.seek(SeekFrom::Start(0))?;
+
+    let header = *ImageHeader::from_bytes(&buf).unwrap();
+    if u32::from_le(header.magic) != ARM64_IMAGE_MAGIC {
+        return Err(ErrorKind::InvalidImage.into());
+    }
+    Ok(header)
+}
+
+/// Pick address of dtb right below kernel. As kernel is loaded near the
+/// start of RAM, dtb is within the first 512 MiB of RAM as kernel requires.
+///
+/// # Arguments
+/// * `sys_mem` - guest memory.
+/// * `kernel_range` - (start, end) of kernel image.
+/// * `initrd_range` - (start, end) of initrd if any.
+/// * `fdt_size` - max size of dtb.
+///
+/// # Errors
+/// * `DtbPlacement`: Dtb is larger than 2 MiB, or there is no RAM for it
+///   below kernel and apart from initrd.
+pub fn plan_dtb_region(
+    sys_mem: &Arc<AddressSpace>,
+    kernel_range: (u64, u64),
+    initrd_range: Option<(u64, u64)>,
+    fdt_size: u64,
+) -> Result<u64> {
+    let err = || ErrorKind::DtbPlacement(fdt_size).into();
+    if fdt_size == 0 || fdt_size > DTB_MAX_SIZE {
+        return Err(err());
+    }
+    let addr = match kernel_range.0.checked_sub(fdt_size) {
+        Some(addr) => addr & !(DTB_ALIGN - 1),
+        None => return Err(err()),
+    };
+    if !sys_mem.address_in_memory(GuestAddress(addr), fdt_size) {
+        return Err(err());
+    }
+    if let Some((start, end)) = initrd_range {
+        if addr < end && start < addr + fdt_size {
+            return Err(err());
+        }
+    }
+    Ok(addr)
+}
+
+/// Write dtb `blob` to guest memory at `addr`.
+///
+/// # Errors
+/// * `DTBOverflow`: Blob runs beyond the end of guest memory.
+pub fn write_dtb(sys_mem: &Arc<AddressSpace>, addr: u64, blob: &[u8]) -> Result<()> {
+    let size = blob.len() as u64;
+    let mem_end = sys_mem.memory_end_address().raw_value();
+    if addr.checked_add(size).map_or(true, |end| end > mem_end) {
+        return Err(ErrorKind::DTBOverflow(addr, size, mem_end).into());
+    }
+    sys_mem
+        .write(&mut &blob[..], GuestAddress(addr), size)
+        .chain_err(|| format!("Failed to load dtb to 0x{:x}", addr))?;
+    Ok(())
+}
+
+/// Load arm64 Image kernel and initrd to guest memory according to
+/// [`arm64 boot protocol`](https://www.kernel.org/doc/html/latest/arm64/booting.html).
+///
+/// Kernel is placed `text_offset` above the 2 MiB aligned start of guest
+/// memory, initrd at the next 2 MiB boundary above `image_size` of kernel,
+/// and dtb right below kernel. If `text_offset` leaves no room for dtb,
+/// kernel is moved up by 2 MiB.
+///
+/// # Errors
+/// * `InvalidImage`: Kernel has no valid Image header.
+/// * `KernelOverflow`: Kernel doesn't fit in guest memory.
+/// * `InitrdOverflow`: Initrd doesn't fit in guest memory.
+/// * `DtbPlacement`: Guest memory has no room for dtb.
+pub fn load_kernel(
     config: &AArch64BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
+    mut timeline: Option<&mut BootTimeline>,
 ) -> Result<AArch64BootLoader> {
-    let dtb_addr =
-        if sys_mem.memory_end_address().raw_value() > u64::from(device_tree::FDT_MAX_SIZE) {
-            if let Some(addr) = sys_mem
-                .memory_end_address()
-                .raw_value()
-                .checked_sub(u64::from(device_tree::FDT_MAX_SIZE))
-            {
-                if sys_mem.address_in_memory(GuestAddress(addr), 0) {
-                    addr
-                } else {
-                    config.mem_start
-                }
-            } else {
-                0
-            }
-        } else {
-            0
-        };
-
-    if dtb_addr == 0 {
-        return Err(ErrorKind::DTBOverflow(sys_mem.memory_end_address().raw_value()).into());
+    let fdt_size = u64::from(device_tree::FDT_MAX_SIZE);
+    let mut kernel_image = File::open(&config.kernel)
+        .chain_err(|| format!("Failed to open kernel {}", config.kernel.display()))?;
+    let header = read_image_header(&mut kernel_image)?;
+    let file_len = kernel_image.metadata()?.len();
+    let (text_offset, image_size) = header.placement(file_len);
+    let mut kernel_base = align_up(config.mem_start, AARCH64_KERNEL_ALIGN);
+    if text_offset < fdt_size {
+        kernel_base += AARCH64_KERNEL_ALIGN;
+    }
+    let kernel_addr = kernel_base + text_offset;
+    let image_size = std::cmp::max(image_size, file_len);
+    if !sys_mem.address_in_memory(GuestAddress(kernel_addr), image_size) {
+        return Err(ErrorKind::KernelOverflow(kernel_addr, image_size).into());
     }
+    sys_mem
+        .write(&mut kernel_image, GuestAddress(kernel_addr), file_len)
+        .chain_err(|| format!("Failed to load kernel to 0x{:x}", kernel_addr))?;
+    mark(&mut timeline, "kernel copy");
 
     let mut initrd_addr = 0;
-    if config.initrd_size > 0 {
-        initrd_addr = if let Some(addr) = dtb_addr.checked_sub(u64::from(config.initrd_size)) {
-            addr
-        } else {
-            return Err(ErrorKind::InitrdOverflow(dtb_addr, config.initrd_size).into());
-        };
-
-        if !sys_mem.address_in_memory(GuestAddress(initrd_addr), 0) {
-            initrd_addr = config.mem_start + u64::from(device_tree::FDT_MAX_SIZE);
+    let mut initrd_range = None;
+    if let Some(initrd) = &config.initrd {
+        initrd_addr = align_up(kernel_addr + image_size, AARCH64_KERNEL_ALIGN);
+        let initrd_size = u64::from(config.initrd_size);
+        if !sys_mem.address_in_memory(GuestAddress(initrd_addr), initrd_size) {
+            return Err(ErrorKind::InitrdOverflow(initrd_addr, config.initrd_size).into());
         }
+        let mut initrd_image = File::open(initrd)
+            .chain_err(|| format!("Failed to open initrd {}", initrd.display()))?;
+        sys_mem
+            .write(
+                &mut initrd_image,
+                GuestAddress(initrd_addr),
+                u64::from(config.initrd_size),
+            )
+            .chain_err(|| format!("Failed to load initrd to 0x{:x}", initrd_addr))?;
+        initrd_range = Some((initrd_addr, initrd_addr + initrd_size));
+        mark(&mut timeline, "initrd");
     } else {
         info!("No initrd image file.");
     }
 
+    let dtb_addr = plan_dtb_region(
+        sys_mem,
+        (kernel_addr, kernel_addr + image_size),
+        initrd_range,
+        fdt_size,
+    )?;
+
     Ok(AArch64BootLoader {
-        kernel_start: config.mem_start + AARCH64_KERNEL_OFFSET,
-        vmlinux_start: config.mem_start + AARCH64_KERNEL_OFFSET,
+        kernel_start: kernel_addr,
+        vmlinux_start: kernel_addr,
         initrd_start: initrd_addr,
         dtb_start: dtb_addr,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use address_space::{HostMemMapping, Region};
+
+    fn test_space(start: u64, size: u64) -> Arc<AddressSpace> {
+        let root = Region::init_container_region(u64::max_value());
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(start), size, -1, 0, false, false).unwrap());
+        root.add_subregion(Region::init_ram_region(ram), start)
+            .unwrap();
+        space
+    }
+
+    fn image_file(name: &str, header: &ImageHeader, len: usize) -> PathBuf {
+        let mut image = vec![0xa5_u8; len];
+        image[..std::mem::size_of::<ImageHeader>()].copy_from_slice(header.as_bytes());
+        let path =
+            std::env::temp_dir().join(format!("stratovirt_image_{}_{}", name, std::process::id()));
+        std::fs::write(&path, image).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_kernel() {
+        let mem_start = 0x4000_0000;
+        let space = test_space(mem_start, 0x1000_0000);
+        let header = ImageHeader {
+            text_offset: 0x8_0000,
+            image_size: 0x30_0000,
+            magic: ARM64_IMAGE_MAGIC,
+            ..Default::default()
+        };
+        let kernel = image_file("kernel", &header, 0x1000);
+        let initrd =
+            std::env::temp_dir().join(format!("stratovirt_image_initrd_{}", std::process::id()));
+        std::fs::write(&initrd, vec![0x5a_u8; 0x100]).unwrap();
+        let mut config = AArch64BootLoaderConfig {
+            kernel: kernel.clone(),
+            initrd: Some(initrd.clone()),
+            initrd_size: 0x100,
+            mem_start,
+        };
+
+        let boot_loader = load_kernel(&config, &space, None).unwrap();
+        assert_eq!(boot_loader.kernel_start, mem_start + 0x8_0000);
+        // Kernel takes image_size, initrd starts at the next 2 MiB.
+        assert_eq!(boot_loader.initrd_start, mem_start + 0x40_0000);
+        assert_eq!(
+            boot_loader.dtb_start,
+            boot_loader.kernel_start - u64::from(device_tree::FDT_MAX_SIZE)
+        );
+        assert_eq!(
+            space
+                .read_object::<u32>(GuestAddress(boot_loader.kernel_start + 0x38))
+                .unwrap(),
+            ARM64_IMAGE_MAGIC
+        );
+        assert_eq!(
+            space
+                .read_object::<u8>(GuestAddress(boot_loader.initrd_start))
+                .unwrap(),
+            0x5a
+        );
+
+        config.initrd_size = 0x1000_0000;
+        assert!(load_kernel(&config, &space, None).is_err());
+
+        let path = image_file("invalid", &ImageHeader::default(), 0x1000);
+        config.kernel = path.clone();
+        config.initrd = None;
+        assert!(load_kernel(&config, &space, None).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&kernel).unwrap();
+        std::fs::remove_file(&initrd).unwrap();
+
+        // No room below kernel with text_offset 0, kernel moves up by 2 MiB.
+        let header = ImageHeader {
+            text_offset: 0,
+            image_size: 0x30_0000,
+            magic: ARM64_IMAGE_MAGIC,
+            ..Default::default()
+        };
+        let kernel = image_file("kernel_offset0", &header, 0x1000);
+        config.kernel = kernel.clone();
+        let boot_loader = load_kernel(&config, &space, None).unwrap();
+        assert_eq!(boot_loader.kernel_start, mem_start + 0x20_0000);
+        assert_eq!(
+            boot_loader.dtb_start,
+            mem_start + 0x20_0000 - u64::from(device_tree::FDT_MAX_SIZE)
+        );
+        std::fs::remove_file(&kernel).unwrap();
+    }
+
+    #[test]
+    fn test_plan_dtb_region() {
+        let mem_start = 0x4000_0000;
+        let space = test_space(mem_start, 0x1000_0000);
+        let kernel = (mem_start + 0x8_0004, mem_start + 0x40_0000);
+
+        let addr = plan_dtb_region(&space, kernel, None, 0x1_0000).unwrap();
+        assert_eq!(addr, mem_start + 0x7_0000);
+        assert_eq!(addr % 8, 0);
+
+        // Overlaps initrd.
+        let initrd = Some((mem_start, mem_start + 0x7_1000));
+        assert!(plan_dtb_region(&space, kernel, initrd, 0x1_0000).is_err());
+        // Below start of guest memory.
+        assert!(plan_dtb_region(&space, kernel, None, 0x10_0000).is_err());
+        // Larger than 2 MiB.
+        let kernel = (mem_start + 0x400_0000, mem_start + 0x500_0000);
+        assert!(plan_dtb_region(&space, kernel, None, 0x20_0001).is_err());
+        assert!(plan_dtb_region(&space, kernel, None, 0x20_0000).is_ok());
+    }
+
+    #[test]
+    fn test_write_dtb() {
+        let mem_start = 0x4000_0000;
+        let space = test_space(mem_start, 0x1000_0000);
+        let blob = [0xd0_u8, 0x0d, 0xfe, 0xed];
+        write_dtb(&space, mem_start, &blob).unwrap();
+        assert_eq!(
+            space.read_object::<u32>(GuestAddress(mem_start)).unwrap(),
+            u32::from_le_bytes(blob)
+        );
+        match write_dtb(&space, mem_start + 0x1000_0000 - 2, &blob) {
+            Err(e) => match e.kind() {
+                ErrorKind::DTBOverflow(addr, size, mem_end) => {
+                    assert_eq!((*addr, *size), (mem_start + 0x1000_0000 - 2, 4));
+                    assert_eq!(*mem_end, mem_start + 0x1000_0000);
+                }
+                _ => panic!("Unexpected error {}", e),
+            },
+            Ok(_) => panic!("Dtb beyond guest memory should be rejected"),
+        }
+    }
+}
@@ -17,9 +17,11 @@
 //! ## Design
 //!
 //! This crate offers support for:
-//! 1. Loading PE (vmlinux.bin) kernel images and bzImage kernel images (only in x86_64).
+//! 1. Loading PE (vmlinux.bin) kernel images, ELF (vmlinux) and bzImage kernel images
+//!    (only in x86_64), and arm64 Image kernel images (only in aarch64).
 //! 2. Loading initrd image.
 //! 3. Initialization for architecture related information.
+//! 4. Loading firmware blob below 1 MiB (only in x86_64).
 //!
 //! ## Platform Support
 //!
@@ -36,24 +38,39 @@
 //! # extern crate boot_loader;
 //!
 //! use address_space::{AddressSpace, Region};
-//! use boot_loader::{BootLoaderConfig, load_kernel};
+//! use boot_loader::{BootLoaderConfig, load_linux};
 //!
 //! #[cfg(target_arch="x86_64")]
 //! fn main() {
 //!     let guest_mem = AddressSpace::new(Region::init_container_region(std::u64::MAX)).unwrap();
 //!     let kernel_file = std::path::PathBuf::from("/path/to/my/kernel");
 //!     let bootloader_config = BootLoaderConfig {
-//!         kernel: kernel_file,
-//!         initrd: None,
+//!         kernel: Some(kernel_file),
+//!         initrd: Vec::new(),
 //!         initrd_size: 0,
-//!         kernel_cmdline: String::new(),
+//!         kernel_cmdline: String::new().into(),
 //!         cpu_count: 0,
+//!         max_cpus: 0,
 //!         gap_range: (0xC000_0000, 0x4000_0000),
 //!         ioapic_addr: 0xFEC0_0000,
 //!         lapic_addr: 0xFEE0_0000,
+//!         prefer_pvh: false,
+//!         firmware: None,
+//!         setup_data: Vec::new(),
+//!         mptable_only: false,
+//!         acpi_addr: None,
+//!         irq_overrides: vec![boot_loader::ISA_TIMER_IRQ_OVERRIDE],
+//!         stream_load: false,
+//!         layout: boot_loader::BootLayout::default(),
+//!         use_gib_pages: false,
+//!         boot_tss: false,
+//!         kernel_hash: None,
+//!         initrd_hash: None,
+//!         screen_info: boot_loader::ScreenInfo::default(),
+//!         min_boot_protocol: boot_loader::BOOT_VERSION,
 //!     };
 //!
-//!     let layout = load_kernel(&bootloader_config, &guest_mem).unwrap();
+//!     let layout = load_linux(&bootloader_config, &guest_mem, None).unwrap();
 //!     // Now PE linux kernel and kernel cmdline are loaded to guest memory...
 //! }
 //!
@@ -68,7 +85,7 @@
 //!         mem_start: 0x4000_0000,
 //!     };
 //!
-//!     let layout = load_kernel(&bootloader_config, &guest_mem).unwrap();
+//!     let layout = load_linux(&bootloader_config, &guest_mem, None).unwrap();
 //!     // Now PE linux kernel is loaded to guest memory...
 //! }
 //! ```
@@ -83,25 +100,36 @@ mod aarch64;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+#[cfg(target_arch = "x86_64")]
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
 use std::sync::Arc;
 
-use address_space::{AddressSpace, GuestAddress};
+use address_space::AddressSpace;
+#[cfg(target_arch = "x86_64")]
+use util::boot_timeline::mark;
+use util::boot_timeline::BootTimeline;
+#[cfg(target_arch = "x86_64")]
+use util::sha256::Sha256;
 
 #[cfg(target_arch = "aarch64")]
-use aarch64::linux_bootloader;
+pub use aarch64::plan_dtb_region;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::AArch64BootLoader as BootLoader;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::AArch64BootLoaderConfig as BootLoaderConfig;
 
 #[cfg(target_arch = "x86_64")]
-use x86_64::linux_bootloader;
+pub use x86_64::boot_regs;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::debug;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::X86BootLoader as BootLoader;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::X86BootLoaderConfig as BootLoaderConfig;
+#[cfg(target_arch = "x86_64")]
+use x86_64::{linux_bootloader, KernelFormat};
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::{BootLayout, ScreenInfo, SetupData, BOOT_VERSION, ISA_TIMER_IRQ_OVERRIDE};
 
 pub mod errors {
     #[cfg(target_arch = "aarch64")]
@@ -121,77 +149,155 @@ pub mod errors {
             BootLoaderOpenInitrd {
                 display("Failed to open initrd image")
             }
+            BootLoaderNoKernel {
+                display("Neither kernel nor firmware is given")
+            }
         }
     }
 }
 
-use self::errors::{ErrorKind, Result, ResultExt};
+use self::errors::Result;
+#[cfg(target_arch = "x86_64")]
+use self::errors::{ErrorKind, ResultExt};
 
-/// Load linux kernel or initrd image file to Guest Memory.
+/// Load linux kernel and other boot source to Guest Memory, with the boot
+/// protocol of target arch.
+///
+/// This is the entry point for booting linux: kernel format is probed, and
+/// images, boot structures and cmdline are written in the order the boot
+/// protocol needs, so that the zero page agrees with what is loaded.
 ///
 /// # Arguments
-/// * `image` - image file for kernel or initrd.
-/// * `start_addr` - image start address in guest memory.
+///
+/// * `config` - boot source config, contains kernel, initrd, kernel
+///   cmdline(only `x86_64`) and firmware(only `x86_64`).
 /// * `sys_mem` - guest memory.
+/// * `timeline` - collector of boot steps' durations, if any.
 ///
 /// # Errors
-/// * `BootLoaderOpenKernel`: Open image failed.
-/// * `AddressSpace`: Write image to guest memory failed.
-fn load_image(image: &mut File, start_addr: u64, sys_mem: &Arc<AddressSpace>) -> Result<()> {
-    let curr_loc = image.seek(SeekFrom::Current(0)).unwrap();
-    let len = image.seek(SeekFrom::End(0)).unwrap();
-    image.seek(SeekFrom::Start(curr_loc)).unwrap();
+///
+/// Load kernel, initrd or kernel cmdline to guest memory failed. Boot source
+/// is broken or guest memory is unnormal.
+pub fn load_linux(
+    config: &BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+    timeline: Option<&mut BootTimeline>,
+) -> Result<BootLoader> {
+    #[cfg(target_arch = "x86_64")]
+    let boot_loader = load_kernel(config, sys_mem, timeline)?;
+    #[cfg(target_arch = "aarch64")]
+    let boot_loader = aarch64::load_kernel(config, sys_mem, timeline)?;
 
-    sys_mem.write(image, GuestAddress(start_addr), len - curr_loc)?;
+    Ok(boot_loader)
+}
 
+/// Write device tree `blob` to guest memory at `addr`, which is usually
+/// `dtb_start` of `BootLoader`.
+///
+/// # Errors
+///
+/// Blob runs beyond the end of guest memory, or write to guest memory failed.
+#[cfg(target_arch = "aarch64")]
+pub fn write_dtb(sys_mem: &Arc<AddressSpace>, addr: u64, blob: &[u8]) -> Result<()> {
+    aarch64::write_dtb(sys_mem, addr, blob)?;
     Ok(())
 }
 
-/// Load PE(vmlinux.bin) linux kernel / bzImage linux kernel (only x86_64) and
-/// other boot source to Guest Memory.
+/// Load PE(vmlinux.bin), ELF or bzImage linux kernel and other boot source
+/// to Guest Memory for `x86_64`.
 ///
 /// # Steps
 ///
 /// 1. Prepare for linux kernel boot env, return guest memory layout.
 /// 2. According guest memory layout, load linux kernel to guest memory.
 /// 3. According guest memory layout, load initrd image to guest memory.
-/// 4. For `x86_64` arch, inject cmdline to guest memory.
-///
-/// # Arguments
-///
-/// * `config` - boot source config, contains kernel, initrd and kernel
-///   cmdline(only `x86_64`).
-/// * `sys_mem` - guest memory.
-///
-/// # Errors
-///
-/// Load kernel, initrd or kernel cmdline to guest memory failed. Boot source
-/// is broken or guest memory is unnormal.
-pub fn load_kernel(config: &BootLoaderConfig, sys_mem: &Arc<AddressSpace>) -> Result<BootLoader> {
-    let mut kernel_image =
-        File::open(&config.kernel).chain_err(|| ErrorKind::BootLoaderOpenKernel)?;
-
-    #[cfg(target_arch = "x86_64")]
-    let boot_loader = {
-        let boot_hdr = x86_64::load_bzimage(&mut kernel_image).ok();
-        linux_bootloader(config, sys_mem, boot_hdr)?
+/// 4. Inject cmdline to guest memory.
+/// 5. Load firmware to guest memory if any. Without kernel, vcpu starts
+///    from the firmware reset vector.
+#[cfg(target_arch = "x86_64")]
+fn load_kernel(
+    config: &BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+    mut timeline: Option<&mut BootTimeline>,
+) -> Result<BootLoader> {
+    let boot_loader = match &config.kernel {
+        Some(kernel) => {
+            let mut kernel_image =
+                File::open(kernel).chain_err(|| ErrorKind::BootLoaderOpenKernel)?;
+            let mut hasher = config.kernel_hash.as_ref().map(|_| Sha256::new());
+            let mut kernel_format = x86_64::probe_kernel(
+                &mut kernel_image,
+                sys_mem,
+                config.prefer_pvh,
+                config.min_boot_protocol,
+                hasher.as_mut(),
+            )?;
+            mark(&mut timeline, "kernel probe");
+            let boot_loader =
+                linux_bootloader(config, sys_mem, &mut kernel_format, timeline.as_deref_mut())?;
+            let mmap = !config.stream_load;
+            match kernel_format {
+                KernelFormat::BzImage(boot_hdr, _) => x86_64::load_kernel_image(
+                    &mut kernel_image,
+                    sys_mem,
+                    &boot_hdr,
+                    mmap,
+                    hasher.as_mut(),
+                )?,
+                KernelFormat::Raw(_) => {
+                    let addr = boot_loader.vmlinux_start;
+                    x86_64::load_raw_kernel(
+                        &mut kernel_image,
+                        sys_mem,
+                        addr,
+                        mmap,
+                        hasher.as_mut(),
+                    )?
+                }
+                // ELF segments are loaded and hashed when probed.
+                KernelFormat::Elf(..) | KernelFormat::Pvh(..) => {}
+            }
+            x86_64::check_digest("kernel", config.kernel_hash.as_deref(), hasher)?;
+            mark(&mut timeline, "kernel copy");
+            x86_64::setup_kernel_cmdline(&config, sys_mem, &kernel_format)?;
+            mark(&mut timeline, "cmdline");
+            boot_loader
+        }
+        None => match &config.firmware {
+            Some(firmware) => {
+                let boot_loader = x86_64::firmware_bootloader(config)?;
+                x86_64::load_firmware(firmware, sys_mem)?;
+                mark(&mut timeline, "firmware");
+                return Ok(boot_loader);
+            }
+            None => return Err(ErrorKind::BootLoaderNoKernel.into()),
+        },
     };
-    #[cfg(target_arch = "aarch64")]
-    let boot_loader = linux_bootloader(config, sys_mem)?;
 
-    load_image(&mut kernel_image, boot_loader.vmlinux_start, &sys_mem)?;
-
-    match &config.initrd {
-        Some(initrd) => {
-            let mut initrd_image =
-                File::open(initrd).chain_err(|| ErrorKind::BootLoaderOpenInitrd)?;
-            load_image(&mut initrd_image, boot_loader.initrd_start, &sys_mem)?;
+    if !config.initrd.is_empty() {
+        let mut initrd_images = Vec::with_capacity(config.initrd.len());
+        for initrd in &config.initrd {
+            initrd_images.push(File::open(initrd).chain_err(|| ErrorKind::BootLoaderOpenInitrd)?);
         }
-        None => {}
-    };
+        let mut hasher = config.initrd_hash.as_ref().map(|_| Sha256::new());
+        x86_64::load_initrds(
+            &mut initrd_images,
+            sys_mem,
+            boot_loader.initrd_start,
+            config.initrd_size,
+            !config.stream_load,
+            hasher.as_mut(),
+        )?;
+        x86_64::check_digest("initrd", config.initrd_hash.as_deref(), hasher)?;
+        mark(&mut timeline, "initrd");
+    }
 
-    #[cfg(target_arch = "x86_64")]
-    x86_64::setup_kernel_cmdline(&config, sys_mem)?;
+    // Firmware mirror below 4 GiB is ram, load it after e820 table is
+    // built from guest memory end.
+    if let Some(firmware) = &config.firmware {
+        x86_64::load_firmware(firmware, sys_mem)?;
+        mark(&mut timeline, "firmware");
+    }
 
     Ok(boot_loader)
 }
@@ -0,0 +1,385 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! ACPI tables describing cpus and interrupt controllers of a hardware-reduced
+//! platform. Tables are laid out from a 16-byte aligned start address:
+//! RSDP, DSDT, FADT, MADT, XSDT and RSDT.
+
+use util::byte_code::ByteCode;
+use util::checksum::{checksum, obj_checksum};
+
+/// Default address of ACPI tables, in the BIOS area scanned for RSDP.
+pub const ACPI_TABLES_START: u64 = 0x000e_0000;
+
+const TABLE_ALIGN: usize = 16;
+const OEM_ID: [u8; 6] = *b"STRATO";
+const OEM_TABLE_ID: [u8; 8] = *b"STRATOVT";
+const CREATOR_ID: [u8; 4] = *b"STRA";
+
+// Variables and Structures below sourced from:
+// ACPI Specification 6.3
+const RSDP_REVISION: u8 = 2;
+/// Length of RSDP covered by the ACPI 1.0 checksum.
+const RSDP_V1_LENGTH: usize = 20;
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 3;
+const FADT_LENGTH: usize = 276;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_IAPC_BOOT_ARCH_OFFSET: usize = 109;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_MINOR_REVISION_OFFSET: usize = 131;
+const FADT_X_DSDT_OFFSET: usize = 140;
+const IAPC_BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const FADT_FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+const MADT_REVISION: u8 = 4;
+const MADT_FLAG_PCAT_COMPAT: u32 = 1;
+const MADT_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_TYPE_IOAPIC: u8 = 1;
+const MADT_TYPE_INT_SRC_OVERRIDE: u8 = 2;
+/// ISA bus of interrupt source override.
+const MADT_BUS_ISA: u8 = 0;
+const LOCAL_APIC_FLAGS_ENABLE: u32 = 1;
+const DSDT_REVISION: u8 = 2;
+const XSDT_REVISION: u8 = 1;
+const RSDT_REVISION: u8 = 1;
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    pub rsdt_address: u32,
+    length: u32,
+    pub xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl ByteCode for Rsdp {}
+
+impl Rsdp {
+    pub fn new(rsdt_address: u32, xsdt_address: u64) -> Self {
+        let mut rsdp = Rsdp {
+            signature: *b"RSD PTR ",
+            oem_id: OEM_ID,
+            revision: RSDP_REVISION,
+            rsdt_address,
+            length: std::mem::size_of::<Rsdp>() as u32,
+            xsdt_address,
+            ..Default::default()
+        };
+
+        let sum = checksum(&rsdp.as_bytes()[..RSDP_V1_LENGTH]);
+        rsdp.checksum = (-(sum as i8)) as u8;
+        let sum = obj_checksum(&rsdp);
+        rsdp.extended_checksum = (-(sum as i8)) as u8;
+
+        rsdp
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AcpiTableHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: [u8; 4],
+    creator_revision: u32,
+}
+
+impl ByteCode for AcpiTableHeader {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MadtLocalApic {
+    type_: u8,
+    length: u8,
+    processor_uid: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+impl ByteCode for MadtLocalApic {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MadtIoApic {
+    type_: u8,
+    length: u8,
+    ioapic_id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+impl ByteCode for MadtIoApic {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MadtIntSrcOverride {
+    type_: u8,
+    length: u8,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+impl ByteCode for MadtIntSrcOverride {}
+
+/// An ACPI system description table, its length and checksum are filled
+/// in when converted to bytes.
+struct AcpiTable {
+    header: AcpiTableHeader,
+    content: Vec<u8>,
+}
+
+impl AcpiTable {
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        AcpiTable {
+            header: AcpiTableHeader {
+                signature: *signature,
+                revision,
+                oem_id: OEM_ID,
+                oem_table_id: OEM_TABLE_ID,
+                oem_revision: 1,
+                creator_id: CREATOR_ID,
+                creator_revision: 1,
+                ..Default::default()
+            },
+            content: Vec::new(),
+        }
+    }
+
+    fn push<T: ByteCode>(&mut self, data: &T) {
+        self.content.extend_from_slice(data.as_bytes());
+    }
+
+    /// Write `value` at `offset` of the whole table, content is extended
+    /// with zeros as needed.
+    fn set_at(&mut self, offset: usize, value: &[u8]) {
+        let start = offset - std::mem::size_of::<AcpiTableHeader>();
+        if self.content.len() < start + value.len() {
+            self.content.resize(start + value.len(), 0);
+        }
+        self.content[start..start + value.len()].copy_from_slice(value);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = self.header;
+        header.length = (std::mem::size_of::<AcpiTableHeader>() + self.content.len()) as u32;
+        let mut bytes = header.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.content);
+        let sum = checksum(&bytes);
+        // Checksum is at offset 9 of header.
+        bytes[9] = (-(sum as i8)) as u8;
+        bytes
+    }
+}
+
+/// Append `table` to `blob` at next aligned offset, return its address.
+fn append_table(blob: &mut Vec<u8>, start: u64, table: &[u8]) -> u64 {
+    let offset = (blob.len() + TABLE_ALIGN - 1) & !(TABLE_ALIGN - 1);
+    blob.resize(offset, 0);
+    blob.extend_from_slice(table);
+    start + offset as u64
+}
+
+/// Build ACPI tables to be placed at `start`, RSDP is at the beginning.
+///
+/// # Arguments
+/// * `start` - guest address of tables, 16-byte aligned and below 4 GiB.
+/// * `num_cpus` - number of enabled cpus, with local APIC id from 0.
+/// * `max_cpus` - number of cpus including disabled hotpluggable ones.
+/// * `ioapic_addr` - IO APIC base address.
+/// * `lapic_addr` - Local APIC base address.
+/// * `irq_overrides` - ISA irqs wired to other IOAPIC pins, as (irq, pin).
+pub fn build_acpi_tables(
+    start: u64,
+    num_cpus: u8,
+    max_cpus: u8,
+    ioapic_addr: u32,
+    lapic_addr: u32,
+    irq_overrides: &[(u8, u8)],
+) -> Vec<u8> {
+    let mut blob = vec![0_u8; std::mem::size_of::<Rsdp>()];
+
+    let dsdt = AcpiTable::new(b"DSDT", DSDT_REVISION);
+    let dsdt_addr = append_table(&mut blob, start, &dsdt.to_bytes());
+
+    let mut fadt = AcpiTable::new(b"FACP", FADT_REVISION);
+    fadt.set_at(FADT_DSDT_OFFSET, &(dsdt_addr as u32).to_le_bytes());
+    fadt.set_at(
+        FADT_IAPC_BOOT_ARCH_OFFSET,
+        &IAPC_BOOT_ARCH_VGA_NOT_PRESENT.to_le_bytes(),
+    );
+    fadt.set_at(FADT_FLAGS_OFFSET, &FADT_FLAG_HW_REDUCED_ACPI.to_le_bytes());
+    fadt.set_at(FADT_MINOR_REVISION_OFFSET, &[FADT_MINOR_REVISION]);
+    fadt.set_at(FADT_X_DSDT_OFFSET, &dsdt_addr.to_le_bytes());
+    // Extend to full length of FADT.
+    fadt.set_at(FADT_LENGTH - 1, &[0]);
+    let fadt_addr = append_table(&mut blob, start, &fadt.to_bytes());
+
+    let mut madt = AcpiTable::new(b"APIC", MADT_REVISION);
+    madt.content.extend_from_slice(&lapic_addr.to_le_bytes());
+    madt.content
+        .extend_from_slice(&MADT_FLAG_PCAT_COMPAT.to_le_bytes());
+    for cpu_id in 0..max_cpus {
+        madt.push(&MadtLocalApic {
+            type_: MADT_TYPE_LOCAL_APIC,
+            length: std::mem::size_of::<MadtLocalApic>() as u8,
+            processor_uid: cpu_id,
+            apic_id: cpu_id,
+            flags: if cpu_id < num_cpus {
+                LOCAL_APIC_FLAGS_ENABLE
+            } else {
+                0
+            },
+        });
+    }
+    madt.push(&MadtIoApic {
+        type_: MADT_TYPE_IOAPIC,
+        length: std::mem::size_of::<MadtIoApic>() as u8,
+        // Same as MP table, next to the last local APIC id.
+        ioapic_id: max_cpus.wrapping_add(1),
+        reserved: 0,
+        address: ioapic_addr,
+        gsi_base: 0,
+    });
+    // Same as MP table, pins of the only IOAPIC are GSIs from 0, and ISA
+    // irqs keep their default polarity and trigger mode.
+    for (irq, pin) in irq_overrides {
+        madt.push(&MadtIntSrcOverride {
+            type_: MADT_TYPE_INT_SRC_OVERRIDE,
+            length: std::mem::size_of::<MadtIntSrcOverride>() as u8,
+            bus: MADT_BUS_ISA,
+            source: *irq,
+            gsi: u32::from(*pin),
+            flags: 0,
+        });
+    }
+    let madt_addr = append_table(&mut blob, start, &madt.to_bytes());
+
+    let mut xsdt = AcpiTable::new(b"XSDT", XSDT_REVISION);
+    let mut rsdt = AcpiTable::new(b"RSDT", RSDT_REVISION);
+    for addr in &[fadt_addr, madt_addr] {
+        xsdt.content.extend_from_slice(&addr.to_le_bytes());
+        rsdt.content
+            .extend_from_slice(&(*addr as u32).to_le_bytes());
+    }
+    let xsdt_addr = append_table(&mut blob, start, &xsdt.to_bytes());
+    let rsdt_addr = append_table(&mut blob, start, &rsdt.to_bytes());
+
+    let rsdp = Rsdp::new(rsdt_addr as u32, xsdt_addr);
+    blob[..std::mem::size_of::<Rsdp>()].copy_from_slice(rsdp.as_bytes());
+    blob
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Get table at guest address `addr` from `blob` placed at `start`,
+    /// check its signature and checksum.
+    fn table<'a>(blob: &'a [u8], start: u64, addr: u64, signature: &[u8; 4]) -> &'a [u8] {
+        let offset = (addr - start) as usize;
+        let header = AcpiTableHeader::from_bytes(
+            &blob[offset..offset + std::mem::size_of::<AcpiTableHeader>()],
+        )
+        .unwrap();
+        assert_eq!(&header.signature, signature);
+        let table = &blob[offset..offset + header.length as usize];
+        assert_eq!(checksum(table), 0);
+        table
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        let mut buf = [0_u8; 4];
+        buf.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(buf)
+    }
+
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        let mut buf = [0_u8; 8];
+        buf.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_acpi_struct_size() {
+        assert_eq!(std::mem::size_of::<Rsdp>(), 36);
+        assert_eq!(std::mem::size_of::<AcpiTableHeader>(), 36);
+        assert_eq!(std::mem::size_of::<MadtLocalApic>(), 8);
+        assert_eq!(std::mem::size_of::<MadtIoApic>(), 12);
+        assert_eq!(std::mem::size_of::<MadtIntSrcOverride>(), 10);
+    }
+
+    #[test]
+    fn test_build_acpi_tables() {
+        let start = ACPI_TABLES_START;
+        let blob = build_acpi_tables(start, 3, 4, 0xFEC0_0000, 0xFEE0_0000, &[(0, 2)]);
+
+        let rsdp = Rsdp::from_bytes(&blob[..std::mem::size_of::<Rsdp>()]).unwrap();
+        assert_eq!(&rsdp.signature, b"RSD PTR ");
+        assert_eq!(checksum(&blob[..RSDP_V1_LENGTH]), 0);
+        assert_eq!(checksum(&blob[..std::mem::size_of::<Rsdp>()]), 0);
+
+        let xsdt = table(&blob, start, rsdp.xsdt_address, b"XSDT");
+        let rsdt = table(&blob, start, u64::from(rsdp.rsdt_address), b"RSDT");
+        assert_eq!(xsdt.len(), 36 + 2 * 8);
+        assert_eq!(rsdt.len(), 36 + 2 * 4);
+        for index in 0..2 {
+            assert_eq!(
+                read_u64(xsdt, 36 + index * 8),
+                u64::from(read_u32(rsdt, 36 + index * 4))
+            );
+        }
+
+        let fadt = table(&blob, start, read_u64(xsdt, 36), b"FACP");
+        assert_eq!(fadt.len(), FADT_LENGTH);
+        assert_ne!(
+            read_u32(fadt, FADT_FLAGS_OFFSET) & FADT_FLAG_HW_REDUCED_ACPI,
+            0
+        );
+        let dsdt_addr = read_u64(fadt, FADT_X_DSDT_OFFSET);
+        assert_eq!(u64::from(read_u32(fadt, FADT_DSDT_OFFSET)), dsdt_addr);
+        table(&blob, start, dsdt_addr, b"DSDT");
+
+        let madt = table(&blob, start, read_u64(xsdt, 44), b"APIC");
+        assert_eq!(read_u32(madt, 36), 0xFEE0_0000);
+        assert_eq!(madt.len(), 36 + 8 + 4 * 8 + 12 + 10);
+        for cpu_id in 0..4 {
+            let entry = &madt[44 + cpu_id * 8..];
+            assert_eq!(
+                &entry[..4],
+                &[MADT_TYPE_LOCAL_APIC, 8, cpu_id as u8, cpu_id as u8]
+            );
+            // The last cpu is hotpluggable.
+            assert_eq!(read_u32(entry, 4), if cpu_id < 3 { 1 } else { 0 });
+        }
+        let ioapic = &madt[44 + 4 * 8..];
+        assert_eq!(&ioapic[..3], &[MADT_TYPE_IOAPIC, 12, 5]);
+        assert_eq!(read_u32(ioapic, 4), 0xFEC0_0000);
+        // The timer irq is routed to pin 2.
+        let iso = &madt[44 + 4 * 8 + 12..];
+        assert_eq!(&iso[..4], &[MADT_TYPE_INT_SRC_OVERRIDE, 10, 0, 0]);
+        assert_eq!(read_u32(iso, 4), 2);
+        assert_eq!(&iso[8..10], &[0, 0]);
+    }
+}
@@ -12,12 +12,43 @@
 
 use util::byte_code::ByteCode;
 
+use super::errors::{ErrorKind, Result};
+use super::VMLINUX_RAM_START;
+
 pub const E820_RAM: u32 = 1;
 pub const E820_RESERVED: u32 = 2;
+/// Max number of e820 entries in zero page.
+pub const E820_MAX_ENTRIES: usize = 0x80;
+/// Oldest boot protocol version supported, 2.00.
 pub const BOOT_VERSION: u16 = 0x0200;
 pub const BOOT_FLAG: u16 = 0xAA55;
 pub const HDRS: u32 = 0x5372_6448;
 pub const UNDEFINED_ID: u8 = 0xFF;
+/// Protected-mode kernel is loaded at 0x100000.
+pub const LOADED_HIGH: u8 = 1 << 0;
+/// Kernel can be loaded above 4G, and so can initrd.
+pub const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+/// Kernel has a 64-bit EFI handover entry at `handover_offset`.
+pub const XLF_EFI_HANDOVER_64: u16 = 1 << 3;
+/// Boot protocol version which has `relocatable_kernel` in header.
+const BOOT_VERSION_RELOCATABLE: u16 = 0x0205;
+/// Boot protocol version which has `cmdline_size` in header.
+const BOOT_VERSION_CMDLINE_SIZE: u16 = 0x0206;
+/// Boot protocol version which has `pref_address` in header.
+const BOOT_VERSION_PREF_ADDRESS: u16 = 0x020a;
+/// Boot protocol version which has `handover_offset` in header.
+const BOOT_VERSION_HANDOVER: u16 = 0x020b;
+/// 64-bit EFI handover entry is this far after the 32-bit one.
+const HANDOVER_64_OFFSET: u64 = 0x200;
+/// Max length of kernel cmdline before `cmdline_size` is defined.
+const CMDLINE_MAX_LEGACY: u32 = 255;
+/// Video mode 3, 80x25 color text.
+pub const VIDEO_MODE_TEXT_80X25: u8 = 3;
+/// BIOS int 0x15 ah=0x88 reports at most 63 MiB of extended memory.
+const EXT_MEM_K_MAX: u64 = 0xfc00;
+/// Types of `setup_data` entries.
+pub const SETUP_DTB: u32 = 2;
+pub const SETUP_RNG_SEED: u32 = 9;
 
 // Structures below sourced from:
 // https://www.kernel.org/doc/html/latest/x86/boot.html
@@ -27,7 +58,7 @@ pub const UNDEFINED_ID: u8 = 0xFF;
 pub struct RealModeKernelHeader {
     pub setup_sects: u8,
     root_flags: u16,
-    syssize: u32,
+    pub syssize: u32,
     ram_size: u16,
     vid_mode: u16,
     root_dev: u16,
@@ -50,19 +81,19 @@ pub struct RealModeKernelHeader {
     ext_loader_type: u8,
     cmdline_ptr: u32,
     initrd_addr_max: u32,
-    kernel_alignment: u32,
-    relocatable_kernel: u8,
+    pub kernel_alignment: u32,
+    pub relocatable_kernel: u8,
     min_alignment: u8,
-    xloadflags: u16,
+    pub xloadflags: u16,
     cmdline_size: u32,
     hardware_subarch: u32,
     hardware_subarch_data: u64,
     payload_offset: u32,
     payload_length: u32,
     setup_data: u64,
-    pref_address: u64,
+    pub pref_address: u64,
     init_size: u32,
-    handover_offset: u32,
+    pub handover_offset: u32,
     kernel_info_offset: u32,
 }
 
@@ -95,6 +126,131 @@ impl RealModeKernelHeader {
         self.ramdisk_image = ramdisk_image;
         self.ramdisk_size = ramdisk_size;
     }
+
+    /// Max length of kernel cmdline, excluding the terminating NUL.
+    pub fn cmdline_max(&self) -> u32 {
+        if self.version < BOOT_VERSION_CMDLINE_SIZE {
+            CMDLINE_MAX_LEGACY
+        } else {
+            self.cmdline_size
+        }
+    }
+
+    /// Whether kernel and initrd can be loaded above 4G.
+    pub fn can_load_above_4g(&self) -> bool {
+        self.xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G != 0
+    }
+
+    /// Whether kernel can be loaded at any address aligned to
+    /// `kernel_alignment` instead of `code32_start`.
+    pub fn relocatable(&self) -> bool {
+        self.version >= BOOT_VERSION_RELOCATABLE && self.relocatable_kernel != 0
+    }
+
+    /// Preferred load address of relocatable kernel, `code32_start` if
+    /// header doesn't have one.
+    pub fn preferred_address(&self) -> u64 {
+        if self.version >= BOOT_VERSION_PREF_ADDRESS && self.pref_address != 0 {
+            self.pref_address
+        } else {
+            u64::from(self.code32_start)
+        }
+    }
+
+    /// Address of 64-bit EFI handover entry of kernel loaded at
+    /// `code32_start`, `None` if kernel doesn't support it.
+    pub fn efi_handover_entry(&self) -> Option<u64> {
+        if self.version >= BOOT_VERSION_HANDOVER && self.xloadflags & XLF_EFI_HANDOVER_64 != 0 {
+            Some(
+                u64::from(self.code32_start) + HANDOVER_64_OFFSET + u64::from(self.handover_offset),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Set address of the first `setup_data` entry, 0 for none.
+    pub fn set_setup_data(&mut self, setup_data: u64) {
+        self.setup_data = setup_data;
+    }
+
+    /// Address and max length of kernel cmdline.
+    pub fn cmdline(&self) -> (u32, u32) {
+        (self.cmdline_ptr, self.cmdline_size)
+    }
+
+    /// Low 32 bits of initrd address and size.
+    pub fn ramdisk(&self) -> (u32, u32) {
+        (self.ramdisk_image, self.ramdisk_size)
+    }
+}
+
+/// Header of a `setup_data` entry, followed by `len` bytes of payload.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SetupDataHeader {
+    /// Address of next entry, 0 for the last one.
+    pub next: u64,
+    pub type_: u32,
+    pub len: u32,
+}
+
+impl ByteCode for SetupDataHeader {}
+
+/// Video state left by BIOS, at the start of zero page.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct ScreenInfo {
+    /// Cursor column.
+    pub orig_x: u8,
+    /// Cursor row.
+    pub orig_y: u8,
+    /// KiB of memory above 1 MiB, filled by boot loader from guest memory.
+    pub ext_mem_k: u16,
+    pub orig_video_page: u16,
+    pub orig_video_mode: u8,
+    pub orig_video_cols: u8,
+    flags: u8,
+    unused2: u8,
+    orig_video_ega_bx: u16,
+    unused3: u16,
+    pub orig_video_lines: u8,
+    pub orig_video_is_vga: u8,
+    /// Font height in pixels.
+    pub orig_video_points: u16,
+    lfb_and_vesa: [u8; 0x2e],
+}
+
+impl ByteCode for ScreenInfo {}
+
+impl Default for ScreenInfo {
+    /// VGA 80x25 text mode with cursor at top left.
+    fn default() -> Self {
+        ScreenInfo {
+            orig_x: 0,
+            orig_y: 0,
+            ext_mem_k: 0,
+            orig_video_page: 0,
+            orig_video_mode: VIDEO_MODE_TEXT_80X25,
+            orig_video_cols: 80,
+            flags: 0,
+            unused2: 0,
+            orig_video_ega_bx: 0,
+            unused3: 0,
+            orig_video_lines: 25,
+            orig_video_is_vga: 1,
+            orig_video_points: 16,
+            lfb_and_vesa: [0; 0x2e],
+        }
+    }
+}
+
+impl ScreenInfo {
+    /// Set `ext_mem_k` from guest memory end `mem_end`.
+    pub fn set_ext_mem(&mut self, mem_end: u64) {
+        let ext_mem_k = mem_end.saturating_sub(VMLINUX_RAM_START) >> 10;
+        self.ext_mem_k = std::cmp::min(ext_mem_k, EXT_MEM_K_MAX) as u16;
+    }
 }
 
 #[repr(C, packed)]
@@ -108,12 +264,13 @@ pub struct E820Entry {
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BootParams {
-    screen_info: [u8; 0x40],
+    screen_info: ScreenInfo,
     apm_bios_info: [u8; 0x14],
     pad1: u32,
     tboot_addr: [u8; 0x8],
     ist_info: [u8; 0x10],
-    pad2: [u8; 0x10],
+    acpi_rsdp_addr: u64,
+    pad2: [u8; 0x8],
     hd0_info: [u8; 0x10],
     hd1_info: [u8; 0x10],
     sys_desc_table: [u8; 0x10],
@@ -137,7 +294,7 @@ pub struct BootParams {
     kernel_header: RealModeKernelHeader, // offset: 0x1f1
     pad6: [u8; 0x24],
     edd_mbr_sig_buffer: [u8; 0x40],
-    e820_table: [E820Entry; 0x80],
+    e820_table: [E820Entry; E820_MAX_ENTRIES],
     pad8: [u8; 0x30],
     eddbuf: [u8; 0x1ec],
 }
@@ -158,9 +315,59 @@ impl BootParams {
         }
     }
 
-    pub fn add_e820_entry(&mut self, addr: u64, size: u64, type_: u32) {
-        self.e820_table[self.e820_entries as usize] = E820Entry { addr, size, type_ };
+    pub fn set_screen_info(&mut self, screen_info: ScreenInfo) {
+        self.screen_info = screen_info;
+    }
+
+    /// Set high 32 bits of initrd address and size.
+    pub fn set_ext_ramdisk(&mut self, ext_ramdisk_image: u32, ext_ramdisk_size: u32) {
+        self.ext_ramdisk_image = ext_ramdisk_image;
+        self.ext_ramdisk_size = ext_ramdisk_size;
+    }
+
+    /// Set address of ACPI RSDP, so that kernel doesn't need to scan for it.
+    pub fn set_acpi_rsdp_addr(&mut self, acpi_rsdp_addr: u64) {
+        self.acpi_rsdp_addr = acpi_rsdp_addr;
+    }
+
+    pub fn kernel_header(&self) -> RealModeKernelHeader {
+        self.kernel_header
+    }
+
+    /// High 32 bits of initrd address and size.
+    pub fn ext_ramdisk(&self) -> (u32, u32) {
+        (self.ext_ramdisk_image, self.ext_ramdisk_size)
+    }
+
+    pub fn acpi_rsdp_addr(&self) -> u64 {
+        self.acpi_rsdp_addr
+    }
+
+    pub fn e820_entries(&self) -> u8 {
+        self.e820_entries
+    }
+
+    /// Valid e820 entries as (addr, size, type), at most `E820_MAX_ENTRIES`.
+    pub fn e820_table(&self) -> Vec<(u64, u64, u32)> {
+        let count = std::cmp::min(self.e820_entries as usize, E820_MAX_ENTRIES);
+        self.e820_table[..count]
+            .iter()
+            .map(|entry| (entry.addr, entry.size, entry.type_))
+            .collect()
+    }
+
+    /// Append an entry to e820 table.
+    ///
+    /// # Errors
+    /// * `E820Overflow`: The table already has `E820_MAX_ENTRIES` entries.
+    pub fn add_e820_entry(&mut self, addr: u64, size: u64, type_: u32) -> Result<()> {
+        let index = self.e820_entries as usize;
+        if index >= E820_MAX_ENTRIES {
+            return Err(ErrorKind::E820Overflow(E820_MAX_ENTRIES).into());
+        }
+        self.e820_table[index] = E820Entry { addr, size, type_ };
         self.e820_entries += 1;
+        Ok(())
     }
 }
 
@@ -170,8 +377,13 @@ mod test {
     use std::sync::Arc;
 
     use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+    use util::kernel_cmdline::KernelCmdline;
 
-    use super::super::{setup_boot_params, X86BootLoaderConfig};
+    use super::super::errors::{Error, ErrorKind};
+    use super::super::{
+        setup_boot_params, BootLayout, SetupData, X86BootLoaderConfig, ISA_TIMER_IRQ_OVERRIDE,
+        SETUP_DATA_START,
+    };
     use super::*;
 
     #[test]
@@ -187,21 +399,37 @@ mod test {
             .unwrap();
 
         let config = X86BootLoaderConfig {
-            kernel: PathBuf::new(),
-            initrd: Some(PathBuf::new()),
+            kernel: None,
+            initrd: vec![PathBuf::new()],
             initrd_size: 0x1_0000,
-            kernel_cmdline: String::from("this_is_a_piece_of_test_string"),
+            kernel_cmdline: KernelCmdline::from("this_is_a_piece_of_test_string"),
             cpu_count: 2,
+            max_cpus: 2,
             gap_range: (0xC000_0000, 0x4000_0000),
             ioapic_addr: 0xFEC0_0000,
             lapic_addr: 0xFEE0_0000,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: Vec::new(),
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            use_gib_pages: false,
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
         };
         let (_, initrd_addr_tmp) = setup_boot_params(&config, &space, None).unwrap();
         assert_eq!(initrd_addr_tmp, 0xfff_0000);
         let test_zero_page = space
             .read_object::<BootParams>(GuestAddress(0x0000_7000))
             .unwrap();
-        assert_eq!(test_zero_page.e820_entries, 4);
+        assert_eq!(test_zero_page.e820_entries, 5);
+        assert_eq!({ test_zero_page.acpi_rsdp_addr }, 0x000E_0000);
 
         unsafe {
             assert_eq!(test_zero_page.e820_table[0].addr, 0);
@@ -212,13 +440,302 @@ mod test {
             assert_eq!(test_zero_page.e820_table[1].size, 0x400);
             assert_eq!(test_zero_page.e820_table[1].type_, 2);
 
-            assert_eq!(test_zero_page.e820_table[2].addr, 0x000F_0000);
-            assert_eq!(test_zero_page.e820_table[2].size, 0);
+            assert_eq!(test_zero_page.e820_table[2].addr, 0x000E_0000);
             assert_eq!(test_zero_page.e820_table[2].type_, 2);
 
-            assert_eq!(test_zero_page.e820_table[3].addr, 0x0010_0000);
-            assert_eq!(test_zero_page.e820_table[3].size, 0x0ff0_0000);
-            assert_eq!(test_zero_page.e820_table[3].type_, 1);
+            assert_eq!(test_zero_page.e820_table[3].addr, 0x000F_0000);
+            assert_eq!(test_zero_page.e820_table[3].size, 0);
+            assert_eq!(test_zero_page.e820_table[3].type_, 2);
+
+            assert_eq!(test_zero_page.e820_table[4].addr, 0x0010_0000);
+            assert_eq!(test_zero_page.e820_table[4].size, 0x0ff0_0000);
+            assert_eq!(test_zero_page.e820_table[4].type_, 1);
+        }
+
+        // screen_info at offsets of `struct screen_info` in Linux.
+        assert_eq!(std::mem::size_of::<ScreenInfo>(), 0x40);
+        let mut bytes = Vec::new();
+        space
+            .read(&mut bytes, GuestAddress(0x0000_7000), 0x40)
+            .unwrap();
+        assert_eq!(u16::from_le_bytes([bytes[0x02], bytes[0x03]]), 0xfc00);
+        assert_eq!(bytes[0x06], VIDEO_MODE_TEXT_80X25);
+        assert_eq!(bytes[0x07], 80);
+        assert_eq!(bytes[0x0e], 25);
+        assert_eq!(bytes[0x0f], 1);
+        assert_eq!(u16::from_le_bytes([bytes[0x10], bytes[0x11]]), 16);
+
+        let mut config = config;
+        config.screen_info.orig_y = 3;
+        config.screen_info.orig_video_lines = 50;
+        config.screen_info.orig_video_points = 8;
+        setup_boot_params(&config, &space, None).unwrap();
+        let mut bytes = Vec::new();
+        space
+            .read(&mut bytes, GuestAddress(0x0000_7000), 0x40)
+            .unwrap();
+        assert_eq!(bytes[0x01], 3);
+        assert_eq!(bytes[0x0e], 50);
+        assert_eq!(u16::from_le_bytes([bytes[0x10], bytes[0x11]]), 8);
+
+        let mut screen_info = ScreenInfo::default();
+        screen_info.set_ext_mem(0x0140_0000);
+        assert_eq!({ screen_info.ext_mem_k }, 19 << 10);
+        screen_info.set_ext_mem(0x8_0000);
+        assert_eq!({ screen_info.ext_mem_k }, 0);
+    }
+
+    #[test]
+    fn test_initrd_above_4g() {
+        const G: u64 = 1 << 30;
+        // 6G guest RAM split by the 32-bit gap [3G, 4G).
+        let root = Region::init_container_region(1 << 40);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let low =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 3 * G, -1, 0, false, false).unwrap());
+        let high =
+            Arc::new(HostMemMapping::new(GuestAddress(4 * G), 3 * G, -1, 0, false, false).unwrap());
+        root.add_subregion(Region::init_ram_region(low), 0).unwrap();
+        root.add_subregion(Region::init_ram_region(high), 4 * G)
+            .unwrap();
+
+        let config = X86BootLoaderConfig {
+            kernel: None,
+            initrd: vec![PathBuf::new()],
+            initrd_size: 512 << 20,
+            kernel_cmdline: KernelCmdline::new(),
+            cpu_count: 1,
+            max_cpus: 1,
+            gap_range: (3 * G, G),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: Vec::new(),
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            use_gib_pages: false,
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
+        };
+
+        let mut boot_hdr = RealModeKernelHeader::new(0, 0, 0, 0);
+        let (_, initrd_addr) = setup_boot_params(&config, &space, Some(boot_hdr)).unwrap();
+        assert!(initrd_addr < 4 * G);
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!({ zero_page.ext_ramdisk_image }, 0);
+
+        boot_hdr.xloadflags = XLF_CAN_BE_LOADED_ABOVE_4G;
+        let (_, initrd_addr) = setup_boot_params(&config, &space, Some(boot_hdr)).unwrap();
+        assert_eq!(initrd_addr, 7 * G - (512 << 20));
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!({ zero_page.ext_ramdisk_image }, (initrd_addr >> 32) as u32);
+        assert_eq!(
+            { zero_page.kernel_header.ramdisk_image },
+            initrd_addr as u32
+        );
+        assert_eq!({ zero_page.ext_ramdisk_size }, 0);
+        assert_eq!({ zero_page.kernel_header.ramdisk_size }, 512 << 20);
+    }
+
+    #[test]
+    fn test_setup_data() {
+        let root = Region::init_container_region(0x1000_0000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, -1, 0, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let rng_seed = SetupData::rng_seed().unwrap();
+        assert_eq!(rng_seed.type_, SETUP_RNG_SEED);
+        assert_eq!(rng_seed.data.len(), 32);
+        assert_ne!(rng_seed, SetupData::rng_seed().unwrap());
+
+        let dtb = SetupData {
+            type_: SETUP_DTB,
+            data: vec![0xd0, 0x0d, 0xfe, 0xed, 0x01],
+        };
+        let mut config = X86BootLoaderConfig {
+            kernel: None,
+            initrd: Vec::new(),
+            initrd_size: 0,
+            kernel_cmdline: KernelCmdline::new(),
+            cpu_count: 1,
+            max_cpus: 1,
+            gap_range: (0xC000_0000, 0x4000_0000),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: vec![dtb.clone(), rng_seed.clone()],
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            use_gib_pages: false,
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
+        };
+        setup_boot_params(&config, &space, None).unwrap();
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!({ zero_page.kernel_header.setup_data }, SETUP_DATA_START);
+
+        // Walk the list and compare with config.
+        let mut addr = zero_page.kernel_header.setup_data;
+        for entry in config.setup_data.iter() {
+            assert_ne!(addr, 0);
+            assert_eq!(addr % 8, 0);
+            let header = space
+                .read_object::<SetupDataHeader>(GuestAddress(addr))
+                .unwrap();
+            assert_eq!(header.type_, entry.type_);
+            assert_eq!(header.len as usize, entry.data.len());
+            let mut data = vec![0_u8; header.len as usize];
+            space
+                .read(
+                    &mut data.as_mut_slice(),
+                    GuestAddress(addr + 16),
+                    u64::from(header.len),
+                )
+                .unwrap();
+            assert_eq!(data, entry.data);
+            addr = header.next;
+        }
+        assert_eq!(addr, 0);
+        // First payload of 5 bytes is padded to 8.
+        assert_eq!(
+            space
+                .read_object::<SetupDataHeader>(GuestAddress(SETUP_DATA_START))
+                .unwrap()
+                .next,
+            SETUP_DATA_START + 24
+        );
+
+        config.setup_data = Vec::new();
+        setup_boot_params(&config, &space, None).unwrap();
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!({ zero_page.kernel_header.setup_data }, 0);
+
+        config.setup_data = vec![SetupData {
+            type_: SETUP_DTB,
+            data: vec![0; 0x1_0000],
+        }];
+        match setup_boot_params(&config, &space, None) {
+            Err(Error(ErrorKind::SetupDataOverflow(size, max), _)) => {
+                assert_eq!((size, max), (0x1_0010, 0x1_0000))
+            }
+            _ => panic!("Setup data larger than its area should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_e820_overflow() {
+        let mut boot_params = BootParams::new(RealModeKernelHeader::default());
+        for index in 0..130_u64 {
+            let result = boot_params.add_e820_entry(index * 0x2000, 0x1000, E820_RAM);
+            if index < E820_MAX_ENTRIES as u64 {
+                assert!(result.is_ok());
+                continue;
+            }
+            match result {
+                Err(Error(ErrorKind::E820Overflow(max), _)) => assert_eq!(max, E820_MAX_ENTRIES),
+                _ => panic!("e820 entry {} above max should be rejected", index + 1),
+            }
+        }
+        assert_eq!(boot_params.e820_entries() as usize, E820_MAX_ENTRIES);
+    }
+
+    #[test]
+    fn test_e820_ram_ranges() {
+        const G: u64 = 1 << 30;
+        // Adjacent RAM [0, 256M) and [256M, 512M), and disjoint RAM [4G, 4.25G).
+        let root = Region::init_container_region(1 << 40);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        for (start, size) in &[
+            (0, 0x1000_0000),
+            (0x1000_0000, 0x1000_0000),
+            (4 * G, 0x1000_0000),
+        ] {
+            let ram = Arc::new(
+                HostMemMapping::new(GuestAddress(*start), *size, -1, 0, false, false).unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram), *start)
+                .unwrap();
+        }
+
+        let config = X86BootLoaderConfig {
+            kernel: None,
+            initrd: Vec::new(),
+            initrd_size: 0,
+            kernel_cmdline: KernelCmdline::new(),
+            cpu_count: 1,
+            max_cpus: 1,
+            gap_range: (0xC000_0000, 0x4000_0000),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: Vec::new(),
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            use_gib_pages: false,
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
+        };
+        setup_boot_params(&config, &space, None).unwrap();
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!(zero_page.e820_entries(), 6);
+        unsafe {
+            assert_eq!(zero_page.e820_table[4].addr, 0x0010_0000);
+            assert_eq!(zero_page.e820_table[4].size, 0x1ff0_0000);
+            assert_eq!(zero_page.e820_table[4].type_, E820_RAM);
+
+            assert_eq!(zero_page.e820_table[5].addr, 4 * G);
+            assert_eq!(zero_page.e820_table[5].size, 0x1000_0000);
+            assert_eq!(zero_page.e820_table[5].type_, E820_RAM);
+        }
+
+        // Each page of RAM with a hole after it takes one more entry.
+        for index in 0..123 {
+            let start = 8 * G + index * 0x2000;
+            let ram = Arc::new(
+                HostMemMapping::new(GuestAddress(start), 0x1000, -1, 0, false, false).unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram), start)
+                .unwrap();
+        }
+        match setup_boot_params(&config, &space, None) {
+            Err(Error(ErrorKind::TooManyE820Entries(entries, max), _)) => {
+                assert_eq!((entries, max), (129, E820_MAX_ENTRIES))
+            }
+            _ => panic!("Memory map above zero page limit should be rejected"),
         }
     }
 }
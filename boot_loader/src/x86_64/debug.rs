@@ -0,0 +1,198 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Human-readable reports of boot structures read back from guest memory,
+//! for debugging guests which fail early.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use address_space::{AddressSpace, GuestAddress};
+use util::checksum::checksum;
+
+use super::bootparam::{BootParams, E820_RAM, E820_RESERVED};
+use super::mptable::{ConfigTableHeader, FloatingPointer};
+
+// Offsets of fields in MP floating pointer and config table header.
+const FP_POINTER_OFFSET: usize = 4;
+const FP_SPEC_OFFSET: usize = 9;
+const CT_LENGTH_OFFSET: usize = 4;
+const CT_ENTRY_COUNT_OFFSET: usize = 34;
+const CT_LAPIC_OFFSET: usize = 36;
+/// Processor entry is 20 bytes, the others are 8.
+const MP_PROCESSOR_ENTRY_SIZE: usize = 20;
+const MP_ENTRY_SIZE: usize = 8;
+const MP_ENTRY_NAMES: [&str; 5] = [
+    "processor",
+    "bus",
+    "ioapic",
+    "io interrupt",
+    "local interrupt",
+];
+
+fn read_bytes(sys_mem: &Arc<AddressSpace>, addr: u64, len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    sys_mem
+        .read(&mut bytes, GuestAddress(addr), len as u64)
+        .map_err(|e| format!("failed to read 0x{:x} bytes at 0x{:x}: {}", len, addr, e))?;
+    Ok(bytes)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0_u8; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn checksum_state(bytes: &[u8]) -> &'static str {
+    if checksum(bytes) == 0 {
+        "ok"
+    } else {
+        "BAD"
+    }
+}
+
+fn e820_type(type_: u32) -> &'static str {
+    match type_ {
+        E820_RAM => "ram",
+        E820_RESERVED => "reserved",
+        _ => "unknown",
+    }
+}
+
+/// Report of zero page at `addr`: cmdline, initrd, ACPI RSDP and e820 table.
+///
+/// # Arguments
+/// * `sys_mem` - guest memory.
+/// * `addr` - Address of zero page, `BootLayout::zero_page`.
+pub fn dump_zero_page(sys_mem: &Arc<AddressSpace>, addr: u64) -> String {
+    let mut report = format!("zero page at 0x{:x}:\n", addr);
+    let params = match sys_mem.read_object::<BootParams>(GuestAddress(addr)) {
+        Ok(params) => params,
+        Err(e) => {
+            let _ = writeln!(report, "  failed to read zero page: {}", e);
+            return report;
+        }
+    };
+
+    let header = params.kernel_header();
+    let (cmdline_ptr, cmdline_size) = header.cmdline();
+    let _ = writeln!(
+        report,
+        "  cmdline: ptr 0x{:x}, max size {}",
+        cmdline_ptr, cmdline_size
+    );
+    let (ramdisk_image, ramdisk_size) = header.ramdisk();
+    let (ext_image, ext_size) = params.ext_ramdisk();
+    let _ = writeln!(
+        report,
+        "  ramdisk: image 0x{:x}, size 0x{:x}",
+        (u64::from(ext_image) << 32) | u64::from(ramdisk_image),
+        (u64::from(ext_size) << 32) | u64::from(ramdisk_size)
+    );
+    let _ = writeln!(report, "  acpi rsdp: 0x{:x}", params.acpi_rsdp_addr());
+
+    let table = params.e820_table();
+    let _ = writeln!(report, "  e820 entries: {}", params.e820_entries());
+    for (start, size, type_) in table {
+        let _ = writeln!(
+            report,
+            "    [0x{:016x}-0x{:016x}) {}",
+            start,
+            start + size,
+            e820_type(type_)
+        );
+    }
+    report
+}
+
+/// Report of MP table whose floating pointer is at `addr`: entry counts
+/// by type and checksums.
+///
+/// # Arguments
+/// * `sys_mem` - guest memory.
+/// * `addr` - Address of MP floating pointer, `BootLayout::mptable`.
+pub fn dump_mptable(sys_mem: &Arc<AddressSpace>, addr: u64) -> String {
+    let mut report = format!("mptable at 0x{:x}:\n", addr);
+    if let Err(e) = dump_mptable_entries(sys_mem, addr, &mut report) {
+        let _ = writeln!(report, "  {}", e);
+    }
+    report
+}
+
+fn dump_mptable_entries(
+    sys_mem: &Arc<AddressSpace>,
+    addr: u64,
+    report: &mut String,
+) -> Result<(), String> {
+    let fp = read_bytes(sys_mem, addr, std::mem::size_of::<FloatingPointer>())?;
+    if &fp[..4] != b"_MP_" {
+        return Err("no MP floating pointer".to_string());
+    }
+    let header_addr = u64::from(read_u32(&fp, FP_POINTER_OFFSET));
+    let _ = writeln!(
+        report,
+        "  floating pointer: config table 0x{:x}, spec 1.{}, checksum {}",
+        header_addr,
+        fp[FP_SPEC_OFFSET],
+        checksum_state(&fp)
+    );
+
+    let header = read_bytes(
+        sys_mem,
+        header_addr,
+        std::mem::size_of::<ConfigTableHeader>(),
+    )?;
+    if &header[..4] != b"PCMP" {
+        return Err("no MP config table".to_string());
+    }
+    let length = read_u16(&header, CT_LENGTH_OFFSET) as usize;
+    let entry_count = read_u16(&header, CT_ENTRY_COUNT_OFFSET);
+    if length < header.len() {
+        return Err(format!("config table length {} too short", length));
+    }
+    let table = read_bytes(sys_mem, header_addr, length)?;
+    let _ = writeln!(
+        report,
+        "  config table: length {}, {} entries, lapic 0x{:x}, checksum {}",
+        length,
+        entry_count,
+        read_u32(&header, CT_LAPIC_OFFSET),
+        checksum_state(&table)
+    );
+
+    let mut counts = [0_usize; MP_ENTRY_NAMES.len()];
+    let mut offset = header.len();
+    for _ in 0..entry_count {
+        let type_ = match table.get(offset) {
+            Some(type_) => *type_ as usize,
+            None => return Err(format!("entry at offset {} beyond table", offset)),
+        };
+        if type_ >= counts.len() {
+            return Err(format!("unknown entry type {} at offset {}", type_, offset));
+        }
+        counts[type_] += 1;
+        offset += if type_ == 0 {
+            MP_PROCESSOR_ENTRY_SIZE
+        } else {
+            MP_ENTRY_SIZE
+        };
+    }
+    for (name, count) in MP_ENTRY_NAMES.iter().zip(counts.iter()) {
+        let _ = writeln!(report, "    {}: {}", name, count);
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use util::byte_code::ByteCode;
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const EM_X86_64: u16 = 62;
+pub const PT_LOAD: u32 = 1;
+pub const PT_NOTE: u32 = 4;
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+
+// Structures below sourced from:
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Elf64Header {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+impl ByteCode for Elf64Header {}
+
+impl Elf64Header {
+    /// Whether it's a little-endian 64-bit ELF header.
+    pub fn is_elf64(&self) -> bool {
+        self.e_ident[..4] == ELF_MAGIC
+            && self.e_ident[EI_CLASS] == ELFCLASS64
+            && self.e_ident[EI_DATA] == ELFDATA2LSB
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Elf64ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+impl ByteCode for Elf64ProgramHeader {}
+
+/// Header of an entry in note segment, followed by name and desc, each
+/// padded to 4 bytes.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Elf64Note {
+    pub n_namesz: u32,
+    pub n_descsz: u32,
+    pub n_type: u32,
+}
+
+impl ByteCode for Elf64Note {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_elf_struct_size() {
+        assert_eq!(std::mem::size_of::<Elf64Header>(), 64);
+        assert_eq!(std::mem::size_of::<Elf64ProgramHeader>(), 56);
+        assert_eq!(std::mem::size_of::<Elf64Note>(), 12);
+    }
+}
@@ -12,6 +12,8 @@
 
 use std::convert::Into;
 
+use hypervisor::SegmentRegister;
+
 // /*
 //  * Constructor for a conventional segment GDT (or LDT) entry.
 //  * This is a macro so it can be used in initializers.
@@ -53,13 +55,13 @@ impl GdtEntry {
 //   Bits(22): D/B, Default Operation Size
 //   Bits(23): G, Granularity
 //   Bits(24 - 31): Base Address 24, 31
-impl Into<kvm_bindings::kvm_segment> for GdtEntry {
-    fn into(self) -> kvm_bindings::kvm_segment {
+impl Into<SegmentRegister> for GdtEntry {
+    fn into(self) -> SegmentRegister {
         let base = (self.0 >> 16 & 0x00ff_ffff) | (self.0 >> (56 - 24) & 0xff00_0000);
         let limit = (self.0 >> (48 - 16) & 0x000f_0000) | (self.0 & 0x0000_ffff);
         let flags = (self.0 >> 40) & 0x0000_f0ff;
 
-        kvm_bindings::kvm_segment {
+        SegmentRegister {
             base,
             limit: limit as u32,
             type_: (flags & 0xf) as u8,
@@ -84,7 +86,6 @@ impl Into<u64> for GdtEntry {
 #[cfg(test)]
 mod test {
     use super::*;
-    use kvm_bindings::kvm_segment;
 
     #[test]
     fn test_gdt_entry() {
@@ -95,7 +96,7 @@ mod test {
     #[test]
     fn test_segment() {
         let gdt_entry = GdtEntry(0xaf9b000000ffff);
-        let seg: kvm_segment = gdt_entry.into();
+        let seg: SegmentRegister = gdt_entry.into();
 
         assert_eq!(1, seg.g);
         assert_eq!(0, seg.db);
@@ -109,4 +110,31 @@ mod test {
         assert_eq!(1048575, seg.limit);
         assert_eq!(0, seg.unusable);
     }
+
+    #[test]
+    fn test_segment_flags_packing() {
+        // Data segment: G=1, D/B=1, P=1, DPL=3, S=1, type=3, base 0x1234_5678.
+        let gdt_entry = GdtEntry::new(0xc0f3, 0x1234_5678, 0xabcde);
+        let seg: SegmentRegister = gdt_entry.into();
+
+        assert_eq!(1, seg.g);
+        assert_eq!(1, seg.db);
+        assert_eq!(0, seg.l);
+        assert_eq!(0, seg.avl);
+        assert_eq!(1, seg.present);
+        assert_eq!(3, seg.dpl);
+        assert_eq!(1, seg.s);
+        assert_eq!(3, seg.type_);
+        assert_eq!(0x1234_5678, seg.base);
+        assert_eq!(0xabcde, seg.limit);
+
+        // Only AVL set, other flags clear.
+        let seg: SegmentRegister = GdtEntry::new(0x1000, 0, 0).into();
+        assert_eq!(1, seg.avl);
+        assert_eq!(0, seg.g);
+        assert_eq!(0, seg.db);
+        assert_eq!(0, seg.l);
+        assert_eq!(0, seg.present);
+        assert_eq!(0, seg.s);
+    }
 }
@@ -0,0 +1,245 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use super::errors::{ErrorKind, Result};
+use super::mptable::FloatingPointer;
+use super::{
+    check_overlap, BOOT_GDT_MAX, BOOT_GDT_OFFSET, BOOT_IDT_OFFSET, BOOT_LOADER_SP, BOOT_TSS_SIZE,
+    CMDLINE_MAX_SIZE, CMDLINE_START, EBDA_START, MB_BIOS_BEGIN, PML4_START, PVH_INFO_START,
+    SETUP_DATA_START, VGA_RAM_BEGIN, VMLINUX_RAM_START, ZERO_PAGE_START,
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+/// Max size of `setup_data` list.
+pub const SETUP_DATA_MAX_SIZE: u64 = 0x0001_0000;
+/// Offset of PVH module list from `hvm_start_info`.
+const PVH_MODLIST_OFFSET: u64 = 0x40;
+/// Offset of PVH memory map from `hvm_start_info`.
+const PVH_MEMMAP_OFFSET: u64 = 0x100;
+/// PML4, PDPTE and one PD page mapping the first 1 GiB.
+const MIN_PAGE_TABLES: u64 = 3;
+/// MP floating pointer is 16-byte aligned.
+const MPTABLE_ALIGN: u64 = 0x10;
+/// Areas where guest searches MP floating pointer, per MP spec: the last
+/// KiB of base memory, which is also EBDA, and BIOS ROM.
+const MPTABLE_AREAS: [(u64, u64); 2] = [
+    (EBDA_START, VGA_RAM_BEGIN),
+    (MB_BIOS_BEGIN, VMLINUX_RAM_START),
+];
+
+/// Guest physical addresses of structures written below 1 MiB for booting
+/// kernel. `Default` is the layout drawn in the module doc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootLayout {
+    /// `hvm_start_info` of PVH boot, followed by module list and memory map
+    /// in the same page.
+    pub pvh_info: u64,
+    /// Zero page of bzImage boot.
+    pub zero_page: u64,
+    /// Initial stack pointer.
+    pub stack_pointer: u64,
+    /// Page Map Level4, followed by one page of PDPTE and one page of PDE.
+    pub pml4: u64,
+    /// Start of `setup_data` list.
+    pub setup_data: u64,
+    /// Kernel cmdline.
+    pub cmdline: u64,
+    /// MP table, starting with the floating pointer which must be in an
+    /// area guest searches, move it to BIOS ROM if firmware owns EBDA.
+    pub mptable: u64,
+    /// Boot gdt.
+    pub gdt: u64,
+    /// Boot idt.
+    pub idt: u64,
+}
+
+impl Default for BootLayout {
+    fn default() -> Self {
+        BootLayout {
+            pvh_info: PVH_INFO_START,
+            zero_page: ZERO_PAGE_START,
+            stack_pointer: BOOT_LOADER_SP,
+            pml4: PML4_START,
+            setup_data: SETUP_DATA_START,
+            cmdline: CMDLINE_START,
+            mptable: EBDA_START,
+            gdt: BOOT_GDT_OFFSET,
+            idt: BOOT_IDT_OFFSET,
+        }
+    }
+}
+
+impl BootLayout {
+    pub fn pdpte(&self) -> u64 {
+        self.pml4 + PAGE_SIZE
+    }
+
+    pub fn pde(&self) -> u64 {
+        self.pml4 + 2 * PAGE_SIZE
+    }
+
+    pub fn pvh_modlist(&self) -> u64 {
+        self.pvh_info + PVH_MODLIST_OFFSET
+    }
+
+    pub fn pvh_memmap(&self) -> u64 {
+        self.pvh_info + PVH_MEMMAP_OFFSET
+    }
+
+    /// TSS of boot gdt, right after idt.
+    pub fn tss(&self) -> u64 {
+        self.idt + std::mem::size_of::<u64>() as u64
+    }
+
+    /// Regions (name, start, end) of fixed size in this layout.
+    ///
+    /// # Arguments
+    /// * `pvh` - `hvm_start_info` takes the place of zero page and page tables.
+    /// * `page_tables` - Number of pages of page tables from `pml4`.
+    pub(super) fn fixed_regions(
+        &self,
+        pvh: bool,
+        page_tables: u64,
+    ) -> Vec<(&'static str, u64, u64)> {
+        let mut regions = Vec::new();
+        if pvh {
+            regions.push(("PVH start info", self.pvh_info, self.pvh_info + PAGE_SIZE));
+        } else {
+            regions.push(("zero page", self.zero_page, self.zero_page + PAGE_SIZE));
+            regions.push((
+                "page tables",
+                self.pml4,
+                self.pml4 + page_tables * PAGE_SIZE,
+            ));
+        }
+        regions.push((
+            "mptable",
+            self.mptable,
+            self.mptable + VGA_RAM_BEGIN - EBDA_START,
+        ));
+        regions.push((
+            "gdt",
+            self.gdt,
+            self.gdt + (BOOT_GDT_MAX * std::mem::size_of::<u64>()) as u64,
+        ));
+        regions.push((
+            "idt",
+            self.idt,
+            self.idt + std::mem::size_of::<u64>() as u64,
+        ));
+        regions.push(("tss", self.tss(), self.tss() + BOOT_TSS_SIZE as u64));
+        regions
+    }
+
+    /// Check entries don't overlap, with `setup_data` and cmdline taking
+    /// their max sizes, and page tables mapping the first 1 GiB.
+    ///
+    /// # Errors
+    /// * `InvalidMptableAddr`: MP floating pointer is out of areas searched
+    ///   by guest.
+    /// * `BootLayoutOverlap`: Two entries overlap.
+    pub fn check(&self) -> Result<()> {
+        let fp_end = self.mptable + std::mem::size_of::<FloatingPointer>() as u64;
+        if self.mptable % MPTABLE_ALIGN != 0
+            || !MPTABLE_AREAS
+                .iter()
+                .any(|(start, end)| self.mptable >= *start && fp_end <= *end)
+        {
+            return Err(ErrorKind::InvalidMptableAddr(self.mptable).into());
+        }
+
+        for pvh in &[false, true] {
+            let mut regions = self.fixed_regions(*pvh, MIN_PAGE_TABLES);
+            regions.push((
+                "setup data",
+                self.setup_data,
+                self.setup_data + SETUP_DATA_MAX_SIZE,
+            ));
+            regions.push((
+                "cmdline",
+                self.cmdline,
+                self.cmdline + u64::from(CMDLINE_MAX_SIZE),
+            ));
+            check_overlap(&regions)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_boot_layout_check() {
+        let layout = BootLayout::default();
+        assert!(layout.check().is_ok());
+        assert_eq!(layout.pdpte(), 0xa000);
+        assert_eq!(layout.pde(), 0xb000);
+        assert_eq!(layout.pvh_modlist(), 0x6040);
+        assert_eq!(layout.pvh_memmap(), 0x6100);
+
+        // Page tables run into zero page.
+        let layout = BootLayout {
+            pml4: ZERO_PAGE_START - 0x2000,
+            ..Default::default()
+        };
+        match layout.check() {
+            Err(e) => match e.kind() {
+                ErrorKind::BootLayoutOverlap(region, _, other, _) => {
+                    assert_eq!((*region, *other), ("zero page", "page tables"));
+                }
+                _ => panic!("Unexpected error {}", e),
+            },
+            Ok(_) => panic!("Overlapping layout should be rejected"),
+        }
+
+        // PVH start info never coexists with zero page.
+        let layout = BootLayout {
+            pvh_info: ZERO_PAGE_START,
+            ..Default::default()
+        };
+        assert!(layout.check().is_ok());
+
+        let layout = BootLayout {
+            idt: BOOT_GDT_OFFSET + 0x10,
+            ..Default::default()
+        };
+        assert!(layout.check().is_err());
+
+        let layout = BootLayout {
+            cmdline: SETUP_DATA_START + 0x8000,
+            ..Default::default()
+        };
+        assert!(layout.check().is_err());
+
+        // MP table can move to BIOS ROM, but not anywhere else.
+        let layout = BootLayout {
+            mptable: MB_BIOS_BEGIN,
+            ..Default::default()
+        };
+        assert!(layout.check().is_ok());
+        for addr in &[0x9_f000, EBDA_START + 0x8, VGA_RAM_BEGIN - 0x8, 0xf_fff8] {
+            let layout = BootLayout {
+                mptable: *addr,
+                ..Default::default()
+            };
+            match layout.check() {
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidMptableAddr(a) => assert_eq!(a, addr),
+                    _ => panic!("Unexpected error {}", e),
+                },
+                Ok(_) => panic!("MP table at 0x{:x} should be rejected", addr),
+            }
+        }
+    }
+}
@@ -10,10 +10,16 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-//! Boot Loader load PE and bzImage linux kernel image to guest memory according
+//! Boot Loader load PE, ELF and bzImage linux kernel image to guest memory according
 //! [`x86 boot protocol`](https://www.kernel.org/doc/Documentation/x86/boot.txt).
+//! ELF kernels with a PVH entry note are booted with
+//! [`PVH boot protocol`](https://xenbits.xen.org/docs/unstable/misc/pvh.html),
+//! `hvm_start_info` at 0x6000 and memory map at 0x6100 take the place of zero page.
+//! A firmware blob can be loaded to end at 0x0010_0000, with its top 128 KiB
+//! mirrored below 4 GiB for the reset vector.
 //!
-//! Below is x86_64 bootloader memory layout:
+//! Below is the default x86_64 bootloader memory layout, boot structures up to
+//! the MP table can be moved with `BootLayout`:
 //!
 //! ``` text
 //!                 +------------------------+
@@ -32,6 +38,9 @@
 //!   0x0000_b000   +------------------------+
 //!                 |  Page Directory Entry  |
 //!                 |                        |
+//!   0x0001_0000   +------------------------+
+//!                 |  Setup Data            |
+//!                 |                        |
 //!   0x0002_0000   +------------------------+
 //!                 |  Kernel Cmdline        |
 //!                 |                        |
@@ -41,8 +50,11 @@
 //!   0x000a_0000   +------------------------+
 //!                 |  VGA_RAM               |
 //!                 |                        |
+//!   0x000e_0000   +------------------------+
+//!                 |  ACPI tables           |
+//!                 |                        |
 //!   0x000f_0000   +------------------------+
-//!                 |  MB_BIOS               |
+//!                 |  MB_BIOS / Firmware    |
 //!                 |                        |
 //!   0x0010_0000   +------------------------+
 //!                 |  Kernel _setup         |
@@ -56,29 +68,54 @@ const REAL_MODE_IVT_BEGIN: u64 = 0x0000_0000;
 
 extern crate address_space;
 
+mod acpi;
 mod bootparam;
+pub mod debug;
+mod elf;
 mod gdt;
+mod layout;
 mod mptable;
+mod pvh;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::string::String;
 use std::sync::Arc;
 
-use kvm_bindings::kvm_segment;
+use hypervisor::{
+    CpuRegisterState, CpuSpecialRegisterState, DescriptorTable, SegmentRegister, EFER_LMA,
+    EFER_LME, X86_CR0_PE, X86_CR0_PG, X86_CR4_PAE,
+};
 
-use self::errors::{ErrorKind, Result, ResultExt};
-use address_space::{AddressSpace, GuestAddress};
-use bootparam::{BootParams, RealModeKernelHeader, BOOT_VERSION, E820_RAM, E820_RESERVED, HDRS};
+use self::errors::{Error, ErrorKind, Result, ResultExt};
+use acpi::{build_acpi_tables, ACPI_TABLES_START};
+use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+use bootparam::{
+    BootParams, RealModeKernelHeader, SetupDataHeader, E820_MAX_ENTRIES, E820_RAM, E820_RESERVED,
+    HDRS, LOADED_HIGH, SETUP_RNG_SEED,
+};
+pub use bootparam::{ScreenInfo, BOOT_VERSION};
+use elf::{Elf64Header, Elf64Note, Elf64ProgramHeader, EM_X86_64, ET_EXEC, PT_LOAD, PT_NOTE};
 use gdt::GdtEntry;
+pub use layout::BootLayout;
+use layout::SETUP_DATA_MAX_SIZE;
 use mptable::{
     BusEntry, ConfigTableHeader, FloatingPointer, IOApicEntry, IOInterruptEntry,
     LocalInterruptEntry, ProcessEntry, DEST_ALL_LAPIC_MASK, INTERRUPT_TYPE_EXTINT,
     INTERRUPT_TYPE_INT, INTERRUPT_TYPE_NMI,
 };
+use pvh::{
+    HvmMemmapTableEntry, HvmModlistEntry, HvmStartInfo, XEN_ELFNOTE_NAME, XEN_ELFNOTE_PHYS32_ENTRY,
+    XEN_HVM_START_INFO_VERSION, XEN_HVM_START_MAGIC_VALUE,
+};
+use util::boot_timeline::{mark, BootTimeline};
 use util::byte_code::ByteCode;
 use util::checksum::obj_checksum;
+use util::kernel_cmdline::KernelCmdline;
+use util::sha256::{to_hex, HashReader, Sha256, SHA256_DIGEST_SIZE};
 
 pub mod errors {
     error_chain! {
@@ -92,36 +129,178 @@ pub mod errors {
             MaxCpus(cpus: u8) {
                 display("Configure cpu number({}) above supported max cpu numbers(254)", cpus)
             }
+            CpusAboveMaxCpus(cpus: u8, max_cpus: u8) {
+                display("Cpu number({}) is above max cpu number({})", cpus, max_cpus)
+            }
+            InvalidCpuCount(cpus: u8) {
+                display("Invalid cpu_count {}, at least 1 cpu is needed", cpus)
+            }
+            EmptyBootPath(field: &'static str) {
+                display("Path of {} is empty", field)
+            }
+            EmptyInitrd(path: String) {
+                display("initrd {} is given with initrd_size 0", path)
+            }
+            InvalidGapRange(start: u64, size: u64) {
+                display("Invalid gap_range (0x{:x}, 0x{:x}), size is 0 or end is above 4 GiB", start, size)
+            }
+            ApicAddrInRam(field: &'static str, addr: u32) {
+                display("{} 0x{:x} is outside gap_range, inside guest RAM", field, addr)
+            }
+            InvalidIrqOverride(irq: u8, pin: u8) {
+                display("Invalid override of ISA irq {} to IOAPIC pin {}", irq, pin)
+            }
             InvalidBzImage {
                 display("Invalid bzImage kernel file")
             }
+            BadHeaderMagic(found: u32) {
+                display("bzImage header magic is 0x{:x}, expect 0x53726448 (\"HdrS\")", found)
+            }
+            OldBootProtocol(found: u16, required: u16) {
+                display("bzImage boot protocol 0x{:04x} is older than required 0x{:04x}", found, required)
+            }
+            NotLoadedHigh {
+                display("bzImage doesn't set LOADED_HIGH in loadflags, kernel loaded at 0x10000 is unsupported")
+            }
+            InvalidSetupSects(setup_sects: u8) {
+                display("bzImage has {} setup sectors, above max 128", setup_sects)
+            }
+            TruncatedBzImage(len: u64, expected: u64) {
+                display("bzImage of {} bytes is truncated, expected at least {} bytes", len, expected)
+            }
+            LoadImage(offset: u64, addr: u64) {
+                display("Failed to load image at file offset 0x{:x} to 0x{:x}", offset, addr)
+            }
+            KernelPlacement(start: u64, size: u64) {
+                display("No room for kernel of size 0x{:x} at 0x{:x}", size, start)
+            }
+            NotElfKernel {
+                display("Kernel file is not an ELF64 little-endian image")
+            }
+            InvalidElfKernel(machine: u16, type_: u16) {
+                display("Invalid ELF kernel file: machine {}, type {}, expect x86_64 executable", machine, type_)
+            }
+            ElfProgramHeader(index: u16) {
+                display("Corrupt ELF program header {}", index)
+            }
+            ElfSegmentOverflow(addr: u64, size: u64, mem_end: u64) {
+                display("ELF segment [0x{:x}, +0x{:x}) exceeds guest memory end 0x{:x}", addr, size, mem_end)
+            }
+            InvalidKernel {
+                display("Kernel file is neither a valid bzImage nor a valid ELF image")
+            }
+            NoPvhEntry {
+                display("PVH boot is preferred, but kernel has no PVH entry note")
+            }
+            BootLayoutOverlap(region: &'static str, range: (u64, u64), other: &'static str, other_range: (u64, u64)) {
+                display(
+                    "{} [0x{:x},0x{:x}) overlaps {} [0x{:x},0x{:x})",
+                    region, range.0, range.1, other, other_range.0, other_range.1
+                )
+            }
+            CmdlineOverflow(len: usize, max: usize) {
+                display("Kernel cmdline length {} exceeds max length {}", len, max)
+            }
+            SetupDataOverflow(size: u64, max: u64) {
+                display("Size 0x{:x} of setup_data exceeds its area of 0x{:x}", size, max)
+            }
+            InvalidAcpiAddr(addr: u64) {
+                display("ACPI tables at 0x{:x} are not 16-byte aligned below 4 GiB", addr)
+            }
+            InvalidFirmwareSize(size: u64) {
+                display("Firmware size 0x{:x} is not a non-zero multiple of 64 KiB up to 256 KiB", size)
+            }
+            E820Overflow(max: usize) {
+                display("e820 table in zero page is full with {} entries", max)
+            }
+            InvalidDigest(field: &'static str, digest: String) {
+                display("{} {} is not a SHA-256 digest in hex", field, digest)
+            }
+            DigestMismatch(file: &'static str, expected: String, computed: String) {
+                display("SHA-256 digest of {} mismatch, expected {}, computed {}", file, expected, computed)
+            }
+            TooManyE820Entries(entries: usize, max: usize) {
+                display("Memory map has {} e820 entries, above max {}", entries, max)
+            }
+            InvalidMptableAddr(addr: u64) {
+                display(
+                    "MP floating pointer at 0x{:x} is not 16-byte aligned in EBDA [0x9fc00, 0xa0000) or BIOS ROM [0xf0000, 0x100000)",
+                    addr
+                )
+            }
+            InitrdOverflow(size: u64, initrd_size: u64) {
+                display("Initrd files of 0x{:x} bytes concatenated exceed initrd_size 0x{:x}", size, initrd_size)
+            }
         }
     }
 }
 
+const PVH_INFO_START: u64 = 0x0000_6000;
 const ZERO_PAGE_START: u64 = 0x0000_7000;
 const PML4_START: u64 = 0x0000_9000;
-const PDPTE_START: u64 = 0x0000_a000;
-const PDE_START: u64 = 0x0000_b000;
+const SETUP_DATA_START: u64 = 0x0001_0000;
 const CMDLINE_START: u64 = 0x0002_0000;
+/// Max length of kernel cmdline if kernel header doesn't tell.
+const CMDLINE_MAX_SIZE: u32 = 2048;
 const BOOT_HDR_START: u64 = 0x0000_01F1;
 const BZIMAGE_BOOT_OFFSET: u64 = 0x0200;
+const BZIMAGE_MAX_SETUP_SECTS: u8 = 128;
+/// Size of each copy from image file to guest memory.
+const LOAD_CHUNK_SIZE: u64 = 0x0001_0000;
 
 const EBDA_START: u64 = 0x0009_fc00;
 const VGA_RAM_BEGIN: u64 = 0x000a_0000;
 const MB_BIOS_BEGIN: u64 = 0x000f_0000;
 pub const VMLINUX_RAM_START: u64 = 0x0010_0000;
 const INITRD_ADDR_MAX: u64 = 0x37ff_ffff;
+/// Initrd files after the first one start at this alignment, as kernel
+/// expects of concatenated cpio archives.
+const INITRD_CONCAT_ALIGN: u64 = 4;
+
+/// Firmware blob is aligned to 64 KiB and ends at `VMLINUX_RAM_START`,
+/// above VGA RAM.
+const FIRMWARE_ALIGN: u64 = 0x0001_0000;
+const FIRMWARE_MAX_SIZE: u64 = 0x0004_0000;
+/// Top of firmware blob mirrored to end at 4 GiB, for the reset vector.
+const FIRMWARE_MIRROR_SIZE: u64 = 0x0002_0000;
+const FIRMWARE_MIRROR_END: u64 = 0x1_0000_0000;
+const RESET_VECTOR: u64 = 0xffff_fff0;
 
 const VMLINUX_STARTUP: u64 = 0x0100_0000;
+/// Alignment of relocatable bzImage if header gives none.
+const KERNEL_ALIGN_DEFAULT: u64 = 0x0020_0000;
 const BOOT_LOADER_SP: u64 = 0x0000_8ff0;
 
 const GDT_ENTRY_BOOT_CS: u8 = 2;
 const GDT_ENTRY_BOOT_DS: u8 = 3;
+/// 64-bit TSS descriptor takes two gdt entries.
+const GDT_ENTRY_BOOT_TSS: u8 = 4;
 const BOOT_GDT_OFFSET: u64 = 0x500;
-const BOOT_IDT_OFFSET: u64 = 0x520;
-
-const BOOT_GDT_MAX: usize = 4;
+const BOOT_IDT_OFFSET: u64 = 0x530;
+
+const BOOT_GDT_MAX: usize = 6;
+/// Size of 64-bit TSS, without IO permission bitmap.
+const BOOT_TSS_SIZE: usize = 0x68;
+/// Offset of IO map base address in 64-bit TSS.
+const TSS_IOMAP_BASE_OFFSET: usize = 0x66;
+/// Flags of busy 64-bit TSS in boot gdt.
+const BOOT_TSS_FLAGS: u64 = 0x8b;
+
+/// MP table supports at most 255 cpus, reserve one for ioapic id.
+const MPTABLE_MAX_CPUS: u32 = 254;
+/// Number of ISA irqs described in MP table.
+const MPTABLE_ISA_IRQS: u8 = 16;
+/// Number of IOAPIC input pins.
+const IOAPIC_NUM_PINS: u8 = 24;
+
+/// Override of ISA irq 0 (PIT timer) to IOAPIC pin 2, as wired on real
+/// chipsets and by KVM in-kernel irqchip.
+pub const ISA_TIMER_IRQ_OVERRIDE: (u8, u8) = (0, 2);
+
+/// Flags of 64-bit code segment in boot gdt.
+const BOOT_CODE64_FLAGS: u64 = 0xa09b;
+/// Flags of 32-bit code segment in boot gdt, for PVH entry.
+const BOOT_CODE32_FLAGS: u64 = 0xc09b;
 
 /// Load bzImage linux kernel to Guest Memory.
 ///
@@ -131,62 +310,791 @@ const BOOT_GDT_MAX: usize = 4;
 /// * the compressed kernel
 /// The setup `RealModeKernelHeader` can be load at offset `0x01f1` in bzImage kernel image.
 /// The compressed kernel will be loaded into guest memory at `code32_start` in
-/// `RealModeKernelHeader`.
+/// `RealModeKernelHeader`, which is moved by `place_bzimage` if kernel is
+/// relocatable.
 /// The start address of compressed kernel is the loader address + 0x200. It will be
 /// set in `kernel_start` in `BootLoader` structure set.
 ///
 /// # Arguments
-/// * `kernel_file` - host path for kernel.
-/// * `sys_mem` - guest memory.
+/// * `kernel_image` - kernel image file.
+/// * `min_boot_protocol` - Oldest boot protocol version accepted, at least
+///   `BOOT_VERSION`.
 ///
 /// # Errors
-/// * `InvalidBzImage`: BzImage header or version is invalid.
-/// * `AddressSpace`: Write bzImage linux kernel to guest memory failed.
-pub fn load_bzimage(kernel_image: &mut File) -> Result<bootparam::RealModeKernelHeader> {
+/// * `InvalidBzImage`: Image is too short for bzImage header.
+/// * `BadHeaderMagic`: Header has no "HdrS" magic, image isn't bzImage.
+/// * `OldBootProtocol`: Boot protocol is older than `min_boot_protocol`.
+/// * `NotLoadedHigh`: Protected-mode kernel isn't loaded at 0x100000.
+/// * `InvalidSetupSects`: Setup code is larger than 128 sectors.
+/// * `TruncatedBzImage`: Image file is shorter than its header claims.
+///
+/// Return header and sizes of bzImage, image file is at the start of
+/// protected-mode kernel.
+pub fn load_bzimage(kernel_image: &mut File, min_boot_protocol: u16) -> Result<BzImageInfo> {
+    const HDR_SIZE: u64 = std::mem::size_of::<bootparam::RealModeKernelHeader>() as u64;
+
+    let file_len = kernel_image.metadata()?.len();
+    if file_len < BOOT_HDR_START + HDR_SIZE {
+        return Err(ErrorKind::InvalidBzImage.into());
+    }
     kernel_image.seek(SeekFrom::Start(BOOT_HDR_START))?;
-    let mut boot_hdr_buf = [0_u8; std::mem::size_of::<bootparam::RealModeKernelHeader>()];
+    let mut boot_hdr_buf = [0_u8; HDR_SIZE as usize];
     kernel_image.read_exact(&mut boot_hdr_buf)?;
-    let boot_hdr = bootparam::RealModeKernelHeader::from_bytes(&boot_hdr_buf).unwrap();
+    let boot_hdr = bootparam::RealModeKernelHeader::from_bytes(&boot_hdr_buf)
+        .ok_or(ErrorKind::InvalidBzImage)?;
 
     if boot_hdr.header != HDRS {
         kernel_image.seek(SeekFrom::Start(0))?;
-        return Err(ErrorKind::InvalidBzImage.into());
+        return Err(ErrorKind::BadHeaderMagic(boot_hdr.header).into());
     }
 
-    if (boot_hdr.version < BOOT_VERSION) || ((boot_hdr.loadflags & 0x1) == 0x0) {
+    let required = std::cmp::max(min_boot_protocol, BOOT_VERSION);
+    if boot_hdr.version < required {
         kernel_image.seek(SeekFrom::Start(0))?;
-        return Err(ErrorKind::InvalidBzImage.into());
+        return Err(ErrorKind::OldBootProtocol(boot_hdr.version, required).into());
+    }
+    if boot_hdr.loadflags & LOADED_HIGH == 0 {
+        kernel_image.seek(SeekFrom::Start(0))?;
+        return Err(ErrorKind::NotLoadedHigh.into());
     }
 
+    if boot_hdr.setup_sects > BZIMAGE_MAX_SETUP_SECTS {
+        kernel_image.seek(SeekFrom::Start(0))?;
+        return Err(ErrorKind::InvalidSetupSects(boot_hdr.setup_sects).into());
+    }
     let mut setup_size = boot_hdr.setup_sects as u64;
     if setup_size == 0 {
         setup_size = 4;
     }
     setup_size = (setup_size + 1) << 9;
 
+    // `syssize` is the size of protected-mode kernel in 16-byte paragraphs.
+    let expected = setup_size + u64::from(boot_hdr.syssize) * 16;
+    if file_len <= setup_size || file_len < expected {
+        kernel_image.seek(SeekFrom::Start(0))?;
+        return Err(
+            ErrorKind::TruncatedBzImage(file_len, std::cmp::max(expected, setup_size + 1)).into(),
+        );
+    }
+
     kernel_image.seek(SeekFrom::Start(setup_size as u64))?;
 
-    Ok(*boot_hdr)
+    Ok(BzImageInfo {
+        header: *boot_hdr,
+        setup_size,
+        kernel_size: file_len - setup_size,
+        handover_entry: boot_hdr.efi_handover_entry(),
+    })
+}
+
+/// Read-only private mapping of part of a file.
+struct FileMapping {
+    host_addr: *mut libc::c_void,
+    size: usize,
+}
+
+impl FileMapping {
+    /// Map `len` bytes of `file` from `offset`, which needs not be page
+    /// aligned. Return the mapping and pointer to data at `offset`.
+    fn new(file: &File, offset: u64, len: u64) -> Option<(FileMapping, *const u8)> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let map_offset = offset & !(page_size - 1);
+        let size = (offset - map_offset + len) as usize;
+        let host_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                map_offset as libc::off_t,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return None;
+        }
+        let data = unsafe { (host_addr as *const u8).add((offset - map_offset) as usize) };
+        Some((FileMapping { host_addr, size }, data))
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.host_addr, self.size);
+        }
+    }
+}
+
+/// Copy image file from current position to its end into guest memory at
+/// `addr`.
+///
+/// With `mmap`, the file is mapped and copied to the host address of guest
+/// RAM at once. Otherwise, or if destination is not a single Ram region, it
+/// is read `LOAD_CHUNK_SIZE` bytes at a time.
+///
+/// Bytes copied are fed to `hasher` in the same pass, if any.
+///
+/// # Errors
+/// * `LoadImage`: Read image or write guest memory failed at an offset.
+fn load_file(
+    image: &mut File,
+    sys_mem: &Arc<AddressSpace>,
+    addr: u64,
+    mmap: bool,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    let start = image.seek(SeekFrom::Current(0))?;
+    let len = image.metadata()?.len().saturating_sub(start);
+    let hva = if mmap && len > 0 {
+        sys_mem.get_host_address(GuestAddress(addr), len).ok()
+    } else {
+        None
+    };
+    if let Some(hva) = hva {
+        if let Some((_mapping, data)) = FileMapping::new(image, start, len) {
+            // Safe as destination is checked to be in one Ram region, and
+            // source is mapped for `len` bytes.
+            let src = unsafe { std::slice::from_raw_parts(data, len as usize) };
+            unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), hva as *mut u8, src.len()) };
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(src);
+            }
+            image.seek(SeekFrom::End(0))?;
+            return Ok(());
+        }
+    }
+
+    let mut reader = HashReader::new(image, hasher);
+    let mut offset = 0;
+    while offset < len {
+        let chunk = std::cmp::min(LOAD_CHUNK_SIZE, len - offset);
+        sys_mem
+            .write(&mut reader, GuestAddress(addr + offset), chunk)
+            .chain_err(|| ErrorKind::LoadImage(start + offset, addr + offset))?;
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/// Feed the whole `image` to `hasher`.
+pub fn hash_file(image: &mut File, hasher: &mut Sha256) -> Result<()> {
+    image.seek(SeekFrom::Start(0))?;
+    std::io::copy(
+        &mut HashReader::new(&mut *image, Some(hasher)),
+        &mut std::io::sink(),
+    )?;
+    Ok(())
+}
+
+/// Check digest computed by `hasher` against `expected` digest in hex, if
+/// both are given.
+///
+/// # Errors
+/// * `DigestMismatch`: Digest of `file` doesn't match.
+pub fn check_digest(
+    file: &'static str,
+    expected: Option<&str>,
+    hasher: Option<Sha256>,
+) -> Result<()> {
+    if let (Some(expected), Some(hasher)) = (expected, hasher) {
+        let computed = to_hex(&hasher.finish());
+        if !computed.eq_ignore_ascii_case(expected) {
+            return Err(ErrorKind::DigestMismatch(file, expected.to_string(), computed).into());
+        }
+    }
+    Ok(())
+}
+
+/// Load protected-mode kernel of bzImage to `code32_start`.
+///
+/// # Arguments
+/// * `kernel_image` - kernel image file, at the start of protected-mode
+///   kernel as left by `load_bzimage`.
+/// * `sys_mem` - guest memory.
+/// * `boot_hdr` - header of bzImage, placed by `linux_bootloader`.
+/// * `mmap` - Copy from mmap of image file, see `load_file`.
+/// * `hasher` - Fed with the whole image file, real-mode setup code
+///   included, if any.
+pub fn load_kernel_image(
+    kernel_image: &mut File,
+    sys_mem: &Arc<AddressSpace>,
+    boot_hdr: &RealModeKernelHeader,
+    mmap: bool,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    if let Some(hasher) = hasher.as_mut() {
+        let setup_size = kernel_image.seek(SeekFrom::Current(0))?;
+        let mut setup = vec![0_u8; setup_size as usize];
+        kernel_image.read_exact_at(&mut setup, 0)?;
+        hasher.update(&setup);
+    }
+    load_file(
+        kernel_image,
+        sys_mem,
+        u64::from(boot_hdr.code32_start),
+        mmap,
+        hasher,
+    )
+}
+
+/// Load initrd image file to guest memory at `addr`, usually `initrd_start`
+/// of `X86BootLoader`. The file is fed to `hasher` if any.
+pub fn load_initrd(
+    initrd: &mut File,
+    sys_mem: &Arc<AddressSpace>,
+    addr: u64,
+    mmap: bool,
+    hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    initrd.seek(SeekFrom::Start(0))?;
+    load_file(initrd, sys_mem, addr, mmap, hasher)
+}
+
+/// Offsets of initrd files of `sizes` placed back-to-back, each starting at
+/// the next `INITRD_CONCAT_ALIGN` boundary, and their total size.
+fn initrd_offsets(sizes: &[u64]) -> (Vec<u64>, u64) {
+    let mut offsets = Vec::with_capacity(sizes.len());
+    let mut end = 0;
+    for size in sizes {
+        let offset = (end + INITRD_CONCAT_ALIGN - 1) & !(INITRD_CONCAT_ALIGN - 1);
+        offsets.push(offset);
+        end = offset + size;
+    }
+    (offsets, end)
+}
+
+/// Size of initrd files concatenated, which is the `initrd_size` to boot
+/// with them.
+///
+/// # Errors
+/// * `InitrdOverflow`: Files concatenated are larger than 4 GiB.
+pub fn initrd_size(initrds: &[PathBuf]) -> Result<u32> {
+    let mut sizes = Vec::with_capacity(initrds.len());
+    for initrd in initrds {
+        sizes.push(fs::metadata(initrd)?.len());
+    }
+    let (_, size) = initrd_offsets(&sizes);
+    if size > u64::from(u32::max_value()) {
+        return Err(ErrorKind::InitrdOverflow(size, u64::from(u32::max_value())).into());
+    }
+    Ok(size as u32)
+}
+
+/// Load initrd files back-to-back to guest memory from `addr`, so that
+/// kernel sees one ramdisk of concatenated cpio archives. Files are fed to
+/// `hasher` in order if any.
+///
+/// # Errors
+/// * `InitrdOverflow`: Files concatenated are larger than `initrd_size`.
+pub fn load_initrds(
+    initrds: &mut [File],
+    sys_mem: &Arc<AddressSpace>,
+    addr: u64,
+    initrd_size: u32,
+    mmap: bool,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    let mut sizes = Vec::with_capacity(initrds.len());
+    for initrd in initrds.iter() {
+        sizes.push(initrd.metadata()?.len());
+    }
+    let (offsets, size) = initrd_offsets(&sizes);
+    if size > u64::from(initrd_size) {
+        return Err(ErrorKind::InitrdOverflow(size, u64::from(initrd_size)).into());
+    }
+    for (initrd, offset) in initrds.iter_mut().zip(offsets) {
+        load_initrd(initrd, sys_mem, addr + offset, mmap, hasher.as_deref_mut())?;
+    }
+    Ok(())
+}
+
+/// Load raw vmlinux.bin from its start to `addr`. The file is fed to
+/// `hasher` if any.
+pub fn load_raw_kernel(
+    kernel_image: &mut File,
+    sys_mem: &Arc<AddressSpace>,
+    addr: u64,
+    mmap: bool,
+    hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    kernel_image.seek(SeekFrom::Start(0))?;
+    load_file(kernel_image, sys_mem, addr, mmap, hasher)
+}
+
+/// Header of bzImage and sizes of its parts in image file.
+#[derive(Debug, Copy, Clone)]
+pub struct BzImageInfo {
+    /// Real-mode kernel header.
+    pub header: RealModeKernelHeader,
+    /// Size of real-mode setup code, which protected-mode kernel follows.
+    pub setup_size: u64,
+    /// Size of protected-mode kernel.
+    pub kernel_size: u64,
+    /// 64-bit EFI handover entry if kernel is loaded at `code32_start` of
+    /// header, `None` if kernel doesn't support it.
+    pub handover_entry: Option<u64>,
+}
+
+/// Get file offset of program header `index` of ELF image, checking that
+/// the header is within image.
+///
+/// # Errors
+/// * `ElfProgramHeader`: Program header is out of image.
+fn elf_phdr_offset(ehdr: &Elf64Header, index: u16, image_len: u64) -> Result<u64> {
+    let phdr_size = std::mem::size_of::<Elf64ProgramHeader>() as u64;
+    u64::from(index)
+        .checked_mul(phdr_size)
+        .and_then(|offset| offset.checked_add(ehdr.e_phoff))
+        .filter(|offset| {
+            offset
+                .checked_add(phdr_size)
+                .map_or(false, |end| end <= image_len)
+        })
+        .ok_or_else(|| ErrorKind::ElfProgramHeader(index).into())
+}
+
+/// Load ELF vmlinux linux kernel to Guest Memory.
+///
+/// # Notes
+/// Each `PT_LOAD` segment is copied to guest memory at its physical address
+/// `p_paddr`. The part of segment beyond file size (bss) is left as it is,
+/// guest RAM is zeroed at start and kernel clears its bss itself.
+///
+/// # Arguments
+/// * `kernel_image` - kernel image file.
+/// * `sys_mem` - guest memory.
+/// * `hasher` - Fed with the whole image file, which is read through once
+///   after headers are checked, if any.
+///
+/// # Errors
+/// * `NotElfKernel`: Image is not an ELF64 little-endian file.
+/// * `InvalidElfKernel`: Image is not a x86_64 executable.
+/// * `ElfProgramHeader`: Program header is out of image or inconsistent.
+/// * `ElfSegmentOverflow`: Segment exceeds the end of guest memory.
+/// * `AddressSpace`: Write segment to guest memory failed.
+///
+/// Return entry point and range [start, end) of loaded segments.
+pub fn load_elf_kernel(
+    kernel_image: &mut File,
+    sys_mem: &Arc<AddressSpace>,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<(u64, (u64, u64))> {
+    let image_len = kernel_image.seek(SeekFrom::End(0))?;
+    if image_len < std::mem::size_of::<Elf64Header>() as u64 {
+        return Err(ErrorKind::NotElfKernel.into());
+    }
+    let mut ehdr = Elf64Header::default();
+    kernel_image.seek(SeekFrom::Start(0))?;
+    kernel_image.read_exact(ehdr.as_mut_bytes())?;
+
+    if !ehdr.is_elf64() {
+        return Err(ErrorKind::NotElfKernel.into());
+    }
+    if ehdr.e_machine != EM_X86_64 || ehdr.e_type != ET_EXEC {
+        return Err(ErrorKind::InvalidElfKernel(ehdr.e_machine, ehdr.e_type).into());
+    }
+
+    let phdr_size = std::mem::size_of::<Elf64ProgramHeader>() as u64;
+    if ehdr.e_phentsize as u64 != phdr_size || ehdr.e_phnum == 0 {
+        return Err(ErrorKind::ElfProgramHeader(0).into());
+    }
+
+    let mem_end = sys_mem.memory_end_address().raw_value();
+    let mut load_range = (u64::max_value(), 0);
+    let mut segments = Vec::new();
+    for index in 0..ehdr.e_phnum {
+        let phdr_offset = elf_phdr_offset(&ehdr, index, image_len)?;
+        let mut phdr = Elf64ProgramHeader::default();
+        kernel_image.seek(SeekFrom::Start(phdr_offset))?;
+        kernel_image.read_exact(phdr.as_mut_bytes())?;
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+
+        let file_end = phdr.p_offset.checked_add(phdr.p_filesz);
+        if phdr.p_filesz > phdr.p_memsz || file_end.map_or(true, |end| end > image_len) {
+            return Err(ErrorKind::ElfProgramHeader(index).into());
+        }
+        if phdr
+            .p_paddr
+            .checked_add(phdr.p_memsz)
+            .map_or(true, |end| end > mem_end)
+        {
+            return Err(ErrorKind::ElfSegmentOverflow(phdr.p_paddr, phdr.p_memsz, mem_end).into());
+        }
+
+        load_range.0 = std::cmp::min(load_range.0, phdr.p_paddr);
+        load_range.1 = std::cmp::max(load_range.1, phdr.p_paddr + phdr.p_memsz);
+        segments.push(phdr);
+    }
+
+    // Read the image through in file order, so every byte is hashed once.
+    // Segment overlapping a loaded one is read apart, it's hashed already.
+    segments.sort_by_key(|phdr| phdr.p_offset);
+    kernel_image.seek(SeekFrom::Start(0))?;
+    let mut pos = 0;
+    for phdr in segments {
+        let load_err = || format!("Failed to load ELF segment to 0x{:x}", phdr.p_paddr);
+        if phdr.p_offset < pos {
+            kernel_image.seek(SeekFrom::Start(phdr.p_offset))?;
+            sys_mem
+                .write(kernel_image, GuestAddress(phdr.p_paddr), phdr.p_filesz)
+                .chain_err(load_err)?;
+            kernel_image.seek(SeekFrom::Start(pos))?;
+            continue;
+        }
+        let gap = (&mut *kernel_image).take(phdr.p_offset - pos);
+        std::io::copy(
+            &mut HashReader::new(gap, hasher.as_deref_mut()),
+            &mut std::io::sink(),
+        )?;
+        sys_mem
+            .write(
+                &mut HashReader::new(&mut *kernel_image, hasher.as_deref_mut()),
+                GuestAddress(phdr.p_paddr),
+                phdr.p_filesz,
+            )
+            .chain_err(load_err)?;
+        pos = phdr.p_offset + phdr.p_filesz;
+    }
+    if let Some(hasher) = hasher {
+        std::io::copy(
+            &mut HashReader::new(&mut *kernel_image, Some(hasher)),
+            &mut std::io::sink(),
+        )?;
+    }
+
+    if load_range.0 > load_range.1 {
+        load_range = (ehdr.e_entry, ehdr.e_entry);
+    }
+
+    Ok((ehdr.e_entry, load_range))
+}
+
+/// Find PVH 32-bit entry point in note segments of ELF kernel.
+///
+/// # Arguments
+/// * `kernel_image` - ELF kernel image file.
+///
+/// # Errors
+/// * `NotElfKernel`: Image is not an ELF64 little-endian file.
+/// * `ElfProgramHeader`: Note segment is out of image or inconsistent.
+pub fn find_pvh_entry(kernel_image: &mut File) -> Result<Option<u64>> {
+    let image_len = kernel_image.seek(SeekFrom::End(0))?;
+    if image_len < std::mem::size_of::<Elf64Header>() as u64 {
+        return Err(ErrorKind::NotElfKernel.into());
+    }
+    let mut ehdr = Elf64Header::default();
+    kernel_image.seek(SeekFrom::Start(0))?;
+    kernel_image.read_exact(ehdr.as_mut_bytes())?;
+    if !ehdr.is_elf64() {
+        return Err(ErrorKind::NotElfKernel.into());
+    }
+
+    let note_size = std::mem::size_of::<Elf64Note>() as u64;
+    let align4 = |n: u32| (u64::from(n) + 3) & !3;
+    for index in 0..ehdr.e_phnum {
+        let phdr_offset = elf_phdr_offset(&ehdr, index, image_len)?;
+        let mut phdr = Elf64ProgramHeader::default();
+        kernel_image.seek(SeekFrom::Start(phdr_offset))?;
+        kernel_image.read_exact(phdr.as_mut_bytes())?;
+        if phdr.p_type != PT_NOTE {
+            continue;
+        }
+        let seg_end = phdr.p_offset.checked_add(phdr.p_filesz);
+        if seg_end.map_or(true, |end| end > image_len) {
+            return Err(ErrorKind::ElfProgramHeader(index).into());
+        }
+
+        let mut offset = phdr.p_offset;
+        while offset + note_size <= phdr.p_offset + phdr.p_filesz {
+            let mut note = Elf64Note::default();
+            kernel_image.seek(SeekFrom::Start(offset))?;
+            kernel_image.read_exact(note.as_mut_bytes())?;
+            let name_offset = offset + note_size;
+            let desc_offset = name_offset + align4(note.n_namesz);
+            offset = desc_offset + align4(note.n_descsz);
+            if offset > phdr.p_offset + phdr.p_filesz {
+                return Err(ErrorKind::ElfProgramHeader(index).into());
+            }
+
+            if note.n_type != XEN_ELFNOTE_PHYS32_ENTRY
+                || note.n_namesz as usize != XEN_ELFNOTE_NAME.len()
+            {
+                continue;
+            }
+            let mut name = [0_u8; 4];
+            kernel_image.read_exact(&mut name)?;
+            if name != XEN_ELFNOTE_NAME {
+                continue;
+            }
+            // Entry is a 32-bit address, stored in 4 or 8 bytes.
+            let mut desc = [0_u8; 8];
+            let desc_len = std::cmp::min(note.n_descsz as usize, desc.len());
+            kernel_image.seek(SeekFrom::Start(desc_offset))?;
+            kernel_image.read_exact(&mut desc[..desc_len])?;
+            return Ok(Some(u64::from_le_bytes(desc)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Format of kernel image.
+#[derive(Debug, Copy, Clone)]
+pub enum KernelFormat {
+    /// bzImage with size of protected-mode kernel, image file is at the start
+    /// of protected-mode kernel.
+    BzImage(RealModeKernelHeader, u64),
+    /// ELF vmlinux with its entry point and range of segments, segments are
+    /// loaded already.
+    Elf(u64, (u64, u64)),
+    /// ELF vmlinux with its PVH 32-bit entry point and range of segments,
+    /// segments are loaded already.
+    Pvh(u64, (u64, u64)),
+    /// Raw vmlinux.bin with its size, image file is at the start.
+    Raw(u64),
+}
+
+impl KernelFormat {
+    /// Max length of kernel cmdline, excluding the terminating NUL.
+    pub fn cmdline_max(&self) -> u32 {
+        match self {
+            KernelFormat::BzImage(boot_hdr, _) => boot_hdr.cmdline_max(),
+            _ => CMDLINE_MAX_SIZE,
+        }
+    }
+
+    /// Range [start, end) of kernel in guest memory.
+    pub fn kernel_range(&self) -> (u64, u64) {
+        match *self {
+            KernelFormat::BzImage(boot_hdr, size) => {
+                let start = u64::from(boot_hdr.code32_start);
+                (start, start + size)
+            }
+            KernelFormat::Elf(_, range) | KernelFormat::Pvh(_, range) => range,
+            KernelFormat::Raw(size) => (VMLINUX_STARTUP, VMLINUX_STARTUP + size),
+        }
+    }
+}
+
+/// Size of the rest of image file from current position.
+fn remaining_size(kernel_image: &mut File) -> Result<u64> {
+    let pos = kernel_image.seek(SeekFrom::Current(0))?;
+    let len = kernel_image.seek(SeekFrom::End(0))?;
+    kernel_image.seek(SeekFrom::Start(pos))?;
+    Ok(len - pos)
+}
+
+/// Probe the format of kernel image: try bzImage first, then ELF. ELF
+/// kernels with a PVH entry note are booted with PVH. Images without ELF
+/// magic are taken as raw vmlinux.bin.
+///
+/// # Arguments
+/// * `kernel_image` - kernel image file.
+/// * `sys_mem` - guest memory, ELF segments are loaded to it.
+/// * `prefer_pvh` - Fail if kernel can't be booted with PVH.
+/// * `min_boot_protocol` - Oldest boot protocol version of bzImage accepted.
+/// * `hasher` - Fed with the whole image file if it's ELF, which is hashed
+///   while segments are loaded.
+///
+/// # Errors
+/// * `InvalidKernel`: Image is neither bzImage nor valid ELF.
+/// * `OldBootProtocol`, `NotLoadedHigh`: Image is unsupported bzImage.
+/// * `InvalidSetupSects`, `TruncatedBzImage`: Image has bzImage header but
+///   is broken.
+/// * `NoPvhEntry`: `prefer_pvh` is set but kernel has no PVH entry.
+pub fn probe_kernel(
+    kernel_image: &mut File,
+    sys_mem: &Arc<AddressSpace>,
+    prefer_pvh: bool,
+    min_boot_protocol: u16,
+    hasher: Option<&mut Sha256>,
+) -> Result<KernelFormat> {
+    let format = match load_bzimage(kernel_image, min_boot_protocol) {
+        Ok(info) => KernelFormat::BzImage(info.header, info.kernel_size),
+        Err(Error(ErrorKind::InvalidBzImage, _)) | Err(Error(ErrorKind::BadHeaderMagic(_), _)) => {
+            info!("Kernel is not bzImage");
+            match load_elf_kernel(kernel_image, sys_mem, hasher) {
+                Ok((entry, range)) => match find_pvh_entry(kernel_image)? {
+                    Some(pvh_entry) => KernelFormat::Pvh(pvh_entry, range),
+                    None => KernelFormat::Elf(entry, range),
+                },
+                Err(Error(ErrorKind::NotElfKernel, _)) => {
+                    kernel_image.seek(SeekFrom::Start(0))?;
+                    KernelFormat::Raw(remaining_size(kernel_image)?)
+                }
+                Err(e) => return Err(e).chain_err(|| ErrorKind::InvalidKernel),
+            }
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let KernelFormat::Pvh(..) = format {
+    } else if prefer_pvh {
+        return Err(ErrorKind::NoPvhEntry.into());
+    }
+    Ok(format)
+}
+
+/// Length of random seed passed to guest.
+const RNG_SEED_LEN: usize = 32;
+
+/// An entry of `setup_data` linked list passed to kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetupData {
+    /// Type of entry, such as `SETUP_RNG_SEED`.
+    pub type_: u32,
+    /// Payload of entry.
+    pub data: Vec<u8>,
+}
+
+impl SetupData {
+    /// Random seed from host as `SETUP_RNG_SEED`, so that guest has
+    /// entropy early in boot.
+    pub fn rng_seed() -> std::io::Result<Self> {
+        let mut data = vec![0_u8; RNG_SEED_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut data)?;
+        Ok(SetupData {
+            type_: SETUP_RNG_SEED,
+            data,
+        })
+    }
 }
 
 /// Boot loader config used for x86_64.
 pub struct X86BootLoaderConfig {
-    /// Path of the kernel image.
-    pub kernel: PathBuf,
-    /// Path of the initrd image.
-    pub initrd: Option<PathBuf>,
-    /// Initrd image size.
+    /// Path of the kernel image, may be omitted if booted with firmware.
+    pub kernel: Option<PathBuf>,
+    /// Paths of initrd images, loaded back-to-back as one ramdisk. Empty
+    /// for no initrd.
+    pub initrd: Vec<PathBuf>,
+    /// Total size of initrd images, see `initrd_size`.
     pub initrd_size: u32,
     /// Kernel cmdline parameters.
-    pub kernel_cmdline: String,
+    pub kernel_cmdline: KernelCmdline,
     /// VM's CPU count.
     pub cpu_count: u8,
+    /// Max CPU count including hotpluggable CPUs, described to guest as
+    /// disabled.
+    pub max_cpus: u8,
     /// (gap start, gap size)
     pub gap_range: (u64, u64),
     /// IO APIC base address
     pub ioapic_addr: u32,
     /// Local APIC base address
     pub lapic_addr: u32,
+    /// Boot with PVH entry, fail if kernel doesn't support it.
+    pub prefer_pvh: bool,
+    /// Path of the firmware blob loaded below 1 MiB.
+    pub firmware: Option<PathBuf>,
+    /// Entries of `setup_data` linked list.
+    pub setup_data: Vec<SetupData>,
+    /// Describe cpus and interrupt controllers with MP table only, without
+    /// ACPI tables.
+    pub mptable_only: bool,
+    /// Address of ACPI tables, `ACPI_TABLES_START` if not set.
+    pub acpi_addr: Option<u64>,
+    /// ISA irqs not wired to the IOAPIC pin of the same number, as
+    /// (irq, pin), e.g. `ISA_TIMER_IRQ_OVERRIDE`.
+    pub irq_overrides: Vec<(u8, u8)>,
+    /// Copy kernel and initrd to guest memory with read instead of mmap,
+    /// mainly for testing.
+    pub stream_load: bool,
+    /// Addresses of boot structures below 1 MiB.
+    pub layout: BootLayout,
+    /// Identity-map guest memory with 1 GiB pages in boot page tables
+    /// instead of 2 MiB pages, which needs cpu support of `pdpe1gb`.
+    pub use_gib_pages: bool,
+    /// Append a 64-bit TSS to boot gdt and load it in TR.
+    pub boot_tss: bool,
+    /// SHA-256 digest of kernel file in hex, boot fails if it doesn't match.
+    pub kernel_hash: Option<String>,
+    /// SHA-256 digest of initrd files concatenated without padding in hex,
+    /// boot fails if it doesn't match.
+    pub initrd_hash: Option<String>,
+    /// Video state in zero page, VGA 80x25 text mode by default.
+    pub screen_info: ScreenInfo,
+    /// Oldest boot protocol version of bzImage accepted, `BOOT_VERSION` by
+    /// default, raise it if newer header fields are relied on.
+    pub min_boot_protocol: u16,
+}
+
+impl X86BootLoaderConfig {
+    /// `initrd` of a single initrd image if given.
+    pub fn single_initrd(initrd: Option<PathBuf>) -> Vec<PathBuf> {
+        initrd.into_iter().collect()
+    }
+
+    /// Check the config is usable before anything is written to guest
+    /// memory.
+    ///
+    /// # Errors
+    /// * `InvalidCpuCount`: No cpu.
+    /// * `CpusAboveMaxCpus`: `cpu_count` is above `max_cpus`.
+    /// * `MaxCpus`: Too many cpus for MP table, and there is no ACPI table.
+    /// * `EmptyBootPath`: Path of kernel, initrd or firmware is empty.
+    /// * `EmptyInitrd`: Initrd is given with size 0.
+    /// * `InvalidGapRange`: 32-bit gap is empty or ends above 4 GiB.
+    /// * `ApicAddrInRam`: IOAPIC or local APIC is not in the 32-bit gap.
+    /// * `BootLayoutOverlap`: Entries of `layout` overlap.
+    pub fn check(&self) -> Result<()> {
+        if self.cpu_count == 0 {
+            return Err(ErrorKind::InvalidCpuCount(self.cpu_count).into());
+        }
+        if self.cpu_count > self.max_cpus {
+            return Err(ErrorKind::CpusAboveMaxCpus(self.cpu_count, self.max_cpus).into());
+        }
+        let acpi = !self.mptable_only && self.firmware.is_none();
+        if !acpi && u32::from(self.max_cpus) > MPTABLE_MAX_CPUS {
+            return Err(ErrorKind::MaxCpus(self.max_cpus).into());
+        }
+
+        let paths = self
+            .kernel
+            .iter()
+            .map(|path| ("kernel", path))
+            .chain(self.initrd.iter().map(|path| ("initrd", path)))
+            .chain(self.firmware.iter().map(|path| ("firmware", path)));
+        for (field, path) in paths {
+            if path.as_os_str().is_empty() {
+                return Err(ErrorKind::EmptyBootPath(field).into());
+            }
+        }
+        for (field, digest) in &[
+            ("kernel_hash", &self.kernel_hash),
+            ("initrd_hash", &self.initrd_hash),
+        ] {
+            if let Some(digest) = digest {
+                if digest.len() != SHA256_DIGEST_SIZE * 2
+                    || !digest.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Err(ErrorKind::InvalidDigest(*field, digest.clone()).into());
+                }
+            }
+        }
+        if let Some(initrd) = self.initrd.first() {
+            if self.initrd_size == 0 {
+                return Err(ErrorKind::EmptyInitrd(initrd.display().to_string()).into());
+            }
+        }
+
+        let (gap_start, gap_size) = self.gap_range;
+        let gap_end = gap_start + gap_size;
+        if gap_size == 0 || gap_end > FIRMWARE_MIRROR_END {
+            return Err(ErrorKind::InvalidGapRange(gap_start, gap_size).into());
+        }
+        for (field, addr) in &[
+            ("ioapic_addr", self.ioapic_addr),
+            ("lapic_addr", self.lapic_addr),
+        ] {
+            if u64::from(*addr) < gap_start || u64::from(*addr) >= gap_end {
+                return Err(ErrorKind::ApicAddrInRam(*field, *addr).into());
+            }
+        }
+        self.layout.check()
+    }
 }
 
 /// The start address for some boot source in guest memory for `x86_64`.
@@ -198,25 +1106,65 @@ pub struct X86BootLoader {
     pub boot_pml4_addr: u64,
     pub zero_page_addr: u64,
     pub segments: BootGdtSegment,
+    /// Address of `hvm_start_info` if booted with PVH, passed in %rbx to
+    /// 32-bit entry.
+    pub pvh_start_info: Option<u64>,
+    /// Number of CPUs described to guest, including disabled ones.
+    pub max_cpus: u8,
+    /// 64-bit EFI handover entry of bzImage, which firmware may jump to
+    /// instead of `kernel_start`.
+    pub efi_handover_entry: Option<u64>,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct BootGdtSegment {
-    pub code_segment: kvm_segment,
-    pub data_segment: kvm_segment,
+    pub code_segment: SegmentRegister,
+    pub data_segment: SegmentRegister,
     pub gdt_base: u64,
     pub gdt_limit: u16,
     pub idt_base: u64,
     pub idt_limit: u16,
+    /// TSS loaded in TR, if boot gdt has one.
+    pub tss_segment: Option<SegmentRegister>,
+    /// Selector of TSS, 0 if there is none.
+    pub tr: u16,
+}
+
+/// Number of GiB identity-mapped by boot page tables for guest memory of
+/// `mem_size`, at least 1 GiB and at most 512 GiB covered by one PML4 entry.
+fn page_table_gibs(mem_size: u64) -> u64 {
+    let gibs = (mem_size + (1 << 30) - 1) >> 30;
+    std::cmp::min(std::cmp::max(gibs, 1), 512)
 }
 
-fn setup_page_table(sys_mem: &Arc<AddressSpace>) -> Result<u64> {
+/// Number of pages taken by boot page tables: PML4, PDPTE and one PD page
+/// per GiB if 2 MiB pages are used.
+fn page_table_pages(mem_size: u64, use_gib_pages: bool) -> u64 {
+    if use_gib_pages {
+        2
+    } else {
+        2 + page_table_gibs(mem_size)
+    }
+}
+
+/// Build page tables identity-mapping guest memory up to `mem_size`, at
+/// most 512 GiB, with 2 MiB pages, or 1 GiB pages if `use_gib_pages`.
+/// PML4 is at `layout.pml4`, PDPTE and PD pages follow it contiguously.
+///
+/// Return address of PML4 and number of pages used.
+fn setup_page_table(
+    sys_mem: &Arc<AddressSpace>,
+    layout: &BootLayout,
+    mem_size: u64,
+    use_gib_pages: bool,
+) -> Result<(u64, u64)> {
     // Initial pagetables.
 
     // Puts PML4 right after zero page but aligned to 4k.
-    let boot_pml4_addr = PML4_START;
-    let boot_pdpte_addr = PDPTE_START;
-    let boot_pde_addr = PDE_START;
+    let boot_pml4_addr = layout.pml4;
+    let boot_pdpte_addr = layout.pdpte();
+    let boot_pde_addr = layout.pde();
+    let gibs = page_table_gibs(mem_size);
 
     // Entry covering VA [0..512GB)
     let pdpte = boot_pdpte_addr | 0x03;
@@ -224,85 +1172,127 @@ fn setup_page_table(sys_mem: &Arc<AddressSpace>) -> Result<u64> {
         .write_object(&pdpte, GuestAddress(boot_pml4_addr))
         .chain_err(|| format!("Failed to load PD PTE to 0x{:x}", boot_pml4_addr))?;
 
-    // Entry covering VA [0..1GB)
-    let pde = boot_pde_addr | 0x03;
-    sys_mem
-        .write_object(&pde, GuestAddress(boot_pdpte_addr))
-        .unwrap();
+    for gib in 0..gibs {
+        let pdpte_addr = boot_pdpte_addr + gib * 8;
+        if use_gib_pages {
+            // 1GB page covering VA [gib..gib + 1GB).
+            let pdpte = (gib << 30) + 0x83u64;
+            sys_mem
+                .write_object(&pdpte, GuestAddress(pdpte_addr))
+                .chain_err(|| format!("Failed to load PDPTE to 0x{:x}", pdpte_addr))?;
+            continue;
+        }
 
-    // 512 2MB entries together covering VA [0..1GB). Note we are assuming
-    // CPU supports 2MB pages (/proc/cpuinfo has 'pse'). All modern CPUs do.
-    for i in 0..512u64 {
-        let pde = (i << 21) + 0x83u64;
+        // Entry covering VA [gib..gib + 1GB)
+        let pd_addr = boot_pde_addr + gib * 0x1000;
+        let pde = pd_addr | 0x03;
         sys_mem
-            .write_object(&pde, GuestAddress(boot_pde_addr + i * 8))
-            .chain_err(|| format!("Failed to load PDE to 0x{:x}", boot_pde_addr + i * 8))?;
+            .write_object(&pde, GuestAddress(pdpte_addr))
+            .chain_err(|| format!("Failed to load PDPTE to 0x{:x}", pdpte_addr))?;
+
+        // 512 2MB entries together covering VA [gib..gib + 1GB). Note we are
+        // assuming CPU supports 2MB pages (/proc/cpuinfo has 'pse'). All
+        // modern CPUs do.
+        for i in 0..512u64 {
+            let pde = (((gib << 9) + i) << 21) + 0x83u64;
+            sys_mem
+                .write_object(&pde, GuestAddress(pd_addr + i * 8))
+                .chain_err(|| format!("Failed to load PDE to 0x{:x}", pd_addr + i * 8))?;
+        }
     }
 
-    Ok(boot_pml4_addr)
+    Ok((boot_pml4_addr, page_table_pages(mem_size, use_gib_pages)))
 }
 
 macro_rules! write_entry {
-    ( $d:expr, $t:ty, $m:expr, $o:expr, $s:expr ) => {
+    ( $d:expr, $t:ty, $m:expr, $o:expr, $s:expr, $c:expr ) => {
         let entry = $d;
-        $m.write_object(&entry, GuestAddress($o))?;
+        let addr = $o;
+        $m.write_object(&entry, GuestAddress(addr))
+            .chain_err(|| format!("Failed to load mptable entry to 0x{:x}", addr))?;
         $o += std::mem::size_of::<$t>() as u64;
         $s = $s.wrapping_add(obj_checksum(&entry));
+        $c += 1;
     };
 }
 
-fn setup_isa_mptable(
-    sys_mem: &Arc<AddressSpace>,
-    start_addr: u64,
-    num_cpus: u8,
-    ioapic_addr: u32,
+/// IOAPIC pin of each ISA irq as (irq, pin). Overridden irqs go to their
+/// pins, others are wired to the pin of same number unless the pin is taken
+/// by an override.
+fn isa_irq_routes(irq_overrides: &[(u8, u8)]) -> Result<Vec<(u8, u8)>> {
+    for (irq, pin) in irq_overrides {
+        if *irq >= MPTABLE_ISA_IRQS || *pin >= IOAPIC_NUM_PINS {
+            return Err(ErrorKind::InvalidIrqOverride(*irq, *pin).into());
+        }
+    }
+
+    let mut routes = Vec::new();
+    for irq in 0..MPTABLE_ISA_IRQS {
+        match irq_overrides.iter().find(|(i, _)| *i == irq) {
+            Some(route) => routes.push(*route),
+            None if irq_overrides.iter().any(|(_, pin)| *pin == irq) => {}
+            None => routes.push((irq, irq)),
+        }
+    }
+    Ok(routes)
+}
+
+fn setup_isa_mptable(
+    sys_mem: &Arc<AddressSpace>,
+    start_addr: u64,
+    num_cpus: u8,
+    max_cpus: u8,
+    ioapic_addr: u32,
     lapic_addr: u32,
+    irq_overrides: &[(u8, u8)],
 ) -> Result<()> {
     const BUS_ID: u8 = 0;
-    const MPTABLE_MAX_CPUS: u32 = 254; // mptable max support 255 cpus, reserve one for ioapic id
-    const MPTABLE_IOAPIC_NR: u8 = 16;
 
-    if u32::from(num_cpus) > MPTABLE_MAX_CPUS {
-        return Err(ErrorKind::MaxCpus(num_cpus).into());
-    }
+    let irq_routes = isa_irq_routes(irq_overrides)?;
 
-    let ioapic_id: u8 = num_cpus + 1;
+    let ioapic_id: u8 = max_cpus + 1;
     let header = start_addr + std::mem::size_of::<FloatingPointer>() as u64;
-    sys_mem.write_object(
-        &FloatingPointer::new(header as u32),
-        GuestAddress(start_addr),
-    )?;
+    sys_mem
+        .write_object(
+            &FloatingPointer::new(header as u32),
+            GuestAddress(start_addr),
+        )
+        .chain_err(|| format!("Failed to load mptable to 0x{:x}", start_addr))?;
 
     let mut offset = header + std::mem::size_of::<ConfigTableHeader>() as u64;
     let mut sum = 0u8;
+    let mut count = 0u16;
 
-    for cpu_id in 0..num_cpus {
+    for cpu_id in 0..max_cpus {
         write_entry!(
-            ProcessEntry::new(cpu_id as u8, true, cpu_id == 0),
+            ProcessEntry::new(cpu_id, cpu_id < num_cpus, cpu_id == 0),
             ProcessEntry,
             sys_mem,
             offset,
-            sum
+            sum,
+            count
         );
     }
 
-    write_entry!(BusEntry::new(BUS_ID), BusEntry, sys_mem, offset, sum);
+    write_entry!(BusEntry::new(BUS_ID), BusEntry, sys_mem, offset, sum, count);
 
     write_entry!(
         IOApicEntry::new(ioapic_id, true, ioapic_addr),
         IOApicEntry,
         sys_mem,
         offset,
-        sum
+        sum,
+        count
     );
 
-    for i in 0..MPTABLE_IOAPIC_NR {
+    for (irq, pin) in irq_routes {
         write_entry!(
-            IOInterruptEntry::new(INTERRUPT_TYPE_INT, BUS_ID, i, ioapic_id, i),
+            IOInterruptEntry::new(INTERRUPT_TYPE_INT, BUS_ID, irq, ioapic_id, pin),
             IOInterruptEntry,
             sys_mem,
             offset,
-            sum
+            sum,
+            count
         );
     }
 
@@ -311,7 +1301,8 @@ fn setup_isa_mptable(
         LocalInterruptEntry,
         sys_mem,
         offset,
-        sum
+        sum,
+        count
     );
 
     write_entry!(
@@ -319,83 +1310,512 @@ fn setup_isa_mptable(
         LocalInterruptEntry,
         sys_mem,
         offset,
-        sum
+        sum,
+        count
     );
 
-    sys_mem.write_object(
-        &ConfigTableHeader::new((offset - header) as u16, sum, lapic_addr),
-        GuestAddress(header),
-    )?;
+    sys_mem
+        .write_object(
+            &ConfigTableHeader::new((offset - header) as u16, count, sum, lapic_addr),
+            GuestAddress(header),
+        )
+        .chain_err(|| format!("Failed to load mptable header to 0x{:x}", header))?;
+
+    Ok(())
+}
+
+/// Place initrd near the top of guest RAM if kernel supports initrd above 4G,
+/// skipping the 32-bit PCI gap. Return `None` if it doesn't fit there.
+fn high_initrd_addr(config: &X86BootLoaderConfig, mem_end: u64) -> Option<u64> {
+    let (gap_start, gap_size) = config.gap_range;
+    let gap_end = gap_start + gap_size;
+    let (ram_start, ram_end) = if mem_end > gap_end {
+        (gap_end, mem_end)
+    } else {
+        (VMLINUX_RAM_START, std::cmp::min(mem_end, gap_start))
+    };
+
+    let addr = ram_end.checked_sub(u64::from(config.initrd_size))? & !0xfff;
+    if addr < ram_start {
+        return None;
+    }
+    Some(addr)
+}
+
+/// Place initrd below `INITRD_ADDR_MAX`.
+fn low_initrd_addr(config: &X86BootLoaderConfig, mem_end: u64) -> u64 {
+    let mut initrd_addr_max = INITRD_ADDR_MAX as u32;
+    if initrd_addr_max as u64 > mem_end {
+        initrd_addr_max = mem_end as u32;
+    };
+
+    u64::from(initrd_addr_max.saturating_sub(config.initrd_size) & !0xfffu32)
+}
+
+/// Place initrd, above 4G if kernel allows it. 0 if there is no initrd.
+fn initrd_addr(
+    config: &X86BootLoaderConfig,
+    mem_end: u64,
+    boot_hdr: Option<&RealModeKernelHeader>,
+) -> u64 {
+    if config.initrd_size == 0 {
+        return 0;
+    }
+    boot_hdr
+        .filter(|hdr| hdr.can_load_above_4g())
+        .and_then(|_| high_initrd_addr(config, mem_end))
+        .unwrap_or_else(|| low_initrd_addr(config, mem_end))
+}
+
+/// Choose load address of bzImage protected-mode kernel of `size` bytes
+/// and write it to `code32_start`.
+///
+/// Non-relocatable kernel stays at `code32_start`. Relocatable kernel is put
+/// at the first address from `pref_address`, aligned to `kernel_alignment`,
+/// which doesn't collide with initrd or ACPI tables. Either way the kernel
+/// must fit in RAM below the 32-bit gap.
+///
+/// # Errors
+/// * `KernelPlacement`: No such address in guest memory.
+fn place_bzimage(
+    config: &X86BootLoaderConfig,
+    mem_end: u64,
+    boot_hdr: &mut RealModeKernelHeader,
+    size: u64,
+    initrd_addr: u64,
+) -> Result<()> {
+    let ram_end = std::cmp::min(mem_end, config.gap_range.0);
+    let fixed = u64::from(boot_hdr.code32_start);
+    if !boot_hdr.relocatable() {
+        if fixed < VMLINUX_RAM_START || fixed + size > ram_end {
+            return Err(ErrorKind::KernelPlacement(fixed, size).into());
+        }
+        return Ok(());
+    }
+
+    let align = match u64::from(boot_hdr.kernel_alignment) {
+        align if align.is_power_of_two() => align,
+        _ => KERNEL_ALIGN_DEFAULT,
+    };
+    let align_up = |addr: u64| (addr + align - 1) & !(align - 1);
+    let mut taken = vec![(initrd_addr, initrd_addr + u64::from(config.initrd_size))];
+    if let Some((start, size)) = acpi_range(config)? {
+        taken.push((start, start + size));
+    }
+
+    let preferred = std::cmp::max(boot_hdr.preferred_address(), VMLINUX_RAM_START);
+    let mut addr = align_up(preferred);
+    while addr + size <= ram_end {
+        match taken
+            .iter()
+            .find(|(start, end)| start < end && *start < addr + size && addr < *end)
+        {
+            Some((_, end)) => addr = align_up(*end),
+            None => {
+                boot_hdr.code32_start = addr as u32;
+                return Ok(());
+            }
+        }
+    }
+    Err(ErrorKind::KernelPlacement(preferred, size).into())
+}
+
+/// Check guest memory regions used in boot don't overlap with each other.
+///
+/// # Arguments
+/// * `config` - boot loader config.
+/// * `kernel_range` - range [start, end) of kernel in guest memory.
+/// * `initrd_addr` - start address of initrd.
+/// * `mem_end` - end address of guest memory, which page tables map.
+/// * `pvh` - `hvm_start_info` takes the place of zero page and page tables.
+///
+/// # Errors
+/// * `BootLayoutOverlap`: Two regions overlap.
+fn check_boot_layout(
+    config: &X86BootLoaderConfig,
+    kernel_range: (u64, u64),
+    initrd_addr: u64,
+    mem_end: u64,
+    pvh: bool,
+) -> Result<()> {
+    let mut regions = vec![
+        (
+            "initrd",
+            initrd_addr,
+            initrd_addr + u64::from(config.initrd_size),
+        ),
+        (
+            "cmdline",
+            config.layout.cmdline,
+            config.layout.cmdline + config.kernel_cmdline.len() as u64 + 1,
+        ),
+        ("kernel", kernel_range.0, kernel_range.1),
+        (
+            "setup data",
+            config.layout.setup_data,
+            config.layout.setup_data + setup_data_size(config),
+        ),
+    ];
+    let firmware_size = firmware_size(config)?;
+    regions.push((
+        "firmware",
+        VMLINUX_RAM_START - firmware_size,
+        VMLINUX_RAM_START,
+    ));
+    if let Some((start, size)) = acpi_range(config)? {
+        regions.push(("acpi", start, start + size));
+    }
+    let page_tables = page_table_pages(mem_end, config.use_gib_pages);
+    regions.extend(config.layout.fixed_regions(pvh, page_tables));
+    check_overlap(&regions)
+}
+
+/// Check no two of `regions` (name, start, end) overlap, empty regions are
+/// ignored.
+///
+/// # Errors
+/// * `BootLayoutOverlap`: Two regions overlap.
+fn check_overlap(regions: &[(&'static str, u64, u64)]) -> Result<()> {
+    for (index, (name, start, end)) in regions.iter().enumerate() {
+        for (other, other_start, other_end) in regions[index + 1..].iter() {
+            if start < end && other_start < other_end && start < other_end && other_start < end {
+                return Err(ErrorKind::BootLayoutOverlap(
+                    *name,
+                    (*start, *end),
+                    *other,
+                    (*other_start, *other_end),
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Size of `setup_data` list, each entry is aligned to 8 bytes.
+fn setup_data_size(config: &X86BootLoaderConfig) -> u64 {
+    config
+        .setup_data
+        .iter()
+        .map(|entry| {
+            (std::mem::size_of::<SetupDataHeader>() as u64 + entry.data.len() as u64 + 7) & !7
+        })
+        .sum()
+}
+
+/// Write `setup_data` list from `config.layout.setup_data`, entries are
+/// linked in the order of `config.setup_data`.
+///
+/// Return address of the first entry, 0 if list is empty.
+///
+/// # Errors
+/// * `SetupDataOverflow`: List is larger than `SETUP_DATA_MAX_SIZE`.
+/// * `AddressSpace`: Write list to guest memory failed.
+fn setup_setup_data(config: &X86BootLoaderConfig, sys_mem: &Arc<AddressSpace>) -> Result<u64> {
+    let size = setup_data_size(config);
+    if size > SETUP_DATA_MAX_SIZE {
+        return Err(ErrorKind::SetupDataOverflow(size, SETUP_DATA_MAX_SIZE).into());
+    }
+    if config.setup_data.is_empty() {
+        return Ok(0);
+    }
+
+    let header_size = std::mem::size_of::<SetupDataHeader>() as u64;
+    let mut addr = config.layout.setup_data;
+    for (index, entry) in config.setup_data.iter().enumerate() {
+        let next = (addr + header_size + entry.data.len() as u64 + 7) & !7;
+        let header = SetupDataHeader {
+            next: if index + 1 < config.setup_data.len() {
+                next
+            } else {
+                0
+            },
+            type_: entry.type_,
+            len: entry.data.len() as u32,
+        };
+        sys_mem
+            .write_object(&header, GuestAddress(addr))
+            .chain_err(|| format!("Failed to load setup_data to 0x{:x}", addr))?;
+        sys_mem
+            .write(
+                &mut entry.data.as_slice(),
+                GuestAddress(addr + header_size),
+                entry.data.len() as u64,
+            )
+            .chain_err(|| format!("Failed to load setup_data to 0x{:x}", addr))?;
+        addr = next;
+    }
+
+    Ok(config.layout.setup_data)
+}
+
+/// Range (start, size) of ACPI tables, `None` if guest has no ACPI tables
+/// from us: MP table is used alone, or firmware provides the tables.
+fn acpi_range(config: &X86BootLoaderConfig) -> Result<Option<(u64, u64)>> {
+    if config.mptable_only || config.firmware.is_some() {
+        return Ok(None);
+    }
+    isa_irq_routes(&config.irq_overrides)?;
+    let addr = config.acpi_addr.unwrap_or(ACPI_TABLES_START);
+    let size = build_acpi_tables(
+        addr,
+        config.cpu_count,
+        config.max_cpus,
+        config.ioapic_addr,
+        config.lapic_addr,
+        &config.irq_overrides,
+    )
+    .len() as u64;
+    if addr % 16 != 0 || addr + size > FIRMWARE_MIRROR_END {
+        return Err(ErrorKind::InvalidAcpiAddr(addr).into());
+    }
+    Ok(Some((addr, size)))
+}
 
+/// Write ACPI tables and MP table describing cpus and interrupt controllers.
+/// MP table is kept for kernels without ACPI, unless there are too many cpus
+/// for it.
+fn setup_platform_tables(config: &X86BootLoaderConfig, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+    if let Some((addr, size)) = acpi_range(config)? {
+        let tables = build_acpi_tables(
+            addr,
+            config.cpu_count,
+            config.max_cpus,
+            config.ioapic_addr,
+            config.lapic_addr,
+            &config.irq_overrides,
+        );
+        sys_mem
+            .write(&mut tables.as_slice(), GuestAddress(addr), size)
+            .chain_err(|| format!("Failed to load ACPI tables to 0x{:x}", addr))?;
+        if u32::from(config.max_cpus) > MPTABLE_MAX_CPUS {
+            return Ok(());
+        }
+    }
+
+    setup_isa_mptable(
+        sys_mem,
+        config.layout.mptable,
+        config.cpu_count,
+        config.max_cpus,
+        config.ioapic_addr,
+        config.lapic_addr,
+        &config.irq_overrides,
+    )
+}
+
+/// Check firmware blob can be placed below 1 MiB.
+fn check_firmware_size(size: u64) -> Result<()> {
+    if size == 0 || size % FIRMWARE_ALIGN != 0 || size > FIRMWARE_MAX_SIZE {
+        return Err(ErrorKind::InvalidFirmwareSize(size).into());
+    }
     Ok(())
 }
 
+/// Size of firmware blob in `config`, 0 if there is none.
+fn firmware_size(config: &X86BootLoaderConfig) -> Result<u64> {
+    match &config.firmware {
+        Some(path) => {
+            let size = fs::metadata(path)
+                .chain_err(|| format!("Failed to stat firmware {}", path.display()))?
+                .len();
+            check_firmware_size(size)?;
+            Ok(size)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Memory map of guest as (addr, size, type), shared by e820 table in zero
+/// page and PVH memory map. RAM above 1 MiB comes from the flat view of
+/// `sys_mem`, with adjacent ranges coalesced. Firmware blob below 1 MiB and
+/// ACPI tables are reserved.
+///
+/// # Errors
+/// * `TooManyE820Entries`: Memory map doesn't fit in zero page.
+fn e820_table(
+    config: &X86BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<Vec<(u64, u64, u32)>> {
+    let firmware_size = firmware_size(config)?;
+    let bios_begin = if firmware_size > 0 {
+        VMLINUX_RAM_START - firmware_size
+    } else {
+        MB_BIOS_BEGIN
+    };
+    let mut table = vec![
+        (
+            REAL_MODE_IVT_BEGIN,
+            EBDA_START - REAL_MODE_IVT_BEGIN,
+            E820_RAM,
+        ),
+        (EBDA_START, VGA_RAM_BEGIN - EBDA_START, E820_RESERVED),
+        (bios_begin, firmware_size, E820_RESERVED),
+    ];
+
+    let mut high_memory: Vec<(u64, u64)> = Vec::new();
+    for range in sys_mem.ram_ranges() {
+        let start = std::cmp::max(range.base.raw_value(), VMLINUX_RAM_START);
+        let end = range.end_addr().raw_value();
+        if end <= start {
+            continue;
+        }
+        match high_memory.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => high_memory.push((start, end)),
+        }
+    }
+    for (start, end) in high_memory {
+        table.push((start, end - start, E820_RAM));
+    }
+
+    if let Some((start, size)) = acpi_range(config)? {
+        table = e820_reserve(table, start, size);
+    }
+    if table.len() > E820_MAX_ENTRIES {
+        return Err(ErrorKind::TooManyE820Entries(table.len(), E820_MAX_ENTRIES).into());
+    }
+    Ok(table)
+}
+
+/// Mark [start, start + size) reserved in e820 `table`, splitting ram
+/// entries it falls into.
+fn e820_reserve(table: Vec<(u64, u64, u32)>, start: u64, size: u64) -> Vec<(u64, u64, u32)> {
+    let end = start + size;
+    let mut result = Vec::with_capacity(table.len() + 2);
+    for (addr, len, type_) in table {
+        if type_ != E820_RAM || addr + len <= start || addr >= end {
+            result.push((addr, len, type_));
+            continue;
+        }
+        if addr < start {
+            result.push((addr, start - addr, E820_RAM));
+        }
+        if addr + len > end {
+            result.push((end, addr + len - end, E820_RAM));
+        }
+    }
+    result.push((start, size, E820_RESERVED));
+    result.sort_by_key(|entry| entry.0);
+    result
+}
+
 fn setup_boot_params(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
     boot_hdr: Option<RealModeKernelHeader>,
 ) -> Result<(u64, u64)> {
+    let mem_end = sys_mem.memory_end_address().raw_value();
     let (ramdisk_size, ramdisk_image, initrd_addr) = if config.initrd_size > 0 {
-        let mut initrd_addr_max = INITRD_ADDR_MAX as u32;
-        if initrd_addr_max as u64 > sys_mem.memory_end_address().raw_value() as u64 {
-            initrd_addr_max = sys_mem.memory_end_address().raw_value() as u32;
-        };
-
-        let img = (initrd_addr_max - config.initrd_size as u32) & !0xfffu32;
-        (config.initrd_size as u32, img, img as u64)
+        let img = initrd_addr(config, mem_end, boot_hdr.as_ref());
+        (config.initrd_size as u32, img as u32, img)
     } else {
         info!("No initrd image file.");
         (0u32, 0u32, 0u64)
     };
 
-    let mut boot_params = if let Some(mut boot_hdr) = boot_hdr {
+    let mut boot_hdr = if let Some(mut boot_hdr) = boot_hdr {
         boot_hdr.setup(
-            CMDLINE_START as u32,
-            config.kernel_cmdline.len() as u32,
+            config.layout.cmdline as u32,
+            config.kernel_cmdline.len() as u32 + 1,
             ramdisk_image,
             ramdisk_size,
         );
-        BootParams::new(boot_hdr)
+        boot_hdr
     } else {
-        BootParams::new(RealModeKernelHeader::new(
-            CMDLINE_START as u32,
-            config.kernel_cmdline.len() as u32,
+        RealModeKernelHeader::new(
+            config.layout.cmdline as u32,
+            config.kernel_cmdline.len() as u32 + 1,
             ramdisk_image,
             ramdisk_size,
-        ))
+        )
     };
+    boot_hdr.set_setup_data(setup_setup_data(config, sys_mem)?);
+    let mut boot_params = BootParams::new(boot_hdr);
+    boot_params.set_ext_ramdisk((initrd_addr >> 32) as u32, 0);
+    let mut screen_info = config.screen_info;
+    screen_info.set_ext_mem(mem_end);
+    boot_params.set_screen_info(screen_info);
+
+    for (addr, size, type_) in e820_table(config, sys_mem)? {
+        boot_params.add_e820_entry(addr, size, type_)?;
+    }
+    if let Some((rsdp_addr, _)) = acpi_range(config)? {
+        boot_params.set_acpi_rsdp_addr(rsdp_addr);
+    }
 
-    boot_params.add_e820_entry(
-        REAL_MODE_IVT_BEGIN,
-        EBDA_START - REAL_MODE_IVT_BEGIN,
-        E820_RAM,
-    );
-    boot_params.add_e820_entry(EBDA_START, VGA_RAM_BEGIN - EBDA_START, E820_RESERVED);
-    boot_params.add_e820_entry(MB_BIOS_BEGIN, 0, E820_RESERVED);
+    let zero_page = config.layout.zero_page;
+    sys_mem
+        .write_object(&boot_params, GuestAddress(zero_page))
+        .chain_err(|| format!("Failed to load zero page to 0x{:x}", zero_page))?;
+
+    Ok((zero_page, initrd_addr))
+}
 
-    let high_memory_start = VMLINUX_RAM_START;
-    let layout_32bit_gap_end = config.gap_range.0 + config.gap_range.1;
+/// Write `hvm_start_info`, module list of initrd and memory map to guest
+/// memory for PVH boot.
+///
+/// Return address of `hvm_start_info` and initrd.
+fn setup_pvh_start_info(
+    config: &X86BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<(u64, u64)> {
     let mem_end = sys_mem.memory_end_address().raw_value();
-    if mem_end < layout_32bit_gap_end {
-        boot_params.add_e820_entry(high_memory_start, mem_end - high_memory_start, E820_RAM);
+    let layout = &config.layout;
+    let mut start_info = HvmStartInfo {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: layout.cmdline,
+        memmap_paddr: layout.pvh_memmap(),
+        ..Default::default()
+    };
+
+    let initrd_addr = if config.initrd_size > 0 {
+        let initrd_addr = low_initrd_addr(config, mem_end);
+        let module = HvmModlistEntry {
+            paddr: initrd_addr,
+            size: u64::from(config.initrd_size),
+            ..Default::default()
+        };
+        sys_mem
+            .write_object(&module, GuestAddress(layout.pvh_modlist()))
+            .chain_err(|| format!("Failed to load PVH modlist to 0x{:x}", layout.pvh_modlist()))?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = layout.pvh_modlist();
+        initrd_addr
     } else {
-        boot_params.add_e820_entry(high_memory_start, config.gap_range.0, E820_RAM);
-        boot_params.add_e820_entry(
-            layout_32bit_gap_end,
-            mem_end - layout_32bit_gap_end,
-            E820_RAM,
-        );
+        info!("No initrd image file.");
+        0
+    };
+
+    if let Some((rsdp_addr, _)) = acpi_range(config)? {
+        start_info.rsdp_paddr = rsdp_addr;
+    }
+
+    let mut memmap_addr = layout.pvh_memmap();
+    for (addr, size, type_) in e820_table(config, sys_mem)? {
+        let entry = HvmMemmapTableEntry {
+            addr,
+            size,
+            type_,
+            reserved: 0,
+        };
+        sys_mem
+            .write_object(&entry, GuestAddress(memmap_addr))
+            .chain_err(|| format!("Failed to load PVH memmap to 0x{:x}", memmap_addr))?;
+        memmap_addr += std::mem::size_of::<HvmMemmapTableEntry>() as u64;
+        start_info.memmap_entries += 1;
     }
 
     sys_mem
-        .write_object(&boot_params, GuestAddress(ZERO_PAGE_START))
-        .chain_err(|| format!("Failed to load zero page to 0x{:x}", ZERO_PAGE_START))?;
+        .write_object(&start_info, GuestAddress(layout.pvh_info))
+        .chain_err(|| format!("Failed to load PVH start info to 0x{:x}", layout.pvh_info))?;
 
-    Ok((ZERO_PAGE_START, initrd_addr))
+    Ok((layout.pvh_info, initrd_addr))
 }
 
-fn write_gdt_table(table: &[u64], guest_mem: &Arc<AddressSpace>) -> Result<()> {
-    let mut boot_gdt_addr = BOOT_GDT_OFFSET as u64;
+fn write_gdt_table(table: &[u64], guest_mem: &Arc<AddressSpace>, addr: u64) -> Result<()> {
+    let mut boot_gdt_addr = addr;
     for (_, entry) in table.iter().enumerate() {
         guest_mem
             .write_object(entry, GuestAddress(boot_gdt_addr))
@@ -405,111 +1825,1586 @@ fn write_gdt_table(table: &[u64], guest_mem: &Arc<AddressSpace>) -> Result<()> {
     Ok(())
 }
 
-fn write_idt_value(val: u64, guest_mem: &Arc<AddressSpace>) -> Result<()> {
-    let boot_idt_addr = BOOT_IDT_OFFSET;
+fn write_idt_value(val: u64, guest_mem: &Arc<AddressSpace>, addr: u64) -> Result<()> {
+    let boot_idt_addr = addr;
     guest_mem
         .write_object(&val, GuestAddress(boot_idt_addr))
-        .chain_err(|| format!("Failed to load gdt to 0x{:x}", boot_idt_addr))?;
+        .chain_err(|| format!("Failed to load idt to 0x{:x}", boot_idt_addr))?;
+
+    Ok(())
+}
+
+/// Write an empty 64-bit TSS without IO permission bitmap.
+fn write_tss(guest_mem: &Arc<AddressSpace>, addr: u64) -> Result<()> {
+    let mut tss = [0_u8; BOOT_TSS_SIZE];
+    tss[TSS_IOMAP_BASE_OFFSET..TSS_IOMAP_BASE_OFFSET + 2]
+        .copy_from_slice(&(BOOT_TSS_SIZE as u16).to_le_bytes());
+    guest_mem
+        .write(&mut tss.as_ref(), GuestAddress(addr), BOOT_TSS_SIZE as u64)
+        .chain_err(|| format!("Failed to load tss to 0x{:x}", addr))?;
 
     Ok(())
 }
 
-pub fn setup_gdt(guest_mem: &Arc<AddressSpace>) -> Result<BootGdtSegment> {
+/// Write boot gdt and idt to guest memory.
+///
+/// # Arguments
+/// * `guest_mem` - guest memory.
+/// * `layout` - addresses of gdt, idt and TSS.
+/// * `with_tss` - append a 64-bit TSS descriptor to gdt, for code which
+///   needs a valid TR before kernel loads its own gdt.
+pub fn setup_gdt(
+    guest_mem: &Arc<AddressSpace>,
+    layout: &BootLayout,
+    with_tss: bool,
+) -> Result<BootGdtSegment> {
+    setup_gdt_with_code(guest_mem, layout, BOOT_CODE64_FLAGS, with_tss)
+}
+
+fn setup_gdt_with_code(
+    guest_mem: &Arc<AddressSpace>,
+    layout: &BootLayout,
+    code_flags: u64,
+    with_tss: bool,
+) -> Result<BootGdtSegment> {
+    let tss = GdtEntry::new(BOOT_TSS_FLAGS, layout.tss(), BOOT_TSS_SIZE as u64 - 1);
     let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
-        GdtEntry::new(0, 0, 0).into(),            // NULL
-        GdtEntry::new(0, 0, 0).into(),            // NULL
-        GdtEntry::new(0xa09b, 0, 0xfffff).into(), // CODE
-        GdtEntry::new(0xc093, 0, 0xfffff).into(), // DATA
+        GdtEntry::new(0, 0, 0).into(),                // NULL
+        GdtEntry::new(0, 0, 0).into(),                // NULL
+        GdtEntry::new(code_flags, 0, 0xfffff).into(), // CODE
+        GdtEntry::new(0xc093, 0, 0xfffff).into(),     // DATA
+        tss.into(),                                   // TSS
+        layout.tss() >> 32,                           // TSS, upper base
     ];
+    let gdt_len = if with_tss {
+        BOOT_GDT_MAX
+    } else {
+        GDT_ENTRY_BOOT_TSS as usize
+    };
 
-    let mut code_seg: kvm_segment = GdtEntry(gdt_table[GDT_ENTRY_BOOT_CS as usize]).into();
+    let mut code_seg: SegmentRegister = GdtEntry(gdt_table[GDT_ENTRY_BOOT_CS as usize]).into();
     code_seg.selector = GDT_ENTRY_BOOT_CS as u16 * 8;
-    let mut data_seg: kvm_segment = GdtEntry(gdt_table[GDT_ENTRY_BOOT_DS as usize]).into();
+    let mut data_seg: SegmentRegister = GdtEntry(gdt_table[GDT_ENTRY_BOOT_DS as usize]).into();
     data_seg.selector = GDT_ENTRY_BOOT_DS as u16 * 8;
 
-    write_gdt_table(&gdt_table[..], guest_mem)?;
-    write_idt_value(0, guest_mem)?;
+    write_gdt_table(&gdt_table[..gdt_len], guest_mem, layout.gdt)?;
+    write_idt_value(0, guest_mem, layout.idt)?;
+
+    let (tss_segment, tr) = if with_tss {
+        write_tss(guest_mem, layout.tss())?;
+        let mut tss_seg: SegmentRegister = GdtEntry(gdt_table[GDT_ENTRY_BOOT_TSS as usize]).into();
+        tss_seg.base = layout.tss();
+        tss_seg.selector = GDT_ENTRY_BOOT_TSS as u16 * 8;
+        (Some(tss_seg), tss_seg.selector)
+    } else {
+        (None, 0)
+    };
 
     Ok(BootGdtSegment {
         code_segment: code_seg,
         data_segment: data_seg,
-        gdt_base: BOOT_GDT_OFFSET,
-        gdt_limit: std::mem::size_of_val(&gdt_table) as u16 - 1,
-        idt_base: BOOT_IDT_OFFSET,
+        gdt_base: layout.gdt,
+        gdt_limit: (gdt_len * std::mem::size_of::<u64>()) as u16 - 1,
+        idt_base: layout.idt,
         idt_limit: std::mem::size_of::<u64>() as u16 - 1,
+        tss_segment,
+        tr,
     })
 }
 
+/// Prepare guest memory for booting kernel of `kernel_format`. Load address
+/// of relocatable bzImage is chosen here and updated in `kernel_format`.
+/// Each step is marked on `timeline` if it's supplied.
+///
+/// This is one step of `boot_loader::load_linux`, which also copies kernel
+/// and initrd and writes cmdline in order. Call it directly only when
+/// images are loaded apart.
 pub fn linux_bootloader(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
-    boot_hdr: Option<RealModeKernelHeader>,
+    kernel_format: &mut KernelFormat,
+    mut timeline: Option<&mut BootTimeline>,
 ) -> Result<X86BootLoader> {
-    let (kernel_start, vmlinux_start) = if let Some(boot_hdr) = boot_hdr {
-        (
+    config.check()?;
+
+    let mem_end = sys_mem.memory_end_address().raw_value();
+    if let KernelFormat::BzImage(boot_hdr, size) = kernel_format {
+        let initrd_addr = initrd_addr(config, mem_end, Some(&*boot_hdr));
+        place_bzimage(config, mem_end, boot_hdr, *size, initrd_addr)?;
+    }
+    let kernel_format = *kernel_format;
+    let kernel_range = kernel_format.kernel_range();
+    let (kernel_start, vmlinux_start, boot_hdr) = match kernel_format {
+        KernelFormat::BzImage(boot_hdr, _) => (
             boot_hdr.code32_start as u64 + BZIMAGE_BOOT_OFFSET,
             boot_hdr.code32_start as u64,
-        )
-    } else {
-        (VMLINUX_STARTUP, VMLINUX_STARTUP)
+            Some(boot_hdr),
+        ),
+        KernelFormat::Elf(entry, _) => (entry, entry, None),
+        KernelFormat::Pvh(entry, _) => {
+            let initrd_addr = initrd_addr(config, mem_end, None);
+            check_boot_layout(config, kernel_range, initrd_addr, mem_end, true)?;
+            mark(&mut timeline, "layout check");
+            return load_pvh_kernel(config, sys_mem, entry, timeline);
+        }
+        KernelFormat::Raw(_) => (VMLINUX_STARTUP, VMLINUX_STARTUP, None),
     };
+    let initrd_addr = initrd_addr(config, mem_end, boot_hdr.as_ref());
+    check_boot_layout(config, kernel_range, initrd_addr, mem_end, false)?;
+    mark(&mut timeline, "layout check");
 
-    let boot_pml4 = setup_page_table(sys_mem)?;
+    let (boot_pml4, _) = setup_page_table(sys_mem, &config.layout, mem_end, config.use_gib_pages)?;
+    mark(&mut timeline, "page tables");
 
-    setup_isa_mptable(
-        sys_mem,
-        EBDA_START,
-        config.cpu_count,
-        config.ioapic_addr,
-        config.lapic_addr,
-    )?;
+    setup_platform_tables(config, sys_mem)?;
+    mark(&mut timeline, "platform tables");
 
     let (zero_page, initrd_addr) = setup_boot_params(&config, sys_mem, boot_hdr)?;
+    mark(&mut timeline, "zero page");
+
+    if log_enabled!(log::Level::Debug) {
+        debug!("{}", debug::dump_zero_page(sys_mem, zero_page));
+        debug!("{}", debug::dump_mptable(sys_mem, config.layout.mptable));
+    }
 
-    let gdt_seg = setup_gdt(sys_mem)?;
+    let gdt_seg = setup_gdt(sys_mem, &config.layout, config.boot_tss)?;
+    mark(&mut timeline, "gdt");
 
     Ok(X86BootLoader {
         kernel_start,
         vmlinux_start,
-        kernel_sp: BOOT_LOADER_SP,
+        kernel_sp: config.layout.stack_pointer,
         initrd_start: initrd_addr,
         boot_pml4_addr: boot_pml4,
         zero_page_addr: zero_page,
         segments: gdt_seg,
+        pvh_start_info: None,
+        max_cpus: config.max_cpus,
+        efi_handover_entry: boot_hdr.and_then(|hdr| hdr.efi_handover_entry()),
+    })
+}
+
+/// Prepare PVH boot of a loaded ELF kernel: vcpu starts at the 32-bit
+/// `entry` in protected mode without paging, with %rbx pointing to
+/// `hvm_start_info` which replaces zero page.
+///
+/// # Arguments
+/// * `config` - boot loader config.
+/// * `sys_mem` - guest memory.
+/// * `entry` - PVH entry found by `find_pvh_entry`.
+/// * `timeline` - collector of boot steps' durations, if any.
+pub fn load_pvh_kernel(
+    config: &X86BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+    entry: u64,
+    mut timeline: Option<&mut BootTimeline>,
+) -> Result<X86BootLoader> {
+    setup_platform_tables(config, sys_mem)?;
+    mark(&mut timeline, "platform tables");
+
+    let (start_info, initrd_addr) = setup_pvh_start_info(config, sys_mem)?;
+    mark(&mut timeline, "start info");
+
+    let gdt_seg = setup_gdt_with_code(sys_mem, &config.layout, BOOT_CODE32_FLAGS, config.boot_tss)?;
+    mark(&mut timeline, "gdt");
+
+    Ok(X86BootLoader {
+        kernel_start: entry,
+        vmlinux_start: entry,
+        kernel_sp: config.layout.stack_pointer,
+        initrd_start: initrd_addr,
+        boot_pml4_addr: 0,
+        zero_page_addr: 0,
+        segments: gdt_seg,
+        pvh_start_info: Some(start_info),
+        max_cpus: config.max_cpus,
+        efi_handover_entry: None,
+    })
+}
+
+/// Load firmware blob to end at 1 MiB, and mirror its top 128 KiB to end at
+/// 4 GiB, where vcpu fetches the reset vector.
+///
+/// # Arguments
+/// * `path` - host path of firmware blob.
+/// * `sys_mem` - guest memory.
+///
+/// # Errors
+/// * `InvalidFirmwareSize`: Size is not a multiple of 64 KiB or too large.
+/// * `AddressSpace`: Map or write firmware to guest memory failed.
+pub fn load_firmware(path: &PathBuf, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+    let mut firmware =
+        File::open(path).chain_err(|| format!("Failed to open firmware {}", path.display()))?;
+    let size = firmware.seek(SeekFrom::End(0))?;
+    check_firmware_size(size)?;
+
+    let low_start = VMLINUX_RAM_START - size;
+    firmware.seek(SeekFrom::Start(0))?;
+    sys_mem
+        .write(&mut firmware, GuestAddress(low_start), size)
+        .chain_err(|| format!("Failed to load firmware to 0x{:x}", low_start))?;
+
+    let mirror_size = std::cmp::min(size, FIRMWARE_MIRROR_SIZE);
+    let mirror_start = FIRMWARE_MIRROR_END - mirror_size;
+    let mirror = Arc::new(HostMemMapping::new(
+        GuestAddress(mirror_start),
+        mirror_size,
+        -1,
+        0,
+        false,
+        false,
+    )?);
+    sys_mem
+        .root()
+        .add_subregion(Region::init_ram_region(mirror), mirror_start)?;
+    firmware.seek(SeekFrom::Start(size - mirror_size))?;
+    sys_mem
+        .write(&mut firmware, GuestAddress(mirror_start), mirror_size)
+        .chain_err(|| format!("Failed to mirror firmware to 0x{:x}", mirror_start))?;
+
+    Ok(())
+}
+
+/// Layout of firmware boot without kernel: vcpu keeps its reset state and
+/// starts at the reset vector, firmware sets up the rest.
+///
+/// # Errors
+/// Same as `X86BootLoaderConfig::check`, or `InvalidFirmwareSize` if the
+/// firmware blob can't be placed below 1 MiB.
+pub fn firmware_bootloader(config: &X86BootLoaderConfig) -> Result<X86BootLoader> {
+    config.check()?;
+    firmware_size(config)?;
+
+    Ok(X86BootLoader {
+        vmlinux_start: 0,
+        kernel_start: RESET_VECTOR,
+        kernel_sp: 0,
+        initrd_start: 0,
+        boot_pml4_addr: 0,
+        zero_page_addr: 0,
+        segments: BootGdtSegment::default(),
+        pvh_start_info: None,
+        max_cpus: config.max_cpus,
+        efi_handover_entry: None,
     })
 }
 
+/// Initial vcpu registers to enter kernel loaded by `loader`: 64-bit long
+/// mode with boot page tables, %rsi pointing to zero page, or 32-bit
+/// protected mode without paging and %rbx pointing to `hvm_start_info` for
+/// PVH boot.
+pub fn boot_regs(loader: &X86BootLoader) -> (CpuRegisterState, CpuSpecialRegisterState) {
+    let regs = CpuRegisterState {
+        rflags: 0x0002, // Bit 1 is reserved as 1.
+        rip: loader.kernel_start,
+        rsp: loader.kernel_sp,
+        rbp: loader.kernel_sp,
+        rsi: loader.zero_page_addr,
+        rbx: loader.pvh_start_info.unwrap_or(0),
+    };
+
+    let segments = &loader.segments;
+    let mut sregs = CpuSpecialRegisterState {
+        cs: segments.code_segment,
+        ds: segments.data_segment,
+        es: segments.data_segment,
+        fs: segments.data_segment,
+        gs: segments.data_segment,
+        ss: segments.data_segment,
+        tr: segments.tss_segment,
+        gdt: DescriptorTable {
+            base: segments.gdt_base,
+            limit: segments.gdt_limit,
+        },
+        idt: DescriptorTable {
+            base: segments.idt_base,
+            limit: segments.idt_limit,
+        },
+        cr0: X86_CR0_PE,
+        ..Default::default()
+    };
+    if loader.pvh_start_info.is_none() {
+        sregs.cr0 |= X86_CR0_PG;
+        sregs.cr3 = loader.boot_pml4_addr;
+        sregs.cr4 = X86_CR4_PAE;
+        sregs.efer = EFER_LME | EFER_LMA;
+    }
+    (regs, sregs)
+}
+
+/// Write NUL-terminated kernel cmdline to guest memory. It must follow
+/// `linux_bootloader` with the same `kernel_format`, whose zero page holds
+/// address and length of cmdline.
+///
+/// # Arguments
+/// * `config` - boot loader config.
+/// * `sys_mem` - guest memory.
+/// * `kernel_format` - format of kernel, bzImage header limits cmdline length.
+///
+/// # Errors
+/// * `CmdlineOverflow`: Cmdline is longer than kernel accepts.
+/// * `Msg`: Cmdline has NUL in it.
+/// * `AddressSpace`: Write cmdline to guest memory failed.
 pub fn setup_kernel_cmdline(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
+    kernel_format: &KernelFormat,
 ) -> Result<()> {
-    let mut cmdline = config.kernel_cmdline.as_bytes();
-    sys_mem.write(
-        &mut cmdline,
-        GuestAddress(CMDLINE_START),
-        config.kernel_cmdline.len() as u64,
-    )?;
+    let max = kernel_format.cmdline_max() as usize;
+    if config.kernel_cmdline.len() > max {
+        return Err(ErrorKind::CmdlineOverflow(config.kernel_cmdline.len(), max).into());
+    }
+
+    let cmdline = config
+        .kernel_cmdline
+        .to_bytes_with_nul(max)
+        .chain_err(|| "Invalid kernel cmdline")?;
+    sys_mem
+        .write(
+            &mut cmdline.as_slice(),
+            GuestAddress(config.layout.cmdline),
+            cmdline.len() as u64,
+        )
+        .chain_err(|| format!("Failed to load cmdline to 0x{:x}", config.layout.cmdline))?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use super::bootparam::{XLF_CAN_BE_LOADED_ABOVE_4G, XLF_EFI_HANDOVER_64};
     use super::*;
     use address_space::*;
+    use std::io::Write;
     use std::sync::Arc;
     use std::vec::Vec;
-    #[test]
-    fn test_x86_bootloader_and_kernel_cmdline() {
-        let root = Region::init_container_region(0x2000_0000);
+
+    fn test_space(size: u64) -> Arc<AddressSpace> {
+        let root = Region::init_container_region(size);
         let space = AddressSpace::new(root.clone()).unwrap();
-        let ram1 = Arc::new(
-            HostMemMapping::new(GuestAddress(0), 0x1000_0000, -1, 0, false, false).unwrap(),
-        );
-        let region_a = Region::init_ram_region(ram1.clone());
-        root.add_subregion(region_a, ram1.start_address().raw_value())
-            .unwrap();
-        assert_eq!(setup_page_table(&space).unwrap(), 0x0000_9000);
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), size, -1, 0, false, false).unwrap());
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        space
+    }
+
+    /// Build an ELF kernel with a note segment and a load segment carrying
+    /// `payload` at `paddr`. The note segment has a PVH entry note if
+    /// `pvh_entry` is given, or an unrelated Xen note.
+    fn elf_kernel(
+        name: &str,
+        machine: u16,
+        paddr: u64,
+        payload: &[u8],
+        memsz: u64,
+        pvh_entry: Option<u32>,
+    ) -> File {
+        let path =
+            std::env::temp_dir().join(format!("stratovirt_elf_{}_{}", name, std::process::id()));
+        let ehdr_size = std::mem::size_of::<Elf64Header>() as u64;
+        let phdr_size = std::mem::size_of::<Elf64ProgramHeader>() as u64;
+
+        let mut ehdr = Elf64Header {
+            e_type: ET_EXEC,
+            e_machine: machine,
+            e_version: 1,
+            e_entry: paddr + 0x10,
+            e_phoff: ehdr_size,
+            e_ehsize: ehdr_size as u16,
+            e_phentsize: phdr_size as u16,
+            e_phnum: 2,
+            ..Default::default()
+        };
+        ehdr.e_ident[..4].copy_from_slice(&elf::ELF_MAGIC);
+        ehdr.e_ident[4] = elf::ELFCLASS64;
+        ehdr.e_ident[5] = elf::ELFDATA2LSB;
+        let mut notes = Vec::new();
+        // XEN_ELFNOTE_ENTRY, skipped.
+        let entry_note = Elf64Note {
+            n_namesz: 4,
+            n_descsz: 8,
+            n_type: 1,
+        };
+        notes.extend_from_slice(entry_note.as_bytes());
+        notes.extend_from_slice(XEN_ELFNOTE_NAME);
+        notes.extend_from_slice(&0xffff_ffff_8100_0000_u64.to_le_bytes());
+        if let Some(entry) = pvh_entry {
+            let pvh_note = Elf64Note {
+                n_namesz: 4,
+                n_descsz: 4,
+                n_type: XEN_ELFNOTE_PHYS32_ENTRY,
+            };
+            notes.extend_from_slice(pvh_note.as_bytes());
+            notes.extend_from_slice(XEN_ELFNOTE_NAME);
+            notes.extend_from_slice(&entry.to_le_bytes());
+        }
+
+        let note = Elf64ProgramHeader {
+            p_type: PT_NOTE,
+            p_offset: ehdr_size + 2 * phdr_size + payload.len() as u64,
+            p_filesz: notes.len() as u64,
+            ..Default::default()
+        };
+        let load = Elf64ProgramHeader {
+            p_type: PT_LOAD,
+            p_offset: ehdr_size + 2 * phdr_size,
+            p_vaddr: 0xffff_ffff_8000_0000 + paddr,
+            p_paddr: paddr,
+            p_filesz: payload.len() as u64,
+            p_memsz: memsz,
+            ..Default::default()
+        };
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.write_all(ehdr.as_bytes()).unwrap();
+        file.write_all(note.as_bytes()).unwrap();
+        file.write_all(load.as_bytes()).unwrap();
+        file.write_all(payload).unwrap();
+        file.write_all(&notes).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_load_elf_kernel() {
+        let space = test_space(0x1000_0000);
+        let payload = [0x5a_u8; 0x40];
+        let mut kernel = elf_kernel("ok", EM_X86_64, VMLINUX_STARTUP, &payload, 0x80, None);
+
+        let (entry, range) = load_elf_kernel(&mut kernel, &space, None).unwrap();
+        assert_eq!(entry, VMLINUX_STARTUP + 0x10);
+        assert_eq!(range, (VMLINUX_STARTUP, VMLINUX_STARTUP + 0x80));
+        let mut loaded = [0_u8; 0x40];
+        space
+            .read(&mut loaded.as_mut(), GuestAddress(VMLINUX_STARTUP), 0x40)
+            .unwrap();
+        assert_eq!(loaded, payload);
+
+        // The whole file is hashed while segments are loaded.
+        let mut whole = Sha256::new();
+        hash_file(&mut kernel, &mut whole).unwrap();
+        let mut hasher = Sha256::new();
+        match probe_kernel(&mut kernel, &space, false, BOOT_VERSION, Some(&mut hasher)).unwrap() {
+            KernelFormat::Elf(entry, _) => assert_eq!(entry, VMLINUX_STARTUP + 0x10),
+            format => panic!("Unexpected kernel format {:?}", format),
+        }
+        assert_eq!(hasher.finish(), whole.finish());
+    }
+
+    #[test]
+    fn test_load_elf_kernel_errors() {
+        let space = test_space(0x1000_0000);
+        let payload = [0x5a_u8; 0x40];
+
+        let mut kernel = elf_kernel("machine", 183, VMLINUX_STARTUP, &payload, 0x40, None);
+        match load_elf_kernel(&mut kernel, &space, None) {
+            Err(Error(ErrorKind::InvalidElfKernel(183, ET_EXEC), _)) => {}
+            _ => panic!("Aarch64 ELF should be rejected"),
+        }
+        match probe_kernel(&mut kernel, &space, false, BOOT_VERSION, None) {
+            Err(Error(ErrorKind::InvalidKernel, _)) => {}
+            _ => panic!("Invalid ELF should fail both bzImage and ELF"),
+        }
+
+        // Segment file size is larger than its memory size.
+        let mut kernel = elf_kernel("phdr", EM_X86_64, VMLINUX_STARTUP, &payload, 0x20, None);
+        match load_elf_kernel(&mut kernel, &space, None) {
+            Err(Error(ErrorKind::ElfProgramHeader(1), _)) => {}
+            _ => panic!("Corrupt program header should be rejected"),
+        }
+
+        let mut kernel = elf_kernel("overflow", EM_X86_64, 0xfff_fff0, &payload, 0x40, None);
+        match load_elf_kernel(&mut kernel, &space, None) {
+            Err(Error(ErrorKind::ElfSegmentOverflow(0xfff_fff0, 0x40, 0x1000_0000), _)) => {}
+            _ => panic!("Segment beyond guest memory should be rejected"),
+        }
+
+        // Offset of program header overflows.
+        let mut kernel = elf_kernel("phoff", EM_X86_64, VMLINUX_STARTUP, &payload, 0x40, None);
+        set_elf_phoff(&mut kernel, u64::max_value() - 8);
+        match load_elf_kernel(&mut kernel, &space, None) {
+            Err(Error(ErrorKind::ElfProgramHeader(0), _)) => {}
+            _ => panic!("Program header offset overflow should be rejected"),
+        }
+    }
+
+    fn set_elf_phoff(kernel: &mut File, phoff: u64) {
+        let mut ehdr = Elf64Header::default();
+        kernel.seek(SeekFrom::Start(0)).unwrap();
+        kernel.read_exact(ehdr.as_mut_bytes()).unwrap();
+        ehdr.e_phoff = phoff;
+        kernel.seek(SeekFrom::Start(0)).unwrap();
+        kernel.write_all(ehdr.as_bytes()).unwrap();
+    }
+
+    fn pvh_config(initrd_size: u32) -> X86BootLoaderConfig {
+        X86BootLoaderConfig {
+            kernel: None,
+            initrd: Vec::new(),
+            initrd_size,
+            kernel_cmdline: KernelCmdline::from("console=ttyS0"),
+            cpu_count: 1,
+            max_cpus: 1,
+            gap_range: (0xC000_0000, 0x4000_0000),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: Vec::new(),
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            use_gib_pages: false,
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
+        }
+    }
+
+    #[test]
+    fn test_find_pvh_entry() {
+        let space = test_space(0x1000_0000);
+        let payload = [0x5a_u8; 0x40];
+
+        let mut kernel = elf_kernel("no_pvh", EM_X86_64, VMLINUX_STARTUP, &payload, 0x40, None);
+        assert_eq!(find_pvh_entry(&mut kernel).unwrap(), None);
+        match probe_kernel(&mut kernel, &space, true, BOOT_VERSION, None) {
+            Err(Error(ErrorKind::NoPvhEntry, _)) => {}
+            _ => panic!("Kernel without PVH entry can't be booted with PVH"),
+        }
+
+        let mut kernel = elf_kernel(
+            "pvh",
+            EM_X86_64,
+            VMLINUX_STARTUP,
+            &payload,
+            0x40,
+            Some(0x100_0080),
+        );
+        assert_eq!(find_pvh_entry(&mut kernel).unwrap(), Some(0x100_0080));
+        match probe_kernel(&mut kernel, &space, false, BOOT_VERSION, None).unwrap() {
+            KernelFormat::Pvh(entry, range) => {
+                assert_eq!(entry, 0x100_0080);
+                assert_eq!(range, (VMLINUX_STARTUP, VMLINUX_STARTUP + 0x40));
+            }
+            format => panic!("Unexpected kernel format {:?}", format),
+        }
+
+        set_elf_phoff(&mut kernel, u64::max_value() - 8);
+        match find_pvh_entry(&mut kernel) {
+            Err(Error(ErrorKind::ElfProgramHeader(0), _)) => {}
+            _ => panic!("Program header offset overflow should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_load_pvh_kernel() {
+        let space = test_space(0x1000_0000);
+        let config = pvh_config(0x1_0000);
+
+        let boot_loader = load_pvh_kernel(&config, &space, 0x100_0080, None).unwrap();
+        assert_eq!(boot_loader.kernel_start, 0x100_0080);
+        assert_eq!(boot_loader.pvh_start_info, Some(PVH_INFO_START));
+        assert_eq!(boot_loader.segments.code_segment.db, 1);
+        assert_eq!(boot_loader.segments.code_segment.l, 0);
+
+        let start_info = space
+            .read_object::<HvmStartInfo>(GuestAddress(PVH_INFO_START))
+            .unwrap();
+        assert_eq!(start_info.magic, XEN_HVM_START_MAGIC_VALUE);
+        assert_eq!(start_info.cmdline_paddr, CMDLINE_START);
+        assert_eq!(start_info.nr_modules, 1);
+        let module = space
+            .read_object::<HvmModlistEntry>(GuestAddress(start_info.modlist_paddr))
+            .unwrap();
+        assert_eq!(module.paddr, boot_loader.initrd_start);
+        assert_eq!(module.size, 0x1_0000);
+
+        // Memory map is the same as e820 table in zero page.
+        let (_, initrd_addr) = setup_boot_params(&config, &space, None).unwrap();
+        assert_eq!(initrd_addr, boot_loader.initrd_start);
+        let boot_params = space
+            .read_object::<BootParams>(GuestAddress(ZERO_PAGE_START))
+            .unwrap();
+        let e820 = e820_table(&config, &space).unwrap();
+        assert_eq!(start_info.memmap_entries as usize, e820.len());
+        assert_eq!(boot_params.e820_entries() as usize, e820.len());
+        for (index, (addr, size, type_)) in e820.iter().enumerate() {
+            let entry = space
+                .read_object::<HvmMemmapTableEntry>(GuestAddress(
+                    start_info.memmap_paddr + index as u64 * 24,
+                ))
+                .unwrap();
+            assert_eq!(
+                (entry.addr, entry.size, entry.type_),
+                (*addr, *size, *type_)
+            );
+        }
+        assert_eq!(e820[1], (EBDA_START, 0x400, E820_RESERVED));
+        assert_eq!(e820[4], (VMLINUX_RAM_START, 0x0ff0_0000, E820_RAM));
+        assert_eq!(start_info.rsdp_paddr, ACPI_TABLES_START);
+    }
+
+    fn firmware_file(name: &str, size: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "stratovirt_firmware_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let blob: Vec<u8> = (0..size).map(|i| (i >> 12) as u8).collect();
+        std::fs::write(&path, &blob).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_firmware() {
+        let root = Region::init_container_region(u64::max_value());
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, -1, 0, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let path = firmware_file("ok", 0x3_0000);
+        load_firmware(&path, &space).unwrap();
+        // Blob ends at 1 MiB.
+        assert_eq!(space.read_object::<u8>(GuestAddress(0xd_0000)).unwrap(), 0);
+        assert_eq!(
+            space.read_object::<u8>(GuestAddress(0xf_ffff)).unwrap(),
+            0x2f
+        );
+        // Top 128 KiB ends at 4 GiB.
+        assert_eq!(
+            space.read_object::<u8>(GuestAddress(0xfffe_0000)).unwrap(),
+            0x10
+        );
+        assert_eq!(
+            space.read_object::<u8>(GuestAddress(RESET_VECTOR)).unwrap(),
+            0x2f
+        );
+        assert!(!space.address_in_memory(GuestAddress(0xfffd_f000), 0x1000));
+        assert_eq!(
+            firmware_bootloader(&pvh_config(0)).unwrap().kernel_start,
+            RESET_VECTOR
+        );
+        // Config is checked without kernel too.
+        let mut config = pvh_config(0);
+        config.cpu_count = 0;
+        match firmware_bootloader(&config) {
+            Err(Error(ErrorKind::InvalidCpuCount(0), _)) => {}
+            _ => panic!("Invalid config should be rejected"),
+        }
+
+        let mut config = pvh_config(0);
+        config.firmware = Some(path.clone());
+        assert_eq!(firmware_size(&config).unwrap(), 0x3_0000);
+        let e820 = e820_table(&config, &space).unwrap();
+        assert_eq!(e820[2], (0xd_0000, 0x3_0000, E820_RESERVED));
+        std::fs::remove_file(&path).unwrap();
+
+        for (name, size) in &[("unaligned", 0x1_8000), ("large", 0x8_0000), ("empty", 0)] {
+            let path = firmware_file(name, *size);
+            match load_firmware(&path, &space) {
+                Err(Error(ErrorKind::InvalidFirmwareSize(s), _)) => assert_eq!(s, *size as u64),
+                _ => panic!("Firmware of size 0x{:x} should be rejected", size),
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_probe_raw_kernel() {
+        let space = test_space(0x1000_0000);
+        let path = std::env::temp_dir().join(format!("stratovirt_raw_{}", std::process::id()));
+        std::fs::write(&path, vec![0x90_u8; 0x1000]).unwrap();
+        let mut kernel = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        match probe_kernel(&mut kernel, &space, false, BOOT_VERSION, None).unwrap() {
+            KernelFormat::Raw(size) => assert_eq!(size, 0x1000),
+            format => panic!("Unexpected kernel format {:?}", format),
+        }
+        assert_eq!(kernel.seek(SeekFrom::Current(0)).unwrap(), 0);
+    }
+    /// Write a bzImage file of `len` bytes with `setup_sects` and `syssize`
+    /// in its header.
+    fn bzimage_file(name: &str, setup_sects: u8, syssize: u32, len: usize) -> PathBuf {
+        let mut boot_hdr = RealModeKernelHeader::new(0, 0, 0, 0);
+        boot_hdr.setup_sects = setup_sects;
+        boot_hdr.version = 0x020f;
+        boot_hdr.loadflags = 1;
+        boot_hdr.syssize = syssize;
+        write_bzimage(name, &boot_hdr, len)
+    }
+
+    fn write_bzimage(name: &str, boot_hdr: &RealModeKernelHeader, len: usize) -> PathBuf {
+        let mut image = vec![0_u8; len];
+        let hdr = boot_hdr.as_bytes();
+        let start = BOOT_HDR_START as usize;
+        image[start..start + hdr.len()].copy_from_slice(hdr);
+
+        let path = std::env::temp_dir().join(format!(
+            "stratovirt_bzimage_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, image).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_bzimage() {
+        let path = bzimage_file("valid", 4, 0x100, 0x1a00);
+        let info = load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION).unwrap();
+        assert_eq!((info.setup_size, info.kernel_size), (0xa00, 0x1000));
+        assert_eq!({ info.header.version }, 0x020f);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = bzimage_file("truncated", 4, 0x100, 0x1200);
+        match load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION) {
+            Err(Error(ErrorKind::TruncatedBzImage(len, expected), _)) => {
+                assert_eq!((len, expected), (0x1200, 0x1a00))
+            }
+            _ => panic!("Truncated bzImage should be rejected"),
+        }
+        // Broken bzImage isn't taken as other formats.
+        let space = test_space(0x1000_0000);
+        assert!(probe_kernel(
+            &mut File::open(&path).unwrap(),
+            &space,
+            false,
+            BOOT_VERSION,
+            None
+        )
+        .is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        // No protected-mode kernel after setup code.
+        let path = bzimage_file("setup_only", 4, 0, 0xa00);
+        assert!(load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let path = bzimage_file("setup_sects", 200, 0, 0x2_0000);
+        match load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION) {
+            Err(Error(ErrorKind::InvalidSetupSects(sects), _)) => assert_eq!(sects, 200),
+            _ => panic!("bzImage with 200 setup sectors should be rejected"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Error of loading bzImage with `boot_hdr`, and its text.
+    fn bzimage_error(boot_hdr: &RealModeKernelHeader, min_boot_protocol: u16) -> (Error, String) {
+        let path = write_bzimage("header", boot_hdr, 0x1a00);
+        let result = load_bzimage(&mut File::open(&path).unwrap(), min_boot_protocol);
+        std::fs::remove_file(&path).unwrap();
+        let err = result.unwrap_err();
+        let text = err.to_string();
+        (err, text)
+    }
+
+    #[test]
+    fn test_bzimage_header_errors() {
+        let mut boot_hdr = RealModeKernelHeader::new(0, 0, 0, 0);
+        boot_hdr.setup_sects = 4;
+        boot_hdr.version = 0x0206;
+        boot_hdr.loadflags = LOADED_HIGH;
+        boot_hdr.syssize = 0x100;
+
+        boot_hdr.header = 0x1234_5678;
+        match bzimage_error(&boot_hdr, BOOT_VERSION) {
+            (Error(ErrorKind::BadHeaderMagic(found), _), text) => {
+                assert_eq!(found, 0x1234_5678);
+                assert!(text.contains("0x12345678"));
+            }
+            (e, _) => panic!("Unexpected error {}", e),
+        }
+        // Image without magic isn't bzImage.
+        let path = write_bzimage("no_magic", &boot_hdr, 0x1a00);
+        let space = test_space(0x1000_0000);
+        match probe_kernel(
+            &mut File::open(&path).unwrap(),
+            &space,
+            false,
+            BOOT_VERSION,
+            None,
+        ) {
+            Ok(KernelFormat::Raw(size)) => assert_eq!(size, 0x1a00),
+            _ => panic!("Image without bzImage magic should be raw kernel"),
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        boot_hdr.header = HDRS;
+        match bzimage_error(&boot_hdr, 0x020a) {
+            (Error(ErrorKind::OldBootProtocol(found, required), _), text) => {
+                assert_eq!((found, required), (0x0206, 0x020a));
+                assert!(text.contains("0x0206") && text.contains("0x020a"));
+            }
+            (e, _) => panic!("Unexpected error {}", e),
+        }
+        // Minimum below 2.00 is raised to it.
+        boot_hdr.version = 0x0104;
+        match bzimage_error(&boot_hdr, 0) {
+            (Error(ErrorKind::OldBootProtocol(found, required), _), text) => {
+                assert_eq!((found, required), (0x0104, BOOT_VERSION));
+                assert!(text.contains("0x0104") && text.contains("0x0200"));
+            }
+            (e, _) => panic!("Unexpected error {}", e),
+        }
+        // Unsupported bzImage isn't taken as other formats.
+        let path = write_bzimage("old_protocol", &boot_hdr, 0x1a00);
+        assert!(probe_kernel(
+            &mut File::open(&path).unwrap(),
+            &space,
+            false,
+            BOOT_VERSION,
+            None
+        )
+        .is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        boot_hdr.version = 0x020a;
+        boot_hdr.loadflags = 0;
+        match bzimage_error(&boot_hdr, 0x020a) {
+            (Error(ErrorKind::NotLoadedHigh, _), text) => assert!(text.contains("LOADED_HIGH")),
+            (e, _) => panic!("Unexpected error {}", e),
+        }
+        boot_hdr.loadflags = LOADED_HIGH;
+        let path = write_bzimage("min_protocol", &boot_hdr, 0x1a00);
+        assert!(load_bzimage(&mut File::open(&path).unwrap(), 0x020a).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bzimage_efi_handover() {
+        let mut boot_hdr = RealModeKernelHeader::new(0, 0, 0, 0);
+        boot_hdr.setup_sects = 4;
+        boot_hdr.version = 0x020f;
+        boot_hdr.loadflags = 1;
+        boot_hdr.syssize = 0x100;
+        boot_hdr.code32_start = 0x10_0000;
+        boot_hdr.xloadflags = XLF_CAN_BE_LOADED_ABOVE_4G | XLF_EFI_HANDOVER_64;
+        boot_hdr.handover_offset = 0x190;
+        let path = write_bzimage("handover", &boot_hdr, 0x1a00);
+        let info = load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION).unwrap();
+        assert_eq!(info.handover_entry, Some(0x10_0390));
+        std::fs::remove_file(&path).unwrap();
+
+        // Boot protocol before 2.11 has no handover_offset.
+        boot_hdr.version = 0x020a;
+        let path = write_bzimage("handover_old", &boot_hdr, 0x1a00);
+        let info = load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION).unwrap();
+        assert_eq!(info.handover_entry, None);
+        std::fs::remove_file(&path).unwrap();
+
+        boot_hdr.version = 0x020f;
+        boot_hdr.xloadflags = XLF_CAN_BE_LOADED_ABOVE_4G;
+        let path = write_bzimage("handover_none", &boot_hdr, 0x1a00);
+        let info = load_bzimage(&mut File::open(&path).unwrap(), BOOT_VERSION).unwrap();
+        assert_eq!(info.handover_entry, None);
+        std::fs::remove_file(&path).unwrap();
+
+        // Entry follows kernel moved by placement.
+        boot_hdr.xloadflags = XLF_EFI_HANDOVER_64;
+        let space = test_space(0x1000_0000);
+        let mut kernel = KernelFormat::BzImage(boot_hdr, 0x1000);
+        let boot_loader = linux_bootloader(&pvh_config(0), &space, &mut kernel, None).unwrap();
+        assert_eq!(
+            boot_loader.efi_handover_entry,
+            Some(boot_loader.vmlinux_start + 0x390)
+        );
+        let boot_loader =
+            linux_bootloader(&pvh_config(0), &space, &mut KernelFormat::Raw(0x1000), None).unwrap();
+        assert_eq!(boot_loader.efi_handover_entry, None);
+    }
+
+    #[test]
+    fn test_boot_timeline_marks() {
+        let space = test_space(0x1000_0000);
+        let steps = |timeline: &BootTimeline| -> Vec<&str> {
+            timeline.marks().iter().map(|(name, _)| *name).collect()
+        };
+
+        let mut timeline = BootTimeline::start();
+        let mut kernel = KernelFormat::Raw(0x1000);
+        linux_bootloader(&pvh_config(0), &space, &mut kernel, Some(&mut timeline)).unwrap();
+        assert_eq!(
+            steps(&timeline),
+            vec![
+                "layout check",
+                "page tables",
+                "platform tables",
+                "zero page",
+                "gdt"
+            ]
+        );
+
+        let mut timeline = BootTimeline::start();
+        let range = (VMLINUX_STARTUP, VMLINUX_STARTUP + 0x1000);
+        let mut kernel = KernelFormat::Pvh(VMLINUX_STARTUP, range);
+        linux_bootloader(&pvh_config(0), &space, &mut kernel, Some(&mut timeline)).unwrap();
+        assert_eq!(
+            steps(&timeline),
+            vec!["layout check", "platform tables", "start info", "gdt"]
+        );
+    }
+
+    #[test]
+    fn test_load_file_mmap() {
+        let len = 0x40_0123_usize;
+        let mut seed = 0x1234_5678_u32;
+        let data: Vec<u8> = (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("stratovirt_mmap_{}", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let mut image = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Start from an offset not aligned to page, like bzImage kernel.
+        let mut loaded = Vec::new();
+        for mmap in &[true, false] {
+            let space = test_space(0x1000_0000);
+            image.seek(SeekFrom::Start(0x123)).unwrap();
+            load_file(&mut image, &space, 0x100_0000, *mmap, None).unwrap();
+            let mut buf = Vec::new();
+            space
+                .read(&mut buf, GuestAddress(0x100_0000), len as u64 - 0x123)
+                .unwrap();
+            loaded.push(buf);
+        }
+        assert!(loaded[0] == loaded[1]);
+        assert!(loaded[0][..] == data[0x123..]);
+
+        // Destination out of Ram falls back to read, which fails.
+        let space = test_space(0x1000_0000);
+        image.seek(SeekFrom::Start(0)).unwrap();
+        match load_file(&mut image, &space, 0x0ff0_0000, true, None) {
+            Err(Error(ErrorKind::LoadImage(offset, addr), _)) => {
+                assert_eq!((offset, addr), (0x10_0000, 0x1000_0000))
+            }
+            _ => panic!("Image beyond guest memory should fail"),
+        }
+    }
+
+    fn bzimage_header(relocatable: bool) -> RealModeKernelHeader {
+        let mut boot_hdr = RealModeKernelHeader::new(0, 0, 0, 0);
+        boot_hdr.version = 0x020f;
+        boot_hdr.code32_start = VMLINUX_RAM_START as u32;
+        boot_hdr.relocatable_kernel = relocatable as u8;
+        boot_hdr.kernel_alignment = 0x20_0000;
+        boot_hdr.pref_address = 0x100_0000;
+        boot_hdr
+    }
+
+    #[test]
+    fn test_place_bzimage() {
+        let mut config = pvh_config(0x10_0000);
+        let mem_end = 0x1000_0000;
+
+        // Relocatable kernel goes to pref_address.
+        let mut boot_hdr = bzimage_header(true);
+        place_bzimage(&config, mem_end, &mut boot_hdr, 0x40_0000, 0).unwrap();
+        assert_eq!({ boot_hdr.code32_start }, 0x100_0000);
+
+        // Initrd at pref_address pushes kernel to the next aligned address.
+        let mut boot_hdr = bzimage_header(true);
+        place_bzimage(&config, mem_end, &mut boot_hdr, 0x40_0000, 0x110_0000).unwrap();
+        assert_eq!({ boot_hdr.code32_start }, 0x120_0000);
+
+        // Kernel must fit below the 32-bit gap.
+        config.gap_range = (0x180_0000, 0x1000_0000 - 0x180_0000);
+        let mut boot_hdr = bzimage_header(true);
+        match place_bzimage(&config, mem_end, &mut boot_hdr, 0x80_0000, 0x110_0000) {
+            Err(Error(ErrorKind::KernelPlacement(start, size), _)) => {
+                assert_eq!((start, size), (0x100_0000, 0x80_0000))
+            }
+            _ => panic!("Kernel should not fit below the gap"),
+        }
+
+        // Non-relocatable kernel stays at code32_start.
+        let mut boot_hdr = bzimage_header(false);
+        place_bzimage(&config, mem_end, &mut boot_hdr, 0x40_0000, 0).unwrap();
+        assert_eq!({ boot_hdr.code32_start }, VMLINUX_RAM_START as u32);
+        assert!(place_bzimage(&config, 0x20_0000, &mut boot_hdr, 0x40_0000, 0).is_err());
+
+        // Header before protocol 2.05 isn't relocatable.
+        let mut boot_hdr = bzimage_header(true);
+        boot_hdr.version = 0x0204;
+        assert!(!boot_hdr.relocatable());
+        place_bzimage(&config, mem_end, &mut boot_hdr, 0x40_0000, 0).unwrap();
+        assert_eq!({ boot_hdr.code32_start }, VMLINUX_RAM_START as u32);
+
+        // Chosen address is passed to kernel in zero page.
+        let space = test_space(0x1000_0000);
+        let config = pvh_config(0);
+        let mut kernel = KernelFormat::BzImage(bzimage_header(true), 0x40_0000);
+        let boot_loader = linux_bootloader(&config, &space, &mut kernel, None).unwrap();
+        assert_eq!(boot_loader.vmlinux_start, 0x100_0000);
+        assert_eq!(boot_loader.kernel_start, 0x100_0000 + BZIMAGE_BOOT_OFFSET);
+        assert_eq!(
+            space
+                .read_object::<u32>(GuestAddress(ZERO_PAGE_START + BOOT_HDR_START + 0x23))
+                .unwrap(),
+            0x100_0000
+        );
+        match kernel {
+            KernelFormat::BzImage(boot_hdr, _) => assert_eq!({ boot_hdr.code32_start }, 0x100_0000),
+            format => panic!("Unexpected kernel format {:?}", format),
+        }
+    }
+
+    #[test]
+    fn test_load_kernel_image() {
+        let space = test_space(0x1000_0000);
+        let path = bzimage_file("load", 4, 0, 0xa00 + 0x2_0010);
+        let mut image = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        image.seek(SeekFrom::End(-1)).unwrap();
+        image.write_all(&[0x5a]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let info = load_bzimage(&mut image, BOOT_VERSION).unwrap();
+        let mut boot_hdr = info.header;
+        boot_hdr.code32_start = 0x100_0000;
+        load_kernel_image(&mut image, &space, &boot_hdr, false, None).unwrap();
+        assert_eq!(
+            space
+                .read_object::<u8>(GuestAddress(0x100_0000 + 0x2_000f))
+                .unwrap(),
+            0x5a
+        );
+
+        // Failure reports the offset in file.
+        match load_initrd(&mut image, &space, 0x0fff_0000, false, None) {
+            Err(Error(ErrorKind::LoadImage(offset, addr), _)) => {
+                assert_eq!((offset, addr), (0x1_0000, 0x1000_0000))
+            }
+            _ => panic!("Initrd beyond guest memory should fail"),
+        }
+    }
+
+    #[test]
+    fn test_load_with_digest() {
+        const ABC_DIGEST: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let space = test_space(0x1000_0000);
+        let path = std::env::temp_dir().join(format!("stratovirt_digest_{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let mut image = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for mmap in &[true, false] {
+            let mut hasher = Some(Sha256::new());
+            load_initrd(&mut image, &space, 0x100_0000, *mmap, hasher.as_mut()).unwrap();
+            assert_eq!(read_signature(&space, 0x100_0000, 3), b"abc");
+            assert!(check_digest("initrd", Some(ABC_DIGEST), hasher).is_ok());
+
+            let mut hasher = Some(Sha256::new());
+            load_raw_kernel(&mut image, &space, 0x100_0000, *mmap, hasher.as_mut()).unwrap();
+            let expected = ABC_DIGEST.replace("ba78", "0000");
+            match check_digest("kernel", Some(&expected), hasher) {
+                Err(Error(ErrorKind::DigestMismatch(file, e, c), _)) => {
+                    assert_eq!(
+                        (file, e.as_str(), c.as_str()),
+                        ("kernel", expected.as_str(), ABC_DIGEST)
+                    )
+                }
+                _ => panic!("Mismatched digest should be rejected"),
+            }
+        }
+        // Digest in upper case is accepted, and nothing is checked without it.
+        let mut hasher = Sha256::new();
+        hash_file(&mut image, &mut hasher).unwrap();
+        assert!(check_digest("kernel", Some(&ABC_DIGEST.to_uppercase()), Some(hasher)).is_ok());
+        assert!(check_digest("kernel", None, Some(Sha256::new())).is_ok());
+
+        // bzImage digest covers real-mode setup code loaded apart.
+        let path = bzimage_file("digest", 4, 0, 0xa00 + 0x1000);
+        let mut image = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut whole = Sha256::new();
+        hash_file(&mut image, &mut whole).unwrap();
+        let expected = to_hex(&whole.finish());
+        let mut boot_hdr = load_bzimage(&mut image, BOOT_VERSION).unwrap().header;
+        boot_hdr.code32_start = 0x100_0000;
+        let mut hasher = Some(Sha256::new());
+        load_kernel_image(&mut image, &space, &boot_hdr, true, hasher.as_mut()).unwrap();
+        assert!(check_digest("kernel", Some(&expected), hasher).is_ok());
+
+        let mut config = pvh_config(0);
+        config.kernel_hash = Some(ABC_DIGEST.to_string());
+        assert!(config.check().is_ok());
+        config.initrd_hash = Some("abc".to_string());
+        match config.check() {
+            Err(Error(ErrorKind::InvalidDigest(field, _), _)) => assert_eq!(field, "initrd_hash"),
+            _ => panic!("Malformed digest should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_load_concatenated_initrds() {
+        let space = test_space(0x1000_0000);
+        let mut paths = Vec::new();
+        let mut images = Vec::new();
+        for (index, payload) in [&b"first"[..], &b"second-archive"[..]].iter().enumerate() {
+            let path = std::env::temp_dir().join(format!(
+                "stratovirt_initrd_{}_{}",
+                index,
+                std::process::id()
+            ));
+            std::fs::write(&path, payload).unwrap();
+            images.push(File::open(&path).unwrap());
+            paths.push(path);
+        }
+        // Second file starts at the 4-byte boundary after 5 bytes of the first.
+        assert_eq!(initrd_size(&paths).unwrap(), 8 + 14);
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        for mmap in &[true, false] {
+            let mut hasher = Some(Sha256::new());
+            load_initrds(&mut images, &space, 0x100_0000, 22, *mmap, hasher.as_mut()).unwrap();
+            assert_eq!(read_signature(&space, 0x100_0000, 5), b"first");
+            assert_eq!(read_signature(&space, 0x100_0008, 14), b"second-archive");
+            let mut expected = Sha256::new();
+            expected.update(b"firstsecond-archive");
+            assert_eq!(hasher.unwrap().finish(), expected.finish());
+        }
+        match load_initrds(&mut images, &space, 0x100_0000, 21, false, None) {
+            Err(Error(ErrorKind::InitrdOverflow(size, max), _)) => {
+                assert_eq!((size, max), (22, 21))
+            }
+            _ => panic!("Initrds larger than initrd_size should be rejected"),
+        }
+        assert!(load_initrds(&mut [], &space, 0x100_0000, 0, false, None).is_ok());
+    }
+
+    /// Expect `BootLayoutOverlap` error between `region` and `other`.
+    fn assert_overlap(result: Result<X86BootLoader>, region: &str, other: &str) {
+        match result {
+            Err(Error(ErrorKind::BootLayoutOverlap(r, _, o, _), _)) => {
+                assert_eq!((r, o), (region, other))
+            }
+            Err(e) => panic!("Unexpected error {}", e),
+            Ok(_) => panic!("{} should overlap {}", region, other),
+        }
+    }
+
+    #[test]
+    fn test_boot_layout_overlap() {
+        // 24M guest memory, raw kernel at 16M takes the top 8M.
+        let space = test_space(0x0180_0000);
+        let mut kernel = KernelFormat::Raw(0x80_0000);
+
+        let mut config = pvh_config(0x10_0000);
+        assert_overlap(
+            linux_bootloader(&config, &space, &mut kernel, None),
+            "initrd",
+            "kernel",
+        );
+        match linux_bootloader(&config, &space, &mut kernel, None) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "initrd [0x1700000,0x1800000) overlaps kernel [0x1000000,0x1800000)"
+            ),
+            Ok(_) => panic!("initrd should overlap kernel"),
+        }
+
+        // Initrd larger than guest memory is placed at 0.
+        config.initrd_size = 0x0200_0000;
+        assert_overlap(
+            linux_bootloader(&config, &space, &mut kernel, None),
+            "initrd",
+            "cmdline",
+        );
+
+        config.initrd_size = 0;
+        config.kernel_cmdline = "a".repeat((EBDA_START - CMDLINE_START) as usize).into();
+        assert_overlap(
+            linux_bootloader(&config, &space, &mut kernel, None),
+            "cmdline",
+            "mptable",
+        );
+
+        config.kernel_cmdline = KernelCmdline::from("console=ttyS0");
+        let mut low_kernel = KernelFormat::Elf(0x8000, (0x8000, 0x1_0000));
+        assert_overlap(
+            linux_bootloader(&config, &space, &mut low_kernel, None),
+            "kernel",
+            "page tables",
+        );
+        let mut low_kernel = KernelFormat::Pvh(0x6000, (0x6800, 0x6900));
+        assert_overlap(
+            linux_bootloader(&config, &space, &mut low_kernel, None),
+            "kernel",
+            "PVH start info",
+        );
+
+        let path = firmware_file("overlap", 0x4_0000);
+        config.firmware = Some(path.clone());
+        let mut bios_kernel = KernelFormat::Elf(0xf_0000, (0xf_0000, 0x10_0000));
+        assert_overlap(
+            linux_bootloader(&config, &space, &mut bios_kernel, None),
+            "kernel",
+            "firmware",
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        config.firmware = None;
+        assert!(linux_bootloader(&config, &space, &mut kernel, None).is_ok());
+    }
+
+    #[test]
+    fn test_boot_regs() {
+        let space = test_space(0x1000_0000);
+        let mut config = pvh_config(0);
+        config.boot_tss = true;
+        let loader =
+            linux_bootloader(&config, &space, &mut KernelFormat::Raw(0x1000), None).unwrap();
+        let (regs, sregs) = boot_regs(&loader);
+        assert_eq!(regs.rflags, 0x2);
+        assert_eq!(regs.rip, VMLINUX_STARTUP);
+        assert_eq!((regs.rsp, regs.rbp), (BOOT_LOADER_SP, BOOT_LOADER_SP));
+        assert_eq!(regs.rsi, ZERO_PAGE_START);
+        assert_eq!(regs.rbx, 0);
+
+        // Long mode: PE|PG, PAE, LME|LMA.
+        assert_eq!(sregs.cr0, 0x8000_0001);
+        assert_eq!(sregs.cr3, PML4_START);
+        assert_eq!(sregs.cr4, 0x20);
+        assert_eq!(sregs.efer, 0x500);
+        assert_eq!(sregs.cs.selector, u16::from(GDT_ENTRY_BOOT_CS) * 8);
+        assert_eq!((sregs.cs.l, sregs.cs.db), (1, 0));
+        for seg in &[sregs.ds, sregs.es, sregs.fs, sregs.gs, sregs.ss] {
+            assert_eq!(*seg, loader.segments.data_segment);
+            assert_eq!(seg.selector, u16::from(GDT_ENTRY_BOOT_DS) * 8);
+        }
+        assert_eq!(sregs.tr, loader.segments.tss_segment);
+        assert!(sregs.tr.is_some());
+        assert_eq!(sregs.gdt.base, BOOT_GDT_OFFSET);
+        assert_eq!(sregs.gdt.limit, loader.segments.gdt_limit);
+        assert_eq!(sregs.idt.base, BOOT_IDT_OFFSET);
+        assert_eq!(sregs.idt.limit, loader.segments.idt_limit);
+
+        // PVH: 32-bit protected mode without paging.
+        let loader = load_pvh_kernel(&config, &space, 0x100_0000, None).unwrap();
+        let (regs, sregs) = boot_regs(&loader);
+        assert_eq!(regs.rip, 0x100_0000);
+        assert_eq!(regs.rbx, PVH_INFO_START);
+        assert_eq!(sregs.cr0, 0x1);
+        assert_eq!((sregs.cr3, sregs.cr4, sregs.efer), (0, 0, 0));
+        assert_eq!((sregs.cs.l, sregs.cs.db), (0, 1));
+    }
+
+    #[test]
+    fn test_shifted_boot_layout() {
+        let space = test_space(0x0180_0000);
+        let kernel = std::env::temp_dir().join(format!("stratovirt_raw_{}", std::process::id()));
+        std::fs::write(&kernel, vec![0x5a_u8; 0x1000]).unwrap();
+        let initrd = std::env::temp_dir().join(format!("stratovirt_initrd_{}", std::process::id()));
+        std::fs::write(&initrd, b"initramfs").unwrap();
+        let mut config = pvh_config(0x1000);
+        config.kernel = Some(kernel.clone());
+        config.initrd = vec![initrd.clone()];
+        config.setup_data = vec![SetupData {
+            type_: SETUP_RNG_SEED,
+            data: vec![0xa5; 16],
+        }];
+        config.layout = BootLayout {
+            pvh_info: 0x6000,
+            zero_page: 0x3000,
+            stack_pointer: 0x2ff0,
+            pml4: 0x4000,
+            setup_data: 0x3_0000,
+            cmdline: 0x4_0000,
+            mptable: 0xf_0000,
+            gdt: 0x1000,
+            idt: 0x1100,
+        };
+
+        let boot_loader = crate::load_linux(&config, &space, None).unwrap();
+        std::fs::remove_file(&kernel).unwrap();
+        std::fs::remove_file(&initrd).unwrap();
+        assert_eq!(boot_loader.zero_page_addr, 0x3000);
+        assert_eq!(boot_loader.boot_pml4_addr, 0x4000);
+        assert_eq!(boot_loader.kernel_sp, 0x2ff0);
+        assert_eq!(boot_loader.segments.gdt_base, 0x1000);
+        assert_eq!(boot_loader.segments.idt_base, 0x1100);
+
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(0x4000)).unwrap(),
+            0x5003
+        );
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(0x5000)).unwrap(),
+            0x6003
+        );
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(0x6000)).unwrap(),
+            0x83
+        );
+        assert_ne!(
+            space
+                .read_object::<u64>(GuestAddress(0x1000 + 8 * GDT_ENTRY_BOOT_CS as u64))
+                .unwrap(),
+            0
+        );
+        // cmd_line_ptr and setup_data in zero page.
+        let hdr = 0x3000 + BOOT_HDR_START;
+        assert_eq!(
+            space.read_object::<u32>(GuestAddress(hdr + 0x37)).unwrap(),
+            0x4_0000
+        );
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(hdr + 0x5f)).unwrap(),
+            0x3_0000
+        );
+        assert_eq!(
+            space
+                .read_object::<u32>(GuestAddress(0x3_0000 + 8))
+                .unwrap(),
+            SETUP_RNG_SEED
+        );
+        assert_eq!(read_signature(&space, 0xf_0000, 4), b"_MP_".to_vec());
+        assert_eq!(
+            read_signature(&space, 0x4_0000, 14),
+            b"console=ttyS0\0".to_vec()
+        );
+        // Kernel and initrd are copied, initrd is recorded in zero page.
+        assert_eq!(read_signature(&space, VMLINUX_STARTUP, 2), vec![0x5a; 2]);
+        assert_eq!(
+            space.read_object::<u32>(GuestAddress(hdr + 0x27)).unwrap() as u64,
+            boot_loader.initrd_start
+        );
+        assert_eq!(
+            read_signature(&space, boot_loader.initrd_start, 9),
+            b"initramfs".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_setup_large_page_table() {
+        let space = test_space(0x10_0000);
+        let layout = BootLayout::default();
+
+        // 2MB pages for 4GB + 1 byte take 5 PD pages.
+        assert_eq!(
+            setup_page_table(&space, &layout, 0x1_0000_0001, false).unwrap(),
+            (0x9000, 7)
+        );
+        for gib in 0..5u64 {
+            assert_eq!(
+                space
+                    .read_object::<u64>(GuestAddress(0xa000 + gib * 8))
+                    .unwrap(),
+                (0xb000 + gib * 0x1000) | 0x03
+            );
+        }
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(0xf000)).unwrap(),
+            (4 << 30) | 0x83
+        );
+        assert_eq!(
+            space
+                .read_object::<u64>(GuestAddress(0xf000 + 511 * 8))
+                .unwrap(),
+            ((4 << 30) + (511 << 21)) | 0x83
+        );
+
+        // 1GB pages, mapping is capped to 512GB.
+        assert_eq!(
+            setup_page_table(&space, &layout, 0x100_0000_0000, true).unwrap(),
+            (0x9000, 2)
+        );
+        assert_eq!(
+            space.read_object::<u64>(GuestAddress(0xa000)).unwrap(),
+            0x83
+        );
+        assert_eq!(
+            space
+                .read_object::<u64>(GuestAddress(0xa000 + 511 * 8))
+                .unwrap(),
+            (511 << 30) | 0x83
+        );
+
+        // Page tables of 2MB pages for 32GB run into cmdline.
+        let mut config = pvh_config(0);
+        let kernel = (VMLINUX_STARTUP, VMLINUX_STARTUP + 0x1000);
+        match check_boot_layout(&config, kernel, 0, 0x8_0000_0000, false) {
+            Err(Error(ErrorKind::BootLayoutOverlap(r, _, o, _), _)) => {
+                assert_eq!((r, o), ("cmdline", "page tables"))
+            }
+            _ => panic!("Page tables should overlap cmdline"),
+        }
+        config.use_gib_pages = true;
+        assert!(check_boot_layout(&config, kernel, 0, 0x8_0000_0000, false).is_ok());
+    }
+
+    fn read_signature(space: &Arc<AddressSpace>, addr: u64, len: u64) -> Vec<u8> {
+        let mut signature = Vec::new();
+        space.read(&mut signature, GuestAddress(addr), len).unwrap();
+        signature
+    }
+
+    #[test]
+    fn test_config_check() {
+        let config = pvh_config(0);
+        config.check().unwrap();
+
+        let mut config = pvh_config(0);
+        config.cpu_count = 0;
+        match config.check() {
+            Err(Error(ErrorKind::InvalidCpuCount(cpus), _)) => assert_eq!(cpus, 0),
+            _ => panic!("0 cpu should be rejected"),
+        }
+
+        let mut config = pvh_config(0);
+        config.cpu_count = 2;
+        match config.check() {
+            Err(Error(ErrorKind::CpusAboveMaxCpus(cpus, max_cpus), _)) => {
+                assert_eq!((cpus, max_cpus), (2, 1))
+            }
+            _ => panic!("cpu_count above max_cpus should be rejected"),
+        }
+
+        // 255 cpus need ACPI tables.
+        let mut config = pvh_config(0);
+        config.max_cpus = 255;
+        config.check().unwrap();
+        config.mptable_only = true;
+        match config.check() {
+            Err(Error(ErrorKind::MaxCpus(cpus), _)) => assert_eq!(cpus, 255),
+            _ => panic!("255 cpus should be rejected with MP table only"),
+        }
+
+        let mut config = pvh_config(0);
+        config.kernel = Some(PathBuf::new());
+        match config.check() {
+            Err(Error(ErrorKind::EmptyBootPath(field), _)) => assert_eq!(field, "kernel"),
+            _ => panic!("Empty kernel path should be rejected"),
+        }
+
+        let mut config = pvh_config(0);
+        config.initrd = vec![PathBuf::from("/path/to/initrd")];
+        match config.check() {
+            Err(Error(ErrorKind::EmptyInitrd(path), _)) => assert_eq!(path, "/path/to/initrd"),
+            _ => panic!("Initrd of size 0 should be rejected"),
+        }
+        config.initrd.push(PathBuf::new());
+        config.initrd_size = 0x1000;
+        match config.check() {
+            Err(Error(ErrorKind::EmptyBootPath(field), _)) => assert_eq!(field, "initrd"),
+            _ => panic!("Empty path of second initrd should be rejected"),
+        }
+
+        let mut config = pvh_config(0);
+        config.gap_range = (0xC000_0000, 0);
+        match config.check() {
+            Err(Error(ErrorKind::InvalidGapRange(start, size), _)) => {
+                assert_eq!((start, size), (0xC000_0000, 0))
+            }
+            _ => panic!("Empty gap should be rejected"),
+        }
+        config.gap_range = (0xC000_0000, 0x8000_0000);
+        assert!(config.check().is_err());
+
+        let mut config = pvh_config(0);
+        config.lapic_addr = 0x8000_0000;
+        match config.check() {
+            Err(Error(ErrorKind::ApicAddrInRam(field, addr), _)) => {
+                assert_eq!((field, addr), ("lapic_addr", 0x8000_0000))
+            }
+            _ => panic!("Local APIC in RAM should be rejected"),
+        }
+        config.lapic_addr = 0xFEE0_0000;
+        config.ioapic_addr = 0x1000;
+        let space = test_space(0x1000_0000);
+        match linux_bootloader(&config, &space, &mut KernelFormat::Raw(0x1000), None) {
+            Err(Error(ErrorKind::ApicAddrInRam(field, _), _)) => assert_eq!(field, "ioapic_addr"),
+            _ => panic!("IOAPIC in RAM should be rejected before boot"),
+        }
+    }
+
+    #[test]
+    fn test_platform_tables() {
+        let space = test_space(0x1000_0000);
+        let mut kernel = KernelFormat::Raw(0x10_0000);
+
+        // ACPI tables and MP table are both provided by default.
+        let mut config = pvh_config(0);
+        linux_bootloader(&config, &space, &mut kernel, None).unwrap();
+        assert_eq!(read_signature(&space, ACPI_TABLES_START, 8), b"RSD PTR ");
+        assert_eq!(read_signature(&space, EBDA_START, 4), b"_MP_");
+        let (acpi_start, acpi_size) = acpi_range(&config).unwrap().unwrap();
+        assert_eq!(acpi_start, ACPI_TABLES_START);
+        let e820 = e820_table(&config, &space).unwrap();
+        assert!(e820.contains(&(ACPI_TABLES_START, acpi_size, E820_RESERVED)));
+
+        // ACPI tables in RAM split the e820 entry.
+        config.acpi_addr = Some(0x20_0000);
+        let e820 = e820_table(&config, &space).unwrap();
+        assert_eq!(e820[3], (VMLINUX_RAM_START, 0x10_0000, E820_RAM));
+        assert_eq!(e820[4], (0x20_0000, acpi_size, E820_RESERVED));
+        assert_eq!(
+            e820[5],
+            (0x20_0000 + acpi_size, 0x0fe0_0000 - acpi_size, E820_RAM)
+        );
+
+        config.max_cpus = 0;
+        match linux_bootloader(&config, &space, &mut kernel, None) {
+            Err(Error(ErrorKind::CpusAboveMaxCpus(cpus, max_cpus), _)) => {
+                assert_eq!((cpus, max_cpus), (1, 0))
+            }
+            _ => panic!("Cpu number above max cpu number should be rejected"),
+        }
+        config.max_cpus = 1;
+
+        config.acpi_addr = Some(0x20_0008);
+        match linux_bootloader(&config, &space, &mut kernel, None) {
+            Err(Error(ErrorKind::InvalidAcpiAddr(addr), _)) => assert_eq!(addr, 0x20_0008),
+            _ => panic!("Unaligned ACPI tables should be rejected"),
+        }
+
+        // MP table alone.
+        let space = test_space(0x1000_0000);
+        config.acpi_addr = None;
+        config.mptable_only = true;
+        linux_bootloader(&config, &space, &mut kernel, None).unwrap();
+        assert_eq!(read_signature(&space, ACPI_TABLES_START, 8), vec![0; 8]);
+        assert_eq!(read_signature(&space, EBDA_START, 4), b"_MP_");
+        assert!(acpi_range(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_x86_bootloader_and_kernel_cmdline() {
+        let root = Region::init_container_region(0x2000_0000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, -1, 0, false, false).unwrap(),
+        );
+        let region_a = Region::init_ram_region(ram1.clone());
+        root.add_subregion(region_a, ram1.start_address().raw_value())
+            .unwrap();
+        assert_eq!(
+            setup_page_table(&space, &BootLayout::default(), 0x4000_0000, false).unwrap(),
+            (0x0000_9000, 3)
+        );
         assert_eq!(
             space.read_object::<u64>(GuestAddress(0x0000_9000)).unwrap(),
             0x0000_a003
@@ -530,20 +3425,145 @@ mod test {
         }
 
         let config = X86BootLoaderConfig {
-            kernel: PathBuf::new(),
-            initrd: Some(PathBuf::new()),
+            kernel: None,
+            initrd: vec![PathBuf::new()],
             initrd_size: 0x1_0000,
-            kernel_cmdline: String::from("this_is_a_piece_of_test_string"),
+            kernel_cmdline: KernelCmdline::from("this_is_a_piece_of_test_string"),
             cpu_count: 2,
+            max_cpus: 4,
             gap_range: (0xC000_0000, 0x4000_0000),
             ioapic_addr: 0xFEC0_0000,
             lapic_addr: 0xFEE0_0000,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: Vec::new(),
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            use_gib_pages: false,
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
         };
         let (_, initrd_addr_tmp) = setup_boot_params(&config, &space, None).unwrap();
         assert_eq!(initrd_addr_tmp, 0xfff_0000);
 
+        //test setup_isa_mptable function
+        setup_isa_mptable(
+            &space,
+            EBDA_START,
+            config.cpu_count,
+            config.max_cpus,
+            config.ioapic_addr,
+            config.lapic_addr,
+            &config.irq_overrides,
+        )
+        .unwrap();
+        let header = EBDA_START + std::mem::size_of::<FloatingPointer>() as u64;
+        let length = space.read_object::<u16>(GuestAddress(header + 4)).unwrap();
+        let entry_count = space.read_object::<u16>(GuestAddress(header + 34)).unwrap();
+        let mut table = Vec::new();
+        space
+            .read(&mut table, GuestAddress(header), u64::from(length))
+            .unwrap();
+        assert_eq!(util::checksum::checksum(&table), 0);
+        let mut offset = std::mem::size_of::<ConfigTableHeader>();
+        let mut entries = 0;
+        let mut irq_routes = Vec::new();
+        let mut cpu_flags = Vec::new();
+        while offset < table.len() {
+            match table[offset] {
+                0 => {
+                    cpu_flags.push(table[offset + 3]);
+                    offset += std::mem::size_of::<ProcessEntry>();
+                }
+                type_ => {
+                    if type_ == 2 {
+                        // IOAPIC id is above all cpus, including hotpluggable ones.
+                        assert_eq!(table[offset + 1], 5);
+                    }
+                    if type_ == 3 {
+                        irq_routes.push((table[offset + 5], table[offset + 7]));
+                    }
+                    offset += std::mem::size_of::<IOInterruptEntry>();
+                }
+            }
+            entries += 1;
+        }
+        assert_eq!(entry_count, entries);
+        // 4 cpus, bus, ioapic, 15 irqs and 2 local interrupts.
+        assert_eq!(entry_count, 23);
+        // Cpu 0 is enabled bsp, cpus 2 and 3 are hotpluggable.
+        assert_eq!(cpu_flags, vec![3, 1, 0, 0]);
+        assert_eq!(irq_routes[0], (0, 2));
+        assert_eq!(irq_routes[1], (1, 1));
+        assert_eq!(irq_routes[2], (3, 3));
+        assert_eq!(irq_routes.len(), 15);
+        assert!(irq_routes.iter().all(|(_, pin)| *pin != 0));
+
+        // Dumps decode what's written above.
+        let dump = debug::dump_zero_page(&space, ZERO_PAGE_START);
+        assert!(dump.contains("cmdline: ptr 0x20000"));
+        assert!(dump.contains("ramdisk: image 0xfff0000, size 0x10000"));
+        assert!(dump.contains("e820 entries: 5"));
+        assert!(dump.contains("[0x0000000000000000-0x000000000009fc00) ram"));
+        assert!(dump.contains("[0x000000000009fc00-0x00000000000a0000) reserved"));
+        assert!(dump.contains("[0x0000000000100000-0x0000000010000000) ram"));
+        let dump = debug::dump_mptable(&space, EBDA_START);
+        assert!(dump.contains("floating pointer: config table 0x9fc10, spec 1.4, checksum ok"));
+        assert!(dump.contains("23 entries, lapic 0xfee00000, checksum ok"));
+        assert!(dump.contains("processor: 4"));
+        assert!(dump.contains("io interrupt: 15"));
+        assert!(dump.contains("local interrupt: 2"));
+        assert!(debug::dump_mptable(&space, ZERO_PAGE_START).contains("no MP floating pointer"));
+        assert!(debug::dump_zero_page(&space, 0x2000_0000).contains("failed to read zero page"));
+
+        // MP table in BIOS ROM, for firmware owning EBDA.
+        setup_isa_mptable(
+            &space,
+            MB_BIOS_BEGIN,
+            config.cpu_count,
+            config.max_cpus,
+            config.ioapic_addr,
+            config.lapic_addr,
+            &config.irq_overrides,
+        )
+        .unwrap();
+        let fp = read_signature(
+            &space,
+            MB_BIOS_BEGIN,
+            std::mem::size_of::<FloatingPointer>() as u64,
+        );
+        assert_eq!(util::checksum::checksum(&fp), 0);
+        let header = MB_BIOS_BEGIN + fp.len() as u64;
+        assert_eq!(
+            space
+                .read_object::<u32>(GuestAddress(MB_BIOS_BEGIN + 4))
+                .unwrap(),
+            header as u32
+        );
+        let length = space.read_object::<u16>(GuestAddress(header + 4)).unwrap();
+        let table = read_signature(&space, header, u64::from(length));
+        assert_eq!(util::checksum::checksum(&table), 0);
+        let dump = debug::dump_mptable(&space, MB_BIOS_BEGIN);
+        assert!(dump.contains("config table 0xf0010, spec 1.4, checksum ok"));
+        assert!(dump.contains("23 entries, lapic 0xfee00000, checksum ok"));
+
+        match isa_irq_routes(&[(16, 2)]) {
+            Err(Error(ErrorKind::InvalidIrqOverride(irq, pin), _)) => {
+                assert_eq!((irq, pin), (16, 2))
+            }
+            _ => panic!("Override of irq 16 should be rejected"),
+        }
+        assert!(isa_irq_routes(&[(0, IOAPIC_NUM_PINS)]).is_err());
+        assert_eq!(isa_irq_routes(&[]).unwrap().len(), 16);
+
         //test setup_gdt function
-        let c_seg = kvm_segment {
+        let c_seg = SegmentRegister {
             base: 0,
             limit: 1048575,
             selector: 16,
@@ -556,9 +3576,8 @@ mod test {
             g: 1,
             avl: 0,
             unusable: 0,
-            padding: 0,
         };
-        let d_seg = kvm_segment {
+        let d_seg = SegmentRegister {
             base: 0,
             limit: 1048575,
             selector: 24,
@@ -571,15 +3590,24 @@ mod test {
             g: 1,
             avl: 0,
             unusable: 0,
-            padding: 0,
         };
 
-        let boot_gdt_seg = setup_gdt(&space).unwrap();
+        let boot_gdt_seg = setup_gdt(&space, &BootLayout::default(), true).unwrap();
 
         assert_eq!(boot_gdt_seg.code_segment, c_seg);
         assert_eq!(boot_gdt_seg.data_segment, d_seg);
-        assert_eq!(boot_gdt_seg.gdt_limit, 31);
+        assert_eq!(boot_gdt_seg.gdt_limit, 47);
         assert_eq!(boot_gdt_seg.idt_limit, 7);
+        assert_eq!(boot_gdt_seg.tr, 32);
+        let tss_seg = boot_gdt_seg.tss_segment.unwrap();
+        assert_eq!((tss_seg.base, tss_seg.limit), (0x538, 0x67));
+        assert_eq!((tss_seg.type_, tss_seg.s, tss_seg.present), (11, 0, 1));
+        assert_eq!(
+            space
+                .read_object::<u16>(GuestAddress(0x538 + 0x66))
+                .unwrap(),
+            0x68
+        );
         let mut arr: Vec<u64> = Vec::new();
         let mut boot_addr: u64 = 0x500;
         for _ in 0..BOOT_GDT_MAX {
@@ -590,20 +3618,106 @@ mod test {
         assert_eq!(arr[1], 0);
         assert_eq!(arr[2], 0xaf9b000000ffff);
         assert_eq!(arr[3], 0xcf93000000ffff);
+        assert_eq!(arr[4], 0x8b00_0538_0067);
+        assert_eq!(arr[5], 0);
+
+        let boot_gdt_seg = setup_gdt(&space, &BootLayout::default(), false).unwrap();
+        assert_eq!(boot_gdt_seg.gdt_limit, 31);
+        assert_eq!((boot_gdt_seg.tss_segment, boot_gdt_seg.tr), (None, 0));
 
         //test setup_kernel_cmdline function
         let cmd_len: u64 = config.kernel_cmdline.len() as u64;
-        let mut read_buffer: [u8; 30] = [0; 30];
+        let mut read_buffer: [u8; 31] = [0xff; 31];
         //let mut read_buffer:Vec<u8> = Vec::with_capacity();
-        assert!(setup_kernel_cmdline(&config, &space).is_ok());
+        assert!(setup_kernel_cmdline(&config, &space, &KernelFormat::Raw(0)).is_ok());
         space
             .read(
                 &mut read_buffer.as_mut(),
                 GuestAddress(0x0002_0000),
-                cmd_len,
+                cmd_len + 1,
             )
             .unwrap();
-        let s = String::from_utf8(read_buffer.to_vec()).unwrap();
+        assert_eq!(read_buffer[30], 0);
+        let s = String::from_utf8(read_buffer[..30].to_vec()).unwrap();
         assert_eq!(s, "this_is_a_piece_of_test_string".to_string());
     }
+
+    #[test]
+    fn test_boot_write_errors() {
+        // RAM ends right after PML4, below PDPTE.
+        let space = test_space(PML4_START + 0x1000);
+        let layout = BootLayout::default();
+        match setup_page_table(&space, &layout, 0x4000_0000, false) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                format!("Failed to load PDPTE to 0x{:x}", layout.pdpte())
+            ),
+            Ok(_) => panic!("Page table beyond RAM should be rejected"),
+        }
+
+        // RAM ends in the middle of the first mptable entry.
+        let space = test_space(0x9_0000);
+        let config = pvh_config(0);
+        let err = setup_isa_mptable(
+            &space,
+            0x9_0000 - 0x40,
+            config.cpu_count,
+            config.max_cpus,
+            config.ioapic_addr,
+            config.lapic_addr,
+            &config.irq_overrides,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to load mptable entry to 0x8fffc");
+
+        let space = test_space(CMDLINE_START);
+        assert!(setup_kernel_cmdline(&config, &space, &KernelFormat::Raw(0)).is_err());
+    }
+
+    #[test]
+    fn test_kernel_cmdline_overflow() {
+        let space = test_space(0x1000_0000);
+        let mut config = pvh_config(0);
+        let raw = KernelFormat::Raw(0);
+
+        config.kernel_cmdline = "a".repeat(CMDLINE_MAX_SIZE as usize).into();
+        setup_kernel_cmdline(&config, &space, &raw).unwrap();
+        assert_eq!(
+            space
+                .read_object::<u8>(GuestAddress(CMDLINE_START + u64::from(CMDLINE_MAX_SIZE)))
+                .unwrap(),
+            0
+        );
+        config.kernel_cmdline = "a".repeat(CMDLINE_MAX_SIZE as usize + 1).into();
+        match setup_kernel_cmdline(&config, &space, &raw) {
+            Err(Error(ErrorKind::CmdlineOverflow(len, max), _)) => {
+                assert_eq!((len, max), (2049, 2048))
+            }
+            _ => panic!("Cmdline of 2049 bytes should overflow"),
+        }
+
+        // bzImage header limits cmdline length.
+        let mut boot_hdr = RealModeKernelHeader::new(0, 64, 0, 0);
+        boot_hdr.version = 0x020f;
+        let bzimage = KernelFormat::BzImage(boot_hdr, 0);
+        config.kernel_cmdline = "a".repeat(64).into();
+        setup_kernel_cmdline(&config, &space, &bzimage).unwrap();
+        config.kernel_cmdline = "a".repeat(65).into();
+        match setup_kernel_cmdline(&config, &space, &bzimage) {
+            Err(Error(ErrorKind::CmdlineOverflow(len, max), _)) => assert_eq!((len, max), (65, 64)),
+            _ => panic!("Cmdline of 65 bytes should overflow"),
+        }
+
+        // Header before boot protocol 2.06 has no cmdline_size.
+        boot_hdr.version = 0x0205;
+        let legacy = KernelFormat::BzImage(boot_hdr, 0);
+        config.kernel_cmdline = "a".repeat(255).into();
+        setup_kernel_cmdline(&config, &space, &legacy).unwrap();
+        config.kernel_cmdline = "a".repeat(256).into();
+        assert!(setup_kernel_cmdline(&config, &space, &legacy).is_err());
+
+        // Cmdline with NUL never reaches guest memory.
+        config.kernel_cmdline = KernelCmdline::from("console=ttyS0 a\0b");
+        assert!(setup_kernel_cmdline(&config, &space, &raw).is_err());
+    }
 }
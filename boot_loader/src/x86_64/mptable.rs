@@ -81,7 +81,7 @@ pub struct ConfigTableHeader {
 impl ByteCode for ConfigTableHeader {}
 
 impl ConfigTableHeader {
-    pub fn new(length: u16, sum: u8, lapic_addr: u32) -> Self {
+    pub fn new(length: u16, entry_count: u16, sum: u8, lapic_addr: u32) -> Self {
         let mut ct = ConfigTableHeader {
             signature: [b'P', b'C', b'M', b'P'],
             length,
@@ -93,7 +93,7 @@ impl ConfigTableHeader {
             ],
             oem_table_pointer: 0,
             oem_table_size: 0,
-            entry_count: 0,
+            entry_count,
             lapic_addr,
             ext_table_length: 0,
             ext_table_checksum: 0,
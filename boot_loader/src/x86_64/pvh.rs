@@ -0,0 +1,75 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use util::byte_code::ByteCode;
+
+/// Magic value of `hvm_start_info`, "xEn3" with the 0x80 bit of "E" set.
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+/// Version of `hvm_start_info` which has memory map fields.
+pub const XEN_HVM_START_INFO_VERSION: u32 = 1;
+/// Type of ELF note holding 32-bit physical entry point.
+pub const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+/// Name of Xen ELF notes, including the terminating nul.
+pub const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+
+// Structures below sourced from:
+// https://xenbits.xen.org/docs/unstable/hypercall/x86_64/include,public,arch-x86,hvm,start_info.h.html
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct HvmStartInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+impl ByteCode for HvmStartInfo {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct HvmModlistEntry {
+    pub paddr: u64,
+    pub size: u64,
+    pub cmdline_paddr: u64,
+    pub reserved: u64,
+}
+
+impl ByteCode for HvmModlistEntry {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct HvmMemmapTableEntry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+impl ByteCode for HvmMemmapTableEntry {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pvh_struct_size() {
+        assert_eq!(std::mem::size_of::<HvmStartInfo>(), 56);
+        assert_eq!(std::mem::size_of::<HvmModlistEntry>(), 32);
+        assert_eq!(std::mem::size_of::<HvmMemmapTableEntry>(), 24);
+    }
+}
@@ -230,6 +230,15 @@ impl CPUAArch64 {
         self.mpidr
     }
 
+    /// Initialize vcpu again on VM reset, so that its state is the same as
+    /// after `realize`, i.e. non-boot cpus are powered off.
+    pub fn reinit_vcpu(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+        if let Err(e) = vcpu_fd.vcpu_init(&self.kvi) {
+            bail!("Failed to init vcpu{} again: {}", self.vcpu_id, e);
+        }
+        Ok(())
+    }
+
     pub fn reset_vcpu(&self, vcpu: &Arc<VcpuFd>) -> Result<()> {
         // Configure PSTATE(Processor State), mask all interrupts.
         let data: u64 = PSR_D_BIT | PSR_A_BIT | PSR_I_BIT | PSR_F_BIT | PSR_MODE_EL1h;
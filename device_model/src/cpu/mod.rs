@@ -34,7 +34,7 @@ mod x86_64;
 use std::cell::RefCell;
 use std::sync::{Arc, Barrier, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use libc::{c_int, c_void, siginfo_t};
@@ -87,6 +87,10 @@ pub mod errors {
                 description("Destroy vcpu error!")
                 display("Failed to destroy kvm vcpu: {}!", err_info)
             }
+            ParkVcpuTimeout(vcpus: String) {
+                description("Park vcpu timeout!")
+                display("Timeout waiting for vcpus to park, still running: {}!", vcpus)
+            }
         }
     }
 }
@@ -108,6 +112,9 @@ const VCPU_TASK_SIGNAL: i32 = 37;
 
 const UNINITIALIZED_VCPU_ID: u32 = 9999;
 
+/// Default time to wait for all vcpu threads to park after a sync pause.
+pub const VCPU_PARK_TIMEOUT: Duration = Duration::from_millis(1000);
+
 /// State for `CPU` lifecycle.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CpuLifecycleState {
@@ -218,6 +225,8 @@ pub struct CPU {
     tid: Arc<Mutex<Option<u64>>>,
     /// The VM combined by this VCPU.
     vm: Arc<Box<Arc<dyn MachineInterface + Send + Sync>>>,
+    /// Barrier which records whether this VCPU is parked outside `KVM_RUN`.
+    park_barrier: Arc<CpuParkBarrier>,
 }
 
 impl CPU {
@@ -229,11 +238,13 @@ impl CPU {
     /// * `id` - ID of this `CPU`.
     /// * `arch_cpu` - Architecture special `CPU` property.
     /// * `vm` - The virtual machine this `CPU` gets attached to.
+    /// * `park_barrier` - The barrier shared by all `CPU`s of `vm`.
     pub fn new(
         vcpu_fd: Arc<VcpuFd>,
         id: u8,
         arch_cpu: Arc<Mutex<ArchCPU>>,
         vm: Arc<Box<Arc<dyn MachineInterface + Send + Sync>>>,
+        park_barrier: Arc<CpuParkBarrier>,
     ) -> Result<Self> {
         Ok(CPU {
             id,
@@ -244,6 +255,7 @@ impl CPU {
             task: Arc::new(Mutex::new(None)),
             tid: Arc::new(Mutex::new(None)),
             vm,
+            park_barrier,
         })
     }
 
@@ -264,6 +276,14 @@ impl CPU {
         &self.arch_cpu
     }
 
+    /// Reset this `CPU` to its boot state on VM reset, it must be parked
+    /// outside `KVM_RUN`.
+    pub fn reset_to_boot(&self) -> Result<()> {
+        #[cfg(target_arch = "aarch64")]
+        self.arch_cpu.lock().unwrap().reinit_vcpu(&self.fd)?;
+        self.reset()
+    }
+
     /// Set task the `CPU` to handle.
     pub fn set_task(&self, task: Option<thread::JoinHandle<()>>) {
         let mut data = self.task.lock().unwrap();
@@ -392,6 +412,7 @@ impl CPUInterface for CPU {
                 // of the CPU state as Stopped.
                 let (cpu_state, cvar) = &*cpu.state;
                 *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
+                cpu.park_barrier.park(cpu.id);
                 cvar.notify_one();
             })
             .unwrap();
@@ -483,7 +504,9 @@ impl CPUInterface for CPU {
     }
 
     fn kvm_vcpu_exec(&self) -> Result<bool> {
-        match self.fd.run() {
+        let exit = self.fd.run();
+        self.vm.flush_coalesced_mmio();
+        match exit {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
@@ -595,13 +618,18 @@ impl CPUWorker for CPU {
                         info!("Vcpu{} paused", self.id);
                         flag = 1;
                     }
+                    self.park_barrier.park(self.id);
                     cpu_state = cvar.wait(cpu_state).unwrap();
                 }
                 CpuLifecycleState::Running => {
+                    // Unpark with `cpu_state` locked, so that a concurrent
+                    // pause either sees this vcpu running or finds it parked.
+                    self.park_barrier.unpark(self.id);
                     return true;
                 }
                 CpuLifecycleState::Stopping => {
                     info!("Vcpu{} shutdown", self.id);
+                    self.park_barrier.park(self.id);
                     cvar.notify_all();
                     return false;
                 }
@@ -614,6 +642,138 @@ impl CPUWorker for CPU {
     }
 }
 
+/// Barrier that records which vcpu threads are parked outside `KVM_RUN`.
+///
+/// A vcpu thread parks itself when it finds its `CPU` paused or stopping,
+/// and unparks right before entering `KVM_RUN` again. Callers that need all
+/// vcpus quiesced wait on it with a timeout.
+pub struct CpuParkBarrier {
+    /// Parked flag of each vcpu, indexed by vcpu id.
+    parked: Mutex<Vec<bool>>,
+    /// Notified every time a vcpu parks.
+    cvar: Condvar,
+}
+
+impl CpuParkBarrier {
+    /// Create a barrier for `nr_vcpus` vcpus, all of which are parked until
+    /// their threads start running.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr_vcpus` - Number of vcpus in VM.
+    pub fn new(nr_vcpus: u8) -> Self {
+        CpuParkBarrier {
+            parked: Mutex::new(vec![true; nr_vcpus as usize]),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Confirm that vcpu `vcpu_id` is parked.
+    pub fn park(&self, vcpu_id: u8) {
+        let mut parked = self.parked.lock().unwrap();
+        parked[vcpu_id as usize] = true;
+        self.cvar.notify_all();
+    }
+
+    /// Mark vcpu `vcpu_id` as going to run guest code.
+    pub fn unpark(&self, vcpu_id: u8) {
+        self.parked.lock().unwrap()[vcpu_id as usize] = false;
+    }
+
+    /// Wait until all vcpus are parked.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Max time to wait for.
+    ///
+    /// # Errors
+    ///
+    /// `ParkVcpuTimeout` with ids of vcpus still running when `timeout` expires.
+    pub fn wait_all_parked(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut parked = self.parked.lock().unwrap();
+        loop {
+            let running: Vec<usize> = parked
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| !**p)
+                .map(|(id, _)| id)
+                .collect();
+            if running.is_empty() {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ErrorKind::ParkVcpuTimeout(format!("{:?}", running)).into());
+            }
+            parked = self.cvar.wait_timeout(parked, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// Guard of vcpus paused by `CpusPauseGuard::pause_sync`.
+///
+/// All vcpus stay outside `KVM_RUN` as long as the guard lives, vcpus paused
+/// by it are resumed on `resume` or drop.
+pub struct CpusPauseGuard {
+    /// Vcpus which were running and paused by this guard.
+    paused: Vec<Arc<CPU>>,
+}
+
+impl CpusPauseGuard {
+    /// Pause all running vcpus and wait until every vcpu thread is parked.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpus` - All vcpus of VM.
+    /// * `park_barrier` - The barrier shared by `cpus`.
+    /// * `timeout` - Max time to wait for vcpus to park.
+    pub fn pause_sync(
+        cpus: &[Arc<CPU>],
+        park_barrier: &CpuParkBarrier,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut guard = CpusPauseGuard { paused: Vec::new() };
+        for cpu in cpus.iter() {
+            if *cpu.state.0.lock().unwrap() != CpuLifecycleState::Running {
+                continue;
+            }
+            // Make sure `KVM_RUN` returns even if the signal arrives
+            // before the vcpu thread enters it.
+            cpu.fd.set_kvm_immediate_exit(1);
+            cpu.pause()?;
+            guard.paused.push(cpu.clone());
+        }
+
+        // Vcpus paused so far are resumed when `guard` drops.
+        park_barrier.wait_all_parked(timeout)?;
+        Ok(guard)
+    }
+
+    /// Resume vcpus paused by this guard.
+    pub fn resume(mut self) -> Result<()> {
+        self.resume_paused()
+    }
+
+    fn resume_paused(&mut self) -> Result<()> {
+        for cpu in self.paused.drain(..) {
+            cpu.resume()?;
+        }
+        Ok(())
+    }
+}
+
+impl address_space::VcpusPaused for CpusPauseGuard {}
+
+impl Drop for CpusPauseGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.resume_paused() {
+            error!("Failed to resume vcpus paused synchronously: {}", e);
+        }
+    }
+}
+
 /// The wrapper for topology for VCPU.
 #[derive(Clone)]
 pub struct CpuTopology {
@@ -662,3 +822,81 @@ impl CpuTopology {
         (socketid, coreid, threadid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    // Fake vcpu thread, which parks while `pause` is set and spins otherwise.
+    fn spawn_fake_vcpu(
+        id: u8,
+        barrier: Arc<CpuParkBarrier>,
+        pause: Arc<AtomicBool>,
+        exit: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()> {
+        barrier.unpark(id);
+        thread::spawn(move || {
+            while !exit.load(Ordering::SeqCst) {
+                if pause.load(Ordering::SeqCst) {
+                    barrier.park(id);
+                    while pause.load(Ordering::SeqCst) && !exit.load(Ordering::SeqCst) {
+                        thread::yield_now();
+                    }
+                    barrier.unpark(id);
+                }
+                thread::yield_now();
+            }
+        })
+    }
+
+    #[test]
+    fn test_park_barrier_all_parked() {
+        let nr_vcpus = 4;
+        let barrier = Arc::new(CpuParkBarrier::new(nr_vcpus));
+        let pause = Arc::new(AtomicBool::new(false));
+        let exit = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..nr_vcpus)
+            .map(|id| spawn_fake_vcpu(id, barrier.clone(), pause.clone(), exit.clone()))
+            .collect();
+
+        // No vcpu parks while running.
+        assert!(barrier.wait_all_parked(Duration::from_millis(20)).is_err());
+
+        pause.store(true, Ordering::SeqCst);
+        assert!(barrier.wait_all_parked(Duration::from_secs(5)).is_ok());
+        assert!(barrier.parked.lock().unwrap().iter().all(|p| *p));
+
+        exit.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_park_barrier_timeout() {
+        let barrier = Arc::new(CpuParkBarrier::new(3));
+        let pause = Arc::new(AtomicBool::new(false));
+        let exit = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..2)
+            .map(|id| spawn_fake_vcpu(id, barrier.clone(), pause.clone(), exit.clone()))
+            .collect();
+        // Vcpu 2 runs but never parks.
+        barrier.unpark(2);
+
+        pause.store(true, Ordering::SeqCst);
+        let err = barrier
+            .wait_all_parked(Duration::from_millis(50))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::ParkVcpuTimeout(vcpus) => assert_eq!(vcpus, "[2]"),
+            _ => panic!("Unexpected error: {}", err),
+        }
+
+        exit.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
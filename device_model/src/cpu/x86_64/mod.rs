@@ -14,9 +14,8 @@ mod cpuid;
 
 use std::sync::Arc;
 
-use kvm_bindings::{
-    kvm_fpu, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs, Msrs, KVM_MAX_CPUID_ENTRIES,
-};
+use hypervisor::{CpuRegisterState, CpuSpecialRegisterState};
+use kvm_bindings::{kvm_fpu, kvm_msr_entry, kvm_regs, kvm_sregs, Msrs, KVM_MAX_CPUID_ENTRIES};
 use kvm_ioctls::{Kvm, VcpuFd, VmFd};
 
 use self::errors::Result;
@@ -51,38 +50,21 @@ const MSR_LIST: &[u32] = &[
 const MSR_IA32_MISC_ENABLE: u32 = 0x01a0;
 const MSR_IA32_MISC_ENABLE_FAST_STRING: u64 = 0x1;
 
-/// X86 CPU booting configure information
+/// X86 CPU booting configure information, usually from `boot_regs` of boot
+/// loader.
 pub struct X86CPUBootConfig {
-    /// Register %rip value
-    pub boot_ip: u64,
-    /// Register %rsp value
-    pub boot_sp: u64,
-    /// zero page address, as the second parameter of __startup_64
-    /// arch/x86/kernel/head_64.S:86
-    pub zero_page: u64,
-    pub code_segment: kvm_segment,
-    pub data_segment: kvm_segment,
-    pub gdt_base: u64,
-    pub gdt_size: u16,
-    pub idt_base: u64,
-    pub idt_size: u16,
-    pub pml4_start: u64,
+    /// General purpose registers, %rip is the kernel entry.
+    pub regs: CpuRegisterState,
+    /// Segment, descriptor table and control registers.
+    pub sregs: CpuSpecialRegisterState,
 }
 
 #[derive(Default, Copy, Clone)]
 pub struct X86CPU {
     id: u32,
     nr_vcpus: u32,
-    boot_ip: u64,
-    boot_sp: u64,
-    zero_page: u64,
-    code_segment: kvm_segment,
-    data_segment: kvm_segment,
-    gdt_base: u64,
-    gdt_size: u16,
-    idt_base: u64,
-    idt_size: u16,
-    pml4_start: u64,
+    boot_regs: CpuRegisterState,
+    boot_sregs: CpuSpecialRegisterState,
 }
 
 impl X86CPU {
@@ -95,16 +77,8 @@ impl X86CPU {
     }
 
     pub fn realize(&mut self, vcpu_fd: &Arc<VcpuFd>, boot_config: &X86CPUBootConfig) -> Result<()> {
-        self.boot_ip = boot_config.boot_ip;
-        self.boot_sp = boot_config.boot_sp;
-        self.zero_page = boot_config.zero_page;
-        self.code_segment = boot_config.code_segment;
-        self.data_segment = boot_config.data_segment;
-        self.gdt_base = boot_config.gdt_base;
-        self.gdt_size = boot_config.gdt_size;
-        self.idt_base = boot_config.idt_base;
-        self.idt_size = boot_config.idt_size;
-        self.pml4_start = boot_config.pml4_start;
+        self.boot_regs = boot_config.regs;
+        self.boot_sregs = boot_config.sregs;
 
         // Only setting vcpu lapic state, other registers should
         // reset when the vcpu start running.
@@ -208,47 +182,8 @@ impl X86CPU {
     }
 
     fn setup_sregs(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
-        // X86_CR0_PE: Protection Enable
-        // EFER_LME: Long mode enable
-        // EFER_LMA: Long mode active
-        // arch/x86/include/uapi/asm/processor-flags.h
-        const X86_CR0_PE: u64 = 0x1;
-        const EFER_LME: u64 = 0x100;
-        const EFER_LMA: u64 = 0x400;
-
-        // X86_CR0_PG: enable Paging
-        // X86_CR4_PAE: enable physical address extensions
-        // arch/x86/include/uapi/asm/processor-flags.h
-        const X86_CR0_PG: u64 = 0x8000_0000;
-        const X86_CR4_PAE: u64 = 0x20;
-
         let mut sregs: kvm_sregs = vcpu_fd.get_sregs()?;
-
-        // Init gdt table, gdt table has loaded to Guest Memory Space
-        sregs.cs = self.code_segment;
-        sregs.ds = self.data_segment;
-        sregs.es = self.data_segment;
-        sregs.fs = self.data_segment;
-        sregs.gs = self.data_segment;
-        sregs.ss = self.data_segment;
-
-        sregs.gdt.base = self.gdt_base;
-        sregs.gdt.limit = self.gdt_size;
-
-        // Init idt table, idt table has loaded to Guest Memory Space
-        sregs.idt.base = self.idt_base;
-        sregs.idt.limit = self.idt_size;
-
-        // Open 64-bit protected mode, include
-        // Protection enable, Long mode enable, Long mode active
-        sregs.cr0 |= X86_CR0_PE;
-        sregs.efer |= EFER_LME | EFER_LMA;
-
-        // Setup page table
-        sregs.cr3 = self.pml4_start;
-        sregs.cr4 |= X86_CR4_PAE;
-        sregs.cr0 |= X86_CR0_PG;
-
+        self.boot_sregs.load_into(&mut sregs);
         vcpu_fd.set_sregs(&sregs)?;
 
         Ok(())
@@ -314,15 +249,7 @@ impl X86CPU {
     }
 
     fn setup_regs(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
-        let regs: kvm_regs = kvm_regs {
-            rflags: 0x0002, /* Means processor has been initialized */
-            rip: self.boot_ip,
-            rsp: self.boot_sp,
-            rbp: self.boot_sp,
-            rsi: self.zero_page,
-            ..Default::default()
-        };
-        vcpu_fd.set_regs(&regs)?;
+        vcpu_fd.set_regs(&kvm_regs::from(self.boot_regs))?;
 
         Ok(())
     }
@@ -382,12 +309,14 @@ impl X86CPU {
 #[cfg(test)]
 mod test {
     use super::*;
-    use kvm_bindings::kvm_segment;
+    use hypervisor::{
+        DescriptorTable, SegmentRegister, EFER_LMA, EFER_LME, X86_CR0_PE, X86_CR0_PG, X86_CR4_PAE,
+    };
     use std::sync::Arc;
 
     #[test]
     fn test_x86_64_cpu() {
-        let code_seg = kvm_segment {
+        let code_seg = SegmentRegister {
             base: 0,
             limit: 1048575,
             selector: 16,
@@ -400,9 +329,8 @@ mod test {
             g: 1,
             avl: 0,
             unusable: 0,
-            padding: 0,
         };
-        let data_seg = kvm_segment {
+        let data_seg = SegmentRegister {
             base: 0,
             limit: 1048575,
             selector: 24,
@@ -415,19 +343,34 @@ mod test {
             g: 1,
             avl: 0,
             unusable: 0,
-            padding: 0,
         };
         let cpu_config = X86CPUBootConfig {
-            boot_ip: 0,
-            boot_sp: 0,
-            zero_page: 0x0000_7000,
-            code_segment: code_seg,
-            data_segment: data_seg,
-            gdt_base: 0x500u64,
-            gdt_size: 16,
-            idt_base: 0x520u64,
-            idt_size: 8,
-            pml4_start: 0x0000_9000,
+            regs: CpuRegisterState {
+                rflags: 0x0002,
+                rsi: 0x0000_7000,
+                ..Default::default()
+            },
+            sregs: CpuSpecialRegisterState {
+                cs: code_seg,
+                ds: data_seg,
+                es: data_seg,
+                fs: data_seg,
+                gs: data_seg,
+                ss: data_seg,
+                tr: None,
+                gdt: DescriptorTable {
+                    base: 0x500,
+                    limit: 16,
+                },
+                idt: DescriptorTable {
+                    base: 0x520,
+                    limit: 8,
+                },
+                cr0: X86_CR0_PE | X86_CR0_PG,
+                cr3: 0x0000_9000,
+                cr4: X86_CR4_PAE,
+                efer: EFER_LME | EFER_LMA,
+            },
         };
 
         let vm = if let Ok(vm_fd) = Kvm::new().and_then(|kvm| kvm.create_vm()) {
@@ -447,19 +390,19 @@ mod test {
         //test setup special registers
         assert!(x86_cpu.setup_sregs(&vcpu).is_ok());
         let x86_sregs = vcpu.get_sregs().unwrap();
-        assert_eq!(x86_sregs.cs, code_seg);
-        assert_eq!(x86_sregs.ds, data_seg);
-        assert_eq!(x86_sregs.es, data_seg);
-        assert_eq!(x86_sregs.fs, data_seg);
-        assert_eq!(x86_sregs.gs, data_seg);
-        assert_eq!(x86_sregs.ss, data_seg);
-        assert_eq!(x86_sregs.gdt.base, cpu_config.gdt_base);
-        assert_eq!(x86_sregs.gdt.limit, cpu_config.gdt_size);
-        assert_eq!(x86_sregs.idt.base, cpu_config.idt_base);
-        assert_eq!(x86_sregs.idt.limit, cpu_config.idt_size);
+        assert_eq!(SegmentRegister::from(x86_sregs.cs), code_seg);
+        assert_eq!(SegmentRegister::from(x86_sregs.ds), data_seg);
+        assert_eq!(SegmentRegister::from(x86_sregs.es), data_seg);
+        assert_eq!(SegmentRegister::from(x86_sregs.fs), data_seg);
+        assert_eq!(SegmentRegister::from(x86_sregs.gs), data_seg);
+        assert_eq!(SegmentRegister::from(x86_sregs.ss), data_seg);
+        assert_eq!(x86_sregs.gdt.base, 0x500);
+        assert_eq!(x86_sregs.gdt.limit, 16);
+        assert_eq!(x86_sregs.idt.base, 0x520);
+        assert_eq!(x86_sregs.idt.limit, 8);
         assert_eq!(x86_sregs.cr0 & 0x1, 1);
         assert_eq!((x86_sregs.cr0 & 0x8000_0000) >> 31, 1);
-        assert_eq!(x86_sregs.cr3, cpu_config.pml4_start);
+        assert_eq!(x86_sregs.cr3, 0x0000_9000);
         assert_eq!((x86_sregs.cr4 & 0x20) >> 5, 1);
         assert_eq!((x86_sregs.efer & 0x700) >> 8, 5);
 
@@ -10,11 +10,13 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use address_space::GuestAddress;
 use byteorder::{ByteOrder, LittleEndian};
+use hypervisor::VmOps;
 use kvm_ioctls::VmFd;
+use machine_manager::config::ClockPolicy;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::super::mmio::errors::{Result, ResultExt};
@@ -85,6 +87,32 @@ impl PL031 {
     fn get_current_value(&self) -> u32 {
         self.base_time.elapsed().as_secs() as u32 + self.tick_offset
     }
+
+    /// Adjust clock after VM resumes from pause.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - `Host` leaves the clock running through the pause, `Keep`
+    ///              hides the pause from guest, `Resync` catches up with host
+    ///              wall clock and raises an interrupt to guest.
+    /// * `paused` - How long VM has been paused.
+    pub fn sync_after_pause(&mut self, policy: ClockPolicy, paused: Duration) {
+        match policy {
+            ClockPolicy::Host => {}
+            ClockPolicy::Keep => {
+                self.base_time += paused;
+            }
+            ClockPolicy::Resync => {
+                self.tick_offset = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time wrong")
+                    .as_secs() as u32;
+                self.base_time = Instant::now();
+                self.risr = 1;
+                self.interrupt();
+            }
+        }
+    }
 }
 
 impl DeviceOps for PL031 {
@@ -160,8 +188,7 @@ impl MmioDeviceOps for PL031 {
     fn realize(&mut self, vm_fd: &VmFd, resource: DeviceResource) -> Result<()> {
         match EventFd::new(libc::EFD_NONBLOCK) {
             Ok(evt) => {
-                vm_fd
-                    .register_irqfd(&evt, resource.irq)
+                VmOps::register_irqfd(vm_fd, &evt, resource.irq)
                     .chain_err(|| "Failed to register irqfd")?;
                 self.interrupt_evt = Some(evt);
 
@@ -176,3 +203,68 @@ impl MmioDeviceOps for PL031 {
         DeviceType::RTC
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn wall_secs() -> u32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32
+    }
+
+    fn read_reg(rtc: &mut PL031, offset: u64) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(rtc.read(&mut data, GuestAddress(0), offset));
+        LittleEndian::read_u32(&data)
+    }
+
+    /// Simulate a VM paused for `paused` since `rtc` is set to `start`.
+    fn paused_rtc(start: u32, paused: Duration) -> PL031 {
+        let mut rtc = PL031::new();
+        rtc.tick_offset = start;
+        rtc.base_time = Instant::now().checked_sub(paused).unwrap();
+        rtc
+    }
+
+    #[test]
+    fn test_rtc_keep_after_pause() {
+        let start = wall_secs() - HOUR.as_secs() as u32;
+        let mut rtc = paused_rtc(start, HOUR);
+        assert!(read_reg(&mut rtc, RTC_DR) >= start + HOUR.as_secs() as u32);
+
+        rtc.sync_after_pause(ClockPolicy::Host, HOUR);
+        assert!(read_reg(&mut rtc, RTC_DR) >= start + HOUR.as_secs() as u32);
+
+        rtc.sync_after_pause(ClockPolicy::Keep, HOUR);
+        let value = read_reg(&mut rtc, RTC_DR);
+        assert!(value >= start && value <= start + 1);
+        assert_eq!(read_reg(&mut rtc, RTC_RIS), 0);
+    }
+
+    #[test]
+    fn test_rtc_resync_after_pause() {
+        let start = wall_secs() - HOUR.as_secs() as u32;
+        let mut rtc = paused_rtc(start, Duration::from_secs(0));
+        rtc.imsr = 1;
+        rtc.interrupt_evt = Some(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+
+        rtc.sync_after_pause(ClockPolicy::Resync, HOUR);
+        let now = wall_secs();
+        let value = read_reg(&mut rtc, RTC_DR);
+        assert!(value + 1 >= now && value <= now + 1);
+        assert_eq!(read_reg(&mut rtc, RTC_RIS), 1);
+        assert_eq!(read_reg(&mut rtc, RTC_MIS), 1);
+        assert_eq!(rtc.interrupt_evt.as_ref().unwrap().read().unwrap(), 1);
+
+        // Guest clears the interrupt.
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, 1);
+        assert!(rtc.write(&data, GuestAddress(0), RTC_ICR));
+        assert_eq!(read_reg(&mut rtc, RTC_RIS), 0);
+    }
+}
@@ -16,6 +16,7 @@ use std::os::unix::io::RawFd;
 use std::sync::{Arc, Mutex};
 
 use address_space::GuestAddress;
+use hypervisor::VmOps;
 use kvm_ioctls::VmFd;
 use util::epoll_context::{EventNotifier, EventNotifierHelper, NotifierOperation};
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, terminal::Terminal};
@@ -345,8 +346,7 @@ impl MmioDeviceOps for Serial {
 
         match EventFd::new(libc::EFD_NONBLOCK) {
             Ok(evt) => {
-                vm_fd
-                    .register_irqfd(&evt, resource.irq)
+                VmOps::register_irqfd(vm_fd, &evt, resource.irq)
                     .chain_err(|| "Failed to register irqfd")?;
                 self.interrupt_evt = Some(evt);
 
@@ -76,6 +76,7 @@ pub mod errors {
             AddressSpace(address_space::errors::Error, address_space::errors::ErrorKind);
             Util(util::errors::Error, util::errors::ErrorKind);
             BootLoader(boot_loader::errors::Error, boot_loader::errors::ErrorKind);
+            Hypervisor(hypervisor::errors::Error, hypervisor::errors::ErrorKind);
             Manager(machine_manager::errors::Error, machine_manager::errors::ErrorKind);
             Cpu(crate::cpu::errors::Error, crate::cpu::errors::ErrorKind);
             Mmio(crate::mmio::errors::Error, crate::mmio::errors::ErrorKind);
@@ -0,0 +1,105 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Guest clock bookkeeping across pause and resume.
+
+use std::time::{Duration, Instant};
+
+use machine_manager::config::ClockPolicy;
+
+/// Guest clock state saved when VM is paused.
+#[derive(Default)]
+pub struct PausedClock {
+    /// Host time when VM is paused.
+    paused_at: Option<Instant>,
+    /// Guest kvmclock value when VM is paused.
+    kvmclock_ns: Option<u64>,
+}
+
+impl PausedClock {
+    /// Record the pause moment and guest kvmclock value, if any.
+    pub fn pause(&mut self, kvmclock_ns: Option<u64>) {
+        self.paused_at = Some(Instant::now());
+        self.kvmclock_ns = kvmclock_ns;
+    }
+
+    /// Take the recorded state, return how long VM has been paused and the
+    /// kvmclock value saved at pause. `None` if VM is not paused.
+    pub fn resume(&mut self) -> Option<(Duration, Option<u64>)> {
+        let paused_at = self.paused_at.take()?;
+        Some((paused_at.elapsed(), self.kvmclock_ns.take()))
+    }
+}
+
+/// Compute kvmclock value to set when VM resumes, `None` if kvmclock is left
+/// alone.
+///
+/// # Arguments
+///
+/// * `policy` - Clock policy of VM.
+/// * `clock_ns` - kvmclock value saved at pause.
+/// * `paused` - How long VM has been paused.
+pub fn kvmclock_after_pause(policy: ClockPolicy, clock_ns: u64, paused: Duration) -> Option<u64> {
+    match policy {
+        ClockPolicy::Host => None,
+        ClockPolicy::Keep => Some(clock_ns),
+        ClockPolicy::Resync => Some(clock_ns.saturating_add(paused.as_nanos() as u64)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const HOUR_NS: u64 = 3_600_000_000_000;
+
+    #[test]
+    fn test_kvmclock_after_pause() {
+        let clock_ns = 42_000_000_000;
+        assert_eq!(
+            kvmclock_after_pause(ClockPolicy::Host, clock_ns, HOUR),
+            None
+        );
+        assert_eq!(
+            kvmclock_after_pause(ClockPolicy::Keep, clock_ns, HOUR),
+            Some(clock_ns)
+        );
+        assert_eq!(
+            kvmclock_after_pause(ClockPolicy::Resync, clock_ns, HOUR),
+            Some(clock_ns + HOUR_NS)
+        );
+        assert_eq!(
+            kvmclock_after_pause(ClockPolicy::Resync, u64::max_value(), HOUR),
+            Some(u64::max_value())
+        );
+    }
+
+    #[test]
+    fn test_paused_clock() {
+        let mut clock = PausedClock::default();
+        assert!(clock.resume().is_none());
+
+        clock.pause(Some(100));
+        // Simulate a 1-hour pause.
+        clock.paused_at = Instant::now().checked_sub(HOUR);
+        let (paused, kvmclock_ns) = clock.resume().unwrap();
+        assert!(paused >= HOUR && paused < HOUR + Duration::from_secs(1));
+        assert_eq!(kvmclock_ns, Some(100));
+        assert_eq!(
+            kvmclock_after_pause(ClockPolicy::Resync, kvmclock_ns.unwrap(), paused).unwrap()
+                / HOUR_NS,
+            1
+        );
+        assert!(clock.resume().is_none());
+    }
+}
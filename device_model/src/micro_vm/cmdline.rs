@@ -15,7 +15,7 @@ use std::io::Read;
 
 use error_chain::bail;
 use machine_manager::config::VmConfig;
-use machine_manager::socket::SocketType;
+use machine_manager::socket::{SocketType, DEFAULT_MAX_CONNECTIONS};
 use util::arg_parser::{Arg, ArgMatches, ArgParser};
 
 use crate::errors::{Result, ResultExt};
@@ -85,7 +85,9 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("machine")
                 .long("machine")
-                .value_name("[type=]name[,dump_guest_core=on|off][,mem-share=on|off]")
+                .value_name(
+                    "[type=]name[,dump_guest_core=on|off][,mem-share=on|off][,auto-cmdline=on|off][,clock=host|keep|resync][,cgroup=path|cgroup-parent=path][,cgroup-strict=on|off][,cgroup-iops=n][,cgroup-bps=n]",
+                )
                 .help("selects emulated machine")
                 .takes_value(true),
         )
@@ -99,10 +101,17 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("memory")
                 .long("m")
-                .value_name("[size=]megs")
+                .value_name("[size=]megs[,maxmem=size][,slots=n][,dimms=size[:size...]][,hugepage=on|off][,prealloc=on|off][,lock=on|off][,host-nodes=n[:n...]][,thp=default|always|never][,seal=on|off]")
                 .help("configure guest RAM")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("balloon")
+                .long("balloon")
+                .value_name("[deflate-on-oom=on|off][,free-page-hinting=on|off]")
+                .help("configure memory balloon")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("mem-path")
                 .long("mem-path")
@@ -110,6 +119,13 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .help("configure file path that backs guest memory.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("mem-fd")
+                .long("mem-fd")
+                .value_name("fd name passed by getfd or fd number")
+                .help("configure file descriptor that backs guest memory, preferred over mem-path.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("config-file")
                 .long("config")
@@ -142,7 +158,7 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("api-channel")
                 .long("api-channel")
-                .value_name("unix:PATH")
+                .value_name("unix:PATH[,max-connections=N]")
                 .help("set api-channel's unixsocket path")
                 .takes_value(true)
                 .required(true),
@@ -151,7 +167,9 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             Arg::with_name("drive")
                 .multiple(true)
                 .long("drive")
-                .value_name("[file=path][,id=str][,readonly=][,direct=]")
+                .value_name(
+                    "[file=path][,id=str][,readonly=][,direct=][,aio=][,format=][,discard=][,detect-zeroes=]",
+                )
                 .help("use 'file' as a drive image")
                 .takes_values(true),
         )
@@ -349,6 +367,8 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
     update_args_to_config!((args.value_of("machine")), vm_cfg, update_machine);
     update_args_to_config!((args.value_of("memory")), vm_cfg, update_memory);
     update_args_to_config!((args.value_of("mem-path")), vm_cfg, update_mem_path);
+    update_args_to_config!((args.value_of("mem-fd")), vm_cfg, update_mem_fd);
+    update_args_to_config!((args.value_of("balloon")), vm_cfg, update_balloon);
     update_args_to_config!((args.value_of("smp")), vm_cfg, update_cpu);
     update_args_to_config!((args.value_of("kernel")), vm_cfg, update_kernel);
     update_args_to_config!((args.value_of("initrd-file")), vm_cfg, update_initrd);
@@ -372,7 +392,8 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
     Ok(vm_cfg)
 }
 
-/// This function is to parse api-channel socket path and type.
+/// This function is to parse api-channel socket path, type and maximum number
+/// of clients connected at the same time.
 ///
 /// # Arguments
 ///
@@ -381,12 +402,14 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
 /// # Errors
 ///
 /// The value of `api-channel` is illegel.
-pub fn check_api_channel(args: &ArgMatches) -> Result<(String, SocketType)> {
+pub fn check_api_channel(args: &ArgMatches) -> Result<(String, SocketType, usize)> {
     if let Some(api) = args.value_of("api-channel") {
         let (api_path, api_type) = parse_path(&api)
             .map(|(path, type_)| (path, type_))
             .chain_err(|| "Failed to parse api-channel socket path")?;
-        Ok((api_path, api_type))
+        let max_connections = parse_max_connections(&api)
+            .chain_err(|| "Failed to parse api-channel max-connections")?;
+        Ok((api_path, api_type, max_connections))
     } else {
         bail!("Please use \'-api-channel\' to give a api-channel path for Unix socket");
     }
@@ -417,6 +440,30 @@ fn parse_path(args_str: &str) -> Result<(String, SocketType)> {
     }
 }
 
+/// This function is to parse `max-connections` in a `String`, default value is
+/// returned if it's not set.
+///
+/// # Arguments
+///
+/// * `args_str` - The arguments `String` would be parsed.
+///
+/// # Errors
+///
+/// The value of `max-connections` isn't a positive integer.
+fn parse_max_connections(args_str: &str) -> Result<usize> {
+    let mut max_connections = DEFAULT_MAX_CONNECTIONS;
+    for arg in args_str.split(',').skip(1) {
+        let item: Vec<&str> = arg.splitn(2, '=').collect();
+        if item[0] == "max-connections" && item.len() > 1 {
+            max_connections = match item[1].parse::<usize>() {
+                Ok(value) if value > 0 => value,
+                _ => bail!("max-connections should be a positive integer: {}", item[1]),
+            };
+        }
+    }
+    Ok(max_connections)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -441,4 +488,25 @@ mod tests {
         let test_path = "file:/tmp/stratovirt-file";
         assert!(parse_path(test_path).is_err());
     }
+
+    #[test]
+    fn test_parse_max_connections() {
+        let test_path = "unix:/tmp/stratovirt.sock";
+        assert_eq!(
+            parse_max_connections(test_path).unwrap(),
+            DEFAULT_MAX_CONNECTIONS
+        );
+
+        let test_path = "unix:/tmp/stratovirt.sock,max-connections=5";
+        assert_eq!(parse_max_connections(test_path).unwrap(), 5);
+
+        let test_path = "unix:/tmp/stratovirt.sock,nowait,max-connections=1,server";
+        assert_eq!(parse_max_connections(test_path).unwrap(), 1);
+
+        let test_path = "unix:/tmp/stratovirt.sock,max-connections=0";
+        assert!(parse_max_connections(test_path).is_err());
+
+        let test_path = "unix:/tmp/stratovirt.sock,max-connections=many";
+        assert!(parse_max_connections(test_path).is_err());
+    }
 }
@@ -46,15 +46,18 @@ const KVM_RUN: u32 = 0xae80;
 
 // See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/asm-generic/kvm.h
 const KVM_SET_DEVICE_ATTR: u32 = 0x4018_aee1;
+// Used by devices plugged into running VM.
+const KVM_IRQFD: u32 = 0x4020_ae76;
+const KVM_IOEVENTFD: u32 = 0x4040_ae79;
 
 /// Create a syscall allowlist for seccomp.
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 35 syscalls
-/// * x86_64-unknown-musl: 34 syscalls
-/// * aarch64-unknown-gnu: 34 syscalls
-/// * aarch64-unknown-musl: 33 syscalls
+/// * x86_64-unknown-gnu: 39 syscalls
+/// * x86_64-unknown-musl: 38 syscalls
+/// * aarch64-unknown-gnu: 38 syscalls
+/// * aarch64-unknown-musl: 37 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 fn syscall_allow_list() -> Vec<BpfRule> {
     vec![
@@ -109,6 +112,27 @@ fn syscall_allow_list() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_fstat),
         BpfRule::new(libc::SYS_pread64),
         BpfRule::new(libc::SYS_pwrite64),
+        // Resume block IO requests deferred by throttling.
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
+        // Free guest memory backed by shared file, discard and zero ranges of
+        // block images.
+        BpfRule::new(libc::SYS_fallocate)
+            .add_constraint(
+                SeccompCmpOpt::Eq,
+                1,
+                (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+            )
+            .add_constraint(
+                SeccompCmpOpt::Eq,
+                1,
+                (libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+            ),
+        // Remove cgroup created for VM when exiting.
+        #[cfg(target_arch = "x86_64")]
+        BpfRule::new(libc::SYS_rmdir),
+        #[cfg(target_arch = "aarch64")]
+        BpfRule::new(libc::SYS_unlinkat),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_madvise).add_constraint(
             SeccompCmpOpt::Eq,
@@ -128,15 +152,19 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, FIONBIO)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_RUN)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_DEVICE_ATTR)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_IRQFD)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_IOEVENTFD)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_GUEST_CID() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_RUNNING() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_CALL() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_NUM() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_ADDR() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_BASE() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_GET_VRING_BASE() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_KICK() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_CALL() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_OWNER() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_GET_FEATURES() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_FEATURES() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_MEM_TABLE() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_NET_SET_BACKEND() as u32)
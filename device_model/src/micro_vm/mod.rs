@@ -32,6 +32,7 @@ extern crate boot_loader;
 extern crate machine_manager;
 extern crate util;
 
+mod clock;
 pub mod cmdline;
 pub mod main_loop;
 pub mod micro_syscall;
@@ -49,20 +50,31 @@ use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
 
-#[cfg(target_arch = "x86_64")]
-use address_space::KvmIoListener;
 use address_space::{create_host_mmaps, AddressSpace, GuestAddress, KvmMemoryListener, Region};
-use boot_loader::{load_kernel, BootLoaderConfig};
+#[cfg(target_arch = "x86_64")]
+use address_space::{KvmIoListener, SpaceType};
+#[cfg(target_arch = "aarch64")]
+use boot_loader::write_dtb;
+#[cfg(target_arch = "x86_64")]
+use boot_loader::{
+    boot_regs, BootLayout, ScreenInfo, SetupData, BOOT_VERSION, ISA_TIMER_IRQ_OVERRIDE,
+};
+use boot_loader::{load_linux, BootLoaderConfig};
+use hypervisor::kvm::CoalescedMmioRing;
+use hypervisor::VmOps;
+use machine_manager::block_backend::BlockBackendRegistry;
 use machine_manager::config::{
-    BootSource, ConsoleConfig, DriveConfig, NetworkInterfaceConfig, SerialConfig, VmConfig,
-    VsockConfig,
+    split_net_fds, BalloonConfig, BootSource, ClockPolicy, ConfigCheck, ConsoleConfig,
+    DetectZeroes, DiscardMode, DriveConfig, ImageFormat, MachineMemConfig, NetworkInterfaceConfig,
+    SerialConfig, VmConfig, VsockConfig,
 };
 use machine_manager::machine::{
-    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
-    MachineInterface, MachineLifecycle,
+    BalloonHandle, DeviceAddArgs, DeviceInterface, KvmVmState, MachineAddressInterface,
+    MachineExternalInterface, MachineInterface, MachineLifecycle,
 };
 #[cfg(feature = "qmp")]
 use machine_manager::{qmp, qmp::qmp_schema as schema, qmp::QmpChannel};
+use util::boot_timeline::BootTimeline;
 #[cfg(target_arch = "aarch64")]
 use util::device_tree;
 #[cfg(target_arch = "aarch64")]
@@ -70,8 +82,13 @@ use util::device_tree::CompileFDT;
 use util::epoll_context::{
     EventNotifier, EventNotifierHelper, MainLoopManager, NotifierCallback, NotifierOperation,
 };
+use util::rollback::Rollback;
 
-use crate::cpu::{ArchCPU, CPUBootConfig, CPUInterface, CpuTopology, CPU};
+use self::clock::PausedClock;
+use crate::cpu::{
+    ArchCPU, CPUBootConfig, CPUInterface, CpuParkBarrier, CpuTopology, CpusPauseGuard, CPU,
+    VCPU_PARK_TIMEOUT,
+};
 use crate::errors::{Result, ResultExt};
 #[cfg(target_arch = "aarch64")]
 use crate::interrupt_controller::{InterruptController, InterruptControllerConfig};
@@ -83,7 +100,7 @@ use crate::MainLoop;
 use crate::{
     legacy::Serial,
     mmio::{Bus, DeviceType, VirtioMmioDevice},
-    virtio::{vhost, Console},
+    virtio::{check_aio_engine, vhost, Balloon, Console, Rng, VirtioDevice},
 };
 
 use crate::{LayoutEntryType, MEM_LAYOUT};
@@ -115,7 +132,7 @@ impl ConfigDevBuilder for NetworkInterfaceConfig {
                 sys_mem.clone(),
             )));
             let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, net)));
-            bus.attach_device(device)
+            bus.attach_device(&self.iface_id, device)
                 .chain_err(|| "build dev from config failed")?;
             Ok(())
         } else {
@@ -129,7 +146,7 @@ impl ConfigDevBuilder for ConsoleConfig {
     fn build_dev(&self, sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
         let console = Arc::new(Mutex::new(Console::new(self.clone())));
         let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, console)));
-        bus.attach_device(device)
+        bus.attach_device(&self.console_id, device)
             .chain_err(|| "build dev from config failed")?;
         Ok(())
     }
@@ -142,7 +159,7 @@ impl ConfigDevBuilder for VsockConfig {
             sys_mem.clone(),
         )));
         let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, vsock)));
-        bus.attach_device(device)
+        bus.attach_device(&self.vsock_id, device)
             .chain_err(|| "build dev from config failed")?;
         Ok(())
     }
@@ -151,7 +168,7 @@ impl ConfigDevBuilder for VsockConfig {
 impl ConfigDevBuilder for SerialConfig {
     fn build_dev(&self, _sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
         let serial = Arc::new(Mutex::new(Serial::new()));
-        bus.attach_device(serial.clone())
+        bus.attach_device("serial", serial.clone())
             .chain_err(|| "build dev from config failed")?;
 
         if self.stdio {
@@ -161,6 +178,14 @@ impl ConfigDevBuilder for SerialConfig {
     }
 }
 
+/// Whether host cpu supports 1 GiB pages (`pdpe1gb`), which guest cpu
+/// inherits from KVM supported cpuid.
+#[cfg(target_arch = "x86_64")]
+fn host_supports_gib_pages() -> bool {
+    let cpuid = unsafe { std::arch::x86_64::__cpuid(0x8000_0001) };
+    cpuid.edx & (1 << 26) != 0
+}
+
 /// A wrapper around creating and using a kvm-based micro VM.
 pub struct LightMachine {
     /// KVM VM file descriptor, represent VM entry in kvm module.
@@ -169,22 +194,41 @@ pub struct LightMachine {
     cpu_topo: CpuTopology,
     /// `vCPU` devices.
     cpus: Arc<Mutex<Vec<Arc<CPU>>>>,
+    /// Barrier which records whether `vCPU`s are parked outside `KVM_RUN`.
+    park_barrier: Arc<CpuParkBarrier>,
     /// Interrupt controller device.
     #[cfg(target_arch = "aarch64")]
     irq_chip: Arc<InterruptController>,
     /// Memory address space.
     sys_mem: Arc<AddressSpace>,
+    /// Ring of guest writes batched in coalesced MMIO ranges of `sys_mem`,
+    /// `None` if KVM doesn't support it.
+    coalesced_ring: Option<Mutex<CoalescedMmioRing>>,
     /// IO address space.
     #[cfg(target_arch = "x86_64")]
     sys_io: Arc<AddressSpace>,
     /// Mmio bus.
     bus: Bus,
+    /// Registry of backends of block devices.
+    block_backends: Arc<BlockBackendRegistry>,
     /// VM running state.
     vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
     /// Vm boot_source config.
     boot_source: Arc<Mutex<BootSource>>,
     /// VM power button, handle VM `Shutdown` event.
     power_button: EventFd,
+    /// Policy of guest clocks after resume.
+    clock_policy: ClockPolicy,
+    /// Guest clock state saved at pause.
+    paused_clock: Mutex<PausedClock>,
+    /// RTC device.
+    #[cfg(target_arch = "aarch64")]
+    rtc: Option<Arc<Mutex<PL031>>>,
+    /// Config of balloon device plugged by QMP, `None` if guest memory is
+    /// locked and can't be reclaimed.
+    balloon_config: Option<BalloonConfig>,
+    /// Id and handle of the balloon device plugged.
+    balloon: Mutex<Option<(String, Arc<dyn BalloonHandle>)>>,
 }
 
 impl LightMachine {
@@ -193,7 +237,12 @@ impl LightMachine {
     /// # Arguments
     ///
     /// * `vm_config` - Represents the configuration for VM.
-    pub fn new(vm_config: VmConfig) -> Result<Arc<LightMachine>> {
+    /// * `rollback` - Bring-up stage which guest memory is registered to.
+    pub fn new(mut vm_config: VmConfig, rollback: &mut Rollback) -> Result<Arc<LightMachine>> {
+        if vm_config.machine_config.auto_cmdline {
+            vm_config.synthesize_kernel_cmdline();
+        }
+
         let kvm = Kvm::new().chain_err(|| "Failed to open /dev/kvm.")?;
         let vm_fd = Arc::new(
             kvm.create_vm()
@@ -208,20 +257,24 @@ impl LightMachine {
         )))?;
 
         #[cfg(target_arch = "x86_64")]
-        let sys_io = AddressSpace::new(Region::init_container_region(1 << 16))?;
+        let sys_io =
+            AddressSpace::new_with_type(Region::init_container_region(1 << 16), SpaceType::Pio)?;
         #[cfg(target_arch = "x86_64")]
         sys_io.register_listener(Box::new(KvmIoListener::new(vm_fd.clone())))?;
 
         // Init guest-memory
-        // Define ram-region ranges according to architectures
-        let ram_ranges = Self::arch_ram_ranges(vm_config.machine_config.mem_config.mem_size);
-        let mem_mappings = create_host_mmaps(&ram_ranges, &vm_config.machine_config.mem_config)?;
-        for mmap in mem_mappings.iter() {
-            sys_mem.root().add_subregion(
-                Region::init_ram_region(mmap.clone()),
-                mmap.start_address().raw_value(),
-            )?;
+        if let Some(fd_name) = &vm_config.machine_config.mem_config.mem_fd_name {
+            vm_config.machine_config.mem_config.mem_fd = Some(Self::mem_backend_fd(fd_name)?);
         }
+        let cpus = Arc::new(Mutex::new(Vec::new()));
+        let park_barrier = Arc::new(CpuParkBarrier::new(vm_config.machine_config.nr_cpus));
+        Self::init_memory(
+            &sys_mem,
+            &vm_config.machine_config.mem_config,
+            &cpus,
+            &park_barrier,
+            rollback,
+        )?;
 
         // Pre init vcpu and cpu topology
         let mut mask: Vec<u8> = Vec::with_capacity(vm_config.machine_config.nr_cpus as usize);
@@ -241,8 +294,11 @@ impl LightMachine {
         let nrcpus = vm_config.machine_config.nr_cpus;
         let mut vcpu_fds = vec![];
         for cpu_id in 0..nrcpus {
-            vcpu_fds.push(Arc::new(vm_fd.create_vcpu(cpu_id)?));
+            vcpu_fds.push(Arc::new(VmOps::create_vcpu(vm_fd.as_ref(), cpu_id)?));
         }
+        let coalesced_ring = CoalescedMmioRing::new(&vm_fd, &vcpu_fds[0])
+            .chain_err(|| "Failed to map coalesced MMIO ring")?
+            .map(Mutex::new);
 
         #[cfg(target_arch = "x86_64")]
         Self::arch_init(&vm_fd)?;
@@ -260,22 +316,43 @@ impl LightMachine {
 
         // Machine state init
         let vm_state = Arc::new((Mutex::new(KvmVmState::Created), Condvar::new()));
+        let block_backends = Arc::new(BlockBackendRegistry::new());
 
         // Create vm object
         let mut vm = LightMachine {
             cpu_topo,
-            cpus: Arc::new(Mutex::new(Vec::new())),
+            cpus,
+            park_barrier,
             #[cfg(target_arch = "aarch64")]
             irq_chip: Arc::new(irq_chip),
             sys_mem: sys_mem.clone(),
+            coalesced_ring,
             #[cfg(target_arch = "x86_64")]
             sys_io,
-            bus: Bus::new(sys_mem),
+            bus: Bus::new(sys_mem, &block_backends),
+            block_backends,
             boot_source: Arc::new(Mutex::new(vm_config.clone().boot_source)),
             vm_fd: vm_fd.clone(),
             vm_state,
             power_button: EventFd::new(libc::EFD_NONBLOCK)
                 .chain_err(|| "Create EventFd for power-button failed.")?,
+            clock_policy: vm_config.machine_config.clock_policy,
+            paused_clock: Mutex::new(PausedClock::default()),
+            #[cfg(target_arch = "aarch64")]
+            rtc: None,
+            balloon_config: if vm_config.machine_config.mem_config.mem_lock {
+                None
+            } else {
+                Some(
+                    vm_config
+                        .machine_config
+                        .mem_config
+                        .balloon
+                        .clone()
+                        .unwrap_or_default(),
+                )
+            },
+            balloon: Mutex::new(None),
         };
 
         // Add mmio devices
@@ -298,6 +375,7 @@ impl LightMachine {
                 vcpu_id,
                 Arc::new(Mutex::new(arch_cpu)),
                 cpu_vm.clone(),
+                vm.park_barrier.clone(),
             )?;
 
             let mut vcpus = vm.cpus.lock().unwrap();
@@ -308,6 +386,129 @@ impl LightMachine {
         Ok(vm)
     }
 
+    /// Map guest RAM and add it to `sys_mem`. RAM regions are deleted on
+    /// rollback with vcpus paused, so that the mappings and their backing
+    /// memfd are released.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Memory address space.
+    /// * `mem_config` - Configuration of guest memory.
+    /// * `cpus` - Vcpus of VM, which are added after memory.
+    /// * `park_barrier` - The barrier shared by `cpus`.
+    /// * `rollback` - Bring-up stage which RAM regions are registered to.
+    fn init_memory(
+        sys_mem: &Arc<AddressSpace>,
+        mem_config: &MachineMemConfig,
+        cpus: &Arc<Mutex<Vec<Arc<CPU>>>>,
+        park_barrier: &Arc<CpuParkBarrier>,
+        rollback: &mut Rollback,
+    ) -> Result<()> {
+        // Define ram-region ranges according to architectures
+        let ram_ranges = Self::arch_ram_ranges(mem_config.mem_size);
+        let mem_mappings = create_host_mmaps(&ram_ranges, mem_config)?;
+        for mmap in mem_mappings.iter() {
+            let region = Region::init_ram_region(mmap.clone());
+            let addr = mmap.start_address().raw_value();
+            sys_mem.root().add_subregion(region.clone(), addr)?;
+
+            let sys_mem = sys_mem.clone();
+            let cpus = cpus.clone();
+            let park_barrier = park_barrier.clone();
+            rollback.register(&format!("guest memory at 0x{:x}", addr), move || {
+                let cpus = cpus.lock().unwrap().clone();
+                let ret = CpusPauseGuard::pause_sync(&cpus, &park_barrier, VCPU_PARK_TIMEOUT)
+                    .chain_err(|| "Failed to pause vcpus synchronously")
+                    .and_then(|paused| {
+                        sys_mem
+                            .root()
+                            .delete_ram_subregion(&region, &paused)
+                            .chain_err(|| "Failed to delete Ram region")
+                    });
+                if let Err(e) = ret {
+                    error!("Failed to delete guest memory at 0x{:x}: {}", addr, e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Look up fd backing guest memory by `fd_name`, which is either passed
+    /// by QMP `getfd` or the number of an inherited fd.
+    fn mem_backend_fd(fd_name: &str) -> Result<RawFd> {
+        #[cfg(feature = "qmp")]
+        {
+            if let Some(fd) = QmpChannel::get_fd(fd_name) {
+                return Ok(fd);
+            }
+        }
+        fd_name
+            .parse::<RawFd>()
+            .chain_err(|| format!("Failed to find memory backend fd {}", fd_name))
+    }
+
+    /// Look up fd of network backend by the last one of `fds` separated by
+    /// ':', which is either passed by QMP `getfd` or the number of an
+    /// inherited fd.
+    fn netdev_fd(fds: &str) -> Result<RawFd> {
+        let fd_name = fds.rsplit(':').next().unwrap_or(fds);
+        #[cfg(feature = "qmp")]
+        {
+            if let Some(fd) = QmpChannel::get_fd(fd_name) {
+                return Ok(fd);
+            }
+        }
+        fd_name
+            .parse::<RawFd>()
+            .chain_err(|| format!("Failed to convert {} to RawFd", fd_name))
+    }
+
+    /// Plug virtio device `id` into running VM through a free MMIO slot
+    /// reserved for hot-plug.
+    fn hotplug_virtio_device(&self, id: &str, device: Arc<Mutex<dyn VirtioDevice>>) -> Result<()> {
+        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(
+            self.sys_mem.clone(),
+            device,
+        )));
+        let resource = self
+            .bus
+            .hotplug_device(id, device, &self.vm_fd, &self.sys_mem)?;
+        info!(
+            "Device {} is plugged at virtio_mmio.device={}@0x{:08x}:{}",
+            id, resource.size, resource.addr, resource.irq
+        );
+        Ok(())
+    }
+
+    /// Plug block or network device `id` into the replaceable slot given by
+    /// `addr` or `lun`.
+    fn replace_device(
+        &self,
+        id: &str,
+        driver: &str,
+        addr: Option<String>,
+        lun: Option<usize>,
+    ) -> machine_manager::errors::Result<()> {
+        // get slot of bus by addr or lun
+        let mut slot = 0;
+        if let Some(addr) = addr {
+            let slot_str = addr.as_str().trim_start_matches("0x");
+
+            if let Ok(n) = usize::from_str_radix(slot_str, 16) {
+                slot = n;
+            }
+        } else if let Some(lun) = lun {
+            slot = lun + 1;
+        }
+
+        if let Err(e) = self.bus.add_replaceable_device(id, driver, slot) {
+            error!("{}", e.display_chain());
+            return Err(e.to_string().into());
+        }
+        Ok(())
+    }
+
     /// Calculate the ranges of memory according to architecture.
     ///
     /// # Arguments
@@ -352,11 +553,30 @@ impl LightMachine {
     }
 
     /// Realize `LightMachine` means let all members of `LightMachine` enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `rollback` - Bring-up stage which realized devices are registered to.
     #[cfg(target_arch = "aarch64")]
-    pub fn realize(&self) -> Result<()> {
+    pub fn realize(&self, rollback: &mut Rollback) -> Result<()> {
         self.bus
-            .realize_devices(&self.vm_fd, &self.boot_source, &self.sys_mem)?;
+            .realize_devices(&self.vm_fd, &self.boot_source, &self.sys_mem, rollback)?;
+
+        let boot_config = self.load_boot_source()?;
+        for cpu_index in 0..self.cpu_topo.max_cpus {
+            self.cpus.lock().unwrap()[cpu_index as usize].realize(&boot_config)?;
+        }
+        self.write_fdt(&boot_config)?;
 
+        self.register_power_event()?;
+
+        Ok(())
+    }
+
+    /// Load kernel and initrd to guest memory, return boot configuration of
+    /// vcpus.
+    #[cfg(target_arch = "aarch64")]
+    fn load_boot_source(&self) -> Result<CPUBootConfig> {
         let boot_source = self.boot_source.lock().unwrap();
 
         let (initrd, initrd_size) = match &boot_source.initrd {
@@ -371,7 +591,9 @@ impl LightMachine {
             mem_start: MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
         };
 
-        let layout = load_kernel(&bootloader_config, &self.sys_mem)?;
+        let mut timeline = BootTimeline::start();
+        let layout = load_linux(&bootloader_config, &self.sys_mem, Some(&mut timeline))?;
+        info!("Boot source loaded in {:?}", timeline.total());
         if let Some(rd) = &boot_source.initrd {
             *rd.initrd_addr.lock().unwrap() = layout.initrd_start;
         }
@@ -379,39 +601,52 @@ impl LightMachine {
         // need to release lock here, as generate_fdt_node will acquire it later
         drop(boot_source);
 
-        let boot_config = CPUBootConfig {
+        Ok(CPUBootConfig {
             fdt_addr: layout.dtb_start,
             kernel_addr: layout.kernel_start,
-        };
-
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            self.cpus.lock().unwrap()[cpu_index as usize].realize(&boot_config)?;
-        }
+        })
+    }
 
+    /// Generate device tree and write it to guest memory, vcpus must be
+    /// realized for their cpu nodes.
+    #[cfg(target_arch = "aarch64")]
+    fn write_fdt(&self, boot_config: &CPUBootConfig) -> Result<()> {
         let mut fdt = vec![0; device_tree::FDT_MAX_SIZE as usize];
         self.generate_fdt_node(&mut fdt)?;
 
-        self.sys_mem.write(
-            &mut fdt.as_slice(),
-            GuestAddress(boot_config.fdt_addr as u64),
-            fdt.len() as u64,
-        )?;
-
-        self.register_power_event()?;
-
+        write_dtb(&self.sys_mem, boot_config.fdt_addr as u64, &fdt)?;
         Ok(())
     }
 
     /// Realize `LightMachine` means let all members of `LightMachine` enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `rollback` - Bring-up stage which realized devices are registered to.
     #[cfg(target_arch = "x86_64")]
-    pub fn realize(&self) -> Result<()> {
+    pub fn realize(&self, rollback: &mut Rollback) -> Result<()> {
         self.bus.realize_devices(
             &self.vm_fd,
             &self.boot_source,
             &self.sys_mem,
             self.sys_io.clone(),
+            rollback,
         )?;
 
+        let boot_config = self.load_boot_source()?;
+        for cpu_index in 0..self.cpu_topo.max_cpus {
+            self.cpus.lock().unwrap()[cpu_index as usize].realize(&boot_config)?;
+        }
+
+        self.register_power_event()?;
+
+        Ok(())
+    }
+
+    /// Load kernel and initrd to guest memory, return boot configuration of
+    /// vcpus.
+    #[cfg(target_arch = "x86_64")]
+    fn load_boot_source(&self) -> Result<CPUBootConfig> {
         let boot_source = self.boot_source.lock().unwrap();
 
         // Load kernel image
@@ -424,37 +659,41 @@ impl LightMachine {
             + MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
         let gap_end = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
         let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
+            kernel: Some(boot_source.kernel_file.clone()),
+            initrd: BootLoaderConfig::single_initrd(initrd),
             initrd_size: initrd_size as u32,
-            kernel_cmdline: boot_source.kernel_cmdline.to_string(),
+            kernel_cmdline: boot_source.kernel_cmdline.to_string().into(),
             cpu_count: self.cpu_topo.nrcpus,
+            max_cpus: self.cpu_topo.max_cpus,
             gap_range: (gap_start, gap_end - gap_start),
             ioapic_addr: MEM_LAYOUT[LayoutEntryType::IoApic as usize].0 as u32,
             lapic_addr: MEM_LAYOUT[LayoutEntryType::LocalApic as usize].0 as u32,
+            prefer_pvh: false,
+            firmware: None,
+            setup_data: vec![SetupData::rng_seed()?],
+            mptable_only: false,
+            acpi_addr: None,
+            irq_overrides: vec![ISA_TIMER_IRQ_OVERRIDE],
+            stream_load: false,
+            layout: BootLayout::default(),
+            // Keep boot page tables small for large guests.
+            use_gib_pages: host_supports_gib_pages(),
+            boot_tss: false,
+            kernel_hash: None,
+            initrd_hash: None,
+            screen_info: ScreenInfo::default(),
+            min_boot_protocol: BOOT_VERSION,
         };
 
-        let layout = load_kernel(&bootloader_config, &self.sys_mem)?;
-        let boot_config = CPUBootConfig {
-            boot_ip: layout.kernel_start,
-            boot_sp: layout.kernel_sp,
-            zero_page: layout.zero_page_addr,
-            code_segment: layout.segments.code_segment,
-            data_segment: layout.segments.data_segment,
-            gdt_base: layout.segments.gdt_base,
-            gdt_size: layout.segments.gdt_limit,
-            idt_base: layout.segments.idt_base,
-            idt_size: layout.segments.idt_limit,
-            pml4_start: layout.boot_pml4_addr,
-        };
-
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            self.cpus.lock().unwrap()[cpu_index as usize].realize(&boot_config)?;
+        let mut timeline = BootTimeline::start();
+        let layout = load_linux(&bootloader_config, &self.sys_mem, Some(&mut timeline))?;
+        info!("Boot source loaded in {:?}", timeline.total());
+        let (mut regs, sregs) = boot_regs(&layout);
+        // Firmware hands bzImage over through its 64-bit EFI entry if any.
+        if let (Some(_), Some(entry)) = (&bootloader_config.firmware, layout.efi_handover_entry) {
+            regs.rip = entry;
         }
-
-        self.register_power_event()?;
-
-        Ok(())
+        Ok(CPUBootConfig { regs, sregs })
     }
 
     /// Start VM, changed `LightMachine`'s `vmstate` to `Paused` or
@@ -494,6 +733,16 @@ impl LightMachine {
         #[cfg(target_arch = "aarch64")]
         self.irq_chip.stop();
 
+        #[cfg(target_arch = "x86_64")]
+        let kvmclock_ns = if self.clock_policy == ClockPolicy::Host {
+            None
+        } else {
+            Some(VmOps::get_clock_ns(self.vm_fd.as_ref()).chain_err(|| "Failed to get kvmclock")?)
+        };
+        #[cfg(target_arch = "aarch64")]
+        let kvmclock_ns = None;
+        self.paused_clock.lock().unwrap().pause(kvmclock_ns);
+
         let mut vmstate = self.vm_state.deref().0.lock().unwrap();
         *vmstate = KvmVmState::Paused;
 
@@ -503,6 +752,8 @@ impl LightMachine {
     /// Resume VM, awaken all vcpu thread. Changed `LightMachine`'s `vmstate`
     /// from `Paused` to `Running`.
     fn vm_resume(&self) -> Result<()> {
+        self.sync_clock_after_pause()?;
+
         for cpu_index in 0..self.cpu_topo.max_cpus {
             self.cpus.lock().unwrap()[cpu_index as usize].resume()?;
         }
@@ -513,6 +764,127 @@ impl LightMachine {
         Ok(())
     }
 
+    /// Adjust guest clocks according to `clock_policy` before vcpus resume.
+    /// x86_64 micro VM has no RTC device, guest time comes from kvmclock only.
+    fn sync_clock_after_pause(&self) -> Result<()> {
+        let (paused, _kvmclock_ns) = match self.paused_clock.lock().unwrap().resume() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        info!(
+            "VM resumes after {}ms pause, clock policy: {}",
+            paused.as_millis(),
+            self.clock_policy
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let clock_ns = _kvmclock_ns
+                .and_then(|ns| clock::kvmclock_after_pause(self.clock_policy, ns, paused));
+            if let Some(clock_ns) = clock_ns {
+                VmOps::set_clock_ns(self.vm_fd.as_ref(), clock_ns)
+                    .chain_err(|| "Failed to set kvmclock")?;
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            if let Some(rtc) = &self.rtc {
+                rtc.lock()
+                    .unwrap()
+                    .sync_after_pause(self.clock_policy, paused);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pause all vcpus and wait until every vcpu thread is parked outside
+    /// `KVM_RUN`, e.g. before removing guest RAM from `sys_mem`.
+    ///
+    /// Vcpus stay paused as long as the returned guard lives, they are
+    /// resumed by `resume_vcpus` or when the guard drops.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if some vcpu fails to park in time, already paused vcpus
+    /// are resumed in this case.
+    pub fn pause_vcpus_sync(&self) -> Result<CpusPauseGuard> {
+        let cpus = self.cpus.lock().unwrap().clone();
+        let guard = CpusPauseGuard::pause_sync(&cpus, &self.park_barrier, VCPU_PARK_TIMEOUT)
+            .chain_err(|| "Failed to pause vcpus synchronously")?;
+        Ok(guard)
+    }
+
+    /// Resume vcpus paused by `pause_vcpus_sync`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guard` - The guard returned by `pause_vcpus_sync`.
+    pub fn resume_vcpus(&self, guard: CpusPauseGuard) -> Result<()> {
+        guard.resume()?;
+        Ok(())
+    }
+
+    /// Dump all guest RAM to file at `path` by `AddressSpace::dump_memory`,
+    /// vcpus are paused during the dump.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `path` isn't writable, or fail to pause vcpus or dump.
+    pub fn dump_guest_memory_to(&self, path: &str) -> Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .chain_err(|| format!("Failed to open dump file {}", path))?;
+        let mut writer = std::io::BufWriter::new(file);
+
+        let guard = self.pause_vcpus_sync()?;
+        let ret = self
+            .sys_mem
+            .dump_memory(&mut writer, None)
+            .chain_err(|| format!("Failed to dump guest memory to {}", path));
+        self.resume_vcpus(guard)?;
+        ret
+    }
+
+    /// Reset VM, vcpus are paused while boot source is loaded to guest
+    /// memory again and vcpus are reset to their boot state. Devices keep
+    /// their state.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if VM isn't running or paused, or fail to pause vcpus,
+    /// load boot source or reset vcpus.
+    fn vm_reset(&self) -> Result<()> {
+        let vmstate = *self.vm_state.deref().0.lock().unwrap();
+        if vmstate != KvmVmState::Running && vmstate != KvmVmState::Paused {
+            bail!("VM can only be reset when it's running or paused");
+        }
+
+        let guard = self.pause_vcpus_sync()?;
+        let ret = self.reset_boot_state();
+        self.resume_vcpus(guard)?;
+        ret
+    }
+
+    /// Load boot source again and reset vcpus to the boot state.
+    fn reset_boot_state(&self) -> Result<()> {
+        let _boot_config = self
+            .load_boot_source()
+            .chain_err(|| "Failed to reload boot source")?;
+        #[cfg(target_arch = "aarch64")]
+        self.write_fdt(&_boot_config)?;
+
+        for cpu in self.cpus.lock().unwrap().iter() {
+            cpu.reset_to_boot()
+                .chain_err(|| format!("Failed to reset vcpu{}", cpu.id()))?;
+        }
+        Ok(())
+    }
+
     /// Destroy VM, kill all vcpu thread. Changed `LightMachine`'s `vmstate`
     /// to `KVM_VMSTATE_DESTROY`.
     fn vm_destroy(&self) -> Result<()> {
@@ -537,33 +909,40 @@ impl LightMachine {
         {
             let rtc = Arc::new(Mutex::new(PL031::new()));
             self.bus
-                .attach_device(rtc)
+                .attach_device("rtc", rtc.clone())
                 .chain_err(|| "add rtc to bus failed")?;
+            self.rtc = Some(rtc);
         }
 
         if let Some(serial) = vm_config.serial {
-            self.register_device(&serial)?;
+            self.register_device(&serial)
+                .chain_err(|| "Failed to add serial device")?;
         }
 
         if let Some(vsock) = vm_config.vsock {
-            self.register_device(&vsock)?;
+            self.register_device(&vsock)
+                .chain_err(|| format!("Failed to add vsock device '{}'", vsock.vsock_id))?;
         }
 
         if let Some(drives) = vm_config.drives {
             for drive in drives {
-                self.register_device(&drive)?;
+                self.register_device(&drive)
+                    .chain_err(|| format!("Failed to add drive device '{}'", drive.drive_id))?;
             }
         }
 
         if let Some(nets) = vm_config.nets {
             for net in nets {
-                self.register_device(&net)?;
+                self.register_device(&net)
+                    .chain_err(|| format!("Failed to add net device '{}'", net.iface_id))?;
             }
         }
 
         if let Some(consoles) = vm_config.consoles {
             for console in consoles {
-                self.register_device(&console)?;
+                self.register_device(&console).chain_err(|| {
+                    format!("Failed to add console device '{}'", console.console_id)
+                })?;
             }
         }
 
@@ -628,6 +1007,21 @@ impl MachineLifecycle for LightMachine {
         true
     }
 
+    fn reset(&self) -> machine_manager::errors::Result<()> {
+        if let Err(e) = self.vm_reset() {
+            error!("{}", e.display_chain());
+            return Err(e.to_string().into());
+        }
+
+        #[cfg(feature = "qmp")]
+        {
+            let reset_msg = schema::RESET { guest: false };
+            event!(RESET; reset_msg);
+        }
+
+        Ok(())
+    }
+
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
         use KvmVmState::*;
 
@@ -713,6 +1107,14 @@ impl MachineAddressInterface for LightMachine {
             .write(&mut data, GuestAddress(addr), count)
             .is_ok()
     }
+
+    fn flush_coalesced_mmio(&self) {
+        if let Some(ring) = &self.coalesced_ring {
+            if let Err(e) = self.sys_mem.drain_coalesced(&mut *ring.lock().unwrap()) {
+                error!("{}", e.display_chain());
+            }
+        }
+    }
 }
 
 impl DeviceInterface for LightMachine {
@@ -724,11 +1126,13 @@ impl DeviceInterface for LightMachine {
                 singlestep: false,
                 running: true,
                 status: schema::RunState::running,
+                clock: Some(self.clock_policy.to_string()),
             },
             KvmVmState::Paused => schema::StatusInfo {
                 singlestep: false,
                 running: true,
                 status: schema::RunState::paused,
+                clock: Some(self.clock_policy.to_string()),
             },
             _ => Default::default(),
         };
@@ -832,44 +1236,78 @@ impl DeviceInterface for LightMachine {
         qmp::Response::create_response(hotplug_vec.into(), None)
     }
 
-    fn device_add(
-        &self,
-        id: String,
-        driver: String,
-        addr: Option<String>,
-        lun: Option<usize>,
-    ) -> bool {
-        // get slot of bus by addr or lun
-        let mut slot = 0;
-        if let Some(addr) = addr {
-            let slot_str = addr.as_str().trim_start_matches("0x");
-
-            if let Ok(n) = usize::from_str_radix(slot_str, 16) {
-                slot = n;
+    fn device_add(&self, id: String, args: DeviceAddArgs) -> machine_manager::errors::Result<()> {
+        // Devices other than block and network take a free reserved MMIO slot.
+        let device: Arc<Mutex<dyn VirtioDevice>> = match args {
+            DeviceAddArgs::Vsock(config) => Arc::new(Mutex::new(vhost::kernel::Vsock::new(
+                config,
+                self.sys_mem.clone(),
+            ))),
+            DeviceAddArgs::Rng(config) => Arc::new(Mutex::new(Rng::new(config))),
+            DeviceAddArgs::Balloon => {
+                let mut locked_balloon = self.balloon.lock().unwrap();
+                if locked_balloon.is_some() {
+                    return Err("Only one balloon device is supported".into());
+                }
+                let config = match &self.balloon_config {
+                    Some(config) => config.clone(),
+                    None => return Err("Balloon can't be used with locked memory".into()),
+                };
+                let balloon = Balloon::new(config, self.sys_mem.ram_size());
+                let handle = balloon.handle();
+                if let Err(e) = self.hotplug_virtio_device(&id, Arc::new(Mutex::new(balloon))) {
+                    error!("{}", e.display_chain());
+                    return Err(e.to_string().into());
+                }
+                *locked_balloon = Some((id, handle));
+                return Ok(());
             }
-        } else if let Some(lun) = lun {
-            slot = lun + 1;
-        }
+            DeviceAddArgs::Replace { driver, addr, lun } => {
+                return self.replace_device(&id, &driver, addr, lun)
+            }
+        };
 
-        self.bus.add_replaceable_device(&id, &driver, slot).is_ok()
+        if let Err(e) = self.hotplug_virtio_device(&id, device) {
+            error!("{}", e.display_chain());
+            return Err(e.to_string().into());
+        }
+        Ok(())
     }
 
     fn device_del(&self, device_id: String) -> bool {
-        match self.bus.del_replaceable_device(&device_id) {
-            Ok(path) => {
-                #[cfg(feature = "qmp")]
-                {
-                    let block_del_event = schema::DEVICE_DELETED {
-                        device: Some(device_id),
-                        path,
-                    };
-                    event!(DEVICE_DELETED; block_del_event);
+        let path = match self
+            .bus
+            .unplug_device(&device_id, &self.vm_fd, &self.sys_mem)
+        {
+            Ok(true) => {
+                let mut locked_balloon = self.balloon.lock().unwrap();
+                if let Some((id, _)) = locked_balloon.as_ref() {
+                    if *id == device_id {
+                        *locked_balloon = None;
+                    }
                 }
-
-                true
+                device_id.clone()
             }
-            _ => false,
+            Ok(false) => match self.bus.del_replaceable_device(&device_id) {
+                Ok(path) => path,
+                _ => return false,
+            },
+            Err(e) => {
+                error!("{}", e.display_chain());
+                return false;
+            }
+        };
+
+        #[cfg(feature = "qmp")]
+        {
+            let device_del_event = schema::DEVICE_DELETED {
+                device: Some(device_id),
+                path,
+            };
+            event!(DEVICE_DELETED; device_del_event);
         }
+
+        true
     }
 
     fn blockdev_add(
@@ -878,7 +1316,10 @@ impl DeviceInterface for LightMachine {
         file: schema::FileOptions,
         cache: Option<schema::CacheOptions>,
         read_only: Option<bool>,
-    ) -> bool {
+        format: Option<ImageFormat>,
+        discard: Option<DiscardMode>,
+        detect_zeroes: Option<DetectZeroes>,
+    ) -> machine_manager::errors::Result<()> {
         let read_only = if let Some(ro) = read_only { ro } else { false };
 
         let direct = if let Some(cache) = cache {
@@ -896,58 +1337,78 @@ impl DeviceInterface for LightMachine {
             read_only,
             direct,
             serial_num: None,
+            aio: file.aio,
+            format: format.unwrap_or_default(),
+            discard: discard.unwrap_or_default(),
+            detect_zeroes: detect_zeroes.unwrap_or_default(),
         };
+        config.check()?;
 
-        self.bus
-            .add_replaceable_config(node_name, Arc::new(config))
-            .is_ok()
+        if let Err(e) = check_aio_engine(config.aio_engine()) {
+            error!("{}", e.display_chain());
+            return Err(e.to_string().into());
+        }
+        if let Err(e) = self.bus.add_replaceable_config(node_name, Arc::new(config)) {
+            error!("{}", e.display_chain());
+            return Err(e.to_string().into());
+        }
+        Ok(())
     }
 
-    fn netdev_add(&self, id: String, if_name: Option<String>, fds: Option<String>) -> bool {
+    fn netdev_add(
+        &self,
+        id: String,
+        if_name: Option<String>,
+        fds: Option<String>,
+        vhost: Option<bool>,
+        vhostfds: Option<String>,
+        queues: Option<u16>,
+    ) -> machine_manager::errors::Result<()> {
         let mut config = NetworkInterfaceConfig {
             iface_id: id.clone(),
-            host_dev_name: "".to_string(),
-            mac: None,
-            tap_fd: None,
-            vhost_type: None,
-            vhost_fd: None,
+            ..Default::default()
         };
 
         if let Some(fds) = fds {
-            let netdev_fd = if fds.contains(':') {
-                let col: Vec<_> = fds.split(':').collect();
-                String::from(col[col.len() - 1])
-            } else {
-                String::from(&fds)
-            };
-
-            #[cfg(feature = "qmp")]
-            {
-                if let Some(fd_num) = QmpChannel::get_fd(&netdev_fd) {
-                    config.tap_fd = Some(fd_num);
-                } else {
-                    // try to convert string to RawFd
-                    let fd_num = match netdev_fd.parse::<i32>() {
-                        Ok(fd) => fd,
-                        _ => {
-                            error!(
-                                "Add netdev error: failed to convert {} to RawFd.",
-                                netdev_fd
-                            );
-                            return false;
-                        }
-                    };
-
-                    config.tap_fd = Some(fd_num);
+            let mut tap_fds = Vec::new();
+            for fd_name in split_net_fds(&fds, ',')? {
+                match LightMachine::netdev_fd(fd_name) {
+                    Ok(fd) => tap_fds.push(fd),
+                    Err(e) => {
+                        error!("Add netdev error: {}", e);
+                        return Err(e.to_string().into());
+                    }
                 }
             }
+            config.tap_fds = Some(tap_fds);
         } else if let Some(if_name) = if_name {
             config.host_dev_name = if_name;
         }
 
-        self.bus
-            .add_replaceable_config(id, Arc::new(config))
-            .is_ok()
+        if let Some(vhostfds) = vhostfds {
+            match LightMachine::netdev_fd(&vhostfds) {
+                Ok(fd) => config.vhost_fd = Some(fd),
+                Err(e) => {
+                    error!("Add netdev error: {}", e);
+                    return Err(e.to_string().into());
+                }
+            }
+        }
+        if vhost.unwrap_or(config.vhost_fd.is_some()) {
+            config.vhost_type = Some("vhost-kernel".to_string());
+        }
+        // Number of queues defaults to the number of fds given.
+        config.queues = match queues {
+            Some(queues) => queues,
+            None => config.tap_fds.as_ref().map_or(1, |fds| fds.len() as u16),
+        };
+        config.check()?;
+
+        if let Err(e) = self.bus.add_replaceable_config(id, Arc::new(config)) {
+            error!("{}", e.display_chain());
+            return Err(e.to_string().into());
+        }
+        Ok(())
     }
 
     #[cfg(feature = "qmp")]
@@ -960,6 +1421,103 @@ impl DeviceInterface for LightMachine {
             qmp::Response::create_error_response(err_resp, None).unwrap()
         }
     }
+
+    #[cfg(feature = "qmp")]
+    fn dump_guest_memory(&self, protocol: String) -> qmp::Response {
+        let ret = if protocol.starts_with("file:") {
+            self.dump_guest_memory_to(&protocol["file:".len()..])
+        } else {
+            Err(format!("Unsupported dump protocol {}", protocol).into())
+        };
+        match ret {
+            Ok(()) => qmp::Response::create_empty_response(),
+            Err(e) => {
+                error!("{}", e.display_chain());
+                let err_resp = schema::QmpErrorClass::GenericError(e.to_string());
+                qmp::Response::create_error_response(err_resp, None).unwrap()
+            }
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    fn block_resize(&self, device: String, size: u64) -> qmp::Response {
+        match self.bus.resize_replaceable_device(&device, size) {
+            Ok(()) => qmp::Response::create_empty_response(),
+            Err(e) => {
+                error!("{}", e.display_chain());
+                let err_resp = schema::QmpErrorClass::GenericError(e.to_string());
+                qmp::Response::create_error_response(err_resp, None).unwrap()
+            }
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_netdev(&self) -> qmp::Response {
+        let netdevs: Vec<schema::NetdevInfo> = self
+            .bus
+            .netdev_configs()
+            .into_iter()
+            .map(|config| schema::NetdevInfo {
+                id: config.iface_id,
+                ifname: if config.host_dev_name.is_empty() {
+                    None
+                } else {
+                    Some(config.host_dev_name)
+                },
+                fds: config.tap_fds.map(|fds| {
+                    fds.iter()
+                        .map(|fd| fd.to_string())
+                        .collect::<Vec<String>>()
+                        .join(",")
+                }),
+                queues: config.queues,
+            })
+            .collect();
+        qmp::Response::create_response(serde_json::to_value(&netdevs).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn set_link(&self, name: String, up: bool) -> qmp::Response {
+        match self.bus.set_replaceable_link(&name, up) {
+            Ok(true) => qmp::Response::create_empty_response(),
+            Ok(false) => {
+                let err_resp =
+                    schema::QmpErrorClass::DeviceNotFound(format!("Net device {} not found", name));
+                qmp::Response::create_error_response(err_resp, None).unwrap()
+            }
+            Err(e) => {
+                error!("{}", e.display_chain());
+                let err_resp = schema::QmpErrorClass::GenericError(e.to_string());
+                qmp::Response::create_error_response(err_resp, None).unwrap()
+            }
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_memory_summary(&self) -> qmp::Response {
+        let summary = schema::MemorySummary {
+            ram_size: self.sys_mem.ram_size(),
+            regions: self.sys_mem.region_count(),
+            flat_ranges: self.sys_mem.flat_range_count(),
+        };
+        qmp::Response::create_response(serde_json::to_value(&summary).unwrap(), None)
+    }
+
+    fn ram_size(&self) -> u64 {
+        self.sys_mem.ram_size()
+    }
+
+    fn block_backends(&self) -> Arc<BlockBackendRegistry> {
+        self.block_backends.clone()
+    }
+
+    fn balloon_handle(&self) -> Option<Arc<dyn BalloonHandle>> {
+        self.balloon
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, handle)| handle.clone())
+    }
 }
 
 impl MachineInterface for LightMachine {}
@@ -1283,3 +1841,54 @@ impl device_tree::CompileFDT for LightMachine {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMFD_NAME: &str = "memfd:stratovirt_anon_mem";
+
+    /// Count fds and mappings of shared anonymous guest memory.
+    fn memfd_count() -> (usize, usize) {
+        let fds = std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+            .filter(|target| target.to_str().map_or(false, |t| t.contains(MEMFD_NAME)))
+            .count();
+        let maps = std::fs::read_to_string("/proc/self/maps")
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains(MEMFD_NAME))
+            .count();
+        (fds, maps)
+    }
+
+    #[test]
+    fn test_rollback_guest_memory() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mem_config = MachineMemConfig {
+            mem_size: 64 << 20,
+            mem_share: true,
+            ..Default::default()
+        };
+        let cpus = Arc::new(Mutex::new(Vec::new()));
+        let park_barrier = Arc::new(CpuParkBarrier::new(1));
+
+        let mut rollback = Rollback::new();
+        rollback
+            .stage("create machine", |rollback| {
+                LightMachine::init_memory(&sys_mem, &mem_config, &cpus, &park_barrier, rollback)
+            })
+            .unwrap();
+        assert_eq!(rollback.len(), 1);
+        assert!(memfd_count().1 > 0);
+        assert_eq!(sys_mem.ram_size(), 64 << 20);
+
+        let ret = rollback.stage("realize machine", |_| -> Result<()> {
+            bail!("Injected failure");
+        });
+        assert!(ret.unwrap_err().to_string().contains("realize machine"));
+        assert_eq!(sys_mem.ram_size(), 0);
+        assert_eq!(memfd_count(), (0, 0));
+    }
+}
@@ -14,11 +14,16 @@ use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use kvm_ioctls::VmFd;
-use machine_manager::config::{BootSource, ConfigCheck};
+use machine_manager::block_backend::BlockBackendRegistry;
+#[cfg(target_arch = "x86_64")]
+use machine_manager::config::Param;
+use machine_manager::config::{BootSource, ConfigCheck, NetworkInterfaceConfig};
+use util::rollback::Rollback;
 
 use super::super::virtio::{Block, Net};
 use super::{
-    errors::Result, DeviceResource, DeviceType, MmioDevice, MmioDeviceOps, VirtioMmioDevice,
+    errors::{Result, ResultExt},
+    DeviceResource, DeviceType, MmioDevice, MmioDeviceOps, VirtioMmioDevice,
 };
 use crate::{LayoutEntryType, MEM_LAYOUT};
 
@@ -38,6 +43,8 @@ const MMIO_LEN: u64 = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
 pub const MMIO_REPLACEABLE_BLK_NR: usize = 6;
 /// The replaceable network device maximum count.
 pub const MMIO_REPLACEABLE_NET_NR: usize = 2;
+/// The count of MMIO slots reserved for devices plugged into running VM.
+pub const MMIO_HOTPLUG_NR: usize = 3;
 
 /// The config of replaceable device.
 struct MmioReplaceableConfig {
@@ -80,12 +87,34 @@ impl MmioReplaceableInfo {
     }
 }
 
+/// The device information of device inserted at startup.
+struct MmioDevInfo {
+    /// Device id, empty for replaceable devices, whose id is given when
+    /// they're filled.
+    id: String,
+    /// The related MMIO device.
+    device: MmioDevice,
+}
+
+/// The device information of device plugged into running VM.
+struct MmioHotplugDevInfo {
+    /// Device id.
+    id: String,
+    /// Index of the MMIO slot occupied by this device.
+    index: usize,
+    /// The related MMIO device.
+    device: MmioDevice,
+}
+
 /// MMIO Bus.
 pub struct Bus {
     /// The devices inserted in bus.
-    devices: Vec<MmioDevice>,
+    devices: Vec<MmioDevInfo>,
     /// All replaceable device information.
     replaceable_info: MmioReplaceableInfo,
+    /// Devices plugged into running VM, which take the MMIO slots reserved
+    /// after `devices`.
+    hotplug_devices: Mutex<Vec<MmioHotplugDevInfo>>,
 }
 
 impl Bus {
@@ -99,16 +128,18 @@ impl Bus {
     /// # Arguments
     ///
     /// * `sys_mem` - guest memory.
-    pub fn new(sys_mem: Arc<AddressSpace>) -> Self {
+    /// * `block_backends` - Registry of backends of block devices.
+    pub fn new(sys_mem: Arc<AddressSpace>, block_backends: &Arc<BlockBackendRegistry>) -> Self {
         let mut bus = Bus {
             devices: Vec::new(),
             replaceable_info: MmioReplaceableInfo::new(),
+            hotplug_devices: Mutex::new(Vec::new()),
         };
 
         for _ in 0..MMIO_REPLACEABLE_BLK_NR {
-            let block = Arc::new(Mutex::new(Block::new()));
+            let block = Arc::new(Mutex::new(Block::new(block_backends.clone())));
             let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem.clone(), block)));
-            if let Ok(dev) = bus.attach_device(device.clone()) {
+            if let Ok(dev) = bus.attach_device("", device.clone()) {
                 bus.replaceable_info
                     .devices
                     .lock()
@@ -124,7 +155,7 @@ impl Bus {
         for _ in 0..MMIO_REPLACEABLE_NET_NR {
             let net = Arc::new(Mutex::new(Net::new()));
             let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem.clone(), net)));
-            if let Ok(dev) = bus.attach_device(device.clone()) {
+            if let Ok(dev) = bus.attach_device("", device.clone()) {
                 bus.replaceable_info
                     .devices
                     .lock()
@@ -144,6 +175,7 @@ impl Bus {
     ///
     /// # Arguments
     ///
+    /// * `id` - Device id.
     /// * `device` - MMIO device.
     ///
     /// # Errors
@@ -151,6 +183,7 @@ impl Bus {
     /// Return Error if irq number exceed the limit as Arch spec defined.
     pub fn attach_device<T: 'static + MmioDeviceOps>(
         &mut self,
+        id: &str,
         device: Arc<Mutex<T>>,
     ) -> Result<MmioDevice> {
         let device_type = device.lock().unwrap().get_type();
@@ -198,23 +231,49 @@ impl Bus {
 
         let mmio_dev = MmioDevice::new(device, resource);
 
-        self.devices.push(mmio_dev.clone());
+        self.devices.push(MmioDevInfo {
+            id: id.to_string(),
+            device: mmio_dev.clone(),
+        });
 
         Ok(mmio_dev)
     }
 
-    /// Get the information of all devices inserted in bus.
+    /// Get the information of all devices inserted in bus, followed by the
+    /// MMIO slots reserved for hot-plug.
     #[cfg(target_arch = "aarch64")]
     pub fn get_devices_info(&self) -> Vec<DeviceResource> {
         let mut infos = Vec::new();
 
-        for dev in self.devices.iter() {
-            infos.push(dev.get_resource())
+        for info in self.devices.iter() {
+            infos.push(info.device.get_resource())
+        }
+        for index in self.hotplug_slots() {
+            infos.push(Self::slot_resource(index, DeviceType::OTHER));
         }
 
         infos
     }
 
+    /// Indexes of MMIO slots reserved for `hotplug_device`, which follow the
+    /// devices inserted at startup and are announced to guest at startup too.
+    /// Less than `MMIO_HOTPLUG_NR` slots are reserved if irqs run out.
+    fn hotplug_slots(&self) -> std::ops::Range<usize> {
+        let start = self.devices.len();
+        let slot_nr = (IRQ_RANGE.1 - IRQ_RANGE.0) as usize + 1;
+        start..std::cmp::max(start, std::cmp::min(start + MMIO_HOTPLUG_NR, slot_nr))
+    }
+
+    /// Get the resource of MMIO slot `index`.
+    fn slot_resource(index: usize, dev_type: DeviceType) -> DeviceResource {
+        DeviceResource {
+            addr: MMIO_BASE + index as u64 * MMIO_LEN,
+            size: MMIO_LEN,
+            irq: IRQ_RANGE.0 + index as u32,
+            dev_type,
+        }
+    }
+
     /// Get an unused entry of replaceable_info, then fill the fields and mark it as `used`.
     ///
     /// # Arguments
@@ -382,30 +441,415 @@ impl Bus {
         Ok(id.to_string())
     }
 
-    /// Realize all the devices inserted in this Bus.
+    /// Resize the backend of replaceable device specified by `id`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device id.
+    /// * `size` - New size of the backend in bytes.
+    pub fn resize_replaceable_device(&self, id: &str, size: u64) -> Result<()> {
+        let replaceable_devices = self.replaceable_info.devices.lock().unwrap();
+        match replaceable_devices
+            .iter()
+            .find(|device_info| device_info.used && device_info.id == id)
+        {
+            Some(device_info) => device_info.device.resize(size),
+            None => bail!("Failed to find the device {}", id),
+        }
+    }
+
+    /// Set link of replaceable network device specified by `id` up or down,
+    /// return false if no network device `id` is plugged.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device id.
+    /// * `up` - Link is up or down.
+    pub fn set_replaceable_link(&self, id: &str, up: bool) -> Result<bool> {
+        let replaceable_devices = self.replaceable_info.devices.lock().unwrap();
+        match replaceable_devices.iter().find(|device_info| {
+            device_info.used
+                && device_info.id == id
+                && device_info.device.resource.dev_type == DeviceType::NET
+        }) {
+            Some(device_info) => {
+                device_info.device.set_link_up(up)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Take a free MMIO slot reserved for hot-plug for device `id`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device id.
+    /// * `device` - MMIO device.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error if `id` is plugged already, or all reserved slots are
+    /// taken.
+    fn alloc_hotplug_slot<T: 'static + MmioDeviceOps>(
+        &self,
+        id: &str,
+        device: Arc<Mutex<T>>,
+    ) -> Result<MmioDevice> {
+        let mut hotplug_devices = self.hotplug_devices.lock().unwrap();
+        if hotplug_devices.iter().any(|info| info.id == id) {
+            bail!("Device {} is plugged repeatedly", id);
+        }
+
+        let index = match self
+            .hotplug_slots()
+            .find(|index| hotplug_devices.iter().all(|info| info.index != *index))
+        {
+            Some(index) => index,
+            None => bail!("No MMIO slot remains for device {}", id),
+        };
+
+        let dev_type = device.lock().unwrap().get_type();
+        let resource = Self::slot_resource(index, dev_type);
+        let mmio_dev = MmioDevice::new(device, resource);
+        hotplug_devices.push(MmioHotplugDevInfo {
+            id: id.to_string(),
+            index,
+            device: mmio_dev.clone(),
+        });
+
+        Ok(mmio_dev)
+    }
+
+    /// Plug a MMIO device into running VM, and return the resource it takes.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device id.
+    /// * `device` - MMIO device.
+    /// * `vm_fd` - The file descriptor of VM.
+    /// * `sys_mem` - The guest memory to device constructs over.
+    pub fn hotplug_device<T: 'static + MmioDeviceOps>(
+        &self,
+        id: &str,
+        device: Arc<Mutex<T>>,
+        vm_fd: &VmFd,
+        sys_mem: &Arc<AddressSpace>,
+    ) -> Result<DeviceResource> {
+        let mmio_dev = self.alloc_hotplug_slot(id, device)?;
+        if let Err(e) = mmio_dev.plug(vm_fd, sys_mem) {
+            self.hotplug_devices
+                .lock()
+                .unwrap()
+                .retain(|info| info.id != id);
+            return Err(e).chain_err(|| format!("Failed to plug mmio device {}", id));
+        }
+
+        Ok(*mmio_dev.resource)
+    }
+
+    /// Unplug the MMIO device `id` plugged by `hotplug_device`, return false
+    /// if no such device is found.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device id.
+    /// * `vm_fd` - The file descriptor of VM.
+    /// * `sys_mem` - The guest memory to device constructs over.
+    pub fn unplug_device(
+        &self,
+        id: &str,
+        vm_fd: &VmFd,
+        sys_mem: &Arc<AddressSpace>,
+    ) -> Result<bool> {
+        let mut hotplug_devices = self.hotplug_devices.lock().unwrap();
+        let pos = match hotplug_devices.iter().position(|info| info.id == id) {
+            Some(pos) => pos,
+            None => return Ok(false),
+        };
+        let info = hotplug_devices.remove(pos);
+        info.device
+            .unplug(vm_fd, sys_mem)
+            .chain_err(|| format!("Failed to unplug mmio device {}", id))?;
+
+        Ok(true)
+    }
+
+    /// Get configurations of network backends in replaceable_info configs
+    /// arrays, in order of addition.
+    pub fn netdev_configs(&self) -> Vec<NetworkInterfaceConfig> {
+        self.replaceable_info
+            .configs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|config| {
+                config
+                    .dev_config
+                    .as_any()
+                    .downcast_ref::<NetworkInterfaceConfig>()
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Get id of device `index` inserted at startup, or of the device filled
+    /// into it if it's a replaceable one.
+    fn device_id(&self, index: usize) -> String {
+        let info = &self.devices[index];
+        if !info.id.is_empty() {
+            return info.id.clone();
+        }
+        match self.replaceable_info.devices.lock().unwrap().get(index) {
+            Some(dev_info) if dev_info.used => dev_info.id.clone(),
+            _ => format!("<unused replaceable slot {}>", index),
+        }
+    }
+
+    /// Realize all the devices inserted in this Bus, and announce the MMIO
+    /// slots reserved for hot-plug by kernel cmdline on x86_64.
     ///
     /// # Arguments
     ///
     /// * `vm_fd` - The file descriptor of VM.
     /// * `bs` - The boot source of VM.
     /// * `sys_mem` - The guest memory to device constructs over.
+    /// * `rollback` - Bring-up stage which realized devices are registered
+    ///   to, they're unrealized on rollback to release their backends.
     pub fn realize_devices(
         &self,
-        vm_fd: &VmFd,
+        vm_fd: &Arc<VmFd>,
         bs: &Arc<Mutex<BootSource>>,
         sys_mem: &Arc<AddressSpace>,
         #[cfg(target_arch = "x86_64")] sys_io: Arc<AddressSpace>,
+        rollback: &mut Rollback,
     ) -> Result<()> {
-        for device in &self.devices {
-            device.realize(
-                vm_fd,
-                &bs,
-                &sys_mem,
-                #[cfg(target_arch = "x86_64")]
-                sys_io.clone(),
-            )?;
+        for (index, info) in self.devices.iter().enumerate() {
+            let device = &info.device;
+            let id = self.device_id(index);
+            device
+                .realize(
+                    vm_fd,
+                    &bs,
+                    &sys_mem,
+                    #[cfg(target_arch = "x86_64")]
+                    sys_io.clone(),
+                )
+                .chain_err(|| {
+                    format!(
+                        "Failed to realize mmio device {} at 0x{:x}",
+                        id, device.resource.addr
+                    )
+                })?;
+
+            let vm_fd = vm_fd.clone();
+            let mmio_dev = device.clone();
+            let name = format!("mmio device {} at 0x{:x}", id, device.resource.addr);
+            rollback.register(&name, move || {
+                let resource = *mmio_dev.resource;
+                if let Err(e) = mmio_dev.device.lock().unwrap().unrealize(&vm_fd, resource) {
+                    error!("Failed to unrealize mmio device: {}", e);
+                }
+            });
+        }
+
+        // Guest fails to probe the empty slots at boot, and binds the device
+        // after it's plugged.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let cmdline = &mut bs.lock().unwrap().kernel_cmdline;
+            for index in self.hotplug_slots() {
+                let resource = Self::slot_resource(index, DeviceType::OTHER);
+                cmdline.push(Param {
+                    param_type: "virtio_mmio.device".to_string(),
+                    value: format!("{}@0x{:08x}:{}", resource.size, resource.addr, resource.irq),
+                });
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use address_space::{GuestAddress, Region};
+    use kvm_ioctls::Kvm;
+
+    use super::super::DeviceOps;
+    use super::*;
+
+    struct DummyDevice;
+
+    impl DeviceOps for DummyDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> bool {
+            true
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> bool {
+            true
+        }
+    }
+
+    impl MmioDeviceOps for DummyDevice {
+        fn realize(&mut self, _vm_fd: &VmFd, _resource: DeviceResource) -> Result<()> {
+            Ok(())
+        }
+
+        fn get_type(&self) -> DeviceType {
+            DeviceType::OTHER
+        }
+    }
+
+    struct BrokenDevice;
+
+    impl DeviceOps for BrokenDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> bool {
+            true
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> bool {
+            true
+        }
+    }
+
+    impl MmioDeviceOps for BrokenDevice {
+        fn realize(&mut self, _vm_fd: &VmFd, _resource: DeviceResource) -> Result<()> {
+            bail!("Backend is broken")
+        }
+
+        fn get_type(&self) -> DeviceType {
+            DeviceType::OTHER
+        }
+    }
+
+    #[test]
+    fn test_alloc_hotplug_slot() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let bus = Bus::new(sys_mem, &Arc::new(BlockBackendRegistry::new()));
+        let boot_nr = bus.devices.len();
+        let slot_nr = bus.hotplug_slots().len();
+        assert!(slot_nr > 0 && slot_nr <= MMIO_HOTPLUG_NR);
+
+        let dev = bus
+            .alloc_hotplug_slot("dev0", Arc::new(Mutex::new(DummyDevice)))
+            .unwrap();
+        assert_eq!(dev.resource.irq, IRQ_RANGE.0 + boot_nr as u32);
+        assert_eq!(dev.resource.addr, MMIO_BASE + boot_nr as u64 * MMIO_LEN);
+        assert!(bus
+            .alloc_hotplug_slot("dev0", Arc::new(Mutex::new(DummyDevice)))
+            .is_err());
+
+        for i in 1..slot_nr {
+            bus.alloc_hotplug_slot(&format!("dev{}", i), Arc::new(Mutex::new(DummyDevice)))
+                .unwrap();
+        }
+        assert!(bus
+            .alloc_hotplug_slot("dev_more", Arc::new(Mutex::new(DummyDevice)))
+            .is_err());
+
+        // The slot released by unplug is reused.
+        bus.hotplug_devices
+            .lock()
+            .unwrap()
+            .retain(|info| info.id != "dev0");
+        let dev = bus
+            .alloc_hotplug_slot("dev_more", Arc::new(Mutex::new(DummyDevice)))
+            .unwrap();
+        assert_eq!(dev.resource.irq, IRQ_RANGE.0 + boot_nr as u32);
+    }
+
+    #[test]
+    fn test_hotplug_device_no_slot() {
+        let vm_fd = if let Ok(vm_fd) = Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            vm_fd
+        } else {
+            return;
+        };
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let bus = Bus::new(sys_mem.clone(), &Arc::new(BlockBackendRegistry::new()));
+
+        let slot_nr = bus.hotplug_slots().len();
+        for i in 0..slot_nr {
+            let resource = bus
+                .hotplug_device(
+                    &format!("dev{}", i),
+                    Arc::new(Mutex::new(DummyDevice)),
+                    &vm_fd,
+                    &sys_mem,
+                )
+                .unwrap();
+            assert_eq!(resource.irq, IRQ_RANGE.0 + (bus.devices.len() + i) as u32);
+        }
+        let err = bus
+            .hotplug_device(
+                "dev_more",
+                Arc::new(Mutex::new(DummyDevice)),
+                &vm_fd,
+                &sys_mem,
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No MMIO slot remains for device dev_more");
+
+        // The slot is available again after unplug.
+        assert!(bus.unplug_device("dev0", &vm_fd, &sys_mem).unwrap());
+        assert!(!bus.unplug_device("dev0", &vm_fd, &sys_mem).unwrap());
+        bus.hotplug_device(
+            "dev_more",
+            Arc::new(Mutex::new(DummyDevice)),
+            &vm_fd,
+            &sys_mem,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_realize_devices_error() {
+        let vm_fd = if let Ok(vm_fd) = Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Arc::new(vm_fd)
+        } else {
+            return;
+        };
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let sys_io = AddressSpace::new_with_type(
+            Region::init_container_region(1 << 16),
+            address_space::SpaceType::Pio,
+        )
+        .unwrap();
+        let mut bus = Bus {
+            devices: Vec::new(),
+            replaceable_info: MmioReplaceableInfo::new(),
+            hotplug_devices: Mutex::new(Vec::new()),
+        };
+        bus.attach_device("dummy-0", Arc::new(Mutex::new(DummyDevice)))
+            .unwrap();
+        bus.attach_device("broken-0", Arc::new(Mutex::new(BrokenDevice)))
+            .unwrap();
+
+        let bs = Arc::new(Mutex::new(BootSource::default()));
+        let mut rollback = Rollback::new();
+        let err = rollback
+            .stage("realize machine", |rollback| {
+                bus.realize_devices(
+                    &vm_fd,
+                    &bs,
+                    &sys_mem,
+                    #[cfg(target_arch = "x86_64")]
+                    sys_io,
+                    rollback,
+                )
+            })
+            .unwrap_err();
+        let causes: Vec<String> = err.iter().map(|e| e.to_string()).collect();
+        assert_eq!(causes[0], "Failed at bring-up stage 'realize machine'");
+        assert_eq!(
+            causes[1],
+            format!(
+                "Failed to realize mmio device broken-0 at 0x{:x}",
+                MMIO_BASE + MMIO_LEN
+            )
+        );
+        assert!(rollback.is_empty());
+    }
+}
@@ -89,6 +89,8 @@ pub struct MmioDevice {
     region_ops: RegionOps,
     /// The DeviceResource required by this MMIO device.
     resource: Arc<DeviceResource>,
+    /// The region registered into system address space by `plug`.
+    region: Arc<Mutex<Option<Region>>>,
 }
 
 impl MmioDevice {
@@ -116,6 +118,7 @@ impl MmioDevice {
             device,
             region_ops,
             resource: Arc::new(res),
+            region: Arc::new(Mutex::new(None)),
         }
     }
     /// Realize this MMIO device for VM.
@@ -168,6 +171,49 @@ impl MmioDevice {
         Ok(())
     }
 
+    /// Plug this MMIO device into a running VM. Different from `realize`,
+    /// the kernel cmdline is left untouched, as the slot is announced at
+    /// startup by `Bus::realize_devices`, guest has to bind the device by
+    /// itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_fd` - The file descriptor of VM.
+    /// * `sys_mem` - The guest memory to device constructs over.
+    pub fn plug(&self, vm_fd: &VmFd, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+        self.device.lock().unwrap().realize(vm_fd, *self.resource)?;
+
+        let region = Region::init_io_region(self.resource.size, self.region_ops.clone());
+        region.set_ioeventfds(&self.device.lock().unwrap().ioeventfds());
+        if let Err(e) = sys_mem
+            .root()
+            .add_subregion(region.clone(), self.resource.addr)
+        {
+            self.device
+                .lock()
+                .unwrap()
+                .unrealize(vm_fd, *self.resource)
+                .unwrap_or_else(|e| error!("Failed to unrealize mmio device, {}", e));
+            return Err(e.into());
+        }
+        *self.region.lock().unwrap() = Some(region);
+
+        Ok(())
+    }
+
+    /// Unplug this MMIO device plugged by `plug` from VM.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_fd` - The file descriptor of VM.
+    /// * `sys_mem` - The guest memory to device constructs over.
+    pub fn unplug(&self, vm_fd: &VmFd, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+        if let Some(region) = self.region.lock().unwrap().take() {
+            sys_mem.root().delete_subregion(&region)?;
+        }
+        self.device.lock().unwrap().unrealize(vm_fd, *self.resource)
+    }
+
     /// Get the resource requirement of MMIO device.
     #[cfg(target_arch = "aarch64")]
     pub fn get_resource(&self) -> DeviceResource {
@@ -182,6 +228,24 @@ impl MmioDevice {
     pub fn update_config(&self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
         self.device.lock().unwrap().update_config(dev_config)
     }
+
+    /// Resize the backend of MMIO device.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - New size of the backend in bytes.
+    pub fn resize(&self, size: u64) -> Result<()> {
+        self.device.lock().unwrap().resize(size)
+    }
+
+    /// Set link of MMIO device up or down.
+    ///
+    /// # Arguments
+    ///
+    /// * `up` - Link is up or down.
+    pub fn set_link_up(&self, up: bool) -> Result<()> {
+        self.device.lock().unwrap().set_link_up(up)
+    }
 }
 
 /// Trait for MMIO device.
@@ -189,6 +253,11 @@ pub trait MmioDeviceOps: Send + DeviceOps {
     /// Realize this MMIO device for VM.
     fn realize(&mut self, vm_fd: &VmFd, resource: DeviceResource) -> Result<()>;
 
+    /// Unrealize this MMIO device before it's unplugged from VM.
+    fn unrealize(&mut self, _vm_fd: &VmFd, _resource: DeviceResource) -> Result<()> {
+        Ok(())
+    }
+
     /// Get the resource requirement of MMIO device.
     fn get_type(&self) -> DeviceType;
 
@@ -197,6 +266,16 @@ pub trait MmioDeviceOps: Send + DeviceOps {
         bail!("Unsupported to update configuration");
     }
 
+    /// Resize the backend of MMIO device.
+    fn resize(&mut self, _size: u64) -> Result<()> {
+        bail!("Unsupported to resize");
+    }
+
+    /// Set link of MMIO device up or down.
+    fn set_link_up(&mut self, _up: bool) -> Result<()> {
+        bail!("Unsupported to set link");
+    }
+
     /// Get IoEventFds of MMIO device.
     fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
         Vec::new()
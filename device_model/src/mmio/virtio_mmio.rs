@@ -15,6 +15,7 @@ use std::sync::{Arc, Mutex};
 
 use address_space::{AddressRange, AddressSpace, GuestAddress, RegionIoEventFd};
 use byteorder::{ByteOrder, LittleEndian};
+use hypervisor::VmOps;
 use kvm_ioctls::VmFd;
 use machine_manager::config::ConfigCheck;
 use vmm_sys_util::eventfd::EventFd;
@@ -458,8 +459,7 @@ impl DeviceOps for VirtioMmioDevice {
 impl MmioDeviceOps for VirtioMmioDevice {
     /// Realize this MMIO device for VM.
     fn realize(&mut self, vm_fd: &VmFd, resource: DeviceResource) -> Result<()> {
-        vm_fd
-            .register_irqfd(&self.interrupt_evt, resource.irq)
+        VmOps::register_irqfd(vm_fd, &self.interrupt_evt, resource.irq)
             .chain_err(|| "Failed to register irqfd")?;
 
         self.device
@@ -468,6 +468,28 @@ impl MmioDeviceOps for VirtioMmioDevice {
             .realize()
             .chain_err(|| "Failed to realize device for virtio mmio device")?;
 
+        // Count of virtqueues may be given by the configuration of the device.
+        let queue_num = self.device.lock().unwrap().queue_num();
+        if queue_num != self.host_notify_info.events.len() {
+            self.host_notify_info = HostNotifyInfo::new(queue_num);
+            self.common_config = VirtioMmioCommonConfig::new(&self.device);
+        }
+
+        Ok(())
+    }
+
+    /// Unrealize this MMIO device before it's unplugged from VM.
+    fn unrealize(&mut self, vm_fd: &VmFd, resource: DeviceResource) -> Result<()> {
+        self.device
+            .lock()
+            .unwrap()
+            .unrealize()
+            .chain_err(|| "Failed to unrealize device for virtio mmio device")?;
+        self.device_activated = false;
+
+        VmOps::unregister_irqfd(vm_fd, &self.interrupt_evt, resource.irq)
+            .chain_err(|| "Failed to unregister irqfd")?;
+
         Ok(())
     }
 
@@ -490,6 +512,18 @@ impl MmioDeviceOps for VirtioMmioDevice {
         Ok(())
     }
 
+    /// Resize the backend of MMIO device.
+    fn resize(&mut self, size: u64) -> Result<()> {
+        self.device.lock().unwrap().resize(size)?;
+        Ok(())
+    }
+
+    /// Set link of MMIO device up or down.
+    fn set_link_up(&mut self, up: bool) -> Result<()> {
+        self.device.lock().unwrap().set_link_up(up)?;
+        Ok(())
+    }
+
     fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
         let mut ret = Vec::new();
         for (index, eventfd) in self.host_notify_info.events.iter().enumerate() {
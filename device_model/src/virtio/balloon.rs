@@ -0,0 +1,408 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::io::Write;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress};
+use machine_manager::config::BalloonConfig;
+use machine_manager::machine::BalloonHandle;
+#[cfg(feature = "qmp")]
+use machine_manager::{qmp::qmp_schema as schema, qmp::QmpChannel};
+use util::byte_code::ByteCode;
+use util::epoll_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::num_ops::{read_u32, write_u32};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+use super::super::micro_vm::main_loop::MainLoop;
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::{
+    Queue, VirtioDevice, VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_F_VERSION_1,
+    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_BALLOON,
+};
+
+/// Number of virtqueues, inflate queue and deflate queue.
+const QUEUE_NUM_BALLOON: usize = 2;
+/// Size of each virtqueue.
+const QUEUE_SIZE_BALLOON: u16 = 256;
+/// Pages given up by guest are always 4K in size, refer to Virtio Spec.
+const BALLOON_PAGE_SHIFT: u32 = 12;
+const BALLOON_PAGE_SIZE: u64 = 1 << BALLOON_PAGE_SHIFT;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioBalloonConfig {
+    /// Number of pages host wants guest to give up.
+    num_pages: u32,
+    /// Number of pages guest has given up.
+    actual: u32,
+}
+
+impl ByteCode for VirtioBalloonConfig {}
+
+/// State of balloon device shared with QMP, through which target size of
+/// guest memory is set.
+struct BalloonState {
+    /// Virtio configuration.
+    config: Mutex<VirtioBalloonConfig>,
+    /// Size of guest RAM in bytes.
+    ram_size: u64,
+    /// Eventfd for triggering interrupts and state of the interrupt,
+    /// `None` until the device is activated.
+    interrupt: Mutex<Option<(EventFd, Arc<AtomicU32>)>>,
+}
+
+impl BalloonHandle for BalloonState {
+    fn set_target(&self, size: u64) {
+        let num_pages = (self.ram_size.saturating_sub(size) >> BALLOON_PAGE_SHIFT) as u32;
+        self.config.lock().unwrap().num_pages = num_pages;
+
+        if let Some((interrupt_evt, interrupt_status)) = self.interrupt.lock().unwrap().as_ref() {
+            interrupt_status.fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::SeqCst);
+            if interrupt_evt.write(1).is_err() {
+                error!("Failed to write interrupt eventfd for balloon");
+            }
+        }
+    }
+
+    fn actual(&self) -> u64 {
+        let actual = u64::from(self.config.lock().unwrap().actual) << BALLOON_PAGE_SHIFT;
+        self.ram_size.saturating_sub(actual)
+    }
+}
+
+/// Balloon device's IO handle context.
+struct BalloonIoHandler {
+    /// Virtqueue where guest gives up pages.
+    inflate_queue: Arc<Mutex<Queue>>,
+    /// Eventfd of inflate_queue.
+    inflate_evt: EventFd,
+    /// Virtqueue where guest takes back pages.
+    deflate_queue: Arc<Mutex<Queue>>,
+    /// Eventfd of deflate_queue.
+    deflate_evt: EventFd,
+    /// The address space to which the balloon device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+}
+
+impl BalloonIoHandler {
+    /// Handle the page frame numbers in requests of `queue`. Pages given up
+    /// by guest are freed back to host, while pages taken back by guest
+    /// need nothing to do as they are faulted in when accessed.
+    fn process_queue(&self, queue: &Arc<Mutex<Queue>>, inflate: bool) -> Result<()> {
+        let mut locked_queue = queue.lock().unwrap();
+        let mut handled = false;
+
+        while let Ok(elem) = locked_queue
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+        {
+            if inflate {
+                for iov in elem.out_iovec.iter() {
+                    let pfn_num = iov.len as u64 / size_of::<u32>() as u64;
+                    for i in 0..pfn_num {
+                        let addr = iov.addr.0 + i * size_of::<u32>() as u64;
+                        let pfn = self
+                            .mem_space
+                            .read_object::<u32>(GuestAddress(addr))
+                            .chain_err(|| "Failed to read pfn of balloon")?;
+                        let gpa = GuestAddress(u64::from(pfn) << BALLOON_PAGE_SHIFT);
+                        if let Err(e) = self.mem_space.discard_range(gpa, BALLOON_PAGE_SIZE) {
+                            error!("Failed to free page 0x{:x} of balloon, {}", gpa.0, e);
+                        }
+                    }
+                }
+            }
+
+            locked_queue
+                .vring
+                .add_used(&self.mem_space, elem.index, 0)
+                .chain_err(|| format!("Balloon: Failed to add used ring {}", elem.index))?;
+            handled = true;
+        }
+
+        if handled {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+            self.interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for BalloonIoHandler {
+    fn internal_notifiers(balloon_io: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_balloon_io = balloon_io.lock().unwrap();
+
+        for (fd, inflate) in &[
+            (locked_balloon_io.inflate_evt.as_raw_fd(), true),
+            (locked_balloon_io.deflate_evt.as_raw_fd(), false),
+        ] {
+            let cloned_balloon_io = balloon_io.clone();
+            let inflate = *inflate;
+            let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                let locked_balloon_io = cloned_balloon_io.lock().unwrap();
+                let queue = if inflate {
+                    &locked_balloon_io.inflate_queue
+                } else {
+                    &locked_balloon_io.deflate_queue
+                };
+                locked_balloon_io
+                    .process_queue(queue, inflate)
+                    .unwrap_or_else(|e| error!("Failed to handle balloon request, {}", e));
+                None
+            });
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                *fd,
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            ));
+        }
+
+        notifiers
+    }
+}
+
+/// Virtio balloon device structure.
+pub struct Balloon {
+    /// Configuration of the balloon device.
+    balloon_cfg: BalloonConfig,
+    /// State shared with QMP.
+    state: Arc<BalloonState>,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Eventfds handled in main loop since the device is activated.
+    deactivate_fds: Vec<RawFd>,
+}
+
+impl Balloon {
+    /// Create a virtio-balloon device.
+    ///
+    /// # Arguments
+    ///
+    /// * `balloon_cfg` - Device configuration set by user.
+    /// * `ram_size` - Size of guest RAM in bytes.
+    pub fn new(balloon_cfg: BalloonConfig, ram_size: u64) -> Self {
+        Balloon {
+            balloon_cfg,
+            state: Arc::new(BalloonState {
+                config: Mutex::new(VirtioBalloonConfig::default()),
+                ram_size,
+                interrupt: Mutex::new(None),
+            }),
+            device_features: 0_u64,
+            driver_features: 0_u64,
+            deactivate_fds: Vec::new(),
+        }
+    }
+
+    /// Get the handle to adjust guest memory through this device.
+    pub fn handle(&self) -> Arc<dyn BalloonHandle> {
+        self.state.clone()
+    }
+}
+
+impl VirtioDevice for Balloon {
+    /// Realize virtio balloon device.
+    fn realize(&mut self) -> Result<()> {
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1;
+        if self.balloon_cfg.deflate_on_oom {
+            self.device_features |= 1_u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_BALLOON
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_BALLOON
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_BALLOON
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut v = write_u32(value, page);
+        let unrequested_features = v & !self.device_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request with unknown feature for balloon.");
+            v &= !unrequested_features;
+        }
+        self.driver_features |= v;
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config = *self.state.config.lock().unwrap();
+        let config_slice = config.as_bytes();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write data to config from guest, only `actual` is writable.
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut config = self.state.config.lock().unwrap();
+        let old_actual = config.actual;
+        let config_slice = config.as_mut_bytes();
+        let config_len = config_slice.len() as u64;
+        let actual_offset = size_of::<u32>() as u64;
+        if offset < actual_offset || offset + data.len() as u64 > config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
+        }
+        config_slice[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+
+        if config.actual != old_actual {
+            drop(config);
+            #[cfg(feature = "qmp")]
+            {
+                let balloon_change = schema::BALLOON_CHANGE {
+                    actual: self.state.actual(),
+                };
+                event!(BALLOON_CHANGE; balloon_change);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicU32>,
+        mut queues: Vec<Arc<Mutex<Queue>>>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        *self.state.interrupt.lock().unwrap() =
+            Some((interrupt_evt.try_clone()?, interrupt_status.clone()));
+
+        let handler = BalloonIoHandler {
+            inflate_queue: queues.remove(0),
+            inflate_evt: queue_evts.remove(0),
+            deflate_queue: queues.remove(0),
+            deflate_evt: queue_evts.remove(0),
+            mem_space,
+            interrupt_evt: interrupt_evt.try_clone()?,
+            interrupt_status,
+            driver_features: self.driver_features,
+        };
+        self.deactivate_fds = vec![
+            handler.inflate_evt.as_raw_fd(),
+            handler.deflate_evt.as_raw_fd(),
+        ];
+
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
+            Mutex::new(handler),
+        )))?;
+
+        Ok(())
+    }
+
+    /// Stop handling the virtqueues of balloon device.
+    fn unrealize(&mut self) -> Result<()> {
+        *self.state.interrupt.lock().unwrap() = None;
+        let notifiers = self
+            .deactivate_fds
+            .drain(..)
+            .map(|fd| EventNotifier::new(NotifierOperation::Delete, fd, None, EventSet::IN, vec![]))
+            .collect();
+        MainLoop::update_event(notifiers)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM_SIZE: u64 = 1 << 30;
+
+    #[test]
+    fn test_balloon_config() {
+        #[cfg(feature = "qmp")]
+        QmpChannel::object_init();
+        let mut balloon = Balloon::new(
+            BalloonConfig {
+                deflate_on_oom: true,
+                free_page_hinting: false,
+            },
+            RAM_SIZE,
+        );
+        balloon.realize().unwrap();
+        assert_eq!(
+            balloon.get_device_features(0),
+            1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM
+        );
+        assert_eq!(balloon.get_device_features(1), 1);
+
+        let handle = balloon.handle();
+        assert_eq!(handle.actual(), RAM_SIZE);
+
+        // Host asks guest to give up 256M.
+        handle.set_target(RAM_SIZE - (256 << 20));
+        let mut data = [0_u8; 4];
+        balloon.read_config(0, &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), (256 << 20) >> BALLOON_PAGE_SHIFT);
+
+        // Guest reports the pages given up, only `actual` is writable.
+        let actual = ((128 << 20) >> BALLOON_PAGE_SHIFT) as u32;
+        assert!(balloon.write_config(0, &actual.to_le_bytes()).is_err());
+        assert!(balloon.write_config(8, &actual.to_le_bytes()).is_err());
+        balloon.write_config(4, &actual.to_le_bytes()).unwrap();
+        assert_eq!(handle.actual(), RAM_SIZE - (128 << 20));
+    }
+}
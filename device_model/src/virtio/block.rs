@@ -20,23 +20,34 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use address_space::{AddressSpace, GuestAddress};
-use machine_manager::config::{ConfigCheck, DriveConfig};
-use util::aio::{Aio, AioCb, AioCompleteFunc, IoCmd, Iovec};
+use machine_manager::block_backend::{
+    BlockBackendHandle, BlockBackendInfo, BlockBackendRegistry, BlockIoStats,
+};
+use machine_manager::config::{
+    AioEngine, ConfigCheck, DetectZeroes, DiscardMode, DriveConfig, ImageFormat,
+};
+use util::aio::{
+    raw_discard, raw_write_zeroes, Aio, AioCb, AioCompleteFunc, IoCmd, Iovec, LibaioContext,
+};
+use util::block_driver::{BlockDriver, Qcow2Driver};
 use util::byte_code::ByteCode;
 use util::epoll_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
 use util::num_ops::{read_u32, write_u32};
-use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, timerfd::TimerFd};
 
 use super::super::micro_vm::main_loop::MainLoop;
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    Element, Queue, VirtioDevice, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
-    VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_FLUSH,
-    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_F_RING_EVENT_IDX,
+    Element, Queue, VirtioDevice, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP, VIRTIO_F_RING_EVENT_IDX,
     VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
     VIRTIO_TYPE_BLOCK,
 };
@@ -47,6 +58,12 @@ const QUEUE_NUM_BLK: usize = 1;
 const QUEUE_SIZE_BLK: u16 = 256;
 /// Size of configuration space of the virtio block device.
 const CONFIG_SPACE_SIZE: usize = 16;
+/// Size of configuration space with discard and write zeroes fields.
+const CONFIG_SPACE_SIZE_DISCARD: usize = 60;
+/// Maximum number of sectors in one discard or write zeroes segment.
+const MAX_DISCARD_SECTORS: u32 = 0x003f_ffff;
+/// Maximum number of segments in one discard or write zeroes request.
+const MAX_DISCARD_SEG: u32 = 1;
 /// Used to compute the number of sectors.
 const SECTOR_SHIFT: u8 = 9;
 /// Size of a sector of the block device.
@@ -54,7 +71,16 @@ const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 /// Size of the dummy block device.
 const DUMMY_IMG_SIZE: u64 = 0;
 
-type SenderConfig = (Option<File>, u64, Option<String>, bool);
+type SenderConfig = (
+    Option<File>,
+    Option<Box<dyn BlockDriver>>,
+    u64,
+    Option<String>,
+    AioEngine,
+    DiscardMode,
+    DetectZeroes,
+    Option<BlockBackendHandle>,
+);
 type VirtioBlockInterrupt = Box<dyn Fn(u32) -> Result<()> + Send + Sync>;
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
@@ -102,7 +128,12 @@ impl RequestOutHeader {
     /// Return true if the request type is valid.
     pub fn is_valid(&self) -> bool {
         match self.request_type {
-            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_FLUSH | VIRTIO_BLK_T_GET_ID => true,
+            VIRTIO_BLK_T_IN
+            | VIRTIO_BLK_T_OUT
+            | VIRTIO_BLK_T_FLUSH
+            | VIRTIO_BLK_T_GET_ID
+            | VIRTIO_BLK_T_DISCARD
+            | VIRTIO_BLK_T_WRITE_ZEROES => true,
             _ => {
                 error!("request type {} is not supported \n", self.request_type);
                 false
@@ -113,6 +144,20 @@ impl RequestOutHeader {
 
 impl ByteCode for RequestOutHeader {}
 
+/// The segment of virtio block's discard and write zeroes request.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardWriteZeroesSeg {
+    /// The first sector of the range.
+    sector: u64,
+    /// The number of sectors of the range.
+    num_sectors: u32,
+    /// Flags of the range, only `VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP` is defined.
+    flags: u32,
+}
+
+impl ByteCode for DiscardWriteZeroesSeg {}
+
 /// The aio control block.
 #[derive(Clone)]
 pub struct AioCompleteCb {
@@ -130,6 +175,8 @@ pub struct AioCompleteCb {
     pub interrupt_cb: Option<Arc<VirtioBlockInterrupt>>,
     /// Bit mask of features negotiated by the backend and the frontend.
     pub driver_features: u64,
+    /// IO statistics of the block backend.
+    pub stats: Option<Arc<BlockIoStats>>,
 }
 
 impl AioCompleteCb {
@@ -143,6 +190,8 @@ impl AioCompleteCb {
     /// * `req_status_addr` - The memory address where stores the result of handling the request.
     /// * `interrupt_cb` - Callback for triggering an interrupt.
     /// * `driver_features` - Bit mask of features negotiated by the backend and the frontend.
+    /// * `stats` - IO statistics of the block backend.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         queue: Arc<Mutex<Queue>>,
         mem_space: Arc<AddressSpace>,
@@ -151,6 +200,7 @@ impl AioCompleteCb {
         req_status_addr: GuestAddress,
         interrupt_cb: Option<Arc<VirtioBlockInterrupt>>,
         driver_features: u64,
+        stats: Option<Arc<BlockIoStats>>,
     ) -> Self {
         AioCompleteCb {
             queue,
@@ -160,6 +210,7 @@ impl AioCompleteCb {
             req_status_addr,
             interrupt_cb,
             driver_features,
+            stats,
         }
     }
 }
@@ -177,6 +228,8 @@ struct Request {
     /// The address of header(in_header) which is writable, and this header
     /// should be written with the result of handling the request.
     in_header: GuestAddress,
+    /// Write request of all zeroes detected, which is turned into zeroing the range.
+    zero_write: bool,
 }
 
 impl Request {
@@ -220,6 +273,7 @@ impl Request {
             iovec: Vec::with_capacity(elem.desc_num as usize),
             data_len: 0,
             in_header: in_iov_elem.addr,
+            zero_write: false,
         };
 
         match out_header.request_type {
@@ -228,27 +282,33 @@ impl Request {
                     if index == elem.in_iovec.len() - 1 {
                         break;
                     }
-                    if let Some(hva) = mem_space.get_host_address(elem_iov.addr) {
-                        let iov = Iovec {
-                            iov_base: hva,
-                            iov_len: u64::from(elem_iov.len),
-                        };
-                        request.iovec.push(iov);
+                    if let Ok(segments) =
+                        mem_space.get_address_map(elem_iov.addr, u64::from(elem_iov.len))
+                    {
+                        for (hva, len) in segments {
+                            request.iovec.push(Iovec {
+                                iov_base: hva,
+                                iov_len: len,
+                            });
+                        }
                         request.data_len += u64::from(elem_iov.len);
                     }
                 }
             }
-            VIRTIO_BLK_T_OUT => {
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
                 for (index, elem_iov) in elem.out_iovec.iter().enumerate() {
                     if index == 0 {
                         continue;
                     }
-                    if let Some(hva) = mem_space.get_host_address(elem_iov.addr) {
-                        let iov = Iovec {
-                            iov_base: hva,
-                            iov_len: u64::from(elem_iov.len),
-                        };
-                        request.iovec.push(iov);
+                    if let Ok(segments) =
+                        mem_space.get_address_map(elem_iov.addr, u64::from(elem_iov.len))
+                    {
+                        for (hva, len) in segments {
+                            request.iovec.push(Iovec {
+                                iov_base: hva,
+                                iov_len: len,
+                            });
+                        }
                         request.data_len += u64::from(elem_iov.len);
                     }
                 }
@@ -259,18 +319,168 @@ impl Request {
         Ok(request)
     }
 
+    /// Return true if data of the request are all zeroes.
+    fn is_all_zeroes(&self) -> bool {
+        self.iovec.iter().all(|iov| {
+            // Safe as the iovec is mapped from guest memory when the request is built.
+            let data = unsafe {
+                std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize)
+            };
+            data.iter().all(|b| *b == 0)
+        })
+    }
+
+    /// Read segments of discard or write zeroes request.
+    fn discard_segments(&self) -> Result<Vec<DiscardWriteZeroesSeg>> {
+        let seg_size = size_of::<DiscardWriteZeroesSeg>() as u64;
+        if self.data_len == 0
+            || self.data_len % seg_size != 0
+            || self.data_len / seg_size > u64::from(MAX_DISCARD_SEG)
+        {
+            bail!(
+                "Invalid discard/write zeroes request: length {}",
+                self.data_len
+            );
+        }
+
+        let mut data = Vec::with_capacity(self.data_len as usize);
+        for iov in self.iovec.iter() {
+            // Safe as the iovec is mapped from guest memory when the request is built.
+            let buf = unsafe {
+                std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize)
+            };
+            data.extend_from_slice(buf);
+        }
+
+        let segments = data
+            .chunks(seg_size as usize)
+            .map(|chunk| {
+                let mut seg = DiscardWriteZeroesSeg::default();
+                seg.as_mut_bytes().copy_from_slice(chunk);
+                seg
+            })
+            .collect();
+        Ok(segments)
+    }
+
+    /// Execute discard or write zeroes request synchronously, return status of the request.
+    fn execute_discard_write_zeroes(
+        &self,
+        disk: &File,
+        disk_sectors: u64,
+        discard: DiscardMode,
+    ) -> u32 {
+        let segments = match self.discard_segments() {
+            Ok(segments) => segments,
+            Err(e) => {
+                error!("{}", e);
+                return VIRTIO_BLK_S_IOERR;
+            }
+        };
+
+        for seg in segments.iter() {
+            let valid_flags = match self.out_header.request_type {
+                VIRTIO_BLK_T_WRITE_ZEROES => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                _ => 0,
+            };
+            if seg.flags & !valid_flags != 0 {
+                return VIRTIO_BLK_S_UNSUPP;
+            }
+            if seg
+                .sector
+                .checked_add(u64::from(seg.num_sectors))
+                .filter(|end| *end <= disk_sectors)
+                .is_none()
+            {
+                error!(
+                    "discard/write zeroes range {}+{} invalid, disk sector {}",
+                    seg.sector, seg.num_sectors, disk_sectors
+                );
+                return VIRTIO_BLK_S_IOERR;
+            }
+
+            let offset = (seg.sector << SECTOR_SHIFT) as usize;
+            let size = u64::from(seg.num_sectors) << SECTOR_SHIFT;
+            let ret = match self.out_header.request_type {
+                VIRTIO_BLK_T_DISCARD if discard == DiscardMode::Unmap => {
+                    raw_discard(disk.as_raw_fd(), offset, size)
+                }
+                // Discard is only a hint, ignoring it is correct.
+                VIRTIO_BLK_T_DISCARD => Ok(0),
+                _ if seg.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+                    && discard == DiscardMode::Unmap =>
+                {
+                    raw_discard(disk.as_raw_fd(), offset, size)
+                }
+                _ => raw_write_zeroes(disk.as_raw_fd(), offset, size),
+            };
+            if let Err(e) = ret {
+                error!("{}", e);
+                return VIRTIO_BLK_S_IOERR;
+            }
+        }
+
+        VIRTIO_BLK_S_OK
+    }
+
+    /// Zero the range of detected write of all zeroes, return false if the range
+    /// can't be zeroed and the data should be written instead.
+    fn execute_zero_write(&self, disk: &File, detect_zeroes: DetectZeroes) -> bool {
+        let offset = (self.out_header.sector << SECTOR_SHIFT) as usize;
+        let ret = if detect_zeroes == DetectZeroes::Unmap {
+            raw_discard(disk.as_raw_fd(), offset, self.data_len)
+        } else {
+            raw_write_zeroes(disk.as_raw_fd(), offset, self.data_len)
+        };
+        ret.is_ok()
+    }
+
+    /// Execute the request by format driver of the image synchronously, return
+    /// status of the request, or None if the request isn't IO on the image.
+    fn execute_driver(
+        &self,
+        driver: &mut dyn BlockDriver,
+        stats: &Option<Arc<BlockIoStats>>,
+    ) -> Option<u32> {
+        let offset = self.out_header.sector << SECTOR_SHIFT;
+        let ret = match self.out_header.request_type {
+            VIRTIO_BLK_T_IN => driver.read_vectored(&self.iovec, offset),
+            VIRTIO_BLK_T_OUT => driver.write_vectored(&self.iovec, offset),
+            VIRTIO_BLK_T_FLUSH => driver.flush(),
+            _ => return None,
+        };
+
+        if let Err(e) = ret {
+            error!("Failed to execute block request by format driver, {}", e);
+            return Some(VIRTIO_BLK_S_IOERR);
+        }
+        if let Some(stats) = stats {
+            match self.out_header.request_type {
+                VIRTIO_BLK_T_IN => stats.account_read(self.data_len),
+                VIRTIO_BLK_T_OUT => stats.account_write(self.data_len),
+                _ => stats.account_flush(),
+            }
+        }
+        Some(VIRTIO_BLK_S_OK)
+    }
+
+    /// Execute the request, return status of the request if it's completed
+    /// synchronously, or None if it's completed by the aio context.
     #[allow(clippy::too_many_arguments)]
     #[allow(clippy::borrowed_box)]
     fn execute(
         &self,
         aio: &mut Box<Aio<AioCompleteCb>>,
         disk: &mut File,
+        disk_driver: &mut Option<Box<dyn BlockDriver>>,
         disk_sectors: u64,
         serial_num: &Option<String>,
-        direct: bool,
+        aio_engine: AioEngine,
+        discard: DiscardMode,
+        detect_zeroes: DetectZeroes,
         last_aio: bool,
         iocompletecb: AioCompleteCb,
-    ) -> Result<u32> {
+    ) -> Result<Option<u32>> {
         let mut top: u64 = self.data_len / SECTOR_SIZE;
         if self.data_len % SECTOR_SIZE != 0 {
             top += 1;
@@ -284,6 +494,12 @@ impl Request {
                 )
             })?;
 
+        if let Some(driver) = disk_driver.as_mut() {
+            if let Some(status) = self.execute_driver(&mut **driver, &iocompletecb.stats) {
+                return Ok(Some(status));
+            }
+        }
+
         let mut aiocb = AioCb {
             last_aio,
             file_fd: disk.as_raw_fd(),
@@ -306,15 +522,21 @@ impl Request {
         match self.out_header.request_type {
             VIRTIO_BLK_T_IN => {
                 aiocb.opcode = IoCmd::PREADV;
-                if direct {
+                if aio_engine == AioEngine::Native {
                     (*aio).as_mut().rw_aio(aiocb)?;
                 } else {
                     (*aio).as_mut().rw_sync(aiocb)?;
                 }
             }
             VIRTIO_BLK_T_OUT => {
+                if self.zero_write && self.execute_zero_write(disk, detect_zeroes) {
+                    if let Some(stats) = &aiocb.iocompletecb.stats {
+                        stats.account_write(self.data_len);
+                    }
+                    return Ok(Some(VIRTIO_BLK_S_OK));
+                }
                 aiocb.opcode = IoCmd::PWRITEV;
-                if direct {
+                if aio_engine == AioEngine::Native {
                     (*aio).as_mut().rw_aio(aiocb)?;
                 } else {
                     (*aio).as_mut().rw_sync(aiocb)?;
@@ -341,11 +563,21 @@ impl Request {
                     }
                 }
 
-                return Ok(1);
+                return Ok(Some(VIRTIO_BLK_S_OK));
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if disk_driver.is_some() => {
+                return Ok(Some(VIRTIO_BLK_S_UNSUPP));
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                return Ok(Some(self.execute_discard_write_zeroes(
+                    disk,
+                    disk_sectors,
+                    discard,
+                )));
             }
             _ => bail!("The type of request is not supported"),
         };
-        Ok(0)
+        Ok(None)
     }
 }
 
@@ -359,16 +591,28 @@ pub struct BlockIoHandler {
     pub mem_space: Arc<AddressSpace>,
     /// The image file opened by the block device.
     pub disk_image: Option<File>,
+    /// Format driver of the image, None for raw image.
+    pub disk_driver: Option<Box<dyn BlockDriver>>,
     /// The number of sectors of the disk image.
     pub disk_sectors: u64,
     /// Serial number of the block device.
     pub serial_num: Option<String>,
-    /// if use direct access io.
-    pub direct: bool,
+    /// Host IO engine.
+    pub aio_engine: AioEngine,
+    /// Handling of discard requests.
+    pub discard: DiscardMode,
+    /// Detection of writes of all zeroes.
+    pub detect_zeroes: DetectZeroes,
     /// Aio context.
     pub aio: Option<Box<Aio<AioCompleteCb>>>,
     /// Bit mask of features negotiated by the backend and the frontend.
     pub driver_features: u64,
+    /// Statistics and throttling of the block backend.
+    pub backend: Option<BlockBackendHandle>,
+    /// IO requests deferred by throttling, in order of the virtqueue.
+    throttled_reqs: Vec<Request>,
+    /// Timer to resume IO requests deferred by throttling.
+    throttle_timer: TimerFd,
     /// The receiving half of Rust's channel to receive the image file.
     receiver: Receiver<SenderConfig>,
     /// Eventfd for config space update.
@@ -380,10 +624,11 @@ pub struct BlockIoHandler {
 impl BlockIoHandler {
     /// Build IO requests if there are elements in virtqueue needed to be finished,
     /// and execute them. If required, an interrupt is sent to the guest.
+    ///
+    /// Requests deferred by throttling before are executed first, and requests
+    /// out of throttling budget are deferred until the throttle timer expires.
     pub fn process_queue(&mut self) -> Result<()> {
-        let mut req_queue = Vec::new();
-        let mut req_index = 0;
-        let mut last_aio_req_index = 0;
+        let mut req_queue = std::mem::take(&mut self.throttled_reqs);
         let mut need_interrupt = false;
 
         while let Ok(elem) = self
@@ -394,15 +639,13 @@ impl BlockIoHandler {
             .pop_avail(&self.mem_space, self.driver_features)
         {
             match Request::new(&self.mem_space, &elem) {
-                Ok(req) => {
-                    match req.out_header.request_type {
-                        VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
-                            last_aio_req_index = req_index;
-                        }
-                        _ => {}
+                Ok(mut req) => {
+                    if req.out_header.request_type == VIRTIO_BLK_T_OUT
+                        && self.detect_zeroes != DetectZeroes::Off
+                    {
+                        req.zero_write = req.is_all_zeroes();
                     }
                     req_queue.push(req);
-                    req_index += 1;
                 }
                 Err(e) => {
                     error!("failed to create request, err {:#?}", e);
@@ -411,8 +654,20 @@ impl BlockIoHandler {
             };
         }
 
+        if self.disk_image.is_some() {
+            self.throttle_requests(&mut req_queue)?;
+        }
+        let last_aio_req_index = req_queue
+            .iter()
+            .rposition(|req| match req.out_header.request_type {
+                VIRTIO_BLK_T_IN => true,
+                VIRTIO_BLK_T_OUT => !req.zero_write,
+                _ => false,
+            })
+            .unwrap_or(0);
+
         if let Some(disk_img) = self.disk_image.as_mut() {
-            req_index = 0;
+            let mut req_index = 0;
             for req in req_queue.iter() {
                 if let Some(ref mut aio) = self.aio {
                     let rw_len = match req.out_header.request_type {
@@ -429,26 +684,29 @@ impl BlockIoHandler {
                         req.in_header,
                         Some(self.interrupt_cb.clone()),
                         self.driver_features,
+                        self.backend.as_ref().map(|backend| backend.stats.clone()),
                     );
 
                     match req.execute(
                         aio,
                         disk_img,
+                        &mut self.disk_driver,
                         self.disk_sectors,
                         &self.serial_num,
-                        self.direct,
+                        self.aio_engine,
+                        self.discard,
+                        self.detect_zeroes,
                         last_aio_req_index == req_index,
                         aiocompletecb,
                     ) {
                         Ok(v) => {
-                            if v == 1 {
-                                // get device id
-                                self.mem_space
-                                    .write_object(&VIRTIO_BLK_S_OK, req.in_header)?;
+                            if let Some(status) = v {
+                                // completed synchronously
+                                self.mem_space.write_object(&status, req.in_header)?;
                                 self.queue.lock().unwrap().vring.add_used(
                                     &self.mem_space,
                                     req.desc_index,
-                                    1,
+                                    cmp::max(rw_len, 1),
                                 )?;
 
                                 if self
@@ -487,6 +745,38 @@ impl BlockIoHandler {
         Ok(())
     }
 
+    /// Admit read and write requests in order within throttling budget of the
+    /// block backend. Requests from the first one out of budget are moved to
+    /// `throttled_reqs`, and the throttle timer is armed to resume them.
+    fn throttle_requests(&mut self, req_queue: &mut Vec<Request>) -> Result<()> {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut deferred = None;
+        for (index, req) in req_queue.iter().enumerate() {
+            let write = match req.out_header.request_type {
+                VIRTIO_BLK_T_IN => false,
+                VIRTIO_BLK_T_OUT => true,
+                _ => continue,
+            };
+            if let Some(wait) = backend.throttle.consume(write, req.data_len, now) {
+                deferred = Some((index, wait));
+                break;
+            }
+        }
+
+        if let Some((index, wait)) = deferred {
+            self.throttled_reqs = req_queue.split_off(index);
+            self.throttle_timer
+                .reset(wait, None)
+                .chain_err(|| "Failed to arm throttle timer of block IO")?;
+        }
+        Ok(())
+    }
+
     /// Build an aio context.
     pub fn build_aio(&self) -> Result<Box<Aio<AioCompleteCb>>> {
         let complete_func = Arc::new(Box::new(move |aiocb: &AioCb<AioCompleteCb>, ret: i64| {
@@ -496,6 +786,11 @@ impl BlockIoHandler {
                 i64::from(VIRTIO_BLK_S_OK)
             };
             let complete_cb = &aiocb.iocompletecb;
+            if ret >= 0 {
+                if let Some(stats) = &complete_cb.stats {
+                    account_block_io(stats, aiocb);
+                }
+            }
 
             if complete_cb
                 .mem_space
@@ -547,17 +842,33 @@ impl BlockIoHandler {
 
     fn update_evt_handler(&mut self) {
         match self.receiver.recv() {
-            Ok((image, disk_sectors, serial_num, direct)) => {
+            Ok((
+                image,
+                disk_driver,
+                disk_sectors,
+                serial_num,
+                aio_engine,
+                discard,
+                detect_zeroes,
+                backend,
+            )) => {
                 self.disk_sectors = disk_sectors;
                 self.disk_image = image;
+                self.disk_driver = disk_driver;
                 self.serial_num = serial_num;
-                self.direct = direct;
+                self.aio_engine = aio_engine;
+                self.discard = discard;
+                self.detect_zeroes = detect_zeroes;
+                self.backend = backend;
             }
             Err(_) => {
                 self.disk_sectors = 0;
                 self.disk_image = None;
+                self.disk_driver = None;
                 self.serial_num = None;
-                self.direct = true;
+                self.aio_engine = AioEngine::Native;
+                self.discard = DiscardMode::default();
+                self.detect_zeroes = DetectZeroes::default();
             }
         };
 
@@ -566,6 +877,34 @@ impl BlockIoHandler {
     }
 }
 
+/// Account a completed IO request to statistics of the block backend.
+fn account_block_io(stats: &BlockIoStats, aiocb: &AioCb<AioCompleteCb>) {
+    let bytes = || aiocb.iovec.iter().map(|iov| iov.iov_len).sum::<u64>();
+    match aiocb.opcode {
+        IoCmd::PREADV => stats.account_read(bytes()),
+        IoCmd::PWRITEV => stats.account_write(bytes()),
+        IoCmd::FDSYNC => stats.account_flush(),
+        _ => {}
+    }
+}
+
+/// Check that host IO engine is available on host.
+///
+/// # Errors
+///
+/// Return Error if host lacks the engine, io_uring isn't supported yet.
+pub fn check_aio_engine(engine: AioEngine) -> Result<()> {
+    match engine {
+        AioEngine::Threads => Ok(()),
+        AioEngine::Native => {
+            LibaioContext::new(1)
+                .chain_err(|| ErrorKind::AioEngineUnavailable(engine.to_string()))?;
+            Ok(())
+        }
+        AioEngine::IoUring => Err(ErrorKind::AioEngineUnavailable(engine.to_string()).into()),
+    }
+}
+
 fn build_event_notifier(fd: RawFd, handler: Box<NotifierCallback>) -> EventNotifier {
     let mut handlers = Vec::new();
     handlers.push(Arc::new(Mutex::new(handler)));
@@ -608,6 +947,22 @@ impl EventNotifierHelper for BlockIoHandler {
             handler,
         ));
 
+        // Register event notifier for throttle_timer.
+        let cloned_block_io = block_io.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+
+            let mut locked_block_io = cloned_block_io.lock().unwrap();
+            locked_block_io
+                .process_queue()
+                .unwrap_or_else(|_| error!("Failed to handle block IO."));
+            None
+        });
+        notifiers.push(build_event_notifier(
+            locked_block_io.throttle_timer.as_raw_fd(),
+            handler,
+        ));
+
         // Register event notifier for aio.
         let cloned_block_io = block_io.clone();
         if let Some(ref aio) = locked_block_io.aio {
@@ -634,6 +989,8 @@ pub struct Block {
     blk_cfg: DriveConfig,
     /// Image file opened.
     disk_image: Option<File>,
+    /// Format driver of the image, None for raw image.
+    disk_driver: Option<Box<dyn BlockDriver>>,
     /// Number of sectors of the image file.
     disk_sectors: u64,
     /// Bit mask of features supported by the backend.
@@ -648,6 +1005,10 @@ pub struct Block {
     sender: Option<Sender<SenderConfig>>,
     /// Eventfd for config space update.
     update_evt: EventFd,
+    /// Registry where the backend of the block device is registered.
+    block_backends: Arc<BlockBackendRegistry>,
+    /// Statistics and throttling of the backend registered.
+    backend: Option<BlockBackendHandle>,
 }
 
 impl Block {
@@ -655,11 +1016,12 @@ impl Block {
     ///
     /// # Arguments
     ///
-    /// * `blk_cfg` - Configuration of the block device.
-    pub fn new() -> Block {
+    /// * `block_backends` - Registry where the backend of the block device is registered.
+    pub fn new(block_backends: Arc<BlockBackendRegistry>) -> Block {
         Block {
             blk_cfg: Default::default(),
             disk_image: None,
+            disk_driver: None,
             disk_sectors: 0,
             device_features: 0,
             driver_features: 0,
@@ -667,10 +1029,47 @@ impl Block {
             interrupt_cb: None,
             sender: None,
             update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            block_backends,
+            backend: None,
+        }
+    }
+
+    /// Open the image file given by configuration.
+    ///
+    /// Format drivers access metadata of images with unaligned buffers, so
+    /// images not raw are opened without `O_DIRECT`.
+    fn open_disk_image(&self) -> Result<File> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(!self.blk_cfg.read_only);
+        if self.blk_cfg.direct && self.blk_cfg.format == ImageFormat::Raw {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let file = options
+            .open(&self.blk_cfg.path_on_host)
+            .chain_err(|| format!("failed to open the file {}", self.blk_cfg.path_on_host))?;
+        Ok(file)
+    }
+
+    /// Register backend of the block device if an image file is given,
+    /// replacing the backend registered before.
+    fn register_backend(&mut self) {
+        if !self.blk_cfg.drive_id.is_empty() && self.blk_cfg.path_on_host != "" {
+            let info = BlockBackendInfo {
+                node_name: self.blk_cfg.drive_id.clone(),
+                file: self.blk_cfg.path_on_host.clone(),
+                read_only: self.blk_cfg.read_only,
+                driver: self.blk_cfg.format.to_string(),
+                aio: self.blk_cfg.aio_engine().to_string(),
+            };
+            self.backend = Some(self.block_backends.register(&self.blk_cfg.drive_id, info));
+        } else {
+            self.backend = None;
         }
     }
 
     fn build_device_config_space(&mut self) -> Result<()> {
+        self.config_space.clear();
+
         // capacity: 64bits
         let num_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
         for i in 0..8 {
@@ -687,6 +1086,29 @@ impl Block {
             self.config_space.push((126 >> (8 * i)) as u8);
         }
 
+        let discard = self.device_features & (1_u64 << VIRTIO_BLK_F_DISCARD) != 0;
+        let write_zeroes = self.device_features & (1_u64 << VIRTIO_BLK_F_WRITE_ZEROES) != 0;
+        if discard || write_zeroes {
+            // geometry, blk_size, topology, writeback and num_queues are unused.
+            self.config_space.resize(CONFIG_SPACE_SIZE_DISCARD, 0);
+            let mut write_u32_at = |offset: usize, value: u32| {
+                self.config_space[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            };
+            if discard {
+                // max_discard_sectors, max_discard_seg, discard_sector_alignment
+                write_u32_at(36, MAX_DISCARD_SECTORS);
+                write_u32_at(40, MAX_DISCARD_SEG);
+                write_u32_at(44, 1);
+            }
+            if write_zeroes {
+                // max_write_zeroes_sectors, max_write_zeroes_seg
+                write_u32_at(48, MAX_DISCARD_SECTORS);
+                write_u32_at(52, MAX_DISCARD_SEG);
+                // write_zeroes_may_unmap
+                self.config_space[56] = discard as u8;
+            }
+        }
+
         Ok(())
     }
 }
@@ -702,41 +1124,46 @@ impl VirtioDevice for Block {
         self.device_features |= 1_u64 << VIRTIO_BLK_F_SIZE_MAX;
         self.device_features |= 1_u64 << VIRTIO_BLK_F_SEG_MAX;
         self.device_features |= 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+        // Format drivers don't support discard and write zeroes.
+        let raw = self.blk_cfg.format == ImageFormat::Raw;
+        if raw && self.blk_cfg.discard == DiscardMode::Unmap {
+            self.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+        }
+        if raw
+            && (self.blk_cfg.discard == DiscardMode::Unmap
+                || self.blk_cfg.detect_zeroes != DetectZeroes::Off)
+        {
+            self.device_features |= 1_u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
 
         self.build_device_config_space()
             .chain_err(|| "Failed to build config space")?;
 
         let mut disk_size = DUMMY_IMG_SIZE;
 
+        self.disk_image = None;
+        self.disk_driver = None;
         if self.blk_cfg.path_on_host != "" {
-            self.disk_image = None;
-
-            let mut file = if self.blk_cfg.direct {
-                OpenOptions::new()
-                    .read(true)
-                    .write(!self.blk_cfg.read_only)
-                    .custom_flags(libc::O_DIRECT)
-                    .open(&self.blk_cfg.path_on_host)
-                    .chain_err(|| {
-                        format!("failed to open the file {}", self.blk_cfg.path_on_host)
-                    })?
-            } else {
-                OpenOptions::new()
-                    .read(true)
-                    .write(!self.blk_cfg.read_only)
-                    .open(&self.blk_cfg.path_on_host)
-                    .chain_err(|| {
-                        format!("failed to open the file {}", self.blk_cfg.path_on_host)
-                    })?
-            };
+            check_aio_engine(self.blk_cfg.aio_engine())?;
 
-            disk_size = file
-                .seek(SeekFrom::End(0))
-                .chain_err(|| "Failed to seek the end")? as u64;
+            let mut file = self.open_disk_image()?;
+
+            match self.blk_cfg.format {
+                ImageFormat::Raw => {
+                    disk_size =
+                        file.seek(SeekFrom::End(0))
+                            .chain_err(|| "Failed to seek the end")? as u64;
+                }
+                ImageFormat::Qcow2 => {
+                    let driver = Qcow2Driver::open(file.try_clone()?).chain_err(|| {
+                        format!("Failed to open qcow2 image {}", self.blk_cfg.path_on_host)
+                    })?;
+                    disk_size = driver.disk_size();
+                    self.disk_driver = Some(Box::new(driver));
+                }
+            }
 
             self.disk_image = Some(file);
-        } else {
-            self.disk_image = None;
         }
 
         self.disk_sectors = disk_size >> SECTOR_SHIFT;
@@ -832,11 +1259,18 @@ impl VirtioDevice for Block {
             queue_evt: queue_evts.remove(0),
             mem_space,
             disk_image: self.disk_image.take(),
+            disk_driver: self.disk_driver.take(),
             disk_sectors: self.disk_sectors,
-            direct: self.blk_cfg.direct,
+            aio_engine: self.blk_cfg.aio_engine(),
+            discard: self.blk_cfg.discard,
+            detect_zeroes: self.blk_cfg.detect_zeroes,
             serial_num: self.blk_cfg.serial_num.clone(),
             aio: None,
             driver_features: self.driver_features,
+            backend: self.backend.clone(),
+            throttled_reqs: Vec::new(),
+            throttle_timer: TimerFd::new()
+                .chain_err(|| "Failed to create throttle timer of block IO")?,
             receiver,
             update_evt: self.update_evt.as_raw_fd(),
             interrupt_cb: cb,
@@ -847,6 +1281,10 @@ impl VirtioDevice for Block {
     }
 
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
+        if !self.blk_cfg.drive_id.is_empty() {
+            self.block_backends.unregister(&self.blk_cfg.drive_id);
+        }
+
         if let Some(conf) = dev_config {
             self.blk_cfg = conf.as_any().downcast_ref::<DriveConfig>().unwrap().clone();
         } else {
@@ -854,14 +1292,19 @@ impl VirtioDevice for Block {
         }
 
         self.realize()?;
+        self.register_backend();
 
         if let Some(sender) = &self.sender {
             sender
                 .send((
                     self.disk_image.take(),
+                    self.disk_driver.take(),
                     self.disk_sectors,
                     self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
+                    self.blk_cfg.aio_engine(),
+                    self.blk_cfg.discard,
+                    self.blk_cfg.detect_zeroes,
+                    self.backend.clone(),
                 ))
                 .chain_err(|| ErrorKind::ChannelSend("image fd".to_string()))?;
 
@@ -876,6 +1319,69 @@ impl VirtioDevice for Block {
 
         Ok(())
     }
+
+    /// Grow the image file, update capacity in config space and notify guest.
+    fn resize(&mut self, size: u64) -> Result<()> {
+        if size % SECTOR_SIZE != 0 {
+            return Err(ErrorKind::BlockSizeNotAligned(size).into());
+        }
+        if self.blk_cfg.path_on_host == ""
+            || self.blk_cfg.read_only
+            || self.blk_cfg.format != ImageFormat::Raw
+        {
+            return Err(ErrorKind::BlockNotResizable(self.blk_cfg.drive_id.clone()).into());
+        }
+
+        let mut file = self.open_disk_image()?;
+        let disk_size = file
+            .seek(SeekFrom::End(0))
+            .chain_err(|| "Failed to seek the end")?;
+        if size < disk_size {
+            bail!(
+                "Shrinking block {} from {} to {} bytes is not supported",
+                self.blk_cfg.drive_id,
+                disk_size,
+                size
+            );
+        }
+        // The image may be grown already, e.g. a block device resized on host.
+        if size > disk_size {
+            file.set_len(size)
+                .chain_err(|| ErrorKind::BlockNotResizable(self.blk_cfg.drive_id.clone()))?;
+        }
+
+        self.disk_sectors = size >> SECTOR_SHIFT;
+        for i in 0..8 {
+            self.config_space[i] = (self.disk_sectors >> (8 * i)) as u8;
+        }
+
+        if let Some(sender) = &self.sender {
+            sender
+                .send((
+                    Some(file),
+                    None,
+                    self.disk_sectors,
+                    self.blk_cfg.serial_num.clone(),
+                    self.blk_cfg.aio_engine(),
+                    self.blk_cfg.discard,
+                    self.blk_cfg.detect_zeroes,
+                    self.backend.clone(),
+                ))
+                .chain_err(|| ErrorKind::ChannelSend("image fd".to_string()))?;
+
+            self.update_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        } else {
+            self.disk_image = Some(file);
+        }
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb(VIRTIO_MMIO_INT_CONFIG).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -886,7 +1392,7 @@ mod tests {
     #[test]
     fn test_block_init() {
         // test block new method
-        let mut block = Block::new();
+        let mut block = Block::new(Arc::new(BlockBackendRegistry::new()));
         assert_eq!(block.disk_sectors, 0);
         assert_eq!(block.device_features, 0);
         assert_eq!(block.driver_features, 0);
@@ -957,6 +1463,109 @@ mod tests {
         assert_eq!(block.write_config(offset, &mut data).is_ok(), true);
     }
 
+    #[test]
+    fn test_block_discard_features() {
+        let discard_features =
+            (1_u64 << VIRTIO_BLK_F_DISCARD) | (1_u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        let read_u32_config = |block: &Block, offset: u64| {
+            let mut data = [0_u8; 4];
+            block.read_config(offset, &mut data).unwrap();
+            u32::from_le_bytes(data)
+        };
+
+        // Defaults don't advertise discard and write zeroes.
+        let mut block = Block::new(Arc::new(BlockBackendRegistry::new()));
+        block.realize().unwrap();
+        assert_eq!(block.device_features & discard_features, 0);
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE);
+
+        block.blk_cfg.discard = DiscardMode::Unmap;
+        block.realize().unwrap();
+        assert_eq!(block.device_features & discard_features, discard_features);
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE_DISCARD);
+        assert_eq!(read_u32_config(&block, 36), MAX_DISCARD_SECTORS);
+        assert_eq!(read_u32_config(&block, 40), MAX_DISCARD_SEG);
+        assert_eq!(read_u32_config(&block, 48), MAX_DISCARD_SECTORS);
+        assert_eq!(read_u32_config(&block, 52), MAX_DISCARD_SEG);
+        assert_eq!(block.config_space[56], 1);
+
+        // Zeroes detected are written by write zeroes, which never unmaps.
+        block.blk_cfg.discard = DiscardMode::Ignore;
+        block.blk_cfg.detect_zeroes = DetectZeroes::On;
+        block.realize().unwrap();
+        assert_eq!(
+            block.device_features & discard_features,
+            1_u64 << VIRTIO_BLK_F_WRITE_ZEROES
+        );
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE_DISCARD);
+        assert_eq!(read_u32_config(&block, 36), 0);
+        assert_eq!(read_u32_config(&block, 48), MAX_DISCARD_SECTORS);
+        assert_eq!(block.config_space[56], 0);
+
+        block.blk_cfg.detect_zeroes = DetectZeroes::Off;
+        block.realize().unwrap();
+        assert_eq!(block.device_features & discard_features, 0);
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE);
+    }
+
+    #[test]
+    fn test_block_qcow2_realize() {
+        use std::os::unix::fs::FileExt;
+
+        let image = "/tmp/stratovirt-block-qcow2.img";
+        let file = File::create(image).unwrap();
+        file.set_len(4 << 16).unwrap();
+
+        let mut block = Block::new(Arc::new(BlockBackendRegistry::new()));
+        block.blk_cfg = DriveConfig {
+            drive_id: "drive-0".to_string(),
+            path_on_host: image.to_string(),
+            format: ImageFormat::Qcow2,
+            ..Default::default()
+        };
+        assert!(block.realize().is_err());
+
+        // Header of a 1M image with 64K clusters, its L1 table, refcount table
+        // and refcount block follow in order.
+        let fields: [(u64, &[u8]); 10] = [
+            (0, b"QFI\xfb"),
+            (4, &3_u32.to_be_bytes()),
+            (20, &16_u32.to_be_bytes()),
+            (24, &(1_u64 << 20).to_be_bytes()),
+            (36, &1_u32.to_be_bytes()),
+            (40, &(1_u64 << 16).to_be_bytes()),
+            (48, &(2_u64 << 16).to_be_bytes()),
+            (56, &1_u32.to_be_bytes()),
+            (96, &4_u32.to_be_bytes()),
+            (2 << 16, &(3_u64 << 16).to_be_bytes()),
+        ];
+        for (offset, bytes) in fields.iter() {
+            file.write_all_at(bytes, *offset).unwrap();
+        }
+        for cluster in 0..4 {
+            file.write_all_at(&1_u16.to_be_bytes(), (3 << 16) + cluster * 2)
+                .unwrap();
+        }
+
+        block.realize().unwrap();
+        assert_eq!(block.disk_sectors, (1 << 20) >> SECTOR_SHIFT);
+        assert!(block.disk_driver.is_some());
+
+        // Discard and write zeroes aren't offered for qcow2 images.
+        block.blk_cfg.discard = DiscardMode::Unmap;
+        block.blk_cfg.detect_zeroes = DetectZeroes::Unmap;
+        block.realize().unwrap();
+        let discard_features =
+            (1_u64 << VIRTIO_BLK_F_DISCARD) | (1_u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        assert_eq!(block.device_features & discard_features, 0);
+        match block.resize(2 << 20) {
+            Err(Error(ErrorKind::BlockNotResizable(id), _)) => assert_eq!(id, "drive-0"),
+            _ => panic!("qcow2 image should not be resizable"),
+        }
+
+        std::fs::remove_file(image).unwrap();
+    }
+
     #[test]
     fn test_serial_num_config() {
         // test get_serial_num_config method
@@ -979,7 +1588,7 @@ mod tests {
 
     #[test]
     fn test_set_driver_features() {
-        let mut block = Block::new();
+        let mut block = Block::new(Arc::new(BlockBackendRegistry::new()));
 
         //If the device feature is 0, all driver features are not supported.
         block.device_features = 0;
@@ -1054,4 +1663,45 @@ mod tests {
             ((1_u64 << VIRTIO_F_VERSION_1) >> 32) as u32
         );
     }
+
+    #[test]
+    fn test_block_resize() {
+        let image = "/tmp/stratovirt-block-resize.img";
+        let file = File::create(image).unwrap();
+        file.set_len(1 << 20).unwrap();
+
+        let mut block = Block::new(Arc::new(BlockBackendRegistry::new()));
+        block.blk_cfg = DriveConfig {
+            drive_id: "drive-0".to_string(),
+            path_on_host: image.to_string(),
+            read_only: false,
+            direct: false,
+            ..Default::default()
+        };
+        block.realize().unwrap();
+        assert_eq!(block.disk_sectors, (1 << 20) >> SECTOR_SHIFT);
+
+        match block.resize((2 << 20) + 1) {
+            Err(Error(ErrorKind::BlockSizeNotAligned(size), _)) => {
+                assert_eq!(size, (2 << 20) + 1)
+            }
+            _ => panic!("Unaligned size should be rejected"),
+        }
+        assert!(block.resize(1 << 19).is_err());
+
+        block.resize(2 << 20).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 2 << 20);
+        assert_eq!(block.disk_sectors, (2 << 20) >> SECTOR_SHIFT);
+        let mut capacity = [0_u8; 8];
+        block.read_config(0, &mut capacity).unwrap();
+        assert_eq!(u64::from_le_bytes(capacity), (2 << 20) >> SECTOR_SHIFT);
+
+        block.blk_cfg.read_only = true;
+        match block.resize(4 << 20) {
+            Err(Error(ErrorKind::BlockNotResizable(id), _)) => assert_eq!(id, "drive-0"),
+            _ => panic!("Read only image should not be resizable"),
+        }
+
+        std::fs::remove_file(image).unwrap();
+    }
 }
@@ -24,16 +24,20 @@
 //!
 //! - `x86_64`
 //! - `aarch64`
+pub mod balloon;
 pub mod block;
 pub mod console;
 pub mod net;
 mod queue;
+pub mod rng;
 pub mod vhost;
 
-pub use self::block::Block;
+pub use self::balloon::Balloon;
+pub use self::block::{check_aio_engine, Block};
 pub use self::console::Console;
 pub use self::net::Net;
 pub use self::queue::*;
+pub use self::rng::Rng;
 
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
@@ -51,8 +55,8 @@ pub fn virtio_has_feature(feature: u64, fbit: u32) -> bool {
 pub const VIRTIO_TYPE_NET: u32 = 1;
 pub const VIRTIO_TYPE_BLOCK: u32 = 2;
 pub const VIRTIO_TYPE_CONSOLE: u32 = 3;
-pub const _VIRTIO_TYPE_RNG: u32 = 4;
-pub const _VIRTIO_TYPE_BALLOON: u32 = 5;
+pub const VIRTIO_TYPE_RNG: u32 = 4;
+pub const VIRTIO_TYPE_BALLOON: u32 = 5;
 pub const VIRTIO_TYPE_VSOCK: u32 = 19;
 pub const _VIRTIO_TYPE_FS: u32 = 26;
 
@@ -84,6 +88,16 @@ pub const VIRTIO_NET_F_GUEST_UFO: u32 = 10;
 pub const VIRTIO_NET_F_HOST_TSO4: u32 = 11;
 /// Device can receive UFO.
 pub const VIRTIO_NET_F_HOST_UFO: u32 = 14;
+/// Configuration status field is available.
+pub const VIRTIO_NET_F_STATUS: u32 = 16;
+/// Control channel is available.
+pub const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
+/// Device supports multiqueue with automatic receive steering.
+pub const VIRTIO_NET_F_MQ: u32 = 22;
+/// Link of the network device is up.
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// Guest deflates balloon when it's out of memory.
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
 /// Configuration cols and rows are valid.
 pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
 /// Maximum size of any single segment is in size_max.
@@ -94,6 +108,10 @@ pub const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
 pub const VIRTIO_BLK_F_RO: u32 = 5;
 /// Cache flush command support.
 pub const VIRTIO_BLK_F_FLUSH: u32 = 9;
+/// Device can support discard command.
+pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
+/// Device can support write zeroes command.
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 
 /// The IO type of virtio block, refer to Virtio Spec.
 /// Read.
@@ -104,10 +122,20 @@ pub const VIRTIO_BLK_T_OUT: u32 = 1;
 pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 /// Device id
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// Discard.
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+/// Write zeroes.
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 /// Device id length
 pub const VIRTIO_BLK_ID_BYTES: u32 = 20;
 /// Success
 pub const VIRTIO_BLK_S_OK: u32 = 0;
+/// IO error.
+pub const VIRTIO_BLK_S_IOERR: u32 = 1;
+/// Request unsupported.
+pub const VIRTIO_BLK_S_UNSUPP: u32 = 2;
+/// Write zeroes request may deallocate the range.
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
 
 /// Interrupt status: Used Buffer Notification
 pub const VIRTIO_MMIO_INT_VRING: u32 = 0x01;
@@ -168,6 +196,15 @@ pub mod errors {
             VhostIoctl(ioctl: String) {
                 display("Vhost ioctl failed: {}", ioctl)
             }
+            BlockSizeNotAligned(size: u64) {
+                display("Block size {} is not multiple of 512", size)
+            }
+            BlockNotResizable(id: String) {
+                display("Image of block {} is not resizable", id)
+            }
+            AioEngineUnavailable(engine: String) {
+                display("IO engine {} is not available on host", engine)
+            }
         }
     }
 }
@@ -223,6 +260,12 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Unrealize low level device before it's unplugged, for example: stop
+    /// handling the events of virtqueues registered in `activate`.
+    fn unrealize(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Update the low level config of MMIO device,
     /// for example: update the images file fd of virtio block device.
     ///
@@ -232,4 +275,24 @@ pub trait VirtioDevice: Send {
     fn update_config(&mut self, _dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
         bail!("Unsupported to update configuration")
     }
+
+    /// Resize the backend of virtio device, and notify guest of the new size,
+    /// for example: grow the image file of virtio block device.
+    ///
+    /// # Arguments
+    ///
+    /// * `_size` - New size of the backend in bytes.
+    fn resize(&mut self, _size: u64) -> Result<()> {
+        bail!("Unsupported to resize")
+    }
+
+    /// Set link of virtio device up or down, and notify guest of the link
+    /// status, for example: carrier of virtio network device.
+    ///
+    /// # Arguments
+    ///
+    /// * `_up` - Link is up or down.
+    fn set_link_up(&mut self, _up: bool) -> Result<()> {
+        bail!("Unsupported to set link")
+    }
 }
@@ -12,7 +12,7 @@
 
 use std::io::Write;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::{cmp, mem};
@@ -29,21 +29,34 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 use super::super::micro_vm::main_loop::MainLoop;
 use super::errors::{ErrorKind, Result, ResultExt};
+use super::vhost::kernel::VhostNet;
 use super::{
-    Queue, VirtioDevice, VirtioNetHdr, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING,
-    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_TYPE_NET,
+    Queue, VirtioDevice, VirtioNetHdr, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_CONFIG,
+    VIRTIO_MMIO_INT_VRING, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
+    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_STATUS, VIRTIO_NET_S_LINK_UP, VIRTIO_TYPE_NET,
 };
 
-/// Number of virtqueues.
+/// Number of virtqueues of each queue pair.
 const QUEUE_NUM_NET: usize = 2;
 /// Size of each virtqueue.
 const QUEUE_SIZE_NET: u16 = 256;
 /// The maximum buffer size when segmentation offload is enabled.
 /// This includes a 12-byte virtio net header, refer to Virtio Spec.
 const FRAME_BUF_SIZE: usize = 65562;
+/// The maximum buffer size of a command in the control virtqueue.
+const CTRL_BUF_SIZE: usize = 64;
+/// Class of control commands for multiqueue, refer to Virtio Spec.
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+/// Command to set the number of queue pairs used by the driver.
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+/// Ack of a control command which succeeds.
+const VIRTIO_NET_OK: u8 = 0;
+/// Ack of a control command which fails.
+const VIRTIO_NET_ERR: u8 = 1;
 
 type SenderConfig = Option<Tap>;
+type VirtioNetInterrupt = Box<dyn Fn(u32) -> Result<()> + Send + Sync>;
 
 /// Configuration of virtio-net devices.
 #[repr(C, packed)]
@@ -127,7 +140,7 @@ impl RxVirtio {
     }
 }
 
-/// Control block of network IO.
+/// Control block of network IO of a queue pair.
 pub struct NetIoHandler {
     /// The receive virtqueue.
     rx: RxVirtio,
@@ -144,6 +157,10 @@ pub struct NetIoHandler {
     interrupt_status: Arc<AtomicU32>,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
+    /// Link is set down, frames received from tap are dropped.
+    link_down: Arc<AtomicBool>,
+    /// Virtqueues are processed by vhost-net, notifications of them are ignored.
+    vhost_running: bool,
     /// The receiving half of Rust's channel to receive tap information.
     receiver: Receiver<SenderConfig>,
     /// Eventfd for config space update.
@@ -208,6 +225,11 @@ impl NetIoHandler {
     }
 
     fn handle_last_frame_rx(&mut self) -> Result<()> {
+        if self.link_down.load(Ordering::Acquire) {
+            self.rx.unfinished_frame = false;
+            return self.handle_rx();
+        }
+
         if self.handle_frame_rx().is_ok() {
             self.rx.unfinished_frame = false;
             self.handle_rx()?;
@@ -226,6 +248,9 @@ impl NetIoHandler {
     fn handle_rx(&mut self) -> Result<()> {
         while let Some(tap) = self.tap.as_mut() {
             match tap.read(&mut self.rx.frame_buf) {
+                Ok(_) if self.link_down.load(Ordering::Acquire) => {
+                    // Drop the frame, as guest sees no carrier.
+                }
                 Ok(count) => {
                     self.rx.bytes_read = count;
                     if self.handle_frame_rx().is_err() {
@@ -376,6 +401,9 @@ impl EventNotifierHelper for NetIoHandler {
         let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
             let mut locked_net_io = cloned_net_io.lock().unwrap();
             read_fd(fd);
+            if locked_net_io.vhost_running {
+                return None;
+            }
             if locked_net_io.rx.unfinished_frame {
                 locked_net_io
                     .handle_last_frame_rx()
@@ -395,10 +423,12 @@ impl EventNotifierHelper for NetIoHandler {
         // Register event notifier for tx.
         let cloned_net_io = net_io.clone();
         let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            let mut locked_net_io = cloned_net_io.lock().unwrap();
             read_fd(fd);
-            cloned_net_io
-                .lock()
-                .unwrap()
+            if locked_net_io.vhost_running {
+                return None;
+            }
+            locked_net_io
                 .handle_tx()
                 .map_err(|e| error!("Failed to handle tx, {}", e))
                 .ok();
@@ -443,22 +473,255 @@ impl EventNotifierHelper for NetIoHandler {
     }
 }
 
+/// Handle a command in the control virtqueue, and return the ack of it.
+///
+/// # Arguments
+///
+/// * `request` - The command, which begins with its class and code.
+/// * `max_queue_pairs` - Number of queue pairs of the device.
+fn handle_ctrl_cmd(request: &[u8], max_queue_pairs: u16) -> u8 {
+    if request.len() < 2 {
+        error!("Net control command is too short: {} bytes", request.len());
+        return VIRTIO_NET_ERR;
+    }
+
+    match (request[0], request[1]) {
+        (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) if request.len() >= 4 => {
+            let queue_pairs = u16::from_le_bytes([request[2], request[3]]);
+            if queue_pairs == 0 || queue_pairs > max_queue_pairs {
+                error!(
+                    "Invalid number of net queue pairs {}, max {}",
+                    queue_pairs, max_queue_pairs
+                );
+                return VIRTIO_NET_ERR;
+            }
+            // All queue pairs are always processed, as the guest only puts
+            // buffers in the queue pairs it uses.
+            VIRTIO_NET_OK
+        }
+        (class, cmd) => {
+            warn!(
+                "Unsupported net control command, class {}, cmd {}",
+                class, cmd
+            );
+            VIRTIO_NET_ERR
+        }
+    }
+}
+
+/// Control block of the control virtqueue.
+struct NetCtrlHandler {
+    /// The control virtqueue.
+    queue: Arc<Mutex<Queue>>,
+    /// Eventfd of this virtqueue for notifing.
+    queue_evt: EventFd,
+    /// The address space to which the network device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for interrupt.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Number of queue pairs of the device.
+    max_queue_pairs: u16,
+}
+
+impl NetCtrlHandler {
+    fn handle_ctrl(&mut self) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut need_irq = false;
+
+        while let Ok(elem) = queue.vring.pop_avail(&self.mem_space, self.driver_features) {
+            let mut request = [0_u8; CTRL_BUF_SIZE];
+            let mut read_count = 0;
+            for elem_iov in elem.out_iovec.iter() {
+                let alloc_read_count = cmp::min(read_count + elem_iov.len as usize, request.len());
+
+                let mut slice = &mut request[read_count..alloc_read_count];
+                self.mem_space
+                    .read(
+                        &mut slice,
+                        elem_iov.addr,
+                        (alloc_read_count - read_count) as u64,
+                    )
+                    .chain_err(|| "Failed to read net control command")?;
+
+                read_count = alloc_read_count;
+            }
+
+            let ack = handle_ctrl_cmd(&request[..read_count], self.max_queue_pairs);
+            // The ack is in the last descriptor written by the device.
+            let mut used_len = 0;
+            if let Some(ack_iov) = elem.in_iovec.last() {
+                self.mem_space
+                    .write_object(&ack, ack_iov.addr)
+                    .chain_err(|| "Failed to write ack of net control command")?;
+                used_len = mem::size_of::<u8>() as u32;
+            }
+
+            queue
+                .vring
+                .add_used(&self.mem_space, elem.index, used_len)
+                .chain_err(|| format!("Net ctrl: Failed to add used ring {}", elem.index))?;
+            need_irq = true;
+        }
+
+        if need_irq {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+            self.interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for NetCtrlHandler {
+    fn internal_notifiers(ctrl_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let cloned_ctrl_handler = ctrl_handler.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            cloned_ctrl_handler
+                .lock()
+                .unwrap()
+                .handle_ctrl()
+                .map_err(|e| error!("Failed to handle ctrl queue, {}", e))
+                .ok();
+            None
+        });
+        let ctrl_fd = ctrl_handler.lock().unwrap().queue_evt.as_raw_fd();
+        vec![build_event_notifier(
+            ctrl_fd,
+            Some(handler),
+            NotifierOperation::AddShared,
+            EventSet::IN,
+        )]
+    }
+}
+
+/// Virtqueues of the activated network device, which are processed by the
+/// userspace IO handlers, or by vhost-net.
+struct NetQueues {
+    /// The address space to which the network device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for interrupt.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// The receive and transmit virtqueues, and the control virtqueue.
+    queues: Vec<Arc<Mutex<Queue>>>,
+    /// Eventfds signaled from guest for the virtqueues.
+    queue_evts: Vec<EventFd>,
+    /// The userspace IO handler of each queue pair.
+    net_ios: Vec<Arc<Mutex<NetIoHandler>>>,
+}
+
 /// Network device structure.
 pub struct Net {
     /// Configuration of the network device.
     net_cfg: NetworkInterfaceConfig,
-    /// Tap device opened.
-    tap: Option<Tap>,
+    /// Tap devices opened, one for each queue pair.
+    taps: Vec<Tap>,
+    /// Number of queue pairs, fixed once the device is realized.
+    queue_pairs: Option<u16>,
     /// Bit mask of features supported by the backend.
     device_features: u64,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
     /// Virtio net configurations.
     device_config: VirtioNetConfig,
-    /// The send half of Rust's channel to send tap information.
-    sender: Option<Sender<SenderConfig>>,
-    /// Eventfd for config space update.
-    update_evt: EventFd,
+    /// The send half of Rust's channel to send tap information, for each
+    /// queue pair.
+    senders: Vec<Sender<SenderConfig>>,
+    /// Eventfds for config space update, for each queue pair.
+    update_evts: Vec<EventFd>,
+    /// Link is set down, shared with the IO handler.
+    link_down: Arc<AtomicBool>,
+    /// Callback to trigger interrupt.
+    interrupt_cb: Option<Arc<VirtioNetInterrupt>>,
+    /// Virtqueues of the device since it's activated.
+    net_queues: Option<NetQueues>,
+    /// Vhost-net processing virtqueues in place of the userspace IO handler.
+    vhost: Option<VhostNet>,
+}
+
+/// Set link status in the virtio configuration, and return true if the
+/// status is changed.
+///
+/// # Arguments
+///
+/// * `device_config` - Virtio net configurations.
+/// * `up` - Link is up or down.
+fn set_link_status(device_config: &mut VirtioNetConfig, up: bool) -> bool {
+    let old_status = device_config.status;
+    let status = if up {
+        old_status | VIRTIO_NET_S_LINK_UP
+    } else {
+        old_status & !VIRTIO_NET_S_LINK_UP
+    };
+    device_config.status = status;
+    status != old_status
+}
+
+/// Let vhost-net process virtqueues of the device with `tap`, if vhost-net is
+/// configured. Return None with a warning if vhost-net fails to start, then
+/// virtqueues are processed in userspace.
+///
+/// # Arguments
+///
+/// * `net_cfg` - Configuration of the network device.
+/// * `driver_features` - Bit mask of features negotiated by the frontend.
+/// * `net_queues` - Virtqueues of the activated device.
+/// * `tap` - Tap device attached to virtqueues.
+fn start_vhost(
+    net_cfg: &NetworkInterfaceConfig,
+    driver_features: u64,
+    net_queues: &NetQueues,
+    tap: Option<&Tap>,
+) -> Option<VhostNet> {
+    let tap = match tap {
+        Some(tap) if net_cfg.vhost_type.is_some() => tap,
+        _ => return None,
+    };
+
+    let start = || -> Result<VhostNet> {
+        // Vhost-net may be started again, e.g. when the link is set up, so the
+        // fd passed is kept and its duplicate is used.
+        let vhost_fd = match net_cfg.vhost_fd {
+            Some(fd) => {
+                let dup_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if dup_fd < 0 {
+                    bail!("Failed to duplicate vhost fd {}", fd);
+                }
+                Some(dup_fd)
+            }
+            None => None,
+        };
+        let mut vhost = VhostNet::new(&net_queues.mem_space, vhost_fd)?;
+        vhost.start(
+            driver_features,
+            &net_queues.queues,
+            &net_queues.queue_evts,
+            &net_queues.interrupt_evt,
+            net_queues.interrupt_status.clone(),
+            tap,
+        )?;
+        Ok(vhost)
+    };
+
+    match start() {
+        Ok(vhost) => Some(vhost),
+        Err(e) => {
+            warn!(
+                "Failed to start vhost-net for net {}, fall back to virtio-net in userspace: {}",
+                net_cfg.iface_id, e
+            );
+            None
+        }
+    }
 }
 
 /// Set Mac address configured into the virtio configuration, and return features mask with
@@ -484,37 +747,50 @@ pub fn build_device_config_space(device_config: &mut VirtioNetConfig, mac: &str)
     config_features
 }
 
-/// Open tap device if no fd provided, configure and return it.
+/// Open tap devices if no fd provided, configure and return them.
 ///
 /// # Arguments
 ///
-/// * `net_fd` - Fd of tap device opened.
+/// * `net_fds` - Fds of tap device opened, one for each queue pair.
 /// * `host_dev_name` - Path of tap device on host.
-pub fn create_tap(net_fd: Option<i32>, host_dev_name: Option<&str>) -> Result<Option<Tap>> {
-    if net_fd.is_none() && host_dev_name.is_none() {
-        return Ok(None);
+/// * `queue_pairs` - Number of queue pairs of tap device opened by path.
+pub fn create_tap(
+    net_fds: Option<&[i32]>,
+    host_dev_name: Option<&str>,
+    queue_pairs: u16,
+) -> Result<Vec<Tap>> {
+    if net_fds.is_none() && host_dev_name.is_none() {
+        return Ok(Vec::new());
     }
-    if net_fd.is_some() && host_dev_name.is_some() {
+    if net_fds.is_some() && host_dev_name.is_some() {
         error!("Create tap: fd and file_path exist meanwhile (use fd by default)");
     }
 
-    let tap = if let Some(fd) = net_fd {
-        Tap::new(None, Some(fd)).chain_err(|| "Failed to create tap")?
+    let mut taps = Vec::new();
+    if let Some(fds) = net_fds {
+        for fd in fds {
+            taps.push(Tap::new(None, Some(*fd), queue_pairs).chain_err(|| "Failed to create tap")?);
+        }
     } else {
         // `unwrap()` won't fail because the arguments have been checked
         let dev_name = host_dev_name.unwrap();
-        Tap::new(Some(dev_name), None)
-            .chain_err(|| format!("Failed to create tap with name {}", dev_name))?
-    };
-
-    tap.set_offload(TUN_F_VIRTIO)
-        .chain_err(|| "Failed to set tap offload")?;
+        for _ in 0..queue_pairs {
+            taps.push(
+                Tap::new(Some(dev_name), None, queue_pairs)
+                    .chain_err(|| format!("Failed to create tap with name {}", dev_name))?,
+            );
+        }
+    }
 
     let vnet_hdr_size = mem::size_of::<VirtioNetHdr>() as u32;
-    tap.set_hdr_size(vnet_hdr_size)
-        .chain_err(|| "Failed to set tap hdr size")?;
+    for tap in taps.iter() {
+        tap.set_offload(TUN_F_VIRTIO)
+            .chain_err(|| "Failed to set tap offload")?;
+        tap.set_hdr_size(vnet_hdr_size)
+            .chain_err(|| "Failed to set tap hdr size")?;
+    }
 
-    Ok(Some(tap))
+    Ok(taps)
 }
 
 impl Net {
@@ -526,14 +802,89 @@ impl Net {
     pub fn new() -> Self {
         Net {
             net_cfg: Default::default(),
-            tap: None,
+            taps: Vec::new(),
+            queue_pairs: None,
             device_features: 0_u64,
             driver_features: 0_u64,
             device_config: VirtioNetConfig::default(),
-            sender: None,
-            update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            senders: Vec::new(),
+            update_evts: Vec::new(),
+            link_down: Arc::new(AtomicBool::new(false)),
+            interrupt_cb: None,
+            net_queues: None,
+            vhost: None,
         }
     }
+
+    /// Hand virtqueues of the activated device over to vhost-net if it's
+    /// configured and the link is up, otherwise to the userspace IO handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `reclaim_tap` - Take the taps back from the userspace IO handlers if
+    ///   the device has no new tap.
+    fn update_backend(&mut self, reclaim_tap: bool) -> Result<()> {
+        let net_queues = match &self.net_queues {
+            Some(net_queues) => net_queues,
+            None => return Ok(()),
+        };
+
+        // Virtqueues are processed in userspace from where vhost-net stopped.
+        let mut locked_net_ios = net_queues
+            .net_ios
+            .iter()
+            .map(|net_io| net_io.lock().unwrap())
+            .collect::<Vec<_>>();
+        if let Some(mut vhost) = self.vhost.take() {
+            vhost
+                .stop(&net_queues.queues)
+                .map_err(|e| error!("Failed to stop vhost-net, {}", e))
+                .ok();
+        }
+        let mut taps = mem::take(&mut self.taps);
+        if reclaim_tap && taps.is_empty() {
+            taps = locked_net_ios
+                .iter_mut()
+                .filter_map(|locked_net_io| locked_net_io.tap.take())
+                .collect();
+        }
+
+        if !self.link_down.load(Ordering::Acquire) {
+            self.vhost = start_vhost(
+                &self.net_cfg,
+                self.driver_features,
+                net_queues,
+                taps.first(),
+            );
+        }
+        for locked_net_io in locked_net_ios.iter_mut() {
+            locked_net_io.vhost_running = self.vhost.is_some();
+            if self.vhost.is_some() {
+                // Frame left in userspace is dropped.
+                locked_net_io.rx.unfinished_frame = false;
+            }
+        }
+        if self.vhost.is_some() {
+            // Tap is kept for vhost-net.
+            self.taps = mem::take(&mut taps);
+        }
+        drop(locked_net_ios);
+
+        let mut taps = taps.into_iter();
+        for (sender, update_evt) in self.senders.iter().zip(self.update_evts.iter()) {
+            sender
+                .send(taps.next())
+                .chain_err(|| ErrorKind::ChannelSend("tap fd".to_string()))?;
+            update_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        // Notifications of guest may be consumed by the previous backend.
+        for queue_evt in net_queues.queue_evts.iter() {
+            queue_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl VirtioDevice for Net {
@@ -545,29 +896,41 @@ impl VirtioDevice for Net {
             | 1 << VIRTIO_NET_F_GUEST_TSO4
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO;
+            | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS;
+        set_link_status(
+            &mut self.device_config,
+            !self.link_down.load(Ordering::Acquire),
+        );
+
+        // Layout of virtqueues is fixed once the device is realized.
+        let queue_pairs = *self.queue_pairs.get_or_insert(self.net_cfg.queues);
+        if queue_pairs > 1 {
+            self.device_features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            self.device_config.max_virtqueue_pairs = queue_pairs;
+        }
 
         if let Some(mac) = &self.net_cfg.mac {
             self.device_features |= build_device_config_space(&mut self.device_config, mac);
         }
 
         if self.net_cfg.host_dev_name != "" {
-            self.tap = None;
-            self.tap = create_tap(None, Some(&self.net_cfg.host_dev_name))
+            self.taps.clear();
+            self.taps = create_tap(None, Some(&self.net_cfg.host_dev_name), queue_pairs)
                 .chain_err(|| "Failed to open tap with file path")?;
-        } else if let Some(fd) = self.net_cfg.tap_fd {
-            let mut need_create = true;
-            if let Some(tap) = &self.tap {
-                if fd == tap.as_raw_fd() {
-                    need_create = false;
-                }
-            }
-
-            if need_create {
-                self.tap = create_tap(Some(fd), None).chain_err(|| "Failed to open tap")?;
+        } else if let Some(fds) = &self.net_cfg.tap_fds {
+            let opened_fds = self
+                .taps
+                .iter()
+                .map(|tap| tap.as_raw_fd())
+                .collect::<Vec<RawFd>>();
+
+            if opened_fds != *fds {
+                self.taps =
+                    create_tap(Some(fds), None, queue_pairs).chain_err(|| "Failed to open tap")?;
             }
         } else {
-            self.tap = None;
+            self.taps.clear();
         }
 
         if let Some(mac) = &self.net_cfg.mac {
@@ -582,9 +945,13 @@ impl VirtioDevice for Net {
         VIRTIO_TYPE_NET
     }
 
-    /// Get the count of virtio device queues.
+    /// Get the count of virtio device queues, including the control virtqueue
+    /// for multiple queue pairs.
     fn queue_num(&self) -> usize {
-        QUEUE_NUM_NET
+        match self.queue_pairs {
+            Some(queue_pairs) if queue_pairs > 1 => QUEUE_NUM_NET * queue_pairs as usize + 1,
+            _ => QUEUE_NUM_NET,
+        }
     }
 
     /// Get the queue size of virtio device.
@@ -645,60 +1012,143 @@ impl VirtioDevice for Net {
         mut queues: Vec<Arc<Mutex<Queue>>>,
         mut queue_evts: Vec<EventFd>,
     ) -> Result<()> {
-        let rx_queue = queues.remove(0);
-        let rx_queue_evt = queue_evts.remove(0);
-        let tx_queue = queues.remove(0);
-        let tx_queue_evt = queue_evts.remove(0);
-
-        let (sender, receiver) = channel();
-        self.sender = Some(sender);
+        let mut cloned_queue_evts = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            cloned_queue_evts.push(queue_evt.try_clone()?);
+        }
+        let cloned_queues = queues.clone();
+        let queue_pairs = queues.len() / QUEUE_NUM_NET;
+
+        let cb_interrupt_evt = interrupt_evt.try_clone()?;
+        let cb_interrupt_status = interrupt_status.clone();
+        self.interrupt_cb = Some(Arc::new(Box::new(move |status: u32| {
+            cb_interrupt_status.fetch_or(status, Ordering::SeqCst);
+            cb_interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)
+        }) as VirtioNetInterrupt));
+
+        let mut taps = mem::take(&mut self.taps).into_iter();
+        let mut net_ios = Vec::with_capacity(queue_pairs);
+        for _ in 0..queue_pairs {
+            let rx_queue = queues.remove(0);
+            let rx_queue_evt = queue_evts.remove(0);
+            let tx_queue = queues.remove(0);
+            let tx_queue_evt = queue_evts.remove(0);
+
+            let (sender, receiver) = channel();
+            let update_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+            let tap = taps.next();
+            let tap_fd = if let Some(tap) = &tap {
+                tap.as_raw_fd()
+            } else {
+                -1
+            };
+
+            let handler = NetIoHandler {
+                rx: RxVirtio::new(rx_queue, rx_queue_evt),
+                tx: TxVirtio::new(tx_queue, tx_queue_evt),
+                tap,
+                tap_fd,
+                mem_space: mem_space.clone(),
+                interrupt_evt: interrupt_evt.try_clone()?,
+                interrupt_status: interrupt_status.clone(),
+                driver_features: self.driver_features,
+                link_down: self.link_down.clone(),
+                vhost_running: false,
+                receiver,
+                update_evt: update_evt.as_raw_fd(),
+            };
+            let net_io = Arc::new(Mutex::new(handler));
+            MainLoop::update_event(EventNotifierHelper::internal_notifiers(net_io.clone()))?;
+
+            self.senders.push(sender);
+            self.update_evts.push(update_evt);
+            net_ios.push(net_io);
+        }
 
-        let tap_fd = if let Some(tap) = &self.tap {
-            tap.as_raw_fd()
-        } else {
-            -1
-        };
+        // The control virtqueue follows the queue pairs.
+        if !queues.is_empty() {
+            let ctrl_handler = NetCtrlHandler {
+                queue: queues.remove(0),
+                queue_evt: queue_evts.remove(0),
+                mem_space: mem_space.clone(),
+                interrupt_evt: interrupt_evt.try_clone()?,
+                interrupt_status: interrupt_status.clone(),
+                driver_features: self.driver_features,
+                max_queue_pairs: queue_pairs as u16,
+            };
+            MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
+                Mutex::new(ctrl_handler),
+            )))?;
+        }
 
-        let handler = NetIoHandler {
-            rx: RxVirtio::new(rx_queue, rx_queue_evt),
-            tx: TxVirtio::new(tx_queue, tx_queue_evt),
-            tap: self.tap.take(),
-            tap_fd,
+        self.net_queues = Some(NetQueues {
             mem_space,
-            interrupt_evt: interrupt_evt.try_clone()?,
+            interrupt_evt,
             interrupt_status,
-            driver_features: self.driver_features,
-            receiver,
-            update_evt: self.update_evt.as_raw_fd(),
-        };
-        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
-            Mutex::new(handler),
-        )))?;
+            queues: cloned_queues,
+            queue_evts: cloned_queue_evts,
+            net_ios,
+        });
+        if self.net_cfg.vhost_type.is_some() {
+            self.update_backend(true)?;
+        }
 
         Ok(())
     }
 
+    /// Close taps opened by `realize` which aren't handed to IO handlers.
+    fn unrealize(&mut self) -> Result<()> {
+        self.taps.clear();
+        Ok(())
+    }
+
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
         if let Some(conf) = dev_config {
-            self.net_cfg = conf
+            let net_cfg = conf
                 .as_any()
                 .downcast_ref::<NetworkInterfaceConfig>()
-                .unwrap()
-                .clone();
+                .unwrap();
+            if let Some(queue_pairs) = self.queue_pairs {
+                if net_cfg.queues != queue_pairs {
+                    bail!(
+                        "Net {} with {} queue pairs can't replace the device with {} queue pairs",
+                        net_cfg.iface_id,
+                        net_cfg.queues,
+                        queue_pairs
+                    );
+                }
+            }
+            self.net_cfg = net_cfg.clone();
         } else {
             self.net_cfg = Default::default();
         }
 
+        // Link of the new backend is up.
+        self.link_down.store(false, Ordering::Release);
+        if set_link_status(&mut self.device_config, true) {
+            if let Some(interrupt_cb) = &self.interrupt_cb {
+                interrupt_cb(VIRTIO_MMIO_INT_CONFIG).chain_err(|| ErrorKind::EventFdWrite)?;
+            }
+        }
         self.realize()?;
 
-        if let Some(sender) = &self.sender {
-            sender
-                .send(self.tap.take())
-                .chain_err(|| ErrorKind::ChannelSend("tap fd".to_string()))?;
+        self.update_backend(false)
+    }
 
-            self.update_evt
-                .write(1)
-                .chain_err(|| ErrorKind::EventFdWrite)?;
+    /// Set link up or down, and notify guest of the link status change.
+    /// Virtqueues are processed in userspace while the link is down, which
+    /// drops frames.
+    fn set_link_up(&mut self, up: bool) -> Result<()> {
+        let changed = self.link_down.swap(!up, Ordering::AcqRel) == up;
+        if set_link_status(&mut self.device_config, up) {
+            if let Some(interrupt_cb) = &self.interrupt_cb {
+                interrupt_cb(VIRTIO_MMIO_INT_CONFIG).chain_err(|| ErrorKind::EventFdWrite)?;
+            }
+        }
+        if changed && self.net_cfg.vhost_type.is_some() {
+            self.update_backend(true)?;
         }
 
         Ok(())
@@ -717,10 +1167,10 @@ mod tests {
         assert_eq!(net.device_features, 0);
         assert_eq!(net.driver_features, 0);
 
-        assert_eq!(net.tap.is_none(), true);
-        assert_eq!(net.sender.is_none(), true);
+        assert_eq!(net.taps.is_empty(), true);
+        assert_eq!(net.senders.is_empty(), true);
         assert_eq!(net.net_cfg.mac.is_none(), true);
-        assert_eq!(net.net_cfg.tap_fd.is_none(), true);
+        assert_eq!(net.net_cfg.tap_fds.is_none(), true);
         assert_eq!(net.net_cfg.vhost_type.is_none(), true);
         assert_eq!(net.net_cfg.vhost_fd.is_none(), true);
 
@@ -768,4 +1218,113 @@ mod tests {
         let mut data: Vec<u8> = vec![0; len as usize];
         assert_eq!(net.write_config(offset, &mut data).is_ok(), true);
     }
+
+    #[test]
+    fn test_net_link_status() {
+        let mut config = VirtioNetConfig::default();
+        assert!(set_link_status(&mut config, true));
+        assert!(!set_link_status(&mut config, true));
+        let status = config.status;
+        assert_eq!(status, VIRTIO_NET_S_LINK_UP);
+        assert!(set_link_status(&mut config, false));
+        assert!(!set_link_status(&mut config, false));
+        let status = config.status;
+        assert_eq!(status, 0);
+
+        let mut net = Net::new();
+        net.realize().unwrap();
+        assert_ne!(net.device_features & (1 << VIRTIO_NET_F_STATUS), 0);
+        let read_status = |net: &Net| {
+            let mut data = [0u8; 2];
+            net.read_config(6, &mut data).unwrap();
+            u16::from_le_bytes(data)
+        };
+        assert_eq!(read_status(&net), VIRTIO_NET_S_LINK_UP);
+
+        // Link status can be set before the device is activated.
+        net.set_link_up(false).unwrap();
+        assert_eq!(read_status(&net), 0);
+        assert!(net.link_down.load(Ordering::Acquire));
+
+        // Config interrupt is triggered only if the status changes.
+        let interrupts = Arc::new(AtomicU32::new(0));
+        let cloned_interrupts = interrupts.clone();
+        net.interrupt_cb = Some(Arc::new(Box::new(move |status: u32| {
+            assert_eq!(status, VIRTIO_MMIO_INT_CONFIG);
+            cloned_interrupts.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }) as VirtioNetInterrupt));
+        net.set_link_up(false).unwrap();
+        assert_eq!(interrupts.load(Ordering::SeqCst), 0);
+        net.set_link_up(true).unwrap();
+        assert_eq!(interrupts.load(Ordering::SeqCst), 1);
+        assert_eq!(read_status(&net), VIRTIO_NET_S_LINK_UP);
+        assert!(!net.link_down.load(Ordering::Acquire));
+        net.set_link_up(false).unwrap();
+        assert_eq!(interrupts.load(Ordering::SeqCst), 2);
+        assert_eq!(read_status(&net), 0);
+
+        // Link of the new backend is up after replacing.
+        net.update_config(None).unwrap();
+        assert_eq!(interrupts.load(Ordering::SeqCst), 3);
+        assert_eq!(read_status(&net), VIRTIO_NET_S_LINK_UP);
+        assert!(!net.link_down.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_net_multiqueue() {
+        let read_max_queue_pairs = |net: &Net| {
+            let mut data = [0u8; 2];
+            net.read_config(8, &mut data).unwrap();
+            u16::from_le_bytes(data)
+        };
+
+        let mut net = Net::new();
+        net.realize().unwrap();
+        assert_eq!(net.queue_num(), 2);
+        assert_eq!(net.device_features & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_eq!(net.device_features & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        assert_eq!(read_max_queue_pairs(&net), 0);
+
+        let mut net = Net::new();
+        let net_cfg = NetworkInterfaceConfig {
+            iface_id: "net-0".to_string(),
+            queues: 4,
+            ..Default::default()
+        };
+        net.update_config(Some(Arc::new(net_cfg))).unwrap();
+        net.realize().unwrap();
+        assert_eq!(net.queue_num(), 9);
+        assert_ne!(net.device_features & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(net.device_features & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        assert_eq!(read_max_queue_pairs(&net), 4);
+
+        // Number of queue pairs can't be changed once realized.
+        let net_cfg = NetworkInterfaceConfig {
+            iface_id: "net-1".to_string(),
+            queues: 2,
+            ..Default::default()
+        };
+        assert!(net.update_config(Some(Arc::new(net_cfg))).is_err());
+        assert_eq!(net.net_cfg.iface_id, "net-0");
+        net.update_config(None).unwrap();
+        assert_eq!(net.queue_num(), 9);
+        assert_eq!(read_max_queue_pairs(&net), 4);
+    }
+
+    #[test]
+    fn test_net_ctrl_cmd() {
+        let mq_cmd = |queue_pairs: u16| {
+            let mut request = vec![VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET];
+            request.extend_from_slice(&queue_pairs.to_le_bytes());
+            request
+        };
+        assert_eq!(handle_ctrl_cmd(&mq_cmd(1), 4), VIRTIO_NET_OK);
+        assert_eq!(handle_ctrl_cmd(&mq_cmd(4), 4), VIRTIO_NET_OK);
+        assert_eq!(handle_ctrl_cmd(&mq_cmd(0), 4), VIRTIO_NET_ERR);
+        assert_eq!(handle_ctrl_cmd(&mq_cmd(5), 4), VIRTIO_NET_ERR);
+        assert_eq!(handle_ctrl_cmd(&mq_cmd(2)[..3], 4), VIRTIO_NET_ERR);
+        assert_eq!(handle_ctrl_cmd(&[VIRTIO_NET_CTRL_MQ], 4), VIRTIO_NET_ERR);
+        assert_eq!(handle_ctrl_cmd(&[0, 0], 4), VIRTIO_NET_ERR);
+    }
 }
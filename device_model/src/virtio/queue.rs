@@ -163,6 +163,19 @@ pub trait VringOps {
 
     /// Get the configuration of the vring.
     fn get_queue_config(&self) -> QueueConfig;
+
+    /// Get the index of the next element to be popped from the available vring.
+    fn get_avail_base(&self) -> u16;
+
+    /// Continue from `base` of the available vring, after the vring was
+    /// processed elsewhere, e.g. by vhost. Index of the used vring is
+    /// synchronized from guest memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Address space to which the vring belongs.
+    /// * `base` - Index of the next element to be popped from the available vring.
+    fn set_avail_base(&mut self, sys_mem: &Arc<AddressSpace>, base: u16) -> Result<()>;
 }
 
 /// Virtio used element.
@@ -701,6 +714,20 @@ impl VringOps for SplitVring {
             size: self.size,
         }
     }
+
+    fn get_avail_base(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    fn set_avail_base(&mut self, sys_mem: &Arc<AddressSpace>, base: u16) -> Result<()> {
+        let used_idx = self
+            .get_used_idx(sys_mem)
+            .chain_err(|| "Failed to get the index of used ring")?;
+        self.next_avail = Wrapping(base);
+        self.next_used = Wrapping(used_idx);
+        self.last_signal_used = Wrapping(used_idx);
+        Ok(())
+    }
 }
 
 /// Virtio queue.
@@ -1474,6 +1501,36 @@ mod tests {
         assert_eq!(vring.get_used_ring_idx(&sys_space).unwrap(), 1);
     }
 
+    #[test]
+    fn test_avail_base() {
+        let sys_space = address_space_init();
+
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * DESCRIPTOR_LEN);
+        queue_config.used_ring = GuestAddress(align(
+            (QUEUE_SIZE as u64) * DESCRIPTOR_LEN
+                + VRING_AVAIL_LEN_EXCEPT_AVAILELEM
+                + AVAILELEM_LEN * (QUEUE_SIZE as u64),
+            4096,
+        ));
+        queue_config.ready = true;
+        queue_config.size = QUEUE_SIZE;
+        let mut vring = SplitVring::new(queue_config);
+        assert_eq!(vring.get_avail_base(), 0);
+
+        // The vring was processed elsewhere, which used 5 elements.
+        assert!(vring.set_used_ring_idx(&sys_space, 5).is_ok());
+        assert!(vring.set_avail_base(&sys_space, 7).is_ok());
+        assert_eq!(vring.get_avail_base(), 7);
+
+        // Used elements are added after ones used elsewhere.
+        assert!(vring.add_used(&sys_space, 3, 100).is_ok());
+        let elem = vring.get_used_elem(&sys_space, 5).unwrap();
+        assert_eq!(elem.id, 3);
+        assert_eq!(vring.get_used_ring_idx(&sys_space).unwrap(), 6);
+    }
+
     #[test]
     fn test_should_notify() {
         let sys_space = address_space_init();
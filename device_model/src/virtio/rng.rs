@@ -0,0 +1,371 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind as IoErrorKind, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use address_space::AddressSpace;
+use machine_manager::config::RngConfig;
+use util::epoll_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::num_ops::{read_u32, write_u32};
+use util::rate_limiter::TokenBucket;
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, timerfd::TimerFd};
+
+use super::super::micro_vm::main_loop::MainLoop;
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::{
+    Queue, VirtioDevice, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING,
+    VIRTIO_TYPE_RNG,
+};
+
+/// Number of virtqueues.
+const QUEUE_NUM_RNG: usize = 1;
+/// Size of virtqueue.
+const QUEUE_SIZE_RNG: u16 = 256;
+/// Max bytes of entropy fed to guest by one request.
+const RNG_BUF_SIZE: usize = 4096;
+
+/// Convert rate limit of `max_bytes` in every `period_ms` milliseconds to
+/// bytes per second of token bucket, which is 1 at least.
+fn rng_rate(max_bytes: u64, period_ms: u64) -> u64 {
+    cmp::max(max_bytes.saturating_mul(1000) / period_ms, 1)
+}
+
+/// Rng device's IO handle context.
+struct RngHandler {
+    /// The only virtqueue of rng device.
+    queue: Arc<Mutex<Queue>>,
+    /// Eventfd of the virtqueue.
+    queue_evt: EventFd,
+    /// The address space to which the rng device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Host file which entropy is read from.
+    random_file: File,
+    /// Rate limit of entropy, `None` if unlimited.
+    rate_limit: Option<TokenBucket>,
+    /// Timer to resume the requests delayed by rate limit.
+    limit_timer: TimerFd,
+}
+
+impl RngHandler {
+    /// Fill the available requests with entropy within the rate limit. If the
+    /// budget runs out, the limit timer is armed to resume the rest requests.
+    fn process_queue(&mut self) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut buffer = [0_u8; RNG_BUF_SIZE];
+        let mut need_interrupt = false;
+
+        loop {
+            let mut allowed = RNG_BUF_SIZE as u64;
+            if let Some(bucket) = self.rate_limit.as_mut() {
+                bucket.refill(Instant::now());
+                allowed = cmp::min(allowed, bucket.available());
+                if allowed == 0 {
+                    self.limit_timer
+                        .reset(bucket.wait_time(1), None)
+                        .chain_err(|| "Failed to arm limit timer of rng")?;
+                    break;
+                }
+            }
+
+            let elem = match queue.vring.pop_avail(&self.mem_space, self.driver_features) {
+                Ok(elem) => elem,
+                Err(_) => break,
+            };
+
+            let in_len: u64 = elem.in_iovec.iter().map(|iov| u64::from(iov.len)).sum();
+            let size = cmp::min(in_len, allowed) as usize;
+            let read_len = match self.random_file.read(&mut buffer[..size]) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e).chain_err(|| "Failed to read entropy for rng"),
+            };
+
+            let mut written = 0_usize;
+            for iov in elem.in_iovec.iter() {
+                if written >= read_len {
+                    break;
+                }
+                let len = cmp::min(iov.len as usize, read_len - written);
+                self.mem_space
+                    .write(&mut &buffer[written..written + len], iov.addr, len as u64)
+                    .chain_err(|| "Failed to write entropy to guest memory")?;
+                written += len;
+            }
+            if let Some(bucket) = self.rate_limit.as_mut() {
+                bucket.consume(written as u64);
+            }
+
+            queue
+                .vring
+                .add_used(&self.mem_space, elem.index, written as u32)
+                .chain_err(|| format!("Rng: Failed to add used ring {}", elem.index))?;
+            if queue
+                .vring
+                .should_notify(&self.mem_space, self.driver_features)
+            {
+                need_interrupt = true;
+            }
+        }
+
+        if need_interrupt {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+            self.interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for RngHandler {
+    fn internal_notifiers(rng_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_handler = rng_handler.lock().unwrap();
+
+        for fd in &[
+            locked_handler.queue_evt.as_raw_fd(),
+            locked_handler.limit_timer.as_raw_fd(),
+        ] {
+            let cloned_handler = rng_handler.clone();
+            let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                cloned_handler
+                    .lock()
+                    .unwrap()
+                    .process_queue()
+                    .unwrap_or_else(|e| error!("Failed to handle rng request, {}", e));
+                None
+            });
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                *fd,
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            ));
+        }
+
+        notifiers
+    }
+}
+
+/// Virtio rng device structure.
+pub struct Rng {
+    /// Configuration of the rng device.
+    rng_cfg: RngConfig,
+    /// Host file which entropy is read from.
+    random_file: Option<File>,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Eventfds handled in main loop since the device is activated.
+    deactivate_fds: Vec<RawFd>,
+}
+
+impl Rng {
+    /// Create a virtio-rng device.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng_cfg` - Device configuration set by user.
+    pub fn new(rng_cfg: RngConfig) -> Self {
+        Rng {
+            rng_cfg,
+            random_file: None,
+            device_features: 0_u64,
+            driver_features: 0_u64,
+            deactivate_fds: Vec::new(),
+        }
+    }
+}
+
+impl VirtioDevice for Rng {
+    /// Realize virtio rng device.
+    fn realize(&mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.rng_cfg.random_file)
+            .chain_err(|| format!("Failed to open rng file {}", self.rng_cfg.random_file))?;
+        self.random_file = Some(file);
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_RNG
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_RNG
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_RNG
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut v = write_u32(value, page);
+        let unrequested_features = v & !self.device_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request with unknown feature for rng.");
+            v &= !unrequested_features;
+        }
+        self.driver_features |= v;
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, _data: &mut [u8]) -> Result<()> {
+        Err(ErrorKind::DevConfigOverflow(offset, 0).into())
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("No device config space")
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicU32>,
+        mut queues: Vec<Arc<Mutex<Queue>>>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        let random_file = match &self.random_file {
+            Some(file) => file.try_clone()?,
+            None => bail!("Rng device is not realized"),
+        };
+        let rate_limit = match (self.rng_cfg.max_bytes, self.rng_cfg.period) {
+            (Some(max_bytes), Some(period)) => Some(TokenBucket::new(
+                rng_rate(max_bytes, period),
+                Instant::now(),
+            )),
+            _ => None,
+        };
+
+        let handler = RngHandler {
+            queue: queues.remove(0),
+            queue_evt: queue_evts.remove(0),
+            mem_space,
+            interrupt_evt: interrupt_evt.try_clone()?,
+            interrupt_status,
+            driver_features: self.driver_features,
+            random_file,
+            rate_limit,
+            limit_timer: TimerFd::new().chain_err(|| "Failed to create limit timer of rng")?,
+        };
+        self.deactivate_fds = vec![
+            handler.queue_evt.as_raw_fd(),
+            handler.limit_timer.as_raw_fd(),
+        ];
+
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
+            Mutex::new(handler),
+        )))?;
+
+        Ok(())
+    }
+
+    /// Stop handling the virtqueue of rng device.
+    fn unrealize(&mut self) -> Result<()> {
+        let notifiers = self
+            .deactivate_fds
+            .drain(..)
+            .map(|fd| EventNotifier::new(NotifierOperation::Delete, fd, None, EventSet::IN, vec![]))
+            .collect();
+        MainLoop::update_event(notifiers)?;
+        self.random_file = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rng_rate_limit() {
+        assert_eq!(rng_rate(1024, 1000), 1024);
+        assert_eq!(rng_rate(100, 100), 1000);
+        assert_eq!(rng_rate(100, 3000), 33);
+        assert_eq!(rng_rate(1, 2000), 1);
+        assert_eq!(rng_rate(u64::MAX, 1), u64::MAX);
+
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(rng_rate(100, 1000), start);
+        assert_eq!(bucket.available(), 100);
+        bucket.consume(60);
+        bucket.refill(start + Duration::from_millis(10));
+        assert_eq!(bucket.available(), 41);
+        bucket.consume(41);
+        assert_eq!(bucket.available(), 0);
+        assert_eq!(bucket.wait_time(1), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_rng_realize() {
+        let mut rng = Rng::new(RngConfig {
+            rng_id: "rng0".to_string(),
+            random_file: "/dev/urandom".to_string(),
+            max_bytes: None,
+            period: None,
+        });
+        assert!(rng.realize().is_ok());
+        assert_eq!(rng.device_type(), VIRTIO_TYPE_RNG);
+        assert_eq!(rng.queue_num(), QUEUE_NUM_RNG);
+        assert_eq!(rng.get_device_features(1), 1);
+
+        let mut data = [0_u8; 4];
+        assert!(rng.read_config(0, &mut data).is_err());
+
+        let mut rng = Rng::new(RngConfig {
+            rng_id: "rng1".to_string(),
+            random_file: "/path/not/exist".to_string(),
+            max_bytes: None,
+            period: None,
+        });
+        assert!(rng.realize().is_err());
+    }
+}
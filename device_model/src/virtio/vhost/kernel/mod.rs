@@ -13,7 +13,7 @@
 mod net;
 mod vsock;
 
-pub use net::Net;
+pub use net::{Net, VhostNet};
 pub use vsock::Vsock;
 
 use std::fs::{File, OpenOptions};
@@ -45,6 +45,7 @@ ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, VhostMemory);
 ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VhostVringState);
 ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VhostVringAddr);
 ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VhostVringState);
+ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST, 0x12, VhostVringState);
 ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VhostVringFile);
 ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VhostVringFile);
 ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VhostVringFile);
@@ -144,6 +145,29 @@ impl VhostMemInfo {
         None
     }
 
+    /// Build the memory table passed to VHOST_SET_MEM_TABLE, which is a
+    /// `VhostMemory` header followed by all regions.
+    fn mem_table(&self) -> Vec<u8> {
+        let regions = self.regions.lock().unwrap();
+        let vm_size = std::mem::size_of::<VhostMemory>();
+        let vmr_size = std::mem::size_of::<VhostMemoryRegion>();
+        let mut bytes: Vec<u8> = vec![0; vm_size + regions.len() * vmr_size];
+
+        bytes[0..vm_size].copy_from_slice(
+            VhostMemory {
+                nregions: regions.len() as u32,
+                padding: 0,
+            }
+            .as_bytes(),
+        );
+
+        for (index, region) in regions.iter().enumerate() {
+            bytes[(vm_size + index * vmr_size)..(vm_size + (index + 1) * vmr_size)]
+                .copy_from_slice(region.as_bytes());
+        }
+        bytes
+    }
+
     fn check_vhost_mem_range(fr: &FlatRange) -> bool {
         fr.owner.region_type() == RegionType::Ram
     }
@@ -215,6 +239,10 @@ impl Listener for VhostMemInfo {
 pub struct VhostBackend {
     fd: File,
     mem_info: VhostMemInfo,
+    /// Address space whose Ram regions are passed to vhost.
+    mem_space: Arc<AddressSpace>,
+    /// Id of `mem_info` registered as listener of `mem_space`.
+    listener_id: u64,
 }
 
 impl VhostBackend {
@@ -233,9 +261,22 @@ impl VhostBackend {
                 .chain_err(|| format!("Failed to open {}.", path))?,
         };
         let mem_info = VhostMemInfo::new();
-        mem_space.register_listener(Box::new(mem_info.clone()))?;
+        let listener_id = mem_space.register_listener(Box::new(mem_info.clone()))?;
+
+        Ok(VhostBackend {
+            fd,
+            mem_info,
+            mem_space: mem_space.clone(),
+            listener_id,
+        })
+    }
+}
 
-        Ok(VhostBackend { fd, mem_info })
+impl Drop for VhostBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.mem_space.unregister_listener(self.listener_id) {
+            error!("Failed to unregister vhost memory listener, {}", e);
+        }
     }
 }
 
@@ -272,24 +313,7 @@ impl VhostOps for VhostBackend {
     }
 
     fn set_mem_table(&self) -> Result<()> {
-        let regions = self.mem_info.regions.lock().unwrap().len();
-        let vm_size = std::mem::size_of::<VhostMemory>();
-        let vmr_size = std::mem::size_of::<VhostMemoryRegion>();
-        let mut bytes: Vec<u8> = vec![0; vm_size + regions * vmr_size];
-
-        bytes[0..vm_size].copy_from_slice(
-            VhostMemory {
-                nregions: regions as u32,
-                padding: 0,
-            }
-            .as_bytes(),
-        );
-
-        for (index, region) in self.mem_info.regions.lock().unwrap().iter().enumerate() {
-            bytes[(vm_size + index * vmr_size)..(vm_size + (index + 1) * vmr_size)]
-                .copy_from_slice(region.as_bytes());
-        }
-
+        let bytes = self.mem_info.mem_table();
         let ret = unsafe { ioctl_with_ptr(self, VHOST_SET_MEM_TABLE(), bytes.as_ptr()) };
         if ret < 0 {
             return Err(ErrorKind::VhostIoctl("VHOST_SET_MEM_TABLE".to_string()).into());
@@ -368,6 +392,18 @@ impl VhostOps for VhostBackend {
         Ok(())
     }
 
+    fn get_vring_base(&self, queue_idx: usize) -> Result<u16> {
+        let mut vring_state = VhostVringState {
+            index: queue_idx as u32,
+            num: 0,
+        };
+        let ret = unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_BASE(), &mut vring_state) };
+        if ret < 0 {
+            return Err(ErrorKind::VhostIoctl("VHOST_GET_VRING_BASE".to_string()).into());
+        }
+        Ok(vring_state.num as u16)
+    }
+
     fn set_vring_call(&self, queue_idx: usize, fd: &EventFd) -> Result<()> {
         let vring_file = VhostVringFile {
             index: queue_idx as u32,
@@ -433,3 +469,80 @@ impl EventNotifierHelper for VhostIoHandler {
         notifiers
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use address_space::{HostMemMapping, Region, RegionOps};
+
+    use super::*;
+
+    #[test]
+    fn test_vhost_mem_table() {
+        let root = Region::init_container_region(1 << 20);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 0x3000, -1, 0, false, false).unwrap());
+        let high_ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0x8000), 0x2000, -1, 0, false, false).unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(Region::init_ram_region(ram.clone()), 0)
+            .unwrap();
+        sys_space
+            .root()
+            .add_subregion(Region::init_ram_region(high_ram.clone()), 0x8000)
+            .unwrap();
+
+        // IO region over Ram splits it into two flat-ranges, and is not passed to vhost.
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        let io_region = Region::init_io_region(0x1000, ops);
+        io_region.set_priority(1).unwrap();
+        sys_space.root().add_subregion(io_region, 0x1000).unwrap();
+
+        let mem_info = VhostMemInfo::new();
+        let id = sys_space
+            .register_listener(Box::new(mem_info.clone()))
+            .unwrap();
+        let expected = [
+            (0, 0x1000, ram.host_address()),
+            (0x2000, 0x1000, ram.host_address() + 0x2000),
+            (0x8000, 0x2000, high_ram.host_address()),
+        ];
+        {
+            let regions = mem_info.regions.lock().unwrap();
+            assert_eq!(regions.len(), expected.len());
+            for (region, (gpa, size, hva)) in regions.iter().zip(expected.iter()) {
+                assert_eq!(region.guest_phys_addr, *gpa);
+                assert_eq!(region.memory_size, *size);
+                assert_eq!(region.userspace_addr, *hva);
+            }
+        }
+        assert_eq!(
+            mem_info.addr_to_host(GuestAddress(0x2010)),
+            Some(ram.host_address() + 0x2010)
+        );
+        assert_eq!(mem_info.addr_to_host(GuestAddress(0x1010)), None);
+
+        // Table is the header followed by regions.
+        let table = mem_info.mem_table();
+        let vm_size = std::mem::size_of::<VhostMemory>();
+        let vmr_size = std::mem::size_of::<VhostMemoryRegion>();
+        assert_eq!(table.len(), vm_size + expected.len() * vmr_size);
+        let header = VhostMemory::from_bytes(&table[0..vm_size]).unwrap();
+        assert_eq!(header.nregions, 3);
+        let region =
+            VhostMemoryRegion::from_bytes(&table[vm_size + vmr_size..vm_size + 2 * vmr_size])
+                .unwrap();
+        assert_eq!(region.guest_phys_addr, 0x2000);
+        assert_eq!(region.userspace_addr, ram.host_address() + 0x2000);
+
+        // Regions are deleted once the listener is unregistered.
+        sys_space.unregister_listener(id).unwrap();
+        assert!(mem_info.regions.lock().unwrap().is_empty());
+        assert_eq!(mem_info.mem_table().len(), vm_size);
+    }
+}
@@ -11,18 +11,18 @@
 // See the Mulan PSL v2 for more details.
 
 use std::cmp;
-use std::fs::File;
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use machine_manager::config::NetworkInterfaceConfig;
 use util::byte_code::ByteCode;
-use util::epoll_context::EventNotifierHelper;
+use util::epoll_context::{EventNotifier, EventNotifierHelper, NotifierOperation};
 use util::num_ops::{read_u32, write_u32};
 use util::tap::Tap;
+use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::ioctl_with_ref;
 
@@ -52,16 +52,16 @@ trait VhostNetBackend {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_backend(&self, queue_index: usize, tap_file: &File) -> Result<()>;
+    /// * `tap_fd` - Fd of the tap device, or -1.
+    fn set_backend(&self, queue_index: usize, tap_fd: RawFd) -> Result<()>;
 }
 
 impl VhostNetBackend for VhostBackend {
     /// Attach virtio net ring to a raw socket, or tap device.
-    fn set_backend(&self, queue_index: usize, tap_file: &File) -> Result<()> {
+    fn set_backend(&self, queue_index: usize, tap_fd: RawFd) -> Result<()> {
         let vring_file = VhostVringFile {
             index: queue_index as u32,
-            fd: tap_file.as_raw_fd(),
+            fd: tap_fd,
         };
 
         let ret = unsafe { ioctl_with_ref(self, VHOST_NET_SET_BACKEND(), &vring_file) };
@@ -72,6 +72,137 @@ impl VhostNetBackend for VhostBackend {
     }
 }
 
+/// Vhost-net kernel backend of the virtio network device processed in
+/// userspace, which takes over its virtqueues from the userspace IO handler.
+pub struct VhostNet {
+    /// Related vhost-net kernel device.
+    backend: VhostBackend,
+    /// Bit mask of features supported by the vhost-net kernel.
+    vhost_features: u64,
+    /// Eventfds signaled by vhost-net, if virtqueues are processed by it.
+    host_notifies: Vec<RawFd>,
+}
+
+impl VhostNet {
+    /// Open vhost-net and set the current process as its owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_space` - System address space, whose Ram regions are passed to vhost-net.
+    /// * `vhost_fd` - Fd of vhost-net opened, `/dev/vhost-net` is opened if not provided.
+    pub fn new(mem_space: &Arc<AddressSpace>, vhost_fd: Option<RawFd>) -> Result<Self> {
+        let backend = VhostBackend::new(mem_space, "/dev/vhost-net", vhost_fd)?;
+        backend.set_owner()?;
+
+        let mut vhost_features = backend.get_features()?;
+        vhost_features &= !(1_u64 << VHOST_NET_F_VIRTIO_NET_HDR);
+        vhost_features &= !(1_u64 << VIRTIO_F_ACCESS_PLATFORM);
+
+        Ok(VhostNet {
+            backend,
+            vhost_features,
+            host_notifies: Vec::new(),
+        })
+    }
+
+    /// Let vhost-net process `queues` with `tap`, starting from the current
+    /// base of the available vrings.
+    ///
+    /// # Arguments
+    ///
+    /// * `driver_features` - Bit mask of features negotiated by the frontend.
+    /// * `queues` - Virtqueues of the device.
+    /// * `queue_evts` - Eventfds signaled from guest, used as vring kick fds.
+    /// * `interrupt_evt` - Eventfd to send interrupt to guest.
+    /// * `interrupt_status` - State of the interrupt in the device.
+    /// * `tap` - Tap device attached to virtqueues.
+    pub fn start(
+        &mut self,
+        driver_features: u64,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: &[EventFd],
+        interrupt_evt: &EventFd,
+        interrupt_status: Arc<AtomicU32>,
+        tap: &Tap,
+    ) -> Result<()> {
+        let backend = &self.backend;
+        backend.set_features(driver_features & self.vhost_features)?;
+        backend.set_mem_table()?;
+
+        let mut host_notifies = Vec::new();
+        for (queue_index, queue_mutex) in queues.iter().enumerate() {
+            let queue = queue_mutex.lock().unwrap();
+            let queue_config = queue.vring.get_queue_config();
+
+            backend.set_vring_num(queue_index, queue.vring.actual_size())?;
+            backend.set_vring_addr(&queue_config, queue_index, 0)?;
+            backend.set_vring_base(queue_index, queue.vring.get_avail_base())?;
+            backend.set_vring_kick(queue_index, &queue_evts[queue_index])?;
+            drop(queue);
+
+            let host_notify = VhostNotify {
+                notify_evt: EventFd::new(libc::EFD_NONBLOCK)
+                    .chain_err(|| ErrorKind::EventFdCreate)?,
+                queue: queue_mutex.clone(),
+            };
+            backend.set_vring_call(queue_index, &host_notify.notify_evt)?;
+            host_notifies.push(host_notify);
+        }
+
+        let handler = VhostIoHandler {
+            interrupt_evt: interrupt_evt.try_clone()?,
+            interrupt_status,
+            host_notifies,
+        };
+        self.host_notifies = handler
+            .host_notifies
+            .iter()
+            .map(|host_notify| host_notify.notify_evt.as_raw_fd())
+            .collect();
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
+            Mutex::new(handler),
+        )))?;
+
+        for queue_index in 0..queues.len() {
+            if let Err(e) = self.backend.set_backend(queue_index, tap.as_raw_fd()) {
+                if let Err(e) = self.stop(&queues[..queue_index]) {
+                    error!("Failed to stop vhost-net, {}", e);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop vhost-net processing `queues`, which continue from where vhost-net
+    /// stopped in the available vrings.
+    ///
+    /// # Arguments
+    ///
+    /// * `queues` - Virtqueues of the device processed by vhost-net.
+    pub fn stop(&mut self, queues: &[Arc<Mutex<Queue>>]) -> Result<()> {
+        let notifiers = self
+            .host_notifies
+            .drain(..)
+            .map(|fd| EventNotifier::new(NotifierOperation::Delete, fd, None, EventSet::IN, vec![]))
+            .collect();
+        MainLoop::update_event(notifiers)?;
+
+        for (queue_index, queue_mutex) in queues.iter().enumerate() {
+            self.backend.set_backend(queue_index, -1)?;
+            let base = self.backend.get_vring_base(queue_index)?;
+            queue_mutex
+                .lock()
+                .unwrap()
+                .vring
+                .set_avail_base(&self.backend.mem_space, base)?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Network device structure.
 pub struct Net {
     /// Configuration of the network device.
@@ -135,8 +266,9 @@ impl VirtioDevice for Net {
             _ => Some(self.net_cfg.host_dev_name.as_str()),
         };
 
-        self.tap =
-            create_tap(self.net_cfg.tap_fd, host_dev_name).chain_err(|| "Failed to create tap")?;
+        self.tap = create_tap(self.net_cfg.tap_fds.as_deref(), host_dev_name, 1)
+            .chain_err(|| "Failed to create tap")?
+            .pop();
         self.backend = Some(backend);
         self.device_features = device_features;
         self.vhost_features = vhost_features;
@@ -249,7 +381,7 @@ impl VirtioDevice for Net {
                 None => bail!("Failed to get tap"),
                 Some(tap_) => tap_,
             };
-            backend.set_backend(queue_index, &tap.file)?;
+            backend.set_backend(queue_index, tap.as_raw_fd())?;
         }
 
         let handler = VhostIoHandler {
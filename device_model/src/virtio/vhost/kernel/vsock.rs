@@ -10,15 +10,16 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use byteorder::{ByteOrder, LittleEndian};
 use machine_manager::config::VsockConfig;
-use util::epoll_context::EventNotifierHelper;
+use util::epoll_context::{EventNotifier, EventNotifierHelper, NotifierOperation};
 use util::num_ops::{read_u32, write_u32};
+use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::ioctl_with_ref;
 
@@ -75,6 +76,8 @@ pub struct Vsock {
     config_space: Vec<u8>,
     /// System address space.
     mem_space: Arc<AddressSpace>,
+    /// Eventfds of vrings handled in main loop since the device is activated.
+    host_notify_fds: Vec<RawFd>,
 }
 
 impl Vsock {
@@ -86,6 +89,7 @@ impl Vsock {
             driver_features: 0_u64,
             config_space: Vec::new(),
             mem_space,
+            host_notify_fds: Vec::new(),
         }
     }
 }
@@ -205,6 +209,8 @@ impl VirtioDevice for Vsock {
                 queue: queue_mutex.clone(),
             };
             backend.set_vring_call(queue_index, &host_notify.notify_evt)?;
+            self.host_notify_fds
+                .push(host_notify.notify_evt.as_raw_fd());
             host_notifies.push(host_notify);
         }
 
@@ -223,4 +229,21 @@ impl VirtioDevice for Vsock {
 
         Ok(())
     }
+
+    /// Stop vhost-vsock and the handling of its vrings.
+    fn unrealize(&mut self) -> Result<()> {
+        if let Some(backend) = self.backend.take() {
+            if !self.host_notify_fds.is_empty() {
+                backend.set_running(false)?;
+            }
+        }
+        let notifiers = self
+            .host_notify_fds
+            .drain(..)
+            .map(|fd| EventNotifier::new(NotifierOperation::Delete, fd, None, EventSet::IN, vec![]))
+            .collect();
+        MainLoop::update_event(notifiers)?;
+
+        Ok(())
+    }
 }
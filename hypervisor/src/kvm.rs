@@ -0,0 +1,559 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! KVM backend of hypervisor abstraction.
+
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{fence, Ordering};
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{kvm_clock_data, kvm_dtable, kvm_regs, kvm_segment, kvm_sregs};
+use kvm_bindings::{
+    kvm_userspace_memory_region, KVMIO, KVM_CAP_COALESCED_MMIO, KVM_MEM_LOG_DIRTY_PAGES,
+    KVM_MEM_READONLY,
+};
+use kvm_ioctls::{Cap, IoEventAddress, NoDatamatch, VcpuFd, VmFd};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl_with_ref, ioctl_with_val};
+
+use crate::errors::{ErrorKind, Result};
+#[cfg(target_arch = "x86_64")]
+use crate::{
+    CpuRegisterState, CpuSpecialRegisterState, DescriptorTable, SegmentRegister, EFER_LMA,
+    EFER_LME, X86_CR0_PE, X86_CR0_PG, X86_CR4_PAE,
+};
+use crate::{DataMatch, IoEventAddr, MemorySlot, VmOps};
+
+// Ioctls of coalesced MMIO, which are not wrapped by kvm-ioctls.
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_iow_nr!(KVM_REGISTER_COALESCED_MMIO, KVMIO, 0x67, CoalescedMmioZone);
+ioctl_iow_nr!(
+    KVM_UNREGISTER_COALESCED_MMIO,
+    KVMIO,
+    0x68,
+    CoalescedMmioZone
+);
+
+/// `struct kvm_coalesced_mmio_zone` in linux/kvm.h.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+struct CoalescedMmioZone {
+    addr: u64,
+    size: u32,
+    pio: u32,
+}
+
+impl CoalescedMmioZone {
+    fn new(addr: u64, size: u64) -> Result<Self> {
+        if size > u64::from(std::u32::MAX) {
+            return Err(ErrorKind::UnsupportedZoneSize(size).into());
+        }
+        Ok(CoalescedMmioZone {
+            addr,
+            size: size as u32,
+            pio: 0,
+        })
+    }
+}
+
+/// Header of `struct kvm_coalesced_mmio_ring` in linux/kvm.h.
+#[repr(C)]
+struct CoalescedMmioRingHeader {
+    first: u32,
+    last: u32,
+}
+
+/// `struct kvm_coalesced_mmio` in linux/kvm.h, an entry of the ring.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CoalescedMmioEntry {
+    phys_addr: u64,
+    len: u32,
+    pio: u32,
+    data: [u8; 8],
+}
+
+/// Ring of guest writes batched in coalesced MMIO zones. It's shared by all
+/// vcpus of a VM, and mapped through the file descriptor of any of them.
+pub struct CoalescedMmioRing {
+    /// Host address of the ring page.
+    addr: *mut CoalescedMmioRingHeader,
+    /// Size of the ring page.
+    size: usize,
+    /// Number of entries in the ring.
+    max: u32,
+}
+
+// The ring page is only accessed through `&mut self`.
+unsafe impl Send for CoalescedMmioRing {}
+
+impl CoalescedMmioRing {
+    /// Map the coalesced MMIO ring of `vm` through `vcpu` of it.
+    /// Return `None` if KVM doesn't support coalesced MMIO.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to map the ring page.
+    pub fn new(vm: &VmFd, vcpu: &VcpuFd) -> Result<Option<Self>> {
+        // The extension is the page offset of the ring in vcpu mapping.
+        // Safe because it only queries the capability.
+        let page_offset = unsafe {
+            ioctl_with_val(
+                vm,
+                KVM_CHECK_EXTENSION(),
+                libc::c_ulong::from(KVM_CAP_COALESCED_MMIO),
+            )
+        };
+        if page_offset <= 0 {
+            return Ok(None);
+        }
+
+        // Safe because sysconf doesn't touch memory.
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // Safe because a new mapping is created, and the result is checked.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                page_offset as libc::off_t * size as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let max = (size - std::mem::size_of::<CoalescedMmioRingHeader>())
+            / std::mem::size_of::<CoalescedMmioEntry>();
+        Ok(Some(CoalescedMmioRing {
+            addr: addr as *mut CoalescedMmioRingHeader,
+            size,
+            max: max as u32,
+        }))
+    }
+
+    /// Pop the oldest write in the ring, as its guest physical address and
+    /// data written.
+    pub fn pop(&mut self) -> Option<(u64, Vec<u8>)> {
+        // Safe because the ring page is mapped during the lifetime of self,
+        // and KVM only appends entries at `last`.
+        unsafe {
+            let first = std::ptr::read_volatile(&(*self.addr).first);
+            if first == std::ptr::read_volatile(&(*self.addr).last) {
+                return None;
+            }
+            fence(Ordering::Acquire);
+
+            let entries = self.addr.add(1) as *const CoalescedMmioEntry;
+            let entry = std::ptr::read_volatile(entries.add(first as usize));
+            fence(Ordering::Release);
+            std::ptr::write_volatile(&mut (*self.addr).first, (first + 1) % self.max);
+
+            let len = std::cmp::min(entry.len as usize, entry.data.len());
+            Some((entry.phys_addr, entry.data[..len].to_vec()))
+        }
+    }
+}
+
+impl Drop for CoalescedMmioRing {
+    fn drop(&mut self) {
+        // Safe because the ring page is mapped by `new`.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl From<SegmentRegister> for kvm_segment {
+    fn from(seg: SegmentRegister) -> Self {
+        kvm_segment {
+            base: seg.base,
+            limit: seg.limit,
+            selector: seg.selector,
+            type_: seg.type_,
+            present: seg.present,
+            dpl: seg.dpl,
+            db: seg.db,
+            s: seg.s,
+            l: seg.l,
+            g: seg.g,
+            avl: seg.avl,
+            unusable: seg.unusable,
+            padding: 0,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl From<kvm_segment> for SegmentRegister {
+    fn from(seg: kvm_segment) -> Self {
+        SegmentRegister {
+            base: seg.base,
+            limit: seg.limit,
+            selector: seg.selector,
+            type_: seg.type_,
+            present: seg.present,
+            dpl: seg.dpl,
+            db: seg.db,
+            s: seg.s,
+            l: seg.l,
+            g: seg.g,
+            avl: seg.avl,
+            unusable: seg.unusable,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl From<CpuRegisterState> for kvm_regs {
+    fn from(regs: CpuRegisterState) -> Self {
+        kvm_regs {
+            rflags: regs.rflags,
+            rip: regs.rip,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            rsi: regs.rsi,
+            rbx: regs.rbx,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl From<DescriptorTable> for kvm_dtable {
+    fn from(table: DescriptorTable) -> Self {
+        kvm_dtable {
+            base: table.base,
+            limit: table.limit,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl CpuSpecialRegisterState {
+    /// Load this state into `sregs` read from vcpu, keeping bits of control
+    /// registers which aren't managed.
+    pub fn load_into(&self, sregs: &mut kvm_sregs) {
+        sregs.cs = self.cs.into();
+        sregs.ds = self.ds.into();
+        sregs.es = self.es.into();
+        sregs.fs = self.fs.into();
+        sregs.gs = self.gs.into();
+        sregs.ss = self.ss.into();
+        if let Some(tr) = self.tr {
+            sregs.tr = tr.into();
+        }
+        sregs.gdt = self.gdt.into();
+        sregs.idt = self.idt.into();
+        sregs.cr0 = (sregs.cr0 & !(X86_CR0_PE | X86_CR0_PG)) | self.cr0;
+        sregs.cr3 = self.cr3;
+        sregs.cr4 = (sregs.cr4 & !X86_CR4_PAE) | self.cr4;
+        sregs.efer = (sregs.efer & !(EFER_LME | EFER_LMA)) | self.efer;
+    }
+}
+
+impl From<MemorySlot> for kvm_userspace_memory_region {
+    fn from(slot: MemorySlot) -> Self {
+        let mut flags = 0;
+        if slot.log_dirty {
+            flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        }
+        if slot.read_only {
+            flags |= KVM_MEM_READONLY;
+        }
+        kvm_userspace_memory_region {
+            slot: slot.slot,
+            guest_phys_addr: slot.guest_addr,
+            memory_size: slot.size,
+            userspace_addr: slot.host_addr,
+            flags,
+        }
+    }
+}
+
+impl From<IoEventAddr> for IoEventAddress {
+    fn from(addr: IoEventAddr) -> Self {
+        match addr {
+            IoEventAddr::Pio(addr) => IoEventAddress::Pio(addr),
+            IoEventAddr::Mmio(addr) => IoEventAddress::Mmio(addr),
+        }
+    }
+}
+
+impl VmOps for VmFd {
+    type Vcpu = VcpuFd;
+
+    fn create_vcpu(&self, id: u8) -> Result<VcpuFd> {
+        Ok(VmFd::create_vcpu(self, id)?)
+    }
+
+    unsafe fn set_memory_slot(&self, slot: MemorySlot) -> Result<()> {
+        Ok(self.set_user_memory_region(slot.into())?)
+    }
+
+    fn readonly_mem_supported(&self) -> bool {
+        self.check_extension(Cap::ReadonlyMem)
+    }
+
+    fn get_dirty_log(&self, slot: u32, size: u64) -> Result<Vec<u64>> {
+        Ok(VmFd::get_dirty_log(self, slot, size as usize)?)
+    }
+
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        Ok(VmFd::register_irqfd(self, fd, gsi)?)
+    }
+
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        Ok(VmFd::unregister_irqfd(self, fd, gsi)?)
+    }
+
+    fn register_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddr,
+        datamatch: DataMatch,
+    ) -> Result<()> {
+        let addr: IoEventAddress = addr.into();
+        match datamatch {
+            DataMatch::None => self.register_ioevent(fd, &addr, NoDatamatch)?,
+            DataMatch::U16(data) => self.register_ioevent(fd, &addr, data)?,
+            DataMatch::U32(data) => self.register_ioevent(fd, &addr, data)?,
+            DataMatch::U64(data) => self.register_ioevent(fd, &addr, data)?,
+        }
+        Ok(())
+    }
+
+    fn unregister_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddr,
+        datamatch: DataMatch,
+    ) -> Result<()> {
+        let addr: IoEventAddress = addr.into();
+        match datamatch {
+            DataMatch::None => self.unregister_ioevent(fd, &addr, NoDatamatch)?,
+            DataMatch::U16(data) => self.unregister_ioevent(fd, &addr, data)?,
+            DataMatch::U32(data) => self.unregister_ioevent(fd, &addr, data)?,
+            DataMatch::U64(data) => self.unregister_ioevent(fd, &addr, data)?,
+        }
+        Ok(())
+    }
+
+    fn register_coalesced_mmio(&self, addr: u64, size: u64) -> Result<()> {
+        let zone = CoalescedMmioZone::new(addr, size)?;
+        // Safe because zone is a valid kvm_coalesced_mmio_zone, and the result
+        // is checked.
+        let ret = unsafe { ioctl_with_ref(self, KVM_REGISTER_COALESCED_MMIO(), &zone) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn unregister_coalesced_mmio(&self, addr: u64, size: u64) -> Result<()> {
+        let zone = CoalescedMmioZone::new(addr, size)?;
+        // Safe because zone is a valid kvm_coalesced_mmio_zone, and the result
+        // is checked.
+        let ret = unsafe { ioctl_with_ref(self, KVM_UNREGISTER_COALESCED_MMIO(), &zone) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_clock_ns(&self) -> Result<u64> {
+        Ok(self.get_clock()?.clock)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_clock_ns(&self, ns: u64) -> Result<()> {
+        let clock = kvm_clock_data {
+            clock: ns,
+            ..Default::default()
+        };
+        Ok(self.set_clock(&clock)?)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_segment_to_kvm() {
+        let code_seg = SegmentRegister {
+            base: 0x1234_5678,
+            limit: 0xf_ffff,
+            selector: 0x10,
+            type_: 0xb,
+            present: 1,
+            dpl: 3,
+            db: 0,
+            s: 1,
+            l: 1,
+            g: 1,
+            avl: 1,
+            unusable: 0,
+        };
+        let kvm_seg: kvm_segment = code_seg.into();
+
+        assert_eq!(kvm_seg.base, 0x1234_5678);
+        assert_eq!(kvm_seg.limit, 0xf_ffff);
+        assert_eq!(kvm_seg.selector, 0x10);
+        assert_eq!(kvm_seg.type_, 0xb);
+        assert_eq!(kvm_seg.present, 1);
+        assert_eq!(kvm_seg.dpl, 3);
+        assert_eq!(kvm_seg.db, 0);
+        assert_eq!(kvm_seg.s, 1);
+        assert_eq!(kvm_seg.l, 1);
+        assert_eq!(kvm_seg.g, 1);
+        assert_eq!(kvm_seg.avl, 1);
+        assert_eq!(kvm_seg.unusable, 0);
+        assert_eq!(kvm_seg.padding, 0);
+    }
+
+    #[test]
+    fn test_memory_slot_to_kvm() {
+        let mut slot = MemorySlot {
+            slot: 1 | (1 << 16),
+            guest_addr: 0x10_0000,
+            size: 0x20_0000,
+            host_addr: 0x7f00_0000_0000,
+            log_dirty: false,
+            read_only: false,
+        };
+        let region: kvm_userspace_memory_region = slot.into();
+        assert_eq!(region.slot, 0x1_0001);
+        assert_eq!(region.guest_phys_addr, 0x10_0000);
+        assert_eq!(region.memory_size, 0x20_0000);
+        assert_eq!(region.userspace_addr, 0x7f00_0000_0000);
+        assert_eq!(region.flags, 0);
+
+        slot.log_dirty = true;
+        let region: kvm_userspace_memory_region = slot.into();
+        assert_eq!(region.flags, KVM_MEM_LOG_DIRTY_PAGES);
+
+        slot.read_only = true;
+        let region: kvm_userspace_memory_region = slot.into();
+        assert_eq!(region.flags, KVM_MEM_LOG_DIRTY_PAGES | KVM_MEM_READONLY);
+    }
+
+    #[test]
+    fn test_coalesced_mmio_zone() {
+        let zone = CoalescedMmioZone::new(0xd000_0000, 0x1000).unwrap();
+        assert_eq!(
+            zone,
+            CoalescedMmioZone {
+                addr: 0xd000_0000,
+                size: 0x1000,
+                pio: 0,
+            }
+        );
+        assert_eq!(std::mem::size_of::<CoalescedMmioZone>(), 16);
+        assert_eq!(std::mem::size_of::<CoalescedMmioEntry>(), 24);
+        assert!(CoalescedMmioZone::new(0, 1 << 32).is_err());
+    }
+
+    #[test]
+    fn test_segment_round_trip() {
+        let data_seg = SegmentRegister {
+            base: 0,
+            limit: 0xf_ffff,
+            selector: 0x18,
+            type_: 3,
+            present: 1,
+            dpl: 0,
+            db: 1,
+            s: 1,
+            l: 0,
+            g: 1,
+            avl: 0,
+            unusable: 1,
+        };
+        let kvm_seg: kvm_segment = data_seg.into();
+        assert_eq!(SegmentRegister::from(kvm_seg), data_seg);
+    }
+
+    #[test]
+    fn test_regs_to_kvm() {
+        let regs = CpuRegisterState {
+            rflags: 0x0002,
+            rip: 0x100_0000,
+            rsp: 0x8ff0,
+            rbp: 0x8ff0,
+            rsi: 0x7000,
+            rbx: 0x6000,
+        };
+        let kvm_regs: kvm_regs = regs.into();
+
+        assert_eq!(kvm_regs.rflags, 0x0002);
+        assert_eq!(kvm_regs.rip, 0x100_0000);
+        assert_eq!(kvm_regs.rsp, 0x8ff0);
+        assert_eq!(kvm_regs.rbp, 0x8ff0);
+        assert_eq!(kvm_regs.rsi, 0x7000);
+        assert_eq!(kvm_regs.rbx, 0x6000);
+        assert_eq!(kvm_regs.rax, 0);
+    }
+
+    #[test]
+    fn test_load_special_regs() {
+        let tss = SegmentRegister {
+            selector: 0x20,
+            type_: 0xb,
+            present: 1,
+            ..Default::default()
+        };
+        let state = CpuSpecialRegisterState {
+            tr: Some(tss),
+            gdt: DescriptorTable {
+                base: 0x500,
+                limit: 0x1f,
+            },
+            cr0: X86_CR0_PE | X86_CR0_PG,
+            cr3: 0x9000,
+            cr4: X86_CR4_PAE,
+            efer: EFER_LME | EFER_LMA,
+            ..Default::default()
+        };
+        // Reset state of cache disable, extension type and MCE bits is kept.
+        let mut sregs = kvm_sregs {
+            cr0: 0x6000_0010,
+            cr4: 0x40,
+            efer: 0x1,
+            ..Default::default()
+        };
+        state.load_into(&mut sregs);
+        assert_eq!(sregs.cr0, 0xe000_0011);
+        assert_eq!(sregs.cr3, 0x9000);
+        assert_eq!(sregs.cr4, 0x60);
+        assert_eq!(sregs.efer, 0x501);
+        assert_eq!((sregs.gdt.base, sregs.gdt.limit), (0x500, 0x1f));
+        assert_eq!(SegmentRegister::from(sregs.tr), tss);
+
+        // Boot bits are cleared if not set in state.
+        let state = CpuSpecialRegisterState {
+            cr0: X86_CR0_PE,
+            ..Default::default()
+        };
+        sregs.tr.selector = 0x28;
+        state.load_into(&mut sregs);
+        assert_eq!(sregs.cr0, 0x6000_0011);
+        assert_eq!(sregs.cr4, 0x40);
+        assert_eq!(sregs.efer, 0x1);
+        assert_eq!(sregs.tr.selector, 0x28);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! # Hypervisor
+//!
+//! The crate to isolate accelerator specific code from the rest of StratoVirt.
+//!
+//! ## Design
+//!
+//! This crate offers support for:
+//! 1. Accelerator neutral cpu register types, such as `SegmentRegister`,
+//!    `CpuRegisterState` and `CpuSpecialRegisterState`, which can be used by
+//!    boot loader and cpu layer.
+//! 2. `VmOps` trait, describing the per-VM operations the machine needs.
+//! 3. KVM backend, the only place to convert between neutral types and
+//!    `kvm_bindings` types.
+
+extern crate kvm_bindings;
+extern crate kvm_ioctls;
+extern crate libc;
+#[macro_use]
+extern crate vmm_sys_util;
+#[macro_use]
+extern crate error_chain;
+
+pub mod kvm;
+
+use vmm_sys_util::eventfd::EventFd;
+
+pub mod errors {
+    error_chain! {
+        foreign_links {
+            KvmIoctl(kvm_ioctls::Error);
+            Io(std::io::Error);
+        }
+        errors {
+            UnsupportedDataMatch(len: u64) {
+                display("Unexpected ioeventfd data length {}", len)
+            }
+            UnsupportedZoneSize(size: u64) {
+                display("Coalesced MMIO zone size {} exceeds 32 bits", size)
+            }
+        }
+    }
+}
+
+use self::errors::{ErrorKind, Result};
+
+/// Segment register of x86 cpu, defined as the cached part of a segment
+/// descriptor (Intel SDM 3A 3.4.5).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct SegmentRegister {
+    /// Segment base address.
+    pub base: u64,
+    /// Segment limit.
+    pub limit: u32,
+    /// Segment selector.
+    pub selector: u16,
+    /// Segment type.
+    pub type_: u8,
+    /// Segment present.
+    pub present: u8,
+    /// Descriptor privilege level.
+    pub dpl: u8,
+    /// Default operation size.
+    pub db: u8,
+    /// Descriptor type.
+    pub s: u8,
+    /// 64-bit code segment.
+    pub l: u8,
+    /// Granularity.
+    pub g: u8,
+    /// Available for use by system software.
+    pub avl: u8,
+    /// Segment is unusable.
+    pub unusable: u8,
+}
+
+/// General purpose registers which are set before vcpu starts running.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CpuRegisterState {
+    /// Register %rflags value.
+    pub rflags: u64,
+    /// Register %rip value.
+    pub rip: u64,
+    /// Register %rsp value.
+    pub rsp: u64,
+    /// Register %rbp value.
+    pub rbp: u64,
+    /// Register %rsi value.
+    pub rsi: u64,
+    /// Register %rbx value.
+    pub rbx: u64,
+}
+
+// Bits of control registers and EFER set up for booting.
+// arch/x86/include/uapi/asm/processor-flags.h
+/// CR0 protection enable.
+pub const X86_CR0_PE: u64 = 0x1;
+/// CR0 paging.
+pub const X86_CR0_PG: u64 = 0x8000_0000;
+/// CR4 physical address extension.
+pub const X86_CR4_PAE: u64 = 0x20;
+/// EFER long mode enable.
+pub const EFER_LME: u64 = 0x100;
+/// EFER long mode active.
+pub const EFER_LMA: u64 = 0x400;
+
+/// Base and limit of gdt or idt.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DescriptorTable {
+    pub base: u64,
+    pub limit: u16,
+}
+
+/// Segment, descriptor table and control registers which are set before
+/// vcpu starts running.
+///
+/// Only boot bits of `cr0` (`X86_CR0_PE`, `X86_CR0_PG`), `cr4`
+/// (`X86_CR4_PAE`) and `efer` (`EFER_LME`, `EFER_LMA`) are managed, other
+/// bits of vcpu's reset state are kept when it's loaded.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CpuSpecialRegisterState {
+    pub cs: SegmentRegister,
+    pub ds: SegmentRegister,
+    pub es: SegmentRegister,
+    pub fs: SegmentRegister,
+    pub gs: SegmentRegister,
+    pub ss: SegmentRegister,
+    /// Task register, kept as is if `None`.
+    pub tr: Option<SegmentRegister>,
+    pub gdt: DescriptorTable,
+    pub idt: DescriptorTable,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+/// A guest memory slot mapped to host virtual memory.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MemorySlot {
+    /// Index of slot, with address space id in high 16 bits.
+    pub slot: u32,
+    /// Guest physical address of slot.
+    pub guest_addr: u64,
+    /// Size of slot, `0` means deleting this slot.
+    pub size: u64,
+    /// Host virtual address of slot.
+    pub host_addr: u64,
+    /// Log guest writes to slot, which are fetched by `get_dirty_log`.
+    pub log_dirty: bool,
+    /// Guest writes to slot exit to userspace instead of modifying memory,
+    /// only valid if `readonly_mem_supported`.
+    pub read_only: bool,
+}
+
+/// Address an ioeventfd is triggered on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoEventAddr {
+    /// Port I/O address.
+    Pio(u64),
+    /// Memory mapped I/O address.
+    Mmio(u64),
+}
+
+/// Data an ioeventfd is compared with when guest writes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataMatch {
+    /// Trigger ioeventfd on any data.
+    None,
+    U16(u16),
+    U32(u32),
+    U64(u64),
+}
+
+impl DataMatch {
+    /// Build `DataMatch` with the length of ioeventfd address range.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - Length of data, should be 2, 4 or 8.
+    /// * `data` - Data to match.
+    pub fn with_len(len: u64, data: u64) -> Result<Self> {
+        match len {
+            2 => Ok(DataMatch::U16(data as u16)),
+            4 => Ok(DataMatch::U32(data as u32)),
+            8 => Ok(DataMatch::U64(data)),
+            _ => Err(ErrorKind::UnsupportedDataMatch(len).into()),
+        }
+    }
+}
+
+/// Per-VM operations provided by an accelerator.
+pub trait VmOps {
+    /// Vcpu handle of this accelerator.
+    type Vcpu;
+
+    /// Create a vcpu with given id.
+    fn create_vcpu(&self, id: u8) -> Result<Self::Vcpu>;
+
+    /// Add, modify or delete (with zero size) a memory slot.
+    ///
+    /// # Safety
+    ///
+    /// Host memory of `slot` must stay valid until the slot is deleted.
+    unsafe fn set_memory_slot(&self, slot: MemorySlot) -> Result<()>;
+
+    /// Whether memory slots can be `read_only`.
+    fn readonly_mem_supported(&self) -> bool;
+
+    /// Get and reset dirty page bitmap of memory slot `slot` of `size` bytes,
+    /// one bit per page. The slot must be set with `log_dirty`.
+    fn get_dirty_log(&self, slot: u32, size: u64) -> Result<Vec<u64>>;
+
+    /// Route notifications of `fd` to the guest interrupt `gsi`.
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Remove an irqfd registered by `register_irqfd`.
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Signal `fd` when guest writes `datamatch` to `addr`.
+    fn register_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddr,
+        datamatch: DataMatch,
+    ) -> Result<()>;
+
+    /// Remove an ioeventfd registered by `register_ioeventfd`.
+    fn unregister_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddr,
+        datamatch: DataMatch,
+    ) -> Result<()>;
+
+    /// Batch guest writes to MMIO range [`addr`, `addr` + `size`) in the
+    /// coalesced ring instead of exiting to userspace for each of them.
+    fn register_coalesced_mmio(&self, addr: u64, size: u64) -> Result<()>;
+
+    /// Remove a zone registered by `register_coalesced_mmio`.
+    fn unregister_coalesced_mmio(&self, addr: u64, size: u64) -> Result<()>;
+
+    /// Get guest paravirtual clock (kvmclock) value in nanoseconds.
+    #[cfg(target_arch = "x86_64")]
+    fn get_clock_ns(&self) -> Result<u64>;
+
+    /// Set guest paravirtual clock (kvmclock) value in nanoseconds.
+    #[cfg(target_arch = "x86_64")]
+    fn set_clock_ns(&self, ns: u64) -> Result<()>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_datamatch_with_len() {
+        assert_eq!(
+            DataMatch::with_len(2, 0x1_0002).unwrap(),
+            DataMatch::U16(0x2)
+        );
+        assert_eq!(
+            DataMatch::with_len(4, 0x1_0000_0003).unwrap(),
+            DataMatch::U32(0x3)
+        );
+        assert_eq!(
+            DataMatch::with_len(8, 0x1_0000_0004).unwrap(),
+            DataMatch::U64(0x1_0000_0004)
+        );
+        assert!(DataMatch::with_len(1, 0).is_err());
+    }
+}
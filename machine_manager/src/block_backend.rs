@@ -0,0 +1,457 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Registry of block backends in use by block devices, with statistics of IO
+//! requests they complete and limits to throttle IO requests.
+
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use util::rate_limiter::RateLimiter;
+
+use crate::errors::Result;
+
+#[cfg(feature = "qmp")]
+use crate::qmp::qmp_schema::{BlockDeviceInfo, BlockDeviceStats, BlockInfo, BlockStats};
+
+/// Statistics of IO requests completed by a block backend.
+///
+/// Counters are updated on data path of block device, so they are only
+/// relaxed atomics and not consistent with each other.
+#[derive(Default)]
+pub struct BlockIoStats {
+    rd_bytes: AtomicU64,
+    wr_bytes: AtomicU64,
+    rd_operations: AtomicU64,
+    wr_operations: AtomicU64,
+    flush_operations: AtomicU64,
+}
+
+impl BlockIoStats {
+    /// Account a completed read request of `bytes` bytes.
+    pub fn account_read(&self, bytes: u64) {
+        self.rd_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.rd_operations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a completed write request of `bytes` bytes.
+    pub fn account_write(&self, bytes: u64) {
+        self.wr_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.wr_operations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a completed flush request.
+    pub fn account_flush(&self) {
+        self.flush_operations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "qmp")]
+    fn snapshot(&self) -> BlockDeviceStats {
+        BlockDeviceStats {
+            rd_bytes: self.rd_bytes.load(Ordering::Relaxed),
+            wr_bytes: self.wr_bytes.load(Ordering::Relaxed),
+            rd_operations: self.rd_operations.load(Ordering::Relaxed),
+            wr_operations: self.wr_operations.load(Ordering::Relaxed),
+            flush_operations: self.flush_operations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// IO throttling limits of a block backend, limit of 0 means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoThrottleLimits {
+    /// Total bytes per second.
+    pub bps: u64,
+    /// Read bytes per second.
+    pub bps_rd: u64,
+    /// Write bytes per second.
+    pub bps_wr: u64,
+    /// Total requests per second.
+    pub iops: u64,
+    /// Read requests per second.
+    pub iops_rd: u64,
+    /// Write requests per second.
+    pub iops_wr: u64,
+}
+
+impl IoThrottleLimits {
+    /// Check that total limit isn't set together with read or write limit of
+    /// the same kind.
+    pub fn check(&self) -> Result<()> {
+        if self.bps != 0 && (self.bps_rd != 0 || self.bps_wr != 0) {
+            bail!("bps can't be set together with bps_rd or bps_wr");
+        }
+        if self.iops != 0 && (self.iops_rd != 0 || self.iops_wr != 0) {
+            bail!("iops can't be set together with iops_rd or iops_wr");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct IoThrottleState {
+    limits: IoThrottleLimits,
+    total: RateLimiter,
+    read: RateLimiter,
+    write: RateLimiter,
+}
+
+/// IO throttling of a block backend, whose limits are set by QMP and consumed
+/// by IO requests of block device.
+#[derive(Default)]
+pub struct IoThrottle {
+    state: Mutex<IoThrottleState>,
+}
+
+impl IoThrottle {
+    /// Set throttling limits, budget of all limits is reset to full.
+    pub fn set_limits(&self, limits: IoThrottleLimits, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        *state = IoThrottleState {
+            limits,
+            total: RateLimiter::new(limits.bps, limits.iops, now),
+            read: RateLimiter::new(limits.bps_rd, limits.iops_rd, now),
+            write: RateLimiter::new(limits.bps_wr, limits.iops_wr, now),
+        };
+    }
+
+    /// Current throttling limits.
+    pub fn limits(&self) -> IoThrottleLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    /// Consume budget of a read or write request of `bytes` at `now`.
+    ///
+    /// Return time to wait if any limit is out of budget, in which case no
+    /// budget is consumed.
+    pub fn consume(&self, write: bool, bytes: u64, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let rw = if write {
+            &mut state.write
+        } else {
+            &mut state.read
+        };
+        if state.total.is_unlimited() && rw.is_unlimited() {
+            return None;
+        }
+
+        let wait = cmp::max(state.total.wait_time(bytes, now), rw.wait_time(bytes, now));
+        if wait > Duration::from_nanos(0) {
+            return Some(wait);
+        }
+        state.total.consume(bytes);
+        rw.consume(bytes);
+        None
+    }
+}
+
+/// Handle of a registered block backend, used by block device on data path.
+#[derive(Clone)]
+pub struct BlockBackendHandle {
+    /// Statistics which the device accounts its IO requests to.
+    pub stats: Arc<BlockIoStats>,
+    /// Throttling which the device consults before submitting IO requests.
+    pub throttle: Arc<IoThrottle>,
+}
+
+/// Backend information of a block device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockBackendInfo {
+    /// Node name given by `blockdev-add` or drive id.
+    pub node_name: String,
+    /// Path of image file on host.
+    pub file: String,
+    /// Backend is read only or not.
+    pub read_only: bool,
+    /// Format driver of image file.
+    pub driver: String,
+    /// Host IO engine in effect.
+    pub aio: String,
+}
+
+struct BlockBackend {
+    /// Id of the block device using the backend.
+    device: String,
+    info: BlockBackendInfo,
+    stats: Arc<BlockIoStats>,
+    throttle: Arc<IoThrottle>,
+}
+
+/// Registry of block backends, which block devices register when backends are
+/// inserted and QMP queries.
+#[derive(Default)]
+pub struct BlockBackendRegistry {
+    backends: Mutex<Vec<BlockBackend>>,
+}
+
+impl BlockBackendRegistry {
+    pub fn new() -> Self {
+        BlockBackendRegistry::default()
+    }
+
+    /// Register backend of block device `device`, the backend registered by
+    /// the device before is replaced and its statistics and throttling limits
+    /// are reset.
+    ///
+    /// Return handle of statistics and throttling of the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - Id of block device.
+    /// * `info` - Backend information.
+    pub fn register(&self, device: &str, info: BlockBackendInfo) -> BlockBackendHandle {
+        let handle = BlockBackendHandle {
+            stats: Arc::new(BlockIoStats::default()),
+            throttle: Arc::new(IoThrottle::default()),
+        };
+        let backend = BlockBackend {
+            device: device.to_string(),
+            info,
+            stats: handle.stats.clone(),
+            throttle: handle.throttle.clone(),
+        };
+
+        let mut backends = self.backends.lock().unwrap();
+        match backends.iter_mut().find(|b| b.device == device) {
+            Some(old) => *old = backend,
+            None => backends.push(backend),
+        }
+        handle
+    }
+
+    /// Unregister backend of block device `device`, return false if the
+    /// device has no backend registered.
+    pub fn unregister(&self, device: &str) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        let len = backends.len();
+        backends.retain(|b| b.device != device);
+        backends.len() != len
+    }
+
+    /// Find the block device whose backend is named `node_name`.
+    pub fn device_of_node(&self, node_name: &str) -> Option<String> {
+        self.backends
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|b| b.info.node_name == node_name)
+            .map(|b| b.device.clone())
+    }
+
+    /// Block device `device` has backend registered or not.
+    pub fn has_device(&self, device: &str) -> bool {
+        self.backends
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|b| b.device == device)
+    }
+
+    /// Set IO throttling limits of backend of block device `device`, return
+    /// false if the device has no backend registered.
+    pub fn set_io_throttle(&self, device: &str, limits: IoThrottleLimits) -> bool {
+        match self
+            .backends
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|b| b.device == device)
+        {
+            Some(backend) => {
+                backend.throttle.set_limits(limits, Instant::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Information of all registered backends, in order of registration.
+    #[cfg(feature = "qmp")]
+    pub fn query_block(&self) -> Vec<BlockInfo> {
+        self.backends
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|b| {
+                let limits = b.throttle.limits();
+                BlockInfo {
+                    device: b.device.clone(),
+                    inserted: BlockDeviceInfo {
+                        node_name: b.info.node_name.clone(),
+                        file: b.info.file.clone(),
+                        ro: b.info.read_only,
+                        drv: b.info.driver.clone(),
+                        aio: b.info.aio.clone(),
+                        bps: limits.bps,
+                        bps_rd: limits.bps_rd,
+                        bps_wr: limits.bps_wr,
+                        iops: limits.iops,
+                        iops_rd: limits.iops_rd,
+                        iops_wr: limits.iops_wr,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Statistics of all registered backends, in order of registration.
+    #[cfg(feature = "qmp")]
+    pub fn query_blockstats(&self) -> Vec<BlockStats> {
+        self.backends
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|b| BlockStats {
+                device: b.device.clone(),
+                stats: b.stats.snapshot(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "qmp"))]
+mod tests {
+    use super::*;
+
+    fn backend_info(node_name: &str) -> BlockBackendInfo {
+        BlockBackendInfo {
+            node_name: node_name.to_string(),
+            file: format!("/path/to/{}", node_name),
+            read_only: false,
+            driver: "raw".to_string(),
+            aio: "native".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_block_backend_registry() {
+        let registry = BlockBackendRegistry::new();
+        let stats = registry.register("drive-0", backend_info("drive-0")).stats;
+        registry.register("drive-1", backend_info("drive-1"));
+
+        stats.account_read(4096);
+        stats.account_read(512);
+        stats.account_write(1024);
+        stats.account_flush();
+
+        let blockstats = registry.query_blockstats();
+        assert_eq!(blockstats.len(), 2);
+        assert_eq!(blockstats[0].device, "drive-0");
+        assert_eq!(blockstats[0].stats.rd_bytes, 4608);
+        assert_eq!(blockstats[0].stats.rd_operations, 2);
+        assert_eq!(blockstats[0].stats.wr_bytes, 1024);
+        assert_eq!(blockstats[0].stats.wr_operations, 1);
+        assert_eq!(blockstats[0].stats.flush_operations, 1);
+        assert_eq!(blockstats[1].stats, BlockDeviceStats::default());
+
+        let block = registry.query_block();
+        assert_eq!(block[1].device, "drive-1");
+        assert_eq!(block[1].inserted.file, "/path/to/drive-1");
+        assert_eq!(block[1].inserted.drv, "raw");
+        assert_eq!(block[1].inserted.aio, "native");
+
+        // Replacing backend resets its statistics.
+        let mut info = backend_info("drive-2");
+        info.read_only = true;
+        registry.register("drive-0", info);
+        stats.account_write(512);
+        let blockstats = registry.query_blockstats();
+        assert_eq!(blockstats[0].stats, BlockDeviceStats::default());
+        let block = registry.query_block();
+        assert_eq!(block.len(), 2);
+        assert_eq!(block[0].inserted.node_name, "drive-2");
+        assert!(block[0].inserted.ro);
+
+        assert_eq!(
+            registry.device_of_node("drive-2"),
+            Some("drive-0".to_string())
+        );
+        assert_eq!(registry.device_of_node("drive-0"), None);
+        assert!(registry.has_device("drive-1"));
+
+        assert!(registry.unregister("drive-1"));
+        assert!(!registry.has_device("drive-1"));
+        assert!(!registry.unregister("drive-1"));
+        assert_eq!(registry.query_block().len(), 1);
+    }
+
+    #[test]
+    fn test_block_backend_io_throttle() {
+        let registry = BlockBackendRegistry::new();
+        let limits = IoThrottleLimits {
+            bps_wr: 8192,
+            iops: 4,
+            ..Default::default()
+        };
+        assert!(limits.check().is_ok());
+        assert!(!registry.set_io_throttle("drive-0", limits));
+
+        let throttle = registry
+            .register("drive-0", backend_info("drive-0"))
+            .throttle;
+        assert!(throttle.consume(true, 1 << 20, Instant::now()).is_none());
+        assert!(registry.set_io_throttle("drive-0", limits));
+        let block = registry.query_block();
+        assert_eq!(block[0].inserted.bps_wr, 8192);
+        assert_eq!(block[0].inserted.iops, 4);
+        assert_eq!(block[0].inserted.bps, 0);
+
+        let start = Instant::now();
+        throttle.set_limits(limits, start);
+        // Write request of 8192 bytes empties write bandwidth budget.
+        assert!(throttle.consume(true, 8192, start).is_none());
+        assert_eq!(
+            throttle.consume(true, 4096, start),
+            Some(Duration::from_millis(500))
+        );
+        // Deferred request consumes no budget, reads are only limited by iops.
+        assert!(throttle.consume(false, 1 << 20, start).is_none());
+        assert!(throttle.consume(false, 1 << 20, start).is_none());
+        assert!(throttle.consume(false, 1 << 20, start).is_none());
+        assert_eq!(
+            throttle.consume(false, 512, start),
+            Some(Duration::from_millis(250))
+        );
+        let later = start + Duration::from_millis(500);
+        assert!(throttle.consume(true, 4096, later).is_none());
+
+        // Throttling is removed by zero limits.
+        assert!(registry.set_io_throttle("drive-0", IoThrottleLimits::default()));
+        assert_eq!(registry.query_block()[0].inserted.iops, 0);
+        assert!(throttle.consume(true, 1 << 30, later).is_none());
+        assert!(throttle.consume(true, 1 << 30, later).is_none());
+
+        // Throttling is reset when backend is replaced.
+        assert!(registry.set_io_throttle("drive-0", limits));
+        let throttle = registry
+            .register("drive-0", backend_info("drive-1"))
+            .throttle;
+        assert_eq!(throttle.limits(), IoThrottleLimits::default());
+        assert_eq!(registry.query_block()[0].inserted.bps_wr, 0);
+
+        let invalid = IoThrottleLimits {
+            iops: 100,
+            iops_rd: 10,
+            ..Default::default()
+        };
+        assert!(invalid.check().is_err());
+        let invalid = IoThrottleLimits {
+            bps: 100,
+            bps_wr: 10,
+            ..Default::default()
+        };
+        assert!(invalid.check().is_err());
+    }
+}
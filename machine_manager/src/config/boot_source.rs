@@ -14,6 +14,8 @@ extern crate serde;
 extern crate serde_json;
 
 use std::fmt;
+use std::fs::File;
+use std::io::Read;
 use std::path::PathBuf;
 use std::sync::Mutex;
 
@@ -25,6 +27,16 @@ use crate::config::{ConfigCheck, Param, ParamOperation, VmConfig};
 const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
 
+/// Console device name of serial in guest kernel.
+#[cfg(target_arch = "x86_64")]
+const SERIAL_CONSOLE: &str = "ttyS0";
+#[cfg(target_arch = "aarch64")]
+const SERIAL_CONSOLE: &str = "ttyAMA0";
+/// Console device name of the first virtio-console in guest kernel.
+const VIRTIO_CONSOLE: &str = "hvc0";
+/// Block device name of the first virtio-blk in guest kernel.
+const VIRTIO_BLK_ROOT: &str = "/dev/vda";
+
 /// Config struct for boot-source.
 /// Contains `kernel_file`, `kernel_cmdline` and `initrd`.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -215,6 +227,31 @@ impl KernelParams {
         }
         false
     }
+
+    /// Check `KernelParam` whether contains flag `item` (param without `=`) or not.
+    pub fn contains_flag(&self, item: &str) -> bool {
+        for i in 0..self.length {
+            if self.params[i].param_type.is_empty() && self.params[i].value == item {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Push `item` to `KernelParams` only if it's not set yet, so params
+    /// provided by user always win. Return whether `item` is pushed.
+    pub fn push_if_absent(&mut self, item: Param) -> bool {
+        let exists = if item.param_type.is_empty() {
+            self.contains_flag(&item.value)
+        } else {
+            self.contains(&item.param_type)
+        };
+        if exists {
+            return false;
+        }
+        self.push(item);
+        true
+    }
 }
 
 impl fmt::Display for KernelParams {
@@ -243,12 +280,121 @@ impl VmConfig {
     pub fn update_initrd(&mut self, initrd: String) {
         self.boot_source.initrd = Some(InitrdConfig::new(&initrd));
     }
+
+    /// Add `console=` and `root=` to kernel cmdline if user doesn't provide
+    /// them, according to the configured devices.
+    ///
+    /// `console=` comes from serial, or the first virtio-console if there is
+    /// no serial. `root=` comes from the first virtio-blk device, and is
+    /// skipped if initrd is used. Return the params which are added.
+    pub fn synthesize_kernel_cmdline(&mut self) -> Vec<Param> {
+        let mut candidates: Vec<Param> = Vec::new();
+
+        if self.serial.is_some() {
+            candidates.push(Param {
+                param_type: "console".to_string(),
+                value: SERIAL_CONSOLE.to_string(),
+            });
+        } else if self.consoles.as_ref().map_or(false, |c| !c.is_empty()) {
+            candidates.push(Param {
+                param_type: "console".to_string(),
+                value: VIRTIO_CONSOLE.to_string(),
+            });
+        }
+
+        if self.boot_source.initrd.is_none() {
+            if let Some(drive) = self.drives.as_ref().and_then(|d| d.first()) {
+                let cmdline = &self.boot_source.kernel_cmdline;
+                if !cmdline.contains("root") {
+                    candidates.push(Param {
+                        param_type: "root".to_string(),
+                        value: VIRTIO_BLK_ROOT.to_string(),
+                    });
+                    if !cmdline.contains_flag("rw") && !cmdline.contains_flag("ro") {
+                        candidates.push(Param {
+                            param_type: String::new(),
+                            value: if drive.read_only { "ro" } else { "rw" }.to_string(),
+                        });
+                    }
+                    if let Some(fs_type) = detect_rootfs_type(&drive.path_on_host) {
+                        candidates.push(Param {
+                            param_type: "rootfstype".to_string(),
+                            value: fs_type.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut added = Vec::new();
+        for param in candidates {
+            if self
+                .boot_source
+                .kernel_cmdline
+                .push_if_absent(param.clone())
+            {
+                added.push(param);
+            }
+        }
+
+        if !added.is_empty() {
+            let added_str: Vec<String> = added.iter().map(|p| p.to_string()).collect();
+            info!("Kernel cmdline synthesized: {}", added_str.join(" "));
+        }
+        added
+    }
+}
+
+/// Detect filesystem type of rootfs image by its superblock magic.
+///
+/// # Arguments
+///
+/// * `path` - Path of rootfs image on host.
+fn detect_rootfs_type(path: &str) -> Option<&'static str> {
+    let mut buf = [0_u8; 1082];
+    let mut file = File::open(path).ok()?;
+    file.read_exact(&mut buf).ok()?;
+
+    if &buf[0..4] == b"XFSB" {
+        Some("xfs")
+    } else if &buf[0..4] == b"hsqs" {
+        Some("squashfs")
+    } else if buf[1080..1082] == [0x53, 0xef] {
+        // ext2/ext3 can also be mounted by ext4 driver.
+        Some("ext4")
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::{Param, ParamOperation};
-    use super::KernelParams;
+    use std::io::Write;
+
+    use super::super::{ConsoleConfig, DriveConfig, Param, ParamOperation, SerialConfig, VmConfig};
+    use super::*;
+
+    fn cmdline_vmconfig(append: &str) -> VmConfig {
+        let mut vm_config = VmConfig::default();
+        vm_config.boot_source.kernel_cmdline = KernelParams::from_str(append.to_string());
+        vm_config
+    }
+
+    fn virtio_console() -> Option<Vec<ConsoleConfig>> {
+        Some(vec![ConsoleConfig {
+            console_id: "console0".to_string(),
+            socket_path: "/tmp/console0.sock".to_string(),
+        }])
+    }
+
+    fn drive(path: &str, read_only: bool) -> Option<Vec<DriveConfig>> {
+        Some(vec![DriveConfig {
+            drive_id: "rootfs".to_string(),
+            path_on_host: path.to_string(),
+            read_only,
+            ..Default::default()
+        }])
+    }
 
     #[test]
     fn test_kernel_params() {
@@ -266,4 +412,141 @@ mod tests {
             "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0 maxcpus=8"
         );
     }
+
+    #[test]
+    fn test_push_if_absent() {
+        let mut params = KernelParams::from_str("console=ttyS1 rw".to_string());
+        assert!(!params.push_if_absent(Param::from_str("console=ttyS0")));
+        assert!(!params.push_if_absent(Param::from_str("rw")));
+        assert!(params.push_if_absent(Param::from_str("ro")));
+        assert!(params.push_if_absent(Param::from_str("root=/dev/vda")));
+        assert_eq!(params.to_string(), "console=ttyS1 rw ro root=/dev/vda");
+    }
+
+    #[test]
+    fn test_synthesize_console() {
+        // Nothing configured, nothing added.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        assert!(vm_config.synthesize_kernel_cmdline().is_empty());
+        assert_eq!(vm_config.boot_source.kernel_cmdline.to_string(), "panic=1");
+
+        // Serial only.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.serial = Some(SerialConfig { stdio: true });
+        assert_eq!(vm_config.synthesize_kernel_cmdline().len(), 1);
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            format!("panic=1 console={}", SERIAL_CONSOLE)
+        );
+
+        // Virtio-console only.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.consoles = virtio_console();
+        vm_config.synthesize_kernel_cmdline();
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "panic=1 console=hvc0"
+        );
+
+        // Serial and virtio-console, serial is preferred.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.serial = Some(SerialConfig { stdio: true });
+        vm_config.consoles = virtio_console();
+        vm_config.synthesize_kernel_cmdline();
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            format!("panic=1 console={}", SERIAL_CONSOLE)
+        );
+
+        // User provided console wins.
+        let mut vm_config = cmdline_vmconfig("console=hvc1 panic=1");
+        vm_config.serial = Some(SerialConfig { stdio: true });
+        assert!(vm_config.synthesize_kernel_cmdline().is_empty());
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "console=hvc1 panic=1"
+        );
+    }
+
+    #[test]
+    fn test_synthesize_root() {
+        // Writable drive, image can't be read so no rootfstype hint.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.drives = drive("/path/not/exist", false);
+        assert_eq!(vm_config.synthesize_kernel_cmdline().len(), 2);
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "panic=1 root=/dev/vda rw"
+        );
+
+        // Read-only drive.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.drives = drive("/path/not/exist", true);
+        vm_config.synthesize_kernel_cmdline();
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "panic=1 root=/dev/vda ro"
+        );
+
+        // User provided root wins, and nothing about rootfs is added.
+        let mut vm_config = cmdline_vmconfig("root=/dev/vdb panic=1");
+        vm_config.drives = drive("/path/not/exist", false);
+        assert!(vm_config.synthesize_kernel_cmdline().is_empty());
+
+        // User provided `ro` is kept.
+        let mut vm_config = cmdline_vmconfig("ro");
+        vm_config.drives = drive("/path/not/exist", false);
+        vm_config.synthesize_kernel_cmdline();
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "ro root=/dev/vda"
+        );
+
+        // Initrd is used as rootfs.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.drives = drive("/path/not/exist", false);
+        vm_config.boot_source.initrd = Some(InitrdConfig::default());
+        assert!(vm_config.synthesize_kernel_cmdline().is_empty());
+
+        // Serial and drive together.
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.serial = Some(SerialConfig { stdio: false });
+        vm_config.drives = drive("/path/not/exist", false);
+        assert_eq!(vm_config.synthesize_kernel_cmdline().len(), 3);
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            format!("panic=1 console={} root=/dev/vda rw", SERIAL_CONSOLE)
+        );
+    }
+
+    #[test]
+    fn test_synthesize_rootfstype() {
+        let image_path = std::env::temp_dir().join("stratovirt_test_ext4_rootfs.img");
+        let mut image = vec![0_u8; 2048];
+        image[1080] = 0x53;
+        image[1081] = 0xef;
+        File::create(&image_path)
+            .unwrap()
+            .write_all(&image)
+            .unwrap();
+
+        let mut vm_config = cmdline_vmconfig("panic=1");
+        vm_config.drives = drive(image_path.to_str().unwrap(), false);
+        vm_config.synthesize_kernel_cmdline();
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "panic=1 root=/dev/vda rw rootfstype=ext4"
+        );
+
+        // User provided rootfstype wins.
+        let mut vm_config = cmdline_vmconfig("rootfstype=ext3");
+        vm_config.drives = drive(image_path.to_str().unwrap(), false);
+        vm_config.synthesize_kernel_cmdline();
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "rootfstype=ext3 root=/dev/vda rw"
+        );
+
+        std::fs::remove_file(&image_path).unwrap();
+    }
 }
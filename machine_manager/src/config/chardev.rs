@@ -177,3 +177,44 @@ impl VmConfig {
         }
     }
 }
+
+/// Default source of entropy for virtio-rng.
+pub const DEFAULT_RNG_FILE: &str = "/dev/random";
+
+/// Config structure for virtio-rng.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RngConfig {
+    pub rng_id: String,
+    /// Host file which entropy is read from.
+    pub random_file: String,
+    /// Bytes allowed to feed guest in every `period`.
+    pub max_bytes: Option<u64>,
+    /// Period of rate limit in milliseconds.
+    pub period: Option<u64>,
+}
+
+impl ConfigCheck for RngConfig {
+    fn check(&self) -> Result<()> {
+        if self.rng_id.len() > MAX_STRING_LENGTH {
+            return Err(
+                ErrorKind::StringLengthTooLong("rng id".to_string(), MAX_STRING_LENGTH).into(),
+            );
+        }
+
+        if self.random_file.len() > MAX_PATH_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "rng filename".to_string(),
+                MAX_PATH_LENGTH,
+            )
+            .into());
+        }
+
+        match (self.max_bytes, self.period) {
+            (None, None) => {}
+            (Some(max_bytes), Some(period)) if max_bytes > 0 && period > 0 => {}
+            _ => return Err(ErrorKind::RngRateError.into()),
+        }
+
+        Ok(())
+    }
+}
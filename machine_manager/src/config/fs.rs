@@ -22,6 +22,154 @@ const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
 const MAX_SERIAL_NUM: usize = 20;
 
+/// Host IO engine of block backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AioEngine {
+    /// IO requests are done by synchronous syscalls in IO thread.
+    #[serde(rename = "threads")]
+    Threads,
+    /// IO requests are submitted to Linux native aio, requires direct IO.
+    #[serde(rename = "native")]
+    Native,
+    /// IO requests are submitted to Linux io_uring.
+    #[serde(rename = "io_uring")]
+    IoUring,
+}
+
+impl AioEngine {
+    fn from_str(engine: &str) -> Self {
+        match engine {
+            "threads" => AioEngine::Threads,
+            "native" => AioEngine::Native,
+            "io_uring" => AioEngine::IoUring,
+            _ => panic!("Can only give `threads`,`native`,`io_uring` for aio."),
+        }
+    }
+}
+
+impl std::fmt::Display for AioEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AioEngine::Threads => write!(f, "threads"),
+            AioEngine::Native => write!(f, "native"),
+            AioEngine::IoUring => write!(f, "io_uring"),
+        }
+    }
+}
+
+/// Handling of discard requests from guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiscardMode {
+    /// Discard requests are ignored.
+    #[serde(rename = "ignore")]
+    Ignore,
+    /// Discarded ranges are deallocated in image file.
+    #[serde(rename = "unmap")]
+    Unmap,
+}
+
+impl Default for DiscardMode {
+    fn default() -> Self {
+        DiscardMode::Ignore
+    }
+}
+
+impl DiscardMode {
+    fn from_str(mode: &str) -> Self {
+        match mode {
+            "ignore" => DiscardMode::Ignore,
+            "unmap" => DiscardMode::Unmap,
+            _ => panic!("Can only give `ignore`,`unmap` for discard."),
+        }
+    }
+}
+
+impl std::fmt::Display for DiscardMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DiscardMode::Ignore => write!(f, "ignore"),
+            DiscardMode::Unmap => write!(f, "unmap"),
+        }
+    }
+}
+
+/// Detection of writes of all zeroes from guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DetectZeroes {
+    /// Writes of zeroes are written as data.
+    #[serde(rename = "off")]
+    Off,
+    /// Writes of zeroes are turned into zeroing ranges of image file.
+    #[serde(rename = "on")]
+    On,
+    /// Writes of zeroes are turned into discards, requires discard `unmap`.
+    #[serde(rename = "unmap")]
+    Unmap,
+}
+
+impl Default for DetectZeroes {
+    fn default() -> Self {
+        DetectZeroes::Off
+    }
+}
+
+impl DetectZeroes {
+    fn from_str(mode: &str) -> Self {
+        match mode {
+            "off" => DetectZeroes::Off,
+            "on" => DetectZeroes::On,
+            "unmap" => DetectZeroes::Unmap,
+            _ => panic!("Can only give `off`,`on`,`unmap` for detect-zeroes."),
+        }
+    }
+}
+
+impl std::fmt::Display for DetectZeroes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DetectZeroes::Off => write!(f, "off"),
+            DetectZeroes::On => write!(f, "on"),
+            DetectZeroes::Unmap => write!(f, "unmap"),
+        }
+    }
+}
+
+/// Format of block image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageFormat {
+    /// Image is the disk as it is.
+    #[serde(rename = "raw")]
+    Raw,
+    /// Image is in qcow2 format.
+    #[serde(rename = "qcow2")]
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Raw
+    }
+}
+
+impl ImageFormat {
+    fn from_str(format: &str) -> Self {
+        match format {
+            "raw" => ImageFormat::Raw,
+            "qcow2" => ImageFormat::Qcow2,
+            _ => panic!("Can only give `raw`,`qcow2` for format."),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ImageFormat::Raw => write!(f, "raw"),
+            ImageFormat::Qcow2 => write!(f, "qcow2"),
+        }
+    }
+}
+
 /// Config struct for `drive`.
 /// Contains block device's attr.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +180,17 @@ pub struct DriveConfig {
     pub read_only: bool,
     pub direct: bool,
     pub serial_num: Option<String>,
+    /// Host IO engine, default engine is used if not given.
+    pub aio: Option<AioEngine>,
+    /// Format of the image.
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// Handling of discard requests.
+    #[serde(default)]
+    pub discard: DiscardMode,
+    /// Detection of writes of all zeroes.
+    #[serde(default)]
+    pub detect_zeroes: DetectZeroes,
 }
 
 impl DriveConfig {
@@ -43,6 +202,16 @@ impl DriveConfig {
     pub fn from_value(value: &serde_json::Value) -> Option<Vec<Self>> {
         serde_json::from_value(value.clone()).ok()
     }
+
+    /// Host IO engine in effect, native aio for direct IO of raw image and
+    /// threads otherwise if not given.
+    pub fn aio_engine(&self) -> AioEngine {
+        match self.aio {
+            Some(aio) => aio,
+            None if self.direct && self.format == ImageFormat::Raw => AioEngine::Native,
+            None => AioEngine::Threads,
+        }
+    }
 }
 
 impl Default for DriveConfig {
@@ -53,6 +222,10 @@ impl Default for DriveConfig {
             read_only: false,
             direct: true,
             serial_num: None,
+            aio: None,
+            format: ImageFormat::Raw,
+            discard: DiscardMode::Ignore,
+            detect_zeroes: DetectZeroes::Off,
         }
     }
 }
@@ -83,6 +256,38 @@ impl ConfigCheck for DriveConfig {
             .into());
         }
 
+        if self.aio_engine() == AioEngine::Native && !self.direct {
+            return Err(ErrorKind::DriveOptionRequired(
+                "aio=native".to_string(),
+                "direct=on".to_string(),
+            )
+            .into());
+        }
+
+        // Images not raw are accessed through their format drivers, by threads.
+        if self.format != ImageFormat::Raw {
+            let raw_only = if let Some(aio) = self.aio {
+                Some(format!("aio={}", aio))
+            } else if self.discard != DiscardMode::Ignore {
+                Some(format!("discard={}", self.discard))
+            } else if self.detect_zeroes != DetectZeroes::Off {
+                Some(format!("detect-zeroes={}", self.detect_zeroes))
+            } else {
+                None
+            };
+            if let Some(opt) = raw_only {
+                return Err(ErrorKind::DriveOptionRequired(opt, "format=raw".to_string()).into());
+            }
+        }
+
+        if self.detect_zeroes == DetectZeroes::Unmap && self.discard != DiscardMode::Unmap {
+            return Err(ErrorKind::DriveOptionRequired(
+                "detect-zeroes=unmap".to_string(),
+                "discard=unmap".to_string(),
+            )
+            .into());
+        }
+
         Ok(())
     }
 }
@@ -117,7 +322,54 @@ impl VmConfig {
             drive.direct = direct.to_bool();
         }
         drive.serial_num = cmd_params.get_value_str("serial");
+        if let Some(aio) = cmd_params.get("aio") {
+            drive.aio = Some(AioEngine::from_str(&aio.value));
+        }
+        if let Some(format) = cmd_params.get("format") {
+            drive.format = ImageFormat::from_str(&format.value);
+        }
+        if let Some(discard) = cmd_params.get("discard") {
+            drive.discard = DiscardMode::from_str(&discard.value);
+        }
+        if let Some(detect_zeroes) = cmd_params.get("detect-zeroes") {
+            drive.detect_zeroes = DetectZeroes::from_str(&detect_zeroes.value);
+        }
 
         self.add_drive(drive);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_drive(drive_config: &str) -> DriveConfig {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_drive(drive_config.to_string());
+        vm_config.drives.unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_drive_config_qcow2() {
+        let drive = parse_drive("id=drive-0,file=/path/to/block.qcow2,format=qcow2");
+        assert_eq!(drive.format, ImageFormat::Qcow2);
+        assert!(drive.check().is_ok());
+
+        // Options handled by raw images only are rejected.
+        for opt in &[
+            "aio=native",
+            "aio=threads",
+            "discard=unmap",
+            "detect-zeroes=on",
+        ] {
+            let drive = parse_drive(&format!(
+                "id=drive-0,file=/path/to/block.qcow2,format=qcow2,{}",
+                opt
+            ));
+            assert_eq!(
+                drive.check().unwrap_err().to_string(),
+                format!("Drive option {} requires format=raw.", opt)
+            );
+        }
+    }
+}
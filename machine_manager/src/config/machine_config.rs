@@ -13,10 +13,13 @@
 extern crate serde;
 extern crate serde_json;
 
+use std::os::unix::io::RawFd;
+
 use serde::{Deserialize, Serialize};
+use util::cgroup::{CgroupLimits, IoLimit, CPU_PERIOD_US};
 
-use super::errors::{ErrorKind, Result};
-use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+use super::errors::{ErrorKind, Result, ResultExt};
+use crate::config::{CmdParams, ConfigCheck, Param, ParamOperation, VmConfig};
 
 const DEFAULT_CPUS: u8 = 1;
 const DEFAULT_MEMSIZE: u64 = 128;
@@ -24,9 +27,127 @@ const MAX_NR_CPUS: u8 = 254;
 const MIN_NR_CPUS: u8 = 1;
 const MAX_MEMSIZE: u64 = 549_755_813_888;
 const MIN_MEMSIZE: u64 = 134_217_728;
+const MAX_MEM_SLOTS: u32 = 256;
+const HUGEPAGE_SIZE: u64 = 2 * M;
+/// Memory used by StratoVirt itself besides guest memory, counted in `memory.max`.
+const VMM_MEM_OVERHEAD: u64 = 64 * M;
 const M: u64 = 1024 * 1024;
 const G: u64 = 1024 * 1024 * 1024;
 
+/// Policy of guest clocks after VM resumes from pause.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClockPolicy {
+    /// Guest clocks are left alone, they follow host time while paused
+    /// without notifying guest.
+    #[serde(rename = "host")]
+    Host,
+    /// Guest clocks don't advance while paused, guest never notices the pause.
+    #[serde(rename = "keep")]
+    Keep,
+    /// Guest clocks catch up with host wall clock on resume.
+    #[serde(rename = "resync")]
+    Resync,
+}
+
+impl Default for ClockPolicy {
+    fn default() -> Self {
+        ClockPolicy::Host
+    }
+}
+
+impl ClockPolicy {
+    fn from_str(policy: &str) -> Self {
+        match policy {
+            "host" => ClockPolicy::Host,
+            "keep" => ClockPolicy::Keep,
+            "resync" => ClockPolicy::Resync,
+            _ => panic!("Can only give `host`,`keep`,`resync` for clock."),
+        }
+    }
+}
+
+impl std::fmt::Display for ClockPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClockPolicy::Host => write!(f, "host"),
+            ClockPolicy::Keep => write!(f, "keep"),
+            ClockPolicy::Resync => write!(f, "resync"),
+        }
+    }
+}
+
+/// Policy of transparent huge pages for guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThpPolicy {
+    /// Follow host policy.
+    #[serde(rename = "default")]
+    Default,
+    /// Back guest memory with transparent huge pages if possible.
+    #[serde(rename = "always")]
+    Always,
+    /// Never back guest memory with transparent huge pages.
+    #[serde(rename = "never")]
+    Never,
+}
+
+impl Default for ThpPolicy {
+    fn default() -> Self {
+        ThpPolicy::Default
+    }
+}
+
+impl ThpPolicy {
+    fn from_str(policy: &str) -> Self {
+        match policy {
+            "default" => ThpPolicy::Default,
+            "always" => ThpPolicy::Always,
+            "never" => ThpPolicy::Never,
+            _ => panic!("Can only give `default`,`always`,`never` for thp."),
+        }
+    }
+}
+
+/// Config of memory balloon.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonConfig {
+    /// Deflate balloon when guest is out of memory.
+    pub deflate_on_oom: bool,
+    /// Guest reports free pages to host.
+    pub free_page_hinting: bool,
+}
+
+/// Config of cgroup v2 the VM runs in.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgroupConfig {
+    /// Path of cgroup pre-created by orchestrator.
+    pub path: Option<String>,
+    /// Parent under which a cgroup named after VM is created.
+    pub parent: Option<String>,
+    /// Failures of setting up cgroup are fatal rather than warnings.
+    #[serde(default)]
+    pub strict: bool,
+    /// IOPS limit of each drive backing device.
+    pub iops: Option<u64>,
+    /// Bytes per second limit of each drive backing device.
+    pub bps: Option<u64>,
+}
+
+impl ConfigCheck for CgroupConfig {
+    fn check(&self) -> Result<()> {
+        match (&self.path, &self.parent) {
+            (Some(_), Some(_)) => Err(ErrorKind::CgroupConflict(
+                "cgroup".to_string(),
+                "cgroup-parent".to_string(),
+            )
+            .into()),
+            (None, None) => Err(ErrorKind::CgroupPathRequired.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// Config that contains machine's memory information config.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MachineMemConfig {
@@ -34,6 +155,35 @@ pub struct MachineMemConfig {
     pub mem_path: Option<String>,
     pub dump_guest_core: bool,
     pub mem_share: bool,
+    /// Upper limit of memory size including hotplugged memory.
+    pub max_mem: Option<u64>,
+    /// Number of memory slots for hotplugged memory.
+    pub mem_slots: u32,
+    /// Sizes of memory DIMMs planned to be plugged, each takes a memory slot,
+    /// and they fit in memory between memory size and `max_mem`.
+    pub dimms: Vec<u64>,
+    /// Memory balloon config.
+    pub balloon: Option<BalloonConfig>,
+    /// Guest memory is backed by hugepages.
+    pub hugepage: bool,
+    /// Populate guest memory at startup.
+    pub mem_prealloc: bool,
+    /// Lock guest memory in host RAM.
+    pub mem_lock: bool,
+    /// Host NUMA node each guest RAM range is bound to, in order of ranges.
+    /// Ranges beyond are not bound.
+    pub host_nodes: Vec<u32>,
+    /// Transparent huge page policy of guest memory.
+    pub thp_policy: ThpPolicy,
+    /// Seal shared anonymous memory against resizing, valid with `mem_share`.
+    pub mem_seal: bool,
+    /// Name of fd backing guest memory, which is passed by QMP `getfd`,
+    /// or number of an inherited fd.
+    pub mem_fd_name: Option<String>,
+    /// Fd backing guest memory, filled from `mem_fd_name` by machine.
+    /// It's preferred over `mem_path`.
+    #[serde(skip)]
+    pub mem_fd: Option<RawFd>,
 }
 
 impl Default for MachineMemConfig {
@@ -43,7 +193,116 @@ impl Default for MachineMemConfig {
             mem_path: None,
             dump_guest_core: true,
             mem_share: false,
+            max_mem: None,
+            mem_slots: 0,
+            dimms: Vec::new(),
+            balloon: None,
+            hugepage: false,
+            mem_prealloc: false,
+            mem_lock: false,
+            host_nodes: Vec::new(),
+            thp_policy: ThpPolicy::default(),
+            mem_seal: true,
+            mem_fd_name: None,
+            mem_fd: None,
+        }
+    }
+}
+
+impl MachineMemConfig {
+    /// Cross-check combinations of memory options.
+    ///
+    /// # Arguments
+    ///
+    /// * `vhost_user` - Whether any vhost-user device is configured, which
+    ///                  needs guest memory shared with backend process.
+    pub fn validate(&self, vhost_user: bool) -> Result<()> {
+        let max_mem = self.max_mem.unwrap_or(self.mem_size);
+        if max_mem < self.mem_size {
+            return Err(ErrorKind::MaxMemTooSmall(max_mem, self.mem_size).into());
+        }
+        if max_mem > MAX_MEMSIZE {
+            return Err(ErrorKind::MemsizeError.into());
         }
+
+        if self.mem_slots > MAX_MEM_SLOTS {
+            return Err(ErrorKind::MemSlotsError(self.mem_slots, MAX_MEM_SLOTS).into());
+        }
+        if max_mem > self.mem_size && self.mem_slots == 0 {
+            return Err(ErrorKind::MemOptionRequired(
+                "maxmem".to_string(),
+                "slots=n (n > 0) to plug memory up to maxmem".to_string(),
+            )
+            .into());
+        }
+        if self.mem_slots > 0 && max_mem == self.mem_size {
+            return Err(ErrorKind::MemOptionRequired(
+                "slots".to_string(),
+                "maxmem larger than memory size".to_string(),
+            )
+            .into());
+        }
+        if (self.mem_slots as usize) < self.dimms.len() {
+            return Err(ErrorKind::MemSlotsTooFew(self.mem_slots, self.dimms.len()).into());
+        }
+        let dimms_size = self
+            .dimms
+            .iter()
+            .try_fold(self.mem_size, |acc, size| acc.checked_add(*size));
+        match dimms_size {
+            Some(size) if size <= max_mem => {}
+            _ => {
+                return Err(ErrorKind::MemOptionRequired(
+                    "dimms".to_string(),
+                    "maxmem no less than memory size plus sizes of DIMMs".to_string(),
+                )
+                .into())
+            }
+        }
+
+        if vhost_user && !self.mem_share {
+            return Err(ErrorKind::MemOptionRequired(
+                "vhost-user device".to_string(),
+                "mem-share=on".to_string(),
+            )
+            .into());
+        }
+
+        if self.hugepage {
+            if self.mem_path.is_none() && self.mem_fd_name.is_none() {
+                return Err(ErrorKind::MemOptionRequired(
+                    "hugepage".to_string(),
+                    "mem-path or mem-fd on a hugetlbfs mount".to_string(),
+                )
+                .into());
+            }
+            if self.mem_size % HUGEPAGE_SIZE != 0 || max_mem % HUGEPAGE_SIZE != 0 {
+                return Err(ErrorKind::MemOptionRequired(
+                    "hugepage".to_string(),
+                    format!("memory size aligned to {}M", HUGEPAGE_SIZE / M),
+                )
+                .into());
+            }
+        }
+
+        if let Some(balloon) = &self.balloon {
+            if self.mem_lock {
+                return Err(ErrorKind::MemConfigConflict(
+                    "lock".to_string(),
+                    "balloon".to_string(),
+                )
+                .into());
+            }
+            if self.mem_prealloc && balloon.free_page_hinting {
+                return Err(ErrorKind::MemConfigConflict(
+                    "prealloc".to_string(),
+                    "balloon free-page-hinting".to_string(),
+                )
+                .into());
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -54,6 +313,12 @@ pub struct MachineConfig {
     pub mach_type: String,
     pub nr_cpus: u8,
     pub mem_config: MachineMemConfig,
+    /// Synthesize `console=` and `root=` for kernel cmdline if absent.
+    pub auto_cmdline: bool,
+    /// Policy of guest clocks after resume.
+    pub clock_policy: ClockPolicy,
+    /// Cgroup v2 the VM runs in.
+    pub cgroup: Option<CgroupConfig>,
 }
 
 impl Default for MachineConfig {
@@ -63,6 +328,9 @@ impl Default for MachineConfig {
             mach_type: "MicroVm".to_string(),
             nr_cpus: DEFAULT_CPUS,
             mem_config: MachineMemConfig::default(),
+            auto_cmdline: false,
+            clock_policy: ClockPolicy::default(),
+            cgroup: None,
         }
     }
 }
@@ -73,7 +341,12 @@ impl MachineConfig {
     /// # Arguments
     ///
     /// * `Value` - structure can be gotten by `json_file`.
-    pub fn from_value(value: &serde_json::Value) -> Self {
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `dimms`, `host_nodes`, `balloon` or `cgroup` is
+    /// malformed.
+    pub fn from_value(value: &serde_json::Value) -> Result<Self> {
         let mut machine_config = MachineConfig::default();
         if value.get("type") != None {
             machine_config.mach_type = value["type"].to_string();
@@ -89,6 +362,9 @@ impl MachineConfig {
             machine_config.mem_config.mem_path =
                 Some(value["mem_path"].to_string().replace("\"", ""));
         }
+        if let Some(fd_name) = value.get("mem_fd") {
+            machine_config.mem_config.mem_fd_name = Some(fd_name.to_string().replace("\"", ""));
+        }
         if value.get("mem_share") != None {
             machine_config.mem_config.mem_share =
                 value["mem_share"].to_string().parse::<bool>().unwrap();
@@ -99,10 +375,67 @@ impl MachineConfig {
                 .parse::<bool>()
                 .unwrap();
         }
-        machine_config
+        if value.get("max_mem") != None {
+            machine_config.mem_config.max_mem =
+                Some(value["max_mem"].to_string().parse::<u64>().unwrap());
+        }
+        if value.get("mem_slots") != None {
+            machine_config.mem_config.mem_slots =
+                value["mem_slots"].to_string().parse::<u32>().unwrap();
+        }
+        if value.get("hugepage") != None {
+            machine_config.mem_config.hugepage =
+                value["hugepage"].to_string().parse::<bool>().unwrap();
+        }
+        if value.get("mem_prealloc") != None {
+            machine_config.mem_config.mem_prealloc =
+                value["mem_prealloc"].to_string().parse::<bool>().unwrap();
+        }
+        if value.get("mem_lock") != None {
+            machine_config.mem_config.mem_lock =
+                value["mem_lock"].to_string().parse::<bool>().unwrap();
+        }
+        if let Some(dimms) = value.get("dimms") {
+            machine_config.mem_config.dimms = parse_field(dimms, "dimms")?;
+        }
+        if let Some(host_nodes) = value.get("host_nodes") {
+            machine_config.mem_config.host_nodes = parse_field(host_nodes, "host_nodes")?;
+        }
+        if value.get("mem_seal") != None {
+            machine_config.mem_config.mem_seal =
+                value["mem_seal"].to_string().parse::<bool>().unwrap();
+        }
+        if let Some(thp) = value.get("thp_policy") {
+            machine_config.mem_config.thp_policy =
+                ThpPolicy::from_str(&thp.to_string().replace("\"", ""));
+        }
+        if let Some(balloon) = value.get("balloon") {
+            machine_config.mem_config.balloon = parse_field(balloon, "balloon")?;
+        }
+        if let Some(clock) = value.get("clock") {
+            machine_config.clock_policy =
+                ClockPolicy::from_str(&clock.to_string().replace("\"", ""));
+        }
+        if let Some(cgroup) = value.get("cgroup") {
+            machine_config.cgroup = parse_field(cgroup, "cgroup")?;
+        }
+        if value.get("auto_cmdline") != None {
+            machine_config.auto_cmdline =
+                value["auto_cmdline"].to_string().parse::<bool>().unwrap();
+        }
+        Ok(machine_config)
     }
 }
 
+/// Deserialize `field` of json config, which is named in error.
+fn parse_field<T: serde::de::DeserializeOwned>(
+    value: &serde_json::Value,
+    field: &str,
+) -> Result<T> {
+    serde_json::from_value(value.clone())
+        .map_err(|e| ErrorKind::InvalidConfigField(field.to_string(), e.to_string()).into())
+}
+
 impl ConfigCheck for MachineConfig {
     fn check(&self) -> Result<()> {
         if self.nr_cpus < MIN_NR_CPUS || self.nr_cpus > MAX_NR_CPUS {
@@ -113,6 +446,10 @@ impl ConfigCheck for MachineConfig {
             return Err(ErrorKind::MemsizeError.into());
         }
 
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.check()?;
+        }
+
         Ok(())
     }
 }
@@ -134,33 +471,132 @@ impl VmConfig {
         if let Some(mem_share) = cmd_params.get("mem-share") {
             self.machine_config.mem_config.mem_share = mem_share.to_bool();
         }
+        if let Some(auto_cmdline) = cmd_params.get("auto-cmdline") {
+            self.machine_config.auto_cmdline = auto_cmdline.to_bool();
+        }
+        if let Some(clock) = cmd_params.get("clock") {
+            self.machine_config.clock_policy = ClockPolicy::from_str(&clock.value);
+        }
+
+        let mut cgroup = self.machine_config.cgroup.take().unwrap_or_default();
+        if let Some(path) = cmd_params.get("cgroup") {
+            cgroup.path = Some(path.value);
+        }
+        if let Some(parent) = cmd_params.get("cgroup-parent") {
+            cgroup.parent = Some(parent.value);
+        }
+        if let Some(strict) = cmd_params.get("cgroup-strict") {
+            cgroup.strict = strict.to_bool();
+        }
+        if let Some(iops) = cmd_params.get("cgroup-iops") {
+            cgroup.iops = Some(iops.value_to_u64());
+        }
+        if let Some(bps) = cmd_params.get("cgroup-bps") {
+            cgroup.bps = Some(bps.value_to_u64());
+        }
+        if cgroup != CgroupConfig::default() {
+            self.machine_config.cgroup = Some(cgroup);
+        }
+    }
+
+    /// Resource limits of cgroup derived from VM config: one cpu period per
+    /// vcpu, guest memory (up to `maxmem`) plus VMM overhead, and
+    /// `iops`/`bps` on the devices backing drives.
+    pub fn cgroup_limits(&self) -> Result<CgroupLimits> {
+        let mem_config = &self.machine_config.mem_config;
+        let mut limits = CgroupLimits {
+            cpu_quota_us: Some(u64::from(self.machine_config.nr_cpus) * CPU_PERIOD_US),
+            memory_max: Some(mem_config.max_mem.unwrap_or(mem_config.mem_size) + VMM_MEM_OVERHEAD),
+            io: Vec::new(),
+        };
+
+        let (iops, bps) = match &self.machine_config.cgroup {
+            Some(cgroup) => (cgroup.iops, cgroup.bps),
+            None => (None, None),
+        };
+        if iops.is_none() && bps.is_none() {
+            return Ok(limits);
+        }
+        if let Some(drives) = &self.drives {
+            for drive in drives {
+                let io = IoLimit::for_path(&drive.path_on_host, iops, bps)
+                    .chain_err(|| format!("Failed to get io limit of drive {}", drive.drive_id))?;
+                // Drives on the same device share one limit.
+                if !limits
+                    .io
+                    .iter()
+                    .any(|l| l.major == io.major && l.minor == io.minor)
+                {
+                    limits.io.push(io);
+                }
+            }
+        }
+        Ok(limits)
     }
     /// Update '-m' memory config to `VmConfig`.
     pub fn update_memory(&mut self, mem_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(mem_config);
-        if let Some(mut mem_size) = cmd_params.get("") {
-            if mem_size.value_replace_blank("M") || mem_size.value_replace_blank("m") {
-                self.machine_config.mem_config.mem_size =
-                    get_inner(mem_size.value_to_u64().checked_mul(M));
-            } else if mem_size.value_replace_blank("G") || mem_size.value_replace_blank("g") {
-                self.machine_config.mem_config.mem_size =
-                    get_inner(mem_size.value_to_u64().checked_mul(G));
-            } else {
-                self.machine_config.mem_config.mem_size = mem_size.value_to_u64();
-            }
-        } else if let Some(mut mem_size) = cmd_params.get("size") {
-            if mem_size.value_replace_blank("M") || mem_size.value_replace_blank("m") {
-                self.machine_config.mem_config.mem_size =
-                    get_inner(mem_size.value_to_u64().checked_mul(M));
-            } else if mem_size.value_replace_blank("G") || mem_size.value_replace_blank("g") {
-                self.machine_config.mem_config.mem_size =
-                    get_inner(mem_size.value_to_u64().checked_mul(G));
-            } else {
-                self.machine_config.mem_config.mem_size = mem_size.value_to_u64();
-            }
+        if let Some(mem_size) = cmd_params.get("") {
+            self.machine_config.mem_config.mem_size = memory_value(mem_size);
+        } else if let Some(mem_size) = cmd_params.get("size") {
+            self.machine_config.mem_config.mem_size = memory_value(mem_size);
+        }
+        if let Some(max_mem) = cmd_params.get("maxmem") {
+            self.machine_config.mem_config.max_mem = Some(memory_value(max_mem));
+        }
+        if let Some(slots) = cmd_params.get("slots") {
+            self.machine_config.mem_config.mem_slots = slots.value_to_u32();
+        }
+        if let Some(dimms) = cmd_params.get_value_str("dimms") {
+            self.machine_config.mem_config.dimms = dimms
+                .split(':')
+                .map(|size| {
+                    memory_value(Param {
+                        param_type: String::new(),
+                        value: size.to_string(),
+                    })
+                })
+                .collect();
+        }
+        if let Some(hugepage) = cmd_params.get("hugepage") {
+            self.machine_config.mem_config.hugepage = hugepage.to_bool();
+        }
+        if let Some(prealloc) = cmd_params.get("prealloc") {
+            self.machine_config.mem_config.mem_prealloc = prealloc.to_bool();
+        }
+        if let Some(lock) = cmd_params.get("lock") {
+            self.machine_config.mem_config.mem_lock = lock.to_bool();
+        }
+        if let Some(seal) = cmd_params.get("seal") {
+            self.machine_config.mem_config.mem_seal = seal.to_bool();
+        }
+        if let Some(thp) = cmd_params.get_value_str("thp") {
+            self.machine_config.mem_config.thp_policy = ThpPolicy::from_str(&thp);
+        }
+        if let Some(host_nodes) = cmd_params.get_value_str("host-nodes") {
+            self.machine_config.mem_config.host_nodes = host_nodes
+                .split(':')
+                .map(|node| {
+                    node.parse::<u32>()
+                        .unwrap_or_else(|_| panic!("Unrecognized host NUMA node: {}", node))
+                })
+                .collect();
         }
     }
 
+    /// Update '-balloon' config to `VmConfig`.
+    pub fn update_balloon(&mut self, balloon_config: String) {
+        let cmd_params: CmdParams = CmdParams::from_str(balloon_config);
+        let mut balloon = BalloonConfig::default();
+        if let Some(deflate) = cmd_params.get("deflate-on-oom") {
+            balloon.deflate_on_oom = deflate.to_bool();
+        }
+        if let Some(hinting) = cmd_params.get("free-page-hinting") {
+            balloon.free_page_hinting = hinting.to_bool();
+        }
+        self.machine_config.mem_config.balloon = Some(balloon);
+    }
+
     /// Update '-smp' cpu config to `VmConfig`.
     pub fn update_cpu(&mut self, cpu_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(cpu_config);
@@ -174,6 +610,21 @@ impl VmConfig {
     pub fn update_mem_path(&mut self, mem_path: String) {
         self.machine_config.mem_config.mem_path = Some(mem_path.replace("\"", ""));
     }
+
+    pub fn update_mem_fd(&mut self, mem_fd: String) {
+        self.machine_config.mem_config.mem_fd_name = Some(mem_fd.replace("\"", ""));
+    }
+}
+
+/// Convert memory size with optional `M`/`G` suffix to bytes.
+fn memory_value(mut mem_size: Param) -> u64 {
+    if mem_size.value_replace_blank("M") || mem_size.value_replace_blank("m") {
+        get_inner(mem_size.value_to_u64().checked_mul(M))
+    } else if mem_size.value_replace_blank("G") || mem_size.value_replace_blank("g") {
+        get_inner(mem_size.value_to_u64().checked_mul(G))
+    } else {
+        mem_size.value_to_u64()
+    }
 }
 
 fn get_inner<T>(outer: Option<T>) -> T {
@@ -183,3 +634,283 @@ fn get_inner<T>(outer: Option<T>) -> T {
         panic!("Integer overflow occurred!");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mem_config(mem_size: u64) -> MachineMemConfig {
+        MachineMemConfig {
+            mem_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mem_validate_default() {
+        assert!(MachineMemConfig::default().validate(false).is_ok());
+    }
+
+    #[test]
+    fn test_mem_validate_maxmem_slots() {
+        let mut config = mem_config(G);
+        config.max_mem = Some(4 * G);
+        config.mem_slots = 2;
+        assert!(config.validate(false).is_ok());
+
+        // maxmem smaller than memory size.
+        config.max_mem = Some(512 * M);
+        assert!(config.validate(false).is_err());
+
+        // maxmem exceeds the max memory size supported.
+        config.max_mem = Some(MAX_MEMSIZE + G);
+        assert!(config.validate(false).is_err());
+
+        // maxmem without slots.
+        config.max_mem = Some(4 * G);
+        config.mem_slots = 0;
+        assert!(config.validate(false).is_err());
+
+        // slots without maxmem.
+        config.max_mem = None;
+        config.mem_slots = 2;
+        assert!(config.validate(false).is_err());
+        config.max_mem = Some(G);
+        assert!(config.validate(false).is_err());
+
+        // Too many slots.
+        config.max_mem = Some(4 * G);
+        config.mem_slots = MAX_MEM_SLOTS + 1;
+        assert!(config.validate(false).is_err());
+    }
+
+    #[test]
+    fn test_mem_validate_dimms() {
+        let mut config = mem_config(G);
+        config.max_mem = Some(4 * G);
+        config.mem_slots = 2;
+        config.dimms = vec![G, 2 * G];
+        assert!(config.validate(false).is_ok());
+
+        // Fewer slots than planned DIMMs.
+        config.mem_slots = 1;
+        assert_eq!(
+            config.validate(false).unwrap_err().to_string(),
+            "Number of memory slots 1 should be no less than planned DIMMs 2."
+        );
+
+        // DIMMs exceed maxmem.
+        config.mem_slots = 2;
+        config.dimms = vec![2 * G, 2 * G];
+        assert!(config.validate(false).is_err());
+        config.dimms = vec![u64::max_value(), G];
+        assert!(config.validate(false).is_err());
+    }
+
+    #[test]
+    fn test_mem_validate_vhost_user() {
+        let mut config = mem_config(G);
+        assert!(config.validate(true).is_err());
+        config.mem_share = true;
+        assert!(config.validate(true).is_ok());
+    }
+
+    #[test]
+    fn test_mem_validate_hugepage() {
+        let mut config = mem_config(G);
+        config.hugepage = true;
+        assert!(config.validate(false).is_err());
+
+        config.mem_path = Some("/dev/hugepages".to_string());
+        assert!(config.validate(false).is_ok());
+
+        config.mem_size = G + M;
+        assert!(config.validate(false).is_err());
+
+        config.mem_size = G;
+        config.max_mem = Some(2 * G + M);
+        config.mem_slots = 1;
+        assert!(config.validate(false).is_err());
+    }
+
+    #[test]
+    fn test_mem_validate_balloon() {
+        let mut config = mem_config(G);
+        config.balloon = Some(BalloonConfig {
+            deflate_on_oom: true,
+            free_page_hinting: true,
+        });
+        assert!(config.validate(false).is_ok());
+
+        // Locked memory can't be reclaimed by balloon.
+        config.mem_lock = true;
+        assert!(config.validate(false).is_err());
+
+        // Preallocated memory is released again by free page hinting.
+        config.mem_lock = false;
+        config.mem_prealloc = true;
+        assert!(config.validate(false).is_err());
+
+        config.balloon.as_mut().unwrap().free_page_hinting = false;
+        assert!(config.validate(false).is_ok());
+    }
+
+    #[test]
+    fn test_update_cgroup() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_machine("type=MicroVm".to_string());
+        assert!(vm_config.machine_config.cgroup.is_none());
+
+        vm_config.update_machine(
+            "type=MicroVm,cgroup-parent=/sys/fs/cgroup/vms,cgroup-strict=on,cgroup-iops=500"
+                .to_string(),
+        );
+        let cgroup = vm_config.machine_config.cgroup.clone().unwrap();
+        assert_eq!(cgroup.parent, Some("/sys/fs/cgroup/vms".to_string()));
+        assert!(cgroup.path.is_none());
+        assert!(cgroup.strict);
+        assert_eq!(cgroup.iops, Some(500));
+        assert!(vm_config.machine_config.check().is_ok());
+
+        vm_config.update_machine("cgroup=/sys/fs/cgroup/vm1".to_string());
+        assert!(vm_config.machine_config.check().is_err());
+
+        let value = serde_json::json!({ "cgroup": { "path": "/sys/fs/cgroup/vm1" } });
+        let machine_config = MachineConfig::from_value(&value).unwrap();
+        let cgroup = machine_config.cgroup.clone().unwrap();
+        assert_eq!(cgroup.path, Some("/sys/fs/cgroup/vm1".to_string()));
+        assert!(!cgroup.strict);
+        assert!(machine_config.check().is_ok());
+
+        // Malformed cgroup isn't taken as no cgroup.
+        for bad in &[
+            serde_json::json!({ "path": "/sys/fs/cgroup/vm1", "strict": "on" }),
+            serde_json::json!({ "parnet": "/sys/fs/cgroup/vms", "strict": true }),
+        ] {
+            let value = serde_json::json!({ "cgroup": bad });
+            let err = MachineConfig::from_value(&value).unwrap_err().to_string();
+            assert!(err.starts_with("Invalid cgroup in json config"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_cgroup_limits() {
+        let mut vm_config = VmConfig::default();
+        vm_config.machine_config.nr_cpus = 4;
+        vm_config.machine_config.mem_config.mem_size = G;
+        let limits = vm_config.cgroup_limits().unwrap();
+        assert_eq!(limits.cpu_quota_us, Some(4 * CPU_PERIOD_US));
+        assert_eq!(limits.memory_max, Some(G + VMM_MEM_OVERHEAD));
+        assert!(limits.io.is_empty());
+
+        vm_config.machine_config.mem_config.max_mem = Some(2 * G);
+        vm_config.machine_config.cgroup = Some(CgroupConfig {
+            parent: Some("/sys/fs/cgroup".to_string()),
+            bps: Some(M),
+            ..Default::default()
+        });
+        let drive = |id: &str, path: &str| crate::config::DriveConfig {
+            drive_id: id.to_string(),
+            path_on_host: path.to_string(),
+            ..Default::default()
+        };
+        vm_config.drives = Some(vec![
+            drive("rootfs", "/proc/self/exe"),
+            drive("data", "/proc/self/exe"),
+        ]);
+        let limits = vm_config.cgroup_limits().unwrap();
+        assert_eq!(limits.memory_max, Some(2 * G + VMM_MEM_OVERHEAD));
+        assert_eq!(limits.io.len(), 1);
+        assert_eq!(limits.io[0].bps, Some(M));
+        assert_eq!(limits.io[0].iops, None);
+
+        vm_config.drives = Some(vec![drive("missing", "/nonexistent")]);
+        assert!(vm_config.cgroup_limits().is_err());
+    }
+
+    #[test]
+    fn test_update_clock_policy() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.clock_policy, ClockPolicy::Host);
+        vm_config.update_machine("type=MicroVm,clock=resync".to_string());
+        assert_eq!(vm_config.machine_config.clock_policy, ClockPolicy::Resync);
+        assert_eq!(vm_config.machine_config.clock_policy.to_string(), "resync");
+
+        let value = serde_json::json!({ "clock": "keep" });
+        let machine_config = MachineConfig::from_value(&value).unwrap();
+        assert_eq!(machine_config.clock_policy, ClockPolicy::Keep);
+    }
+
+    #[test]
+    fn test_machine_config_from_value() {
+        let value = serde_json::json!({
+            "dimms": [536870912, 1073741824],
+            "host_nodes": [0, 1],
+            "balloon": { "deflate_on_oom": true, "free_page_hinting": false },
+        });
+        let machine_config = MachineConfig::from_value(&value).unwrap();
+        let config = &machine_config.mem_config;
+        assert_eq!(config.dimms, vec![512 * M, G]);
+        assert_eq!(config.host_nodes, vec![0, 1]);
+        assert_eq!(
+            config.balloon,
+            Some(BalloonConfig {
+                deflate_on_oom: true,
+                free_page_hinting: false,
+            })
+        );
+
+        // Malformed fields are reported instead of dropped.
+        for (field, bad) in &[
+            ("dimms", serde_json::json!("512M")),
+            ("host_nodes", serde_json::json!([-1])),
+            ("balloon", serde_json::json!({ "deflate-on-oom": true })),
+        ] {
+            let mut value = serde_json::json!({});
+            value[*field] = bad.clone();
+            let err = MachineConfig::from_value(&value).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Invalid {} in json config", field)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_update_memory() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_memory(
+            "size=1G,maxmem=4G,slots=3,dimms=512M:1G,hugepage=on,prealloc=on,lock=off,host-nodes=0:1"
+                .to_string(),
+        );
+        let config = &vm_config.machine_config.mem_config;
+        assert_eq!(config.mem_size, G);
+        assert_eq!(config.max_mem, Some(4 * G));
+        assert_eq!(config.mem_slots, 3);
+        assert_eq!(config.dimms, vec![512 * M, G]);
+        assert!(config.hugepage);
+        assert!(config.mem_prealloc);
+        assert!(!config.mem_lock);
+        assert_eq!(config.host_nodes, vec![0, 1]);
+        assert_eq!(config.thp_policy, ThpPolicy::Default);
+        assert!(config.mem_seal);
+
+        vm_config.update_memory("512M,thp=never,seal=off".to_string());
+        assert!(!vm_config.machine_config.mem_config.mem_seal);
+        assert_eq!(vm_config.machine_config.mem_config.mem_size, 512 * M);
+        assert_eq!(
+            vm_config.machine_config.mem_config.thp_policy,
+            ThpPolicy::Never
+        );
+
+        vm_config.update_balloon("deflate-on-oom=on".to_string());
+        assert_eq!(
+            vm_config.machine_config.mem_config.balloon,
+            Some(BalloonConfig {
+                deflate_on_oom: true,
+                free_page_hinting: false,
+            })
+        );
+    }
+}
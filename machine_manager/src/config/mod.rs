@@ -65,6 +65,58 @@ pub mod errors {
                 description("Check legality of file.")
                 display("{} is not a regular File.", t)
             }
+            MaxMemTooSmall(max_mem: u64, mem_size: u64) {
+                description("Limit the maxmem no less than memory size.")
+                display("maxmem {} should be no less than memory size {}.", max_mem, mem_size)
+            }
+            MemSlotsError(slots: u32, max: u32) {
+                description("Limit the number of memory slots.")
+                display("Number of memory slots {} should be no more than {}.", slots, max)
+            }
+            MemSlotsTooFew(slots: u32, dimms: usize) {
+                description("Memory slots are too few for planned DIMMs.")
+                display("Number of memory slots {} should be no less than planned DIMMs {}.", slots, dimms)
+            }
+            MemOptionRequired(opt: String, required: String) {
+                description("Memory option requires another option.")
+                display("Memory option {} requires {}.", opt, required)
+            }
+            MemConfigConflict(opt1: String, opt2: String) {
+                description("Memory options conflict with each other.")
+                display("Memory option {} can't be used together with {}.", opt1, opt2)
+            }
+            CgroupConflict(opt1: String, opt2: String) {
+                description("Cgroup options conflict with each other.")
+                display("Cgroup option {} can't be used together with {}.", opt1, opt2)
+            }
+            CgroupPathRequired {
+                description("Cgroup options require a cgroup path.")
+                display("Cgroup options require cgroup or cgroup-parent.")
+            }
+            DriveOptionRequired(opt: String, required: String) {
+                description("Drive option requires another option.")
+                display("Drive option {} requires {}.", opt, required)
+            }
+            NetQueuesError(queues: u16, max: u16) {
+                description("Limit the number of net queues.")
+                display("Number of net queues {} should be more than 0 and no more than {}.", queues, max)
+            }
+            NetFdsMismatch(fds: usize, queues: u16) {
+                description("Number of net fds should match the number of net queues.")
+                display("Number of net fds {} doesn't match the number of net queues {}.", fds, queues)
+            }
+            NetConflict(opt1: String, opt2: String) {
+                description("Net options conflict with each other.")
+                display("Net option {} can't be used together with {}.", opt1, opt2)
+            }
+            RngRateError {
+                description("Check legality of rate limit of virtio-rng.")
+                display("Rng max-bytes and period should be given together and be more than 0.")
+            }
+            InvalidConfigField(field: String, reason: String) {
+                description("Field of json config is invalid.")
+                display("Invalid {} in json config: {}.", field, reason)
+            }
         }
     }
 }
@@ -79,7 +131,7 @@ const MAX_STRING_LENGTH: usize = 255;
 /// # Example
 ///
 /// ```text
-/// config_parse!(machine_config, value, "machine-config", MachineConfig);
+/// config_parse!(boot_source, value, "boot-source", BootSource);
 /// ```
 macro_rules! config_parse {
     ( $x:expr, $y:expr, $z:expr, $s:tt ) => {
@@ -117,8 +169,10 @@ impl VmConfig {
         let mut vsock = None;
         let mut serial = None;
 
+        if let Some(machine_value) = value.get("machine-config") {
+            machine_config = MachineConfig::from_value(machine_value)?;
+        }
         // Use macro to use from_value function for every member
-        config_parse!(machine_config, value, "machine-config", MachineConfig);
         config_parse!(boot_source, value, "boot-source", BootSource);
         config_parse!(drives, value, "drive", DriveConfig);
         config_parse!(nets, value, "net", NetworkInterfaceConfig);
@@ -142,6 +196,11 @@ impl VmConfig {
     pub fn check_vmconfig(&self, is_daemonize: bool) -> Result<()> {
         self.boot_source.check()?;
         self.machine_config.check()?;
+        let vhost_user = self.nets.as_ref().map_or(false, |nets| {
+            nets.iter()
+                .any(|net| net.vhost_type.as_deref() == Some("vhost-user"))
+        });
+        self.machine_config.mem_config.validate(vhost_user)?;
 
         if self.guest_name.len() > MAX_STRING_LENGTH {
             return Err(self::errors::ErrorKind::StringLengthTooLong(
@@ -20,6 +20,8 @@ use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
 
 const MAX_STRING_LENGTH: usize = 255;
 const MAC_ADDRESS_LENGTH: usize = 17;
+/// The most queue pairs a network device supports.
+pub const MAX_NET_QUEUES: u16 = 16;
 
 /// Config struct for network
 /// Contains network device config, such as `host_dev_name`, `mac`...
@@ -29,9 +31,15 @@ pub struct NetworkInterfaceConfig {
     pub iface_id: String,
     pub host_dev_name: String,
     pub mac: Option<String>,
-    pub tap_fd: Option<i32>,
+    pub tap_fds: Option<Vec<i32>>,
     pub vhost_type: Option<String>,
     pub vhost_fd: Option<i32>,
+    #[serde(default = "default_net_queues")]
+    pub queues: u16,
+}
+
+fn default_net_queues() -> u16 {
+    1
 }
 
 impl NetworkInterfaceConfig {
@@ -52,9 +60,10 @@ impl Default for NetworkInterfaceConfig {
             iface_id: "".to_string(),
             host_dev_name: "".to_string(),
             mac: None,
-            tap_fd: None,
+            tap_fds: None,
             vhost_type: None,
             vhost_fd: None,
+            queues: default_net_queues(),
         }
     }
 }
@@ -85,6 +94,24 @@ impl ConfigCheck for NetworkInterfaceConfig {
             }
         }
 
+        if self.queues == 0 || self.queues > MAX_NET_QUEUES {
+            return Err(ErrorKind::NetQueuesError(self.queues, MAX_NET_QUEUES).into());
+        }
+
+        if let Some(tap_fds) = self.tap_fds.as_ref() {
+            if tap_fds.len() != self.queues as usize {
+                return Err(ErrorKind::NetFdsMismatch(tap_fds.len(), self.queues).into());
+            }
+        }
+
+        if self.vhost_type.is_some() && self.queues > 1 {
+            return Err(ErrorKind::NetConflict(
+                "vhost".to_string(),
+                "more than one queue".to_string(),
+            )
+            .into());
+        }
+
         Ok(())
     }
 }
@@ -117,22 +144,54 @@ impl VmConfig {
         if let Some(net_mac) = cmd_params.get("mac") {
             net.mac = Some(net_mac.value);
         }
-        if let Some(tap_fd) = cmd_params.get("fds") {
-            net.tap_fd = Some(tap_fd.value_to_u32() as i32);
-        }
-        if let Some(vhost) = cmd_params.get("vhost") {
-            if vhost.to_bool() {
-                net.vhost_type = Some("vhost-kernel".to_string());
-            }
+        if let Some(tap_fds) = cmd_params.get("fds") {
+            let fds = split_net_fds(&tap_fds.value, ':')
+                .unwrap_or_else(|e| panic!("{}", e))
+                .iter()
+                .map(|fd| {
+                    fd.parse::<i32>()
+                        .unwrap_or_else(|_| panic!("Unrecognized value to fd: {}", fd))
+                })
+                .collect::<Vec<i32>>();
+            net.tap_fds = Some(fds);
         }
+        // Number of queues defaults to the number of fds given.
+        net.queues = match cmd_params.get("queues") {
+            Some(queues) => queues.value_to_u32() as u16,
+            None => net.tap_fds.as_ref().map_or(1, |fds| fds.len() as u16),
+        };
         if let Some(vhostfd) = cmd_params.get("vhostfds") {
             net.vhost_fd = Some(vhostfd.value_to_u32() as i32);
         }
+        // `vhostfds` implies `vhost=on`, unless vhost is set off.
+        let vhost = match cmd_params.get("vhost") {
+            Some(vhost) => vhost.to_bool(),
+            None => net.vhost_fd.is_some(),
+        };
+        if vhost {
+            net.vhost_type = Some("vhost-kernel".to_string());
+        }
 
         self.add_netdev(net);
     }
 }
 
+/// Split `fds` of network backend into fd names. Fds are separated by ','
+/// in QMP `netdev_add`, and by ':' in command line.
+///
+/// # Arguments
+///
+/// * `fds` - Fds of network backend.
+/// * `separator` - Separator between fds.
+pub fn split_net_fds(fds: &str, separator: char) -> Result<Vec<&str>> {
+    let names: Vec<&str> = fds.split(separator).map(|name| name.trim()).collect();
+    if names.iter().any(|name| name.is_empty()) {
+        return Err(format!("Empty fd in net fds \"{}\"", fds).into());
+    }
+
+    Ok(names)
+}
+
 fn check_mac_address(mac: &str) -> bool {
     if mac.len() != MAC_ADDRESS_LENGTH {
         return false;
@@ -161,3 +220,93 @@ fn check_mac_address(mac: &str) -> bool {
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_net(net_config: &str) -> NetworkInterfaceConfig {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_net(net_config.to_string());
+        vm_config.nets.unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_net_config_vhost() {
+        let net = parse_net("id=net-0,netdev=tap0,mac=12:34:56:78:9A:BC");
+        assert_eq!(net.iface_id, "net-0");
+        assert_eq!(net.host_dev_name, "tap0");
+        assert!(net.vhost_type.is_none());
+        assert!(net.vhost_fd.is_none());
+        assert!(net.check().is_ok());
+
+        let net = parse_net("id=net-0,netdev=tap0,vhost=on");
+        assert_eq!(net.vhost_type, Some("vhost-kernel".to_string()));
+        assert!(net.vhost_fd.is_none());
+        assert!(net.check().is_ok());
+
+        let net = parse_net("id=net-0,netdev=tap0,vhost=off");
+        assert!(net.vhost_type.is_none());
+
+        let net = parse_net("id=net-0,fds=20,vhostfds=21");
+        assert_eq!(net.tap_fds, Some(vec![20]));
+        assert_eq!(net.vhost_type, Some("vhost-kernel".to_string()));
+        assert_eq!(net.vhost_fd, Some(21));
+
+        let net = parse_net("id=net-0,fds=20,vhost=off,vhostfds=21");
+        assert!(net.vhost_type.is_none());
+        assert_eq!(net.vhost_fd, Some(21));
+
+        let mut net = parse_net("id=net-0,netdev=tap0,vhost=on");
+        net.vhost_type = Some("vhost-user".to_string());
+        assert!(net.check().is_err());
+    }
+
+    #[test]
+    fn test_net_config_queues() {
+        let net = parse_net("id=net-0,netdev=tap0");
+        assert_eq!(net.queues, 1);
+        assert!(net.check().is_ok());
+
+        let net = parse_net("id=net-0,netdev=tap0,queues=4");
+        assert_eq!(net.queues, 4);
+        assert!(net.check().is_ok());
+
+        let net = parse_net("id=net-0,fds=20:21:22");
+        assert_eq!(net.tap_fds, Some(vec![20, 21, 22]));
+        assert_eq!(net.queues, 3);
+        assert!(net.check().is_ok());
+
+        let net = parse_net("id=net-0,netdev=tap0,queues=16");
+        assert!(net.check().is_ok());
+        let net = parse_net("id=net-0,netdev=tap0,queues=17");
+        assert_eq!(
+            net.check().unwrap_err().to_string(),
+            "Number of net queues 17 should be more than 0 and no more than 16."
+        );
+        let net = parse_net("id=net-0,netdev=tap0,queues=0");
+        assert!(net.check().is_err());
+
+        let net = parse_net("id=net-0,fds=20:21,queues=4");
+        assert_eq!(
+            net.check().unwrap_err().to_string(),
+            "Number of net fds 2 doesn't match the number of net queues 4."
+        );
+
+        let net = parse_net("id=net-0,netdev=tap0,vhost=on,queues=2");
+        assert!(net.check().is_err());
+    }
+
+    #[test]
+    fn test_split_net_fds() {
+        assert_eq!(split_net_fds("fd-tap", ',').unwrap(), vec!["fd-tap"]);
+        assert_eq!(
+            split_net_fds("fd-tap0,fd-tap1, fd-tap2", ',').unwrap(),
+            vec!["fd-tap0", "fd-tap1", "fd-tap2"]
+        );
+        assert_eq!(split_net_fds("20:21", ':').unwrap(), vec!["20", "21"]);
+        assert!(split_net_fds("", ',').is_err());
+        assert!(split_net_fds("fd-tap0,,fd-tap1", ',').is_err());
+        assert!(split_net_fds("fd-tap0,", ',').is_err());
+    }
+}
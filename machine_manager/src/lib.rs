@@ -20,6 +20,7 @@
 //! 1. A communication way to handle VM outside.
 //! 2. The API interface over VM inside and outside.
 //! 3. Configuration for VM and its devices.
+//! 4. Registry of block backends and their IO statistics.
 
 #[macro_use]
 extern crate log;
@@ -27,6 +28,7 @@ extern crate log;
 extern crate error_chain;
 extern crate serde_json;
 
+pub mod block_backend;
 pub mod config;
 pub mod machine;
 #[cfg(feature = "qmp")]
@@ -13,10 +13,17 @@
 extern crate util;
 
 use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use crate::block_backend::BlockBackendRegistry;
+use crate::config::{RngConfig, VsockConfig};
+use crate::errors::Result;
 
 #[cfg(feature = "qmp")]
 use crate::qmp::Response;
 
+#[cfg(feature = "qmp")]
+use crate::config::{DetectZeroes, DiscardMode, ImageFormat};
 #[cfg(feature = "qmp")]
 use crate::qmp::qmp_schema::{CacheOptions, FileOptions};
 
@@ -42,6 +49,23 @@ pub enum VmEvent {
 unsafe impl Sync for VmEvent {}
 unsafe impl Send for VmEvent {}
 
+/// Device to be added by `device_add`, with config checked for its driver.
+pub enum DeviceAddArgs {
+    /// Fill the replaceable block or network device in slot `addr` with the
+    /// backend added before.
+    Replace {
+        driver: String,
+        addr: Option<String>,
+        lun: Option<usize>,
+    },
+    /// Plug the virtio-balloon device.
+    Balloon,
+    /// Plug a virtio-rng device.
+    Rng(RngConfig),
+    /// Plug a vhost-vsock device.
+    Vsock(VsockConfig),
+}
+
 /// Trait to handle virtual machine lifecycle.
 ///
 /// # Notes
@@ -85,6 +109,21 @@ pub trait MachineLifecycle {
         self.notify_lifecycle(KvmVmState::Running, KvmVmState::Shutdown)
     }
 
+    /// Reset VM, boot source is loaded again and vcpus restart from their
+    /// boot state.
+    fn reset(&self) -> Result<()> {
+        Err("Reset is not supported".into())
+    }
+
+    /// Press the power button of VM, which notifies guest to shut down.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if VM has no power button device.
+    fn powerdown(&self) -> Result<()> {
+        Err("No power button device to notify guest".into())
+    }
+
     /// When VM or Device life state changed, notify concerned entry.
     ///
     /// # Arguments
@@ -117,6 +156,10 @@ pub trait MachineAddressInterface {
     fn mmio_read(&self, addr: u64, data: &mut [u8]) -> bool;
 
     fn mmio_write(&self, addr: u64, data: &[u8]) -> bool;
+
+    /// Flush guest writes batched in coalesced MMIO ranges, called on each
+    /// vcpu exit before it's handled.
+    fn flush_coalesced_mmio(&self);
 }
 
 /// Device external api
@@ -139,13 +182,7 @@ pub trait DeviceInterface {
     fn query_hotpluggable_cpus(&self) -> Response;
 
     /// Add a device with configuration.
-    fn device_add(
-        &self,
-        device_id: String,
-        driver: String,
-        addr: Option<String>,
-        lun: Option<usize>,
-    ) -> bool;
+    fn device_add(&self, device_id: String, args: DeviceAddArgs) -> Result<()>;
 
     /// Delete a device with device id.
     fn device_del(&self, device_id: String) -> bool;
@@ -157,14 +194,68 @@ pub trait DeviceInterface {
         file: FileOptions,
         cache: Option<CacheOptions>,
         read_only: Option<bool>,
-    ) -> bool;
+        format: Option<ImageFormat>,
+        discard: Option<DiscardMode>,
+        detect_zeroes: Option<DetectZeroes>,
+    ) -> Result<()>;
 
     /// Create a new network device.
-    fn netdev_add(&self, id: String, if_name: Option<String>, fds: Option<String>) -> bool;
+    fn netdev_add(
+        &self,
+        id: String,
+        if_name: Option<String>,
+        fds: Option<String>,
+        vhost: Option<bool>,
+        vhostfds: Option<String>,
+        queues: Option<u16>,
+    ) -> Result<()>;
 
     /// Receive a file descriptor via SCM rights and assign it a name.
     #[cfg(feature = "qmp")]
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response;
+
+    /// Dump guest memory to the destination given by `protocol`.
+    #[cfg(feature = "qmp")]
+    fn dump_guest_memory(&self, protocol: String) -> Response;
+
+    /// Query size of guest RAM and layout of guest memory address space.
+    #[cfg(feature = "qmp")]
+    fn query_memory_summary(&self) -> Response;
+
+    /// Grow image of block device `device` to `size` bytes.
+    #[cfg(feature = "qmp")]
+    fn block_resize(&self, device: String, size: u64) -> Response;
+
+    /// Query network backends added by `netdev_add` or command line.
+    #[cfg(feature = "qmp")]
+    fn query_netdev(&self) -> Response;
+
+    /// Set link of network device `name` up or down.
+    #[cfg(feature = "qmp")]
+    fn set_link(&self, name: String, up: bool) -> Response;
+
+    /// Size of guest RAM in bytes.
+    fn ram_size(&self) -> u64;
+
+    /// Registry of block backends used by block devices.
+    fn block_backends(&self) -> Arc<BlockBackendRegistry>;
+
+    /// Balloon device registered to adjust guest memory, `None` if the
+    /// machine has no balloon device.
+    fn balloon_handle(&self) -> Option<Arc<dyn BalloonHandle>> {
+        None
+    }
+}
+
+/// Handle of balloon device, through which guest is requested to adjust its
+/// memory size. The device emits QMP event `BALLOON_CHANGE` after guest has
+/// adjusted.
+pub trait BalloonHandle: Send + Sync {
+    /// Set target size of guest memory in bytes.
+    fn set_target(&self, size: u64);
+
+    /// Actual size of guest memory in bytes.
+    fn actual(&self) -> u64;
 }
 
 /// Machine interface which is exposed to inner hypervisor.
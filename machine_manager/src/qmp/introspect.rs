@@ -0,0 +1,509 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Introspection of QMP schema for `query-qmp-schema`.
+//!
+//! Schema of a type is traced from its `Deserialize` implementation, the one
+//! used to parse commands, so it never drifts from the structures in
+//! `qmp_schema.rs`. `Tracer` is a deserializer feeding dummy values to the
+//! type, and records which kind of value is asked for by each member.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+use serde::de::value::Error;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+
+use super::qmp_schema::{SchemaInfo, SchemaInfoObjectMember, SchemaMetaType};
+use super::{Command, Event};
+
+/// Type of a member which can't be traced.
+const ANY_TYPE: &str = "any";
+
+fn schema_info(name: &str, meta_type: SchemaMetaType) -> SchemaInfo {
+    SchemaInfo {
+        name: name.to_string(),
+        meta_type,
+        arg_type: None,
+        ret_type: None,
+        members: None,
+        values: None,
+        element_type: None,
+        json_type: None,
+    }
+}
+
+/// Collection of `SchemaInfo` of commands, events and types they use.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    /// Commands and events in the order they are added.
+    entities: RefCell<Vec<SchemaInfo>>,
+    /// Types in the order they are traced, each type is listed once.
+    types: RefCell<Vec<SchemaInfo>>,
+    type_names: RefCell<HashSet<String>>,
+}
+
+impl SchemaRegistry {
+    /// Add command `name`, whose arguments are `T` and return is `T::Res`.
+    pub fn add_command<T: Command + DeserializeOwned>(&self, name: &str) {
+        let mut info = schema_info(name, SchemaMetaType::Command);
+        info.arg_type = Some(self.trace::<T>(Some(format!("q_obj_{}-arg", name))));
+        info.ret_type = Some(self.trace::<T::Res>(None));
+        self.entities.borrow_mut().push(info);
+    }
+
+    /// Add event `T::NAME`, whose data is `T`.
+    pub fn add_event<T: Event>(&self) {
+        let mut info = schema_info(T::NAME, SchemaMetaType::Event);
+        info.arg_type = Some(self.trace::<T>(Some(format!("q_obj_{}-arg", T::NAME))));
+        self.entities.borrow_mut().push(info);
+    }
+
+    /// Get the schema, commands and events are followed by the types.
+    pub fn into_schema(self) -> Vec<SchemaInfo> {
+        let mut schema = self.entities.into_inner();
+        schema.extend(self.types.into_inner());
+        schema
+    }
+
+    /// Trace type `T` and return its name in schema.
+    ///
+    /// # Arguments
+    ///
+    /// * `object_name` - Name of `T` instead of its struct name, if `T` is an
+    ///   object only used implicitly, e.g. arguments of a command.
+    fn trace<T: DeserializeOwned>(&self, object_name: Option<String>) -> String {
+        let type_name = RefCell::new(None);
+        let optional = Cell::new(false);
+        // The value deserialized from dummy values is meaningless, and the
+        // error just stops tracing of the rest members, which are `any`.
+        let _ = T::deserialize(Tracer {
+            registry: self,
+            type_name: &type_name,
+            optional: &optional,
+            object_name,
+        });
+        type_name
+            .into_inner()
+            .unwrap_or_else(|| ANY_TYPE.to_string())
+    }
+
+    fn add_type(&self, info: SchemaInfo) {
+        if self.type_names.borrow_mut().insert(info.name.clone()) {
+            self.types.borrow_mut().push(info);
+        }
+    }
+}
+
+/// Deserializer recording the kind of value asked for by a type.
+struct Tracer<'a> {
+    registry: &'a SchemaRegistry,
+    /// Name of the type traced.
+    type_name: &'a RefCell<Option<String>>,
+    /// Whether the value can be omitted.
+    optional: &'a Cell<bool>,
+    /// Name of the object traced instead of its struct name.
+    object_name: Option<String>,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(
+        registry: &'a SchemaRegistry,
+        type_name: &'a RefCell<Option<String>>,
+        optional: &'a Cell<bool>,
+    ) -> Self {
+        Tracer {
+            registry,
+            type_name,
+            optional,
+            object_name: None,
+        }
+    }
+
+    fn set_type(&self, info: SchemaInfo) {
+        *self.type_name.borrow_mut() = Some(info.name.clone());
+        self.registry.add_type(info);
+    }
+
+    fn set_builtin(&self, name: &str, json_type: &str) {
+        let mut info = schema_info(name, SchemaMetaType::Builtin);
+        info.json_type = Some(json_type.to_string());
+        self.set_type(info);
+    }
+}
+
+macro_rules! trace_builtin {
+    ( $( $method:ident => ($name:literal, $json_type:literal, $visit:ident( $( $dummy:expr )? )) ),* $(,)? ) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.set_builtin($name, $json_type);
+                visitor.$visit($( $dummy )?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Tracer<'a> {
+    type Error = Error;
+
+    trace_builtin!(
+        deserialize_any => ("any", "value", visit_unit()),
+        deserialize_bool => ("bool", "boolean", visit_bool(false)),
+        deserialize_i8 => ("int", "int", visit_i8(0)),
+        deserialize_i16 => ("int", "int", visit_i16(0)),
+        deserialize_i32 => ("int", "int", visit_i32(0)),
+        deserialize_i64 => ("int", "int", visit_i64(0)),
+        deserialize_u8 => ("int", "int", visit_u8(0)),
+        deserialize_u16 => ("int", "int", visit_u16(0)),
+        deserialize_u32 => ("int", "int", visit_u32(0)),
+        deserialize_u64 => ("int", "int", visit_u64(0)),
+        deserialize_f32 => ("number", "number", visit_f32(0.0)),
+        deserialize_f64 => ("number", "number", visit_f64(0.0)),
+        deserialize_char => ("str", "string", visit_char(' ')),
+        deserialize_str => ("str", "string", visit_str("")),
+        deserialize_string => ("str", "string", visit_str("")),
+        deserialize_bytes => ("str", "string", visit_bytes(&[])),
+        deserialize_byte_buf => ("str", "string", visit_bytes(&[])),
+        deserialize_identifier => ("str", "string", visit_str("")),
+        deserialize_unit => ("null", "null", visit_unit()),
+    );
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.optional.set(true);
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // Only arguments declared as `Ignored` ask for it, they're optional.
+        self.optional.set(true);
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let element_type = RefCell::new(None);
+        let optional = Cell::new(false);
+        let result = visitor.visit_seq(OneElement {
+            element: Some(Tracer::new(self.registry, &element_type, &optional)),
+        });
+
+        let element_type = element_type
+            .into_inner()
+            .unwrap_or_else(|| ANY_TYPE.to_string());
+        let mut info = schema_info(&format!("[{}]", element_type), SchemaMetaType::Array);
+        info.element_type = Some(element_type);
+        self.set_type(info);
+        result
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // Keys of map aren't known, so it's any JSON object.
+        self.set_builtin(ANY_TYPE, "value");
+        visitor.visit_map(Members {
+            registry: self.registry,
+            fields: &[],
+            members: &[],
+            index: 0,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let members: Vec<(RefCell<Option<String>>, Cell<bool>)> = fields
+            .iter()
+            .map(|_| (RefCell::new(None), Cell::new(false)))
+            .collect();
+        let result = visitor.visit_map(Members {
+            registry: self.registry,
+            fields,
+            members: &members,
+            index: 0,
+        });
+
+        let name = self.object_name.clone().unwrap_or_else(|| name.to_string());
+        let mut info = schema_info(&name, SchemaMetaType::Object);
+        info.members = Some(
+            fields
+                .iter()
+                .zip(members)
+                .map(|(field, (type_name, optional))| SchemaInfoObjectMember {
+                    name: field.to_string(),
+                    type_: type_name
+                        .into_inner()
+                        .unwrap_or_else(|| ANY_TYPE.to_string()),
+                    optional: optional.get(),
+                })
+                .collect(),
+        );
+        self.set_type(info);
+        result
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let mut info = schema_info(name, SchemaMetaType::Enum);
+        info.values = Some(variants.iter().map(|v| v.to_string()).collect());
+        self.set_type(info);
+        match variants.first() {
+            Some(&variant) => visitor.visit_enum(UnitVariant(variant)),
+            None => Err(de::Error::custom(format!("Enum {} has no variant", name))),
+        }
+    }
+}
+
+/// Sequence of a single element traced.
+struct OneElement<'a> {
+    element: Option<Tracer<'a>>,
+}
+
+impl<'de, 'a> SeqAccess<'de> for OneElement<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.element.take() {
+            Some(element) => seed.deserialize(element).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Members of struct, each of them is traced.
+struct Members<'a> {
+    registry: &'a SchemaRegistry,
+    fields: &'static [&'static str],
+    /// Type name and optional of each member.
+    members: &'a [(RefCell<Option<String>>, Cell<bool>)],
+    index: usize,
+}
+
+impl<'de, 'a> MapAccess<'de> for Members<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.fields.get(self.index) {
+            Some(field) => seed.deserialize(field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let members = self.members;
+        let (type_name, optional) = &members[self.index];
+        self.index += 1;
+        seed.deserialize(Tracer::new(self.registry, type_name, optional))
+    }
+}
+
+/// The first variant of enum, which must be a unit variant.
+struct UnitVariant(&'static str);
+
+impl<'de> EnumAccess<'de> for UnitVariant {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        seed.deserialize(self.0.into_deserializer())
+            .map(|value| (value, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for UnitVariant {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, _seed: T) -> Result<T::Value, Error> {
+        Err(de::Error::custom("Only unit variant is supported"))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, _visitor: V) -> Result<V::Value, Error> {
+        Err(de::Error::custom("Only unit variant is supported"))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Error> {
+        Err(de::Error::custom("Only unit variant is supported"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp::qmp_schema as schema;
+
+    fn find<'a>(schema: &'a [SchemaInfo], name: &str) -> &'a SchemaInfo {
+        schema
+            .iter()
+            .find(|info| info.name == name)
+            .unwrap_or_else(|| panic!("{} is missing", name))
+    }
+
+    fn member<'a>(info: &'a SchemaInfo, name: &str) -> &'a SchemaInfoObjectMember {
+        info.members
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|member| member.name == name)
+            .unwrap_or_else(|| panic!("{} of {} is missing", name, info.name))
+    }
+
+    #[test]
+    fn test_schema_blockdev_add() {
+        let schema = SchemaInfo::all();
+
+        let command = find(&schema, "blockdev-add");
+        assert_eq!(command.meta_type, SchemaMetaType::Command);
+        assert_eq!(command.ret_type.as_deref(), Some("Empty"));
+        let args = find(&schema, command.arg_type.as_ref().unwrap());
+        assert_eq!(args.meta_type, SchemaMetaType::Object);
+
+        let node_name = member(args, "node-name");
+        assert_eq!(node_name.type_, "str");
+        assert!(!node_name.optional);
+        let cache = member(args, "cache");
+        assert_eq!(cache.type_, "CacheOptions");
+        assert!(cache.optional);
+        let read_only = member(args, "read-only");
+        assert_eq!(read_only.type_, "bool");
+        assert!(read_only.optional);
+        let file = member(args, "file");
+        assert!(!file.optional);
+
+        // Nested objects and enums are listed with their members and values.
+        let file = find(&schema, &file.type_);
+        assert_eq!(file.meta_type, SchemaMetaType::Object);
+        assert!(!member(file, "filename").optional);
+        let aio = member(file, "aio");
+        assert!(aio.optional);
+        let aio = find(&schema, &aio.type_);
+        assert_eq!(aio.meta_type, SchemaMetaType::Enum);
+        assert_eq!(
+            aio.values.as_ref().unwrap(),
+            &vec![
+                "threads".to_string(),
+                "native".to_string(),
+                "io_uring".to_string()
+            ]
+        );
+        assert_eq!(
+            member(find(&schema, "CacheOptions"), "no-flush").type_,
+            "bool"
+        );
+
+        let int = find(&schema, "int");
+        assert_eq!(int.meta_type, SchemaMetaType::Builtin);
+        assert_eq!(int.json_type.as_deref(), Some("int"));
+    }
+
+    #[test]
+    fn test_schema_all() {
+        let schema = SchemaInfo::all();
+
+        // Every command and event is listed, with its types.
+        for name in schema::QMP_COMMANDS {
+            let command = find(&schema, name);
+            assert_eq!(command.meta_type, SchemaMetaType::Command);
+            find(&schema, command.arg_type.as_ref().unwrap());
+            find(&schema, command.ret_type.as_ref().unwrap());
+        }
+        for name in &["SHUTDOWN", "RESET", "DEVICE_DELETED"] {
+            let event = find(&schema, name);
+            assert_eq!(event.meta_type, SchemaMetaType::Event);
+            find(&schema, event.arg_type.as_ref().unwrap());
+        }
+        let deleted = find(&schema, "q_obj_DEVICE_DELETED-arg");
+        assert!(member(deleted, "device").optional);
+        assert!(!member(deleted, "path").optional);
+
+        // Arrays, and arrays of enum.
+        let commands = find(&schema, "query-commands");
+        let ret = find(&schema, commands.ret_type.as_ref().unwrap());
+        assert_eq!(ret.meta_type, SchemaMetaType::Array);
+        assert_eq!(ret.element_type.as_deref(), Some("CommandInfo"));
+        let enable = member(find(&schema, "q_obj_qmp_capabilities-arg"), "enable");
+        assert_eq!(enable.type_, "[QmpCapability]");
+        assert!(enable.optional);
+        assert_eq!(
+            find(&schema, "QmpCapability").values.as_ref().unwrap(),
+            &vec!["oob".to_string()]
+        );
+        assert!(find(&schema, "q_obj_stop-arg")
+            .members
+            .as_ref()
+            .unwrap()
+            .is_empty());
+
+        // Each type is listed once.
+        let mut names = HashSet::new();
+        for info in &schema {
+            assert!(
+                names.insert(info.name.clone()),
+                "{} is duplicated",
+                info.name
+            );
+        }
+    }
+}
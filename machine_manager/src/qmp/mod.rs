@@ -19,39 +19,57 @@
 //! It has three feature:
 //! 1. Qmp server is no-async service as well as Qemu's.
 //! Command + events can replace asynchronous command.
-//! 2. Qmp server can only be connected a client at one time.
-//! It's no situation where be communicated with many clients.
-//! When it must use, can use other communication way not QMP.
+//! 2. Qmp server can be connected by several clients at the same time.
+//! Each client negotiates capabilities itself, responses are only sent to
+//! the client issuing the command, and events are sent to all clients which
+//! have executed `qmp_capabilities`.
 //! 3. Qmp's message structure base is transformed by scripts from Qemu's
 //! `qmp-schema.json`. It's can be compatible by Qemu's zoology. Those
 //! transformed structures can be found in `machine_manager/src/qmp/qmp_schema.rs`
 extern crate serde;
 extern crate serde_json;
 
+mod introspect;
 #[allow(non_upper_case_globals)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 pub mod qmp_schema;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
-use std::os::unix::io::RawFd;
-use std::sync::{Arc, RwLock};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use util::epoll_context::{read_fd, EventNotifier, NotifierOperation};
+use util::rollback::run_exit_hooks;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
 
+use crate::block_backend::IoThrottleLimits;
+use crate::config::{ConfigCheck, RngConfig, VsockConfig, DEFAULT_RNG_FILE};
 use crate::errors::Result;
-use crate::machine::MachineExternalInterface;
+use crate::machine::{DeviceAddArgs, MachineExternalInterface};
 use crate::socket::SocketRWHandler;
 use qmp_schema as schema;
 use schema::QmpCommand;
 
 static mut QMP_CHANNEL: Option<Arc<QmpChannel>> = None;
 
+/// Capabilities advertised in greeting, which can be enabled by command
+/// `qmp_capabilities`.
+const QMP_CAPABILITIES: &[&str] = &["oob"];
+
+/// Commands allowed to be executed out-of-band by `exec-oob`, which don't
+/// wait for main loop. `migrate_cancel` is left out as there's no migration
+/// to cancel, it has to be added here once migration is supported.
+const OOB_COMMANDS: &[&str] = &["quit", "query-status"];
+
 /// Macro `event!`: send event to qmp-client.
 ///
 /// # Arguments
@@ -172,7 +190,7 @@ impl QmpGreeting {
             minor,
             major,
         };
-        let cap: Vec<String> = Default::default();
+        let cap: Vec<String> = QMP_CAPABILITIES.iter().map(|c| c.to_string()).collect();
         let version = Version {
             application: version_number,
             package: "".to_string(),
@@ -198,7 +216,7 @@ pub struct Response {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     error: Option<ErrorMessage>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    id: Option<u32>,
+    id: Option<Value>,
 }
 
 impl Response {
@@ -209,7 +227,7 @@ impl Response {
     /// * `v` - The `Value` of qmp `return` field.
     /// * `id` - The `id` for qmp `Response`, it must be equal to `Request`'s
     ///          `id`.
-    pub fn create_response(v: Value, id: Option<u32>) -> Self {
+    pub fn create_response(v: Value, id: Option<Value>) -> Self {
         Response {
             return_: Some(v),
             error: None,
@@ -234,7 +252,7 @@ impl Response {
     ///          `id`.
     pub fn create_error_response(
         err_class: schema::QmpErrorClass,
-        id: Option<u32>,
+        id: Option<Value>,
     ) -> Result<Self> {
         Ok(Response {
             return_: None,
@@ -243,7 +261,7 @@ impl Response {
         })
     }
 
-    fn change_id(&mut self, id: Option<u32>) {
+    fn change_id(&mut self, id: Option<Value>) {
         self.id = id;
     }
 }
@@ -263,6 +281,19 @@ impl From<bool> for Response {
 }
 
 /// `ErrorMessage` for Qmp Response.
+impl From<Result<()>> for Response {
+    fn from(ret: Result<()>) -> Self {
+        match ret {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+            .unwrap(),
+        }
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ErrorMessage {
     #[serde(rename = "class")]
@@ -330,51 +361,270 @@ pub fn create_timestamp() -> TimeStamp {
 ///
 /// # Errors
 ///
-/// This function will fail when json parser failed or socket file description broke.
-pub fn handle_qmp(stream_fd: RawFd, controller: &Arc<dyn MachineExternalInterface>) -> Result<()> {
+/// This function will fail when json parser failed, socket file description broke
+/// or the stream isn't bound to `QmpChannel`.
+pub fn handle_qmp(
+    stream_fd: RawFd,
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+) -> Result<()> {
+    let serial = match QmpChannel::client_serial(stream_fd) {
+        Some(serial) => serial,
+        None => bail!("Qmp client {} isn't bound", stream_fd),
+    };
     let mut qmp_service = crate::socket::SocketHandler::new(stream_fd);
-    match qmp_service.decode_line() {
+    match qmp_service.decode_line::<Value>() {
         (Ok(None), _) => Ok(()),
-        (Ok(buffer), if_fd) => {
-            info!("QMP: <-- {:?}", buffer);
-            let qmp_command: schema::QmpCommand = buffer.unwrap();
-            let (return_msg, shutdown_flag) = qmp_command_exec(qmp_command, controller, if_fd);
-            info!("QMP: --> {:?}", return_msg);
-            qmp_service.send_str(&return_msg)?;
-
-            // handle shutdown command
-            if shutdown_flag {
-                let shutdown_msg = schema::SHUTDOWN {
-                    guest: false,
-                    reason: "host-qmp-quit".to_string(),
-                };
-                event!(SHUTDOWN; shutdown_msg);
-
-                std::io::stdin()
-                    .lock()
-                    .set_canon_mode()
-                    .expect("Failed to set terminal to canon mode.");
-                std::process::exit(1);
+        (Ok(Some(request)), if_fd) => {
+            info!("QMP: <-- {:?}", request);
+            match parse_request(request, qmp_service.get_line(), stream_fd) {
+                // Capabilities are state of the connection rather than the VM,
+                // negotiate at once so that following `exec-oob` is accepted.
+                QmpRequest::InBand(QmpCommand::qmp_capabilities { arguments, id }) => {
+                    let oob = arguments
+                        .enable
+                        .unwrap_or_default()
+                        .contains(&schema::QmpCapability::oob);
+                    QmpChannel::negotiate(stream_fd, oob);
+                    let mut qmp_response = Response::create_empty_response();
+                    qmp_response.change_id(id);
+                    send_response(
+                        stream_fd,
+                        serial,
+                        &serde_json::to_string(&qmp_response)?,
+                        false,
+                    )
+                }
+                QmpRequest::InBand(command) => {
+                    QmpChannel::push_in_band(InBandRequest {
+                        stream_fd,
+                        serial,
+                        command,
+                        if_fd,
+                    });
+                    Ok(())
+                }
+                QmpRequest::OutOfBand(command) => {
+                    let (return_msg, shutdown_flag) = qmp_oob_exec(command, controller);
+                    send_response(stream_fd, serial, &return_msg, shutdown_flag)
+                }
+                QmpRequest::Invalid(err_resp) => {
+                    send_response(stream_fd, serial, &serde_json::to_string(&err_resp)?, false)
+                }
             }
-
-            Ok(())
         }
         (Err(e), _) => {
-            let err_resp = schema::QmpErrorClass::GenericError(format!("{}", &e));
             warn!("Qmp json parser made an error:{}", e);
-            qmp_service.send_str(&serde_json::to_string(&Response::create_error_response(
-                err_resp, None,
-            )?)?)?;
-            Ok(())
+            let err_resp = create_parse_error_response(qmp_service.get_line(), &e.to_string())?;
+            send_response(stream_fd, serial, &serde_json::to_string(&err_resp)?, false)
+        }
+    }
+}
+
+/// Execute in-band commands forwarded by `handle_qmp` in the order they're
+/// received, it's called in main loop. Commands of all clients are executed
+/// one by one, and responses are sent to the client issuing the command.
+///
+/// # Arguments
+///
+/// * `controller` - The controller which execute actual qmp command.
+pub fn handle_in_band(controller: &Arc<dyn MachineExternalInterface + Send + Sync>) {
+    while let Some(request) = QmpChannel::pop_in_band() {
+        let (return_msg, shutdown_flag) =
+            qmp_command_exec(request.command, controller, request.if_fd);
+        if let Err(e) = send_response(
+            request.stream_fd,
+            request.serial,
+            &return_msg,
+            shutdown_flag,
+        ) {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Create notifier of main loop, which executes in-band commands forwarded
+/// by `handle_qmp`.
+///
+/// # Arguments
+///
+/// * `controller` - The controller which execute actual qmp command.
+pub fn in_band_notifier(
+    controller: Arc<dyn MachineExternalInterface + Send + Sync>,
+) -> EventNotifier {
+    let handler: Box<dyn Fn(EventSet, RawFd) -> Option<Vec<EventNotifier>>> =
+        Box::new(move |_, fd| {
+            read_fd(fd);
+            handle_in_band(&controller);
+            None
+        });
+
+    EventNotifier::new(
+        NotifierOperation::AddShared,
+        QmpChannel::inner().in_band_evt.as_raw_fd(),
+        None,
+        EventSet::IN,
+        vec![Arc::new(Mutex::new(handler))],
+    )
+}
+
+/// Send response of a command to client, and exit if the command is `quit`.
+fn send_response(
+    stream_fd: RawFd,
+    serial: u64,
+    return_msg: &str,
+    shutdown_flag: bool,
+) -> Result<()> {
+    QmpChannel::send_response(stream_fd, serial, return_msg)?;
+
+    // handle shutdown command
+    if shutdown_flag {
+        let shutdown_msg = schema::SHUTDOWN {
+            guest: false,
+            reason: "host-qmp-quit".to_string(),
+        };
+        event!(SHUTDOWN; shutdown_msg);
+
+        std::io::stdin()
+            .lock()
+            .set_canon_mode()
+            .expect("Failed to set terminal to canon mode.");
+        // Destructors don't run on `exit`, release resources kept after
+        // bring-up explicitly.
+        run_exit_hooks();
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Qmp request received from client.
+enum QmpRequest {
+    /// Command by `execute`, which is executed in main loop.
+    InBand(QmpCommand),
+    /// Command by `exec-oob`, which is executed once received.
+    OutOfBand(QmpCommand),
+    /// Request can't be executed, with the error response.
+    Invalid(Response),
+}
+
+/// Parse qmp request, `exec-oob` is used instead of `execute` for commands
+/// executed out-of-band.
+///
+/// # Arguments
+///
+/// * `request` - Request received from client.
+/// * `line` - The line of `request`, to create error response.
+/// * `stream_fd` - Stream fd of the client sending `request`.
+fn parse_request(mut request: Value, line: &str, stream_fd: RawFd) -> QmpRequest {
+    let mut oob_command = None;
+    if let Some(object) = request.as_object_mut() {
+        if let Some(name) = object.remove("exec-oob") {
+            if object.contains_key("execute") {
+                return QmpRequest::Invalid(
+                    Response::create_error_response(
+                        schema::QmpErrorClass::GenericError(
+                            "execute and exec-oob can't be used together".to_string(),
+                        ),
+                        parse_request_id(line),
+                    )
+                    .unwrap(),
+                );
+            }
+            oob_command = Some(name.as_str().unwrap_or_default().to_string());
+            object.insert("execute".to_string(), name);
+        }
+    }
+
+    let command = match serde_json::from_value::<QmpCommand>(request) {
+        Ok(command) => command,
+        Err(e) => {
+            warn!("Qmp json parser made an error:{}", e);
+            return QmpRequest::Invalid(create_parse_error_response(line, &e.to_string()).unwrap());
+        }
+    };
+    let name = match oob_command {
+        Some(name) => name,
+        None => return QmpRequest::InBand(command),
+    };
+
+    let desc = if !QmpChannel::is_oob_enabled(stream_fd) {
+        "Out-of-band execution isn't enabled by qmp_capabilities".to_string()
+    } else if !OOB_COMMANDS.contains(&name.as_str()) {
+        format!("Command {} can't be executed out-of-band", name)
+    } else {
+        return QmpRequest::OutOfBand(command);
+    };
+    QmpRequest::Invalid(
+        Response::create_error_response(
+            schema::QmpErrorClass::GenericError(desc),
+            parse_request_id(line),
+        )
+        .unwrap(),
+    )
+}
+
+/// Execute command in `OOB_COMMANDS` out-of-band, main loop may be blocked.
+fn qmp_oob_exec(
+    qmp_command: QmpCommand,
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+) -> (String, bool) {
+    match qmp_command {
+        // VM isn't destroyed which may wait for the blocked main loop, the
+        // process just exits after response.
+        QmpCommand::quit { id, .. } => {
+            let mut qmp_response = Response::create_empty_response();
+            qmp_response.change_id(id);
+            (serde_json::to_string(&qmp_response).unwrap(), true)
         }
+        _ => qmp_command_exec(qmp_command, controller, None),
     }
 }
 
+/// Get `id` of a qmp request which fails to be parsed as `QmpCommand`, so the
+/// error response can still be matched by client. Return `None` if the
+/// request isn't a JSON object.
+fn parse_request_id(line: &str) -> Option<Value> {
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(mut request)) => request.remove("id").filter(|id| !id.is_null()),
+        _ => None,
+    }
+}
+
+/// Get name of the command of a qmp request which fails to be parsed as
+/// `QmpCommand`. Return `None` if it isn't a known command.
+fn parse_request_command(line: &str) -> Option<String> {
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(request)) => {
+            match request.get("execute").or_else(|| request.get("exec-oob")) {
+                Some(Value::String(name)) if schema::QMP_COMMANDS.contains(&name.as_str()) => {
+                    Some(name.clone())
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Create error response for a qmp request which fails to be parsed as
+/// `QmpCommand`. If the command is known, the error must be in its arguments,
+/// e.g. an unknown field, so the command is named in the description.
+fn create_parse_error_response(line: &str, err: &str) -> Result<Response> {
+    let desc = match parse_request_command(line) {
+        Some(command) => format!("Invalid arguments for command {}: {}", command, err),
+        None => err.to_string(),
+    };
+    Response::create_error_response(
+        schema::QmpErrorClass::GenericError(desc),
+        parse_request_id(line),
+    )
+}
+
 /// Create a match , where `qmp_command` and its arguments matching by handle
 /// function, and exec this qmp command.
 fn qmp_command_exec(
     qmp_command: QmpCommand,
-    controller: &Arc<dyn MachineExternalInterface>,
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
     if_fd: Option<RawFd>,
 ) -> (String, bool) {
     let mut qmp_response = Response::create_empty_response();
@@ -387,11 +637,26 @@ fn qmp_command_exec(
         (cont, resume),
         (query_status, query_status),
         (query_cpus, query_cpus),
-        (query_hotpluggable_cpus, query_hotpluggable_cpus);
-        (device_add, device_add, id, driver, addr, lun),
+        (query_hotpluggable_cpus, query_hotpluggable_cpus),
+        (query_memory_summary, query_memory_summary),
+        (query_netdev, query_netdev),
+        (system_reset, reset),
+        (system_powerdown, powerdown);
         (device_del, device_del, id),
-        (blockdev_add, blockdev_add, node_name, file, cache, read_only),
-        (netdev_add, netdev_add, id, if_name, fds)
+        (
+            blockdev_add,
+            blockdev_add,
+            node_name,
+            file,
+            cache,
+            read_only,
+            format,
+            discard,
+            detect_zeroes
+        ),
+        (netdev_add, netdev_add, id, if_name, fds, vhost, vhostfds, queues),
+        (set_link, set_link, name, up),
+        (dump_guest_memory, dump_guest_memory, protocol)
     );
 
     // Handle the Qmp command which macro can't cover
@@ -406,6 +671,62 @@ fn qmp_command_exec(
                 qmp_response = controller.getfd(arguments.fd_name, if_fd);
                 id
             }
+            QmpCommand::query_version { id, .. } => {
+                let version = schema::VersionInfo::current();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(&version).unwrap(), None);
+                id
+            }
+            QmpCommand::query_target { id, .. } => {
+                let target = schema::TargetInfo::current();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(&target).unwrap(), None);
+                id
+            }
+            QmpCommand::query_commands { id, .. } => {
+                let commands = schema::CommandInfo::all();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(&commands).unwrap(), None);
+                id
+            }
+            QmpCommand::query_qmp_schema { id, .. } => {
+                let schema = schema::SchemaInfo::all();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(&schema).unwrap(), None);
+                id
+            }
+            QmpCommand::device_add { arguments, id } => {
+                qmp_response = qmp_device_add(controller, arguments);
+                id
+            }
+            QmpCommand::balloon { arguments, id } => {
+                qmp_response = qmp_balloon(controller, arguments.value);
+                id
+            }
+            QmpCommand::query_balloon { id, .. } => {
+                qmp_response = qmp_query_balloon(controller);
+                id
+            }
+            QmpCommand::query_block { id, .. } => {
+                let block = controller.block_backends().query_block();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(&block).unwrap(), None);
+                id
+            }
+            QmpCommand::query_blockstats { id, .. } => {
+                let blockstats = controller.block_backends().query_blockstats();
+                qmp_response =
+                    Response::create_response(serde_json::to_value(&blockstats).unwrap(), None);
+                id
+            }
+            QmpCommand::block_resize { arguments, id } => {
+                qmp_response = qmp_block_resize(controller, arguments);
+                id
+            }
+            QmpCommand::block_set_io_throttle { arguments, id } => {
+                qmp_response = qmp_block_set_io_throttle(controller, arguments);
+                id
+            }
             _ => None,
         }
     }
@@ -415,15 +736,211 @@ fn qmp_command_exec(
     (serde_json::to_string(&qmp_response).unwrap(), shutdown_flag)
 }
 
+/// Add device by `device_add`, arguments for its driver are checked first.
+fn qmp_device_add(
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+    args: schema::device_add,
+) -> Response {
+    let id = args.id.clone();
+    device_add_args(args)
+        .and_then(|args| controller.device_add(id, args))
+        .into()
+}
+
+/// Build `DeviceAddArgs` from arguments of `device_add`, and check the
+/// device config of driver.
+fn device_add_args(args: schema::device_add) -> Result<DeviceAddArgs> {
+    match args.driver.as_str() {
+        "virtio-balloon-mmio" => Ok(DeviceAddArgs::Balloon),
+        "virtio-rng" => {
+            let config = RngConfig {
+                rng_id: args.id,
+                random_file: args
+                    .filename
+                    .unwrap_or_else(|| DEFAULT_RNG_FILE.to_string()),
+                max_bytes: args.max_bytes,
+                period: args.period,
+            };
+            config.check()?;
+            Ok(DeviceAddArgs::Rng(config))
+        }
+        "vhost-vsock" => {
+            let guest_cid = match args.guest_cid {
+                Some(cid) => cid,
+                None => bail!("Device vhost-vsock requires guest-cid"),
+            };
+            let config = VsockConfig {
+                vsock_id: args.id,
+                guest_cid,
+                vhost_fd: None,
+            };
+            config.check()?;
+            Ok(DeviceAddArgs::Vsock(config))
+        }
+        _ => Ok(DeviceAddArgs::Replace {
+            driver: args.driver,
+            addr: args.addr,
+            lun: args.lun,
+        }),
+    }
+}
+
+/// Forward target size of guest memory to balloon device, the size should be
+/// in range (0, RAM size].
+fn qmp_balloon(
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+    value: u64,
+) -> Response {
+    let balloon = match controller.balloon_handle() {
+        Some(balloon) => balloon,
+        None => return balloon_not_active(),
+    };
+
+    let ram_size = controller.ram_size();
+    if value == 0 || value > ram_size {
+        return Response::create_error_response(
+            schema::QmpErrorClass::GenericError(format!(
+                "Invalid balloon target size {}, should be in range (0, {}]",
+                value, ram_size
+            )),
+            None,
+        )
+        .unwrap();
+    }
+
+    balloon.set_target(value);
+    Response::create_empty_response()
+}
+
+/// Query actual size of guest memory from balloon device.
+fn qmp_query_balloon(controller: &Arc<dyn MachineExternalInterface + Send + Sync>) -> Response {
+    match controller.balloon_handle() {
+        Some(balloon) => {
+            let info = schema::BalloonInfo {
+                actual: balloon.actual(),
+            };
+            Response::create_response(serde_json::to_value(&info).unwrap(), None)
+        }
+        None => balloon_not_active(),
+    }
+}
+
+/// Find the block device by its id or node name of its backend, and grow
+/// its image.
+fn qmp_block_resize(
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+    args: schema::block_resize,
+) -> Response {
+    let block_backends = controller.block_backends();
+    let device = match (args.device, args.node_name) {
+        (Some(device), None) if block_backends.has_device(&device) => Some(device),
+        (None, Some(node_name)) => block_backends.device_of_node(&node_name),
+        (Some(_), Some(_)) | (None, None) => {
+            return Response::create_error_response(
+                schema::QmpErrorClass::GenericError(
+                    "Either device or node-name should be given".to_string(),
+                ),
+                None,
+            )
+            .unwrap();
+        }
+        (Some(_), None) => None,
+    };
+
+    match device {
+        Some(device) => controller.block_resize(device, args.size),
+        None => Response::create_error_response(
+            schema::QmpErrorClass::DeviceNotFound("Block device or node not found".to_string()),
+            None,
+        )
+        .unwrap(),
+    }
+}
+
+/// Set IO throttling limits of the block device, which take effect on its
+/// next IO requests.
+fn qmp_block_set_io_throttle(
+    controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+    args: schema::block_set_io_throttle,
+) -> Response {
+    let limits = IoThrottleLimits {
+        bps: args.bps,
+        bps_rd: args.bps_rd,
+        bps_wr: args.bps_wr,
+        iops: args.iops,
+        iops_rd: args.iops_rd,
+        iops_wr: args.iops_wr,
+    };
+    if let Err(e) = limits.check() {
+        return Response::create_error_response(
+            schema::QmpErrorClass::GenericError(e.to_string()),
+            None,
+        )
+        .unwrap();
+    }
+
+    if controller
+        .block_backends()
+        .set_io_throttle(&args.device, limits)
+    {
+        Response::create_empty_response()
+    } else {
+        Response::create_error_response(
+            schema::QmpErrorClass::DeviceNotFound("Block device not found".to_string()),
+            None,
+        )
+        .unwrap()
+    }
+}
+
+fn balloon_not_active() -> Response {
+    Response::create_error_response(
+        schema::QmpErrorClass::DeviceNotActive("No balloon device has been activated".to_string()),
+        None,
+    )
+    .unwrap()
+}
+
 /// The struct `QmpChannel` is the only struct can handle Global variable
 /// `QMP_CHANNEL`.
 /// It is used to send event to qmp client and restore some file descriptor
 /// which was sended by client.
 pub struct QmpChannel {
-    /// The `writer` to send `QmpEvent`.
-    event_writer: RwLock<Option<SocketRWHandler>>,
+    /// Clients connected, indexed by their stream fd.
+    clients: RwLock<BTreeMap<RawFd, QmpClient>>,
+    /// Serial number of the next client.
+    next_serial: AtomicU64,
     /// Restore file descriptor received from client.
     fds: Arc<RwLock<BTreeMap<String, RawFd>>>,
+    /// In-band commands waiting for main loop.
+    in_band: Mutex<VecDeque<InBandRequest>>,
+    /// Notify main loop of in-band commands.
+    in_band_evt: EventFd,
+}
+
+/// A client connected to qmp server.
+struct QmpClient {
+    /// Serial number of the connection, as stream fd may be reused by a later
+    /// connection after the client is gone.
+    serial: u64,
+    /// The `writer` to send responses and events to the client.
+    writer: SocketRWHandler,
+    /// Client has executed `qmp_capabilities`, events are only sent to
+    /// negotiated clients.
+    negotiated: bool,
+    /// Capability `oob` is enabled by client.
+    oob_enabled: bool,
+}
+
+/// In-band command forwarded to main loop.
+struct InBandRequest {
+    /// Stream to send response.
+    stream_fd: RawFd,
+    /// Serial number of the client.
+    serial: u64,
+    command: QmpCommand,
+    /// File descriptor received with the command.
+    if_fd: Option<RawFd>,
 }
 
 impl QmpChannel {
@@ -432,30 +949,104 @@ impl QmpChannel {
         unsafe {
             if QMP_CHANNEL.is_none() {
                 QMP_CHANNEL = Some(Arc::new(QmpChannel {
-                    event_writer: RwLock::new(None),
+                    clients: RwLock::new(BTreeMap::new()),
+                    next_serial: AtomicU64::new(0),
                     fds: Arc::new(RwLock::new(BTreeMap::new())),
+                    in_band: Mutex::new(VecDeque::new()),
+                    in_band_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
                 }));
             }
         }
     }
 
-    /// Bind a `SocketRWHanler` to `QMP_CHANNEL`.
+    /// Bind a `SocketRWHanler` of a new client to `QMP_CHANNEL`.
     ///
     /// # Arguments
     ///
     /// * `writer` - The `SocketRWHandler` used to communicate with client.
     pub fn bind_writer(writer: SocketRWHandler) {
-        *Self::inner().event_writer.write().unwrap() = Some(writer);
+        let client = QmpClient {
+            serial: Self::inner().next_serial.fetch_add(1, Ordering::SeqCst),
+            writer,
+            negotiated: false,
+            oob_enabled: false,
+        };
+        Self::inner()
+            .clients
+            .write()
+            .unwrap()
+            .insert(client.writer.get_socket_fd(), client);
+    }
+
+    /// Unbind `SocketRWHandler` of a client from `QMP_CHANNEL`, in-band
+    /// commands of the client not executed yet are dropped with it.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - Stream fd of the client.
+    pub fn unbind(stream_fd: RawFd) {
+        let client = Self::inner().clients.write().unwrap().remove(&stream_fd);
+        if let Some(client) = client {
+            Self::inner()
+                .in_band
+                .lock()
+                .unwrap()
+                .retain(|request| request.serial != client.serial);
+        }
     }
 
-    /// Unbind `SocketRWHandler` from `QMP_CHANNEL`.
-    pub fn unbind() {
-        *Self::inner().event_writer.write().unwrap() = None;
+    /// Get serial number of the client bound with `stream_fd`.
+    fn client_serial(stream_fd: RawFd) -> Option<u64> {
+        Self::inner()
+            .clients
+            .read()
+            .unwrap()
+            .get(&stream_fd)
+            .map(|client| client.serial)
     }
 
-    /// Check whether a `SocketRWHandler` bind with `QMP_CHANNEL` or not.
+    /// Finish capabilities negotiation of a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - Stream fd of the client.
+    /// * `oob` - Whether out-of-band execution is enabled.
+    fn negotiate(stream_fd: RawFd, oob: bool) {
+        if let Some(client) = Self::inner().clients.write().unwrap().get_mut(&stream_fd) {
+            client.negotiated = true;
+            client.oob_enabled = oob;
+        }
+    }
+
+    /// Check whether out-of-band execution is enabled by a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - Stream fd of the client.
+    pub fn is_oob_enabled(stream_fd: RawFd) -> bool {
+        Self::inner()
+            .clients
+            .read()
+            .unwrap()
+            .get(&stream_fd)
+            .map_or(false, |client| client.oob_enabled)
+    }
+
+    /// Forward an in-band command to main loop.
+    fn push_in_band(request: InBandRequest) {
+        Self::inner().in_band.lock().unwrap().push_back(request);
+        if let Err(e) = Self::inner().in_band_evt.write(1) {
+            error!("Failed to notify main loop of qmp command: {}", e);
+        }
+    }
+
+    fn pop_in_band() -> Option<InBandRequest> {
+        Self::inner().in_band.lock().unwrap().pop_front()
+    }
+
+    /// Check whether any `SocketRWHandler` bind with `QMP_CHANNEL` or not.
     pub fn is_connected() -> bool {
-        Self::inner().event_writer.read().unwrap().is_some()
+        !Self::inner().clients.read().unwrap().is_empty()
     }
 
     /// Restore extern file descriptor in `QMP_CHANNEL`.
@@ -480,24 +1071,57 @@ impl QmpChannel {
         }
     }
 
-    /// Send a `QmpEvent` to client.
+    /// Send a `QmpEvent` to all negotiated clients.
     ///
     /// # Arguments
     ///
     /// * `event` - The `QmpEvent` sent to client.
-    #[allow(clippy::unused_io_amount)]
     pub fn send_event(event: &schema::QmpEvent) {
         if Self::is_connected() {
             let event_str = serde_json::to_string(&event).unwrap();
-            let mut writer_unlocked = Self::inner().event_writer.write().unwrap();
-            let writer = writer_unlocked.as_mut().unwrap();
-            writer.flush().unwrap();
-            writer.write(event_str.as_bytes()).unwrap();
-            writer.write(&[b'\n']).unwrap();
+            let mut clients = Self::inner().clients.write().unwrap();
+            for (stream_fd, client) in clients.iter_mut() {
+                if !client.negotiated {
+                    continue;
+                }
+                // A broken client is removed when its hang-up is handled,
+                // it mustn't prevent other clients from getting the event.
+                if let Err(e) = Self::write_line(&mut client.writer, &event_str) {
+                    warn!("Failed to send event to qmp client {}: {}", stream_fd, e);
+                }
+            }
             info!("EVENT: --> {:?}", event);
         }
     }
 
+    /// Send response of a command to the client issuing it. The response is
+    /// dropped if the client is gone.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - Stream fd of the client.
+    /// * `serial` - Serial number of the client.
+    /// * `return_msg` - The response sent to client.
+    fn send_response(stream_fd: RawFd, serial: u64, return_msg: &str) -> Result<()> {
+        let mut clients = Self::inner().clients.write().unwrap();
+        match clients.get_mut(&stream_fd) {
+            Some(client) if client.serial == serial => {
+                Self::write_line(&mut client.writer, return_msg)?;
+                info!("QMP: --> {:?}", return_msg);
+            }
+            _ => warn!("Qmp client is gone, response dropped: {:?}", return_msg),
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::unused_io_amount)]
+    fn write_line(writer: &mut SocketRWHandler, msg: &str) -> std::io::Result<()> {
+        writer.flush()?;
+        writer.write(msg.as_bytes())?;
+        writer.write(&[b'\n'])?;
+        Ok(())
+    }
+
     fn inner() -> &'static std::sync::Arc<QmpChannel> {
         unsafe {
             match &QMP_CHANNEL {
@@ -531,7 +1155,7 @@ mod tests {
                         },
                         "package": ""
                     },
-                    "capabilities": []
+                    "capabilities": ["oob"]
                 }
             }
         "#;
@@ -544,12 +1168,12 @@ mod tests {
     fn test_qmp_resp() {
         // 1.Empty response and ID change;
         let mut resp = Response::create_empty_response();
-        resp.change_id(Some(0));
+        resp.change_id(Some(Value::from(0)));
 
         let json_msg = r#"{"return":{},"id":0}"#;
         assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
 
-        resp.change_id(Some(1));
+        resp.change_id(Some(Value::from(1)));
         let json_msg = r#"{"return":{},"id":1}"#;
         assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
 
@@ -558,6 +1182,7 @@ mod tests {
             singlestep: false,
             running: true,
             status: schema::RunState::running,
+            clock: None,
         };
         let resp = Response::create_response(serde_json::to_value(&resp_value).unwrap(), None);
 
@@ -574,6 +1199,1265 @@ mod tests {
         assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
     }
 
+    #[test]
+    fn test_qmp_version_target() {
+        let cmd: QmpCommand =
+            serde_json::from_str(r#"{"execute":"query-version","id":3}"#).unwrap();
+        match cmd {
+            QmpCommand::query_version { id, .. } => assert_eq!(id, Some(Value::from(3))),
+            _ => panic!("expected query-version"),
+        }
+        let cmd: QmpCommand = serde_json::from_str(r#"{"execute":"query-target"}"#).unwrap();
+        match cmd {
+            QmpCommand::query_target { id, .. } => assert_eq!(id, None),
+            _ => panic!("expected query-target"),
+        }
+
+        let version = schema::VersionInfo {
+            qemu: schema::VersionNumber {
+                micro: 2,
+                minor: 1,
+                major: 5,
+            },
+            package: "".to_string(),
+        };
+        let resp = Response::create_response(
+            serde_json::to_value(&version).unwrap(),
+            Some(Value::from(3)),
+        );
+        let json_msg = r#"{"return":{"qemu":{"micro":2,"minor":1,"major":5},"package":""},"id":3}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        let current = schema::VersionInfo::current();
+        let version_str = format!(
+            "{}.{}.{}",
+            current.qemu.major, current.qemu.minor, current.qemu.micro
+        );
+        assert!(env!("CARGO_PKG_VERSION").starts_with(&version_str));
+
+        let target = schema::TargetInfo {
+            arch: "aarch64".to_string(),
+        };
+        let resp = Response::create_response(serde_json::to_value(&target).unwrap(), None);
+        assert_eq!(
+            serde_json::to_string(&resp).unwrap(),
+            r#"{"return":{"arch":"aarch64"}}"#
+        );
+        assert_eq!(schema::TargetInfo::current().arch, std::env::consts::ARCH);
+    }
+
+    #[test]
+    fn test_qmp_query_commands() {
+        let resp = Response::create_response(
+            serde_json::to_value(&schema::CommandInfo::all()).unwrap(),
+            None,
+        );
+        let json_msg = serde_json::to_string(&resp).unwrap();
+        assert!(json_msg.starts_with(r#"{"return":[{"name":"qmp_capabilities"},{"name":"quit"}"#));
+
+        let resp: Response = serde_json::from_str(&json_msg).unwrap();
+        let commands: Vec<schema::CommandInfo> =
+            serde_json::from_value(resp.return_.unwrap()).unwrap();
+        let names: Vec<&str> = commands.iter().map(|c| c.name.as_str()).collect();
+        for name in &[
+            "device_add",
+            "blockdev-add",
+            "query-status",
+            "query-memory-summary",
+            "query-commands",
+        ] {
+            assert!(names.contains(name), "{} is missing", name);
+        }
+
+        // Every listed name is accepted by the parser of `QmpCommand`.
+        for name in names {
+            let cmd = format!(r#"{{"execute":"{}","arguments":{{}}}}"#, name);
+            match serde_json::from_str::<QmpCommand>(&cmd) {
+                Ok(_) => {}
+                Err(e) => assert!(!e.to_string().contains("unknown variant"), "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_qmp_system_reset_powerdown() {
+        let cmd: QmpCommand = serde_json::from_str(r#"{"execute":"system_reset"}"#).unwrap();
+        match cmd {
+            QmpCommand::system_reset { id, .. } => assert_eq!(id, None),
+            _ => panic!("expected system_reset"),
+        }
+        let cmd: QmpCommand =
+            serde_json::from_str(r#"{"execute":"system_powerdown","id":2}"#).unwrap();
+        match cmd {
+            QmpCommand::system_powerdown { id, .. } => assert_eq!(id, Some(Value::from(2))),
+            _ => panic!("expected system_powerdown"),
+        }
+
+        let event_json = r#"{"event":"POWERDOWN","data":{},"timestamp":{"seconds":1575531524,"microseconds":91519}}"#;
+        match serde_json::from_str::<schema::QmpEvent>(&event_json).unwrap() {
+            schema::QmpEvent::POWERDOWN { .. } => {}
+            _ => panic!("expected POWERDOWN event"),
+        }
+        let reset_event = schema::QmpEvent::RESET {
+            data: schema::RESET { guest: false },
+            timestamp: TimeStamp {
+                seconds: 1,
+                microseconds: 2,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&reset_event).unwrap(),
+            r#"{"event":"RESET","data":{"guest":false},"timestamp":{"seconds":1,"microseconds":2}}"#
+        );
+    }
+
+    use crate::block_backend::{BlockBackendInfo, BlockBackendRegistry};
+    use crate::config::NetworkInterfaceConfig;
+    use crate::machine::{BalloonHandle, DeviceInterface, KvmVmState, MachineLifecycle};
+
+    #[derive(Default)]
+    struct TestMachine {
+        resets: std::sync::Mutex<u32>,
+        has_power_button: bool,
+        ram_size: u64,
+        balloon: Option<Arc<TestBalloon>>,
+        block_backends: Arc<BlockBackendRegistry>,
+        link_up: std::sync::Mutex<bool>,
+        netdev_vhost: std::sync::Mutex<Option<(String, Option<bool>, Option<String>)>>,
+        netdev_queues: std::sync::Mutex<u16>,
+        added_devices: std::sync::Mutex<Vec<String>>,
+        blocker: std::sync::Mutex<()>,
+    }
+
+    #[derive(Default)]
+    struct TestBalloon {
+        target: std::sync::Mutex<u64>,
+    }
+
+    impl BalloonHandle for TestBalloon {
+        fn set_target(&self, size: u64) {
+            *self.target.lock().unwrap() = size;
+        }
+
+        fn actual(&self) -> u64 {
+            *self.target.lock().unwrap()
+        }
+    }
+
+    impl MachineLifecycle for TestMachine {
+        fn reset(&self) -> Result<()> {
+            let _blocker = self.blocker.lock().unwrap();
+            *self.resets.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn powerdown(&self) -> Result<()> {
+            if self.has_power_button {
+                Ok(())
+            } else {
+                Err("No power button device to notify guest".into())
+            }
+        }
+
+        fn notify_lifecycle(&self, _old: KvmVmState, _new: KvmVmState) -> bool {
+            true
+        }
+    }
+
+    impl DeviceInterface for TestMachine {
+        fn query_status(&self) -> Response {
+            Response::create_empty_response()
+        }
+        fn query_cpus(&self) -> Response {
+            Response::create_empty_response()
+        }
+        fn query_hotpluggable_cpus(&self) -> Response {
+            Response::create_empty_response()
+        }
+        fn device_add(&self, id: String, _: DeviceAddArgs) -> Result<()> {
+            self.added_devices.lock().unwrap().push(id);
+            Ok(())
+        }
+        fn device_del(&self, _: String) -> bool {
+            true
+        }
+        fn blockdev_add(
+            &self,
+            _: String,
+            _: schema::FileOptions,
+            _: Option<schema::CacheOptions>,
+            _: Option<bool>,
+            _: Option<crate::config::ImageFormat>,
+            _: Option<crate::config::DiscardMode>,
+            _: Option<crate::config::DetectZeroes>,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn netdev_add(
+            &self,
+            id: String,
+            _: Option<String>,
+            _: Option<String>,
+            vhost: Option<bool>,
+            vhostfds: Option<String>,
+            queues: Option<u16>,
+        ) -> Result<()> {
+            let config = NetworkInterfaceConfig {
+                queues: queues.unwrap_or(1),
+                ..Default::default()
+            };
+            config.check()?;
+            *self.netdev_vhost.lock().unwrap() = Some((id, vhost, vhostfds));
+            *self.netdev_queues.lock().unwrap() = config.queues;
+            Ok(())
+        }
+        fn getfd(&self, _: String, _: Option<RawFd>) -> Response {
+            Response::create_empty_response()
+        }
+        fn dump_guest_memory(&self, _: String) -> Response {
+            Response::create_empty_response()
+        }
+        fn query_memory_summary(&self) -> Response {
+            Response::create_empty_response()
+        }
+        fn block_resize(&self, device: String, size: u64) -> Response {
+            let msg = format!("{} resized to {}", device, size);
+            Response::create_error_response(schema::QmpErrorClass::GenericError(msg), None).unwrap()
+        }
+        fn query_netdev(&self) -> Response {
+            let netdevs = vec![schema::NetdevInfo {
+                id: "net-0".to_string(),
+                ifname: Some("tap0".to_string()),
+                fds: None,
+                queues: 1,
+            }];
+            Response::create_response(serde_json::to_value(&netdevs).unwrap(), None)
+        }
+        fn set_link(&self, name: String, up: bool) -> Response {
+            if name != "net-0" {
+                return Response::create_error_response(
+                    schema::QmpErrorClass::DeviceNotFound(format!("Net device {} not found", name)),
+                    None,
+                )
+                .unwrap();
+            }
+            *self.link_up.lock().unwrap() = up;
+            Response::create_empty_response()
+        }
+        fn ram_size(&self) -> u64 {
+            self.ram_size
+        }
+        fn block_backends(&self) -> Arc<BlockBackendRegistry> {
+            self.block_backends.clone()
+        }
+        fn balloon_handle(&self) -> Option<Arc<dyn BalloonHandle>> {
+            self.balloon
+                .clone()
+                .map(|balloon| balloon as Arc<dyn BalloonHandle>)
+        }
+    }
+
+    impl MachineExternalInterface for TestMachine {}
+
+    #[test]
+    fn test_qmp_reset_powerdown_dispatch() {
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let (resp, shutdown) = qmp_command_exec(
+            QmpCommand::system_reset {
+                arguments: Default::default(),
+                id: Some(Value::from(5)),
+            },
+            &controller,
+            None,
+        );
+        assert_eq!(resp, r#"{"return":{},"id":5}"#);
+        assert!(!shutdown);
+        assert_eq!(*machine.resets.lock().unwrap(), 1);
+
+        let (resp, _) = qmp_command_exec(
+            QmpCommand::system_powerdown {
+                arguments: Default::default(),
+                id: None,
+            },
+            &controller,
+            None,
+        );
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"No power button device to notify guest"}}"#
+        );
+
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = Arc::new(TestMachine {
+            has_power_button: true,
+            ..Default::default()
+        });
+        let (resp, _) = qmp_command_exec(
+            QmpCommand::system_powerdown {
+                arguments: Default::default(),
+                id: None,
+            },
+            &controller,
+            None,
+        );
+        assert_eq!(resp, r#"{"return":{}}"#);
+    }
+
+    #[test]
+    fn test_qmp_arbitrary_id() {
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+
+        // 1.String, object and numeric ids are echoed back verbatim.
+        for (id, expect) in &[
+            (r#""libvirt-42""#, r#"{"return":{},"id":"libvirt-42"}"#),
+            (
+                r#"{"a":1,"b":[true,null]}"#,
+                r#"{"return":{},"id":{"a":1,"b":[true,null]}}"#,
+            ),
+            ("7", r#"{"return":{},"id":7}"#),
+        ] {
+            let cmd: QmpCommand =
+                serde_json::from_str(&format!(r#"{{"execute":"system_reset","id":{}}}"#, id))
+                    .unwrap();
+            let (resp, _) = qmp_command_exec(cmd, &controller, None);
+            assert_eq!(&resp, expect);
+        }
+        assert_eq!(*machine.resets.lock().unwrap(), 3);
+
+        // 2.Error response carries the id too.
+        let cmd: QmpCommand =
+            serde_json::from_str(r#"{"execute":"system_powerdown","id":["x",1]}"#).unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"No power button device to notify guest"},"id":["x",1]}"#
+        );
+
+        // 3.Malformed command, id is still picked up when the request is a JSON object.
+        let line = r#"{"execute":"no-such-command","id":"x"}"#;
+        assert!(serde_json::from_str::<QmpCommand>(line).is_err());
+        let id = parse_request_id(line);
+        assert_eq!(id, Some(Value::from("x")));
+        let qmp_err = schema::QmpErrorClass::GenericError("unknown command".to_string());
+        let resp = Response::create_error_response(qmp_err, id).unwrap();
+        assert_eq!(
+            serde_json::to_string(&resp).unwrap(),
+            r#"{"error":{"class":"GenericError","desc":"unknown command"},"id":"x"}"#
+        );
+        let line = r#"{"execute":"system_reset","arguments":1,"id":{"seq":2}}"#;
+        assert!(serde_json::from_str::<QmpCommand>(line).is_err());
+        assert_eq!(parse_request_id(line), Some(serde_json::json!({"seq": 2})));
+        assert_eq!(parse_request_id(r#"{"execute":"stop","id":null}"#), None);
+        assert_eq!(parse_request_id(r#"{"execute":"stop","id":"#), None);
+        assert_eq!(parse_request_id(r#"["execute","stop"]"#), None);
+
+        // 4.Events never carry an id.
+        let event = schema::QmpEvent::RESET {
+            data: schema::RESET { guest: false },
+            timestamp: TimeStamp {
+                seconds: 1,
+                microseconds: 2,
+            },
+        };
+        let event_value = serde_json::to_value(&event).unwrap();
+        assert!(event_value.get("id").is_none());
+    }
+
+    #[test]
+    fn test_qmp_unknown_fields() {
+        // 1.Misspelled arguments are rejected, naming the field and command.
+        let line = r#"{"execute":"blockdev-add","arguments":{"node_name":"drive-0","file":{"driver":"file","filename":"/path/to/block"}},"id":"add-0"}"#;
+        let err = serde_json::from_str::<QmpCommand>(line).unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `node_name`"),
+            "{}",
+            err
+        );
+        let resp = create_parse_error_response(line, &err.to_string()).unwrap();
+        let resp = serde_json::to_value(&resp).unwrap();
+        let desc = resp["error"]["desc"].as_str().unwrap();
+        assert!(
+            desc.starts_with(
+                "Invalid arguments for command blockdev-add: unknown field `node_name`"
+            ),
+            "{}",
+            desc
+        );
+        assert_eq!(resp["id"], Value::from("add-0"));
+
+        for (line, field) in &[
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","file_name":"/path/to/block"}}}"#,
+                "file_name",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block"},"cache":{"no_flush":true}}}"#,
+                "no_flush",
+            ),
+            (
+                r#"{"execute":"device_add","arguments":{"id":"rng-0","driver":"virtio-rng","max_bytes":1024}}"#,
+                "max_bytes",
+            ),
+            (r#"{"execute":"stop","arguments":{"now":true}}"#, "now"),
+        ] {
+            let err = serde_json::from_str::<QmpCommand>(line).unwrap_err();
+            let desc =
+                serde_json::to_value(&create_parse_error_response(line, &err.to_string()).unwrap())
+                    .unwrap()["error"]["desc"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+            assert!(
+                desc.contains(&format!("unknown field `{}`", field)),
+                "{}",
+                desc
+            );
+        }
+
+        // 2.Unknown command isn't reported as invalid arguments.
+        let line = r#"{"execute":"blockdev_add","arguments":{}}"#;
+        let err = serde_json::from_str::<QmpCommand>(line).unwrap_err();
+        let resp =
+            serde_json::to_value(&create_parse_error_response(line, &err.to_string()).unwrap())
+                .unwrap();
+        assert!(!resp["error"]["desc"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid arguments"));
+
+        // 3.Valid commands still parse.
+        for line in &[
+            r#"{"execute":"qmp_capabilities"}"#,
+            r#"{"execute":"qmp_capabilities","arguments":{"enable":["oob"]}}"#,
+            r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block","aio":"native"},"cache":{"direct":true,"no-flush":false},"read-only":false,"format":"qcow2","discard":"unmap","detect-zeroes":"unmap"}}"#,
+            r#"{"execute":"device_add","arguments":{"id":"rng-0","driver":"virtio-rng","filename":"/dev/urandom","max-bytes":1024,"period":1000}}"#,
+            r#"{"execute":"netdev_add","arguments":{"id":"net-0","fds":"fd-tap","vhost":true,"vhostfds":"fd-vhost","queues":1}}"#,
+            r#"{"execute":"block_set_io_throttle","arguments":{"device":"drive-0","bps":0,"bps_rd":0,"bps_wr":0,"iops":1000,"iops_rd":0,"iops_wr":0}}"#,
+            r#"{"execute":"set_link","arguments":{"name":"net-0","up":false},"id":1}"#,
+        ] {
+            if let Err(e) = serde_json::from_str::<QmpCommand>(line) {
+                panic!("Failed to parse {}: {}", line, e);
+            }
+        }
+
+        // 4.Arguments declared as `Ignored` are accepted whatever they are.
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Arguments {
+            #[serde(rename = "node-name")]
+            node_name: String,
+            #[serde(rename = "bus", default)]
+            _bus: schema::Ignored,
+        }
+        for line in &[
+            r#"{"node-name":"drive-0"}"#,
+            r#"{"node-name":"drive-0","bus":"pci.0"}"#,
+            r#"{"node-name":"drive-0","bus":{"addr":[1,2]}}"#,
+        ] {
+            let args: Arguments = serde_json::from_str(line).unwrap();
+            assert_eq!(args.node_name, "drive-0");
+        }
+        assert!(serde_json::from_str::<Arguments>(r#"{"node-name":"drive-0","bu":1}"#).is_err());
+    }
+
+    // Read next response of client, events sent by other tests are skipped.
+    fn read_response(reader: &mut std::io::BufReader<UnixStream>) -> Value {
+        use std::io::BufRead;
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let response: Value = serde_json::from_str(&line).unwrap();
+            if response.get("event").is_none() {
+                return response;
+            }
+        }
+    }
+
+    // Send request of client, and handle it as monitor thread.
+    fn send_request(
+        client: &mut UnixStream,
+        server: &UnixStream,
+        controller: &Arc<dyn MachineExternalInterface + Send + Sync>,
+        request: &str,
+    ) {
+        use std::io::Write;
+
+        client
+            .write_all(format!("{}\n", request).as_bytes())
+            .unwrap();
+        handle_qmp(server.as_raw_fd(), controller).unwrap();
+    }
+
+    // In-band commands are queued in global `QMP_CHANNEL`, the cases are run in
+    // one test so that they don't execute commands of each other.
+    #[test]
+    fn test_qmp_in_band_and_oob() {
+        check_qmp_oob();
+        check_qmp_multiple_clients();
+    }
+
+    fn check_qmp_oob() {
+        QmpChannel::object_init();
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut reader = std::io::BufReader::new(client.try_clone().unwrap());
+        QmpChannel::bind_writer(SocketRWHandler::new(server.as_raw_fd()));
+
+        // 1.exec-oob is refused before the capability is enabled.
+        assert!(!QmpChannel::is_oob_enabled(server.as_raw_fd()));
+        send_request(
+            &mut client,
+            &server,
+            &controller,
+            r#"{"exec-oob":"query-status","id":"a"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader),
+            serde_json::json!({"error":{"class":"GenericError","desc":"Out-of-band execution isn't enabled by qmp_capabilities"},"id":"a"})
+        );
+
+        // 2.Capability negotiation.
+        send_request(
+            &mut client,
+            &server,
+            &controller,
+            r#"{"execute":"qmp_capabilities","arguments":{"enable":["oob"]},"id":"b"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader),
+            serde_json::json!({"return":{},"id":"b"})
+        );
+        assert!(QmpChannel::is_oob_enabled(server.as_raw_fd()));
+
+        // 3.Only commands in allowlist can be executed out-of-band.
+        send_request(
+            &mut client,
+            &server,
+            &controller,
+            r#"{"exec-oob":"stop","id":"c"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader),
+            serde_json::json!({"error":{"class":"GenericError","desc":"Command stop can't be executed out-of-band"},"id":"c"})
+        );
+        send_request(
+            &mut client,
+            &server,
+            &controller,
+            r#"{"exec-oob":"stop","execute":"stop","id":"d"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader),
+            serde_json::json!({"error":{"class":"GenericError","desc":"execute and exec-oob can't be used together"},"id":"d"})
+        );
+
+        // 4.Out-of-band command is answered while in-band command is blocked.
+        send_request(
+            &mut client,
+            &server,
+            &controller,
+            r#"{"execute":"system_reset","id":"e"}"#,
+        );
+        let blocker = machine.blocker.lock().unwrap();
+        let in_band_machine = machine.clone();
+        let in_band = std::thread::spawn(move || {
+            let controller: Arc<dyn MachineExternalInterface + Send + Sync> = in_band_machine;
+            handle_in_band(&controller);
+        });
+        send_request(
+            &mut client,
+            &server,
+            &controller,
+            r#"{"exec-oob":"query-status","id":"f"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader),
+            serde_json::json!({"return":{},"id":"f"})
+        );
+        assert_eq!(*machine.resets.lock().unwrap(), 0);
+
+        let cmd: QmpCommand = serde_json::from_str(r#"{"execute":"quit","id":"g"}"#).unwrap();
+        assert_eq!(
+            qmp_oob_exec(cmd, &controller),
+            (r#"{"return":{},"id":"g"}"#.to_string(), true)
+        );
+
+        drop(blocker);
+        in_band.join().unwrap();
+        assert_eq!(
+            read_response(&mut reader),
+            serde_json::json!({"return":{},"id":"e"})
+        );
+        assert_eq!(*machine.resets.lock().unwrap(), 1);
+
+        QmpChannel::unbind(server.as_raw_fd());
+    }
+
+    fn check_qmp_multiple_clients() {
+        QmpChannel::object_init();
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let (mut client_a, server_a) = UnixStream::pair().unwrap();
+        let (mut client_b, server_b) = UnixStream::pair().unwrap();
+        let mut reader_a = std::io::BufReader::new(client_a.try_clone().unwrap());
+        let mut reader_b = std::io::BufReader::new(client_b.try_clone().unwrap());
+        QmpChannel::bind_writer(SocketRWHandler::new(server_a.as_raw_fd()));
+        QmpChannel::bind_writer(SocketRWHandler::new(server_b.as_raw_fd()));
+
+        // 1.Capabilities are negotiated by each client independently.
+        send_request(
+            &mut client_a,
+            &server_a,
+            &controller,
+            r#"{"execute":"qmp_capabilities","arguments":{"enable":["oob"]},"id":"a1"}"#,
+        );
+        send_request(
+            &mut client_b,
+            &server_b,
+            &controller,
+            r#"{"execute":"qmp_capabilities","id":"b1"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader_a),
+            serde_json::json!({"return":{},"id":"a1"})
+        );
+        assert_eq!(
+            read_response(&mut reader_b),
+            serde_json::json!({"return":{},"id":"b1"})
+        );
+        assert!(QmpChannel::is_oob_enabled(server_a.as_raw_fd()));
+        assert!(!QmpChannel::is_oob_enabled(server_b.as_raw_fd()));
+
+        send_request(
+            &mut client_b,
+            &server_b,
+            &controller,
+            r#"{"exec-oob":"query-status","id":"b2"}"#,
+        );
+        send_request(
+            &mut client_a,
+            &server_a,
+            &controller,
+            r#"{"exec-oob":"query-status","id":"a2"}"#,
+        );
+        assert_eq!(
+            read_response(&mut reader_a),
+            serde_json::json!({"return":{},"id":"a2"})
+        );
+        assert_eq!(
+            read_response(&mut reader_b),
+            serde_json::json!({"error":{"class":"GenericError","desc":"Out-of-band execution isn't enabled by qmp_capabilities"},"id":"b2"})
+        );
+
+        // 2.In-band commands are executed in order, and responses are only
+        // sent to the client issuing the command.
+        send_request(
+            &mut client_b,
+            &server_b,
+            &controller,
+            r#"{"execute":"system_reset","id":"b3"}"#,
+        );
+        send_request(
+            &mut client_a,
+            &server_a,
+            &controller,
+            r#"{"execute":"system_powerdown","id":"a3"}"#,
+        );
+        handle_in_band(&controller);
+        assert_eq!(*machine.resets.lock().unwrap(), 1);
+        assert_eq!(
+            read_response(&mut reader_a),
+            serde_json::json!({"error":{"class":"GenericError","desc":"No power button device to notify guest"},"id":"a3"})
+        );
+        assert_eq!(
+            read_response(&mut reader_b),
+            serde_json::json!({"return":{},"id":"b3"})
+        );
+
+        // 3.Disconnection of a client drops its pending commands, and doesn't
+        // affect others.
+        send_request(
+            &mut client_a,
+            &server_a,
+            &controller,
+            r#"{"execute":"system_reset","id":"a4"}"#,
+        );
+        send_request(
+            &mut client_b,
+            &server_b,
+            &controller,
+            r#"{"execute":"system_reset","id":"b4"}"#,
+        );
+        QmpChannel::unbind(server_a.as_raw_fd());
+        drop(server_a);
+        handle_in_band(&controller);
+        assert_eq!(*machine.resets.lock().unwrap(), 2);
+        assert_eq!(
+            read_response(&mut reader_b),
+            serde_json::json!({"return":{},"id":"b4"})
+        );
+        let mut rest = String::new();
+        std::io::Read::read_to_string(&mut reader_a, &mut rest).unwrap();
+        assert!(rest.lines().all(|line| serde_json::from_str::<Value>(line)
+            .unwrap()
+            .get("event")
+            .is_some()));
+
+        QmpChannel::unbind(server_b.as_raw_fd());
+    }
+
+    #[test]
+    fn test_qmp_balloon() {
+        let balloon = |value| QmpCommand::balloon {
+            arguments: schema::balloon { value },
+            id: None,
+        };
+        let query_balloon = QmpCommand::query_balloon {
+            arguments: Default::default(),
+            id: None,
+        };
+
+        // Without balloon device.
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = Arc::new(TestMachine {
+            ram_size: 1 << 30,
+            ..Default::default()
+        });
+        let not_active = r#"{"error":{"class":"DeviceNotActive","desc":"No balloon device has been activated"}}"#;
+        let (resp, _) = qmp_command_exec(balloon(1 << 29), &controller, None);
+        assert_eq!(resp, not_active);
+        let (resp, _) = qmp_command_exec(query_balloon.clone(), &controller, None);
+        assert_eq!(resp, not_active);
+
+        // With balloon device.
+        let device = Arc::new(TestBalloon::default());
+        *device.target.lock().unwrap() = 1 << 30;
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = Arc::new(TestMachine {
+            ram_size: 1 << 30,
+            balloon: Some(device.clone()),
+            ..Default::default()
+        });
+        let (resp, _) = qmp_command_exec(balloon(0), &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"Invalid balloon target size 0, should be in range (0, 1073741824]"}}"#
+        );
+        let (resp, _) = qmp_command_exec(balloon((1 << 30) + 1), &controller, None);
+        assert!(resp.contains("GenericError"));
+        assert_eq!(*device.target.lock().unwrap(), 1 << 30);
+
+        let (resp, _) = qmp_command_exec(balloon(1 << 29), &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        assert_eq!(*device.target.lock().unwrap(), 1 << 29);
+        let (resp, _) = qmp_command_exec(query_balloon, &controller, None);
+        assert_eq!(resp, r#"{"return":{"actual":536870912}}"#);
+
+        // Deserialize command and event.
+        let cmd: QmpCommand =
+            serde_json::from_str(r#"{"execute":"balloon","arguments":{"value":1024}}"#).unwrap();
+        match cmd {
+            QmpCommand::balloon { arguments, .. } => assert_eq!(arguments.value, 1024),
+            _ => panic!("Unexpected command"),
+        }
+        let event = r#"{"event":"BALLOON_CHANGE","data":{"actual":1024},"timestamp":{"seconds":1575531524,"microseconds":91519}}"#;
+        let msg: schema::QmpEvent = serde_json::from_str(event).unwrap();
+        assert_eq!(serde_json::to_string(&msg).unwrap(), event);
+    }
+
+    #[test]
+    fn test_qmp_query_block() {
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let query_block = QmpCommand::query_block {
+            arguments: Default::default(),
+            id: None,
+        };
+        let query_blockstats = QmpCommand::query_blockstats {
+            arguments: Default::default(),
+            id: None,
+        };
+        let (resp, _) = qmp_command_exec(query_block.clone(), &controller, None);
+        assert_eq!(resp, r#"{"return":[]}"#);
+
+        let stats = machine
+            .block_backends
+            .register(
+                "drive-0",
+                BlockBackendInfo {
+                    node_name: "drive-0".to_string(),
+                    file: "/path/to/block".to_string(),
+                    read_only: true,
+                    driver: "raw".to_string(),
+                    aio: "threads".to_string(),
+                },
+            )
+            .stats;
+        stats.account_read(4096);
+        stats.account_write(512);
+        stats.account_flush();
+
+        let (resp, _) = qmp_command_exec(query_block, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"return":[{"device":"drive-0","inserted":{"node-name":"drive-0","file":"/path/to/block","ro":true,"drv":"raw","aio":"threads","bps":0,"bps_rd":0,"bps_wr":0,"iops":0,"iops_rd":0,"iops_wr":0}}]}"#
+        );
+        let (resp, _) = qmp_command_exec(query_blockstats, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"return":[{"device":"drive-0","stats":{"rd_bytes":4096,"wr_bytes":512,"rd_operations":1,"wr_operations":1,"flush_operations":1}}]}"#
+        );
+    }
+
+    #[test]
+    fn test_qmp_block_resize() {
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"block_resize","arguments":{"node-name":"drive-0","size":1048576}}"#,
+        )
+        .unwrap();
+        match &cmd {
+            QmpCommand::block_resize { arguments, .. } => {
+                assert_eq!(arguments.device, None);
+                assert_eq!(arguments.node_name, Some("drive-0".to_string()));
+                assert_eq!(arguments.size, 1048576);
+            }
+            _ => panic!("Unexpected command"),
+        }
+        let err: std::result::Result<QmpCommand, _> =
+            serde_json::from_str(r#"{"execute":"block_resize","arguments":{"device":"vda"}}"#);
+        assert!(err.is_err());
+
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let not_found =
+            r#"{"error":{"class":"DeviceNotFound","desc":"Block device or node not found"}}"#;
+        let (resp, _) = qmp_command_exec(cmd.clone(), &controller, None);
+        assert_eq!(resp, not_found);
+
+        machine.block_backends.register(
+            "vda",
+            BlockBackendInfo {
+                node_name: "drive-0".to_string(),
+                ..Default::default()
+            },
+        );
+        // Resize is forwarded to the device using the backend.
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert!(resp.contains("vda resized to 1048576"));
+
+        let resize = |device: Option<&str>, node_name: Option<&str>| QmpCommand::block_resize {
+            arguments: schema::block_resize {
+                device: device.map(String::from),
+                node_name: node_name.map(String::from),
+                size: 1048576,
+            },
+            id: None,
+        };
+        let (resp, _) = qmp_command_exec(resize(Some("vda"), None), &controller, None);
+        assert!(resp.contains("vda resized to 1048576"));
+        let (resp, _) = qmp_command_exec(resize(Some("drive-0"), None), &controller, None);
+        assert_eq!(resp, not_found);
+        let (resp, _) = qmp_command_exec(resize(None, Some("vda")), &controller, None);
+        assert_eq!(resp, not_found);
+        let (resp, _) = qmp_command_exec(resize(None, None), &controller, None);
+        assert!(resp.contains("Either device or node-name should be given"));
+        let (resp, _) = qmp_command_exec(resize(Some("vda"), Some("drive-0")), &controller, None);
+        assert!(resp.contains("GenericError"));
+    }
+
+    #[test]
+    fn test_qmp_block_set_io_throttle() {
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"block_set_io_throttle","arguments":{"device":"drive-0","bps":0,"bps_rd":1048576,"bps_wr":0,"iops":0,"iops_rd":0,"iops_wr":100}}"#,
+        )
+        .unwrap();
+        match &cmd {
+            QmpCommand::block_set_io_throttle { arguments, .. } => {
+                assert_eq!(arguments.device, "drive-0");
+                assert_eq!(arguments.bps_rd, 1048576);
+                assert_eq!(arguments.iops_wr, 100);
+            }
+            _ => panic!("Unexpected command"),
+        }
+        let err: std::result::Result<QmpCommand, _> = serde_json::from_str(
+            r#"{"execute":"block_set_io_throttle","arguments":{"device":"drive-0","bps":0}}"#,
+        );
+        assert!(err.is_err());
+
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let (resp, _) = qmp_command_exec(cmd.clone(), &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"DeviceNotFound","desc":"Block device not found"}}"#
+        );
+
+        machine.block_backends.register(
+            "drive-0",
+            BlockBackendInfo {
+                node_name: "drive-0".to_string(),
+                ..Default::default()
+            },
+        );
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        let block = machine.block_backends.query_block();
+        assert_eq!(block[0].inserted.bps_rd, 1048576);
+        assert_eq!(block[0].inserted.iops_wr, 100);
+
+        let throttle = |bps: u64, bps_rd: u64| QmpCommand::block_set_io_throttle {
+            arguments: schema::block_set_io_throttle {
+                device: "drive-0".to_string(),
+                bps,
+                bps_rd,
+                ..Default::default()
+            },
+            id: None,
+        };
+        let (resp, _) = qmp_command_exec(throttle(4096, 1024), &controller, None);
+        assert!(resp.contains("bps can't be set together with bps_rd or bps_wr"));
+        assert_eq!(
+            machine.block_backends.query_block()[0].inserted.bps_rd,
+            1048576
+        );
+
+        // Zero limits remove throttling.
+        let (resp, _) = qmp_command_exec(throttle(0, 0), &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        let block = machine.block_backends.query_block();
+        assert_eq!(block[0].inserted.bps_rd, 0);
+        assert_eq!(block[0].inserted.iops_wr, 0);
+    }
+
+    #[test]
+    fn test_qmp_device_add_args() {
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"device_add","arguments":{"id":"rng-0","driver":"virtio-rng","filename":"/dev/urandom","max-bytes":1024,"period":1000}}"#,
+        )
+        .unwrap();
+        match &cmd {
+            QmpCommand::device_add { arguments, .. } => {
+                assert_eq!(arguments.filename, Some("/dev/urandom".to_string()));
+                assert_eq!(arguments.max_bytes, Some(1024));
+                assert_eq!(arguments.period, Some(1000));
+                assert_eq!(arguments.guest_cid, None);
+            }
+            _ => panic!("Unexpected command"),
+        }
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+
+        // Rate limit of virtio-rng needs both max-bytes and period.
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"device_add","arguments":{"id":"rng-1","driver":"virtio-rng","max-bytes":1024}}"#,
+        )
+        .unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"Rng max-bytes and period should be given together and be more than 0."}}"#
+        );
+
+        // Guest-cid is mandatory for vhost-vsock.
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"device_add","arguments":{"id":"vsock-0","driver":"vhost-vsock"}}"#,
+        )
+        .unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"Device vhost-vsock requires guest-cid"}}"#
+        );
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"device_add","arguments":{"id":"vsock-0","driver":"vhost-vsock","guest-cid":2}}"#,
+        )
+        .unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"Vsock guest-cid should be more than 3 and less than 4294967296."}}"#
+        );
+
+        let ret: std::result::Result<QmpCommand, _> = serde_json::from_str(
+            r#"{"execute":"device_add","arguments":{"id":"vsock-0","driver":"vhost-vsock","guest-cid":"3"}}"#,
+        );
+        assert!(ret.is_err());
+        assert_eq!(*machine.added_devices.lock().unwrap(), vec!["rng-0"]);
+    }
+
+    #[test]
+    fn test_qmp_netdev_link() {
+        let cmd: QmpCommand = serde_json::from_str(r#"{"execute":"query-netdev"}"#).unwrap();
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"return":[{"id":"net-0","ifname":"tap0","queues":1}]}"#
+        );
+
+        let info: schema::NetdevInfo =
+            serde_json::from_str(r#"{"id":"net-1","fds":"12,13","queues":2}"#).unwrap();
+        assert_eq!(info.ifname, None);
+        assert_eq!(info.fds, Some("12,13".to_string()));
+        assert_eq!(info.queues, 2);
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"set_link","arguments":{"name":"net-0","up":false}}"#,
+        )
+        .unwrap();
+        match &cmd {
+            QmpCommand::set_link { arguments, .. } => {
+                assert_eq!(arguments.name, "net-0");
+                assert!(!arguments.up);
+            }
+            _ => panic!("Unexpected command"),
+        }
+        *machine.link_up.lock().unwrap() = true;
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        assert!(!*machine.link_up.lock().unwrap());
+
+        let err: std::result::Result<QmpCommand, _> =
+            serde_json::from_str(r#"{"execute":"set_link","arguments":{"name":"net-0"}}"#);
+        assert!(err.is_err());
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"set_link","arguments":{"name":"net-1","up":true}}"#,
+        )
+        .unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"DeviceNotFound","desc":"Net device net-1 not found"}}"#
+        );
+    }
+
+    #[test]
+    fn test_qmp_netdev_add_vhost() {
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"netdev_add","arguments":{"id":"net-0","ifname":"tap0"}}"#,
+        )
+        .unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        assert_eq!(
+            *machine.netdev_vhost.lock().unwrap(),
+            Some(("net-0".to_string(), None, None))
+        );
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"netdev_add","arguments":{"id":"net-1","fds":"fd-tap","vhost":true,"vhostfds":"fd-vhost"}}"#,
+        )
+        .unwrap();
+        match &cmd {
+            QmpCommand::netdev_add { arguments, .. } => {
+                assert_eq!(arguments.fds, Some("fd-tap".to_string()));
+                assert_eq!(arguments.vhost, Some(true));
+                assert_eq!(arguments.vhostfds, Some("fd-vhost".to_string()));
+            }
+            _ => panic!("Unexpected command"),
+        }
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        assert_eq!(
+            *machine.netdev_vhost.lock().unwrap(),
+            Some((
+                "net-1".to_string(),
+                Some(true),
+                Some("fd-vhost".to_string())
+            ))
+        );
+
+        for args in &[r#""vhost":"on""#, r#""vhost":1"#, r#""vhostfds":12"#] {
+            let json_msg = format!(
+                r#"{{"execute":"netdev_add","arguments":{{"id":"net-2","ifname":"tap2",{}}}}}"#,
+                args
+            );
+            let ret: std::result::Result<QmpCommand, _> = serde_json::from_str(&json_msg);
+            assert!(ret.is_err());
+        }
+    }
+
+    #[test]
+    fn test_qmp_netdev_add_queues() {
+        let machine = Arc::new(TestMachine::default());
+        let controller: Arc<dyn MachineExternalInterface + Send + Sync> = machine.clone();
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"netdev_add","arguments":{"id":"net-0","fds":"fd-tap0,fd-tap1","queues":2}}"#,
+        )
+        .unwrap();
+        match &cmd {
+            QmpCommand::netdev_add { arguments, .. } => {
+                assert_eq!(arguments.fds, Some("fd-tap0,fd-tap1".to_string()));
+                assert_eq!(arguments.queues, Some(2));
+            }
+            _ => panic!("Unexpected command"),
+        }
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(resp, r#"{"return":{}}"#);
+        assert_eq!(*machine.netdev_queues.lock().unwrap(), 2);
+
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"netdev_add","arguments":{"id":"net-1","ifname":"tap1","queues":17}}"#,
+        )
+        .unwrap();
+        let (resp, _) = qmp_command_exec(cmd, &controller, None);
+        assert_eq!(
+            resp,
+            r#"{"error":{"class":"GenericError","desc":"Number of net queues 17 should be more than 0 and no more than 16."}}"#
+        );
+        assert_eq!(*machine.netdev_queues.lock().unwrap(), 2);
+
+        let ret: std::result::Result<QmpCommand, _> = serde_json::from_str(
+            r#"{"execute":"netdev_add","arguments":{"id":"net-2","ifname":"tap2","queues":"2"}}"#,
+        );
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_qmp_blockdev_add_aio() {
+        for aio in &["threads", "native", "io_uring"] {
+            let json_msg = format!(
+                r#"{{"execute":"blockdev-add","arguments":{{"node-name":"drive-0","file":{{"driver":"file","filename":"/path/to/block","aio":"{}"}},"cache":{{"no-flush":null,"direct":true}},"read-only":false,"format":null,"discard":null,"detect-zeroes":null}}}}"#,
+                aio
+            );
+            let cmd: QmpCommand = serde_json::from_str(&json_msg).unwrap();
+            match &cmd {
+                QmpCommand::blockdev_add { arguments, .. } => {
+                    assert_eq!(arguments.file.aio.unwrap().to_string(), *aio)
+                }
+                _ => panic!("Unexpected command"),
+            }
+            let msg = serde_json::to_string(&cmd).unwrap();
+            assert_eq!(msg, json_msg);
+        }
+
+        let file: schema::FileOptions =
+            serde_json::from_str(r#"{"driver":"file","filename":"/path/to/block"}"#).unwrap();
+        assert!(file.aio.is_none());
+
+        for aio in &["\"uring\"", "\"Native\"", "\"\"", "1"] {
+            let json_msg = format!(
+                r#"{{"execute":"blockdev-add","arguments":{{"node-name":"drive-0","file":{{"driver":"file","filename":"/path/to/block","aio":{}}}}}}}"#,
+                aio
+            );
+            let ret: std::result::Result<QmpCommand, _> = serde_json::from_str(&json_msg);
+            assert!(ret.is_err());
+        }
+    }
+
+    #[test]
+    fn test_qmp_blockdev_add_format() {
+        use crate::config::ImageFormat;
+
+        let blockdev_add = |format: &str| {
+            format!(
+                r#"{{"execute":"blockdev-add","arguments":{{"node-name":"drive-0","file":{{"driver":"file","filename":"/path/to/block.qcow2","aio":null}},"cache":null,"read-only":null,"format":{},"discard":null,"detect-zeroes":null}}}}"#,
+                format
+            )
+        };
+        let formats = [
+            ("null", None),
+            ("\"raw\"", Some(ImageFormat::Raw)),
+            ("\"qcow2\"", Some(ImageFormat::Qcow2)),
+        ];
+        for (format, image_format) in formats.iter() {
+            let json_msg = blockdev_add(format);
+            let cmd: QmpCommand = serde_json::from_str(&json_msg).unwrap();
+            match &cmd {
+                QmpCommand::blockdev_add { arguments, .. } => {
+                    assert_eq!(arguments.format, *image_format);
+                }
+                _ => panic!("Unexpected command"),
+            }
+            assert_eq!(serde_json::to_string(&cmd).unwrap(), json_msg);
+        }
+
+        for format in &["\"vmdk\"", "\"QCOW2\"", "2"] {
+            let ret: std::result::Result<QmpCommand, _> =
+                serde_json::from_str(&blockdev_add(format));
+            assert!(ret.is_err());
+        }
+    }
+
+    #[test]
+    fn test_qmp_blockdev_add_discard() {
+        use crate::config::{DetectZeroes, DiscardMode};
+
+        let blockdev_add = |discard: &str, detect_zeroes: &str| {
+            format!(
+                r#"{{"execute":"blockdev-add","arguments":{{"node-name":"drive-0","file":{{"driver":"file","filename":"/path/to/block","aio":null}},"cache":null,"read-only":null,"format":null,"discard":{},"detect-zeroes":{}}}}}"#,
+                discard, detect_zeroes
+            )
+        };
+        let modes = [
+            ("null", "null", None, None),
+            (
+                "\"ignore\"",
+                "\"off\"",
+                Some(DiscardMode::Ignore),
+                Some(DetectZeroes::Off),
+            ),
+            (
+                "\"unmap\"",
+                "\"on\"",
+                Some(DiscardMode::Unmap),
+                Some(DetectZeroes::On),
+            ),
+            (
+                "\"unmap\"",
+                "\"unmap\"",
+                Some(DiscardMode::Unmap),
+                Some(DetectZeroes::Unmap),
+            ),
+        ];
+        for (discard, detect_zeroes, discard_mode, detect_mode) in modes.iter() {
+            let json_msg = blockdev_add(discard, detect_zeroes);
+            let cmd: QmpCommand = serde_json::from_str(&json_msg).unwrap();
+            match &cmd {
+                QmpCommand::blockdev_add { arguments, .. } => {
+                    assert_eq!(arguments.discard, *discard_mode);
+                    assert_eq!(arguments.detect_zeroes, *detect_mode);
+                }
+                _ => panic!("Unexpected command"),
+            }
+            assert_eq!(serde_json::to_string(&cmd).unwrap(), json_msg);
+        }
+
+        // Defaults are used if not given.
+        let cmd: QmpCommand = serde_json::from_str(
+            r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block"}}}"#,
+        )
+        .unwrap();
+        match cmd {
+            QmpCommand::blockdev_add { arguments, .. } => {
+                assert!(arguments.discard.is_none());
+                assert!(arguments.detect_zeroes.is_none());
+            }
+            _ => panic!("Unexpected command"),
+        }
+
+        for (discard, detect_zeroes) in &[
+            ("\"on\"", "null"),
+            ("\"Unmap\"", "null"),
+            ("null", "\"ignore\""),
+            ("null", "true"),
+        ] {
+            let ret: std::result::Result<QmpCommand, _> =
+                serde_json::from_str(&blockdev_add(discard, detect_zeroes));
+            assert!(ret.is_err());
+        }
+    }
+
     #[test]
     fn test_qmp_event_msg() {
         let event_json =
@@ -617,10 +2501,11 @@ mod tests {
         let mut buffer = [0u8; 200];
         let (listener, mut client, server) = prepare_unix_socket_environment("06");
 
-        // Use event! macro to send event msg to client
+        // Use event! macro to send event msg to negotiated client
         let socket = Socket::from_unix_listener(listener, None);
-        socket.bind_unix_stream(server);
-        QmpChannel::bind_writer(SocketRWHandler::new(socket.get_stream_fd()));
+        let stream_fd = socket.bind_unix_stream(server);
+        QmpChannel::bind_writer(SocketRWHandler::new(stream_fd));
+        QmpChannel::negotiate(stream_fd, false);
 
         // 1.send no-content event
         event!(STOP);
@@ -654,7 +2539,26 @@ mod tests {
             _ => assert!(false),
         }
 
+        // 3.events are only sent to negotiated clients
+        let (mut other_client, other_server) = UnixStream::pair().unwrap();
+        QmpChannel::bind_writer(SocketRWHandler::new(other_server.as_raw_fd()));
+        event!(STOP);
+        let length = client.read(&mut buffer).unwrap();
+        let qmp_event: schema::QmpEvent =
+            serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
+        match qmp_event {
+            schema::QmpEvent::STOP { .. } => {}
+            _ => panic!("Event isn't STOP"),
+        }
+        other_client.set_nonblocking(true).unwrap();
+        assert_eq!(
+            other_client.read(&mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        QmpChannel::unbind(other_server.as_raw_fd());
+
         // After test. Environment Recover
+        QmpChannel::unbind(stream_fd);
         recover_unix_socket_environment("06");
     }
 
@@ -669,10 +2573,10 @@ mod tests {
 
         // Use event! macro to send event msg to client
         let socket = Socket::from_unix_listener(listener, None);
-        socket.bind_unix_stream(server);
+        let stream_fd = socket.bind_unix_stream(server);
 
         // 1.send greeting response
-        socket.send_response(true);
+        socket.send_response(stream_fd, true);
         let length = client.read(&mut buffer).unwrap();
         let qmp_response: QmpGreeting =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
@@ -680,7 +2584,7 @@ mod tests {
         assert_eq!(qmp_greeting, qmp_response);
 
         // 2.send empty response
-        socket.send_response(false);
+        socket.send_response(stream_fd, false);
         let length = client.read(&mut buffer).unwrap();
         let qmp_response: Response =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
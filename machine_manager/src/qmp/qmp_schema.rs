@@ -14,8 +14,11 @@ extern crate serde;
 extern crate serde_json;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 pub use serde_json::Value as Any;
 
+use crate::config::{AioEngine, DetectZeroes, DiscardMode, ImageFormat};
+use crate::qmp::introspect::SchemaRegistry;
 use crate::qmp::{Command, Empty, Event, TimeStamp};
 
 /// A error enum for qmp
@@ -45,105 +48,131 @@ impl QmpErrorClass {
     }
 }
 
-/// A enum to store all command struct
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "execute")]
-pub enum QmpCommand {
-    #[serde(rename = "qmp_capabilities")]
-    qmp_capabilities {
-        #[serde(default)]
-        arguments: qmp_capabilities,
-    },
-    quit {
-        #[serde(default)]
-        arguments: quit,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    stop {
-        #[serde(default)]
-        arguments: stop,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    cont {
-        #[serde(default)]
-        arguments: cont,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    device_add {
-        arguments: device_add,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    device_del {
-        arguments: device_del,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    netdev_add {
-        arguments: netdev_add,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    netdev_del {
-        arguments: netdev_del,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    #[serde(rename = "query-hotpluggable-cpus")]
-    query_hotpluggable_cpus {
-        #[serde(default)]
-        arguments: query_hotpluggable_cpus,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    #[serde(rename = "query-cpus")]
-    query_cpus {
-        #[serde(default)]
-        arguments: query_cpus,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    #[serde(rename = "query-status")]
-    query_status {
-        #[serde(default)]
-        arguments: query_status,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    getfd {
-        arguments: getfd,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    #[serde(rename = "blockdev-add")]
-    blockdev_add {
-        arguments: blockdev_add,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
-    #[serde(rename = "blockdev-del")]
-    blockdev_del {
-        arguments: blockdev_del,
-        #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
-    },
+/// Argument which is accepted for compatibility but intentionally ignored.
+///
+/// Argument structs of commands deny unknown fields, so that a misspelled
+/// argument is rejected rather than silently ignored. An argument sent by
+/// clients which we don't handle yet is declared with this type:
+///
+/// ```text
+/// #[serde(rename = "bus", default, skip_serializing)]
+/// pub bus: Ignored,
+/// ```
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Ignored;
+
+impl<'de> Deserialize<'de> for Ignored {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        serde::de::IgnoredAny::deserialize(deserializer).map(|_| Ignored)
+    }
 }
 
+/// Define `QmpCommand` with all commands and their names in QMP, together
+/// with `QMP_COMMANDS` listing the names and `introspect_commands` adding
+/// their `SchemaInfo`, so that they never drift.
+///
+/// Each command is given as `struct_name(name)`, or `struct_name(name, default)`
+/// if its arguments can be omitted.
+macro_rules! define_qmp_command_enum {
+    ( $( $command:ident ( $name:literal $(, $default:ident)? ) ),* $(,)? ) => {
+        /// A enum to store all command struct
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        #[serde(tag = "execute")]
+        pub enum QmpCommand {
+            $(
+                #[serde(rename = $name)]
+                $command {
+                    $( #[serde($default)] )?
+                    arguments: $command,
+                    #[serde(default, skip_serializing_if = "Option::is_none")]
+                    id: Option<Value>,
+                },
+            )*
+        }
+
+        /// Names of all commands in `QmpCommand`.
+        pub const QMP_COMMANDS: &[&str] = &[ $( $name ),* ];
+
+        /// Add `SchemaInfo` of all commands in `QmpCommand` to `registry`.
+        fn introspect_commands(registry: &SchemaRegistry) {
+            $( registry.add_command::<$command>($name); )*
+        }
+    };
+}
+
+define_qmp_command_enum!(
+    qmp_capabilities("qmp_capabilities", default),
+    quit("quit", default),
+    stop("stop", default),
+    cont("cont", default),
+    device_add("device_add"),
+    device_del("device_del"),
+    netdev_add("netdev_add"),
+    netdev_del("netdev_del"),
+    query_hotpluggable_cpus("query-hotpluggable-cpus", default),
+    query_cpus("query-cpus", default),
+    query_status("query-status", default),
+    getfd("getfd"),
+    blockdev_add("blockdev-add"),
+    blockdev_del("blockdev-del"),
+    dump_guest_memory("dump-guest-memory"),
+    query_memory_summary("query-memory-summary", default),
+    query_version("query-version", default),
+    query_target("query-target", default),
+    query_commands("query-commands", default),
+    query_qmp_schema("query-qmp-schema", default),
+    system_reset("system_reset", default),
+    system_powerdown("system_powerdown", default),
+    balloon("balloon"),
+    query_balloon("query-balloon", default),
+    query_block("query-block", default),
+    query_blockstats("query-blockstats", default),
+    block_resize("block_resize"),
+    block_set_io_throttle("block_set_io_throttle"),
+    query_netdev("query-netdev", default),
+    set_link("set_link"),
+);
+
 /// qmp_capabilities
 ///
 /// Enable QMP capabilities.
 ///
+/// # Arguments
+///
+/// * `enable` - Capabilities to enable, which are advertised in greeting.
+///
+/// # Notes
+///
+/// With capability `oob`, commands `quit` and `query-status` can be executed
+/// out-of-band by `exec-oob` instead of `execute`. They're executed once
+/// received, without waiting for main loop or in-band commands before them.
+/// `migrate_cancel` isn't among them as migration isn't supported.
+///
 /// # Examples
 ///
 /// ```text
-/// -> { "execute": "qmp_capabilities" }
+/// -> { "execute": "qmp_capabilities", "arguments": { "enable": [ "oob" ] } }
 /// <- { "return": {} }
+/// -> { "exec-oob": "query-status", "id": "oob-0" }
+/// <- { "return": { "running": true, "singlestep": false, "status": "running" },
+///      "id": "oob-0" }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct qmp_capabilities {}
+#[serde(deny_unknown_fields)]
+pub struct qmp_capabilities {
+    #[serde(rename = "enable", default, skip_serializing_if = "Option::is_none")]
+    pub enable: Option<Vec<QmpCapability>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum QmpCapability {
+    /// Out-of-band execution of commands.
+    #[serde(rename = "oob")]
+    oob,
+}
 
 impl Command for qmp_capabilities {
     const NAME: &'static str = "qmp_capabilities";
@@ -168,6 +197,7 @@ impl Command for qmp_capabilities {
 /// <- { "return": {}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct quit {}
 
 impl Command for quit {
@@ -190,6 +220,7 @@ impl Command for quit {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct stop {}
 
 impl Command for stop {
@@ -212,6 +243,7 @@ impl Command for stop {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct cont {}
 
 impl Command for cont {
@@ -231,7 +263,19 @@ impl Command for cont {
 /// * `driver` - the name of the new device's driver.
 /// * `addr` - the address device insert into.
 ///
-/// Additional arguments depend on the type.
+/// Additional arguments depend on the type:
+///
+/// * `filename` - host file which entropy is read from, for `virtio-rng`,
+///   default to `/dev/random`.
+/// * `max-bytes` - bytes of entropy allowed in every `period`, for `virtio-rng`.
+/// * `period` - period of rate limit in milliseconds, for `virtio-rng`.
+/// * `guest-cid` - context ID of guest, mandatory for `vhost-vsock`.
+///
+/// # Errors
+///
+/// Devices of driver `virtio-balloon-mmio`, `virtio-rng` and `vhost-vsock`
+/// take a free MMIO slot reserved for hot-plug at startup, GenericError is
+/// returned if no reserved slot remains.
 ///
 /// # Examples
 ///
@@ -239,8 +283,16 @@ impl Command for cont {
 /// -> { "execute": "device_add",
 ///      "arguments": { "id": "net-0", "driver": "virtio-net-mmio", "addr": "0x0"}}
 /// <- { "return": {} }
+/// -> { "execute": "device_add",
+///      "arguments": { "id": "rng-0", "driver": "virtio-rng", "filename": "/dev/urandom",
+///                     "max-bytes": 1024, "period": 1000 }}
+/// <- { "return": {} }
+/// -> { "execute": "device_add",
+///      "arguments": { "id": "vsock-0", "driver": "vhost-vsock", "guest-cid": 3 }}
+/// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct device_add {
     #[serde(rename = "id")]
     pub id: String,
@@ -250,6 +302,14 @@ pub struct device_add {
     pub addr: Option<String>,
     #[serde(rename = "lun")]
     pub lun: Option<usize>,
+    #[serde(rename = "filename")]
+    pub filename: Option<String>,
+    #[serde(rename = "max-bytes")]
+    pub max_bytes: Option<u64>,
+    #[serde(rename = "period")]
+    pub period: Option<u64>,
+    #[serde(rename = "guest-cid")]
+    pub guest_cid: Option<u64>,
 }
 
 impl Command for device_add {
@@ -262,12 +322,16 @@ impl Command for device_add {
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FileOptions {
     pub driver: String,
     pub filename: String,
+    /// Host IO engine: `threads`, `native` or `io_uring`.
+    pub aio: Option<AioEngine>,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CacheOptions {
     #[serde(rename = "no-flush")]
     pub no_flush: Option<bool>,
@@ -282,6 +346,9 @@ pub struct CacheOptions {
 /// * `file` - the backend file information.
 /// * `cache` - if use direct io.
 /// * `read_only` - if readonly.
+/// * `format` - format of the image, `raw` or `qcow2`, default `raw`.
+/// * `discard` - `ignore` or `unmap` discard requests, default `ignore`.
+/// * `detect_zeroes` - detect writes of zeroes `off`, `on` or `unmap`, default `off`.
 ///
 /// Additional arguments depend on the type.
 ///
@@ -290,11 +357,13 @@ pub struct CacheOptions {
 /// ```text
 /// -> { "execute": "blockdev_add",
 ///      "arguments":  {"node-name": "drive-0",
-///                     "file": {"driver": "file", "filename": "/path/to/block"},
-///                     "cache": {"direct": true}, "read-only": false }}
+///                     "file": {"driver": "file", "filename": "/path/to/block", "aio": "native"},
+///                     "cache": {"direct": true}, "read-only": false,
+///                     "discard": "unmap", "detect-zeroes": "unmap" }}
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct blockdev_add {
     #[serde(rename = "node-name")]
     pub node_name: String,
@@ -302,6 +371,10 @@ pub struct blockdev_add {
     pub cache: Option<CacheOptions>,
     #[serde(rename = "read-only")]
     pub read_only: Option<bool>,
+    pub format: Option<ImageFormat>,
+    pub discard: Option<DiscardMode>,
+    #[serde(rename = "detect-zeroes")]
+    pub detect_zeroes: Option<DetectZeroes>,
 }
 
 impl Command for blockdev_add {
@@ -319,23 +392,47 @@ impl Command for blockdev_add {
 ///
 /// * `id` - the device's ID, must be unique.
 /// * `ifname` - the backend tap dev name.
-/// * `fds` - the file fd opened by upper level.
+/// * `fds` - the file fds opened by upper level, separated by ','.
+/// * `vhost` - whether to process virtqueues by vhost-net kernel, default false.
+/// * `vhostfds` - the vhost-net fd opened by upper level, implies `vhost`.
+/// * `queues` - the number of queue pairs, default the number of `fds`, or 1.
 ///
 /// Additional arguments depend on the type.
 ///
+/// # Errors
+///
+/// If `queues` is more than 16, or doesn't match the number of `fds`,
+/// GenericError.
+///
+/// # Notes
+///
+/// If vhost-net fails to start, virtqueues are processed in userspace.
+/// Multiple queues are opened on the tap device `ifname`, and can't be
+/// processed by vhost-net.
+///
 /// # Examples
 ///
 /// ```text
 /// -> { "execute": "netdev_add",
 ///      "arguments":  {"id": "net-0", "ifname": "tap0", "fds": 123 }}
 /// <- { "return": {} }
+/// -> { "execute": "netdev_add",
+///      "arguments":  {"id": "net-1", "ifname": "tap1", "vhost": true }}
+/// <- { "return": {} }
+/// -> { "execute": "netdev_add",
+///      "arguments":  {"id": "net-2", "fds": "fd-tap0,fd-tap1", "queues": 2 }}
+/// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct netdev_add {
     pub id: String,
     #[serde(rename = "ifname")]
     pub if_name: Option<String>,
     pub fds: Option<String>,
+    pub vhost: Option<bool>,
+    pub vhostfds: Option<String>,
+    pub queues: Option<u16>,
 }
 
 impl Command for netdev_add {
@@ -376,6 +473,7 @@ impl Command for netdev_add {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct device_del {
     pub id: String,
 }
@@ -390,6 +488,7 @@ impl Command for device_del {
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct blockdev_del {
     #[serde(rename = "node-name")]
     pub node_name: String,
@@ -423,6 +522,7 @@ impl Command for blockdev_del {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct netdev_del {
     pub id: String,
 }
@@ -460,6 +560,7 @@ impl Command for netdev_del {
 ///    ]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct query_hotpluggable_cpus {}
 
 impl Command for query_hotpluggable_cpus {
@@ -532,6 +633,7 @@ pub struct CpuInstanceProperties {
 ///    }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct query_cpus {}
 
 impl Command for query_cpus {
@@ -604,9 +706,11 @@ pub struct CpuInfoArm {}
 /// -> { "execute": "query-status" }
 /// <- { "return": { "running": true,
 ///                  "singlestep": false,
-///                  "status": "running" } }
+///                  "status": "running",
+///                  "clock": "host" } }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct query_status {}
 
 impl Command for query_status {
@@ -626,6 +730,8 @@ pub struct StatusInfo {
     pub running: bool,
     #[serde(rename = "status")]
     pub status: RunState,
+    #[serde(rename = "clock", skip_serializing_if = "Option::is_none")]
+    pub clock: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -687,6 +793,7 @@ impl Default for RunState {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct getfd {
     #[serde(rename = "fdname")]
     pub fd_name: String,
@@ -702,6 +809,754 @@ impl Command for getfd {
     }
 }
 
+/// dump-guest-memory
+///
+/// Dump guest memory to a file, vcpus are paused during the dump.
+///
+/// # Arguments
+///
+/// * `protocol` - Destination of the dump, only `file:<path>` is supported.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "dump-guest-memory", "arguments": { "protocol": "file:/tmp/guest.dump" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct dump_guest_memory {
+    pub protocol: String,
+}
+
+impl Command for dump_guest_memory {
+    const NAME: &'static str = "dump-guest-memory";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-memory-summary
+///
+/// Query the size of guest RAM and the layout of guest memory address space.
+///
+/// # Returns
+///
+/// `MemorySummary` of guest memory address space.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-memory-summary" }
+/// <- { "return": { "ram-size": 1073741824, "regions": 3, "flat-ranges": 3 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_memory_summary {}
+
+impl Command for query_memory_summary {
+    const NAME: &'static str = "query-memory-summary";
+    type Res = MemorySummary;
+
+    fn back(self) -> MemorySummary {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MemorySummary {
+    #[serde(rename = "ram-size")]
+    pub ram_size: u64,
+    #[serde(rename = "regions")]
+    pub regions: usize,
+    #[serde(rename = "flat-ranges")]
+    pub flat_ranges: usize,
+}
+
+/// query-version
+///
+/// Query the version of StratoVirt.
+///
+/// # Returns
+///
+/// `VersionInfo` of StratoVirt.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-version" }
+/// <- { "return": { "qemu": { "micro": 0, "minor": 1, "major": 0 }, "package": "" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_version {}
+
+impl Command for query_version {
+    const NAME: &'static str = "query-version";
+    type Res = VersionInfo;
+
+    fn back(self) -> VersionInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VersionInfo {
+    #[serde(rename = "qemu")]
+    pub qemu: VersionNumber,
+    #[serde(rename = "package")]
+    pub package: String,
+}
+
+impl VersionInfo {
+    /// Version of this build of StratoVirt.
+    pub fn current() -> Self {
+        let mut numbers = env!("CARGO_PKG_VERSION")
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|n| n.parse::<u8>().unwrap_or(0));
+        let major = numbers.next().unwrap_or(0);
+        let minor = numbers.next().unwrap_or(0);
+        let micro = numbers.next().unwrap_or(0);
+        VersionInfo {
+            qemu: VersionNumber {
+                micro,
+                minor,
+                major,
+            },
+            package: String::new(),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VersionNumber {
+    #[serde(rename = "micro")]
+    pub micro: u8,
+    #[serde(rename = "minor")]
+    pub minor: u8,
+    #[serde(rename = "major")]
+    pub major: u8,
+}
+
+/// query-target
+///
+/// Query the target architecture StratoVirt is built for.
+///
+/// # Returns
+///
+/// `TargetInfo` of StratoVirt.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-target" }
+/// <- { "return": { "arch": "x86_64" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_target {}
+
+impl Command for query_target {
+    const NAME: &'static str = "query-target";
+    type Res = TargetInfo;
+
+    fn back(self) -> TargetInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TargetInfo {
+    #[serde(rename = "arch")]
+    pub arch: String,
+}
+
+impl TargetInfo {
+    /// Target of this build of StratoVirt.
+    pub fn current() -> Self {
+        TargetInfo {
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+/// query-commands
+///
+/// Query the commands supported by StratoVirt.
+///
+/// # Returns
+///
+/// A list of `CommandInfo` of all commands.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-commands" }
+/// <- { "return": [ { "name": "qmp_capabilities" }, { "name": "quit" }, ... ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_commands {}
+
+impl Command for query_commands {
+    const NAME: &'static str = "query-commands";
+    type Res = Vec<CommandInfo>;
+
+    fn back(self) -> Vec<CommandInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CommandInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+}
+
+impl CommandInfo {
+    /// Information of all commands in `QmpCommand`.
+    pub fn all() -> Vec<CommandInfo> {
+        QMP_COMMANDS
+            .iter()
+            .map(|name| CommandInfo {
+                name: name.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// query-qmp-schema
+///
+/// Query the QMP schema, i.e. commands and events supported by StratoVirt,
+/// and the types of their arguments, returns and data.
+///
+/// # Returns
+///
+/// A list of `SchemaInfo`. Commands and events refer to the types they use
+/// by name, and every type is listed once.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-qmp-schema" }
+/// <- { "return": [ ...
+///                  { "name": "block_resize", "meta-type": "command",
+///                    "arg-type": "q_obj_block_resize-arg", "ret-type": "Empty" },
+///                  ...
+///                  { "name": "q_obj_block_resize-arg", "meta-type": "object",
+///                    "members": [ { "name": "device", "type": "str", "optional": true },
+///                                 { "name": "node-name", "type": "str", "optional": true },
+///                                 { "name": "size", "type": "int", "optional": false } ] },
+///                  { "name": "int", "meta-type": "builtin", "json-type": "int" },
+///                  ... ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_qmp_schema {}
+
+impl Command for query_qmp_schema {
+    const NAME: &'static str = "query-qmp-schema";
+    type Res = Vec<SchemaInfo>;
+
+    fn back(self) -> Vec<SchemaInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "meta-type")]
+    pub meta_type: SchemaMetaType,
+    /// Type of arguments of command, or data of event.
+    #[serde(rename = "arg-type", default, skip_serializing_if = "Option::is_none")]
+    pub arg_type: Option<String>,
+    /// Type of return of command.
+    #[serde(rename = "ret-type", default, skip_serializing_if = "Option::is_none")]
+    pub ret_type: Option<String>,
+    /// Members of object.
+    #[serde(rename = "members", default, skip_serializing_if = "Option::is_none")]
+    pub members: Option<Vec<SchemaInfoObjectMember>>,
+    /// Values of enum.
+    #[serde(rename = "values", default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<String>>,
+    /// Type of elements of array.
+    #[serde(
+        rename = "element-type",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub element_type: Option<String>,
+    /// JSON type of builtin type: `string`, `int`, `number`, `boolean`,
+    /// `null` or `value`.
+    #[serde(rename = "json-type", default, skip_serializing_if = "Option::is_none")]
+    pub json_type: Option<String>,
+}
+
+impl SchemaInfo {
+    /// Schema of all commands in `QmpCommand` and events in `QmpEvent`.
+    pub fn all() -> Vec<SchemaInfo> {
+        let registry = SchemaRegistry::default();
+        introspect_commands(&registry);
+        introspect_events(&registry);
+        registry.into_schema()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SchemaMetaType {
+    #[serde(rename = "builtin")]
+    Builtin,
+    #[serde(rename = "enum")]
+    Enum,
+    #[serde(rename = "array")]
+    Array,
+    #[serde(rename = "object")]
+    Object,
+    #[serde(rename = "command")]
+    Command,
+    #[serde(rename = "event")]
+    Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaInfoObjectMember {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(rename = "optional")]
+    pub optional: bool,
+}
+
+/// system_reset
+///
+/// Reset guest, boot source is loaded again and VCPUs restart from their
+/// boot state.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "system_reset" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct system_reset {}
+
+impl Command for system_reset {
+    const NAME: &'static str = "system_reset";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// system_powerdown
+///
+/// Press the power button of guest, which is notified to shut down.
+///
+/// # Notes
+///
+/// Guest may ignore the notification, it fails if the machine has no power
+/// button device.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "system_powerdown" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct system_powerdown {}
+
+impl Command for system_powerdown {
+    const NAME: &'static str = "system_powerdown";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// balloon
+///
+/// Request guest to adjust its memory size through balloon device.
+///
+/// # Arguments
+///
+/// * `value` - Target size of guest memory in bytes, in range (0, RAM size].
+///
+/// # Errors
+///
+/// `DeviceNotActive` if no balloon device is plugged by `device_add`.
+///
+/// # Notes
+///
+/// Guest adjusts asynchronously, event `BALLOON_CHANGE` is emitted after it
+/// has done.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "balloon", "arguments": { "value": 536870912 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct balloon {
+    #[serde(rename = "value")]
+    pub value: u64,
+}
+
+impl Command for balloon {
+    const NAME: &'static str = "balloon";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-balloon
+///
+/// Query the actual size of guest memory adjusted by balloon device.
+///
+/// # Returns
+///
+/// `BalloonInfo` of guest memory.
+///
+/// # Errors
+///
+/// `DeviceNotActive` if no balloon device is plugged by `device_add`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-balloon" }
+/// <- { "return": { "actual": 1073741824 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_balloon {}
+
+impl Command for query_balloon {
+    const NAME: &'static str = "query-balloon";
+    type Res = BalloonInfo;
+
+    fn back(self) -> BalloonInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BalloonInfo {
+    #[serde(rename = "actual")]
+    pub actual: u64,
+}
+
+/// query-block
+///
+/// Query the block devices and their backends.
+///
+/// # Returns
+///
+/// `BlockInfo` of each block device.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-block" }
+/// <- { "return": [ { "device": "drive-0",
+///                    "inserted": { "node-name": "drive-0", "file": "/path/to/block",
+///                                  "ro": false, "drv": "raw", "aio": "native",
+///                                  "bps": 0, "bps_rd": 0, "bps_wr": 0,
+///                                  "iops": 0, "iops_rd": 0, "iops_wr": 0 } } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_block {}
+
+impl Command for query_block {
+    const NAME: &'static str = "query-block";
+    type Res = Vec<BlockInfo>;
+
+    fn back(self) -> Vec<BlockInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    #[serde(rename = "device")]
+    pub device: String,
+    #[serde(rename = "inserted")]
+    pub inserted: BlockDeviceInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    #[serde(rename = "file")]
+    pub file: String,
+    #[serde(rename = "ro")]
+    pub ro: bool,
+    #[serde(rename = "drv")]
+    pub drv: String,
+    #[serde(rename = "aio")]
+    pub aio: String,
+    #[serde(rename = "bps")]
+    pub bps: u64,
+    #[serde(rename = "bps_rd")]
+    pub bps_rd: u64,
+    #[serde(rename = "bps_wr")]
+    pub bps_wr: u64,
+    #[serde(rename = "iops")]
+    pub iops: u64,
+    #[serde(rename = "iops_rd")]
+    pub iops_rd: u64,
+    #[serde(rename = "iops_wr")]
+    pub iops_wr: u64,
+}
+
+/// query-blockstats
+///
+/// Query the statistics of IO requests completed by block devices.
+///
+/// # Returns
+///
+/// `BlockStats` of each block device.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-blockstats" }
+/// <- { "return": [ { "device": "drive-0",
+///                    "stats": { "rd_bytes": 4096, "wr_bytes": 512, "rd_operations": 1,
+///                               "wr_operations": 1, "flush_operations": 0 } } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_blockstats {}
+
+impl Command for query_blockstats {
+    const NAME: &'static str = "query-blockstats";
+    type Res = Vec<BlockStats>;
+
+    fn back(self) -> Vec<BlockStats> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockStats {
+    #[serde(rename = "device")]
+    pub device: String,
+    #[serde(rename = "stats")]
+    pub stats: BlockDeviceStats,
+}
+
+/// block_resize
+///
+/// Grow the image file of a block device, and notify guest of the new
+/// capacity.
+///
+/// # Arguments
+///
+/// * `device` - Id of the block device.
+/// * `node_name` - Node name of the block backend, either `device` or
+///   `node_name` should be given.
+/// * `size` - New size of the image in bytes, multiple of 512.
+///
+/// # Notes
+///
+/// Shrinking image is not supported.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_resize", "arguments": { "node-name": "drive-0", "size": 2147483648 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_resize {
+    #[serde(rename = "device")]
+    pub device: Option<String>,
+    #[serde(rename = "node-name")]
+    pub node_name: Option<String>,
+    #[serde(rename = "size")]
+    pub size: u64,
+}
+
+impl Command for block_resize {
+    const NAME: &'static str = "block_resize";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block_set_io_throttle
+///
+/// Set IO throttling limits of a block device.
+///
+/// # Arguments
+///
+/// * `device` - Id of the block device.
+/// * `bps` - Total bytes per second.
+/// * `bps_rd` - Read bytes per second.
+/// * `bps_wr` - Write bytes per second.
+/// * `iops` - Total requests per second.
+/// * `iops_rd` - Read requests per second.
+/// * `iops_wr` - Write requests per second.
+///
+/// # Notes
+///
+/// Limit of 0 means unlimited, so throttling is removed if all limits are 0.
+/// Total limit can't be set together with read or write limit of the same
+/// kind.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_set_io_throttle",
+///      "arguments": { "device": "drive-0", "bps": 0, "bps_rd": 0, "bps_wr": 0,
+///                     "iops": 1000, "iops_rd": 0, "iops_wr": 0 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_set_io_throttle {
+    #[serde(rename = "device")]
+    pub device: String,
+    #[serde(rename = "bps")]
+    pub bps: u64,
+    #[serde(rename = "bps_rd")]
+    pub bps_rd: u64,
+    #[serde(rename = "bps_wr")]
+    pub bps_wr: u64,
+    #[serde(rename = "iops")]
+    pub iops: u64,
+    #[serde(rename = "iops_rd")]
+    pub iops_rd: u64,
+    #[serde(rename = "iops_wr")]
+    pub iops_wr: u64,
+}
+
+impl Command for block_set_io_throttle {
+    const NAME: &'static str = "block_set_io_throttle";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-netdev
+///
+/// Query the network backends added by `netdev_add` or command line.
+///
+/// # Returns
+///
+/// `NetdevInfo` of each network backend.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-netdev" }
+/// <- { "return": [ { "id": "net-0", "ifname": "tap0", "queues": 1 },
+///                  { "id": "net-1", "fds": "12,13", "queues": 2 } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_netdev {}
+
+impl Command for query_netdev {
+    const NAME: &'static str = "query-netdev";
+    type Res = Vec<NetdevInfo>;
+
+    fn back(self) -> Vec<NetdevInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetdevInfo {
+    #[serde(rename = "id")]
+    pub id: String,
+    #[serde(rename = "ifname", default, skip_serializing_if = "Option::is_none")]
+    pub ifname: Option<String>,
+    #[serde(rename = "fds", default, skip_serializing_if = "Option::is_none")]
+    pub fds: Option<String>,
+    #[serde(rename = "queues")]
+    pub queues: u16,
+}
+
+/// set_link
+///
+/// Set link of a network device up or down.
+///
+/// # Arguments
+///
+/// * `name` - Id of the network device.
+/// * `up` - Link is up or down.
+///
+/// # Errors
+///
+/// If `name` is not a valid network device, DeviceNotFound.
+///
+/// # Notes
+///
+/// When link is down, frames received from the backend are dropped, and
+/// guest is notified of carrier loss.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "set_link", "arguments": { "name": "net-0", "up": false } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct set_link {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "up")]
+    pub up: bool,
+}
+
+impl Command for set_link {
+    const NAME: &'static str = "set_link";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    #[serde(rename = "rd_bytes")]
+    pub rd_bytes: u64,
+    #[serde(rename = "wr_bytes")]
+    pub wr_bytes: u64,
+    #[serde(rename = "rd_operations")]
+    pub rd_operations: u64,
+    #[serde(rename = "wr_operations")]
+    pub wr_operations: u64,
+    #[serde(rename = "flush_operations")]
+    pub flush_operations: u64,
+}
+
 /// SHUTDOWN
 ///
 /// Emitted when the virtual machine has shut down, indicating that StratoVirt is
@@ -741,6 +1596,31 @@ impl Event for RESET {
     const NAME: &'static str = "RESET";
 }
 
+/// POWERDOWN
+///
+/// Emitted when the virtual machine is powered down through the power button,
+/// e.g. by QMP command system_powerdown.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct POWERDOWN {}
+
+impl Event for POWERDOWN {
+    const NAME: &'static str = "POWERDOWN";
+}
+
+/// BALLOON_CHANGE
+///
+/// Emitted when guest has adjusted its memory size through balloon device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BALLOON_CHANGE {
+    /// Actual size of guest memory in bytes.
+    #[serde(rename = "actual")]
+    pub actual: u64,
+}
+
+impl Event for BALLOON_CHANGE {
+    const NAME: &'static str = "BALLOON_CHANGE";
+}
+
 /// STOP
 ///
 /// Emitted when the virtual machine is stopped
@@ -789,31 +1669,38 @@ impl Event for DEVICE_DELETED {
     const NAME: &'static str = "DEVICE_DELETED";
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "event")]
-pub enum QmpEvent {
-    #[serde(rename = "SHUTDOWN")]
-    SHUTDOWN {
-        data: SHUTDOWN,
-        timestamp: TimeStamp,
-    },
-    #[serde(rename = "RESET")]
-    RESET { data: RESET, timestamp: TimeStamp },
-    #[serde(rename = "STOP")]
-    STOP {
-        #[serde(default)]
-        data: STOP,
-        timestamp: TimeStamp,
-    },
-    #[serde(rename = "RESUME")]
-    RESUME {
-        #[serde(default)]
-        data: RESUME,
-        timestamp: TimeStamp,
-    },
-    #[serde(rename = "DEVICE_DELETED")]
-    DEVICE_DELETED {
-        data: DEVICE_DELETED,
-        timestamp: TimeStamp,
-    },
+/// Define `QmpEvent` with all events, together with `introspect_events`
+/// adding their `SchemaInfo`, so that they never drift.
+///
+/// Each event is given as `struct_name`, or `struct_name(default)` if its
+/// data can be omitted. Name of event is the same as its struct.
+macro_rules! define_qmp_event_enum {
+    ( $( $event:ident $( ( $default:ident ) )? ),* $(,)? ) => {
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        #[serde(tag = "event")]
+        pub enum QmpEvent {
+            $(
+                $event {
+                    $( #[serde($default)] )?
+                    data: $event,
+                    timestamp: TimeStamp,
+                },
+            )*
+        }
+
+        /// Add `SchemaInfo` of all events in `QmpEvent` to `registry`.
+        fn introspect_events(registry: &SchemaRegistry) {
+            $( registry.add_event::<$event>(); )*
+        }
+    };
 }
+
+define_qmp_event_enum!(
+    SHUTDOWN,
+    RESET,
+    POWERDOWN(default),
+    BALLOON_CHANGE,
+    STOP(default),
+    RESUME(default),
+    DEVICE_DELETED,
+);
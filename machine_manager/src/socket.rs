@@ -11,13 +11,14 @@
 // See the Mulan PSL v2 for more details.
 
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::io;
 use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::{Arc, Mutex, RwLock};
 
-use util::epoll_context::{EventNotifier, EventNotifierHelper, NotifierOperation};
+use util::epoll_context::{EventNotifier, EventNotifierHelper, MainLoopContext, NotifierOperation};
 use vmm_sys_util::epoll::EventSet;
 
 use super::errors::Result;
@@ -29,6 +30,8 @@ use crate::{
 };
 
 const MAX_SOCKET_MSG_LENGTH: usize = 8192;
+/// Default maximum number of clients connected to `Socket` at the same time.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 3;
 
 /// The wrapper over Unix socket and socket handler.
 ///
@@ -58,10 +61,12 @@ pub struct Socket {
     sock_type: SocketType,
     /// Socket listener tuple
     listener: UnixListener,
-    /// Socket stream with RwLock
-    stream: RwLock<Option<SocketStream>>,
+    /// Socket streams of connected clients, indexed by fd
+    streams: RwLock<BTreeMap<RawFd, SocketStream>>,
+    /// Maximum number of clients connected at the same time
+    max_connections: usize,
     /// Perform socket command
-    performer: Option<Arc<dyn MachineExternalInterface>>,
+    performer: Option<Arc<dyn MachineExternalInterface + Send + Sync>>,
 }
 
 impl Socket {
@@ -73,35 +78,91 @@ impl Socket {
     /// * `performer` - The `VM` to perform socket command.
     pub fn from_unix_listener(
         listener: UnixListener,
-        performer: Option<Arc<dyn MachineExternalInterface>>,
+        performer: Option<Arc<dyn MachineExternalInterface + Send + Sync>>,
     ) -> Self {
         Socket {
             sock_type: SocketType::Unix,
             listener,
-            stream: RwLock::new(None),
+            streams: RwLock::new(BTreeMap::new()),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
             performer,
         }
     }
 
+    /// Set maximum number of clients connected at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_connections` - The maximum number of clients.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Serve the `Socket` in a dedicated `qmp-monitor` thread with its own
+    /// event loop, so that out-of-band commands are still answered while
+    /// main loop is busy.
+    ///
+    /// # Arguments
+    ///
+    /// * `thread_init` - Called in the monitor thread before serving, e.g.
+    ///   to install seccomp filter for the thread.
+    pub fn run_in_monitor_thread<F>(self, thread_init: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        std::thread::Builder::new()
+            .name("qmp-monitor".to_string())
+            .spawn(move || {
+                let mut monitor_loop = MainLoopContext::new();
+                if let Err(e) = monitor_loop.update_events(EventNotifierHelper::internal_notifiers(
+                    Arc::new(Mutex::new(self)),
+                )) {
+                    error!("Failed to add api event to monitor loop: {}", e);
+                    return;
+                }
+
+                thread_init();
+                loop {
+                    if let Err(e) = monitor_loop.run() {
+                        error!("Monitor loop exits unexpectedly: {}", e);
+                        break;
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
     /// Get listener's fd from `Socket`.
     pub fn get_listener_fd(&self) -> RawFd {
         self.listener.as_raw_fd()
     }
 
-    /// Accept stream and bind to Socket.
-    pub fn accept(&self) {
-        match self.sock_type {
+    /// Accept stream and bind to Socket. The stream is closed at once if
+    /// there're already `max_connections` clients connected.
+    ///
+    /// Return fd of the stream bound to Socket.
+    pub fn accept(&self) -> Option<RawFd> {
+        let stream_fd = match self.sock_type {
             SocketType::Unix => {
                 let stream = self.accept_unix_stream();
-                self.bind_unix_stream(stream);
+                if self.connection_count() >= self.max_connections {
+                    warn!(
+                        "Connection refused, {} clients are connected already",
+                        self.max_connections
+                    );
+                    return None;
+                }
+                self.bind_unix_stream(stream)
             }
-        }
+        };
 
         #[cfg(feature = "qmp")]
         {
-            QmpChannel::bind_writer(SocketRWHandler::new(self.get_stream_fd()));
-            self.send_response(true);
+            QmpChannel::bind_writer(SocketRWHandler::new(stream_fd));
+            self.send_response(stream_fd, true);
         }
+
+        Some(stream_fd)
     }
 
     /// Accept a new incoming connection unix stream from unix listener.
@@ -115,64 +176,75 @@ impl Socket {
         self.sock_type
     }
 
-    /// Bind `Socket` with a `UnixStream`.
+    /// Bind `Socket` with a `UnixStream` of a new client.
+    ///
+    /// Return fd of the stream.
     ///
     /// # Arguments
     ///
     /// * `unix_stream` - The `UnixStream` bind to `Socket`.
-    pub fn bind_unix_stream(&self, unix_stream: UnixStream) {
+    pub fn bind_unix_stream(&self, unix_stream: UnixStream) -> RawFd {
         let stream = SocketStream::from_unix_stream(unix_stream);
-        *self.stream.write().unwrap() = Some(stream);
+        let stream_fd = stream.socket_fd;
+        self.streams.write().unwrap().insert(stream_fd, stream);
+        stream_fd
     }
 
-    /// Unbind stream from `Socket`, reset the state.
-    pub fn drop_stream(&self) {
-        *self.stream.write().unwrap() = None;
+    /// Unbind stream of a client from `Socket`, the stream is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The fd of stream.
+    pub fn drop_stream(&self, stream_fd: RawFd) {
+        self.streams.write().unwrap().remove(&stream_fd);
     }
 
-    /// Confirm whether socket stream bind to `Socket` or not.
+    /// Confirm whether any socket stream bind to `Socket` or not.
     pub fn is_connected(&self) -> bool {
-        self.stream.read().unwrap().is_some()
+        !self.streams.read().unwrap().is_empty()
     }
 
-    /// Get socket fd from `Socket`, it a private function.
-    pub fn get_stream_fd(&self) -> RawFd {
-        if self.is_connected() {
-            self.stream.read().unwrap().as_ref().unwrap().socket_fd
-        } else {
-            panic!("Failed to get socket fd!");
-        }
+    /// Get number of clients connected to `Socket`.
+    pub fn connection_count(&self) -> usize {
+        self.streams.read().unwrap().len()
     }
 
-    /// Get a `SocketHandler` from `Socket`.
-    pub fn get_socket_handler(&self) -> SocketHandler {
-        SocketHandler::new(self.get_stream_fd())
+    /// Get a `SocketHandler` of a stream from `Socket`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The fd of stream.
+    pub fn get_socket_handler(&self, stream_fd: RawFd) -> SocketHandler {
+        SocketHandler::new(stream_fd)
     }
 
-    /// In qmp feature, send event to client.
+    /// In qmp feature, send event to all clients.
     ///
     /// # Arguments
     ///
     /// * `event` - The `QmpEvent` will be sent to client.
     #[cfg(feature = "qmp")]
     pub fn send_event(&self, event: &QmpEvent) {
-        if self.is_connected() {
-            let mut handler = self.get_socket_handler();
-            let event_str = serde_json::to_string(&event).unwrap();
-            handler.send_str(&event_str).unwrap();
-            info!("EVENT: --> {:?}", event);
+        let event_str = serde_json::to_string(&event).unwrap();
+        for stream_fd in self.streams.read().unwrap().keys() {
+            let mut handler = self.get_socket_handler(*stream_fd);
+            if let Err(e) = handler.send_str(&event_str) {
+                warn!("Failed to send event to client {}: {}", stream_fd, e);
+            }
         }
+        info!("EVENT: --> {:?}", event);
     }
 
-    /// In qmp feature, send empty or greeting response to client.
+    /// In qmp feature, send empty or greeting response to a client.
     ///
     /// # Arguments
     ///
+    /// * `stream_fd` - The fd of stream to the client.
     /// * `is_greeting` - Whether sending greeting response or not.
     #[cfg(feature = "qmp")]
-    pub fn send_response(&self, is_greeting: bool) {
-        if self.is_connected() {
-            let mut handler = self.get_socket_handler();
+    pub fn send_response(&self, stream_fd: RawFd, is_greeting: bool) {
+        if self.streams.read().unwrap().contains_key(&stream_fd) {
+            let mut handler = self.get_socket_handler(stream_fd);
             let resp = if is_greeting {
                 serde_json::to_string(&QmpGreeting::create_greeting(1, 0, 4)).unwrap()
             } else {
@@ -189,17 +261,15 @@ impl Socket {
         shared_socket: Arc<Mutex<Self>>,
     ) -> Option<Vec<EventNotifier>> {
         let mut notifiers = Vec::new();
-        self.accept();
+        let stream_fd = self.accept()?;
 
         let mut handlers = Vec::new();
         let handler: Box<dyn Fn(EventSet, RawFd) -> Option<Vec<EventNotifier>>> =
             Box::new(move |event, _| {
                 if event == EventSet::IN {
-                    let socket_mutexed = shared_socket.lock().unwrap();
-                    let stream_fd = socket_mutexed.get_stream_fd();
-
                     #[cfg(feature = "qmp")]
                     {
+                        let socket_mutexed = shared_socket.lock().unwrap();
                         let performer = &socket_mutexed.performer.as_ref().unwrap();
 
                         if let Err(e) = crate::qmp::handle_qmp(stream_fd, performer) {
@@ -215,19 +285,18 @@ impl Socket {
                     }
                 }
                 if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                    let socket_mutexed = shared_socket.lock().unwrap();
-                    let stream_fd = socket_mutexed.get_stream_fd();
-                    let listener_fd = socket_mutexed.get_listener_fd();
-
+                    // Only the client hanging up is dropped, others are
+                    // still served.
                     #[cfg(feature = "qmp")]
                     {
-                        QmpChannel::unbind();
+                        QmpChannel::unbind(stream_fd);
                     }
+                    shared_socket.lock().unwrap().drop_stream(stream_fd);
 
                     Some(vec![EventNotifier::new(
                         NotifierOperation::Delete,
                         stream_fd,
-                        Some(listener_fd),
+                        None,
                         EventSet::IN | EventSet::HANG_UP,
                         Vec::new(),
                     )])
@@ -237,10 +306,11 @@ impl Socket {
             });
         handlers.push(Arc::new(Mutex::new(handler)));
 
+        // Listener isn't parked, so that more clients can connect.
         let notifier = EventNotifier::new(
             NotifierOperation::AddShared,
-            self.get_stream_fd(),
-            Some(self.get_listener_fd()),
+            stream_fd,
+            None,
             EventSet::IN | EventSet::HANG_UP,
             handlers,
         );
@@ -346,6 +416,11 @@ impl SocketRWHandler {
         }
     }
 
+    /// Get the socket fd of `SocketRWHandler`.
+    pub fn get_socket_fd(&self) -> RawFd {
+        self.socket_fd
+    }
+
     /// Get inner buf as a `String`.
     pub fn get_buf_string(&mut self) -> Result<String> {
         if self.buf.len() > MAX_SOCKET_MSG_LENGTH {
@@ -614,6 +689,11 @@ impl SocketHandler {
         }
     }
 
+    /// Get the line received by the last `decode_line`.
+    pub fn get_line(&self) -> &str {
+        &self.buffer
+    }
+
     /// Send String to `socket_fd`.
     ///
     /// # Arguments
@@ -830,12 +910,12 @@ mod tests {
         assert_eq!(socket.is_connected(), false);
 
         // 2.Connected
-        socket.bind_unix_stream(server);
+        let stream_fd = socket.bind_unix_stream(server);
         assert_eq!(socket.is_connected(), true);
         assert_eq!(socket.get_socket_type(), SocketType::Unix);
 
         // 3.Unbind SocketStream, reset state
-        socket.drop_stream();
+        socket.drop_stream(stream_fd);
         assert_eq!(socket.is_connected(), false);
 
         // 4.Accept and reconnect a new UnixStream
@@ -847,4 +927,43 @@ mod tests {
         // After test. Environment Recover
         recover_unix_socket_environment("04");
     }
+
+    #[test]
+    fn test_socket_max_connections() {
+        // Pre test. Environment Preparation
+        #[cfg(feature = "qmp")]
+        crate::qmp::QmpChannel::object_init();
+        let (listener, _first_client, server) = prepare_unix_socket_environment("05");
+        let mut socket = Socket::from_unix_listener(listener, None);
+        socket.set_max_connections(2);
+        let first_fd = socket.bind_unix_stream(server);
+
+        // 1.Clients are accepted until max_connections
+        let _second_client = UnixStream::connect("test_05.sock").unwrap();
+        let second_fd = socket.accept().unwrap();
+        assert_ne!(first_fd, second_fd);
+        assert_eq!(socket.connection_count(), 2);
+
+        // 2.More client is refused, its stream is closed
+        let mut third_client = UnixStream::connect("test_05.sock").unwrap();
+        assert!(socket.accept().is_none());
+        assert_eq!(socket.connection_count(), 2);
+        let mut buffer = [0u8; 10];
+        assert_eq!(third_client.read(&mut buffer).unwrap(), 0);
+
+        // 3.Client disconnected makes room for a new one, others are kept
+        socket.drop_stream(first_fd);
+        assert_eq!(socket.connection_count(), 1);
+        let _fourth_client = UnixStream::connect("test_05.sock").unwrap();
+        let fourth_fd = socket.accept().unwrap();
+        assert_eq!(socket.connection_count(), 2);
+
+        // After test. Environment Recover
+        #[cfg(feature = "qmp")]
+        {
+            crate::qmp::QmpChannel::unbind(second_fd);
+            crate::qmp::QmpChannel::unbind(fourth_fd);
+        }
+        recover_unix_socket_environment("05");
+    }
 }
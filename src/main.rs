@@ -18,17 +18,18 @@ extern crate vmm_sys_util;
 
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::net::UnixListener;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use vmm_sys_util::terminal::Terminal;
 
 use device_model::cmdline::{check_api_channel, create_args_parser, create_vmconfig};
 use device_model::{register_seccomp, LightMachine, MainLoop};
-use machine_manager::config::VmConfig;
+use machine_manager::config::{CgroupConfig, VmConfig};
 #[cfg(feature = "qmp")]
 use machine_manager::qmp::QmpChannel;
 use machine_manager::socket::Socket;
-use util::epoll_context::EventNotifierHelper;
+use util::cgroup::{thread_ids, Cgroup};
+use util::rollback::{register_exit_hook, run_exit_hooks, Rollback};
 use util::unix::limit_permission;
 use util::{arg_parser, daemonize::daemonize, logger};
 
@@ -91,6 +92,7 @@ fn run() -> Result<()> {
             error!("{}", error_chain::ChainedError::display_chain(e));
         }
     }
+    run_exit_hooks();
 
     Ok(())
 }
@@ -99,46 +101,102 @@ fn real_main(cmd_args: &arg_parser::ArgMatches) -> Result<()> {
     let vm_config: VmConfig = create_vmconfig(cmd_args)?;
     info!("VmConfig is {:?}", vm_config);
 
-    if cmd_args.is_present("daemonize") {
-        match daemonize(cmd_args.value_of("pidfile")) {
-            Ok(()) => info!("Daemonize mode start!"),
-            Err(e) => error!("Daemonize start failed: {}", e),
+    // Every bring-up stage registers cleanup of resources it creates, any
+    // failure unwinds them in reverse order.
+    let mut rollback = Rollback::new();
+
+    rollback.stage("daemonize", |rollback| -> Result<()> {
+        if cmd_args.is_present("daemonize") {
+            let pidfile = cmd_args.value_of("pidfile");
+            match daemonize(pidfile.clone()) {
+                Ok(()) => {
+                    if let Some(path) = pidfile {
+                        rollback.register_unlink(&path);
+                    }
+                    info!("Daemonize mode start!")
+                }
+                Err(e) => error!("Daemonize start failed: {}", e),
+            }
+        } else {
+            std::io::stdin()
+                .lock()
+                .set_raw_mode()
+                .chain_err(|| "Failed to set terminal to raw mode.")?;
         }
-    } else {
-        std::io::stdin()
-            .lock()
-            .set_raw_mode()
-            .chain_err(|| "Failed to set terminal to raw mode.")?;
+        Ok(())
+    })?;
+
+    // Enter cgroup before guest memory is allocated, so that it's charged to
+    // the cgroup. The cgroup created by us is removed in exit hooks, which
+    // also run on `quit`.
+    let cgroup_config = vm_config.machine_config.cgroup.clone();
+    let cgroup = match &cgroup_config {
+        Some(cgroup_config) => rollback.stage("cgroup", |_| {
+            degrade_cgroup_error(cgroup_config, setup_cgroup(cgroup_config, &vm_config))
+        })?,
+        None => None,
+    }
+    .map(Arc::new);
+    if let Some(cgroup) = &cgroup {
+        let cgroup = cgroup.clone();
+        register_exit_hook("cgroup", move || cgroup.remove());
     }
 
     #[cfg(feature = "qmp")]
     QmpChannel::object_init();
     MainLoop::object_init();
 
-    let vm = LightMachine::new(vm_config)?;
+    let vm = rollback.stage("create machine", |rollback| {
+        LightMachine::new(vm_config, rollback)
+    })?;
     MainLoop::set_manager(vm.clone());
 
-    let api_socket = {
-        let (api_path, _) = check_api_channel(&cmd_args)?;
+    let api_socket = rollback.stage("api-channel", |rollback| -> Result<Socket> {
+        let (api_path, _, max_connections) = check_api_channel(&cmd_args)?;
         let listener = UnixListener::bind(&api_path)?;
+        rollback.register_unlink(&api_path);
         limit_permission(&api_path)?;
-        Socket::from_unix_listener(listener, Some(vm.clone()))
-    };
-
-    MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
-        Mutex::new(api_socket),
-    )))
-    .chain_err(|| "Failed to add api event to MainLoop")?;
+        let mut socket = Socket::from_unix_listener(listener, Some(vm.clone()));
+        socket.set_max_connections(max_connections);
+        Ok(socket)
+    })?;
 
-    vm.realize()?;
-    vm.vm_start(
-        cmd_args.is_present("freeze_cpu"),
-        !cmd_args.is_present("disable-seccomp"),
-    )?;
+    // In-band qmp commands are forwarded from the monitor thread and executed
+    // in main loop.
+    #[cfg(feature = "qmp")]
+    MainLoop::update_event(vec![machine_manager::qmp::in_band_notifier(vm.clone())])
+        .chain_err(|| "Failed to add qmp event to MainLoop")?;
+
+    let monitor_seccomp = !cmd_args.is_present("disable-seccomp");
+    api_socket
+        .run_in_monitor_thread(move || {
+            if monitor_seccomp {
+                if let Err(e) = register_seccomp() {
+                    error!("Failed to register seccomp for qmp monitor: {}", e);
+                }
+            }
+        })
+        .chain_err(|| "Failed to start qmp monitor thread")?;
+
+    rollback.stage("realize machine", |rollback| vm.realize(rollback))?;
+    rollback.stage("start machine", |_| {
+        vm.vm_start(
+            cmd_args.is_present("freeze_cpu"),
+            !cmd_args.is_present("disable-seccomp"),
+        )
+    })?;
+
+    // Vcpu threads are created now, move them into cgroup as well.
+    if let (Some(cgroup), Some(cgroup_config)) = (&cgroup, &cgroup_config) {
+        rollback.stage("cgroup threads", |_| {
+            degrade_cgroup_error(cgroup_config, attach_threads(cgroup))
+        })?;
+    }
 
     if !cmd_args.is_present("disable-seccomp") {
-        register_seccomp()?;
+        rollback.stage("seccomp", |_| register_seccomp())?;
     }
+    rollback.commit();
 
     loop {
         if !MainLoop::run().chain_err(|| "MainLoop exits unexpectedly: error occurs")? {
@@ -148,3 +206,40 @@ fn real_main(cmd_args: &arg_parser::ArgMatches) -> Result<()> {
 
     Ok(())
 }
+
+/// Open or create the cgroup of VM, write limits derived from config and
+/// move the process into it.
+fn setup_cgroup(cgroup_config: &CgroupConfig, vm_config: &VmConfig) -> Result<Cgroup> {
+    let cgroup = match (&cgroup_config.path, &cgroup_config.parent) {
+        (Some(path), _) => Cgroup::open(path)?,
+        (None, Some(parent)) => Cgroup::create(parent, &vm_config.guest_name)?,
+        (None, None) => bail!("No cgroup path is given"),
+    };
+    cgroup.set_limits(&vm_config.cgroup_limits()?)?;
+    attach_threads(&cgroup)?;
+    info!("Run in cgroup {}", cgroup.path().display());
+    Ok(cgroup)
+}
+
+fn attach_threads(cgroup: &Cgroup) -> Result<()> {
+    let pid = std::process::id();
+    let tids = thread_ids(std::path::Path::new("/proc"), pid)?;
+    cgroup.attach(pid, &tids)?;
+    Ok(())
+}
+
+/// Failures of cgroup, e.g. lack of permissions, are only warned unless
+/// strict mode is set.
+fn degrade_cgroup_error<T>(cgroup_config: &CgroupConfig, ret: Result<T>) -> Result<Option<T>> {
+    match ret {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if !cgroup_config.strict => {
+            warn!(
+                "Cgroup is not applied: {}",
+                error_chain::ChainedError::display_chain(&e)
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -120,3 +120,11 @@ impl LibaioContext {
         })
     }
 }
+
+impl Drop for LibaioContext {
+    fn drop(&mut self) {
+        if !self.ctx.is_null() {
+            unsafe { libc::syscall(libc::SYS_io_destroy, self.ctx) };
+        }
+    }
+}
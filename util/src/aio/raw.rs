@@ -11,7 +11,10 @@
 // See the Mulan PSL v2 for more details.
 
 use super::Result;
-use libc::{c_void, fdatasync, pread, pwrite};
+use libc::{
+    c_void, fallocate, fdatasync, pread, pwrite, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+    FALLOC_FL_ZERO_RANGE,
+};
 use std::os::unix::io::RawFd;
 
 pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> Result<i64> {
@@ -40,3 +43,69 @@ pub fn raw_datasync(fd: RawFd) -> Result<i64> {
 
     Ok(ret)
 }
+
+/// Deallocate `size` bytes at `offset` of file, which read as zero afterwards.
+pub fn raw_discard(fd: RawFd, offset: usize, size: u64) -> Result<i64> {
+    let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe { i64::from(fallocate(fd, mode, offset as i64, size as i64)) };
+    if ret < 0 {
+        bail!("Failed to punch hole for {}, return {}.", fd, ret);
+    }
+
+    Ok(ret)
+}
+
+/// Zero `size` bytes at `offset` of file without writing data.
+pub fn raw_write_zeroes(fd: RawFd, offset: usize, size: u64) -> Result<i64> {
+    let mode = FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe { i64::from(fallocate(fd, mode, offset as i64, size as i64)) };
+    if ret < 0 {
+        bail!("Failed to zero range for {}, return {}.", fd, ret);
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_raw_discard() {
+        let path = "/tmp/stratovirt-raw-discard.img";
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        file.write_all(&vec![0xa5_u8; 1 << 20]).unwrap();
+        file.sync_all().unwrap();
+        let blocks = file.metadata().unwrap().blocks();
+
+        raw_discard(file.as_raw_fd(), 0, 1 << 19).unwrap();
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.len(), 1 << 20);
+        assert!(meta.blocks() < blocks);
+
+        let mut buf = vec![0xff_u8; 512];
+        raw_read(file.as_raw_fd(), buf.as_mut_ptr() as u64, buf.len(), 4096).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        raw_read(
+            file.as_raw_fd(),
+            buf.as_mut_ptr() as u64,
+            buf.len(),
+            1 << 19,
+        )
+        .unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Atomically swapped `Arc`, whose snapshot is read without lock.
+
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Cell holding an `Arc<T>`, which is replaced as a whole by `store`.
+///
+/// Readers clone the current `Arc` wait-free, by a fixed number of atomic
+/// operations. A writer publishes the new `Arc` by swapping the pointer, then
+/// waits for readers in flight, which may still be cloning the old `Arc`,
+/// before releasing it.
+pub struct ArcSwap<T> {
+    /// Pointer got by `Arc::into_raw`, the cell owns one reference of it.
+    ptr: AtomicPtr<T>,
+    /// Number of readers between loading `ptr` and cloning the `Arc`.
+    readers: AtomicUsize,
+}
+
+// Safe because only `Arc<T>` is shared or sent by `ArcSwap`.
+unsafe impl<T: Send + Sync> Send for ArcSwap<T> {}
+unsafe impl<T: Send + Sync> Sync for ArcSwap<T> {}
+
+impl<T> ArcSwap<T> {
+    /// Create the cell holding `value`.
+    pub fn new(value: Arc<T>) -> Self {
+        ArcSwap {
+            ptr: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+            readers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the current snapshot.
+    pub fn load(&self) -> Arc<T> {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        // Safe because `store` doesn't release the `Arc` of `ptr` until this
+        // reader leaves, and the reference owned by the cell isn't dropped.
+        let current = ManuallyDrop::new(unsafe { Arc::from_raw(ptr) });
+        let snapshot = Arc::clone(&current);
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+        snapshot
+    }
+
+    /// Replace the snapshot with `value`. Readers got the old snapshot keep
+    /// it until they drop it.
+    pub fn store(&self, value: Arc<T>) {
+        let old = self
+            .ptr
+            .swap(Arc::into_raw(value) as *mut T, Ordering::SeqCst);
+        // Readers entering from now on load the new pointer.
+        while self.readers.load(Ordering::SeqCst) != 0 {
+            std::thread::yield_now();
+        }
+        // Safe because `old` is got by `Arc::into_raw`, and no reader is
+        // cloning it.
+        drop(unsafe { Arc::from_raw(old) });
+    }
+}
+
+impl<T> Drop for ArcSwap<T> {
+    fn drop(&mut self) {
+        // Safe because the cell owns one reference of `ptr`.
+        drop(unsafe { Arc::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_store() {
+        let value = Arc::new(1_u64);
+        let cell = ArcSwap::new(value.clone());
+        let first = cell.load();
+        assert_eq!(*first, 1);
+        assert_eq!(Arc::strong_count(&value), 3);
+
+        cell.store(Arc::new(2));
+        assert_eq!(*cell.load(), 2);
+        // Snapshot got before is still valid.
+        assert_eq!(*first, 1);
+        assert_eq!(Arc::strong_count(&value), 2);
+        drop(first);
+        assert_eq!(Arc::strong_count(&value), 1);
+
+        let last = Arc::new(3_u64);
+        cell.store(last.clone());
+        drop(cell);
+        assert_eq!(Arc::strong_count(&last), 1);
+    }
+
+    #[test]
+    fn test_concurrent_load_store() {
+        // Each snapshot is a vector of one repeated value, a torn or freed
+        // snapshot breaks the pattern.
+        let cell = Arc::new(ArcSwap::new(Arc::new(vec![0_u64; 64])));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = cell.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20000 {
+                        let view = cell.load();
+                        assert!(view.iter().all(|v| *v == view[0]));
+                    }
+                })
+            })
+            .collect();
+        for i in 1..2000_u64 {
+            cell.store(Arc::new(vec![i; 64]));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(*cell.load(), vec![1999_u64; 64]);
+    }
+}
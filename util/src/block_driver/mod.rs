@@ -0,0 +1,49 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Format drivers of block images, which translate IO on the virtual disk to
+//! IO on the image file.
+
+mod qcow2;
+
+pub use qcow2::Qcow2Driver;
+
+use super::aio::Iovec;
+use super::errors::Result;
+
+/// Format driver of a block image.
+///
+/// Raw images need no translation and are accessed by aio directly, images of
+/// other formats are accessed synchronously through their drivers.
+pub trait BlockDriver: Send {
+    /// Size in bytes of the virtual disk.
+    fn disk_size(&self) -> u64;
+
+    /// Read the virtual disk at `offset` into buffers of `iovec`.
+    ///
+    /// # Arguments
+    ///
+    /// * `iovec` - Buffers, which must be valid and writable.
+    /// * `offset` - Offset in bytes on the virtual disk.
+    fn read_vectored(&mut self, iovec: &[Iovec], offset: u64) -> Result<()>;
+
+    /// Write buffers of `iovec` to the virtual disk at `offset`.
+    ///
+    /// # Arguments
+    ///
+    /// * `iovec` - Buffers, which must be valid.
+    /// * `offset` - Offset in bytes on the virtual disk.
+    fn write_vectored(&mut self, iovec: &[Iovec], offset: u64) -> Result<()>;
+
+    /// Flush data and metadata written to the image file.
+    fn flush(&mut self) -> Result<()>;
+}
@@ -0,0 +1,661 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Driver of qcow2 images.
+//!
+//! Guest offsets are translated by a two-level table: L1 table entries point to
+//! L2 tables, whose entries point to data clusters. Clusters are allocated at
+//! end of the image and accounted in 16 bits refcounts.
+//!
+//! Compression, encryption, backing files and internal snapshots are not
+//! supported.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+
+use super::BlockDriver;
+use crate::aio::Iovec;
+use crate::errors::{ErrorKind, Result, ResultExt};
+
+/// Magic of qcow2 image, "QFI\xfb".
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+/// Size of header of version 3 image, excluding optional fields.
+const QCOW_HEADER_V3_SIZE: usize = 104;
+/// Minimum and maximum of cluster bits.
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Order of refcount bits, only 16 bits refcounts are supported.
+const REFCOUNT_ORDER: u32 = 4;
+/// Size of a refcount entry.
+const REFCOUNT_BYTES: u64 = 2;
+/// Mask of host cluster offset in L1, L2 and refcount table entries.
+const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Refcount of the cluster is exactly one, so it's written in place.
+const ENTRY_COPIED: u64 = 1 << 63;
+/// Data cluster is compressed.
+const L2_ENTRY_COMPRESSED: u64 = 1 << 62;
+/// Data cluster reads as zeroes.
+const L2_ENTRY_ZERO: u64 = 1;
+/// Maximum size in bytes of L1 table and refcount table, which are read in memory.
+const MAX_TABLE_SIZE: u64 = 0x0200_0000;
+/// Number of L2 tables cached.
+const L2_CACHE_SIZE: usize = 16;
+/// Names of incompatible features, indexed by feature bit.
+const INCOMPATIBLE_FEATURES: [&str; 5] = [
+    "dirty",
+    "corrupt",
+    "external_data_file",
+    "compression_type",
+    "extended_l2",
+];
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0_u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Read a table of `entries` big-endian 64 bits entries at `offset` of file.
+fn read_table(file: &File, offset: u64, entries: usize) -> Result<Vec<u64>> {
+    let mut buf = vec![0_u8; entries * 8];
+    file.read_exact_at(&mut buf, offset)
+        .chain_err(|| format!("Failed to read qcow2 table at {}", offset))?;
+    Ok((0..entries).map(|i| be_u64(&buf, i * 8)).collect())
+}
+
+/// Read `buf` at `offset` of file, the part beyond end of file reads as zeroes.
+fn read_at_zero_fill(file: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let count = file.read_at(&mut buf[done..], offset + done as u64)?;
+        if count == 0 {
+            for byte in buf[done..].iter_mut() {
+                *byte = 0;
+            }
+            break;
+        }
+        done += count;
+    }
+    Ok(())
+}
+
+/// Header of qcow2 image, fields not used are omitted.
+#[derive(Default, Clone, Copy)]
+struct QcowHeader {
+    version: u32,
+    backing_file_offset: u64,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    refcount_order: u32,
+}
+
+impl QcowHeader {
+    /// Parse header from the first `QCOW_HEADER_V3_SIZE` bytes of image.
+    fn from_buf(buf: &[u8]) -> Result<Self> {
+        if be_u32(buf, 0) != QCOW_MAGIC {
+            return Err(ErrorKind::Qcow2Unsupported("bad magic".to_string()).into());
+        }
+
+        let mut header = QcowHeader {
+            version: be_u32(buf, 4),
+            backing_file_offset: be_u64(buf, 8),
+            cluster_bits: be_u32(buf, 20),
+            size: be_u64(buf, 24),
+            crypt_method: be_u32(buf, 32),
+            l1_size: be_u32(buf, 36),
+            l1_table_offset: be_u64(buf, 40),
+            refcount_table_offset: be_u64(buf, 48),
+            refcount_table_clusters: be_u32(buf, 56),
+            nb_snapshots: be_u32(buf, 60),
+            incompatible_features: 0,
+            refcount_order: REFCOUNT_ORDER,
+        };
+        match header.version {
+            2 => {}
+            3 => {
+                header.incompatible_features = be_u64(buf, 72);
+                header.refcount_order = be_u32(buf, 96);
+            }
+            version => {
+                return Err(ErrorKind::Qcow2Unsupported(format!("version {}", version)).into());
+            }
+        }
+
+        Ok(header)
+    }
+
+    /// Check that the image can be opened by the driver.
+    fn check(&self) -> Result<()> {
+        if self.incompatible_features != 0 {
+            let features = (0..64)
+                .filter(|bit| self.incompatible_features & (1_u64 << bit) != 0)
+                .map(|bit| match INCOMPATIBLE_FEATURES.get(bit) {
+                    Some(name) => name.to_string(),
+                    None => format!("bit {}", bit),
+                })
+                .collect::<Vec<String>>();
+            return Err(ErrorKind::Qcow2IncompatibleFeatures(features.join(", ")).into());
+        }
+        if self.crypt_method != 0 {
+            return Err(ErrorKind::Qcow2Unsupported("encryption".to_string()).into());
+        }
+        if self.backing_file_offset != 0 {
+            return Err(ErrorKind::Qcow2Unsupported("backing file".to_string()).into());
+        }
+        if self.nb_snapshots != 0 {
+            return Err(ErrorKind::Qcow2Unsupported("internal snapshots".to_string()).into());
+        }
+        if self.refcount_order != REFCOUNT_ORDER {
+            return Err(ErrorKind::Qcow2Unsupported(format!(
+                "refcount bits {}",
+                1_u64 << self.refcount_order
+            ))
+            .into());
+        }
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            bail!("Invalid qcow2 cluster bits {}", self.cluster_bits);
+        }
+
+        let cluster_size = 1_u64 << self.cluster_bits;
+        if self.l1_table_offset % cluster_size != 0
+            || self.refcount_table_offset % cluster_size != 0
+        {
+            bail!("Qcow2 L1 table or refcount table is not aligned to cluster");
+        }
+        let refcount_table_size = u64::from(self.refcount_table_clusters) * cluster_size;
+        if refcount_table_size == 0 || refcount_table_size > MAX_TABLE_SIZE {
+            bail!(
+                "Invalid qcow2 refcount table of {} clusters",
+                self.refcount_table_clusters
+            );
+        }
+        if u64::from(self.l1_size) * 8 > MAX_TABLE_SIZE {
+            bail!("Invalid qcow2 L1 table of {} entries", self.l1_size);
+        }
+        // Each L1 entry covers a L2 table of `cluster_size / 8` clusters.
+        let l1_coverage = cluster_size * (cluster_size / 8);
+        let l1_entries = self.size / l1_coverage + u64::from(self.size % l1_coverage != 0);
+        if u64::from(self.l1_size) < l1_entries {
+            bail!(
+                "Qcow2 L1 table of {} entries is too small for size {}",
+                self.l1_size,
+                self.size
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Driver of a qcow2 image.
+pub struct Qcow2Driver {
+    /// The image file.
+    file: File,
+    /// Header of the image.
+    header: QcowHeader,
+    /// Size of a cluster in bytes.
+    cluster_size: u64,
+    /// L1 table, which is kept in memory.
+    l1_table: Vec<u64>,
+    /// Refcount table, which is kept in memory.
+    refcount_table: Vec<u64>,
+    /// L2 tables cached with their host offsets, most recently used first.
+    l2_cache: Vec<(u64, Vec<u64>)>,
+    /// Index of cluster where searching for free cluster starts.
+    free_cluster_hint: u64,
+}
+
+impl Qcow2Driver {
+    /// Open the qcow2 image `file`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `file` is not a qcow2 image, or the image uses features
+    /// not supported by the driver.
+    pub fn open(file: File) -> Result<Self> {
+        let mut buf = [0_u8; QCOW_HEADER_V3_SIZE];
+        file.read_exact_at(&mut buf, 0)
+            .chain_err(|| ErrorKind::Qcow2Unsupported("truncated header".to_string()))?;
+        let header = QcowHeader::from_buf(&buf)?;
+        header.check()?;
+
+        let cluster_size = 1_u64 << header.cluster_bits;
+        let l1_table = read_table(&file, header.l1_table_offset, header.l1_size as usize)?;
+        let refcount_table = read_table(
+            &file,
+            header.refcount_table_offset,
+            (u64::from(header.refcount_table_clusters) * cluster_size / 8) as usize,
+        )?;
+        let file_size = (&file).seek(SeekFrom::End(0))?;
+
+        Ok(Qcow2Driver {
+            file,
+            header,
+            cluster_size,
+            l1_table,
+            refcount_table,
+            l2_cache: Vec::with_capacity(L2_CACHE_SIZE),
+            free_cluster_hint: (file_size + cluster_size - 1) / cluster_size,
+        })
+    }
+
+    /// Indexes in L1 table and L2 table of guest `offset`.
+    fn table_indexes(&self, offset: u64) -> (usize, usize) {
+        let cluster = offset >> self.header.cluster_bits;
+        let l2_entries = self.cluster_size / 8;
+        (
+            (cluster / l2_entries) as usize,
+            (cluster % l2_entries) as usize,
+        )
+    }
+
+    /// Write a big-endian 64 bits table entry at `offset` of image.
+    fn write_entry(&self, offset: u64, entry: u64) -> Result<()> {
+        self.file
+            .write_all_at(&entry.to_be_bytes(), offset)
+            .chain_err(|| format!("Failed to write qcow2 table entry at {}", offset))
+    }
+
+    fn zero_cluster(&self, offset: u64) -> Result<()> {
+        self.file
+            .write_all_at(&vec![0_u8; self.cluster_size as usize], offset)
+            .chain_err(|| format!("Failed to zero qcow2 cluster at {}", offset))
+    }
+
+    /// Get L2 table at host `l2_offset`, through the cache.
+    fn l2_table(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>> {
+        if let Some(pos) = self.l2_cache.iter().position(|(off, _)| *off == l2_offset) {
+            let cached = self.l2_cache.remove(pos);
+            self.l2_cache.insert(0, cached);
+        } else {
+            let table = read_table(&self.file, l2_offset, (self.cluster_size / 8) as usize)?;
+            self.l2_cache.truncate(L2_CACHE_SIZE - 1);
+            self.l2_cache.insert(0, (l2_offset, table));
+        }
+        Ok(&mut self.l2_cache[0].1)
+    }
+
+    /// Host offset of refcount of `cluster`, the refcount block is allocated
+    /// if `cluster` isn't covered by any yet.
+    fn refcount_offset(&mut self, cluster: u64) -> Result<u64> {
+        let block_entries = self.cluster_size / REFCOUNT_BYTES;
+        let table_index = (cluster / block_entries) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(ErrorKind::Qcow2Unsupported("growing refcount table".to_string()).into());
+        }
+        let entry_offset = (cluster % block_entries) * REFCOUNT_BYTES;
+
+        let mut block = self.refcount_table[table_index] & ENTRY_OFFSET_MASK;
+        if block == 0 {
+            // No cluster covered by the new block is in use, so the block is put
+            // in `cluster` itself and refers to itself.
+            block = cluster * self.cluster_size;
+            self.zero_cluster(block)?;
+            self.file
+                .write_all_at(&1_u16.to_be_bytes(), block + entry_offset)
+                .chain_err(|| "Failed to write qcow2 refcount")?;
+            self.write_entry(
+                self.header.refcount_table_offset + table_index as u64 * 8,
+                block,
+            )?;
+            self.refcount_table[table_index] = block;
+        }
+
+        Ok(block + entry_offset)
+    }
+
+    /// Refcount of `cluster`.
+    fn refcount(&mut self, cluster: u64) -> Result<u16> {
+        let offset = self.refcount_offset(cluster)?;
+        let mut buf = [0_u8; REFCOUNT_BYTES as usize];
+        self.file
+            .read_exact_at(&mut buf, offset)
+            .chain_err(|| "Failed to read qcow2 refcount")?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Allocate a free cluster filled with zeroes, return its host offset.
+    fn alloc_cluster(&mut self) -> Result<u64> {
+        loop {
+            let cluster = self.free_cluster_hint;
+            self.free_cluster_hint += 1;
+            if self.refcount(cluster)? != 0 {
+                continue;
+            }
+
+            let offset = self.refcount_offset(cluster)?;
+            self.file
+                .write_all_at(&1_u16.to_be_bytes(), offset)
+                .chain_err(|| "Failed to write qcow2 refcount")?;
+            let host_offset = cluster * self.cluster_size;
+            self.zero_cluster(host_offset)?;
+            return Ok(host_offset);
+        }
+    }
+
+    /// Host offset of guest `offset`, or None if it reads as zeroes.
+    fn host_offset(&mut self, offset: u64) -> Result<Option<u64>> {
+        let (l1_index, l2_index) = self.table_indexes(offset);
+        let l2_offset = self.l1_table[l1_index] & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(None);
+        }
+
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            return Err(ErrorKind::Qcow2Unsupported("compressed cluster".to_string()).into());
+        }
+        let cluster = entry & ENTRY_OFFSET_MASK;
+        if cluster == 0 || entry & L2_ENTRY_ZERO != 0 {
+            return Ok(None);
+        }
+        Ok(Some(cluster + (offset & (self.cluster_size - 1))))
+    }
+
+    /// Host offset of guest `offset` for writing, the L2 table and the data
+    /// cluster are allocated if not yet.
+    fn alloc_host_offset(&mut self, offset: u64) -> Result<u64> {
+        let (l1_index, l2_index) = self.table_indexes(offset);
+        let mut l2_offset = self.l1_table[l1_index] & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.alloc_cluster()?;
+            let entry = l2_offset | ENTRY_COPIED;
+            self.write_entry(self.header.l1_table_offset + l1_index as u64 * 8, entry)?;
+            self.l1_table[l1_index] = entry;
+        }
+
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            return Err(ErrorKind::Qcow2Unsupported("compressed cluster".to_string()).into());
+        }
+        let mut cluster = entry & ENTRY_OFFSET_MASK;
+        if cluster == 0 || entry & L2_ENTRY_ZERO != 0 {
+            // Data beside the write should read as zeroes.
+            if cluster == 0 {
+                cluster = self.alloc_cluster()?;
+            } else {
+                self.zero_cluster(cluster)?;
+            }
+            let entry = cluster | ENTRY_COPIED;
+            self.write_entry(l2_offset + l2_index as u64 * 8, entry)?;
+            self.l2_table(l2_offset)?[l2_index] = entry;
+        }
+
+        Ok(cluster + (offset & (self.cluster_size - 1)))
+    }
+
+    fn rw_vectored(&mut self, iovec: &[Iovec], mut offset: u64, write: bool) -> Result<()> {
+        let len = iovec.iter().map(|iov| iov.iov_len).sum::<u64>();
+        if offset
+            .checked_add(len)
+            .filter(|end| *end <= self.header.size)
+            .is_none()
+        {
+            bail!(
+                "Qcow2 IO at {} of {} bytes is beyond disk size {}",
+                offset,
+                len,
+                self.header.size
+            );
+        }
+
+        for iov in iovec.iter() {
+            let mut done = 0;
+            while done < iov.iov_len {
+                let in_cluster = offset & (self.cluster_size - 1);
+                let count = cmp::min(iov.iov_len - done, self.cluster_size - in_cluster);
+                // Safe as buffers of iovec are guaranteed valid by the caller.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut((iov.iov_base + done) as *mut u8, count as usize)
+                };
+
+                if write {
+                    let host_offset = self.alloc_host_offset(offset)?;
+                    self.file
+                        .write_all_at(buf, host_offset)
+                        .chain_err(|| format!("Failed to write qcow2 data at {}", host_offset))?;
+                } else {
+                    match self.host_offset(offset)? {
+                        Some(host_offset) => read_at_zero_fill(&self.file, buf, host_offset)?,
+                        None => {
+                            for byte in buf.iter_mut() {
+                                *byte = 0;
+                            }
+                        }
+                    }
+                }
+
+                done += count;
+                offset += count;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockDriver for Qcow2Driver {
+    fn disk_size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn read_vectored(&mut self, iovec: &[Iovec], offset: u64) -> Result<()> {
+        self.rw_vectored(iovec, offset, false)
+    }
+
+    fn write_vectored(&mut self, iovec: &[Iovec], offset: u64) -> Result<()> {
+        self.rw_vectored(iovec, offset, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file
+            .sync_data()
+            .chain_err(|| "Failed to flush qcow2 image")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::*;
+
+    /// Create a version 3 image, of which header, L1 table, refcount table and
+    /// the first refcount block take a cluster each.
+    fn create_image(path: &str, cluster_bits: u32, size: u64) -> File {
+        let cluster_size = 1_u64 << cluster_bits;
+        let l1_coverage = cluster_size * (cluster_size / 8);
+
+        let mut header = vec![0_u8; QCOW_HEADER_V3_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            header[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, &QCOW_MAGIC.to_be_bytes());
+        put(4, &3_u32.to_be_bytes());
+        put(20, &cluster_bits.to_be_bytes());
+        put(24, &size.to_be_bytes());
+        put(
+            36,
+            &(((size + l1_coverage - 1) / l1_coverage) as u32).to_be_bytes(),
+        );
+        put(40, &cluster_size.to_be_bytes());
+        put(48, &(2 * cluster_size).to_be_bytes());
+        put(56, &1_u32.to_be_bytes());
+        put(96, &REFCOUNT_ORDER.to_be_bytes());
+        put(100, &(QCOW_HEADER_V3_SIZE as u32).to_be_bytes());
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        file.set_len(4 * cluster_size).unwrap();
+        file.write_all_at(&header, 0).unwrap();
+        file.write_all_at(&(3 * cluster_size).to_be_bytes(), 2 * cluster_size)
+            .unwrap();
+        for cluster in 0..4 {
+            file.write_all_at(&1_u16.to_be_bytes(), 3 * cluster_size + cluster * 2)
+                .unwrap();
+        }
+        file
+    }
+
+    fn reopen(path: &str) -> Qcow2Driver {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        Qcow2Driver::open(file).unwrap()
+    }
+
+    fn iovec_of(buf: &mut [u8], lens: &[usize]) -> Vec<Iovec> {
+        let mut iovec = Vec::new();
+        let mut start = 0;
+        for len in lens.iter().chain(&[buf.len() - lens.iter().sum::<usize>()]) {
+            iovec.push(Iovec {
+                iov_base: buf[start..].as_mut_ptr() as u64,
+                iov_len: *len as u64,
+            });
+            start += len;
+        }
+        iovec
+    }
+
+    fn read(driver: &mut Qcow2Driver, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0xff_u8; len];
+        driver
+            .read_vectored(&iovec_of(&mut buf, &[]), offset)
+            .unwrap();
+        buf
+    }
+
+    fn write(driver: &mut Qcow2Driver, offset: u64, data: &[u8]) {
+        let mut buf = data.to_vec();
+        driver
+            .write_vectored(&iovec_of(&mut buf, &[]), offset)
+            .unwrap();
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_qcow2_read_write() {
+        let path = "/tmp/stratovirt-qcow2-rw.img";
+        let mut driver = Qcow2Driver::open(create_image(path, 9, 1 << 20)).unwrap();
+        assert_eq!(driver.disk_size(), 1 << 20);
+        assert_eq!(read(&mut driver, 0, 4096), vec![0_u8; 4096]);
+
+        // The write crosses clusters and L2 tables.
+        let offset = (32 << 10) - 700;
+        let mut data = pattern(3000);
+        write(&mut driver, offset, &data);
+        assert_eq!(read(&mut driver, offset, data.len()), data);
+        assert_eq!(read(&mut driver, offset - 512, 512), vec![0_u8; 512]);
+        assert_ne!(driver.l1_table[0] & ENTRY_OFFSET_MASK, 0);
+        assert_ne!(driver.l1_table[1] & ENTRY_OFFSET_MASK, 0);
+        assert_eq!(driver.l1_table[2], 0);
+        let size = driver.file.metadata().unwrap().len();
+        assert!(size > 4 * 512);
+
+        // Allocated clusters are written in place.
+        write(&mut driver, offset + 100, &[0x5a_u8; 512]);
+        data[100..612].copy_from_slice(&[0x5a_u8; 512]);
+        assert_eq!(read(&mut driver, offset, data.len()), data);
+        assert_eq!(driver.file.metadata().unwrap().len(), size);
+
+        let disk_size = driver.disk_size();
+        let mut buf = vec![0_u8; 512];
+        assert!(driver
+            .write_vectored(&iovec_of(&mut buf, &[]), disk_size - 256)
+            .is_err());
+        assert!(driver.flush().is_ok());
+
+        let mut driver = reopen(path);
+        assert_eq!(read(&mut driver, offset, data.len()), data);
+        assert_eq!(read(&mut driver, 64 << 10, 512), vec![0_u8; 512]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_alloc_refcount_block() {
+        let path = "/tmp/stratovirt-qcow2-refcount.img";
+        let mut driver = Qcow2Driver::open(create_image(path, 9, 1 << 20)).unwrap();
+
+        // A refcount block covers 256 clusters, the write allocates more.
+        let offset = 4096;
+        let mut data = pattern(256 << 10);
+        driver
+            .write_vectored(&iovec_of(&mut data, &[1000, 100_000]), offset)
+            .unwrap();
+        assert_ne!(driver.refcount_table[1], 0);
+
+        let mut driver = reopen(path);
+        let mut buf = vec![0_u8; data.len()];
+        driver
+            .read_vectored(&iovec_of(&mut buf, &[512, 77, 30_000]), offset)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        // Every cluster of the image is in use and referred once.
+        let clusters = driver.file.metadata().unwrap().len() / driver.cluster_size;
+        assert!(clusters > 512);
+        for cluster in 0..clusters {
+            assert_eq!(driver.refcount(cluster).unwrap(), 1);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_unsupported_image() {
+        let path = "/tmp/stratovirt-qcow2-unsupported.img";
+        let open_err = |offset: u64, bytes: &[u8]| {
+            let file = create_image(path, 16, 1 << 30);
+            file.write_all_at(bytes, offset).unwrap();
+            Qcow2Driver::open(file).err().unwrap().to_string()
+        };
+
+        let incompatible_features = (1_u64 << 0) | (1_u64 << 4) | (1_u64 << 9);
+        assert_eq!(
+            open_err(72, &incompatible_features.to_be_bytes()),
+            "Unsupported qcow2 incompatible features: dirty, extended_l2, bit 9"
+        );
+        assert!(open_err(0, b"QFI\x00").contains("bad magic"));
+        assert!(open_err(8, &4096_u64.to_be_bytes()).contains("backing file"));
+        assert!(open_err(60, &1_u32.to_be_bytes()).contains("internal snapshots"));
+        assert!(open_err(96, &5_u32.to_be_bytes()).contains("refcount bits 32"));
+        assert!(open_err(36, &0_u32.to_be_bytes()).contains("too small"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! This module implements a collector of boot-time milestones.
+
+use std::time::{Duration, Instant};
+
+/// Time taken by each boot step, measured between consecutive marks.
+///
+/// # Examples
+///
+/// ```rust
+/// extern crate util;
+/// use util::boot_timeline::BootTimeline;
+///
+/// let mut timeline = BootTimeline::start();
+/// // Parse kernel header...
+/// timeline.mark("kernel probe");
+/// // Build page tables...
+/// timeline.mark("page tables");
+/// assert_eq!(timeline.marks().len(), 2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BootTimeline {
+    start: Instant,
+    last: Instant,
+    marks: Vec<(&'static str, Duration)>,
+}
+
+impl BootTimeline {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        BootTimeline {
+            start: now,
+            last: now,
+            marks: Vec::new(),
+        }
+    }
+
+    /// Record that step `name` is done, taking the time since last mark.
+    pub fn mark(&mut self, name: &'static str) {
+        let now = Instant::now();
+        let delta = now.duration_since(self.last);
+        self.last = now;
+        info!("Boot step {} took {:?}", name, delta);
+        self.marks.push((name, delta));
+    }
+
+    /// Recorded steps with their durations, in order.
+    pub fn marks(&self) -> &[(&'static str, Duration)] {
+        &self.marks
+    }
+
+    /// Time from `start` to the last mark.
+    pub fn total(&self) -> Duration {
+        self.last.duration_since(self.start)
+    }
+}
+
+/// Mark step `name` on `timeline` if it's supplied, do nothing otherwise.
+pub fn mark(timeline: &mut Option<&mut BootTimeline>, name: &'static str) {
+    if let Some(timeline) = timeline {
+        timeline.mark(name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_boot_timeline() {
+        let mut timeline = BootTimeline::start();
+        timeline.mark("first");
+        std::thread::sleep(Duration::from_millis(2));
+        timeline.mark("second");
+
+        let marks = timeline.marks();
+        assert_eq!(marks.len(), 2);
+        assert_eq!((marks[0].0, marks[1].0), ("first", "second"));
+        assert!(marks[1].1 >= Duration::from_millis(2));
+        assert_eq!(timeline.total(), marks[0].1 + marks[1].1);
+
+        let mut supplied = Some(&mut timeline);
+        mark(&mut supplied, "third");
+        assert_eq!(timeline.marks().len(), 3);
+
+        let mut none: Option<&mut BootTimeline> = None;
+        mark(&mut none, "ignored");
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Place the VMM into a cgroup v2 hierarchy with resource limits.
+//!
+//! The cgroup is either pre-created by the orchestrator, or created as a
+//! child of a given parent and removed when `Cgroup` drops, or by `remove`
+//! if the process exits without dropping it.
+
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::errors::{ErrorKind, Result, ResultExt};
+
+/// Default period of `cpu.max` in microseconds.
+pub const CPU_PERIOD_US: u64 = 100_000;
+
+/// Limit of a block device in `io.max`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoLimit {
+    /// Major number of block device.
+    pub major: u64,
+    /// Minor number of block device.
+    pub minor: u64,
+    /// Read and write IO per second.
+    pub iops: Option<u64>,
+    /// Read and write bytes per second.
+    pub bps: Option<u64>,
+}
+
+impl IoLimit {
+    /// Build `IoLimit` for the block device backing `path`, which is either
+    /// a block device itself or a file on it.
+    pub fn for_path(path: &str, iops: Option<u64>, bps: Option<u64>) -> Result<Self> {
+        let meta = fs::metadata(path).chain_err(|| format!("Failed to stat {}", path))?;
+        let dev = if meta.file_type().is_block_device() {
+            meta.rdev()
+        } else {
+            meta.dev()
+        };
+        Ok(IoLimit {
+            major: ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff),
+            minor: (dev & 0xff) | ((dev >> 12) & !0xff),
+            iops,
+            bps,
+        })
+    }
+
+    /// Line written to `io.max`.
+    fn to_line(&self) -> String {
+        let mut line = format!("{}:{}", self.major, self.minor);
+        if let Some(iops) = self.iops {
+            line += &format!(" riops={} wiops={}", iops, iops);
+        }
+        if let Some(bps) = self.bps {
+            line += &format!(" rbps={} wbps={}", bps, bps);
+        }
+        line
+    }
+}
+
+/// Resource limits written to cgroup interface files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// Cpu time in microseconds per `CPU_PERIOD_US`.
+    pub cpu_quota_us: Option<u64>,
+    /// Memory limit in bytes.
+    pub memory_max: Option<u64>,
+    /// Limits of block devices.
+    pub io: Vec<IoLimit>,
+}
+
+/// A cgroup v2 directory.
+pub struct Cgroup {
+    /// Path of cgroup directory.
+    path: PathBuf,
+    /// The cgroup is created by us and should be removed.
+    created: AtomicBool,
+}
+
+impl Cgroup {
+    /// Open a cgroup pre-created by the orchestrator.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of cgroup directory.
+    pub fn open(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        if !path.join("cgroup.procs").is_file() {
+            return Err(ErrorKind::CgroupNotFound(path.display().to_string()).into());
+        }
+        Ok(Cgroup {
+            path,
+            created: AtomicBool::new(false),
+        })
+    }
+
+    /// Create a child cgroup named after the VM under `parent`.
+    ///
+    /// # Arguments
+    ///
+    /// * `parent` - Path of parent cgroup directory.
+    /// * `name` - Name of VM.
+    pub fn create(parent: &str, name: &str) -> Result<Self> {
+        if !Path::new(parent).join("cgroup.procs").is_file() {
+            return Err(ErrorKind::CgroupNotFound(parent.to_string()).into());
+        }
+        let path = Self::child_path(parent, name);
+        fs::create_dir(&path)
+            .chain_err(|| format!("Failed to create cgroup {}", path.display()))?;
+        Ok(Cgroup {
+            path,
+            created: AtomicBool::new(true),
+        })
+    }
+
+    /// Path of child cgroup for VM `name` under `parent`. `/` in name is
+    /// replaced to keep the child directly under parent.
+    pub fn child_path(parent: &str, name: &str) -> PathBuf {
+        let name = name.replace('/', "_");
+        let name = match name.as_str() {
+            "" | "." | ".." => "stratovirt".to_string(),
+            _ => name,
+        };
+        Path::new(parent).join(name)
+    }
+
+    /// Path of cgroup directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        let file_path = self.path.join(file);
+        fs::write(&file_path, value).chain_err(|| {
+            ErrorKind::CgroupWrite(value.to_string(), file_path.display().to_string())
+        })
+    }
+
+    /// Write `limits` to `cpu.max`, `memory.max` and `io.max`.
+    pub fn set_limits(&self, limits: &CgroupLimits) -> Result<()> {
+        if let Some(quota) = limits.cpu_quota_us {
+            self.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_US))?;
+        }
+        if let Some(memory_max) = limits.memory_max {
+            self.write("memory.max", &memory_max.to_string())?;
+        }
+        for io in limits.io.iter() {
+            self.write("io.max", &io.to_line())?;
+        }
+        Ok(())
+    }
+
+    /// Move threads `tids` into this cgroup.
+    ///
+    /// Threads can only be moved alone in a threaded cgroup, otherwise the
+    /// whole process `pid` is moved.
+    pub fn attach(&self, pid: u32, tids: &[u64]) -> Result<()> {
+        let threaded = fs::read_to_string(self.path.join("cgroup.type"))
+            .map(|t| t.trim() == "threaded")
+            .unwrap_or(false);
+        if threaded {
+            for tid in tids {
+                self.write("cgroup.threads", &tid.to_string())?;
+            }
+            Ok(())
+        } else {
+            self.write("cgroup.procs", &pid.to_string())
+        }
+    }
+
+    /// Remove the cgroup created by us, only the first call takes effect.
+    /// The process is moved back to the parent first, as a cgroup with
+    /// processes in it can't be removed.
+    pub fn remove(&self) {
+        if !self.created.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::write(parent.join("cgroup.procs"), std::process::id().to_string());
+        }
+        if let Err(e) = fs::remove_dir(&self.path) {
+            error!("Failed to remove cgroup {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Get ids of all threads in process `pid`.
+///
+/// # Arguments
+///
+/// * `proc_root` - Mount point of procfs, usually `/proc`.
+/// * `pid` - Process id.
+pub fn thread_ids(proc_root: &Path, pid: u32) -> Result<Vec<u64>> {
+    let task_dir = proc_root.join(pid.to_string()).join("task");
+    let mut tids = Vec::new();
+    for entry in
+        fs::read_dir(&task_dir).chain_err(|| format!("Failed to read {}", task_dir.display()))?
+    {
+        let entry = entry?;
+        if let Some(tid) = entry.file_name().to_str().and_then(|t| t.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+    tids.sort();
+    Ok(tids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fake cgroupfs directory with interface files of a cgroup.
+    fn fake_cgroup(tag: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("stratovirt_cgroup_{}_{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("cgroup.procs"), "").unwrap();
+        root
+    }
+
+    #[test]
+    fn test_child_path() {
+        assert_eq!(
+            Cgroup::child_path("/sys/fs/cgroup/vms", "vm1"),
+            PathBuf::from("/sys/fs/cgroup/vms/vm1")
+        );
+        assert_eq!(
+            Cgroup::child_path("/sys/fs/cgroup/vms", "../vm1"),
+            PathBuf::from("/sys/fs/cgroup/vms/.._vm1")
+        );
+        assert_eq!(
+            Cgroup::child_path("/sys/fs/cgroup/vms", ".."),
+            PathBuf::from("/sys/fs/cgroup/vms/stratovirt")
+        );
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let root = fake_cgroup("limits");
+        let cgroup = Cgroup::open(root.to_str().unwrap()).unwrap();
+        let limits = CgroupLimits {
+            cpu_quota_us: Some(2 * CPU_PERIOD_US),
+            memory_max: Some(1 << 30),
+            io: vec![IoLimit {
+                major: 8,
+                minor: 16,
+                iops: Some(1000),
+                bps: None,
+            }],
+        };
+        cgroup.set_limits(&limits).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("cpu.max")).unwrap(),
+            "200000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("memory.max")).unwrap(),
+            "1073741824"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("io.max")).unwrap(),
+            "8:16 riops=1000 wiops=1000"
+        );
+
+        drop(cgroup);
+        // Pre-created cgroup is kept.
+        assert!(root.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let root = fake_cgroup("create");
+        assert!(Cgroup::open(root.join("vm1").to_str().unwrap()).is_err());
+        assert!(Cgroup::create(root.join("none").to_str().unwrap(), "vm1").is_err());
+
+        let cgroup = Cgroup::create(root.to_str().unwrap(), "vm1").unwrap();
+        assert_eq!(cgroup.path(), root.join("vm1").as_path());
+        cgroup.attach(42, &[42, 43]).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("vm1").join("cgroup.procs")).unwrap(),
+            "42"
+        );
+        // Interface files are removed with the directory in real cgroupfs.
+        fs::remove_file(root.join("vm1").join("cgroup.procs")).unwrap();
+
+        cgroup.remove();
+        assert!(!root.join("vm1").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("cgroup.procs")).unwrap(),
+            std::process::id().to_string()
+        );
+        // Removed already, a new cgroup of the same name is left alone.
+        fs::create_dir(root.join("vm1")).unwrap();
+        drop(cgroup);
+        assert!(root.join("vm1").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_attach_threaded() {
+        let root = fake_cgroup("threaded");
+        fs::write(root.join("cgroup.type"), "threaded\n").unwrap();
+        let cgroup = Cgroup::open(root.to_str().unwrap()).unwrap();
+        cgroup.attach(42, &[42, 43]).unwrap();
+        // Fake file keeps the last write only.
+        assert_eq!(
+            fs::read_to_string(root.join("cgroup.threads")).unwrap(),
+            "43"
+        );
+        assert_eq!(fs::read_to_string(root.join("cgroup.procs")).unwrap(), "");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_thread_ids() {
+        let root = fake_cgroup("proc");
+        for tid in &["100", "102", "101"] {
+            fs::create_dir_all(root.join("100").join("task").join(tid)).unwrap();
+        }
+        assert_eq!(thread_ids(&root, 100).unwrap(), vec![100, 101, 102]);
+        assert!(thread_ids(&root, 200).is_err());
+        fs::remove_dir_all(&root).unwrap();
+
+        let tids = thread_ids(Path::new("/proc"), std::process::id()).unwrap();
+        assert!(tids.contains(&crate::unix::gettid()));
+    }
+
+    #[test]
+    fn test_io_limit_for_path() {
+        let io = IoLimit::for_path("/proc/self/exe", None, Some(1 << 20)).unwrap();
+        assert_eq!(
+            io.to_line(),
+            format!("{}:{} rbps=1048576 wbps=1048576", io.major, io.minor)
+        );
+        assert!(IoLimit::for_path("/nonexistent", None, None).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! This module implements a builder of kernel command line.
+
+use std::fmt;
+
+use crate::errors::{ErrorKind, Result};
+
+/// Kernel command line, a list of `key=value` params and flags.
+///
+/// # Examples
+///
+/// ```rust
+/// extern crate util;
+/// use util::kernel_cmdline::KernelCmdline;
+///
+/// let mut cmdline = KernelCmdline::from("reboot=k panic=1");
+/// cmdline.push("console", "ttyS0").unwrap();
+/// cmdline.push_flag("quiet").unwrap();
+/// assert!(cmdline.push("console", "hvc0").is_err());
+/// assert_eq!(cmdline.to_string(), "reboot=k panic=1 console=ttyS0 quiet");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KernelCmdline {
+    params: Vec<String>,
+}
+
+impl KernelCmdline {
+    pub fn new() -> Self {
+        KernelCmdline { params: Vec::new() }
+    }
+
+    /// Append param `key=value`.
+    ///
+    /// # Errors
+    /// * `CmdlineNul`: `key` or `value` has NUL in it.
+    /// * `CmdlineDuplicate`: `key` is set already.
+    pub fn push(&mut self, key: &str, value: &str) -> Result<()> {
+        self.push_param(key, format!("{}={}", key, value))
+    }
+
+    /// Append flag, a param without value.
+    ///
+    /// # Errors
+    /// * `CmdlineNul`: `flag` has NUL in it.
+    /// * `CmdlineDuplicate`: `flag` is set already.
+    pub fn push_flag(&mut self, flag: &str) -> Result<()> {
+        self.push_param(flag, flag.to_string())
+    }
+
+    fn push_param(&mut self, key: &str, param: String) -> Result<()> {
+        if param.contains('\0') {
+            return Err(ErrorKind::CmdlineNul(param).into());
+        }
+        if self.contains(key) {
+            return Err(ErrorKind::CmdlineDuplicate(key.to_string()).into());
+        }
+        self.params.push(param);
+        Ok(())
+    }
+
+    /// Check whether param `key`, with value or not, is set.
+    pub fn contains(&self, key: &str) -> bool {
+        self.params.iter().any(|param| {
+            param == key || (param.starts_with(key) && param[key.len()..].starts_with('='))
+        })
+    }
+
+    /// Length of the joined cmdline, excluding the terminating NUL.
+    pub fn len(&self) -> usize {
+        let chars: usize = self.params.iter().map(|param| param.len()).sum();
+        chars + self.params.len().saturating_sub(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    /// NUL-terminated cmdline to be written to guest memory.
+    ///
+    /// # Arguments
+    /// * `max_len` - Max length kernel accepts, excluding the terminating NUL.
+    ///
+    /// # Errors
+    /// * `CmdlineNul`: A param has NUL in it.
+    /// * `CmdlineOverflow`: Cmdline is longer than `max_len`.
+    pub fn to_bytes_with_nul(&self, max_len: usize) -> Result<Vec<u8>> {
+        if let Some(param) = self.params.iter().find(|param| param.contains('\0')) {
+            return Err(ErrorKind::CmdlineNul(param.clone()).into());
+        }
+        if self.len() > max_len {
+            return Err(ErrorKind::CmdlineOverflow(self.len(), max_len).into());
+        }
+        let mut bytes = self.to_string().into_bytes();
+        bytes.push(0);
+        Ok(bytes)
+    }
+}
+
+impl From<&str> for KernelCmdline {
+    /// Split `cmdline` by whitespace, params are kept as they are.
+    fn from(cmdline: &str) -> Self {
+        KernelCmdline {
+            params: cmdline.split_whitespace().map(String::from).collect(),
+        }
+    }
+}
+
+impl From<String> for KernelCmdline {
+    fn from(cmdline: String) -> Self {
+        KernelCmdline::from(cmdline.as_str())
+    }
+}
+
+impl fmt::Display for KernelCmdline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.params.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::Error;
+
+    #[test]
+    fn test_cmdline_round_trip() {
+        let text = "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0";
+        let cmdline = KernelCmdline::from(text);
+        assert_eq!(cmdline.to_string(), text);
+        assert_eq!(cmdline.len(), text.len());
+        assert_eq!(KernelCmdline::from(cmdline.to_string()), cmdline);
+
+        // Whitespace collapses to single spaces.
+        let cmdline = KernelCmdline::from("  console=ttyS0 \t quiet \n");
+        assert_eq!(cmdline.to_string(), "console=ttyS0 quiet");
+        assert_eq!(cmdline.len(), 19);
+
+        let mut cmdline = KernelCmdline::new();
+        assert!(cmdline.is_empty());
+        assert_eq!(cmdline.len(), 0);
+        cmdline.push("console", "ttyS0").unwrap();
+        cmdline.push_flag("quiet").unwrap();
+        cmdline.push("root", "/dev/vda").unwrap();
+        assert_eq!(cmdline.to_string(), "console=ttyS0 quiet root=/dev/vda");
+        assert_eq!(
+            cmdline.to_bytes_with_nul(64).unwrap(),
+            b"console=ttyS0 quiet root=/dev/vda\0".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_cmdline_duplicate() {
+        let mut cmdline = KernelCmdline::from("console=ttyS1 rw 8250.nr_uarts=0");
+        assert!(cmdline.contains("console"));
+        assert!(cmdline.contains("rw"));
+        assert!(cmdline.contains("8250.nr_uarts"));
+        assert!(!cmdline.contains("consol"));
+        assert!(!cmdline.contains("ro"));
+
+        match cmdline.push("console", "ttyS0") {
+            Err(Error(ErrorKind::CmdlineDuplicate(key), _)) => assert_eq!(key, "console"),
+            _ => panic!("Duplicate console= should be rejected"),
+        }
+        match cmdline.push_flag("rw") {
+            Err(Error(ErrorKind::CmdlineDuplicate(key), _)) => assert_eq!(key, "rw"),
+            _ => panic!("Duplicate flag rw should be rejected"),
+        }
+        cmdline.push("consoleblank", "0").unwrap();
+        assert_eq!(
+            cmdline.to_string(),
+            "console=ttyS1 rw 8250.nr_uarts=0 consoleblank=0"
+        );
+    }
+
+    #[test]
+    fn test_cmdline_invalid() {
+        let mut cmdline = KernelCmdline::new();
+        assert!(cmdline.push("init", "/sbin/init\0").is_err());
+        assert!(cmdline.push_flag("qu\0iet").is_err());
+        assert!(cmdline.is_empty());
+
+        let cmdline = KernelCmdline::from("panic=1 a\0b");
+        match cmdline.to_bytes_with_nul(64) {
+            Err(Error(ErrorKind::CmdlineNul(param), _)) => assert_eq!(param, "a\0b"),
+            _ => panic!("Cmdline with NUL should be rejected"),
+        }
+
+        let cmdline = KernelCmdline::from("a".repeat(65));
+        assert_eq!(cmdline.to_bytes_with_nul(65).unwrap().len(), 66);
+        match cmdline.to_bytes_with_nul(64) {
+            Err(Error(ErrorKind::CmdlineOverflow(len, max), _)) => assert_eq!((len, max), (65, 64)),
+            _ => panic!("Cmdline longer than max should be rejected"),
+        }
+    }
+}
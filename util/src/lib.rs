@@ -21,15 +21,24 @@ extern crate kvm_bindings;
 extern crate kvm_ioctls;
 
 pub mod aio;
+pub mod arc_swap;
 pub mod arg_parser;
+pub mod block_driver;
+pub mod boot_timeline;
 pub mod byte_code;
+pub mod cgroup;
 pub mod checksum;
 pub mod daemonize;
 pub mod device_tree;
 pub mod epoll_context;
+pub mod kernel_cmdline;
 mod link_list;
 pub mod num_ops;
+pub mod numa;
+pub mod rate_limiter;
+pub mod rollback;
 pub mod seccomp;
+pub mod sha256;
 pub mod tap;
 pub mod unix;
 #[macro_use]
@@ -124,6 +133,42 @@ pub mod errors {
                 description("Chmod command failed.")
                 display("Chmod command failed, os error {}", e)
             }
+            // cgroup submodule error
+            CgroupNotFound(path: String) {
+                description("Cgroup directory is not found.")
+                display("Cgroup v2 directory {} is not found.", path)
+            }
+            CgroupWrite(value: String, file: String) {
+                description("Failed to write cgroup interface file.")
+                display("Failed to write '{}' to {}.", value, file)
+            }
+            // kernel_cmdline submodule error
+            CmdlineNul(param: String) {
+                description("Kernel cmdline param has NUL in it.")
+                display("Kernel cmdline param {:?} has NUL in it.", param)
+            }
+            CmdlineDuplicate(key: String) {
+                description("Kernel cmdline param is set more than once.")
+                display("Kernel cmdline param '{}' is set already.", key)
+            }
+            CmdlineOverflow(len: usize, max: usize) {
+                description("Kernel cmdline is too long.")
+                display("Kernel cmdline length {} exceeds max length {}.", len, max)
+            }
+            // block_driver submodule error
+            Qcow2Unsupported(what: String) {
+                description("Qcow2 image uses feature not supported.")
+                display("Unsupported qcow2 image: {}", what)
+            }
+            Qcow2IncompatibleFeatures(features: String) {
+                description("Qcow2 image has incompatible features not supported.")
+                display("Unsupported qcow2 incompatible features: {}", features)
+            }
+            // rollback submodule error
+            BringUpStage(stage: String) {
+                description("Bring-up stage failed.")
+                display("Failed at bring-up stage '{}'", stage)
+            }
         }
     }
 }
@@ -0,0 +1,112 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Bind memory to host NUMA nodes.
+
+use crate::errors::{Result, ResultExt};
+
+/// Maximum number of host NUMA nodes.
+pub const MAX_NUMA_NODES: u32 = 1024;
+
+/// Memory policy which allocates memory only from the given nodes.
+const MPOL_BIND: libc::c_int = 2;
+
+/// Bits in one word of nodemask.
+const MASK_WORD_BITS: u32 = (std::mem::size_of::<libc::c_ulong>() * 8) as u32;
+
+/// Build nodemask of `nodes` for mbind, bit `n` is set for node `n`.
+///
+/// # Arguments
+///
+/// * `nodes` - Host NUMA node ids, less than `MAX_NUMA_NODES`.
+///
+/// # Errors
+///
+/// Return Error if `nodes` is empty or any node id is out of range.
+pub fn nodemask(nodes: &[u32]) -> Result<Vec<libc::c_ulong>> {
+    let max_node = match nodes.iter().max() {
+        Some(node) => *node,
+        None => bail!("No host NUMA node given"),
+    };
+    if max_node >= MAX_NUMA_NODES {
+        bail!(
+            "Invalid host NUMA node {}, should be less than {}",
+            max_node,
+            MAX_NUMA_NODES
+        );
+    }
+
+    let mut mask = vec![0 as libc::c_ulong; (max_node / MASK_WORD_BITS + 1) as usize];
+    for node in nodes {
+        mask[(node / MASK_WORD_BITS) as usize] |= 1 << (node % MASK_WORD_BITS);
+    }
+    Ok(mask)
+}
+
+/// Bind memory of `len` bytes at `addr` to host NUMA `nodes`, pages which
+/// are not faulted in yet are allocated from these nodes only.
+///
+/// # Arguments
+///
+/// * `addr` - Start host address of memory, aligned to page size.
+/// * `len` - Length of memory.
+/// * `nodes` - Host NUMA node ids.
+///
+/// # Errors
+///
+/// Return Error if nodemask is invalid or the syscall fails.
+pub fn mbind(addr: u64, len: u64, nodes: &[u32]) -> Result<()> {
+    let mask = nodemask(nodes)?;
+    // Kernel reads `maxnode - 1` bits of mask.
+    let maxnode = mask.len() as u64 * u64::from(MASK_WORD_BITS) + 1;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr as *mut libc::c_void,
+            len,
+            MPOL_BIND,
+            mask.as_ptr(),
+            maxnode,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error())
+            .chain_err(|| format!("Failed to bind memory to host NUMA nodes {:?}", nodes));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodemask() {
+        assert_eq!(nodemask(&[0]).unwrap(), vec![0x1]);
+        assert_eq!(nodemask(&[1, 3]).unwrap(), vec![0xa]);
+        assert_eq!(nodemask(&[63]).unwrap(), vec![1 << 63]);
+        assert_eq!(nodemask(&[64]).unwrap(), vec![0, 1]);
+        assert_eq!(nodemask(&[0, 65]).unwrap(), vec![1, 2]);
+
+        let mask = nodemask(&[0, 512, 1023]).unwrap();
+        assert_eq!(mask.len(), 16);
+        assert_eq!(mask[0], 1);
+        assert_eq!(mask[8], 1);
+        assert_eq!(mask[15], 1 << 63);
+        assert!(mask[1..8].iter().all(|word| *word == 0));
+
+        assert!(nodemask(&[]).is_err());
+        assert!(nodemask(&[1024]).is_err());
+        assert!(nodemask(&[0, 4096]).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Token bucket rate limiter, which limits bandwidth and operation rate of
+//! device IO.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Bucket refilled with `rate` tokens per second, holding tokens of one
+/// second at most.
+///
+/// Budget is kept in units of 1/10^9 token, so refilling by elapsed
+/// nanoseconds loses no fraction of token. A request larger than the capacity
+/// is allowed once the bucket is full, which leaves the bucket in debt.
+pub struct TokenBucket {
+    /// Tokens refilled per second.
+    rate: u64,
+    /// Budget in 1/10^9 token, negative if the bucket is in debt.
+    budget: i128,
+    /// Time of last refill.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Tokens refilled per second, must not be 0.
+    /// * `now` - Current time.
+    pub fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            budget: i128::from(rate) * NANOS_PER_SEC,
+            last_refill: now,
+        }
+    }
+
+    fn capacity(&self) -> i128 {
+        i128::from(self.rate) * NANOS_PER_SEC
+    }
+
+    /// Refill the bucket with tokens for time elapsed since last refill.
+    pub fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = cmp::min((now - self.last_refill).as_nanos(), u128::from(u64::MAX));
+        self.last_refill = now;
+
+        let tokens = (elapsed as i128).saturating_mul(i128::from(self.rate));
+        self.budget = cmp::min(self.budget.saturating_add(tokens), self.capacity());
+    }
+
+    /// Time to wait until `tokens` can be consumed, zero if they can be now.
+    pub fn wait_time(&self, tokens: u64) -> Duration {
+        let need = i128::from(cmp::min(tokens, self.rate)) * NANOS_PER_SEC;
+        if self.budget >= need {
+            return Duration::from_nanos(0);
+        }
+
+        // Budget grows by `rate` per nanosecond.
+        let rate = i128::from(self.rate);
+        let nanos = (need - self.budget + rate - 1) / rate;
+        Duration::from_nanos(cmp::min(nanos, i128::from(u64::MAX)) as u64)
+    }
+
+    /// Whole tokens in the bucket, zero if the bucket is in debt.
+    pub fn available(&self) -> u64 {
+        cmp::max(self.budget / NANOS_PER_SEC, 0) as u64
+    }
+
+    /// Consume `tokens`, which may leave the bucket in debt.
+    pub fn consume(&mut self, tokens: u64) {
+        self.budget = self
+            .budget
+            .saturating_sub(i128::from(tokens) * NANOS_PER_SEC);
+    }
+}
+
+/// Rate limiter of bandwidth and operations of IO.
+#[derive(Default)]
+pub struct RateLimiter {
+    bytes: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter with full budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `bps` - Bytes per second, 0 means unlimited.
+    /// * `ops` - Operations per second, 0 means unlimited.
+    /// * `now` - Current time.
+    pub fn new(bps: u64, ops: u64, now: Instant) -> Self {
+        let bucket = |rate| {
+            if rate == 0 {
+                None
+            } else {
+                Some(TokenBucket::new(rate, now))
+            }
+        };
+        RateLimiter {
+            bytes: bucket(bps),
+            ops: bucket(ops),
+        }
+    }
+
+    /// Neither bandwidth nor operations is limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes.is_none() && self.ops.is_none()
+    }
+
+    /// Refill budget up to `now`, and return time to wait until an operation
+    /// of `bytes` can be consumed, zero if it can be now.
+    pub fn wait_time(&mut self, bytes: u64, now: Instant) -> Duration {
+        let mut wait = Duration::from_nanos(0);
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.refill(now);
+            wait = cmp::max(wait, bucket.wait_time(bytes));
+        }
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.refill(now);
+            wait = cmp::max(wait, bucket.wait_time(1));
+        }
+        wait
+    }
+
+    /// Consume budget of an operation of `bytes`.
+    pub fn consume(&mut self, bytes: u64) {
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.consume(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert_eq!(bucket.wait_time(1000), Duration::from_nanos(0));
+
+        assert_eq!(bucket.available(), 1000);
+
+        bucket.consume(1000);
+        assert_eq!(bucket.available(), 0);
+        // 1000 tokens per second refills a token per millisecond.
+        assert_eq!(bucket.wait_time(1), Duration::from_millis(1));
+        assert_eq!(bucket.wait_time(500), Duration::from_millis(500));
+
+        bucket.refill(start + Duration::from_millis(250));
+        assert_eq!(bucket.wait_time(250), Duration::from_nanos(0));
+        assert_eq!(bucket.wait_time(300), Duration::from_millis(50));
+
+        // Partial tokens are kept in budget.
+        bucket.refill(start + Duration::from_micros(250_500));
+        bucket.consume(250);
+        assert_eq!(bucket.wait_time(1), Duration::from_micros(500));
+
+        // Budget is capped at capacity of one second.
+        bucket.refill(start + Duration::from_secs(10));
+        bucket.consume(1000);
+        assert_eq!(bucket.wait_time(1), Duration::from_millis(1));
+
+        // Refilling with time earlier than last refill adds nothing.
+        bucket.refill(start);
+        assert_eq!(bucket.wait_time(1), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_token_bucket_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, start);
+
+        // Request larger than capacity is allowed with full bucket.
+        assert_eq!(bucket.wait_time(300), Duration::from_nanos(0));
+        bucket.consume(300);
+        assert_eq!(bucket.available(), 0);
+        // Debt of 200 tokens is paid before next request.
+        assert_eq!(bucket.wait_time(1), Duration::from_millis(2010));
+        // Waiting for request larger than capacity ends with full bucket.
+        assert_eq!(bucket.wait_time(300), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_rate_limiter_deferral() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(0, 0, start);
+        assert!(limiter.is_unlimited());
+        assert_eq!(limiter.wait_time(1 << 30, start), Duration::from_nanos(0));
+
+        // Requests of 4096 bytes are limited by 16384 bytes per second.
+        let mut limiter = RateLimiter::new(16384, 10, start);
+        assert!(!limiter.is_unlimited());
+        let mut now = start;
+        let mut admitted = 0;
+        while now < start + Duration::from_secs(3) {
+            let wait = limiter.wait_time(4096, now);
+            if wait == Duration::from_nanos(0) {
+                limiter.consume(4096);
+                admitted += 1;
+            } else {
+                now += wait;
+            }
+        }
+        // 4 requests with full budget at start, then one every 250ms.
+        assert_eq!(admitted, 15);
+
+        // Requests of 512 bytes are limited by 10 operations per second.
+        let mut limiter = RateLimiter::new(16384, 10, start);
+        let mut now = start;
+        let mut admitted = 0;
+        while now < start + Duration::from_secs(3) {
+            let wait = limiter.wait_time(512, now);
+            if wait == Duration::from_nanos(0) {
+                limiter.consume(512);
+                admitted += 1;
+            } else {
+                now += wait;
+            }
+        }
+        // 10 requests with full budget at start, then one every 100ms.
+        assert_eq!(admitted, 39);
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Ordered bring-up stages with cleanup on failure.
+//!
+//! Every resource which outlives the process (pidfile, socket file) or needs
+//! to be released explicitly (tap, fd) registers a cleanup action once it is
+//! created. If any stage fails, or `Rollback` is dropped without `commit()`,
+//! registered actions are run in reverse order.
+//!
+//! Resources kept after bring-up, e.g. the cgroup created by us, register an
+//! exit hook instead, which is run by `run_exit_hooks` before the process
+//! exits by `std::process::exit`, where destructors are skipped.
+
+use std::sync::{Mutex, Once};
+
+use super::errors::{ErrorKind, Result, ResultExt};
+
+type CleanupAction = Box<dyn FnOnce()>;
+type ExitAction = Box<dyn FnOnce() + Send>;
+
+static mut EXIT_HOOKS: Option<Mutex<Vec<(String, ExitAction)>>> = None;
+static EXIT_HOOKS_INIT: Once = Once::new();
+
+fn exit_hooks() -> &'static Mutex<Vec<(String, ExitAction)>> {
+    // Written only once under `EXIT_HOOKS_INIT`, read-only afterwards.
+    unsafe {
+        EXIT_HOOKS_INIT.call_once(|| EXIT_HOOKS = Some(Mutex::new(Vec::new())));
+        EXIT_HOOKS.as_ref().unwrap()
+    }
+}
+
+/// Register cleanup `action` which is run before the process exits.
+///
+/// # Arguments
+///
+/// * `name` - Name of resource, used for logging.
+/// * `action` - Closure to release the resource.
+pub fn register_exit_hook<F: FnOnce() + Send + 'static>(name: &str, action: F) {
+    exit_hooks()
+        .lock()
+        .unwrap()
+        .push((name.to_string(), Box::new(action)));
+}
+
+/// Run registered exit hooks in reverse order, each of them runs once.
+pub fn run_exit_hooks() {
+    let hooks = std::mem::take(&mut *exit_hooks().lock().unwrap());
+    for (name, action) in hooks.into_iter().rev() {
+        info!("Exit: release {}", name);
+        action();
+    }
+}
+
+/// Scope guard for staged bring-up.
+pub struct Rollback {
+    /// Registered cleanup actions with the name of resource.
+    actions: Vec<(String, CleanupAction)>,
+}
+
+impl Default for Rollback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rollback {
+    pub fn new() -> Self {
+        Rollback {
+            actions: Vec::new(),
+        }
+    }
+
+    /// Register cleanup `action` of a created resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of resource, used for logging.
+    /// * `action` - Closure to release the resource.
+    pub fn register<F: FnOnce() + 'static>(&mut self, name: &str, action: F) {
+        self.actions.push((name.to_string(), Box::new(action)));
+    }
+
+    /// Register a file or socket path to be unlinked.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of file created in bring-up.
+    pub fn register_unlink(&mut self, path: &str) {
+        let file_path = path.to_string();
+        self.register(path, move || {
+            if let Err(e) = std::fs::remove_file(&file_path) {
+                error!("Failed to unlink {}: {}", file_path, e);
+            }
+        });
+    }
+
+    /// Run a bring-up stage, failure of it is chained with the stage name
+    /// and unwinds all registered resources.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Name of stage.
+    /// * `f` - Stage body, which can register cleanup actions.
+    pub fn stage<T, E, F>(&mut self, stage: &str, f: F) -> Result<T>
+    where
+        E: std::error::Error + Send + 'static,
+        F: FnOnce(&mut Rollback) -> std::result::Result<T, E>,
+    {
+        let ret = f(self).chain_err(|| ErrorKind::BringUpStage(stage.to_string()));
+        if ret.is_err() {
+            self.unwind();
+        }
+        ret
+    }
+
+    /// All stages succeed, keep every created resource.
+    pub fn commit(mut self) {
+        self.actions.clear();
+    }
+
+    /// Number of cleanup actions registered.
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Whether no cleanup action is registered.
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    fn unwind(&mut self) {
+        while let Some((name, action)) = self.actions.pop() {
+            info!("Rollback: release {}", name);
+            action();
+        }
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        self.unwind();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::*;
+
+    const TMP_PREFIX: &str = "stratovirt_rollback_";
+
+    /// Count fds of this process which point to files created by these tests.
+    /// Other fds are skipped as tests run concurrently.
+    fn fd_count(tag: &str) -> usize {
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+            .filter(|target| {
+                target
+                    .to_str()
+                    .map_or(false, |t| t.contains(&format!("{}{}", TMP_PREFIX, tag)))
+            })
+            .count()
+    }
+
+    fn tmp_path(tag: &str, name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "{}{}_{}_{}",
+            TMP_PREFIX,
+            tag,
+            name,
+            std::process::id()
+        ))
+    }
+
+    /// Mock bring-up: pidfile, monitor socket and three tap-like devices
+    /// backed by opened files, stage `fail_stage` fails.
+    fn bring_up(tag: &str, fail_stage: usize) -> Result<Rollback> {
+        let mut rollback = Rollback::new();
+
+        rollback.stage("pidfile", |rb| -> std::io::Result<()> {
+            if fail_stage == 0 {
+                return Err(std::io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            let pidfile = tmp_path(tag, "pid");
+            File::create(&pidfile)?;
+            rb.register_unlink(pidfile.to_str().unwrap());
+            Ok(())
+        })?;
+
+        rollback.stage("api-channel", |rb| -> std::io::Result<()> {
+            let sock = tmp_path(tag, "sock");
+            let listener = UnixListener::bind(&sock)?;
+            rb.register_unlink(sock.to_str().unwrap());
+            rb.register("api-channel listener", move || drop(listener));
+            if fail_stage == 1 {
+                return Err(std::io::Error::from_raw_os_error(libc::EACCES));
+            }
+            Ok(())
+        })?;
+
+        for id in 0..3 {
+            let stage = format!("netdev net{}", id);
+            rollback.stage(&stage, |rb| -> std::io::Result<()> {
+                if fail_stage == 2 + id {
+                    return Err(std::io::Error::from_raw_os_error(libc::ENODEV));
+                }
+                let tap_path = tmp_path(tag, &format!("tap{}", id));
+                let tap = File::create(&tap_path)?;
+                rb.register_unlink(tap_path.to_str().unwrap());
+                rb.register(&format!("tap of net{}", id), move || drop(tap));
+                Ok(())
+            })?;
+        }
+
+        Ok(rollback)
+    }
+
+    fn leftover_files(tag: &str) -> usize {
+        std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry.file_name().to_str().map_or(false, |name| {
+                    name.starts_with(&format!("{}{}", TMP_PREFIX, tag))
+                })
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_rollback_each_stage() {
+        let tag = "stage";
+        for fail_stage in 0..5 {
+            let err = bring_up(tag, fail_stage).err().unwrap();
+
+            let msg = err.to_string();
+            match fail_stage {
+                0 => assert!(msg.contains("pidfile")),
+                1 => assert!(msg.contains("api-channel")),
+                _ => assert!(msg.contains(&format!("net{}", fail_stage - 2))),
+            }
+            assert_eq!(leftover_files(tag), 0);
+            assert_eq!(fd_count(tag), 0);
+        }
+    }
+
+    #[test]
+    fn test_rollback_commit() {
+        let tag = "commit";
+        let rollback = bring_up(tag, usize::max_value()).unwrap();
+        // pidfile, socket file, listener, three tap files and their fds.
+        assert_eq!(rollback.len(), 9);
+        assert_eq!(fd_count(tag), 3);
+        rollback.commit();
+        // Committed resources are kept, fds are closed with their owner.
+        assert_eq!(leftover_files(tag), 5);
+        assert_eq!(fd_count(tag), 0);
+
+        for name in &["pid", "sock", "tap0", "tap1", "tap2"] {
+            std::fs::remove_file(tmp_path(tag, name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rollback_order() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut rollback = Rollback::new();
+            for i in 0..3 {
+                let order = order.clone();
+                rollback.register(&i.to_string(), move || order.borrow_mut().push(i));
+            }
+        }
+        assert_eq!(*order.borrow(), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_exit_hooks() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..3 {
+            let order = order.clone();
+            register_exit_hook(&i.to_string(), move || order.lock().unwrap().push(i));
+        }
+        run_exit_hooks();
+        assert_eq!(*order.lock().unwrap(), vec![2, 1, 0]);
+
+        // Hooks are consumed by the first run.
+        run_exit_hooks();
+        assert_eq!(order.lock().unwrap().len(), 3);
+    }
+}
@@ -26,6 +26,7 @@ pub const TUN_F_VIRTIO: u32 = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_UFO;
 
 const IFF_TAP: u16 = 0x02;
 const IFF_NO_PI: u16 = 0x1000;
+const IFF_MULTI_QUEUE: u16 = 0x0100;
 const IFF_VNET_HDR: u16 = 0x4000;
 const TUNTAP_PATH: &str = "/dev/net/tun";
 
@@ -44,7 +45,15 @@ pub struct Tap {
 }
 
 impl Tap {
-    pub fn new(name: Option<&str>, fd: Option<RawFd>) -> Result<Self> {
+    /// Open a tap device by `name`, or take over the tap device `fd` opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the tap device on host.
+    /// * `fd` - Fd of the tap device opened.
+    /// * `queue_pairs` - Number of queue pairs of the tap device opened by
+    ///   `name`, one of which is attached to the returned fd.
+    pub fn new(name: Option<&str>, fd: Option<RawFd>, queue_pairs: u16) -> Result<Self> {
         let file;
 
         if let Some(name) = name {
@@ -56,9 +65,13 @@ impl Tap {
             let (left, _) = ifr_name.split_at_mut(name.len());
             left.copy_from_slice(name.as_bytes());
 
+            let mut ifr_flags = IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+            if queue_pairs > 1 {
+                ifr_flags |= IFF_MULTI_QUEUE;
+            }
             let mut if_req = IfReq {
                 ifr_name,
-                ifr_flags: IFF_TAP | IFF_NO_PI | IFF_VNET_HDR,
+                ifr_flags,
             };
 
             let file_ = OpenOptions::new()
@@ -68,7 +81,10 @@ impl Tap {
                 .open(TUNTAP_PATH)
                 .chain_err(|| format!("Open {} failed.", TUNTAP_PATH))?;
 
-            unsafe { ioctl_with_mut_ref(&file_, TUNSETIFF(), &mut if_req) };
+            let ret = unsafe { ioctl_with_mut_ref(&file_, TUNSETIFF(), &mut if_req) };
+            if ret < 0 {
+                return Err(format!("ioctl TUNSETIFF of tap {} failed.", name).into());
+            }
 
             file = file_;
         } else if let Some(fd) = fd {